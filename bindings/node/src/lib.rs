@@ -0,0 +1,289 @@
+//! Node.js bindings for iceberg.
+//!
+//! Exposes `Database` as a JavaScript class. Values are `Buffer`s, and every
+//! operation that touches storage returns a `Promise` and runs on the libuv
+//! thread pool, so embedding the database never blocks the event loop.
+
+use iceberg::db::Database as Db;
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A commit, as returned by writes and `log`.
+#[napi(object)]
+pub struct Commit {
+    pub id: String,
+    pub parent: Option<String>,
+    pub tree_root: String,
+    pub message: String,
+    /// RFC 3339 timestamp.
+    pub timestamp: String,
+}
+
+impl From<iceberg::commit::Commit> for Commit {
+    fn from(c: iceberg::commit::Commit) -> Self {
+        Self {
+            id: c.id,
+            parent: c.parent,
+            tree_root: c.tree_root,
+            message: c.message,
+            timestamp: c.timestamp.to_rfc3339(),
+        }
+    }
+}
+
+/// A named pointer to a commit.
+#[napi(object)]
+pub struct Tag {
+    pub name: String,
+    pub commit_id: String,
+    pub message: Option<String>,
+    /// RFC 3339 timestamp.
+    pub created_at: String,
+}
+
+impl From<iceberg::tag::Tag> for Tag {
+    fn from(t: iceberg::tag::Tag) -> Self {
+        Self {
+            name: t.name,
+            commit_id: t.commit_id,
+            message: t.message,
+            created_at: t.created_at.to_rfc3339(),
+        }
+    }
+}
+
+/// A key and its value, as returned by `scanPrefix`.
+#[napi(object)]
+pub struct Entry {
+    pub key: String,
+    pub value: Buffer,
+}
+
+type KeyValues = Vec<(String, Vec<u8>)>;
+
+/// Blocking database work run off the JavaScript thread.
+///
+/// `T` is computed on a worker thread and turned into the JavaScript-facing
+/// `J` on the main thread, since `Buffer`s may only be created there.
+pub struct Job<T, J> {
+    run: Option<Box<dyn FnOnce() -> iceberg::error::Result<T> + Send>>,
+    convert: fn(T) -> J,
+}
+
+fn job<T, J>(
+    run: impl FnOnce() -> iceberg::error::Result<T> + Send + 'static,
+    convert: fn(T) -> J,
+) -> AsyncTask<Job<T, J>>
+where
+    Job<T, J>: Task,
+{
+    AsyncTask::new(Job {
+        run: Some(Box::new(run)),
+        convert,
+    })
+}
+
+impl<T: Send + 'static, J: ToNapiValue + TypeName> Task for Job<T, J> {
+    type Output = T;
+    type JsValue = J;
+
+    fn compute(&mut self) -> Result<T> {
+        let run = self.run.take().expect("job already ran");
+        run().map_err(to_js_error)
+    }
+
+    fn resolve(&mut self, _env: Env, output: T) -> Result<J> {
+        Ok((self.convert)(output))
+    }
+}
+
+fn to_js_error(e: iceberg::error::IcebergError) -> Error {
+    Error::from_reason(e.to_string())
+}
+
+fn same<T>(t: T) -> T {
+    t
+}
+
+/// An iceberg database.
+#[napi]
+pub struct Database {
+    inner: Arc<Db>,
+}
+
+#[napi]
+impl Database {
+    /// Open or create a database at `path`.
+    #[napi(factory)]
+    pub fn open(path: String) -> Result<Self> {
+        let inner = Db::open(Path::new(&path)).map_err(to_js_error)?;
+        Ok(Self {
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// Initialize a new database at `path` (creates the "main" branch).
+    #[napi(factory)]
+    pub fn init(path: String) -> Result<Self> {
+        let inner = Db::init(Path::new(&path)).map_err(to_js_error)?;
+        Ok(Self {
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// Get a value from the current branch HEAD.
+    #[napi(ts_return_type = "Promise<Buffer>")]
+    pub fn get(&self, key: String) -> AsyncTask<Job<Vec<u8>, Buffer>> {
+        let db = Arc::clone(&self.inner);
+        job(move || db.get(&key), Buffer::from)
+    }
+
+    /// Get a value as of a specific commit.
+    #[napi(ts_return_type = "Promise<Buffer>")]
+    pub fn get_at(&self, key: String, commit_id: String) -> AsyncTask<Job<Vec<u8>, Buffer>> {
+        let db = Arc::clone(&self.inner);
+        job(move || db.get_at(&key, &commit_id), Buffer::from)
+    }
+
+    /// Store a value, creating a commit.
+    #[napi(ts_return_type = "Promise<Commit>")]
+    pub fn put(
+        &self,
+        key: String,
+        value: Buffer,
+        message: Option<String>,
+    ) -> AsyncTask<Job<iceberg::commit::Commit, Commit>> {
+        let db = Arc::clone(&self.inner);
+        let value = Vec::from(value);
+        job(
+            move || db.put(&key, value, message.as_deref()),
+            Commit::from,
+        )
+    }
+
+    /// Delete a key, creating a commit.
+    #[napi(ts_return_type = "Promise<Commit>")]
+    pub fn delete(
+        &self,
+        key: String,
+        message: Option<String>,
+    ) -> AsyncTask<Job<iceberg::commit::Commit, Commit>> {
+        let db = Arc::clone(&self.inner);
+        job(move || db.delete(&key, message.as_deref()), Commit::from)
+    }
+
+    /// All keys starting with `prefix`, with their values, in key order.
+    #[napi(ts_return_type = "Promise<Entry[]>")]
+    pub fn scan_prefix(&self, prefix: String) -> AsyncTask<Job<KeyValues, Vec<Entry>>> {
+        let db = Arc::clone(&self.inner);
+        job(
+            move || db.scan_prefix(&prefix),
+            |entries| {
+                entries
+                    .into_iter()
+                    .map(|(key, value)| Entry {
+                        key,
+                        value: value.into(),
+                    })
+                    .collect()
+            },
+        )
+    }
+
+    /// Commit history of the current branch, newest first: at most `limit`
+    /// commits after skipping the newest `skip`.
+    #[napi(ts_return_type = "Promise<Commit[]>")]
+    pub fn log(
+        &self,
+        limit: Option<u32>,
+        skip: Option<u32>,
+    ) -> AsyncTask<Job<Vec<iceberg::commit::Commit>, Vec<Commit>>> {
+        let db = Arc::clone(&self.inner);
+        let limit = limit.map_or(usize::MAX, |n| n as usize);
+        let skip = skip.unwrap_or(0) as usize;
+        job(
+            move || db.log_page(limit, skip),
+            |log| log.into_iter().map(Commit::from).collect(),
+        )
+    }
+
+    /// Name of the checked-out branch.
+    #[napi(ts_return_type = "Promise<string>")]
+    pub fn current_branch(&self) -> AsyncTask<Job<String, String>> {
+        let db = Arc::clone(&self.inner);
+        job(move || db.current_branch(), same)
+    }
+
+    /// Names of all branches.
+    #[napi(ts_return_type = "Promise<string[]>")]
+    pub fn branches(&self) -> AsyncTask<Job<Vec<String>, Vec<String>>> {
+        let db = Arc::clone(&self.inner);
+        job(move || db.branches(), same)
+    }
+
+    /// Create a branch at the current HEAD.
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn create_branch(&self, name: String) -> AsyncTask<Job<(), ()>> {
+        let db = Arc::clone(&self.inner);
+        job(move || db.create_branch(&name), same)
+    }
+
+    /// Switch to another branch.
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn checkout(&self, name: String) -> AsyncTask<Job<(), ()>> {
+        let db = Arc::clone(&self.inner);
+        job(move || db.checkout(&name), same)
+    }
+
+    /// Delete a branch.
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn delete_branch(&self, name: String) -> AsyncTask<Job<(), ()>> {
+        let db = Arc::clone(&self.inner);
+        job(move || db.delete_branch(&name), same)
+    }
+
+    /// Merge another branch into the current one.
+    #[napi(ts_return_type = "Promise<Commit>")]
+    pub fn merge(
+        &self,
+        source: String,
+        message: Option<String>,
+    ) -> AsyncTask<Job<iceberg::commit::Commit, Commit>> {
+        let db = Arc::clone(&self.inner);
+        job(move || db.merge(&source, message.as_deref()), Commit::from)
+    }
+
+    /// Tag a commit (the current HEAD if `commitId` is omitted).
+    #[napi(ts_return_type = "Promise<Tag>")]
+    pub fn create_tag(
+        &self,
+        name: String,
+        commit_id: Option<String>,
+        message: Option<String>,
+    ) -> AsyncTask<Job<iceberg::tag::Tag, Tag>> {
+        let db = Arc::clone(&self.inner);
+        job(
+            move || db.create_tag(&name, commit_id.as_deref(), message.as_deref()),
+            Tag::from,
+        )
+    }
+
+    /// All tags, newest first.
+    #[napi(ts_return_type = "Promise<Tag[]>")]
+    pub fn tags(&self) -> AsyncTask<Job<Vec<iceberg::tag::Tag>, Vec<Tag>>> {
+        let db = Arc::clone(&self.inner);
+        job(
+            move || db.tags(),
+            |tags| tags.into_iter().map(Tag::from).collect(),
+        )
+    }
+
+    /// Persist pending bloom filter and index changes.
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn flush(&self) -> AsyncTask<Job<(), ()>> {
+        let db = Arc::clone(&self.inner);
+        job(move || db.flush(), same)
+    }
+}
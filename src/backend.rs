@@ -0,0 +1,505 @@
+use crate::error::Result;
+use std::collections::BTreeMap;
+use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{Cursor, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+/// Suffix of the temporary file `write_atomic` writes before renaming.
+pub const TEMP_SUFFIX: &str = ".tmp";
+
+/// Byte-object storage underneath a database.
+///
+/// Everything the database persists — refs, trees, commits, blocks, the WAL —
+/// goes through this trait, so it can live somewhere other than a local
+/// filesystem (memory, browser storage). Keys are `/`-separated relative
+/// paths such as `refs/refs.json` or `store/blocks/ab/ab12…`; a key prefix
+/// acts as a directory for `list`.
+pub trait Backend: Send + Sync {
+    /// Read an object; `None` if it does not exist.
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Read an object a piece at a time; `None` if it does not exist.
+    /// Backends that cannot stream read the whole object first.
+    fn read_stream(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        Ok(self
+            .read(key)?
+            .map(|data| Box::new(Cursor::new(data)) as Box<dyn Read + Send>))
+    }
+
+    /// Create or replace an object atomically and durably: after a crash the
+    /// old or the new content is visible, never a mix.
+    fn write(&self, key: &str, data: &[u8]) -> Result<()>;
+
+    /// Write a new immutable object. Unlike `write` this may skip the
+    /// durability work, because content-addressed objects are verified on read.
+    fn create(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.write(key, data)
+    }
+
+    /// Append to an object, creating it if needed.
+    fn append(&self, key: &str, data: &[u8]) -> Result<()>;
+
+    /// Make earlier appends to an object durable.
+    fn sync(&self, _key: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Delete an object. Deleting a missing object is not an error.
+    fn delete(&self, key: &str) -> Result<()>;
+
+    /// Move an object to a new key, replacing any object there. Moving a
+    /// missing object is not an error.
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        if let Some(data) = self.read(from)? {
+            self.write(to, &data)?;
+            self.delete(from)?;
+        }
+        Ok(())
+    }
+
+    /// Size of an object in bytes, `None` if it does not exist.
+    fn size(&self, key: &str) -> Result<Option<u64>>;
+
+    /// Whether an object exists.
+    fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.size(key)?.is_some())
+    }
+
+    /// A value that changes whenever the object is written, for callers
+    /// that cache what they read from it; `None` if it does not exist.
+    /// Backends without cheap metadata hash the content.
+    fn version(&self, key: &str) -> Result<Option<u64>> {
+        Ok(self.read(key)?.map(|data| {
+            let mut hasher = DefaultHasher::new();
+            data.hash(&mut hasher);
+            hasher.finish()
+        }))
+    }
+
+    /// Names of the objects and sub-directories directly under `dir`, sorted.
+    fn list(&self, dir: &str) -> Result<Vec<String>>;
+
+    /// Local file backing an object, if any. Enables memory-mapped reads.
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Join a directory key and a name.
+pub fn key(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// Objects stored as files below a root directory.
+pub struct FsBackend {
+    root: PathBuf,
+}
+
+impl FsBackend {
+    /// Use (and create if needed) the directory at `root`.
+    pub fn open(root: &Path) -> Result<Self> {
+        fs::create_dir_all(root)?;
+        Ok(Self {
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// The root directory.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    /// Path of `key`, with its parent directory created.
+    fn prepare(&self, key: &str) -> Result<PathBuf> {
+        let path = self.path(key);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(path)
+    }
+}
+
+impl Backend for FsBackend {
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.path(key)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn read_stream(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        match fs::File::open(self.path(key)) {
+            Ok(file) => Ok(Some(Box::new(file))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, key: &str, data: &[u8]) -> Result<()> {
+        write_atomic(&self.prepare(key)?, data)
+    }
+
+    /// As `write`: block files are memory-mapped on read, which is only
+    /// sound for files that are complete once they exist.
+    fn create(&self, key: &str, data: &[u8]) -> Result<()> {
+        write_atomic(&self.prepare(key)?, data)
+    }
+
+    fn append(&self, key: &str, data: &[u8]) -> Result<()> {
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.prepare(key)?)?
+            .write_all(data)?;
+        Ok(())
+    }
+
+    fn sync(&self, key: &str) -> Result<()> {
+        match fs::OpenOptions::new().write(true).open(self.path(key)) {
+            Ok(f) => Ok(f.sync_all()?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        match fs::remove_file(self.path(key)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        match fs::rename(self.path(from), self.prepare(to)?) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// From the file's metadata. `write` replaces the file with a new one,
+    /// so its inode changes even where timestamps are coarse.
+    fn version(&self, key: &str) -> Result<Option<u64>> {
+        let meta = match fs::metadata(self.path(key)) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut hasher = DefaultHasher::new();
+        meta.len().hash(&mut hasher);
+        meta.modified().ok().hash(&mut hasher);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            (meta.dev(), meta.ino()).hash(&mut hasher);
+        }
+        Ok(Some(hasher.finish()))
+    }
+
+    fn size(&self, key: &str) -> Result<Option<u64>> {
+        match fs::metadata(self.path(key)) {
+            Ok(m) => Ok(Some(m.len())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>> {
+        let entries = match fs::read_dir(self.path(dir)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            // Leftovers of interrupted atomic writes are not objects.
+            if !is_temp_file(Path::new(&name)) {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path(key))
+    }
+}
+
+/// Objects kept in process memory. Nothing survives the process; useful for
+/// tests, caches, and as the working set of backends that persist
+/// asynchronously.
+#[derive(Default)]
+pub struct MemoryBackend {
+    objects: RwLock<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from an existing set of objects.
+    pub fn with_objects(objects: BTreeMap<String, Vec<u8>>) -> Self {
+        Self {
+            objects: RwLock::new(objects),
+        }
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.objects.read().unwrap().get(key).cloned())
+    }
+
+    fn write(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.objects
+            .write()
+            .unwrap()
+            .insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn append(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.objects
+            .write()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .extend_from_slice(data);
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.objects.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let mut objects = self.objects.write().unwrap();
+        if let Some(data) = objects.remove(from) {
+            objects.insert(to.to_string(), data);
+        }
+        Ok(())
+    }
+
+    fn size(&self, key: &str) -> Result<Option<u64>> {
+        Ok(self
+            .objects
+            .read()
+            .unwrap()
+            .get(key)
+            .map(|v| v.len() as u64))
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>> {
+        let prefix = key(dir, "");
+        let objects = self.objects.read().unwrap();
+        let mut names: Vec<String> = objects
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| {
+                let rest = &k[prefix.len()..];
+                rest.split('/').next().unwrap_or(rest).to_string()
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+}
+
+/// Replace `path` with `data` atomically.
+///
+/// The data is written to a `.tmp` file beside `path` and fsynced, renamed
+/// over `path`, and the directory is fsynced so the rename itself survives
+/// a crash. Readers see either the old or the new file, never a partial
+/// one. A leftover `.tmp` file means a write was interrupted and can be
+/// ignored.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    write_atomic_with(path, |f| Ok(f.write_all(data)?))
+}
+
+/// `write_atomic` of whatever `write` writes into the file, for data
+/// produced as it goes. Should `write` fail, `path` is left as it was and
+/// the temp file removed.
+pub fn write_atomic_with<T>(
+    path: &Path,
+    write: impl FnOnce(&mut fs::File) -> Result<T>,
+) -> Result<T> {
+    let tmp = temp_path(path);
+    let written = (|| -> Result<T> {
+        let mut f = fs::File::create(&tmp)?;
+        let written = write(&mut f)?;
+        f.sync_all()?;
+        Ok(written)
+    })();
+    let written = match written {
+        Ok(written) => written,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+    };
+    fs::rename(&tmp, path)?;
+    if let Some(dir) = path.parent() {
+        sync_dir(dir)?;
+    }
+    Ok(written)
+}
+
+/// A temp file beside `path` for `write_atomic`, named by process and
+/// counter so that writers of the same object, such as two processes
+/// storing one block, never share one.
+fn temp_path(path: &Path) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(
+        ".{}-{}{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed),
+        TEMP_SUFFIX
+    ));
+    PathBuf::from(tmp)
+}
+
+/// Whether `path` is an interrupted `write_atomic` temp file.
+pub fn is_temp_file(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(TEMP_SUFFIX)
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Directories can't be opened for fsync on this platform; renames are
+/// durable once the file itself is synced.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exercise(backend: &dyn Backend) {
+        assert_eq!(backend.read("refs/refs.json").unwrap(), None);
+        backend.write("refs/refs.json", b"old").unwrap();
+        let old = backend.version("refs/refs.json").unwrap();
+        backend.write("refs/refs.json", b"new").unwrap();
+        assert_eq!(backend.read("refs/refs.json").unwrap().unwrap(), b"new");
+        let new = backend.version("refs/refs.json").unwrap();
+        assert!(old.is_some() && new.is_some() && old != new);
+        assert_eq!(backend.version("refs/refs.json").unwrap(), new);
+        assert_eq!(backend.version("missing").unwrap(), None);
+
+        backend.create("store/blocks/ab/ab12", b"x").unwrap();
+        backend.create("store/blocks/cd/cd34", b"yy").unwrap();
+        assert_eq!(backend.list("store/blocks").unwrap(), vec!["ab", "cd"]);
+        assert_eq!(backend.list("store/blocks/cd").unwrap(), vec!["cd34"]);
+        assert_eq!(backend.size("store/blocks/cd/cd34").unwrap(), Some(2));
+        assert!(backend.list("missing").unwrap().is_empty());
+
+        backend.append("wal/wal.log", b"ab").unwrap();
+        backend.append("wal/wal.log", b"cd").unwrap();
+        backend.sync("wal/wal.log").unwrap();
+        assert_eq!(backend.read("wal/wal.log").unwrap().unwrap(), b"abcd");
+
+        backend.delete("store/blocks/ab/ab12").unwrap();
+        backend.delete("store/blocks/ab/ab12").unwrap();
+        assert!(!backend.exists("store/blocks/ab/ab12").unwrap());
+
+        backend.rename("wal/wal.log", "blobs/ef/ef56").unwrap();
+        backend.rename("wal/wal.log", "blobs/ef/ef56").unwrap();
+        assert!(!backend.exists("wal/wal.log").unwrap());
+        let mut streamed = Vec::new();
+        backend
+            .read_stream("blobs/ef/ef56")
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut streamed)
+            .unwrap();
+        assert_eq!(streamed, b"abcd");
+        assert!(backend.read_stream("missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn fs_backend_contract() {
+        let tmp = tempfile::tempdir().unwrap();
+        let backend = FsBackend::open(tmp.path()).unwrap();
+        exercise(&backend);
+        assert_eq!(
+            backend.local_path("refs/refs.json"),
+            Some(tmp.path().join("refs/refs.json"))
+        );
+    }
+
+    #[test]
+    fn fs_backend_creates_objects_by_rename() {
+        let tmp = tempfile::tempdir().unwrap();
+        let backend = FsBackend::open(tmp.path()).unwrap();
+        backend.create("blocks/ab/ab12", b"old").unwrap();
+        let block = tmp.path().join("blocks/ab/ab12");
+        let mapped = tmp.path().join("mapped");
+        fs::hard_link(&block, &mapped).unwrap();
+        // A file already open (or mapped) keeps its content.
+        backend.create("blocks/ab/ab12", b"new").unwrap();
+        assert_eq!(fs::read(&mapped).unwrap(), b"old");
+        assert_eq!(fs::read(&block).unwrap(), b"new");
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| backend.create("blocks/cd/cd34", b"same").unwrap());
+            }
+        });
+        assert_eq!(backend.read("blocks/cd/cd34").unwrap().unwrap(), b"same");
+        assert_eq!(
+            fs::read_dir(tmp.path().join("blocks/cd")).unwrap().count(),
+            1
+        );
+    }
+
+    #[test]
+    fn memory_backend_contract() {
+        let backend = MemoryBackend::new();
+        exercise(&backend);
+        assert_eq!(backend.local_path("refs/refs.json"), None);
+    }
+
+    #[test]
+    fn write_atomic_replaces_without_leftovers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("refs.json");
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        let names: Vec<_> = fs::read_dir(tmp.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec!["refs.json"]);
+        assert!(is_temp_file(Path::new("refs.json.tmp")));
+
+        let failed = write_atomic_with::<()>(&path, |f| {
+            f.write_all(b"partial")?;
+            Err(crate::error::IcebergError::KeyNotFound("k".into()))
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 1);
+    }
+}
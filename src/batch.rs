@@ -0,0 +1,210 @@
+//! Several puts and deletes committed together, for `Database::write`.
+
+use crate::error::{IcebergError, Result};
+use serde::Deserialize;
+use std::io::BufRead;
+
+/// One write of a `WriteBatch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Put { key: String, value: Vec<u8> },
+    Delete { key: String },
+}
+
+impl BatchOp {
+    /// The key the write touches.
+    pub fn key(&self) -> &str {
+        match self {
+            BatchOp::Put { key, .. } | BatchOp::Delete { key } => key,
+        }
+    }
+}
+
+/// One line of a batch file, as `WriteBatch::from_jsonl` reads it.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase", deny_unknown_fields)]
+enum Line {
+    Put {
+        key: String,
+        value: serde_json::Value,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+/// A point in a `WriteBatch` that `rollback_to` can return to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savepoint {
+    id: u64,
+}
+
+/// Writes applied in order as a single commit: either all of them land or
+/// none do. Later writes to a key override earlier ones.
+///
+/// Savepoints work as in SQL: rolling back to one drops the writes queued
+/// since and the savepoints taken since, but keeps the savepoint itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+    /// Live savepoints, oldest first, with the number of writes queued
+    /// when each was taken.
+    savepoints: Vec<(u64, usize)>,
+    next_savepoint: u64,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read a batch from JSON lines such as
+    /// `{"op": "put", "key": "k", "value": "v"}` or
+    /// `{"op": "delete", "key": "k"}`. A string value is stored as its
+    /// text, any other JSON value as compact JSON. Blank lines are skipped.
+    pub fn from_jsonl(reader: impl BufRead) -> Result<Self> {
+        let mut batch = Self::new();
+        for (n, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let parsed = serde_json::from_str(&line).map_err(|source| IcebergError::Parse {
+                line: n + 1,
+                source,
+            })?;
+            match parsed {
+                Line::Put { key, value } => {
+                    let value = match value {
+                        serde_json::Value::String(text) => text.into_bytes(),
+                        other => other.to_string().into_bytes(),
+                    };
+                    batch.put(&key, value)
+                }
+                Line::Delete { key } => batch.delete(&key),
+            };
+        }
+        Ok(batch)
+    }
+
+    /// Queue a put of `value` under `key`.
+    pub fn put(&mut self, key: &str, value: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Put {
+            key: key.to_string(),
+            value,
+        });
+        self
+    }
+
+    /// Queue a delete of `key`, which must exist when the write reaches it.
+    pub fn delete(&mut self, key: &str) -> &mut Self {
+        self.ops.push(BatchOp::Delete {
+            key: key.to_string(),
+        });
+        self
+    }
+
+    /// Mark the writes queued so far, to return to with `rollback_to`.
+    pub fn savepoint(&mut self) -> Savepoint {
+        let id = self.next_savepoint;
+        self.next_savepoint += 1;
+        self.savepoints.push((id, self.ops.len()));
+        Savepoint { id }
+    }
+
+    /// Drop the writes queued since `savepoint`, and any savepoints taken
+    /// since. Fails if `savepoint` was itself dropped by an earlier
+    /// rollback.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> Result<()> {
+        let Some(pos) = self
+            .savepoints
+            .iter()
+            .position(|(id, _)| *id == savepoint.id)
+        else {
+            return Err(IcebergError::InvalidQuery(
+                "savepoint is not live in this batch".into(),
+            ));
+        };
+        self.ops.truncate(self.savepoints[pos].1);
+        self.savepoints.truncate(pos + 1);
+        Ok(())
+    }
+
+    /// The queued writes, in order.
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollback_drops_later_writes_and_savepoints() {
+        let mut batch = WriteBatch::new();
+        batch.put("a", b"1".to_vec());
+        let first = batch.savepoint();
+        batch.put("b", b"2".to_vec());
+        let second = batch.savepoint();
+        batch.delete("a");
+
+        batch.rollback_to(second).unwrap();
+        assert_eq!(batch.len(), 2);
+        batch.put("c", b"3".to_vec());
+        batch.rollback_to(second).unwrap();
+        assert_eq!(batch.len(), 2);
+
+        batch.rollback_to(first).unwrap();
+        assert_eq!(
+            batch.ops(),
+            [BatchOp::Put {
+                key: "a".into(),
+                value: b"1".to_vec()
+            }]
+        );
+        assert!(batch.rollback_to(second).is_err());
+        let third = batch.savepoint();
+        assert_ne!(third, second);
+    }
+
+    #[test]
+    fn reads_json_lines() {
+        let input = r#"{"op": "put", "key": "a", "value": "text"}
+
+{"op": "put", "key": "b", "value": {"n": 1}}
+{"op": "delete", "key": "c"}
+"#;
+        let batch = WriteBatch::from_jsonl(input.as_bytes()).unwrap();
+        let mut expected = WriteBatch::new();
+        expected
+            .put("a", b"text".to_vec())
+            .put("b", br#"{"n":1}"#.to_vec())
+            .delete("c");
+        assert_eq!(batch, expected);
+
+        for bad in [
+            r#"{"op": "put", "key": "a"}"#,
+            r#"{"op": "rename", "key": "a"}"#,
+            r#"{"op": "delete", "key": "a", "value": 1}"#,
+            "not json",
+        ] {
+            let input = format!("{{\"op\": \"delete\", \"key\": \"x\"}}\n{}", bad);
+            let err = WriteBatch::from_jsonl(input.as_bytes()).unwrap_err();
+            assert!(
+                matches!(err, IcebergError::Parse { line: 2, .. }),
+                "{}",
+                err
+            );
+            assert!(err.to_string().starts_with("Parse error on line 2: "));
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Micro-benchmarks driving the public API, for `iceberg bench`.
+
+use crate::db::Database;
+use crate::error::Result;
+use crate::vector::XorShift;
+use std::time::{Duration, Instant};
+
+/// What a benchmark run does for each operation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Workload {
+    /// Write new keys.
+    #[default]
+    Put,
+    /// Read random keys of a preloaded key space.
+    Get,
+    /// Half reads, half overwrites of random keys of a preloaded key space.
+    Mixed,
+}
+
+impl std::str::FromStr for Workload {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "put" => Ok(Workload::Put),
+            "get" => Ok(Workload::Get),
+            "mixed" => Ok(Workload::Mixed),
+            other => Err(format!("unknown workload: {}", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for Workload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Workload::Put => "put",
+            Workload::Get => "get",
+            Workload::Mixed => "mixed",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Settings of a benchmark run.
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    pub workload: Workload,
+    /// Operations to time.
+    pub ops: usize,
+    /// Bytes per written value; values are random so compression can't
+    /// flatter the numbers.
+    pub value_size: usize,
+    /// Keys written before `get` and `mixed` runs, untimed.
+    pub keys: usize,
+    /// Seed for values and key choice.
+    pub seed: u64,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self {
+            workload: Workload::Put,
+            ops: 10_000,
+            value_size: 1024,
+            keys: 1000,
+            seed: 0x1ceb_e76b,
+        }
+    }
+}
+
+/// Throughput and latency of a benchmark run.
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub workload: Workload,
+    pub ops: usize,
+    pub elapsed: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl BenchReport {
+    /// Operations per second.
+    pub fn throughput(&self) -> f64 {
+        self.ops as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+impl std::fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{}: {} ops in {:.2?} ({:.0} ops/s)",
+            self.workload,
+            self.ops,
+            self.elapsed,
+            self.throughput()
+        )?;
+        writeln!(
+            f,
+            "latency: p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, max {:.2?}",
+            self.p50, self.p90, self.p99, self.max
+        )
+    }
+}
+
+/// Run a benchmark against `db`, calling `progress(done, total)` as timed
+/// operations complete. Writes go to keys under `bench:`.
+pub fn run(
+    db: &Database,
+    options: &BenchOptions,
+    mut progress: impl FnMut(usize, usize),
+) -> Result<BenchReport> {
+    let mut rng = XorShift(options.seed.max(1));
+    let value: Vec<u8> = (0..options.value_size)
+        .map(|_| rng.below(256) as u8)
+        .collect();
+    let key = |i: usize| format!("bench:{:010}", i);
+    let keys = match options.workload {
+        Workload::Put => 0,
+        Workload::Get | Workload::Mixed => options.keys.max(1),
+    };
+    for i in 0..keys {
+        db.put(&key(i), value.clone(), None)?;
+    }
+
+    let step = (options.ops / 100).max(1);
+    let mut latencies = Vec::with_capacity(options.ops);
+    let start = Instant::now();
+    for i in 0..options.ops {
+        let op = Instant::now();
+        match options.workload {
+            Workload::Put => {
+                db.put(&key(i), value.clone(), None)?;
+            }
+            Workload::Get => {
+                db.get(&key(rng.below(keys)))?;
+            }
+            Workload::Mixed if i % 2 == 0 => {
+                db.get(&key(rng.below(keys)))?;
+            }
+            Workload::Mixed => {
+                db.put(&key(rng.below(keys)), value.clone(), None)?;
+            }
+        }
+        latencies.push(op.elapsed());
+        if (i + 1) % step == 0 || i + 1 == options.ops {
+            progress(i + 1, options.ops);
+        }
+    }
+    let elapsed = start.elapsed();
+
+    latencies.sort_unstable();
+    let percentile = |p: usize| {
+        let last = latencies.len().saturating_sub(1);
+        latencies.get(last * p / 100).copied().unwrap_or_default()
+    };
+    Ok(BenchReport {
+        workload: options.workload,
+        ops: options.ops,
+        elapsed,
+        p50: percentile(50),
+        p90: percentile(90),
+        p99: percentile(99),
+        max: percentile(100),
+    })
+}
+
+/// Parse a byte count such as `512`, `1k` or `4M` (binary multiples).
+pub fn parse_size(s: &str) -> std::result::Result<usize, String> {
+    let s = s.trim();
+    let (digits, shift) = match s.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&s[..s.len() - 1], 10),
+        Some('m') => (&s[..s.len() - 1], 20),
+        Some('g') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use std::sync::Arc;
+
+    #[test]
+    fn sizes_take_binary_suffixes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("1k"), Ok(1024));
+        assert_eq!(parse_size("4M"), Ok(4 << 20));
+        assert!(parse_size("k").is_err());
+        assert!(parse_size("1t").is_err());
+    }
+
+    #[test]
+    fn runs_each_workload() {
+        for workload in [Workload::Put, Workload::Get, Workload::Mixed] {
+            let db = Database::init_with_backend(Arc::new(MemoryBackend::new())).unwrap();
+            let options = BenchOptions {
+                workload,
+                ops: 20,
+                value_size: 100,
+                keys: 5,
+                ..Default::default()
+            };
+            let mut reports = 0;
+            let report = run(&db, &options, |_, _| reports += 1).unwrap();
+            assert_eq!(report.ops, 20);
+            assert!(report.p50 <= report.p90 && report.p99 <= report.max);
+            assert_eq!(reports, 20);
+            let keys = if workload == Workload::Put { 20 } else { 5 };
+            assert_eq!(db.count("bench:").unwrap(), keys);
+            assert!(report
+                .to_string()
+                .starts_with(&format!("{}: 20 ops", workload)));
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! The blob store: values too large for the block store, kept as raw
+//! objects addressed by the SHA-256 of their content.
+//!
+//! Unlike blocks, blobs carry no header and are neither compressed nor
+//! cached, and they are written and read a piece at a time, so a value of
+//! hundreds of megabytes never has to be held in memory or framed as one
+//! record. Trees refer to them with `TreeValue::Blob`.
+
+use crate::backend::{self, Backend};
+use crate::block::BlockHash;
+use crate::error::{IcebergError, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Bytes read or written at a time.
+const CHUNK_SIZE: usize = 1 << 20;
+/// Directory of blobs still being written, under the store's.
+const INCOMING_DIR: &str = "incoming";
+
+/// Raw objects under `blobs/<first two hex digits>/<hash>` of a backend.
+pub struct BlobStore {
+    backend: Arc<dyn Backend>,
+    /// Key prefix of the store inside the backend.
+    dir: String,
+    /// Numbers the incoming objects of this handle.
+    next_incoming: AtomicU64,
+}
+
+impl BlobStore {
+    /// Open the blob store under the `dir` key prefix of a backend.
+    pub fn with_backend(backend: Arc<dyn Backend>, dir: &str) -> Self {
+        Self {
+            backend,
+            dir: dir.to_string(),
+            next_incoming: AtomicU64::new(0),
+        }
+    }
+
+    /// Start writing a blob.
+    pub fn writer(&self) -> Result<BlobWriter<'_>> {
+        let name = format!(
+            "{}-{}{}",
+            std::process::id(),
+            self.next_incoming.fetch_add(1, Ordering::Relaxed),
+            backend::TEMP_SUFFIX
+        );
+        let incoming = backend::key(&backend::key(&self.dir, INCOMING_DIR), &name);
+        // Left over by an earlier process with the same id.
+        self.backend.delete(&incoming)?;
+        Ok(BlobWriter {
+            store: self,
+            incoming: Some(incoming),
+            hasher: Sha256::new(),
+            size: 0,
+        })
+    }
+
+    /// Store a blob held in memory. Returns its hash.
+    pub fn put(&self, data: &[u8]) -> Result<BlockHash> {
+        let mut writer = self.writer()?;
+        for chunk in data.chunks(CHUNK_SIZE) {
+            writer.write(chunk)?;
+        }
+        Ok(writer.finish()?.0)
+    }
+
+    /// Stream a blob from `reader`. Returns its hash and size.
+    pub fn put_reader(&self, mut reader: impl Read) -> Result<(BlockHash, u64)> {
+        let mut writer = self.writer()?;
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            writer.write(&buf[..n])?;
+        }
+        writer.finish()
+    }
+
+    /// Stream a blob into `writer`, checking it against its hash as it
+    /// goes. Returns the number of bytes written; fails with `Corruption`
+    /// after writing them all if the content does not match.
+    pub fn write_to(&self, hash: &str, mut writer: impl Write) -> Result<u64> {
+        let mut reader = self
+            .backend
+            .read_stream(&self.blob_key(hash))?
+            .ok_or_else(|| IcebergError::Corruption(format!("blob not found: {}", hash)))?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut size = 0u64;
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            writer.write_all(&buf[..n])?;
+            size += n as u64;
+        }
+        if format!("{:x}", hasher.finalize()) != hash {
+            return Err(IcebergError::Corruption(format!(
+                "blob integrity check failed: {}",
+                hash
+            )));
+        }
+        Ok(size)
+    }
+
+    /// Read a whole blob into memory.
+    pub fn get(&self, hash: &str) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.write_to(hash, &mut data)?;
+        Ok(data)
+    }
+
+    /// Read a blob through and check it matches its hash.
+    pub fn verify(&self, hash: &str) -> Result<()> {
+        self.write_to(hash, std::io::sink()).map(|_| ())
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.backend.exists(&self.blob_key(hash)).unwrap_or(false)
+    }
+
+    /// The backend key of a blob.
+    pub fn blob_key(&self, hash: &str) -> String {
+        let prefix = &hash[..2.min(hash.len())];
+        backend::key(&self.dir, &format!("{}/{}", prefix, hash))
+    }
+
+    /// Hashes of all stored blobs with their sizes.
+    pub fn blob_sizes(&self) -> Result<Vec<(BlockHash, u64)>> {
+        self.blob_keys()?
+            .into_iter()
+            .map(|key| {
+                let size = self.backend.size(&key)?.unwrap_or(0);
+                let hash = key.rsplit('/').next().unwrap_or_default().to_string();
+                Ok((hash, size))
+            })
+            .collect()
+    }
+
+    /// Delete every blob not in `live`, calling `tick` for each blob
+    /// checked. Returns the number removed and the bytes they took.
+    pub fn retain_with(
+        &self,
+        live: &HashSet<BlockHash>,
+        mut tick: impl FnMut(),
+    ) -> Result<(usize, u64)> {
+        let mut removed = 0;
+        let mut bytes = 0;
+        for key in self.blob_keys()? {
+            tick();
+            let hash = key.rsplit('/').next().unwrap_or_default();
+            if live.contains(hash) {
+                continue;
+            }
+            bytes += self.backend.size(&key)?.unwrap_or(0);
+            self.backend.delete(&key)?;
+            removed += 1;
+        }
+        Ok((removed, bytes))
+    }
+
+    /// Keys of all stored blobs, which live in two-character prefix
+    /// directories beside the incoming one.
+    fn blob_keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for prefix in self.backend.list(&self.dir)? {
+            if prefix == INCOMING_DIR {
+                continue;
+            }
+            let prefix = backend::key(&self.dir, &prefix);
+            for name in self.backend.list(&prefix)? {
+                keys.push(backend::key(&prefix, &name));
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// A blob being written, from `BlobStore::writer`.
+///
+/// The content goes to an incoming object and only moves under its hash
+/// once complete, so a blob that exists is always whole. Dropping the
+/// writer unfinished deletes what was written.
+pub struct BlobWriter<'a> {
+    store: &'a BlobStore,
+    incoming: Option<String>,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl BlobWriter<'_> {
+    /// Append to the blob.
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        if let Some(incoming) = &self.incoming {
+            self.store.backend.append(incoming, data)?;
+        }
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Bytes written so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Store the blob under its hash, returning the hash and size. A blob
+    /// already stored is kept and the new copy dropped.
+    pub fn finish(self) -> Result<(BlockHash, u64)> {
+        self.finish_with(|_| {})
+    }
+
+    /// `finish`, calling `before_store` with the blob's hash before it is
+    /// stored under it, or found stored already.
+    pub fn finish_with(mut self, before_store: impl FnOnce(&str)) -> Result<(BlockHash, u64)> {
+        let incoming = self.incoming.take().unwrap_or_default();
+        let backend = &self.store.backend;
+        // Make sure the empty blob exists even though nothing was appended.
+        if self.size == 0 {
+            backend.write(&incoming, &[])?;
+        }
+        let hash = format!("{:x}", std::mem::take(&mut self.hasher).finalize());
+        before_store(&hash);
+        let key = self.store.blob_key(&hash);
+        if backend.exists(&key)? {
+            backend.delete(&incoming)?;
+        } else {
+            backend.sync(&incoming)?;
+            backend.rename(&incoming, &key)?;
+        }
+        Ok((hash, self.size))
+    }
+}
+
+impl Drop for BlobWriter<'_> {
+    fn drop(&mut self) {
+        if let Some(incoming) = &self.incoming {
+            let _ = self.store.backend.delete(incoming);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{FsBackend, MemoryBackend};
+    use crate::block::compute_hash;
+
+    #[test]
+    fn streams_blobs_in_and_out() {
+        let tmp = tempfile::tempdir().unwrap();
+        let fs: Arc<dyn Backend> = Arc::new(FsBackend::open(tmp.path()).unwrap());
+        for backend in [fs, Arc::new(MemoryBackend::new())] {
+            let store = BlobStore::with_backend(Arc::clone(&backend), "blobs");
+            let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 7).map(|i| (i % 251) as u8).collect();
+            let (hash, size) = store.put_reader(&data[..]).unwrap();
+            assert_eq!(
+                (hash.as_str(), size),
+                (compute_hash(&data).as_str(), 2 * 1048576 + 7)
+            );
+            assert_eq!(store.put(&data).unwrap(), hash);
+            assert_eq!(store.get(&hash).unwrap(), data);
+            let empty = store.put(&[]).unwrap();
+            assert_eq!(store.get(&empty).unwrap(), b"");
+
+            // An abandoned writer leaves nothing behind.
+            let mut writer = store.writer().unwrap();
+            writer.write(b"partial").unwrap();
+            drop(writer);
+            assert_eq!(store.blob_sizes().unwrap().len(), 2);
+            assert!(backend.list("blobs/incoming").unwrap().is_empty());
+
+            backend.write(&store.blob_key(&empty), b"tampered").unwrap();
+            assert!(store.verify(&empty).is_err());
+            let (removed, bytes) = store
+                .retain_with(&HashSet::from([hash.clone()]), || {})
+                .unwrap();
+            assert_eq!((removed, bytes), (1, 8));
+            assert!(store.contains(&hash) && !store.contains(&empty));
+        }
+    }
+}
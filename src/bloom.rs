@@ -1,5 +1,18 @@
+use crate::cuckoo::CuckooFilter;
+use crate::error::{IcebergError, Result};
+use crate::namespace;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// Magic prefix of a binary bloom filter file.
+const FILTER_MAGIC: &[u8; 4] = b"IBLM";
+/// Magic prefix of a binary file of a `BloomSet`'s filters.
+const SET_MAGIC: &[u8; 4] = b"IBLS";
+/// Current format version of binary bloom filter files.
+const FILTER_FORMAT_VERSION: u8 = 1;
+/// Current format version of binary `BloomSet` files.
+const SET_FORMAT_VERSION: u8 = 2;
 
 /// A simple Bloom filter for fast negative lookups.
 ///
@@ -50,6 +63,16 @@ impl BloomFilter {
         }
     }
 
+    /// A filter holding exactly `keys`, sized for them at a 1% false
+    /// positive rate.
+    pub fn with_keys<'a>(keys: impl ExactSizeIterator<Item = &'a String>) -> Self {
+        let mut filter = Self::new(keys.len(), FP_RATE);
+        for key in keys {
+            filter.insert(key.as_bytes());
+        }
+        filter
+    }
+
     /// Insert a key into the filter.
     pub fn insert(&mut self, key: &[u8]) {
         for i in 0..self.num_hashes {
@@ -114,6 +137,48 @@ impl BloomFilter {
         true
     }
 
+    /// Binary form: magic, format version, then the filter (see `encode`).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(25 + self.bits.len());
+        out.extend_from_slice(FILTER_MAGIC);
+        out.push(FILTER_FORMAT_VERSION);
+        self.encode(&mut out);
+        out
+    }
+
+    /// Decode `to_bytes` output.
+    pub fn from_bytes(raw: &[u8]) -> Result<Self> {
+        let mut reader = Reader(check_header(raw, FILTER_MAGIC, FILTER_FORMAT_VERSION)?.1);
+        let filter = Self::decode(&mut reader)?;
+        reader.finish()?;
+        Ok(filter)
+    }
+
+    /// Bit count (LE u64), hash count (LE u32), item count (LE u64), then
+    /// the bit vector.
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.num_bits as u64).to_le_bytes());
+        out.extend_from_slice(&self.num_hashes.to_le_bytes());
+        out.extend_from_slice(&(self.count as u64).to_le_bytes());
+        out.extend_from_slice(&self.bits);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        let num_bits = reader.u64()? as usize;
+        let num_hashes = reader.u32()?;
+        let count = reader.u64()? as usize;
+        if num_bits == 0 || num_hashes == 0 {
+            return Err(corrupt("empty filter"));
+        }
+        let bits = reader.take(num_bits.div_ceil(8))?.to_vec();
+        Ok(Self {
+            bits,
+            num_bits,
+            num_hashes,
+            count,
+        })
+    }
+
     /// Hash a key with a given seed to produce a bit index.
     fn hash(&self, key: &[u8], seed: u32) -> usize {
         let mut hasher = Sha256::new();
@@ -125,6 +190,423 @@ impl BloomFilter {
     }
 }
 
+/// A probabilistic set of keys: `may_contain` may answer yes for a key
+/// that was never inserted, but never no for one that was.
+pub trait MembershipFilter {
+    /// Insert a key. Returns `false`, changing nothing, if the filter has
+    /// no room left for it.
+    fn insert(&mut self, key: &[u8]) -> bool;
+
+    /// `false` if the key is definitely not present, `true` if it may be.
+    fn may_contain(&self, key: &[u8]) -> bool;
+
+    /// Remove one insertion of a key. Returns `false` if the filter cannot
+    /// remove keys or holds no trace of this one. Only keys that were
+    /// inserted may be removed.
+    fn remove(&mut self, key: &[u8]) -> bool;
+
+    /// Number of items inserted (and not removed).
+    fn count(&self) -> usize;
+
+    /// Memory usage in bytes.
+    fn size_bytes(&self) -> usize;
+
+    /// Estimated false positive rate given current fill.
+    fn estimated_fp_rate(&self) -> f64;
+}
+
+impl MembershipFilter for BloomFilter {
+    fn insert(&mut self, key: &[u8]) -> bool {
+        BloomFilter::insert(self, key);
+        true
+    }
+
+    fn may_contain(&self, key: &[u8]) -> bool {
+        BloomFilter::may_contain(self, key)
+    }
+
+    fn remove(&mut self, _key: &[u8]) -> bool {
+        false
+    }
+
+    fn count(&self) -> usize {
+        BloomFilter::count(self)
+    }
+
+    fn size_bytes(&self) -> usize {
+        BloomFilter::size_bytes(self)
+    }
+
+    fn estimated_fp_rate(&self) -> f64 {
+        BloomFilter::estimated_fp_rate(self)
+    }
+}
+
+/// Which membership filter a database keeps of its keys.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterKind {
+    /// Bloom filters: never full, but cannot forget deleted keys.
+    #[default]
+    Bloom,
+    /// Cuckoo filters: forget deleted keys and are smaller at low false
+    /// positive rates, but are rebuilt larger when they fill up.
+    Cuckoo,
+}
+
+impl std::str::FromStr for FilterKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "bloom" => Ok(FilterKind::Bloom),
+            "cuckoo" => Ok(FilterKind::Cuckoo),
+            other => Err(format!("unknown filter kind: {}", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for FilterKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            FilterKind::Bloom => "bloom",
+            FilterKind::Cuckoo => "cuckoo",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A membership filter of either kind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyFilter {
+    Bloom(BloomFilter),
+    Cuckoo(CuckooFilter),
+}
+
+impl KeyFilter {
+    /// An empty filter of `kind` with room for `items` keys; a bloom filter
+    /// takes more, at a rising false positive rate.
+    pub fn new(kind: FilterKind, items: usize) -> Self {
+        match kind {
+            FilterKind::Bloom => KeyFilter::Bloom(BloomFilter::new(items, FP_RATE)),
+            FilterKind::Cuckoo => KeyFilter::Cuckoo(CuckooFilter::new(items)),
+        }
+    }
+
+    pub fn kind(&self) -> FilterKind {
+        match self {
+            KeyFilter::Bloom(_) => FilterKind::Bloom,
+            KeyFilter::Cuckoo(_) => FilterKind::Cuckoo,
+        }
+    }
+
+    /// Number of bits the filter takes.
+    pub fn num_bits(&self) -> usize {
+        match self {
+            KeyFilter::Bloom(filter) => filter.num_bits(),
+            KeyFilter::Cuckoo(filter) => filter.size_bytes() * 8,
+        }
+    }
+
+    fn filter(&self) -> &dyn MembershipFilter {
+        match self {
+            KeyFilter::Bloom(filter) => filter,
+            KeyFilter::Cuckoo(filter) => filter,
+        }
+    }
+
+    fn filter_mut(&mut self) -> &mut dyn MembershipFilter {
+        match self {
+            KeyFilter::Bloom(filter) => filter,
+            KeyFilter::Cuckoo(filter) => filter,
+        }
+    }
+
+    /// Binary form (see `BloomSet::to_bytes`): a kind tag byte, then the
+    /// filter.
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            KeyFilter::Bloom(filter) => {
+                out.push(0);
+                filter.encode(out);
+            }
+            KeyFilter::Cuckoo(filter) => {
+                out.push(1);
+                filter.encode(out);
+            }
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        match reader.take(1)?[0] {
+            0 => Ok(KeyFilter::Bloom(BloomFilter::decode(reader)?)),
+            1 => Ok(KeyFilter::Cuckoo(CuckooFilter::decode(reader)?)),
+            tag => Err(corrupt(&format!("unknown filter kind {}", tag))),
+        }
+    }
+}
+
+impl MembershipFilter for KeyFilter {
+    fn insert(&mut self, key: &[u8]) -> bool {
+        self.filter_mut().insert(key)
+    }
+
+    fn may_contain(&self, key: &[u8]) -> bool {
+        self.filter().may_contain(key)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> bool {
+        self.filter_mut().remove(key)
+    }
+
+    fn count(&self) -> usize {
+        self.filter().count()
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.filter().size_bytes()
+    }
+
+    fn estimated_fp_rate(&self) -> f64 {
+        self.filter().estimated_fp_rate()
+    }
+}
+
+/// Capacity of a filter created for keys not covered by a rebuild.
+const DEFAULT_ITEMS: usize = 10000;
+/// Smallest capacity a rebuilt filter is sized for.
+const MIN_ITEMS: usize = 1000;
+const FP_RATE: f64 = 0.01;
+
+/// One membership filter per key namespace, so each is sized by and filled
+/// with only its own namespace's keys. Keys are the ones stored in trees;
+/// their namespace picks the filter. All filters are of the same kind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomSet {
+    default: KeyFilter,
+    namespaces: BTreeMap<String, KeyFilter>,
+    /// Set once an insert found its filter full; every key may then be
+    /// present until the set is rebuilt larger.
+    full: bool,
+}
+
+impl Default for BloomSet {
+    fn default() -> Self {
+        Self::empty(FilterKind::default())
+    }
+}
+
+impl BloomSet {
+    /// Assemble a set from the default namespace's filter and the others'.
+    pub fn new(default: KeyFilter, namespaces: BTreeMap<String, KeyFilter>) -> Self {
+        Self {
+            default,
+            namespaces,
+            full: false,
+        }
+    }
+
+    /// A set of `kind` holding no keys.
+    pub fn empty(kind: FilterKind) -> Self {
+        Self::new(KeyFilter::new(kind, DEFAULT_ITEMS), BTreeMap::new())
+    }
+
+    /// Build filters of `kind` holding exactly `keys`.
+    pub fn from_keys<'a>(kind: FilterKind, keys: impl IntoIterator<Item = &'a String>) -> Self {
+        let mut grouped: BTreeMap<Option<&str>, Vec<&str>> = BTreeMap::new();
+        for key in keys {
+            grouped
+                .entry(namespace::split(key).0)
+                .or_default()
+                .push(key);
+        }
+        // A full cuckoo filter rejects inserts, so leave it room to grow.
+        let items = |n: usize| match kind {
+            FilterKind::Bloom => n.max(MIN_ITEMS),
+            FilterKind::Cuckoo => (2 * n).max(MIN_ITEMS),
+        };
+        let mut set = Self::new(KeyFilter::new(kind, items(0)), BTreeMap::new());
+        for (ns, keys) in grouped {
+            let mut filter = KeyFilter::new(kind, items(keys.len()));
+            for key in keys {
+                set.full |= !filter.insert(key.as_bytes());
+            }
+            match ns {
+                None => set.default = filter,
+                Some(ns) => {
+                    set.namespaces.insert(ns.to_string(), filter);
+                }
+            }
+        }
+        set
+    }
+
+    /// The kind of the set's filters.
+    pub fn kind(&self) -> FilterKind {
+        self.default.kind()
+    }
+
+    /// The filter of the default namespace.
+    pub fn default_filter(&self) -> &KeyFilter {
+        &self.default
+    }
+
+    /// The filters of the other namespaces, by name.
+    pub fn namespaces(&self) -> &BTreeMap<String, KeyFilter> {
+        &self.namespaces
+    }
+
+    /// The filter of a namespace (`None` for the default one), if it has one.
+    pub fn filter(&self, ns: Option<&str>) -> Option<&KeyFilter> {
+        match ns {
+            None => Some(&self.default),
+            Some(ns) => self.namespaces.get(ns),
+        }
+    }
+
+    /// Whether an insert found its filter full (see `from_keys`).
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    /// Insert a key into its namespace's filter.
+    pub fn insert(&mut self, key: &str) {
+        let kind = self.kind();
+        let filter = match namespace::split(key).0 {
+            None => &mut self.default,
+            Some(ns) => self
+                .namespaces
+                .entry(ns.to_string())
+                .or_insert_with(|| KeyFilter::new(kind, DEFAULT_ITEMS)),
+        };
+        self.full |= !filter.insert(key.as_bytes());
+    }
+
+    /// Remove a key inserted earlier, if the filters can; see
+    /// `MembershipFilter::remove`.
+    pub fn remove(&mut self, key: &str) -> bool {
+        let filter = match namespace::split(key).0 {
+            None => Some(&mut self.default),
+            Some(ns) => self.namespaces.get_mut(ns),
+        };
+        filter.is_some_and(|filter| filter.remove(key.as_bytes()))
+    }
+
+    /// Insert a key unless its namespace's filter may already hold it,
+    /// returning whether it was inserted. Unlike `may_contain`, an empty or
+    /// missing filter does not count as holding the key.
+    pub fn cover(&mut self, key: &str) -> bool {
+        let held = self
+            .filter(namespace::split(key).0)
+            .is_some_and(|filter| filter.may_contain(key.as_bytes()));
+        if !held {
+            self.insert(key);
+        }
+        !held
+    }
+
+    /// Binary form: magic, format version, the default namespace's filter,
+    /// then the number of other namespaces (LE u32) and each one's name
+    /// (LE u32 length, UTF-8) and filter. Filters start with a kind tag
+    /// since version 2; version 1 holds only bloom filters.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(SET_MAGIC);
+        out.push(SET_FORMAT_VERSION);
+        self.default.encode(&mut out);
+        out.extend_from_slice(&(self.namespaces.len() as u32).to_le_bytes());
+        for (ns, filter) in &self.namespaces {
+            out.extend_from_slice(&(ns.len() as u32).to_le_bytes());
+            out.extend_from_slice(ns.as_bytes());
+            filter.encode(&mut out);
+        }
+        out
+    }
+
+    /// Decode `to_bytes` output.
+    pub fn from_bytes(raw: &[u8]) -> Result<Self> {
+        let (version, body) = check_header(raw, SET_MAGIC, SET_FORMAT_VERSION)?;
+        let mut reader = Reader(body);
+        let filter = |reader: &mut Reader| match version {
+            1 => BloomFilter::decode(reader).map(KeyFilter::Bloom),
+            _ => KeyFilter::decode(reader),
+        };
+        let default = filter(&mut reader)?;
+        let mut namespaces = BTreeMap::new();
+        for _ in 0..reader.u32()? {
+            let len = reader.u32()? as usize;
+            let ns = std::str::from_utf8(reader.take(len)?)
+                .map_err(|_| corrupt("invalid namespace name"))?;
+            namespaces.insert(ns.to_string(), filter(&mut reader)?);
+        }
+        reader.finish()?;
+        if namespaces.values().any(|f| f.kind() != default.kind()) {
+            return Err(corrupt("filters of mixed kinds"));
+        }
+        Ok(Self::new(default, namespaces))
+    }
+
+    /// Check if a key might be present. A namespace whose filter is empty or
+    /// missing knows nothing, so every key in it might be; nor does a full
+    /// set.
+    pub fn may_contain(&self, key: &str) -> bool {
+        match self.filter(namespace::split(key).0) {
+            Some(filter) if filter.count() > 0 && !self.full => filter.may_contain(key.as_bytes()),
+            _ => true,
+        }
+    }
+}
+
+fn corrupt(what: &str) -> IcebergError {
+    IcebergError::Corruption(format!("bloom filter: {}", what))
+}
+
+/// The format version and body of a binary file with `magic`, of a version
+/// up to `latest`.
+fn check_header<'a>(raw: &'a [u8], magic: &[u8; 4], latest: u8) -> Result<(u8, &'a [u8])> {
+    let body = raw
+        .strip_prefix(magic.as_slice())
+        .ok_or_else(|| corrupt("bad magic"))?;
+    match body.split_first() {
+        Some((&version, body)) if (1..=latest).contains(&version) => Ok((version, body)),
+        Some((version, _)) => Err(corrupt(&format!("unsupported version {}", version))),
+        None => Err(corrupt("truncated")),
+    }
+}
+
+/// Reads little-endian fields off the front of a binary file.
+pub(crate) struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(raw: &'a [u8]) -> Self {
+        Self(raw)
+    }
+
+    pub(crate) fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(corrupt("truncated"));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn finish(&self) -> Result<()> {
+        match self.0.is_empty() {
+            true => Ok(()),
+            false => Err(corrupt("trailing bytes")),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -219,4 +701,78 @@ mod tests {
         assert!(bf.size_bytes() > 100);
         assert!(bf.size_bytes() < 10000);
     }
+
+    #[test]
+    fn bloom_set_keeps_namespaces_apart() {
+        let keys: Vec<String> = vec![
+            "alice".into(),
+            namespace::qualify("users", "bob"),
+            namespace::qualify("users", "carol"),
+        ];
+        let set = BloomSet::from_keys(FilterKind::Bloom, &keys);
+        assert_eq!(set.default_filter().count(), 1);
+        assert_eq!(set.filter(Some("users")).unwrap().count(), 2);
+        assert!(set.filter(Some("orders")).is_none());
+        for key in &keys {
+            assert!(set.may_contain(key));
+        }
+        // A namespace without a filter cannot rule anything out.
+        assert!(set.may_contain(&namespace::qualify("orders", "1")));
+    }
+
+    #[test]
+    fn cuckoo_sets_forget_removed_keys_and_report_full() {
+        let keys: Vec<String> = vec!["alice".into(), namespace::qualify("users", "bob")];
+        let mut set = BloomSet::from_keys(FilterKind::Cuckoo, &keys);
+        assert_eq!(set.kind(), FilterKind::Cuckoo);
+        set.insert("carol");
+        assert!(set.remove("alice") && set.remove(&keys[1]));
+        assert!(!set.may_contain("alice"));
+        assert_eq!(set.filter(Some("users")).unwrap().count(), 0);
+        assert!(set.may_contain("carol"));
+        assert!(!set.remove("dave"));
+        assert!(!BloomSet::from_keys(FilterKind::Bloom, &keys).remove("alice"));
+
+        let mut set = BloomSet::new(KeyFilter::Cuckoo(CuckooFilter::new(8)), BTreeMap::new());
+        for i in 0..100 {
+            set.insert(&format!("k{}", i));
+        }
+        assert!(set.is_full());
+        assert!(set.may_contain("never inserted"));
+    }
+
+    #[test]
+    fn binary_form_round_trips_and_rejects_damage() {
+        let keys: Vec<String> = (0..300)
+            .map(|i| match i % 3 {
+                0 => format!("k{}", i),
+                _ => namespace::qualify(&format!("ns{}", i % 3), &format!("k{}", i)),
+            })
+            .collect();
+        for kind in [FilterKind::Bloom, FilterKind::Cuckoo] {
+            let set = BloomSet::from_keys(kind, &keys);
+            let bytes = set.to_bytes();
+            assert_eq!(BloomSet::from_bytes(&bytes).unwrap(), set);
+            assert!(BloomSet::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+            assert!(BloomSet::from_bytes(&[bytes.as_slice(), b"x"].concat()).is_err());
+            let mut future = bytes.clone();
+            future[4] = SET_FORMAT_VERSION + 1;
+            assert!(BloomSet::from_bytes(&future).is_err());
+        }
+
+        let filter = BloomFilter::with_keys(keys.iter());
+        let bytes = filter.to_bytes();
+        // Raw bits plus a few header bytes, far below the JSON array.
+        assert!(bytes.len() * 2 < serde_json::to_vec(&filter).unwrap().len());
+        assert_eq!(BloomFilter::from_bytes(&bytes).unwrap(), filter);
+        assert!(BloomSet::from_bytes(&bytes).is_err());
+        assert!(BloomSet::from_bytes(b"{").is_err());
+
+        // Version 1 sets held bloom filters without a kind tag.
+        let mut v1 = [SET_MAGIC.as_slice(), &[1]].concat();
+        filter.encode(&mut v1);
+        v1.extend_from_slice(&0u32.to_le_bytes());
+        let set = BloomSet::from_bytes(&v1).unwrap();
+        assert_eq!(set.default_filter(), &KeyFilter::Bloom(filter));
+    }
 }
@@ -0,0 +1,246 @@
+use crate::batch::WriteBatch;
+use crate::commit::Commit;
+use crate::db::{self, Database, ScanOptions, Target};
+use crate::error::{IcebergError, Result};
+use crate::snapshot::Snapshot;
+use std::io::{Read, Write};
+
+/// A view of one branch of a database, from `Database::on_branch`.
+///
+/// Reads and writes go to the branch whatever HEAD is, and never move HEAD,
+/// so scripts sharing a database directory can each work on their own
+/// branch without checking it out, and one process can serve several
+/// branches from as many threads.
+pub struct Branch<'a> {
+    db: &'a Database,
+    name: String,
+}
+
+impl<'a> Branch<'a> {
+    pub(crate) fn new(db: &'a Database, name: &str) -> Self {
+        Self {
+            db,
+            name: name.to_string(),
+        }
+    }
+
+    /// The branch name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn target(&self) -> Target<'_> {
+        Target::Branch(&self.name)
+    }
+
+    /// The commit at the tip of the branch.
+    pub fn head_commit(&self) -> Result<Commit> {
+        self.db.branch_commit(&self.name)
+    }
+
+    /// Commit log of the branch, newest first.
+    pub fn log(&self) -> Result<Vec<Commit>> {
+        self.db.log_of(self.target())
+    }
+
+    /// `log` loading commits on demand, as `Database::log_iter`.
+    pub fn log_iter(&self) -> Result<impl Iterator<Item = Result<Commit>> + '_> {
+        self.db.log_iter_of(self.target())
+    }
+
+    /// Get a value from the tip of the branch.
+    pub fn get(&self, key: &str) -> Result<Vec<u8>> {
+        self.db.get_in(self.target(), key)
+    }
+
+    /// Get a value as of a specific commit.
+    pub fn get_at(&self, key: &str, commit_id: &str) -> Result<Vec<u8>> {
+        self.db.get_at(key, commit_id)
+    }
+
+    /// Stream a value from the tip of the branch into a writer, as
+    /// `Database::get_writer`.
+    pub fn get_writer(&self, key: &str, writer: impl Write) -> Result<u64> {
+        let tree = self.db.branch_tree(&self.name)?;
+        let value = tree
+            .get(key)
+            .ok_or_else(|| IcebergError::KeyNotFound(key.into()))?;
+        self.db.write_value(value, writer)
+    }
+
+    /// Put a key-value pair; creates a new commit on the branch.
+    pub fn put(&self, key: &str, value: Vec<u8>, message: Option<&str>) -> Result<Commit> {
+        crate::namespace::check_key(key)?;
+        let msg = message
+            .map(String::from)
+            .unwrap_or_else(|| format!("put {}", key));
+        self.db.put_raw(self.target(), key, value, None, None, &msg)
+    }
+
+    /// `put` that fails with `Conflict` unless the branch is still at
+    /// `expected_head`, as `Database::put_if_head`.
+    pub fn put_if_head(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected_head: &str,
+        message: Option<&str>,
+    ) -> Result<Commit> {
+        crate::namespace::check_key(key)?;
+        let msg = message
+            .map(String::from)
+            .unwrap_or_else(|| format!("put {}", key));
+        self.db
+            .put_raw(self.target(), key, value, Some(expected_head), None, &msg)
+    }
+
+    /// Stream a value from a reader, as `Database::put_reader`; creates a
+    /// new commit on the branch.
+    pub fn put_reader(
+        &self,
+        key: &str,
+        reader: impl Read,
+        message: Option<&str>,
+    ) -> Result<Commit> {
+        crate::namespace::check_key(key)?;
+        let msg = message
+            .map(String::from)
+            .unwrap_or_else(|| format!("put {}", key));
+        self.db.put_reader_raw(self.target(), key, reader, &msg)
+    }
+
+    /// Delete a key; creates a new commit on the branch.
+    pub fn delete(&self, key: &str, message: Option<&str>) -> Result<Commit> {
+        crate::namespace::check_key(key)?;
+        let msg = message
+            .map(String::from)
+            .unwrap_or_else(|| format!("delete {}", key));
+        self.db.delete_raw(self.target(), key, None, &msg)
+    }
+
+    /// `delete` that fails with `Conflict` unless the branch is still at
+    /// `expected_head`.
+    pub fn delete_if_head(
+        &self,
+        key: &str,
+        expected_head: &str,
+        message: Option<&str>,
+    ) -> Result<Commit> {
+        crate::namespace::check_key(key)?;
+        let msg = message
+            .map(String::from)
+            .unwrap_or_else(|| format!("delete {}", key));
+        self.db
+            .delete_raw(self.target(), key, Some(expected_head), &msg)
+    }
+
+    /// Delete every key in `[start, end)` in a single commit on the
+    /// branch, or return `None` when there are none.
+    pub fn delete_range(
+        &self,
+        start: &str,
+        end: &str,
+        message: Option<&str>,
+    ) -> Result<Option<Commit>> {
+        self.db
+            .delete_range_raw(self.target(), None, start, end, message)
+    }
+
+    /// Apply a batch of writes as a single commit on the branch, as
+    /// `Database::write`.
+    pub fn write(&self, batch: &WriteBatch, message: Option<&str>) -> Result<Option<Commit>> {
+        self.db.write_checked(self.target(), batch, None, message)
+    }
+
+    /// `write` that fails with `Conflict` unless the branch is still at
+    /// `expected_head`.
+    pub fn write_if_head(
+        &self,
+        batch: &WriteBatch,
+        expected_head: &str,
+        message: Option<&str>,
+    ) -> Result<Option<Commit>> {
+        self.db
+            .write_checked(self.target(), batch, Some(expected_head), message)
+    }
+
+    /// A snapshot of the tip of the branch, as `Database::snapshot`.
+    pub fn snapshot(&self) -> Result<Snapshot<'a>> {
+        self.db.snapshot_of(self.target())
+    }
+
+    /// Scan keys at the tip of the branch by prefix.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.scan_prefix_with(prefix, &ScanOptions::default())
+    }
+
+    /// One page of `scan_prefix`.
+    pub fn scan_prefix_with(
+        &self,
+        prefix: &str,
+        options: &ScanOptions,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        self.db.scan_prefix_in(self.target(), prefix, options)
+    }
+
+    /// One page of `scan_prefix` as of a past commit.
+    pub fn scan_prefix_at(
+        &self,
+        prefix: &str,
+        commit_id: &str,
+        options: &ScanOptions,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        self.db.scan_prefix_at(prefix, commit_id, options)
+    }
+
+    /// Range scan at the tip of the branch.
+    pub fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.range_with(start, end, &ScanOptions::default())
+    }
+
+    /// One page of `range`.
+    pub fn range_with(
+        &self,
+        start: &str,
+        end: &str,
+        options: &ScanOptions,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        let tree = self.db.branch_tree(&self.name)?;
+        self.db.scan_tree(&tree, None, start, Some(end), options)
+    }
+
+    /// Keys under a prefix at the tip of the branch, without reading any
+    /// values.
+    pub fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.scan_keys_with(prefix, &ScanOptions::default())
+    }
+
+    /// One page of the keys under a prefix, without reading any values.
+    pub fn scan_keys_with(&self, prefix: &str, options: &ScanOptions) -> Result<Vec<String>> {
+        let tree = self.db.branch_tree(&self.name)?;
+        Ok(db::scan_page(&tree, None, prefix, None, options)
+            .map(|(k, _)| k.to_string())
+            .collect())
+    }
+
+    /// One page of `scan_keys` as of a past commit.
+    pub fn scan_keys_at(
+        &self,
+        prefix: &str,
+        commit_id: &str,
+        options: &ScanOptions,
+    ) -> Result<Vec<String>> {
+        self.db.scan_keys_at(prefix, commit_id, options)
+    }
+
+    /// Number of keys under a prefix at the tip of the branch.
+    pub fn count(&self, prefix: &str) -> Result<usize> {
+        let tree = self.db.branch_tree(&self.name)?;
+        Ok(db::scan_page(&tree, None, prefix, None, &ScanOptions::default()).count())
+    }
+
+    /// Number of keys under a prefix as of a past commit.
+    pub fn count_at(&self, prefix: &str, commit_id: &str) -> Result<usize> {
+        self.db.count_at(prefix, commit_id)
+    }
+}
@@ -0,0 +1,196 @@
+use crate::backend::{Backend, MemoryBackend};
+use crate::error::{IcebergError, Result};
+use js_sys::{Array, Promise, Uint8Array};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    IdbDatabase, IdbFactory, IdbOpenDbRequest, IdbRequest, IdbTransaction, IdbTransactionMode,
+    IdbVersionChangeEvent, WorkerGlobalScope,
+};
+
+/// Object store holding every key of a database.
+const OBJECT_STORE: &str = "objects";
+
+/// Storage in a browser's IndexedDB, for databases compiled to
+/// `wasm32-unknown-unknown`.
+///
+/// IndexedDB is asynchronous while `Backend` is not, so the whole database
+/// is loaded into memory by `open` and served from there. Changes are
+/// remembered and written back by `persist`, in a single IndexedDB
+/// transaction, so the stored copy always moves from one consistent state to
+/// the next. Call it after `Database::flush` (or after each batch of writes)
+/// to make them survive a reload:
+///
+/// ```ignore
+/// let idb = Arc::new(IdbBackend::open("my-db").await?);
+/// let db = Database::init_with_backend(idb.clone())?;
+/// db.put("k", b"v".to_vec(), None)?;
+/// db.flush()?;
+/// idb.persist().await?;
+/// ```
+pub struct IdbBackend {
+    name: String,
+    memory: MemoryBackend,
+    /// Keys changed since the last `persist`.
+    dirty: Mutex<BTreeSet<String>>,
+}
+
+impl IdbBackend {
+    /// Open (creating if needed) the IndexedDB database `name` and load its
+    /// objects.
+    pub async fn open(name: &str) -> Result<Self> {
+        let db = open_database(name).await?;
+        let tx = db.transaction_with_str(OBJECT_STORE).map_err(js_error)?;
+        let store = tx.object_store(OBJECT_STORE).map_err(js_error)?;
+        // Both requests run in one transaction and return records in key
+        // order, so keys and values line up.
+        let keys = request(store.get_all_keys().map_err(js_error)?).await?;
+        let values = request(store.get_all().map_err(js_error)?).await?;
+        db.close();
+        let objects: BTreeMap<String, Vec<u8>> = keys
+            .unchecked_into::<Array>()
+            .iter()
+            .zip(values.unchecked_into::<Array>().iter())
+            .filter_map(|(k, v)| Some((k.as_string()?, Uint8Array::new(&v).to_vec())))
+            .collect();
+        Ok(Self {
+            name: name.to_string(),
+            memory: MemoryBackend::with_objects(objects),
+            dirty: Mutex::new(BTreeSet::new()),
+        })
+    }
+
+    /// Whether there are changes `persist` has not written yet.
+    pub fn has_unpersisted(&self) -> bool {
+        !self.dirty.lock().unwrap().is_empty()
+    }
+
+    /// Write all changes since the last call to IndexedDB.
+    pub async fn persist(&self) -> Result<()> {
+        let keys = std::mem::take(&mut *self.dirty.lock().unwrap());
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let result = self.write_back(&keys).await;
+        if result.is_err() {
+            // Nothing was committed; try these keys again next time.
+            self.dirty.lock().unwrap().extend(keys);
+        }
+        result
+    }
+
+    async fn write_back(&self, keys: &BTreeSet<String>) -> Result<()> {
+        let db = open_database(&self.name).await?;
+        let tx = db
+            .transaction_with_str_and_mode(OBJECT_STORE, IdbTransactionMode::Readwrite)
+            .map_err(js_error)?;
+        let store = tx.object_store(OBJECT_STORE).map_err(js_error)?;
+        for key in keys {
+            let js_key = JsValue::from_str(key);
+            match self.memory.read(key)? {
+                Some(data) => store.put_with_key(&Uint8Array::from(data.as_slice()), &js_key),
+                None => store.delete(&js_key),
+            }
+            .map_err(js_error)?;
+        }
+        let done = completion(&tx).await;
+        db.close();
+        done
+    }
+
+    fn touch(&self, key: &str) {
+        self.dirty.lock().unwrap().insert(key.to_string());
+    }
+}
+
+impl Backend for IdbBackend {
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.memory.read(key)
+    }
+
+    fn write(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.memory.write(key, data)?;
+        self.touch(key);
+        Ok(())
+    }
+
+    fn append(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.memory.append(key, data)?;
+        self.touch(key);
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.memory.delete(key)?;
+        self.touch(key);
+        Ok(())
+    }
+
+    fn size(&self, key: &str) -> Result<Option<u64>> {
+        self.memory.size(key)
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>> {
+        self.memory.list(dir)
+    }
+}
+
+fn js_error(e: JsValue) -> IcebergError {
+    IcebergError::Io(std::io::Error::other(format!("IndexedDB: {:?}", e)))
+}
+
+/// The IndexedDB factory of the current window or worker.
+fn factory() -> Result<IdbFactory> {
+    let global = js_sys::global();
+    let factory = if let Some(window) = global.dyn_ref::<web_sys::Window>() {
+        window.indexed_db()
+    } else if let Some(worker) = global.dyn_ref::<WorkerGlobalScope>() {
+        worker.indexed_db()
+    } else {
+        Ok(None)
+    };
+    factory
+        .map_err(js_error)?
+        .ok_or_else(|| js_error(JsValue::from_str("not available in this context")))
+}
+
+async fn open_database(name: &str) -> Result<IdbDatabase> {
+    let open: IdbOpenDbRequest = factory()?.open_with_u32(name, 1).map_err(js_error)?;
+    let creating = open.clone();
+    // Runs on first open: create the object store.
+    let on_upgrade = Closure::<dyn FnMut(IdbVersionChangeEvent)>::new(move |_| {
+        if let Ok(db) = creating.result() {
+            let _ = db
+                .unchecked_into::<IdbDatabase>()
+                .create_object_store(OBJECT_STORE);
+        }
+    });
+    open.set_onupgradeneeded(Some(on_upgrade.as_ref().unchecked_ref()));
+    let db = request(open.clone().into()).await;
+    open.set_onupgradeneeded(None);
+    Ok(db?.unchecked_into())
+}
+
+/// Wait for a request to finish and return its result.
+async fn request(req: IdbRequest) -> Result<JsValue> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        req.set_onsuccess(Some(&resolve));
+        req.set_onerror(Some(&reject));
+    });
+    JsFuture::from(promise).await.map_err(js_error)?;
+    req.result().map_err(js_error)
+}
+
+/// Wait for a transaction to commit.
+async fn completion(tx: &IdbTransaction) -> Result<()> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        tx.set_oncomplete(Some(&resolve));
+        tx.set_onerror(Some(&reject));
+        tx.set_onabort(Some(&reject));
+    });
+    JsFuture::from(promise).await.map_err(js_error)?;
+    Ok(())
+}
@@ -0,0 +1,215 @@
+use serde::Serialize;
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// A least-recently-used cache bounded by a byte budget.
+///
+/// Each entry is inserted with its (estimated) size in bytes; once the total
+/// exceeds the budget, the least recently used entries are evicted. A budget
+/// of 0 disables caching entirely.
+#[derive(Debug)]
+pub struct LruCache<K, V> {
+    capacity: usize,
+    used: usize,
+    tick: u64,
+    entries: HashMap<K, CacheEntry<V>>,
+    /// Access tick → key, oldest first.
+    order: BTreeMap<u64, K>,
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry<V> {
+    value: V,
+    size: usize,
+    tick: u64,
+}
+
+/// Hit/miss counters and occupancy of a cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+    pub capacity: usize,
+}
+
+impl CacheStats {
+    /// Fraction of lookups served from the cache (0.0 if there were none).
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    /// Create a cache holding at most `capacity` bytes.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Look up a value, marking it as most recently used.
+    pub fn get<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                let k = self
+                    .order
+                    .remove(&entry.tick)
+                    .expect("lru order out of sync");
+                entry.tick = tick;
+                self.order.insert(tick, k);
+                self.hits += 1;
+                Some(entry.value.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Insert a value of the given size, evicting old entries as needed.
+    /// Values larger than the whole budget are not cached.
+    pub fn insert(&mut self, key: K, value: V, size: usize) {
+        self.remove(&key);
+        if self.capacity == 0 || size > self.capacity {
+            return;
+        }
+        self.tick += 1;
+        self.used += size;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                value,
+                size,
+                tick: self.tick,
+            },
+        );
+        self.evict();
+    }
+
+    /// Remove a value if present.
+    pub fn remove<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.tick);
+            self.used -= entry.size;
+        }
+    }
+
+    /// Drop every cached value (counters are kept).
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.used = 0;
+    }
+
+    /// Change the byte budget, evicting immediately if it shrank.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    /// Current counters and occupancy.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+            bytes: self.used,
+            capacity: self.capacity,
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.used > self.capacity {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.used -= entry.size;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hit_and_miss_counters() {
+        let mut cache: LruCache<String, u32> = LruCache::new(100);
+        assert_eq!(cache.get("a"), None);
+        cache.insert("a".into(), 1, 10);
+        assert_eq!(cache.get("a"), Some(1));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!(stats.bytes, 10);
+        assert!((stats.hit_rate() - 0.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache: LruCache<&str, u32> = LruCache::new(30);
+        cache.insert("a", 1, 10);
+        cache.insert("b", 2, 10);
+        cache.insert("c", 3, 10);
+        cache.get("a"); // "b" is now the oldest
+        cache.insert("d", 4, 10);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(1));
+        assert_eq!(cache.get("d"), Some(4));
+        assert_eq!(cache.stats().bytes, 30);
+    }
+
+    #[test]
+    fn oversized_values_and_zero_capacity_skip_caching() {
+        let mut cache: LruCache<&str, u32> = LruCache::new(5);
+        cache.insert("big", 1, 6);
+        assert_eq!(cache.get("big"), None);
+
+        let mut disabled: LruCache<&str, u32> = LruCache::new(0);
+        disabled.insert("a", 1, 1);
+        assert_eq!(disabled.stats().entries, 0);
+    }
+
+    #[test]
+    fn replace_and_shrink() {
+        let mut cache: LruCache<&str, u32> = LruCache::new(100);
+        cache.insert("a", 1, 40);
+        cache.insert("a", 2, 50);
+        assert_eq!(cache.stats().bytes, 50);
+        cache.insert("b", 3, 40);
+        cache.set_capacity(45);
+        assert_eq!(cache.stats().entries, 1);
+        assert_eq!(cache.get("b"), Some(3));
+        cache.remove("b");
+        assert_eq!(cache.stats().bytes, 0);
+    }
+}
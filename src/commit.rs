@@ -63,6 +63,17 @@ impl Commit {
         );
         compute_hash(payload.as_bytes())
     }
+
+    /// Check that the id matches the commit's contents.
+    pub fn verify(&self) -> bool {
+        self.id
+            == Self::compute_id(
+                &self.parent,
+                &self.tree_root,
+                &self.timestamp,
+                &self.message,
+            )
+    }
 }
 
 #[cfg(test)]
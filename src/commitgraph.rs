@@ -0,0 +1,322 @@
+//! The commit-graph file: the parent, tree, timestamp and generation of
+//! every commit in one binary file, so history walks need not read and
+//! parse each commit.
+//!
+//! A commit's generation is its distance from a root, counting the root as
+//! 1: an ancestor always has a lower generation than its descendants, so a
+//! walk looking for one can stop as soon as it passes below it.
+
+use crate::bloom::Reader;
+use crate::commit::Commit;
+use crate::error::{IcebergError, Result};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+const MAGIC: &[u8; 4] = b"IBCG";
+/// Version 1 has no generations.
+const FORMAT_VERSION: u8 = 2;
+
+/// What history walks need of a commit: everything but the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphEntry {
+    pub parent: Option<String>,
+    pub tree_root: String,
+    pub timestamp: DateTime<Utc>,
+    /// 1 plus the parent's generation, or 1 without a parent in the graph;
+    /// 0 until worked out.
+    pub generation: u64,
+}
+
+impl GraphEntry {
+    fn same_links(&self, other: &GraphEntry) -> bool {
+        (&self.parent, &self.tree_root, self.timestamp)
+            == (&other.parent, &other.tree_root, other.timestamp)
+    }
+}
+
+impl From<&Commit> for GraphEntry {
+    fn from(commit: &Commit) -> Self {
+        Self {
+            parent: commit.parent.clone(),
+            tree_root: commit.tree_root.clone(),
+            timestamp: commit.timestamp,
+            generation: 0,
+        }
+    }
+}
+
+/// The `GraphEntry` of each commit, by id.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommitGraph {
+    entries: HashMap<String, GraphEntry>,
+    /// Set when a change may have left generations wrong, until
+    /// `refresh_generations`.
+    stale: bool,
+}
+
+impl CommitGraph {
+    pub fn build<'a>(commits: impl IntoIterator<Item = &'a Commit>) -> Self {
+        let mut graph = Self::default();
+        for commit in commits {
+            graph.insert(commit);
+        }
+        graph
+    }
+
+    /// Add a commit, returning whether it replaced a different entry, as
+    /// when a commit is rewritten in place.
+    ///
+    /// A new commit on top of one already in the graph gets its generation
+    /// straight away; anything else, a rewrite above all, leaves the
+    /// generations stale.
+    pub fn insert(&mut self, commit: &Commit) -> bool {
+        let mut entry = GraphEntry::from(commit);
+        entry.generation = match &commit.parent {
+            None => 1,
+            Some(parent) => self
+                .generation(parent)
+                .map_or(0, |generation| generation + 1),
+        };
+        self.stale |= entry.generation == 0;
+        let rewritten = match self.entries.insert(commit.id.clone(), entry) {
+            Some(old) => !old.same_links(&self.entries[&commit.id]),
+            None => false,
+        };
+        self.stale |= rewritten;
+        rewritten
+    }
+
+    /// Remove a commit. Its children, if any, now count as roots, so
+    /// generations go stale.
+    pub fn remove(&mut self, id: &str) -> bool {
+        let removed = self.entries.remove(id).is_some();
+        self.stale |= removed;
+        removed
+    }
+
+    /// A commit's generation, or `None` if it is not in the graph or the
+    /// generations are stale.
+    pub fn generation(&self, id: &str) -> Option<u64> {
+        match self.stale {
+            true => None,
+            false => self.entries.get(id).map(|e| e.generation),
+        }
+    }
+
+    pub fn generations_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// Work out every generation again if they are stale.
+    pub fn refresh_generations(&mut self) {
+        if !self.stale {
+            return;
+        }
+        let mut done: HashMap<String, u64> = HashMap::with_capacity(self.entries.len());
+        for id in self.entries.keys() {
+            // Climb to a commit already done or a root, then number the
+            // chain on the way back down.
+            let mut chain = Vec::new();
+            let mut next = Some(id);
+            let mut base = 0;
+            // A corrupt graph could link commits in a loop.
+            while let Some(id) = next.filter(|_| chain.len() <= self.entries.len()) {
+                if let Some(&generation) = done.get(id) {
+                    base = generation;
+                    break;
+                }
+                chain.push(id);
+                next = self.entries[id]
+                    .parent
+                    .as_ref()
+                    .filter(|p| self.entries.contains_key(*p));
+            }
+            for (i, id) in chain.into_iter().rev().enumerate() {
+                done.insert(id.clone(), base + i as u64 + 1);
+            }
+        }
+        for (id, entry) in &mut self.entries {
+            entry.generation = done[id];
+        }
+        self.stale = false;
+    }
+
+    pub fn get(&self, id: &str) -> Option<&GraphEntry> {
+        self.entries.get(id)
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    /// Ids of every commit in the graph, in no particular order.
+    pub fn ids(&self) -> impl Iterator<Item = &String> {
+        self.entries.keys()
+    }
+
+    /// Each commit's id and parent, in no particular order.
+    pub fn links(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.entries
+            .iter()
+            .map(|(id, e)| (id.as_str(), e.parent.as_deref()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Binary form: magic, format version, the number of commits (LE u64),
+    /// then for each its id, parent (empty for a root commit) and tree root
+    /// as LE u32 length and UTF-8, its timestamp as seconds (LE i64) and
+    /// nanoseconds (LE u32) since the epoch, and its generation (LE u64).
+    /// Commits are sorted by id, so the same graph always encodes the same
+    /// way. Stale generations are written as 0.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        fn string(out: &mut Vec<u8>, s: &str) {
+            out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            out.extend_from_slice(s.as_bytes());
+        }
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(FORMAT_VERSION);
+        out.extend_from_slice(&(self.entries.len() as u64).to_le_bytes());
+        let mut ids: Vec<&String> = self.entries.keys().collect();
+        ids.sort();
+        for id in ids {
+            let entry = &self.entries[id];
+            string(&mut out, id);
+            string(&mut out, entry.parent.as_deref().unwrap_or(""));
+            string(&mut out, &entry.tree_root);
+            out.extend_from_slice(&entry.timestamp.timestamp().to_le_bytes());
+            out.extend_from_slice(&entry.timestamp.timestamp_subsec_nanos().to_le_bytes());
+            let generation = self.generation(id).unwrap_or(0);
+            out.extend_from_slice(&generation.to_le_bytes());
+        }
+        out
+    }
+
+    /// Decode `to_bytes` output.
+    pub fn from_bytes(raw: &[u8]) -> Result<Self> {
+        let body = raw
+            .strip_prefix(MAGIC.as_slice())
+            .ok_or_else(|| corrupt("bad magic"))?;
+        let (version, body) = match body.split_first() {
+            Some((&version, body)) if (1..=FORMAT_VERSION).contains(&version) => (version, body),
+            Some((version, _)) => return Err(corrupt(&format!("unsupported version {}", version))),
+            None => return Err(corrupt("truncated")),
+        };
+        let mut reader = Reader::new(body);
+        let string = |reader: &mut Reader| -> Result<String> {
+            let len = reader.u32()? as usize;
+            std::str::from_utf8(reader.take(len)?)
+                .map(String::from)
+                .map_err(|_| corrupt("invalid id"))
+        };
+        let mut graph = Self::default();
+        for _ in 0..reader.u64()? {
+            let id = string(&mut reader)?;
+            let parent = Some(string(&mut reader)?).filter(|p| !p.is_empty());
+            let tree_root = string(&mut reader)?;
+            let secs = reader.u64()? as i64;
+            let nanos = reader.u32()?;
+            let timestamp =
+                DateTime::from_timestamp(secs, nanos).ok_or_else(|| corrupt("bad timestamp"))?;
+            let generation = match version {
+                1 => 0,
+                _ => reader.u64()?,
+            };
+            graph.stale |= generation == 0;
+            graph.entries.insert(
+                id,
+                GraphEntry {
+                    parent,
+                    tree_root,
+                    timestamp,
+                    generation,
+                },
+            );
+        }
+        reader.finish()?;
+        Ok(graph)
+    }
+}
+
+fn corrupt(what: &str) -> IcebergError {
+    IcebergError::Corruption(format!("commit graph: {}", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generations_count_from_the_roots() {
+        let a = Commit::new(None, "t".into(), "a".into());
+        let b = Commit::new(Some(a.id.clone()), "t".into(), "b".into());
+        let c = Commit::new(Some(b.id.clone()), "t".into(), "c".into());
+        let d = Commit::new(Some(b.id.clone()), "t".into(), "d".into());
+        let mut graph = CommitGraph::default();
+        for commit in [&a, &b, &c] {
+            graph.insert(commit);
+        }
+        assert!(!graph.generations_stale());
+        assert_eq!(graph.generation(&c.id), Some(3));
+
+        // Out of order, generations wait for a refresh.
+        let mut graph = CommitGraph::build([&d, &c, &b, &a]);
+        assert!(graph.generations_stale());
+        graph.refresh_generations();
+        let generations: Vec<_> = [&a, &b, &c, &d]
+            .iter()
+            .map(|commit| graph.generation(&commit.id))
+            .collect();
+        assert_eq!(generations, [Some(1), Some(2), Some(3), Some(3)]);
+
+        // Version 1 files carry none.
+        let mut v1 = MAGIC.to_vec();
+        v1.push(1);
+        v1.extend_from_slice(&1u64.to_le_bytes());
+        for field in [a.id.as_str(), "", "t"] {
+            v1.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            v1.extend_from_slice(field.as_bytes());
+        }
+        v1.extend_from_slice(&a.timestamp.timestamp().to_le_bytes());
+        v1.extend_from_slice(&a.timestamp.timestamp_subsec_nanos().to_le_bytes());
+        let mut old = CommitGraph::from_bytes(&v1).unwrap();
+        assert!(old.generations_stale());
+        old.refresh_generations();
+        assert_eq!(old.generation(&a.id), Some(1));
+    }
+
+    #[test]
+    fn round_trips_and_tracks_rewrites() {
+        let root = Commit::new(None, "t1".into(), "first".into());
+        let child = Commit::new(Some(root.id.clone()), "t2".into(), "second".into());
+        let mut graph = CommitGraph::build([&root, &child]);
+        assert_eq!(graph.get(&child.id).unwrap().parent, Some(root.id.clone()));
+
+        let decoded = CommitGraph::from_bytes(&graph.to_bytes()).unwrap();
+        assert_eq!(decoded, graph);
+        assert_eq!(decoded.get(&root.id).unwrap().timestamp, root.timestamp);
+        assert_eq!(decoded.to_bytes(), graph.to_bytes());
+
+        assert!(!graph.insert(&child));
+        let mut rerooted = child.clone();
+        rerooted.parent = None;
+        assert!(graph.insert(&rerooted));
+        assert!(graph.remove(&root.id));
+        assert_eq!(graph.len(), 1);
+
+        assert_eq!(graph.generation(&child.id), None);
+        graph.refresh_generations();
+        assert_eq!(graph.generation(&child.id), Some(1));
+
+        let bytes = graph.to_bytes();
+        assert!(CommitGraph::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(CommitGraph::from_bytes(b"IBLS\x01").is_err());
+    }
+}
@@ -1,17 +1,44 @@
+use crate::block::BlockHash;
+use crate::commit::Commit;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 /// Configuration for compaction / garbage collection.
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct CompactionPolicy {
     /// Maximum number of versions to retain per branch (0 = unlimited).
     pub max_versions: usize,
     /// Maximum age of commits to retain (None = unlimited).
     pub max_age_days: Option<u64>,
+    /// Keep commits that a tag points to even when the limits above would
+    /// remove them. When off, tags on removed commits are deleted.
+    #[serde(default = "default_keep_tagged")]
+    pub keep_tagged: bool,
+    /// Replace the removed commits with a single snapshot commit holding the
+    /// newest removed commit's tree, instead of dropping them. Kept commits
+    /// are rewritten on top of it and so get new ids.
+    #[serde(default)]
+    pub squash: bool,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        Self {
+            max_versions: 0,
+            max_age_days: None,
+            keep_tagged: default_keep_tagged(),
+            squash: false,
+        }
+    }
+}
+
+fn default_keep_tagged() -> bool {
+    true
 }
 
 /// Result of a compaction run.
-#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
 pub struct CompactionResult {
     /// Number of commits removed.
     pub commits_removed: usize,
@@ -19,15 +46,99 @@ pub struct CompactionResult {
     pub trees_removed: usize,
     /// Number of blocks removed.
     pub blocks_removed: usize,
+    /// Number of blobs removed.
+    pub blobs_removed: usize,
+    /// Number of tags removed along with their commits.
+    pub tags_removed: usize,
     /// Bytes reclaimed.
     pub bytes_reclaimed: u64,
 }
 
+/// Result of squashing history into a snapshot commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SquashResult {
+    /// The synthetic commit standing in for the squashed history.
+    pub snapshot: Commit,
+    /// Number of commits the snapshot replaces.
+    pub squashed: usize,
+    /// Old → new ids of the squashed-up-to commit and every commit rebuilt
+    /// on top of the snapshot. Branches and tags have been moved along.
+    pub rewritten: BTreeMap<BlockHash, BlockHash>,
+    /// Old commits, trees and blocks deleted afterwards.
+    pub collected: CompactionResult,
+}
+
+impl std::fmt::Display for SquashResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Squashed {} commits into snapshot {}",
+            self.squashed,
+            &self.snapshot.id[..8.min(self.snapshot.id.len())]
+        )?;
+        writeln!(f, "Commits rewritten: {}", self.rewritten.len())?;
+        write!(f, "{}", self.collected)
+    }
+}
+
+/// Result of truncating history below a commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TruncateResult {
+    /// The kept commit, now a root.
+    pub root: Commit,
+    /// Branches that pointed at dropped commits and now point at the root.
+    pub moved_branches: Vec<String>,
+    /// Dropped commits, tags on them, and trees and blocks deleted
+    /// afterwards.
+    pub collected: CompactionResult,
+}
+
+impl std::fmt::Display for TruncateResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Truncated history below {}",
+            &self.root.id[..8.min(self.root.id.len())]
+        )?;
+        if !self.moved_branches.is_empty() {
+            writeln!(f, "Branches moved:  {}", self.moved_branches.join(", "))?;
+        }
+        write!(f, "{}", self.collected)
+    }
+}
+
+/// Result of purging a key from all history.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PurgeResult {
+    /// Old → new commit ids. Commits that only touched the purged key are
+    /// dropped and map to their (rewritten) parent. Branches and tags have
+    /// been moved along.
+    pub rewritten: BTreeMap<BlockHash, BlockHash>,
+    /// Number of commits dropped because they only touched the key.
+    pub dropped: usize,
+    /// Old commits, trees and blocks deleted afterwards.
+    pub collected: CompactionResult,
+}
+
+impl std::fmt::Display for PurgeResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Commits rewritten: {}", self.rewritten.len())?;
+        writeln!(f, "Commits dropped:   {}", self.dropped)?;
+        write!(f, "{}", self.collected)
+    }
+}
+
 impl std::fmt::Display for CompactionResult {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "Commits removed: {}", self.commits_removed)?;
         writeln!(f, "Trees removed:   {}", self.trees_removed)?;
         writeln!(f, "Blocks removed:  {}", self.blocks_removed)?;
+        if self.blobs_removed > 0 {
+            writeln!(f, "Blobs removed:   {}", self.blobs_removed)?;
+        }
+        if self.tags_removed > 0 {
+            writeln!(f, "Tags removed:    {}", self.tags_removed)?;
+        }
         writeln!(f, "Bytes reclaimed: {}", self.bytes_reclaimed)?;
         Ok(())
     }
@@ -96,6 +207,7 @@ mod tests {
         let policy = CompactionPolicy {
             max_versions: 2,
             max_age_days: None,
+            ..Default::default()
         };
         let removable = find_removable_commits(&commits, &policy, now);
         assert_eq!(removable, vec!["c", "d"]);
@@ -112,6 +224,7 @@ mod tests {
         let policy = CompactionPolicy {
             max_versions: 0,
             max_age_days: Some(7),
+            ..Default::default()
         };
         let removable = find_removable_commits(&commits, &policy, now);
         assert_eq!(removable, vec!["c"]);
@@ -128,6 +241,7 @@ mod tests {
         let policy = CompactionPolicy {
             max_versions: 5,
             max_age_days: Some(7),
+            ..Default::default()
         };
         let removable = find_removable_commits(&commits, &policy, now);
         assert_eq!(removable, vec!["c"]);
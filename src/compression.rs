@@ -1,4 +1,46 @@
 use lz4_flex::{compress_prepend_size, decompress_size_prepended};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
+
+/// Default zstd compression level (matches the zstd CLI default).
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// Compression codec applied to block payloads before they are written to disk.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// Store data as-is.
+    None,
+    /// Fast LZ4 block compression.
+    #[default]
+    Lz4,
+    /// zstd with a configurable level and optional trained dictionary.
+    Zstd,
+}
+
+impl std::str::FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Codec::None),
+            "lz4" => Ok(Codec::Lz4),
+            "zstd" => Ok(Codec::Zstd),
+            other => Err(format!("unknown codec: {}", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Codec::None => "none",
+            Codec::Lz4 => "lz4",
+            Codec::Zstd => "zstd",
+        };
+        f.write_str(name)
+    }
+}
 
 /// Compress data using LZ4.
 pub fn compress(data: &[u8]) -> Vec<u8> {
@@ -10,6 +52,36 @@ pub fn decompress(data: &[u8]) -> Result<Vec<u8>, lz4_flex::block::DecompressErr
     decompress_size_prepended(data)
 }
 
+/// Compress data using zstd at the given level, optionally with a dictionary.
+pub fn zstd_compress(data: &[u8], level: i32, dict: Option<&[u8]>) -> io::Result<Vec<u8>> {
+    match dict {
+        Some(dict) => zstd::bulk::Compressor::with_dictionary(level, dict)?.compress(data),
+        None => zstd::bulk::compress(data, level),
+    }
+}
+
+/// Decompress zstd data. The same dictionary used for compression must be supplied.
+pub fn zstd_decompress(data: &[u8], dict: Option<&[u8]>) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match dict {
+        Some(dict) => {
+            zstd::stream::read::Decoder::with_dictionary(data, dict)?.read_to_end(&mut out)?;
+        }
+        None => {
+            zstd::stream::read::Decoder::new(data)?.read_to_end(&mut out)?;
+        }
+    }
+    Ok(out)
+}
+
+/// Train a zstd dictionary from sample values.
+///
+/// Works best with many small, similar samples (e.g. JSON documents sharing a schema).
+/// Fails if there are too few samples for zstd to build a useful dictionary.
+pub fn train_dictionary<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> io::Result<Vec<u8>> {
+    zstd::dict::from_samples(samples, max_size)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -35,4 +107,43 @@ mod tests {
         let decompressed = decompress(&compressed).unwrap();
         assert!(decompressed.is_empty());
     }
+
+    #[test]
+    fn zstd_roundtrip_levels() {
+        let data: Vec<u8> = "abcdefgh".repeat(1000).into_bytes();
+        for level in [1, 3, 19] {
+            let compressed = zstd_compress(&data, level, None).unwrap();
+            assert!(compressed.len() < data.len());
+            assert_eq!(zstd_decompress(&compressed, None).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn zstd_dictionary_roundtrip() {
+        let samples: Vec<Vec<u8>> = (0..500)
+            .map(|i| {
+                format!(
+                    r#"{{"id":{},"name":"user{}","city":"Zurich","active":true}}"#,
+                    i, i
+                )
+                .into_bytes()
+            })
+            .collect();
+        let dict = train_dictionary(&samples, 4096).unwrap();
+        assert!(!dict.is_empty());
+
+        let value = br#"{"id":9999,"name":"user9999","city":"Zurich","active":true}"#;
+        let with_dict = zstd_compress(value, DEFAULT_ZSTD_LEVEL, Some(&dict)).unwrap();
+        let without = zstd_compress(value, DEFAULT_ZSTD_LEVEL, None).unwrap();
+        assert!(with_dict.len() < without.len());
+        assert_eq!(zstd_decompress(&with_dict, Some(&dict)).unwrap(), value);
+    }
+
+    #[test]
+    fn codec_parse_and_display() {
+        assert_eq!("zstd".parse::<Codec>().unwrap(), Codec::Zstd);
+        assert_eq!("LZ4".parse::<Codec>().unwrap(), Codec::Lz4);
+        assert!("gzip".parse::<Codec>().is_err());
+        assert_eq!(Codec::None.to_string(), "none");
+    }
 }
@@ -0,0 +1,293 @@
+use crate::backend::Backend;
+use crate::block::BlockHash;
+use crate::bloom::FilterKind;
+use crate::compaction::CompactionPolicy;
+use crate::compression::{Codec, DEFAULT_ZSTD_LEVEL};
+use crate::error::{IcebergError, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Name of the configuration file in the database root.
+pub const CONFIG_FILE: &str = "config.json";
+
+/// Default byte budget for each in-process cache.
+pub const DEFAULT_CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Default size below which values are stored inline in trees.
+pub const DEFAULT_INLINE_THRESHOLD: usize = 4096;
+
+/// Default size from which values are spilled to the blob store.
+pub const DEFAULT_BLOB_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Default number of writes between automatic bloom filter / index flushes.
+pub const DEFAULT_FLUSH_INTERVAL: usize = 1000;
+
+/// Default WAL size that triggers an automatic checkpoint.
+pub const DEFAULT_WAL_CHECKPOINT_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Default WAL record count that triggers an automatic checkpoint.
+pub const DEFAULT_WAL_CHECKPOINT_ENTRIES: usize = 10_000;
+
+/// Persistent database configuration.
+///
+/// Every section uses `#[serde(default)]` so config files written by older
+/// versions keep loading as new options are added.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct DbConfig {
+    /// How block payloads are compressed on disk.
+    pub compression: CompressionConfig,
+    /// Values smaller than this many bytes are stored inline in the tree;
+    /// larger values are stored as blocks and referenced by hash.
+    pub inline_threshold: usize,
+    /// Values of at least this many bytes are stored as raw objects in the
+    /// blob store and streamed in and out, rather than as blocks (0 = never).
+    pub blob_threshold: u64,
+    /// Memory-map block files on read instead of copying them into memory.
+    pub mmap_reads: bool,
+    /// In-process cache budgets.
+    pub cache: CacheConfig,
+    /// Persist the bloom filter and secondary indexes after this many writes
+    /// (0 = only on `flush`/`close`). Unflushed changes are rebuilt from the
+    /// WAL on the next open.
+    pub flush_interval: usize,
+    /// When the WAL is checkpointed during normal operation.
+    pub wal: WalConfig,
+    /// When compaction runs without being asked to.
+    pub auto_compaction: AutoCompactionConfig,
+    /// Kind of membership filter kept of the keys for fast negative
+    /// lookups. Changing it rebuilds the filters on the next open.
+    pub key_filter: FilterKind,
+    /// Keep a commit-graph file of every commit's parent, tree and
+    /// timestamp, loaded at open, so history walks need not read each
+    /// commit.
+    pub commit_graph: bool,
+    /// Limits on the keys under each prefix, checked by every put and
+    /// batch write.
+    pub quotas: BTreeMap<String, Quota>,
+    /// Limits every written key and value must respect.
+    pub limits: WriteLimits,
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        Self {
+            compression: CompressionConfig::default(),
+            inline_threshold: DEFAULT_INLINE_THRESHOLD,
+            blob_threshold: DEFAULT_BLOB_THRESHOLD,
+            mmap_reads: false,
+            cache: CacheConfig::default(),
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            wal: WalConfig::default(),
+            auto_compaction: AutoCompactionConfig::default(),
+            key_filter: FilterKind::default(),
+            commit_graph: false,
+            quotas: BTreeMap::new(),
+            limits: WriteLimits::default(),
+        }
+    }
+}
+
+/// Limits on each key and value written, checked by every put and batch
+/// write so malformed keys never reach a tree. Keys in namespaces are
+/// checked without their namespace. 0 disables a limit.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct WriteLimits {
+    /// Longest key allowed, in bytes.
+    pub max_key_len: usize,
+    /// Regular expression every key must match in full, such as
+    /// `[a-z0-9:_-]+`.
+    pub key_pattern: Option<String>,
+    /// Largest value allowed, in bytes.
+    pub max_value_size: u64,
+}
+
+impl WriteLimits {
+    /// `key_pattern` compiled to match whole keys.
+    pub fn compile_key_pattern(&self) -> Result<Option<Regex>> {
+        self.key_pattern
+            .as_ref()
+            .map(|pattern| {
+                Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| {
+                    IcebergError::InvalidQuery(format!("key pattern {:?}: {}", pattern, e))
+                })
+            })
+            .transpose()
+    }
+}
+
+/// Limits on the keys of a branch under one prefix, in the default
+/// namespace; keys of other namespaces are matched as qualified by
+/// `namespace::qualify`. 0 disables a limit.
+///
+/// A write that would take the prefix over a limit fails with
+/// `QuotaExceeded`; writes that shrink a prefix already over it, as after
+/// lowering a limit, still go through.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct Quota {
+    /// Most keys the prefix may hold.
+    pub max_keys: u64,
+    /// Most bytes the prefix's values may take, counted before compression.
+    pub max_bytes: u64,
+}
+
+/// Automatic compaction: once a threshold is crossed, the database compacts
+/// with `policy`, either right after the commit that crossed it (`inline`) or
+/// on the next pass of the background maintenance thread. 0 disables a
+/// threshold; without a policy nothing runs.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct AutoCompactionConfig {
+    /// Policy to compact with.
+    pub policy: Option<CompactionPolicy>,
+    /// Compact after this many commits since the last compaction. Commits
+    /// are counted in memory by each open database, from when it opened.
+    pub commits: usize,
+    /// Compact once block files take more than this many bytes.
+    /// Inline, this is only measured every `AUTO_COMPACTION_DISK_CHECK`
+    /// commits, since it means listing the whole store.
+    pub disk_bytes: u64,
+    /// Compact on the writing thread instead of the maintenance thread.
+    pub inline: bool,
+}
+
+/// Commits between disk usage checks of inline auto-compaction.
+pub const AUTO_COMPACTION_DISK_CHECK: usize = 100;
+
+impl AutoCompactionConfig {
+    /// Whether auto-compaction is configured at all.
+    pub fn is_enabled(&self) -> bool {
+        self.policy.is_some() && (self.commits > 0 || self.disk_bytes > 0)
+    }
+}
+
+/// Thresholds after which a write checkpoints the WAL: pending bloom filter
+/// and index changes are flushed and the log is truncated. 0 disables a
+/// threshold.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct WalConfig {
+    /// Checkpoint once the WAL file reaches this many bytes.
+    pub checkpoint_bytes: u64,
+    /// Checkpoint once the WAL holds this many records.
+    pub checkpoint_entries: usize,
+}
+
+impl Default for WalConfig {
+    fn default() -> Self {
+        Self {
+            checkpoint_bytes: DEFAULT_WAL_CHECKPOINT_BYTES,
+            checkpoint_entries: DEFAULT_WAL_CHECKPOINT_ENTRIES,
+        }
+    }
+}
+
+/// Byte budgets for the LRU caches in front of block and tree reads.
+/// A budget of 0 disables that cache.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Budget for decoded block data.
+    pub block_bytes: usize,
+    /// Budget for parsed trees.
+    pub tree_bytes: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            block_bytes: DEFAULT_CACHE_BYTES,
+            tree_bytes: DEFAULT_CACHE_BYTES,
+        }
+    }
+}
+
+/// Block compression settings.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// Codec used for newly written blocks.
+    pub codec: Codec,
+    /// zstd compression level (1–22); ignored for other codecs.
+    pub zstd_level: i32,
+    /// Id of the trained zstd dictionary to compress new blocks with, if any.
+    pub dictionary: Option<BlockHash>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            codec: Codec::default(),
+            zstd_level: DEFAULT_ZSTD_LEVEL,
+            dictionary: None,
+        }
+    }
+}
+
+impl DbConfig {
+    /// Load the config from a database's backend, falling back to defaults
+    /// if absent.
+    pub fn load(backend: &dyn Backend) -> Result<Self> {
+        match backend.read(CONFIG_FILE)? {
+            Some(data) => Ok(serde_json::from_slice(&data)?),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Persist the config into a database's backend.
+    pub fn save(&self, backend: &dyn Backend) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        backend.write(CONFIG_FILE, &data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Backend, FsBackend};
+
+    #[test]
+    fn missing_config_uses_defaults() {
+        let tmp = tempfile::tempdir().unwrap();
+        let backend = FsBackend::open(tmp.path()).unwrap();
+        let cfg = DbConfig::load(&backend).unwrap();
+        assert_eq!(cfg, DbConfig::default());
+        assert_eq!(cfg.compression.codec, Codec::Lz4);
+    }
+
+    #[test]
+    fn save_and_load_roundtrip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let backend = FsBackend::open(tmp.path()).unwrap();
+        let mut cfg = DbConfig::default();
+        cfg.compression.codec = Codec::Zstd;
+        cfg.compression.zstd_level = 19;
+        cfg.auto_compaction = AutoCompactionConfig {
+            policy: Some(CompactionPolicy {
+                max_versions: 10,
+                ..Default::default()
+            }),
+            commits: 100,
+            disk_bytes: 0,
+            inline: true,
+        };
+        cfg.save(&backend).unwrap();
+        assert_eq!(DbConfig::load(&backend).unwrap(), cfg);
+    }
+
+    #[test]
+    fn partial_config_fills_defaults() {
+        let tmp = tempfile::tempdir().unwrap();
+        let backend = FsBackend::open(tmp.path()).unwrap();
+        backend
+            .write(CONFIG_FILE, br#"{"compression":{"codec":"zstd"}}"#)
+            .unwrap();
+        let cfg = DbConfig::load(&backend).unwrap();
+        assert_eq!(cfg.compression.codec, Codec::Zstd);
+        assert_eq!(cfg.compression.zstd_level, DEFAULT_ZSTD_LEVEL);
+        assert_eq!(cfg.inline_threshold, DEFAULT_INLINE_THRESHOLD);
+    }
+}
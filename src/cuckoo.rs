@@ -0,0 +1,237 @@
+//! Cuckoo filter: a membership filter that, unlike a bloom filter, can
+//! remove keys, and takes less space at low false positive rates.
+
+use crate::bloom::{MembershipFilter, Reader};
+use crate::error::{IcebergError, Result};
+use sha2::{Digest, Sha256};
+
+/// Fingerprints per bucket.
+const BUCKET_SIZE: usize = 4;
+/// Evictions tried before an insert gives up on a full filter.
+const MAX_KICKS: usize = 500;
+/// Share of slots a filter is sized to fill, as inserts start failing
+/// well before every slot is taken.
+const TARGET_LOAD: f64 = 0.9;
+
+/// A cuckoo filter of 16-bit fingerprints in buckets of four.
+///
+/// Each key has two candidate buckets, the second derived from the first
+/// and the fingerprint, so a fingerprint can be moved ("kicked") to its
+/// other bucket to make room. Inserting a key twice stores it twice;
+/// removing it once leaves the other copy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CuckooFilter {
+    /// `BUCKET_SIZE` fingerprints per bucket; 0 marks an empty slot.
+    slots: Vec<u16>,
+    /// Number of fingerprints stored.
+    count: usize,
+}
+
+impl CuckooFilter {
+    /// An empty filter with room for about `capacity` keys.
+    pub fn new(capacity: usize) -> Self {
+        let buckets = (capacity.max(1) as f64 / (BUCKET_SIZE as f64 * TARGET_LOAD)).ceil() as usize;
+        Self {
+            slots: vec![0; buckets.next_power_of_two().max(2) * BUCKET_SIZE],
+            count: 0,
+        }
+    }
+
+    /// Number of keys the filter can hold at most.
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn num_buckets(&self) -> usize {
+        self.slots.len() / BUCKET_SIZE
+    }
+
+    /// A key's fingerprint and first bucket.
+    fn locate(&self, key: &[u8]) -> (u16, usize) {
+        let digest = Sha256::digest(key);
+        let hash = u64::from_le_bytes(digest[..8].try_into().unwrap());
+        let fingerprint = u16::from_le_bytes([digest[8], digest[9]]).max(1);
+        (fingerprint, hash as usize & (self.num_buckets() - 1))
+    }
+
+    /// The other bucket of a fingerprint in `bucket`; applying it twice
+    /// gives `bucket` back.
+    fn alternate(&self, bucket: usize, fingerprint: u16) -> usize {
+        let mix = u64::from(fingerprint).wrapping_mul(0x5bd1_e995_9e37_79b9);
+        (bucket ^ (mix >> 32) as usize) & (self.num_buckets() - 1)
+    }
+
+    fn bucket(&self, bucket: usize) -> &[u16] {
+        &self.slots[bucket * BUCKET_SIZE..(bucket + 1) * BUCKET_SIZE]
+    }
+
+    /// Put a fingerprint in a free slot of `bucket`, if it has one.
+    fn place(&mut self, bucket: usize, fingerprint: u16) -> bool {
+        let start = bucket * BUCKET_SIZE;
+        match self.slots[start..start + BUCKET_SIZE]
+            .iter()
+            .position(|&slot| slot == 0)
+        {
+            Some(free) => {
+                self.slots[start + free] = fingerprint;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Binary form (see `BloomSet::to_bytes`): slot count (LE u64), stored
+    /// count (LE u64), then each slot as a LE u16.
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.slots.len() as u64).to_le_bytes());
+        out.extend_from_slice(&(self.count as u64).to_le_bytes());
+        for slot in &self.slots {
+            out.extend_from_slice(&slot.to_le_bytes());
+        }
+    }
+
+    pub(crate) fn decode(reader: &mut Reader) -> Result<Self> {
+        let corrupt = |what: &str| IcebergError::Corruption(format!("cuckoo filter: {}", what));
+        let len = reader.u64()? as usize;
+        let count = reader.u64()? as usize;
+        let buckets = len / BUCKET_SIZE;
+        if !len.is_multiple_of(BUCKET_SIZE) || !buckets.is_power_of_two() {
+            return Err(corrupt("bad slot count"));
+        }
+        let slots: Vec<u16> = reader
+            .take(
+                len.checked_mul(2)
+                    .ok_or_else(|| corrupt("bad slot count"))?,
+            )?
+            .chunks_exact(2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        Ok(Self { slots, count })
+    }
+}
+
+impl MembershipFilter for CuckooFilter {
+    fn insert(&mut self, key: &[u8]) -> bool {
+        let (mut fingerprint, first) = self.locate(key);
+        let second = self.alternate(first, fingerprint);
+        if self.place(first, fingerprint) || self.place(second, fingerprint) {
+            self.count += 1;
+            return true;
+        }
+        let mut bucket = [first, second][usize::from(fingerprint) % 2];
+        let mut kicked = Vec::new();
+        for kick in 0..MAX_KICKS {
+            let at = bucket * BUCKET_SIZE + (usize::from(fingerprint) + kick) % BUCKET_SIZE;
+            std::mem::swap(&mut fingerprint, &mut self.slots[at]);
+            kicked.push(at);
+            bucket = self.alternate(bucket, fingerprint);
+            if self.place(bucket, fingerprint) {
+                self.count += 1;
+                return true;
+            }
+        }
+        // Full: undo the evictions so every stored fingerprint stays where
+        // lookups will find it.
+        for at in kicked.into_iter().rev() {
+            std::mem::swap(&mut fingerprint, &mut self.slots[at]);
+        }
+        false
+    }
+
+    fn may_contain(&self, key: &[u8]) -> bool {
+        let (fingerprint, first) = self.locate(key);
+        let second = self.alternate(first, fingerprint);
+        self.bucket(first).contains(&fingerprint) || self.bucket(second).contains(&fingerprint)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> bool {
+        let (fingerprint, first) = self.locate(key);
+        for bucket in [first, self.alternate(first, fingerprint)] {
+            let start = bucket * BUCKET_SIZE;
+            if let Some(at) = self.bucket(bucket).iter().position(|&s| s == fingerprint) {
+                self.slots[start + at] = 0;
+                self.count -= 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn count(&self) -> usize {
+        self.count
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.slots.len() * 2
+    }
+
+    fn estimated_fp_rate(&self) -> f64 {
+        // A lookup compares against the occupied slots of two buckets, each
+        // matching by chance once in 2^16 - 1 fingerprints.
+        let load = self.count as f64 / self.slots.len() as f64;
+        2.0 * BUCKET_SIZE as f64 * load / f64::from(u16::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserts_finds_and_removes_keys() {
+        let mut filter = CuckooFilter::new(1000);
+        let keys: Vec<String> = (0..1000).map(|i| format!("key_{}", i)).collect();
+        for key in &keys {
+            assert!(filter.insert(key.as_bytes()), "no room for {}", key);
+        }
+        assert_eq!(filter.count(), 1000);
+        for key in &keys {
+            assert!(
+                filter.may_contain(key.as_bytes()),
+                "false negative for {}",
+                key
+            );
+        }
+        let false_positives = (0..10_000)
+            .filter(|i| filter.may_contain(format!("other_{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 10, "{} false positives", false_positives);
+
+        for key in &keys[..500] {
+            assert!(filter.remove(key.as_bytes()));
+        }
+        assert_eq!(filter.count(), 500);
+        for key in &keys[500..] {
+            assert!(filter.may_contain(key.as_bytes()));
+        }
+        assert!(
+            keys[..500]
+                .iter()
+                .filter(|key| filter.may_contain(key.as_bytes()))
+                .count()
+                < 5
+        );
+    }
+
+    #[test]
+    fn full_filter_rejects_inserts_without_losing_keys() {
+        let mut filter = CuckooFilter::new(8);
+        let mut stored = Vec::new();
+        for i in 0.. {
+            let key = format!("k{}", i);
+            if !filter.insert(key.as_bytes()) {
+                break;
+            }
+            stored.push(key);
+        }
+        assert!(stored.len() >= 8 && stored.len() <= filter.capacity());
+        for key in &stored {
+            assert!(filter.may_contain(key.as_bytes()));
+        }
+        // The same key twice is stored twice.
+        let mut filter = CuckooFilter::new(8);
+        assert!(filter.insert(b"twice") && filter.insert(b"twice"));
+        assert!(filter.remove(b"twice"));
+        assert!(filter.may_contain(b"twice"));
+    }
+}
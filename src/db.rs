@@ -1,33 +1,134 @@
-use crate::block::Block;
-use crate::bloom::BloomFilter;
+use crate::backend::{self, Backend, FsBackend};
+use crate::batch::{BatchOp, WriteBatch};
+use crate::blob::BlobStore;
+use crate::block::{compute_hash, Block};
+use crate::bloom::{BloomFilter, BloomSet, FilterKind, KeyFilter, MembershipFilter};
+use crate::branch::Branch;
+use crate::cache::{CacheStats, LruCache};
 use crate::commit::Commit;
-use crate::compaction::{find_removable_commits, CompactionPolicy, CompactionResult};
+use crate::commitgraph::{CommitGraph, GraphEntry};
+use crate::compaction::{
+    find_removable_commits, CompactionPolicy, CompactionResult, PurgeResult, SquashResult,
+    TruncateResult,
+};
+use crate::compression;
+use crate::config::{
+    AutoCompactionConfig, CacheConfig, CompressionConfig, DbConfig, Quota, WriteLimits,
+    AUTO_COMPACTION_DISK_CHECK, CONFIG_FILE,
+};
 use crate::error::{IcebergError, Result};
-use crate::index::IndexManager;
-use crate::storage::BlockStore;
-use crate::tag::Tag;
-use crate::tree::{Tree, TreeDiff};
-use crate::wal::Wal;
+use crate::fsck::{FsckIssue, FsckReport, RepairAction, RepairReport};
+use crate::graph::{self, ChildIndex};
+use crate::grep::{GrepMatch, GrepOptions, Matcher};
+use crate::hyperloglog::{self, DistinctSet, HyperLogLog};
+use crate::index::{
+    IndexManager, IndexOptions, IndexPage, IndexStats, PageOptions, SecondaryIndex, INDEX_DIR,
+    LEGACY_INDEXES_FILE,
+};
+use crate::keylock::{KeyLock, KeyLocks};
+use crate::maintenance::{
+    MaintenanceOptions, MaintenanceReport, MaintenanceStatus, MaintenanceWorker,
+};
+use crate::namespace::{self, Namespace};
+use crate::pattern::KeyPattern;
+use crate::query::{Aggregate, Aggregator, Groups, Query, QueryPlan};
+use crate::snapshot::Snapshot;
+use crate::sql::{self, Rows};
+use crate::storage::{par_map, BlockStore, ValueRef, PARALLEL_MIN_BYTES};
+use crate::tag::{self, SigningKey, Tag, TagLogEntry, VerifyingKey};
+use crate::tenant::{self, Tenant};
+use crate::tree::{DiffStat, Tree, TreeDiff, TreeValue, ValueChange};
+use crate::vector::{self, VectorOptions, XorShift};
+use crate::wal::{Wal, WalEntry, WalRecovery};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::fs;
-use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
 
-const REFS_DIR: &str = "refs";
+const REFS_FILE: &str = "refs/refs.json";
 const TREES_DIR: &str = "trees";
 const COMMITS_DIR: &str = "commits";
+/// Values over `DbConfig::blob_threshold`, as raw `BlobStore` objects.
+const BLOBS_DIR: &str = "blobs";
 const TAGS_DIR: &str = "tags";
-const BLOOM_DIR: &str = "bloom";
-const INDEXES_FILE: &str = "indexes.json";
+/// Every change to a tag, one `TagLogEntry` as JSON per line.
+const TAG_LOG_FILE: &str = "logs/tags.jsonl";
+/// The bloom filters of every namespace, in `BloomSet`'s binary form.
+const BLOOM_FILE: &str = "bloom/filters.bin";
+/// The default namespace's bloom filter as JSON, from before `BLOOM_FILE`.
+const LEGACY_BLOOM_FILE: &str = "bloom/keys.json";
+/// The other namespaces' bloom filters as JSON, by name, from before
+/// `BLOOM_FILE`.
+const LEGACY_NAMESPACE_BLOOM_FILE: &str = "bloom/namespaces.json";
+/// A bloom filter of each tree's keys, named by the tree's root hash, so
+/// historical lookups can skip trees that cannot hold a key.
+const TREE_BLOOMS_DIR: &str = "bloom/trees";
+/// The parent, tree and timestamp of every commit, in `CommitGraph`'s
+/// binary form, while `DbConfig::commit_graph` is on.
+const COMMIT_GRAPH_FILE: &str = "commit-graph";
+/// The advisory locks taken with `lock_key`, as JSON.
+const KEY_LOCKS_FILE: &str = "locks/keys.json";
+/// HyperLogLog sketches of the specs tracked by `track_distinct`.
+const DISTINCT_FILE: &str = "bloom/distinct.json";
+/// Items processed between progress reports of bulk operations.
+const PROGRESS_STEP: usize = 1000;
+/// Where `repair` moves corrupt objects, under their original keys.
+const CORRUPT_DIR: &str = "corrupt";
+/// Largest parent/child difference `repair` searches for a lost tree.
+const REGENERATE_MAX_CHANGES: usize = 1024;
+
+/// Chunk size used when streaming values in and out of the block store.
+pub const STREAM_CHUNK_SIZE: usize = 1 << 20;
+
+/// Shortest commit id prefix `resolve_ref` accepts, so short words aren't
+/// mistaken for ids.
+pub const MIN_ID_PREFIX: usize = 4;
 
 /// The main database: versioned, branching, immutable key-value store.
 pub struct Database {
-    root: PathBuf,
+    backend: Arc<dyn Backend>,
     store: BlockStore,
+    blobs: BlobStore,
+    config: Mutex<DbConfig>,
+    /// Trees are shared rather than copied out of the cache: callers that
+    /// change one clone it first.
+    tree_cache: Mutex<LruCache<String, Arc<Tree>>>,
+    head: RwLock<HeadCache>,
+    /// Serializes writers: held across the whole read-modify-write of refs
+    /// (and of the derived bloom filter / indexes) so concurrent writes never
+    /// build on the same parent. Readers never take it.
+    writer: Mutex<()>,
+    dirty: Mutex<DirtyState>,
+    /// Commits made since this handle last compacted, for auto-compaction.
+    commits_since_compaction: AtomicUsize,
+    maintenance: Mutex<Option<MaintenanceWorker>>,
     wal: Mutex<Wal>,
-    bloom: Mutex<BloomFilter>,
-    indexes: Mutex<IndexManager>,
+    /// The key filters, distinct counts and secondary indexes are read on
+    /// first use rather than at open, as many commands never touch them.
+    bloom: OnceLock<Mutex<BloomSet>>,
+    distinct: OnceLock<Mutex<DistinctSet>>,
+    indexes: OnceLock<Mutex<IndexManager>>,
+    /// Set once the refs are found changed by another handle or process:
+    /// the key filters may lack the keys it wrote, so `get` stops trusting
+    /// them to rule keys out until they are rebuilt.
+    foreign_refs: AtomicBool,
+    /// Trees held by live `Snapshot`s, by root hash, with how many hold
+    /// each: compaction keeps them as if a ref reached them.
+    pins: Mutex<HashMap<String, usize>>,
+    /// Blocks and blobs stored by streaming puts that have not committed
+    /// yet, with how many hold each: compaction keeps them. See `InFlight`.
+    in_flight: Mutex<HashMap<String, usize>>,
+    /// Children of every stored commit, built on first use and then kept
+    /// up to date as commits are saved and deleted.
+    children: Mutex<Option<ChildIndex>>,
+    /// Every stored commit's links while `DbConfig::commit_graph` is on,
+    /// kept up to date like `children`.
+    commit_graph: RwLock<Option<CommitGraph>>,
+    /// `WriteLimits::key_pattern` of the config, compiled.
+    key_pattern: RwLock<Option<Regex>>,
 }
 
 /// Persistent refs: branches and current HEAD.
@@ -37,1260 +138,9682 @@ struct Refs {
     branches: HashMap<String, String>,
     /// Current branch name
     head: String,
+    /// Maps tenant → its checked-out branch, named within the tenant
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    tenant_heads: HashMap<String, String>,
+    /// Set while HEAD is detached at a tag; `head` keeps the branch that
+    /// was checked out before.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    detached: Option<DetachedHead>,
 }
 
-impl Database {
-    /// Open or create a database at the given path.
-    pub fn open(path: &Path) -> Result<Self> {
-        fs::create_dir_all(path)?;
-        let store = BlockStore::open(&path.join("store"))?;
-        fs::create_dir_all(path.join(TREES_DIR))?;
-        fs::create_dir_all(path.join(COMMITS_DIR))?;
-        fs::create_dir_all(path.join(REFS_DIR))?;
-        fs::create_dir_all(path.join(TAGS_DIR))?;
-        fs::create_dir_all(path.join(BLOOM_DIR))?;
-        let wal = Wal::open(&path.join("wal"))?;
-        let bloom = Self::load_bloom_from(path);
-        let indexes = Self::load_indexes_from(path);
-        let db = Self {
-            root: path.to_path_buf(),
-            store,
-            wal: Mutex::new(wal),
-            bloom: Mutex::new(bloom),
-            indexes: Mutex::new(indexes),
-        };
-        db.recover_wal()?;
-        Ok(db)
-    }
-
-    /// Initialize a new database (creates the "main" branch).
-    pub fn init(path: &Path) -> Result<Self> {
-        let db = Self::open(path)?;
-        if !db.refs_path().exists() {
-            let refs = Refs {
-                branches: HashMap::new(),
-                head: "main".into(),
-            };
-            db.save_refs(&refs)?;
-        }
-        Ok(db)
-    }
+/// Where a detached HEAD stands: the tag checked out and the commit it
+/// pointed at then, which HEAD keeps even if the tag moves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DetachedHead {
+    tag: String,
+    commit: String,
+}
 
-    /// Recover from WAL after crash.
-    fn recover_wal(&self) -> Result<()> {
-        let mut wal = self.wal.lock().unwrap();
-        let recovery = wal.recover()?;
-        if !recovery.uncommitted.is_empty() {
-            // Uncommitted transactions are simply ignored (rolled back).
-            // The WAL is truncated after recovery.
+impl Refs {
+    fn new() -> Self {
+        Self {
+            branches: HashMap::new(),
+            head: DEFAULT_BRANCH.into(),
+            tenant_heads: HashMap::new(),
+            detached: None,
         }
-        wal.truncate()?;
-        Ok(())
     }
 
-    fn load_bloom_from(path: &Path) -> BloomFilter {
-        let bloom_path = path.join(BLOOM_DIR).join("keys.json");
-        if bloom_path.exists() {
-            if let Ok(data) = fs::read(&bloom_path) {
-                if let Ok(bf) = serde_json::from_slice(&data) {
-                    return bf;
-                }
+    /// Full name of the branch checked out by `tenant`, or globally for
+    /// `None`.
+    fn head_of(&self, tenant: Option<&str>) -> String {
+        match tenant {
+            None => self.head.clone(),
+            Some(t) => {
+                let branch = self.tenant_heads.get(t).map(String::as_str);
+                tenant::qualify(t, branch.unwrap_or(tenant::DEFAULT_BRANCH))
             }
         }
-        BloomFilter::new(10000, 0.01)
     }
 
-    fn save_bloom(&self) -> Result<()> {
-        let bloom = self.bloom.lock().unwrap();
-        let path = self.root.join(BLOOM_DIR).join("keys.json");
-        let data = serde_json::to_vec(&*bloom)?;
-        fs::write(path, data)?;
-        Ok(())
+    /// Fail with `Conflict` unless `branch` points at `expected`, if given.
+    /// Writers check under the writer lock, so the branch cannot move
+    /// between the check and their commit.
+    fn check_head(&self, branch: &str, expected: Option<&str>) -> Result<()> {
+        let Some(expected) = expected else {
+            return Ok(());
+        };
+        let actual = self.branches.get(branch).map(String::as_str);
+        if actual == Some(expected) {
+            return Ok(());
+        }
+        Err(IcebergError::Conflict {
+            branch: branch.to_string(),
+            expected: expected.to_string(),
+            actual: actual.unwrap_or("no commits").to_string(),
+        })
     }
 
-    fn load_indexes_from(path: &Path) -> IndexManager {
-        let idx_path = path.join(INDEXES_FILE);
-        if idx_path.exists() {
-            if let Ok(data) = fs::read(&idx_path) {
-                if let Ok(mgr) = serde_json::from_slice(&data) {
-                    return mgr;
-                }
-            }
+    /// Full name of the branch that reads and writes through `target` use,
+    /// HEAD's detached commit aside.
+    fn branch_of(&self, target: Target) -> String {
+        match target {
+            Target::Head => self.head.clone(),
+            Target::Tenant(t) => self.head_of(Some(t)),
+            Target::Branch(branch) => branch.to_string(),
         }
-        IndexManager::new()
     }
 
-    fn save_indexes(&self) -> Result<()> {
-        let indexes = self.indexes.lock().unwrap();
-        let path = self.root.join(INDEXES_FILE);
-        let data = serde_json::to_vec_pretty(&*indexes)?;
-        fs::write(path, data)?;
-        Ok(())
+    /// Whether `branch` is the one checked out, whose keys the secondary
+    /// indexes and distinct counts follow.
+    fn is_checked_out(&self, branch: &str) -> bool {
+        self.detached.is_none() && self.head == branch
     }
 
-    // ── Key-Value API ─────────────────────────────────────────
-
-    /// Get a value by key from the current branch HEAD.
-    /// Uses bloom filter for fast negative lookups.
-    pub fn get(&self, key: &str) -> Result<Vec<u8>> {
-        // Fast path: bloom filter says definitely not present
-        {
-            let bloom = self.bloom.lock().unwrap();
-            if bloom.count() > 0 && !bloom.may_contain(key.as_bytes()) {
-                return Err(IcebergError::KeyNotFound(key.into()));
-            }
+    /// `branch_of` for a commit through `target`. Fails while HEAD is
+    /// detached, as there is no branch for the commit to go on.
+    fn write_branch(&self, target: Target) -> Result<String> {
+        match (target, &self.detached) {
+            (Target::Head, Some(detached)) => Err(IcebergError::DetachedHead(detached.tag.clone())),
+            _ => Ok(self.branch_of(target)),
         }
-        let tree = self.current_tree()?;
-        tree.get(key)
-            .cloned()
-            .ok_or_else(|| IcebergError::KeyNotFound(key.into()))
     }
 
-    /// Put a key-value pair; creates a new commit on the current branch.
-    /// Writes are WAL-protected for crash safety.
-    pub fn put(&self, key: &str, value: Vec<u8>, message: Option<&str>) -> Result<Commit> {
-        // WAL: begin transaction
-        let tx_id = {
-            let mut wal = self.wal.lock().unwrap();
-            let tx = wal.begin()?;
-            wal.log_write(tx, key.into(), value.clone())?;
-            tx
-        };
+    /// The commit reads through `target` see, if it has one.
+    fn tip_of(&self, target: Target) -> Option<&String> {
+        match (target, &self.detached) {
+            (Target::Head, Some(detached)) => Some(&detached.commit),
+            _ => self.branches.get(&self.branch_of(target)),
+        }
+    }
+}
 
-        let tree = self.current_tree().unwrap_or_else(|_| Tree::empty());
-        let new_tree = tree.insert(key.into(), value.clone());
-        let msg = message
-            .map(String::from)
-            .unwrap_or_else(|| format!("put {}", key));
-        let commit = self.commit_tree(&new_tree, &msg)?;
+/// Where a read or write goes: the checked-out branch, a tenant's HEAD, or
+/// a named branch regardless of what is checked out.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Target<'a> {
+    Head,
+    Tenant(&'a str),
+    Branch(&'a str),
+}
 
-        // WAL: commit transaction
-        {
-            let mut wal = self.wal.lock().unwrap();
-            wal.commit(tx_id, commit.id.clone())?;
-        }
+/// The branch a new database starts on.
+pub const DEFAULT_BRANCH: &str = "main";
+
+/// In-memory copy of the refs and the current HEAD commit with its tree.
+///
+/// Every ref update of this handle goes through `save_refs`, which keeps
+/// `refs` in sync. Other handles and processes write the refs file too, so
+/// `refs` is kept with the file's `Backend::version` and only used while
+/// that still matches. The HEAD entry is keyed by commit id, so moving a
+/// branch or checking out another one simply misses and reloads.
+#[derive(Default)]
+struct HeadCache {
+    refs: Option<(u64, Refs)>,
+    head: Option<(Commit, Arc<Tree>)>,
+}
 
-        // Update bloom filter
-        {
-            let mut bloom = self.bloom.lock().unwrap();
-            bloom.insert(key.as_bytes());
-        }
-        self.save_bloom()?;
+/// The blocks and blobs one streaming put has stored so far. They are
+/// stored before the put takes the writer lock, so until its commit reaches
+/// a branch no tree refers to them; holding them here keeps a concurrent
+/// `sweep` off them. Released on drop.
+struct InFlight<'a> {
+    db: &'a Database,
+    held: Vec<String>,
+}
 
-        // Update secondary indexes
-        {
-            let mut indexes = self.indexes.lock().unwrap();
-            indexes.on_put(key, &value);
+impl<'a> InFlight<'a> {
+    fn new(db: &'a Database) -> Self {
+        Self {
+            db,
+            held: Vec::new(),
         }
-        self.save_indexes()?;
+    }
 
-        Ok(commit)
+    /// Hold `hash`; call before storing it, as a sweep already under way
+    /// may otherwise delete it right after.
+    fn hold(&mut self, hash: &str) {
+        *self
+            .db
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(hash.to_string())
+            .or_default() += 1;
+        self.held.push(hash.to_string());
     }
+}
 
-    /// Delete a key; creates a new commit.
-    /// Writes are WAL-protected for crash safety.
-    pub fn delete(&self, key: &str, message: Option<&str>) -> Result<Commit> {
-        let tree = self.current_tree()?;
-        if !tree.contains_key(key) {
-            return Err(IcebergError::KeyNotFound(key.into()));
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.db.in_flight.lock().unwrap();
+        for hash in &self.held {
+            if let Some(count) = in_flight.get_mut(hash) {
+                *count -= 1;
+                if *count == 0 {
+                    in_flight.remove(hash);
+                }
+            }
         }
+    }
+}
 
-        // WAL: begin transaction
-        let tx_id = {
-            let mut wal = self.wal.lock().unwrap();
-            let tx = wal.begin()?;
-            wal.log_delete(tx, key.into())?;
-            tx
-        };
-
-        let new_tree = tree.delete(key);
-        let msg = message
-            .map(String::from)
-            .unwrap_or_else(|| format!("delete {}", key));
-        let commit = self.commit_tree(&new_tree, &msg)?;
+/// Derived structures with changes not yet written to disk, and the number
+/// of writes since the last flush.
+#[derive(Debug, Default)]
+struct DirtyState {
+    bloom: bool,
+    indexes: bool,
+    commit_graph: bool,
+    writes: usize,
+}
 
-        // WAL: commit
-        {
-            let mut wal = self.wal.lock().unwrap();
-            wal.commit(tx_id, commit.id.clone())?;
-        }
+impl Database {
+    /// Open or create a database at the given path.
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::open_with_backend(Arc::new(FsBackend::open(path)?))
+    }
 
-        // Update secondary indexes
-        {
-            let mut indexes = self.indexes.lock().unwrap();
-            indexes.on_delete(key);
+    /// Open or create a database stored in `backend`.
+    pub fn open_with_backend(backend: Arc<dyn Backend>) -> Result<Self> {
+        let config = DbConfig::load(backend.as_ref())?;
+        let store = BlockStore::with_backend(Arc::clone(&backend), "store");
+        let blobs = BlobStore::with_backend(Arc::clone(&backend), BLOBS_DIR);
+        store.set_compression(config.compression.clone());
+        store.set_mmap(config.mmap_reads);
+        store.set_cache_capacity(config.cache.block_bytes);
+        let wal = Wal::with_backend(Arc::clone(&backend), "wal")?;
+        let key_pattern = config.limits.compile_key_pattern()?;
+        let db = Self {
+            backend,
+            store,
+            blobs,
+            tree_cache: Mutex::new(LruCache::new(config.cache.tree_bytes)),
+            config: Mutex::new(config),
+            head: RwLock::new(HeadCache::default()),
+            writer: Mutex::new(()),
+            dirty: Mutex::new(DirtyState::default()),
+            commits_since_compaction: AtomicUsize::new(0),
+            maintenance: Mutex::new(None),
+            wal: Mutex::new(wal),
+            bloom: OnceLock::new(),
+            distinct: OnceLock::new(),
+            indexes: OnceLock::new(),
+            foreign_refs: AtomicBool::new(false),
+            pins: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+            children: Mutex::new(None),
+            commit_graph: RwLock::new(None),
+            key_pattern: RwLock::new(key_pattern),
+        };
+        if db.config().commit_graph {
+            db.load_commit_graph()?;
         }
-        self.save_indexes()?;
+        db.recover_wal()?;
+        // Cache the refs now, so that later changes by others are noticed.
+        db.load_refs()?;
+        Ok(db)
+    }
 
-        Ok(commit)
+    /// Open a database and start a background thread that periodically
+    /// flushes the bloom filter and indexes, checkpoints the WAL and
+    /// optionally compacts, keeping that work off the write path.
+    ///
+    /// The thread stops when the returned database is dropped.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn open_with_maintenance(path: &Path, options: MaintenanceOptions) -> Result<Arc<Self>> {
+        let db = Arc::new(Self::open(path)?);
+        let worker = MaintenanceWorker::spawn(Arc::downgrade(&db), options);
+        *db.maintenance.lock().unwrap() = Some(worker);
+        Ok(db)
     }
 
-    /// Scan keys by prefix.
-    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
-        let tree = self.current_tree()?;
-        Ok(tree
-            .scan_prefix(prefix)
-            .into_iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect())
+    /// Initialize a new database (creates the "main" branch).
+    pub fn init(path: &Path) -> Result<Self> {
+        Self::init_with_backend(Arc::new(FsBackend::open(path)?))
     }
 
-    /// Range scan.
-    pub fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>> {
-        let tree = self.current_tree()?;
-        Ok(tree
-            .range(start, end)
-            .into_iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect())
+    /// Initialize a new database stored in `backend`.
+    pub fn init_with_backend(backend: Arc<dyn Backend>) -> Result<Self> {
+        let db = Self::open_with_backend(backend)?;
+        if !db.backend.exists(REFS_FILE)? {
+            db.save_refs(&Refs::new())?;
+        }
+        Ok(db)
     }
 
-    // ── Version History ───────────────────────────────────────
+    /// Initialize a new database with the given configuration.
+    /// The config is persisted and used by every later `open`.
+    pub fn init_with_config(path: &Path, config: DbConfig) -> Result<Self> {
+        let db = Self::init(path)?;
+        db.update_config(|c| *c = config)?;
+        db.match_key_filter()?;
+        Ok(db)
+    }
 
-    /// Get the current branch's HEAD commit.
-    pub fn head_commit(&self) -> Result<Commit> {
-        let refs = self.load_refs()?;
-        let commit_id = refs
-            .branches
-            .get(&refs.head)
-            .ok_or(IcebergError::EmptyDatabase)?;
-        self.load_commit(commit_id)
+    /// Current database configuration.
+    pub fn config(&self) -> DbConfig {
+        self.config.lock().unwrap().clone()
     }
 
-    /// Get the full commit log for the current branch (newest first).
-    pub fn log(&self) -> Result<Vec<Commit>> {
-        let mut commits = Vec::new();
-        let head = match self.head_commit() {
-            Ok(c) => c,
-            Err(IcebergError::EmptyDatabase) => return Ok(commits),
-            Err(e) => return Err(e),
-        };
-        let mut current = Some(head);
-        while let Some(commit) = current {
-            let parent_id = commit.parent.clone();
-            commits.push(commit);
-            current = match parent_id {
-                Some(id) => Some(self.load_commit(&id)?),
-                None => None,
-            };
-        }
-        Ok(commits)
+    /// Change the size below which values are stored inline in trees.
+    /// Only affects values written from now on.
+    pub fn set_inline_threshold(&self, threshold: usize) -> Result<()> {
+        self.update_config(|c| c.inline_threshold = threshold)
     }
 
-    /// Get a tree at a specific commit.
-    pub fn tree_at(&self, commit_id: &str) -> Result<Tree> {
-        let commit = self.load_commit(commit_id)?;
-        self.load_tree(&commit.tree_root)
+    /// Change the size from which values are spilled to the blob store (0
+    /// never spills). Only affects values written from now on.
+    pub fn set_blob_threshold(&self, threshold: u64) -> Result<()> {
+        self.update_config(|c| c.blob_threshold = threshold)
     }
 
-    /// Get a value at a specific version.
-    pub fn get_at(&self, key: &str, commit_id: &str) -> Result<Vec<u8>> {
-        let tree = self.tree_at(commit_id)?;
-        tree.get(key)
-            .cloned()
-            .ok_or_else(|| IcebergError::KeyNotFound(key.into()))
+    /// Enable or disable memory-mapped block reads for `get_ref`.
+    pub fn set_mmap_reads(&self, enabled: bool) -> Result<()> {
+        self.update_config(|c| c.mmap_reads = enabled)
     }
 
-    /// Diff between two commits.
-    pub fn diff(&self, commit_a: &str, commit_b: &str) -> Result<TreeDiff> {
-        let tree_a = self.tree_at(commit_a)?;
-        let tree_b = self.tree_at(commit_b)?;
-        Ok(tree_a.diff(&tree_b))
+    /// Change the byte budgets of the block and tree caches.
+    pub fn set_cache_config(&self, cache: CacheConfig) -> Result<()> {
+        self.update_config(|c| c.cache = cache)
     }
 
-    // ── Branching ─────────────────────────────────────────────
+    /// Change the compression used for newly written blocks.
+    pub fn set_compression(&self, compression: CompressionConfig) -> Result<()> {
+        self.update_config(|c| c.compression = compression)
+    }
 
-    /// Get the current branch name.
-    pub fn current_branch(&self) -> Result<String> {
-        Ok(self.load_refs()?.head)
+    /// Switch the membership filter kept of the keys, rebuilding it from
+    /// every branch head.
+    pub fn set_key_filter(&self, kind: FilterKind) -> Result<()> {
+        self.update_config(|c| c.key_filter = kind)?;
+        self.match_key_filter()
     }
 
-    /// List all branches.
-    pub fn branches(&self) -> Result<Vec<String>> {
-        let refs = self.load_refs()?;
-        let mut names: Vec<_> = refs.branches.keys().cloned().collect();
-        names.sort();
-        // Include head branch even if no commits
-        if !names.contains(&refs.head) {
-            names.push(refs.head);
-            names.sort();
+    /// Rebuild the key filters if they are not of the configured kind.
+    fn match_key_filter(&self) -> Result<()> {
+        if self.bloom().lock().unwrap().kind() != self.config().key_filter {
+            self.rebuild_bloom()?;
         }
-        Ok(names)
+        Ok(())
     }
 
-    /// Create a new branch from the current HEAD.
-    pub fn create_branch(&self, name: &str) -> Result<()> {
-        let mut refs = self.load_refs()?;
-        if refs.branches.contains_key(name) {
-            return Err(IcebergError::BranchExists(name.into()));
-        }
-        if let Some(head_id) = refs.branches.get(&refs.head).cloned() {
-            refs.branches.insert(name.into(), head_id);
+    /// Turn the commit-graph file on or off. Turning it on builds it from
+    /// every stored commit; turning it off deletes it.
+    pub fn set_commit_graph(&self, enabled: bool) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        self.update_config(|c| c.commit_graph = enabled)?;
+        if enabled {
+            self.load_commit_graph()?;
+            self.save_commit_graph()
+        } else {
+            *self.commit_graph.write().unwrap() = None;
+            self.dirty.lock().unwrap().commit_graph = false;
+            self.backend.delete(COMMIT_GRAPH_FILE)
         }
-        // If no commits yet, branch will be created on first commit
-        self.save_refs(&refs)
     }
 
-    /// Switch to a branch.
-    pub fn checkout(&self, name: &str) -> Result<()> {
-        let mut refs = self.load_refs()?;
-        // Allow checkout even if branch has no commits yet
-        let exists = refs.branches.contains_key(name)
-            || refs.head == name
-            || self
-                .branches()
-                .map(|b| b.contains(&name.to_string()))
-                .unwrap_or(false);
-        if !exists {
-            return Err(IcebergError::BranchNotFound(name.into()));
-        }
-        refs.head = name.into();
-        self.save_refs(&refs)
+    /// Number of commits in the commit graph, or `None` while it is off.
+    pub fn commit_graph_len(&self) -> Option<usize> {
+        self.commit_graph.read().unwrap().as_ref().map(|g| g.len())
     }
 
-    /// Delete a branch (cannot delete current branch).
-    pub fn delete_branch(&self, name: &str) -> Result<()> {
-        let mut refs = self.load_refs()?;
-        if refs.head == name {
-            return Err(IcebergError::Corruption(
-                "cannot delete current branch".into(),
-            ));
+    /// Load the commit-graph file and bring it in line with the stored
+    /// commits: commits written since it was saved are read and added,
+    /// deleted ones dropped. Without a readable file every commit is read.
+    fn load_commit_graph(&self) -> Result<()> {
+        let mut graph = self
+            .backend
+            .read(COMMIT_GRAPH_FILE)
+            .ok()
+            .flatten()
+            .and_then(|data| CommitGraph::from_bytes(&data).ok())
+            .unwrap_or_default();
+        let ids: HashSet<String> = self.backend.list(COMMITS_DIR)?.into_iter().collect();
+        let stale: Vec<String> = graph
+            .ids()
+            .filter(|id| !ids.contains(*id))
+            .cloned()
+            .collect();
+        let mut changed = !stale.is_empty();
+        for id in stale {
+            graph.remove(&id);
         }
-        if refs.branches.remove(name).is_none() {
-            return Err(IcebergError::BranchNotFound(name.into()));
+        let missing: Vec<&String> = ids.iter().filter(|id| !graph.contains(id)).collect();
+        for id in missing {
+            // Unreadable commits are for fsck to report.
+            if let Ok(commit) = self.load_commit(id) {
+                graph.insert(&commit);
+                changed = true;
+            }
         }
-        self.save_refs(&refs)
+        *self.commit_graph.write().unwrap() = Some(graph);
+        self.dirty.lock().unwrap().commit_graph |= changed;
+        Ok(())
     }
 
-    /// Merge another branch into the current branch (fast-forward or snapshot merge).
-    pub fn merge(&self, source_branch: &str, message: Option<&str>) -> Result<Commit> {
-        let refs = self.load_refs()?;
-        let source_id = refs
-            .branches
-            .get(source_branch)
-            .ok_or_else(|| IcebergError::BranchNotFound(source_branch.into()))?
-            .clone();
+    fn save_commit_graph(&self) -> Result<()> {
+        let data = match self.commit_graph.write().unwrap().as_mut() {
+            Some(graph) => {
+                graph.refresh_generations();
+                graph.to_bytes()
+            }
+            None => return Ok(()),
+        };
+        self.backend.write(COMMIT_GRAPH_FILE, &data)
+    }
 
-        let source_tree = self
-            .load_commit(&source_id)
-            .and_then(|c| self.load_tree(&c.tree_root))?;
-        let current_tree = self.current_tree().unwrap_or_else(|_| Tree::empty());
+    /// Set the quota on keys starting with `prefix`, replacing any it had.
+    /// Existing keys are not checked: a prefix already over the new limit
+    /// can only shrink until it is back under.
+    pub fn set_quota(&self, prefix: &str, quota: Quota) -> Result<()> {
+        self.update_config(|c| {
+            c.quotas.insert(prefix.to_string(), quota);
+        })
+    }
 
-        // Simple merge: apply all entries from source on top of current
-        let mut merged = current_tree.entries.clone();
-        for (k, v) in &source_tree.entries {
-            merged.insert(k.clone(), v.clone());
-        }
+    /// Drop the quota on `prefix`, returning whether it had one.
+    pub fn remove_quota(&self, prefix: &str) -> Result<bool> {
+        let mut removed = false;
+        self.update_config(|c| removed = c.quotas.remove(prefix).is_some())?;
+        Ok(removed)
+    }
 
-        let merged_tree = Tree {
-            root_hash: {
-                let serialized = serde_json::to_vec(&merged).unwrap_or_default();
-                crate::block::compute_hash(&serialized)
-            },
-            entries: merged,
+    /// Every quota with its usage on the current branch.
+    pub fn quotas(&self) -> Result<Vec<QuotaUsage>> {
+        let tree = match self.current_tree() {
+            Ok(tree) => tree,
+            Err(IcebergError::EmptyDatabase) => Arc::new(Tree::empty()),
+            Err(e) => return Err(e),
         };
-
-        let msg = message
-            .map(String::from)
-            .unwrap_or_else(|| format!("merge branch '{}'", source_branch));
-        self.commit_tree(&merged_tree, &msg)
+        Ok(self
+            .config()
+            .quotas
+            .into_iter()
+            .map(|(prefix, quota)| {
+                let (keys, bytes) = tree.prefix_usage(&prefix);
+                QuotaUsage {
+                    prefix,
+                    quota,
+                    keys,
+                    bytes,
+                }
+            })
+            .collect())
     }
 
-    // ── Tags ──────────────────────────────────────────────────
+    /// Replace the limits on written keys and values. Keys already stored
+    /// are not checked. Fails on a key pattern that is not a valid regular
+    /// expression.
+    pub fn set_write_limits(&self, limits: WriteLimits) -> Result<()> {
+        self.update_config(|c| c.limits = limits)
+    }
 
-    /// Create a tag pointing to a specific commit (or current HEAD).
-    pub fn create_tag(
-        &self,
-        name: &str,
-        commit_id: Option<&str>,
-        message: Option<&str>,
-    ) -> Result<Tag> {
-        // Check if tag name already exists
-        if self.load_tag_by_name(name)?.is_some() {
-            return Err(IcebergError::Corruption(format!(
-                "tag already exists: {}",
-                name
+    /// Fail if a put of `size` bytes under `key`, as stored in trees,
+    /// breaks `DbConfig::limits`. Namespaced keys are checked without
+    /// their namespace.
+    fn check_limits(&self, key: &str, size: u64) -> Result<()> {
+        let limits = self.config.lock().unwrap().limits.clone();
+        let plain = namespace::split(key).1;
+        if limits.max_key_len > 0 && plain.len() > limits.max_key_len {
+            return Err(IcebergError::InvalidKey(format!(
+                "{:?} is {} bytes long, over the limit of {}",
+                plain,
+                plain.len(),
+                limits.max_key_len
             )));
         }
-        let cid = match commit_id {
-            Some(id) => {
-                // Verify commit exists
-                self.load_commit(id)?;
-                id.to_string()
+        if let Some(pattern) = self.key_pattern.read().unwrap().as_ref() {
+            if !pattern.is_match(plain) {
+                return Err(IcebergError::InvalidKey(format!(
+                    "{:?} does not match the key pattern {:?}",
+                    plain,
+                    limits.key_pattern.unwrap_or_default()
+                )));
             }
-            None => self.head_commit()?.id,
-        };
-        let tag = Tag::new(name.into(), cid, message.map(String::from));
-        self.save_tag(&tag)?;
-        Ok(tag)
+        }
+        if limits.max_value_size > 0 && size > limits.max_value_size {
+            return Err(IcebergError::ValueTooLarge {
+                key: plain.to_string(),
+                size,
+                limit: limits.max_value_size,
+            });
+        }
+        Ok(())
     }
 
-    /// List all tags.
-    pub fn tags(&self) -> Result<Vec<Tag>> {
-        let dir = self.root.join(TAGS_DIR);
-        let mut tags = Vec::new();
-        if dir.exists() {
-            for entry in fs::read_dir(&dir)? {
-                let entry = entry?;
-                let data = fs::read(entry.path())?;
-                let tag: Tag = serde_json::from_slice(&data)?;
-                tags.push(tag);
+    /// Fail with `QuotaExceeded` if `writes` (keys with the size of the
+    /// value put, or `None` for a delete, later ones winning) would take a
+    /// prefix of `tree` over a quota it is not already over.
+    fn check_quotas<'k>(
+        &self,
+        tree: &Tree,
+        writes: impl IntoIterator<Item = (&'k str, Option<u64>)>,
+    ) -> Result<()> {
+        let quotas = {
+            let config = self.config.lock().unwrap();
+            if config.quotas.is_empty() {
+                return Ok(());
+            }
+            config.quotas.clone()
+        };
+        let writes: BTreeMap<&str, Option<u64>> = writes.into_iter().collect();
+        for (prefix, quota) in &quotas {
+            let mut touched = writes
+                .iter()
+                .filter(|(key, _)| key.starts_with(prefix.as_str()))
+                .peekable();
+            if touched.peek().is_none() {
+                continue;
+            }
+            let before = tree.prefix_usage(prefix);
+            let (mut keys, mut bytes) = before;
+            for (key, size) in touched {
+                if let Some(old) = tree.get(key) {
+                    keys -= 1;
+                    bytes -= old.size();
+                }
+                if let Some(size) = size {
+                    keys += 1;
+                    bytes += size;
+                }
+            }
+            for (resource, limit, used, was) in [
+                ("keys", quota.max_keys, keys, before.0),
+                ("bytes", quota.max_bytes, bytes, before.1),
+            ] {
+                if limit > 0 && used > limit && used > was {
+                    return Err(IcebergError::QuotaExceeded {
+                        prefix: prefix.clone(),
+                        resource,
+                        limit,
+                        requested: used,
+                    });
+                }
             }
         }
-        tags.sort_by(|a, b| b.created_at.cmp(&a.created_at));
-        Ok(tags)
+        Ok(())
     }
 
-    /// Get a tag by name.
-    pub fn get_tag(&self, name: &str) -> Result<Tag> {
-        self.load_tag_by_name(name)?
-            .ok_or_else(|| IcebergError::Corruption(format!("tag not found: {}", name)))
+    /// Change when compaction runs on its own (see `AutoCompactionConfig`).
+    pub fn set_auto_compaction(&self, auto: AutoCompactionConfig) -> Result<()> {
+        self.update_config(|c| c.auto_compaction = auto)
     }
 
-    /// Delete a tag by name.
-    pub fn delete_tag(&self, name: &str) -> Result<()> {
-        let tag = self.get_tag(name)?;
-        let path = self.root.join(TAGS_DIR).join(&tag.id);
-        fs::remove_file(path)?;
+    fn update_config(&self, f: impl FnOnce(&mut DbConfig)) -> Result<()> {
+        let mut config = self.config.lock().unwrap();
+        let mut updated = config.clone();
+        f(&mut updated);
+        let key_pattern = updated.limits.compile_key_pattern()?;
+        updated.save(self.backend.as_ref())?;
+        *config = updated;
+        *self.key_pattern.write().unwrap() = key_pattern;
+        self.store.set_compression(config.compression.clone());
+        self.store.set_mmap(config.mmap_reads);
+        self.store.set_cache_capacity(config.cache.block_bytes);
+        self.tree_cache
+            .lock()
+            .unwrap()
+            .set_capacity(config.cache.tree_bytes);
         Ok(())
     }
 
-    // ── Cherry-pick ───────────────────────────────────────────
-
-    /// Cherry-pick a commit onto the current branch.
-    /// Applies the diff introduced by the given commit.
-    pub fn cherry_pick(&self, commit_id: &str, message: Option<&str>) -> Result<Commit> {
-        let commit = self.load_commit(commit_id)?;
-        let commit_tree = self.load_tree(&commit.tree_root)?;
-
-        // Get the parent tree (empty if no parent)
-        let parent_tree = match &commit.parent {
-            Some(pid) => {
-                let pc = self.load_commit(pid)?;
-                self.load_tree(&pc.tree_root)?
+    /// Recover from WAL after crash.
+    ///
+    /// The WAL is only truncated when the bloom filter and indexes are
+    /// flushed, so committed writes still in it may be missing from their
+    /// persisted copies; those are reapplied before the WAL is truncated.
+    fn recover_wal(&self) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        let recovery = wal.recover()?;
+        // Uncommitted transactions are simply ignored (rolled back).
+        self.replay_unapplied(&recovery)?;
+        let mut replayed = false;
+        // Only load the filters and indexes if there is something to replay.
+        if !recovery.committed.is_empty() {
+            let mut bloom = self.bloom().lock().unwrap();
+            let mut distinct = self.distinct().lock().unwrap();
+            let mut indexes = self.indexes().lock().unwrap();
+            for entry in &recovery.entries {
+                match entry {
+                    WalEntry::Write { tx_id, key, value }
+                        if recovery.committed.contains_key(tx_id) =>
+                    {
+                        bloom.insert(key);
+                        distinct.insert(key, Some(value));
+                        indexes.on_put(key, value);
+                    }
+                    WalEntry::WriteRef { tx_id, key, .. }
+                        if recovery.committed.contains_key(tx_id) =>
+                    {
+                        bloom.insert(key);
+                        distinct.insert(key, None);
+                        indexes.on_put(key, &[]);
+                    }
+                    WalEntry::Delete { tx_id, key } if recovery.committed.contains_key(tx_id) => {
+                        indexes.on_delete(key);
+                    }
+                    _ => continue,
+                }
+                replayed = true;
             }
-            None => Tree::empty(),
-        };
-
-        // Compute the diff introduced by this commit
-        let diff = parent_tree.diff(&commit_tree);
+        }
+        if replayed {
+            self.grow_full_bloom()?;
+            self.save_bloom()?;
+            self.save_indexes()?;
+        }
+        wal.truncate()?;
+        Ok(())
+    }
 
-        // Apply the diff to current tree
-        let mut current = self.current_tree().unwrap_or_else(|_| Tree::empty());
-        for key in &diff.added {
-            if let Some(val) = commit_tree.get(key) {
-                current = current.insert(key.clone(), val.clone());
-            }
+    /// Finish committed transactions whose commit never reached a branch,
+    /// i.e. the process died between the WAL commit and the ref update.
+    ///
+    /// Each write waits for the previous to finish, so unapplied
+    /// transactions are the newest ones and belong on their branch: HEAD,
+    /// unless the WAL names another (a tenant's, or one written through
+    /// `on_branch`). Normally the prepared commit sits directly on that
+    /// branch and it is fast-forwarded to it; otherwise the logged
+    /// operations are re-applied as a new commit.
+    fn replay_unapplied(&self, recovery: &WalRecovery) -> Result<()> {
+        if recovery.committed.is_empty() {
+            return Ok(());
         }
-        for key in &diff.modified {
-            if let Some(val) = commit_tree.get(key) {
-                current = current.insert(key.clone(), val.clone());
+        let reachable = self.reachable_commits()?;
+        let mut pending: Vec<_> = recovery
+            .committed
+            .iter()
+            .filter(|(_, id)| !reachable.contains(*id))
+            .collect();
+        pending.sort();
+        for (tx_id, commit_id) in pending {
+            let branch = match recovery.branches.get(tx_id) {
+                Some(branch) => branch.clone(),
+                None => self.load_refs()?.head,
+            };
+            let head = self.branch_commit(&branch).ok().map(|c| c.id);
+            let prepared = self.load_commit(commit_id).ok();
+            if let Some(commit) = prepared.as_ref().filter(|c| c.parent == head) {
+                if let Ok(tree) = self.load_tree(&commit.tree_root) {
+                    self.advance_branch(&branch, commit, tree)?;
+                    continue;
+                }
             }
-        }
-        for key in &diff.removed {
-            if current.contains_key(key) {
-                current = current.delete(key);
+            let mut tree = self
+                .branch_tree(&branch)
+                .map(|t| Tree::clone(&t))
+                .unwrap_or_else(|_| Tree::empty());
+            for entry in &recovery.entries {
+                tree = match entry {
+                    WalEntry::Write {
+                        tx_id: t,
+                        key,
+                        value,
+                    } if t == tx_id => tree.insert(key.clone(), self.store_value(value.clone())?),
+                    WalEntry::WriteRef {
+                        tx_id: t,
+                        key,
+                        value,
+                    } if t == tx_id => tree.insert(key.clone(), value.clone()),
+                    WalEntry::Delete { tx_id: t, key } if t == tx_id && tree.contains_key(key) => {
+                        tree.delete(key)
+                    }
+                    _ => continue,
+                };
             }
+            let message = prepared
+                .map(|c| c.message)
+                .unwrap_or_else(|| format!("replay WAL transaction {}", tx_id));
+            self.commit_tree(&branch, &tree, &message)?;
         }
+        Ok(())
+    }
 
-        let msg = message
-            .map(String::from)
-            .unwrap_or_else(|| format!("cherry-pick {}", &commit_id[..8.min(commit_id.len())]));
-        self.commit_tree(&current, &msg)
+    /// Persist the bloom filter and secondary indexes if they have unsaved
+    /// changes, then truncate the WAL that covered them.
+    pub fn flush(&self) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        self.flush_dirty().map(|_| ())
     }
 
-    // ── Rebase ─────────────────────────────────────────────────
+    /// Run one housekeeping pass: flush pending state (checkpointing the
+    /// WAL) and compact with `compaction`, if given, or else with the
+    /// configured auto-compaction policy once one of its thresholds is
+    /// crossed. The background worker calls this periodically; embedders
+    /// without one may call it directly.
+    pub fn maintain(&self, compaction: Option<&CompactionPolicy>) -> Result<MaintenanceReport> {
+        let _writer = self.writer.lock().unwrap();
+        let flushed = self.flush_dirty()?;
+        let policy = match compaction {
+            Some(policy) => Some(policy.clone()),
+            None => self.auto_compaction_due(false)?,
+        };
+        let compaction = policy
+            .map(|p| self.compact_locked(&p, &mut |_, _| {}))
+            .transpose()?;
+        Ok(MaintenanceReport {
+            flushed,
+            compaction,
+        })
+    }
 
-    /// Rebase the current branch onto another branch.
-    /// Takes all commits unique to the current branch and replays them
-    /// on top of the target branch's HEAD.
-    pub fn rebase(&self, onto_branch: &str) -> Result<Vec<Commit>> {
-        let refs = self.load_refs()?;
-        let current_branch = refs.head.clone();
+    /// The auto-compaction policy, if one of its thresholds has been crossed.
+    fn auto_compaction_due(&self, inline: bool) -> Result<Option<CompactionPolicy>> {
+        let auto = self.config.lock().unwrap().auto_compaction.clone();
+        let Some(policy) = auto.policy else {
+            return Ok(None);
+        };
+        let commits = self.commits_since_compaction.load(Ordering::Relaxed);
+        if auto.commits > 0 && commits >= auto.commits {
+            return Ok(Some(policy));
+        }
+        let measure =
+            !inline || (commits > 0 && commits.is_multiple_of(AUTO_COMPACTION_DISK_CHECK));
+        if auto.disk_bytes > 0 && measure && self.store.disk_usage()? > auto.disk_bytes {
+            return Ok(Some(policy));
+        }
+        Ok(None)
+    }
 
-        if current_branch == onto_branch {
-            return Err(IcebergError::Corruption(
-                "cannot rebase a branch onto itself".into(),
-            ));
+    /// Counters of the background maintenance worker, if one is running.
+    pub fn maintenance_status(&self) -> Option<MaintenanceStatus> {
+        self.maintenance
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|w| w.status())
+    }
+
+    /// Flush if anything is dirty; returns whether anything was written.
+    /// Callers hold the writer lock, so no write is half-applied meanwhile.
+    fn flush_dirty(&self) -> Result<bool> {
+        let mut dirty = self.dirty.lock().unwrap();
+        if dirty.writes == 0 && !dirty.bloom && !dirty.indexes && !dirty.commit_graph {
+            return Ok(false);
+        }
+        if dirty.bloom {
+            self.save_bloom()?;
+        }
+        if dirty.indexes {
+            self.save_indexes()?;
+        }
+        if dirty.commit_graph {
+            self.save_commit_graph()?;
         }
+        self.wal.lock().unwrap().truncate()?;
+        *dirty = DirtyState::default();
+        Ok(true)
+    }
 
-        let onto_id = refs
-            .branches
-            .get(onto_branch)
-            .ok_or_else(|| IcebergError::BranchNotFound(onto_branch.into()))?
-            .clone();
+    /// Flush pending state and close the database.
+    ///
+    /// Dropping a `Database` flushes as well, but ignores errors; call
+    /// `close` to observe them.
+    pub fn close(self) -> Result<()> {
+        self.flush()
+    }
 
-        // Collect commits on the target branch (to find the fork point)
-        let onto_ancestors: HashSet<String> = {
-            let mut ancestors = HashSet::new();
-            let mut current_id = Some(onto_id.clone());
-            while let Some(id) = current_id {
-                if !ancestors.insert(id.clone()) {
-                    break;
-                }
-                current_id = self.load_commit(&id).ok().and_then(|c| c.parent);
-            }
-            ancestors
+    /// Record a write that changed the bloom filter and/or the indexes,
+    /// flushing (and so checkpointing the WAL) once `flush_interval` writes
+    /// have accumulated or the WAL crosses its checkpoint thresholds.
+    fn mark_dirty(&self, bloom: bool, indexes: bool) -> Result<()> {
+        let due = {
+            let mut dirty = self.dirty.lock().unwrap();
+            dirty.bloom |= bloom;
+            dirty.indexes |= indexes;
+            dirty.writes += 1;
+            let config = self.config.lock().unwrap();
+            let wal = self.wal.lock().unwrap();
+            let over = |limit: u64, value: u64| limit > 0 && value >= limit;
+            over(config.flush_interval as u64, dirty.writes as u64)
+                || over(config.wal.checkpoint_bytes, wal.size())
+                || over(
+                    config.wal.checkpoint_entries as u64,
+                    wal.entry_count() as u64,
+                )
         };
-
-        // Collect commits unique to the current branch (stop at fork point)
-        let current_log = self.log()?;
-        let mut unique_commits: Vec<Commit> = Vec::new();
-        for commit in &current_log {
-            if onto_ancestors.contains(&commit.id) {
-                break;
+        if due {
+            self.flush_dirty()?;
+        }
+        if self.config.lock().unwrap().auto_compaction.inline {
+            if let Some(policy) = self.auto_compaction_due(true)? {
+                self.compact_locked(&policy, &mut |_, _| {})?;
             }
-            unique_commits.push(commit.clone());
         }
-        unique_commits.reverse(); // oldest first for replay
+        Ok(())
+    }
 
-        if unique_commits.is_empty() {
-            return Ok(Vec::new());
-        }
+    /// The key filters, read from the backend on first use. Filters of
+    /// another kind than the configured one still answer correctly until
+    /// the next write rebuilds them (see `grow_full_bloom`).
+    fn bloom(&self) -> &Mutex<BloomSet> {
+        self.bloom.get_or_init(|| {
+            let kind = self.config.lock().unwrap().key_filter;
+            Mutex::new(Self::load_bloom_from(self.backend.as_ref(), kind))
+        })
+    }
 
-        // Switch to onto_branch's state as our new base
-        let mut current_tree = self
-            .load_commit(&onto_id)
-            .and_then(|c| self.load_tree(&c.tree_root))?;
-        let mut parent_id = Some(onto_id);
-        let mut new_commits = Vec::new();
+    /// The distinct-count sketches, read from the backend on first use.
+    fn distinct(&self) -> &Mutex<DistinctSet> {
+        self.distinct.get_or_init(|| {
+            let distinct = self
+                .backend
+                .read(DISTINCT_FILE)
+                .ok()
+                .flatten()
+                .and_then(|data| serde_json::from_slice(&data).ok())
+                .unwrap_or_default();
+            Mutex::new(distinct)
+        })
+    }
 
-        // Replay each unique commit
-        for old_commit in &unique_commits {
-            let old_tree = self.load_tree(&old_commit.tree_root)?;
-            let old_parent_tree = match &old_commit.parent {
-                Some(pid) => self
-                    .load_commit(pid)
-                    .and_then(|c| self.load_tree(&c.tree_root))
-                    .unwrap_or_else(|_| Tree::empty()),
-                None => Tree::empty(),
-            };
+    /// The secondary indexes, read from the backend on first use.
+    fn indexes(&self) -> &Mutex<IndexManager> {
+        self.indexes
+            .get_or_init(|| Mutex::new(Self::load_indexes_from(self.backend.as_ref())))
+    }
 
-            // Compute the diff this commit introduced
-            let diff = old_parent_tree.diff(&old_tree);
+    fn load_bloom_from(backend: &dyn Backend, kind: FilterKind) -> BloomSet {
+        Self::read_bloom(backend, &mut Vec::new())
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| BloomSet::empty(kind))
+    }
 
-            // Apply the diff to current_tree
-            for key in &diff.added {
-                if let Some(val) = old_tree.get(key) {
-                    current_tree = current_tree.insert(key.clone(), val.clone());
-                }
+    /// The persisted bloom filters, from `BLOOM_FILE` or else the legacy
+    /// JSON files. Files that cannot be read are added to `corrupt` as
+    /// `(file, reason)`.
+    fn read_bloom(
+        backend: &dyn Backend,
+        corrupt: &mut Vec<(String, String)>,
+    ) -> Result<Option<BloomSet>> {
+        if let Some(data) = backend.read(BLOOM_FILE)? {
+            match BloomSet::from_bytes(&data) {
+                Ok(bloom) => return Ok(Some(bloom)),
+                Err(e) => corrupt.push((BLOOM_FILE.to_string(), e.to_string())),
             }
-            for key in &diff.modified {
-                if let Some(val) = old_tree.get(key) {
-                    current_tree = current_tree.insert(key.clone(), val.clone());
-                }
+        }
+        let mut namespaces = BTreeMap::<String, BloomFilter>::new();
+        if let Some(data) = backend.read(LEGACY_NAMESPACE_BLOOM_FILE)? {
+            match serde_json::from_slice(&data) {
+                Ok(filters) => namespaces = filters,
+                Err(e) => corrupt.push((LEGACY_NAMESPACE_BLOOM_FILE.to_string(), e.to_string())),
             }
-            for key in &diff.removed {
-                if current_tree.contains_key(key) {
-                    current_tree = current_tree.delete(key);
+        }
+        let mut bloom = None;
+        if let Some(data) = backend.read(LEGACY_BLOOM_FILE)? {
+            match serde_json::from_slice::<BloomFilter>(&data) {
+                Ok(default) => {
+                    let namespaces = namespaces
+                        .into_iter()
+                        .map(|(ns, filter)| (ns, KeyFilter::Bloom(filter)))
+                        .collect();
+                    bloom = Some(BloomSet::new(KeyFilter::Bloom(default), namespaces));
                 }
+                Err(e) => corrupt.push((LEGACY_BLOOM_FILE.to_string(), e.to_string())),
             }
-
-            // Create a new commit with the rebased tree
-            self.save_tree(&current_tree)?;
-            for v in current_tree.entries.values() {
-                let block = Block::new(v.clone());
-                self.store.put(&block)?;
-            }
-            let new_commit = Commit::new(
-                parent_id,
-                current_tree.root_hash.clone(),
-                old_commit.message.clone(),
-            );
-            self.save_commit(&new_commit)?;
-            parent_id = Some(new_commit.id.clone());
-            new_commits.push(new_commit);
         }
+        Ok(bloom)
+    }
 
-        // Update the current branch ref to point to the last new commit
-        if let Some(last) = new_commits.last() {
-            let mut refs = self.load_refs()?;
-            refs.branches.insert(current_branch, last.id.clone());
-            self.save_refs(&refs)?;
+    /// Add the keys of `tree` the bloom filter lacks. Writes keep the
+    /// filter covering every branch head, but a branch can also be pointed
+    /// at a tree whose keys it dropped in a rebuild, such as an old commit
+    /// or a cherry-pick from one, and the file can go stale behind our back.
+    /// Saved right away, as such moves are not in the WAL.
+    fn cover_bloom(&self, tree: &Tree) -> Result<()> {
+        let changed = {
+            let mut bloom = self.bloom().lock().unwrap();
+            tree.entries
+                .keys()
+                .fold(false, |changed, key| bloom.cover(key) | changed)
+        };
+        if changed {
+            self.grow_full_bloom()?;
+            self.save_bloom()?;
         }
-
-        Ok(new_commits)
+        Ok(())
     }
 
-    // ── Secondary Indexes ─────────────────────────────────────
-
-    /// Create a secondary index on a JSON field.
-    pub fn create_index(&self, name: &str, field_path: &str) -> Result<()> {
+    /// Rebuild the key filters from every branch head if an insert found
+    /// one full, which only cuckoo filters ever are, or if they were loaded
+    /// of another kind than the configured one. Callers hold the writer
+    /// lock or have the database to themselves.
+    fn grow_full_bloom(&self) -> Result<()> {
+        let kind = self.config.lock().unwrap().key_filter;
         {
-            let mut indexes = self.indexes.lock().unwrap();
-            indexes.create_index(name, field_path)?;
-
-            // Rebuild from current tree
-            if let Ok(tree) = self.current_tree() {
-                let entries: Vec<_> = tree
-                    .entries
-                    .iter()
-                    .map(|(k, v)| (k.clone(), v.clone()))
-                    .collect();
-                indexes.rebuild_all(&entries);
+            let bloom = self.bloom().lock().unwrap();
+            if !bloom.is_full() && bloom.kind() == kind {
+                return Ok(());
             }
         }
-        self.save_indexes()
+        self.rebuild_bloom_locked()
     }
 
-    /// Drop a secondary index.
-    pub fn drop_index(&self, name: &str) -> Result<()> {
-        {
-            let mut indexes = self.indexes.lock().unwrap();
-            indexes.drop_index(name)?;
+    fn save_bloom(&self) -> Result<()> {
+        let data = self.bloom().lock().unwrap().to_bytes();
+        self.backend.write(BLOOM_FILE, &data)?;
+        self.backend.delete(LEGACY_BLOOM_FILE)?;
+        self.backend.delete(LEGACY_NAMESPACE_BLOOM_FILE)?;
+        self.save_distinct()
+    }
+
+    fn save_distinct(&self) -> Result<()> {
+        let distinct = self.distinct().lock().unwrap();
+        if distinct.specs().next().is_none() {
+            return self.backend.delete(DISTINCT_FILE);
         }
-        self.save_indexes()
+        let data = serde_json::to_vec(&*distinct)?;
+        self.backend.write(DISTINCT_FILE, &data)
     }
 
-    /// Query a secondary index by exact value. Returns matching primary keys.
-    pub fn query_index(&self, index_name: &str, value: &str) -> Result<Vec<String>> {
-        let indexes = self.indexes.lock().unwrap();
-        indexes.query(index_name, value)
+    fn load_indexes_from(backend: &dyn Backend) -> IndexManager {
+        IndexManager::load(backend)
+            .map(|(mgr, _)| mgr)
+            .unwrap_or_default()
     }
 
-    /// Query a secondary index by prefix. Returns matching primary keys.
-    pub fn query_index_prefix(&self, index_name: &str, prefix: &str) -> Result<Vec<String>> {
-        let indexes = self.indexes.lock().unwrap();
-        indexes.query_prefix(index_name, prefix)
+    fn save_indexes(&self) -> Result<()> {
+        let mut indexes = self.indexes().lock().unwrap();
+        indexes.persist(self.backend.as_ref())
     }
 
-    /// List all secondary indexes.
-    pub fn list_indexes(&self) -> Vec<String> {
-        let indexes = self.indexes.lock().unwrap();
-        indexes.list_indexes()
+    // ── Key-Value API ─────────────────────────────────────────
+
+    /// Get a value by key from the current branch HEAD.
+    /// Uses bloom filter for fast negative lookups.
+    pub fn get(&self, key: &str) -> Result<Vec<u8>> {
+        self.get_in(Target::Head, key)
     }
 
-    // ── Bloom Filter ──────────────────────────────────────────
+    /// `get` from `target`.
+    pub(crate) fn get_in(&self, target: Target, key: &str) -> Result<Vec<u8>> {
+        // Fast path: bloom filter says definitely not present
+        if !self.foreign_refs.load(Ordering::Relaxed) {
+            let bloom = self.bloom().lock().unwrap();
+            if !bloom.may_contain(key) {
+                return Err(IcebergError::KeyNotFound(key.into()));
+            }
+        }
+        let tree = self.target_tree(target)?;
+        match tree.get(key) {
+            Some(v) => self.load_value(v),
+            None => Err(IcebergError::KeyNotFound(key.into())),
+        }
+    }
 
-    /// Rebuild the bloom filter from the current tree.
-    pub fn rebuild_bloom(&self) -> Result<()> {
-        let tree = self.current_tree().unwrap_or_else(|_| Tree::empty());
-        let mut bloom = BloomFilter::new(tree.len().max(1000), 0.01);
-        for key in tree.entries.keys() {
-            bloom.insert(key.as_bytes());
+    /// Get a value without copying it when possible.
+    ///
+    /// With `mmap_reads` enabled, values stored as a single uncompressed block
+    /// are borrowed straight from a memory-mapped file; all other values are
+    /// returned owned.
+    pub fn get_ref(&self, key: &str) -> Result<ValueRef> {
+        let tree = self.current_tree()?;
+        match tree.get(key) {
+            Some(TreeValue::Block { hash, .. }) => self.store.get_ref(hash),
+            Some(v) => Ok(ValueRef::Owned(self.load_value(v)?)),
+            None => Err(IcebergError::KeyNotFound(key.into())),
         }
-        *self.bloom.lock().unwrap() = bloom;
-        self.save_bloom()
     }
 
-    /// Get bloom filter stats.
-    pub fn bloom_stats(&self) -> (usize, usize, f64) {
-        let bloom = self.bloom.lock().unwrap();
-        (bloom.count(), bloom.num_bits(), bloom.estimated_fp_rate())
+    /// Put a key-value pair; creates a new commit on the current branch.
+    /// Writes are WAL-protected for crash safety.
+    pub fn put(&self, key: &str, value: Vec<u8>, message: Option<&str>) -> Result<Commit> {
+        namespace::check_key(key)?;
+        let msg = message
+            .map(String::from)
+            .unwrap_or_else(|| format!("put {}", key));
+        self.put_raw(Target::Head, key, value, None, None, &msg)
     }
 
-    // ── Compaction ────────────────────────────────────────────
+    /// `put` that fails with `Conflict`, writing nothing, unless the
+    /// current branch is still at `expected_head`: the id of the commit the
+    /// caller read before deciding what to write. Concurrent writers can
+    /// then re-read and retry rather than silently overwrite each other.
+    pub fn put_if_head(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected_head: &str,
+        message: Option<&str>,
+    ) -> Result<Commit> {
+        namespace::check_key(key)?;
+        let msg = message
+            .map(String::from)
+            .unwrap_or_else(|| format!("put {}", key));
+        self.put_raw(Target::Head, key, value, Some(expected_head), None, &msg)
+    }
 
-    /// Run compaction with the given policy on the current branch.
-    /// Removes old commits and unreachable trees/blocks.
-    pub fn compact(&self, policy: &CompactionPolicy) -> Result<CompactionResult> {
-        let now = chrono::Utc::now();
-        let log = self.log()?;
-        let commits_with_ts: Vec<_> = log.iter().map(|c| (c.id.clone(), c.timestamp)).collect();
+    /// `put` that respects key locks: fails with `KeyLocked`, writing
+    /// nothing, while someone other than `holder` has a live lock on `key`.
+    pub fn put_as(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        holder: &str,
+        message: Option<&str>,
+    ) -> Result<Commit> {
+        namespace::check_key(key)?;
+        let msg = message
+            .map(String::from)
+            .unwrap_or_else(|| format!("put {}", key));
+        self.put_raw(Target::Head, key, value, None, Some(holder), &msg)
+    }
 
-        let removable = find_removable_commits(&commits_with_ts, policy, now);
-        if removable.is_empty() {
-            return Ok(CompactionResult::default());
+    /// `put` of a key as stored in trees, which may be namespaced, to
+    /// `target`, optionally only while it is at an expected head and only
+    /// if no one but `holder` has the key locked.
+    pub(crate) fn put_raw(
+        &self,
+        target: Target,
+        key: &str,
+        value: Vec<u8>,
+        expected_head: Option<&str>,
+        holder: Option<&str>,
+        msg: &str,
+    ) -> Result<Commit> {
+        self.check_limits(key, value.len() as u64)?;
+        let _writer = self.writer.lock().unwrap();
+        let refs = self.load_refs()?;
+        let branch = refs.write_branch(target)?;
+        refs.check_head(&branch, expected_head)?;
+        if let Some(holder) = holder {
+            self.load_key_locks()?
+                .check(key, holder, chrono::Utc::now())?;
+        }
+        let tree = self
+            .branch_tree(&branch)
+            .unwrap_or_else(|_| Arc::new(Tree::empty()));
+        self.check_quotas(&tree, [(key, Some(value.len() as u64))])?;
+        // The indexes and distinct counts only follow the checked-out
+        // branch; the key filters cover every branch head.
+        let head = refs.is_checked_out(&branch);
+        // Checked under the writer lock so no other put can take the value
+        // between the check and the commit.
+        if head {
+            self.indexes().lock().unwrap().check_unique(key, &value)?;
         }
+        // A spilled value is logged by reference, so it is stored first.
+        let spilled = self.spill(&value)?;
+        // WAL: begin transaction
+        let tx_id = {
+            let mut wal = self.wal.lock().unwrap();
+            let tx = self.begin_logged(&mut wal, target, &branch)?;
+            match &spilled {
+                Some(stored) => wal.log_write_ref(tx, key.into(), stored.clone())?,
+                None => wal.log_write(tx, key.into(), value.clone())?,
+            }
+            tx
+        };
 
-        // Collect all reachable tree roots and block hashes from commits we're keeping
-        let keep_commit_ids: HashSet<_> = log
-            .iter()
-            .map(|c| c.id.clone())
-            .filter(|id| !removable.contains(id))
-            .collect();
+        let stored = match spilled {
+            Some(stored) => stored,
+            None => self.store_value(value.clone())?,
+        };
+        let new_tree = tree.insert(key.into(), stored);
+        let commit =
+            self.commit_logged(&branch, refs.branches.get(&branch), tx_id, &new_tree, msg)?;
 
-        // Also collect from all branches (not just current)
-        let refs = self.load_refs()?;
-        let mut all_reachable_commits = HashSet::new();
-        for cid in refs.branches.values() {
-            let mut current_id = Some(cid.clone());
-            while let Some(id) = current_id {
-                if !all_reachable_commits.insert(id.clone()) {
-                    break; // already visited
-                }
-                if let Ok(c) = self.load_commit(&id) {
-                    current_id = c.parent;
-                } else {
-                    break;
-                }
-            }
+        // Update bloom filter and distinct counts. The filter already holds
+        // every key of a branch head, so only a new key needs inserting.
+        if !tree.contains_key(key) {
+            self.bloom().lock().unwrap().insert(key);
+            self.grow_full_bloom()?;
         }
+        if head {
+            self.distinct().lock().unwrap().insert(key, Some(&value));
+            self.indexes().lock().unwrap().on_put(key, &value);
+        }
+        self.mark_dirty(true, head)?;
+
+        Ok(commit)
+    }
+
+    /// Stream a value from a reader into the store; creates a new commit.
+    ///
+    /// The value is split into `STREAM_CHUNK_SIZE` blocks as it is read, so it
+    /// never has to fit in memory; once it reaches `DbConfig::blob_threshold`
+    /// it goes to the blob store instead. The WAL records the chunk or blob
+    /// references rather than the content. Streamed values are not indexed
+    /// by secondary indexes.
+    pub fn put_reader(
+        &self,
+        key: &str,
+        reader: impl Read,
+        message: Option<&str>,
+    ) -> Result<Commit> {
+        namespace::check_key(key)?;
+        let msg = message
+            .map(String::from)
+            .unwrap_or_else(|| format!("put {}", key));
+        self.put_reader_raw(Target::Head, key, reader, &msg)
+    }
+
+    /// `put_reader` of a key as stored in trees, which may be namespaced, to
+    /// `target`.
+    pub(crate) fn put_reader_raw(
+        &self,
+        target: Target,
+        key: &str,
+        mut reader: impl Read,
+        msg: &str,
+    ) -> Result<Commit> {
+        self.check_limits(key, 0)?;
+        let blob_threshold = self.config.lock().unwrap().blob_threshold;
+        // Released only once the commit is on its branch.
+        let mut in_flight = InFlight::new(self);
+        let mut chunks: Vec<String> = Vec::new();
+        let mut blob = None;
+        let mut size = 0u64;
+        let mut buf = vec![0u8; STREAM_CHUNK_SIZE];
+        loop {
+            let n = read_full(&mut reader, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            size += n as u64;
+            // Stop reading as soon as the value is too large; the chunks
+            // already stored are left for compaction.
+            self.check_limits(key, size)?;
+            if blob.is_none() && blob_threshold > 0 && size >= blob_threshold {
+                // Large enough to spill: move what was read so far over.
+                let mut writer = self.blobs.writer()?;
+                for hash in chunks.drain(..) {
+                    writer.write(&self.store.get(&hash)?.data)?;
+                }
+                blob = Some(writer);
+            }
+            match &mut blob {
+                Some(writer) => writer.write(&buf[..n])?,
+                None => {
+                    let block = Block::new(buf[..n].to_vec());
+                    in_flight.hold(&block.hash);
+                    chunks.push(self.store.put(&block)?);
+                }
+            }
+            if n < buf.len() {
+                break;
+            }
+        }
+        let value = match blob {
+            Some(writer) => {
+                let (blob, size) = writer.finish_with(|hash| in_flight.hold(hash))?;
+                TreeValue::Blob { blob, size }
+            }
+            None => TreeValue::Chunked { chunks, size },
+        };
+
+        let _writer = self.writer.lock().unwrap();
+        let refs = self.load_refs()?;
+        let branch = refs.write_branch(target)?;
+        let head = refs.is_checked_out(&branch);
+        // Streamed values are not parsed, but key-sourced indexes still apply.
+        if head {
+            self.indexes().lock().unwrap().check_unique(key, &[])?;
+        }
+        let tree = self
+            .branch_tree(&branch)
+            .unwrap_or_else(|_| Arc::new(Tree::empty()));
+        self.check_quotas(&tree, [(key, Some(size))])?;
+        let tx_id = {
+            let mut wal = self.wal.lock().unwrap();
+            let tx = self.begin_logged(&mut wal, target, &branch)?;
+            wal.log_write_ref(tx, key.into(), value.clone())?;
+            tx
+        };
+
+        let new_tree = tree.insert(key.into(), value);
+        let commit =
+            self.commit_logged(&branch, refs.branches.get(&branch), tx_id, &new_tree, msg)?;
+
+        if !tree.contains_key(key) {
+            self.bloom().lock().unwrap().insert(key);
+            self.grow_full_bloom()?;
+        }
+        // Drop any index entries left over from a previous (non-streamed)
+        // value; only indexes over the key itself index streamed values.
+        if head {
+            self.distinct().lock().unwrap().insert(key, None);
+            self.indexes().lock().unwrap().on_put(key, &[]);
+        }
+        self.mark_dirty(true, head)?;
+
+        Ok(commit)
+    }
+
+    /// Stream a value from the current branch HEAD into a writer, one block
+    /// at a time. Returns the number of bytes written.
+    pub fn get_writer(&self, key: &str, writer: impl Write) -> Result<u64> {
+        let tree = self.current_tree()?;
+        let value = tree
+            .get(key)
+            .ok_or_else(|| IcebergError::KeyNotFound(key.into()))?;
+        self.write_value(value, writer)
+    }
+
+    /// Write a stored value into `writer`, one block at a time.
+    pub(crate) fn write_value(&self, value: &TreeValue, mut writer: impl Write) -> Result<u64> {
+        match value {
+            TreeValue::Inline(v) => writer.write_all(v)?,
+            TreeValue::Block { hash, .. } => writer.write_all(&self.store.get(hash)?.data)?,
+            TreeValue::Chunked { chunks, .. } => {
+                for hash in chunks {
+                    writer.write_all(&self.store.get(hash)?.data)?;
+                }
+            }
+            TreeValue::Blob { blob, .. } => {
+                self.blobs.write_to(blob, &mut writer)?;
+            }
+        }
+        writer.flush()?;
+        Ok(value.size())
+    }
+
+    /// Delete a key; creates a new commit.
+    /// Writes are WAL-protected for crash safety.
+    pub fn delete(&self, key: &str, message: Option<&str>) -> Result<Commit> {
+        namespace::check_key(key)?;
+        let msg = message
+            .map(String::from)
+            .unwrap_or_else(|| format!("delete {}", key));
+        self.delete_raw(Target::Head, key, None, &msg)
+    }
+
+    /// `delete` that fails with `Conflict` unless the current branch is
+    /// still at `expected_head`, like `put_if_head`.
+    pub fn delete_if_head(
+        &self,
+        key: &str,
+        expected_head: &str,
+        message: Option<&str>,
+    ) -> Result<Commit> {
+        namespace::check_key(key)?;
+        let msg = message
+            .map(String::from)
+            .unwrap_or_else(|| format!("delete {}", key));
+        self.delete_raw(Target::Head, key, Some(expected_head), &msg)
+    }
+
+    /// `delete` of a key as stored in trees, which may be namespaced, from
+    /// `target`, optionally only while it is at an expected head.
+    pub(crate) fn delete_raw(
+        &self,
+        target: Target,
+        key: &str,
+        expected_head: Option<&str>,
+        msg: &str,
+    ) -> Result<Commit> {
+        let _writer = self.writer.lock().unwrap();
+        let refs = self.load_refs()?;
+        let branch = refs.write_branch(target)?;
+        refs.check_head(&branch, expected_head)?;
+        let tree = self.branch_tree(&branch)?;
+        if !tree.contains_key(key) {
+            return Err(IcebergError::KeyNotFound(key.into()));
+        }
+
+        // WAL: begin transaction
+        let tx_id = {
+            let mut wal = self.wal.lock().unwrap();
+            let tx = self.begin_logged(&mut wal, target, &branch)?;
+            wal.log_delete(tx, key.into())?;
+            tx
+        };
+
+        let new_tree = tree.delete(key);
+        let commit =
+            self.commit_logged(&branch, refs.branches.get(&branch), tx_id, &new_tree, msg)?;
+
+        let head = refs.is_checked_out(&branch);
+        if head {
+            self.indexes().lock().unwrap().on_delete(key);
+        }
+        let forgotten = self.forget_keys(&refs, [key]);
+        self.mark_dirty(forgotten, head)?;
+
+        Ok(commit)
+    }
+
+    /// Apply a batch of puts and deletes to the current branch as a single
+    /// commit, or return `None` when it is empty. Nothing is written if a
+    /// delete finds no key or a put would break a unique index.
+    pub fn write(&self, batch: &WriteBatch, message: Option<&str>) -> Result<Option<Commit>> {
+        self.write_checked(Target::Head, batch, None, message)
+    }
+
+    /// `write` that fails with `Conflict` unless the current branch is
+    /// still at `expected_head`, like `put_if_head`.
+    pub fn write_if_head(
+        &self,
+        batch: &WriteBatch,
+        expected_head: &str,
+        message: Option<&str>,
+    ) -> Result<Option<Commit>> {
+        self.write_checked(Target::Head, batch, Some(expected_head), message)
+    }
+
+    /// `write` to `target`, optionally only while it is at an expected
+    /// head.
+    pub(crate) fn write_checked(
+        &self,
+        target: Target,
+        batch: &WriteBatch,
+        expected_head: Option<&str>,
+        message: Option<&str>,
+    ) -> Result<Option<Commit>> {
+        for op in batch.ops() {
+            namespace::check_key(op.key())?;
+        }
+        let msg = message
+            .map(String::from)
+            .unwrap_or_else(|| format!("write {} keys", batch.len()));
+        self.write_raw(target, batch, expected_head, &msg)
+    }
+
+    /// `write` of keys as stored in trees, which may be namespaced, to
+    /// `target`, optionally only while it is at an expected head.
+    pub(crate) fn write_raw(
+        &self,
+        target: Target,
+        batch: &WriteBatch,
+        expected_head: Option<&str>,
+        msg: &str,
+    ) -> Result<Option<Commit>> {
+        let _writer = self.writer.lock().unwrap();
+        let refs = self.load_refs()?;
+        let branch = refs.write_branch(target)?;
+        refs.check_head(&branch, expected_head)?;
+        if batch.is_empty() {
+            return Ok(None);
+        }
+        for op in batch.ops() {
+            if let BatchOp::Put { key, value } = op {
+                self.check_limits(key, value.len() as u64)?;
+            }
+        }
+        let tree = self
+            .branch_tree(&branch)
+            .unwrap_or_else(|_| Arc::new(Tree::empty()));
+
+        // Whether each written key exists once the batch is applied.
+        let mut present: BTreeMap<&str, bool> = BTreeMap::new();
+        for op in batch.ops() {
+            let key = op.key();
+            let exists = present
+                .get(key)
+                .copied()
+                .unwrap_or_else(|| tree.contains_key(key));
+            if matches!(op, BatchOp::Delete { .. }) && !exists {
+                return Err(IcebergError::KeyNotFound(key.into()));
+            }
+            present.insert(key, matches!(op, BatchOp::Put { .. }));
+        }
+        let head = refs.is_checked_out(&branch);
+        if head {
+            self.indexes()
+                .lock()
+                .unwrap()
+                .check_unique_all(batch.ops().iter().map(|op| match op {
+                    BatchOp::Put { key, value } => (key.as_str(), Some(value.as_slice())),
+                    BatchOp::Delete { key } => (key.as_str(), None),
+                }))?;
+        }
+        self.check_quotas(
+            &tree,
+            batch.ops().iter().map(|op| match op {
+                BatchOp::Put { key, value } => (key.as_str(), Some(value.len() as u64)),
+                BatchOp::Delete { key } => (key.as_str(), None),
+            }),
+        )?;
+
+        // Spilled values are logged by reference, so they are stored first.
+        let spilled = batch
+            .ops()
+            .iter()
+            .map(|op| match op {
+                BatchOp::Put { value, .. } => self.spill(value),
+                BatchOp::Delete { .. } => Ok(None),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let tx_id = {
+            let mut wal = self.wal.lock().unwrap();
+            let tx = self.begin_logged(&mut wal, target, &branch)?;
+            for (op, spilled) in batch.ops().iter().zip(&spilled) {
+                match (op, spilled) {
+                    (BatchOp::Put { key, .. }, Some(stored)) => {
+                        wal.log_write_ref(tx, key.clone(), stored.clone())?
+                    }
+                    (BatchOp::Put { key, value }, None) => {
+                        wal.log_write(tx, key.clone(), value.clone())?
+                    }
+                    (BatchOp::Delete { key }, _) => wal.log_delete(tx, key.clone())?,
+                }
+            }
+            tx
+        };
+
+        let unspilled = batch
+            .ops()
+            .iter()
+            .zip(&spilled)
+            .filter_map(|(op, spilled)| match (op, spilled) {
+                (BatchOp::Put { value, .. }, None) => Some(value.clone()),
+                _ => None,
+            })
+            .collect();
+        let mut stored = self.store_values(unspilled)?.into_iter();
+        let writes: Vec<_> = batch
+            .ops()
+            .iter()
+            .zip(spilled)
+            .map(|(op, spilled)| match (op, spilled) {
+                (BatchOp::Put { key, .. }, Some(spilled)) => (key.clone(), Some(spilled)),
+                (BatchOp::Put { key, .. }, None) => (key.clone(), stored.next()),
+                (BatchOp::Delete { key }, _) => (key.clone(), None),
+            })
+            .collect();
+        let new_tree = tree.apply(writes);
+        let commit =
+            self.commit_logged(&branch, refs.branches.get(&branch), tx_id, &new_tree, msg)?;
+
+        if head {
+            let mut distinct = self.distinct().lock().unwrap();
+            let mut indexes = self.indexes().lock().unwrap();
+            for op in batch.ops() {
+                match op {
+                    BatchOp::Put { key, value } => {
+                        distinct.insert(key, Some(value));
+                        indexes.on_put(key, value);
+                    }
+                    BatchOp::Delete { key } => indexes.on_delete(key),
+                }
+            }
+        }
+        // Only keys that came or went change the filter; each just once, as
+        // a cuckoo filter counts repeated inserts.
+        let added: Vec<&str> = present
+            .iter()
+            .filter(|(key, now)| **now && !tree.contains_key(key))
+            .map(|(key, _)| *key)
+            .collect();
+        if !added.is_empty() {
+            let mut bloom = self.bloom().lock().unwrap();
+            for key in &added {
+                bloom.insert(key);
+            }
+        }
+        self.grow_full_bloom()?;
+        let removed = present
+            .iter()
+            .filter(|(key, now)| !**now && tree.contains_key(key))
+            .map(|(key, _)| *key);
+        let forgotten = self.forget_keys(&refs, removed);
+        // Distinct counts are saved with the filter.
+        let counted = head
+            && batch
+                .ops()
+                .iter()
+                .any(|op| matches!(op, BatchOp::Put { .. }));
+        self.mark_dirty(counted || forgotten, head)?;
+
+        Ok(Some(commit))
+    }
+
+    /// Delete every key in `[start, end)` in a single commit, or return
+    /// `None` when there are none.
+    pub fn delete_range(
+        &self,
+        start: &str,
+        end: &str,
+        message: Option<&str>,
+    ) -> Result<Option<Commit>> {
+        self.delete_range_raw(Target::Head, None, start, end, message)
+    }
+
+    /// `delete_range` on `target` within namespace `ns`.
+    pub(crate) fn delete_range_raw(
+        &self,
+        target: Target,
+        ns: Option<&str>,
+        start: &str,
+        end: &str,
+        message: Option<&str>,
+    ) -> Result<Option<Commit>> {
+        let _writer = self.writer.lock().unwrap();
+        let refs = self.load_refs()?;
+        let branch = refs.write_branch(target)?;
+        let tree = self.branch_tree(&branch)?;
+        let keys: Vec<String> = scan_page(&tree, ns, start, Some(end), &ScanOptions::default())
+            .map(|(key, _)| match ns {
+                Some(ns) => namespace::qualify(ns, key),
+                None => key.to_string(),
+            })
+            .collect();
+        if keys.is_empty() {
+            return Ok(None);
+        }
+        let msg = message
+            .map(String::from)
+            .unwrap_or_else(|| format!("delete {} keys in [{}, {})", keys.len(), start, end));
+
+        let tx_id = {
+            let mut wal = self.wal.lock().unwrap();
+            let tx = self.begin_logged(&mut wal, target, &branch)?;
+            for key in &keys {
+                wal.log_delete(tx, key.clone())?;
+            }
+            tx
+        };
+
+        let new_tree = tree.delete_all(keys.iter().map(String::as_str));
+        let commit =
+            self.commit_logged(&branch, refs.branches.get(&branch), tx_id, &new_tree, &msg)?;
+
+        let head = refs.is_checked_out(&branch);
+        if head {
+            let mut indexes = self.indexes().lock().unwrap();
+            for key in &keys {
+                indexes.on_delete(key);
+            }
+        }
+        let forgotten = self.forget_keys(&refs, keys.iter().map(String::as_str));
+        self.mark_dirty(forgotten, head)?;
+
+        Ok(Some(commit))
+    }
+
+    /// Scan keys by prefix.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.scan_prefix_with(prefix, &ScanOptions::default())
+    }
+
+    /// One page of `scan_prefix`; only the values on the page are read.
+    pub fn scan_prefix_with(
+        &self,
+        prefix: &str,
+        options: &ScanOptions,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        self.scan_prefix_in(Target::Head, prefix, options)
+    }
+
+    /// One page of `scan_prefix` as of a past commit.
+    pub fn scan_prefix_at(
+        &self,
+        prefix: &str,
+        commit_id: &str,
+        options: &ScanOptions,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        let tree = self.tree_at(commit_id)?;
+        self.scan_tree(&tree, None, prefix, None, options)
+    }
+
+    /// `scan_prefix_with` at `target`.
+    pub(crate) fn scan_prefix_in(
+        &self,
+        target: Target,
+        prefix: &str,
+        options: &ScanOptions,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        let tree = self.target_tree(target)?;
+        self.scan_tree(&tree, None, prefix, None, options)
+    }
+
+    /// Range scan.
+    pub fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.range_with(start, end, &ScanOptions::default())
+    }
+
+    /// One page of `range`; only the values on the page are read.
+    pub fn range_with(
+        &self,
+        start: &str,
+        end: &str,
+        options: &ScanOptions,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        let tree = self.current_tree()?;
+        self.scan_tree(&tree, None, start, Some(end), options)
+    }
+
+    /// Keys in `[start, end)` without reading any values: what
+    /// `delete_range` would delete.
+    pub fn range_keys(&self, start: &str, end: &str) -> Result<Vec<String>> {
+        self.range_keys_in(None, start, end)
+    }
+
+    pub(crate) fn range_keys_in(
+        &self,
+        ns: Option<&str>,
+        start: &str,
+        end: &str,
+    ) -> Result<Vec<String>> {
+        let tree = self.current_tree()?;
+        Ok(
+            scan_page(&tree, ns, start, Some(end), &ScanOptions::default())
+                .map(|(k, _)| k.to_string())
+                .collect(),
+        )
+    }
+
+    /// Keys matching a glob such as `user:*:settings`, with their values.
+    pub fn scan_glob(&self, pattern: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let pattern = KeyPattern::glob(pattern)?;
+        let prefix = pattern.prefix().to_string();
+        self.scan_prefix_with(&prefix, &pattern.into())
+    }
+
+    /// Keys matching a regex anywhere, unless anchored, with their values.
+    pub fn scan_regex(&self, pattern: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.scan_prefix_with("", &KeyPattern::regex(pattern)?.into())
+    }
+
+    /// Keys under a prefix, without reading any values.
+    pub fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.scan_keys_with(prefix, &ScanOptions::default())
+    }
+
+    /// One page of `scan_keys`.
+    pub fn scan_keys_with(&self, prefix: &str, options: &ScanOptions) -> Result<Vec<String>> {
+        self.scan_keys_in(None, prefix, None, options)
+    }
+
+    /// One page of `scan_keys` as of a past commit.
+    pub fn scan_keys_at(
+        &self,
+        prefix: &str,
+        commit_id: &str,
+        options: &ScanOptions,
+    ) -> Result<Vec<String>> {
+        self.scan_keys_in(None, prefix, Some(commit_id), options)
+    }
+
+    /// `scan_keys_with` in namespace `ns`, at HEAD or as of `commit_id`.
+    pub(crate) fn scan_keys_in(
+        &self,
+        ns: Option<&str>,
+        prefix: &str,
+        commit_id: Option<&str>,
+        options: &ScanOptions,
+    ) -> Result<Vec<String>> {
+        let tree = match commit_id {
+            Some(commit_id) => self.tree_at(commit_id)?,
+            None => self.current_tree()?,
+        };
+        Ok(scan_page(&tree, ns, prefix, None, options)
+            .map(|(k, _)| k.to_string())
+            .collect())
+    }
+
+    /// Number of keys under a prefix; no values are read.
+    pub fn count(&self, prefix: &str) -> Result<usize> {
+        self.count_in(None, prefix, None)
+    }
+
+    /// Number of keys under a prefix as of a past commit.
+    pub fn count_at(&self, prefix: &str, commit_id: &str) -> Result<usize> {
+        self.count_in(None, prefix, Some(commit_id))
+    }
+
+    /// `count` in namespace `ns`, at HEAD or as of `commit_id`.
+    pub(crate) fn count_in(
+        &self,
+        ns: Option<&str>,
+        prefix: &str,
+        commit_id: Option<&str>,
+    ) -> Result<usize> {
+        let tree = match commit_id {
+            Some(commit_id) => self.tree_at(commit_id)?,
+            None => self.current_tree()?,
+        };
+        Ok(scan_page(&tree, ns, prefix, None, &ScanOptions::default()).count())
+    }
+
+    /// Up to `n` keys under a prefix, sampled uniformly and returned in key
+    /// order; no values are read.
+    pub fn sample(&self, n: usize, prefix: &str) -> Result<Vec<String>> {
+        self.sample_with_seed(n, prefix, uuid::Uuid::new_v4().as_u64_pair().0)
+    }
+
+    /// `sample` with a fixed seed, so the same tree gives the same keys.
+    pub fn sample_with_seed(&self, n: usize, prefix: &str, seed: u64) -> Result<Vec<String>> {
+        self.sample_in(None, n, prefix, seed)
+    }
+
+    pub(crate) fn sample_in(
+        &self,
+        ns: Option<&str>,
+        n: usize,
+        prefix: &str,
+        seed: u64,
+    ) -> Result<Vec<String>> {
+        let tree = self.current_tree()?;
+        // Reservoir sampling: the i-th key replaces a kept one with
+        // probability n / (i + 1).
+        let mut rng = XorShift(seed.max(1));
+        let mut kept: Vec<&str> = Vec::with_capacity(n);
+        for (i, (key, _)) in scan_page(&tree, ns, prefix, None, &ScanOptions::default()).enumerate()
+        {
+            if kept.len() < n {
+                kept.push(key);
+            } else if n > 0 {
+                let j = rng.below(i + 1);
+                if j < n {
+                    kept[j] = key;
+                }
+            }
+        }
+        kept.sort_unstable();
+        Ok(kept.into_iter().map(String::from).collect())
+    }
+
+    /// Search the values under a prefix for a substring, or a regex with
+    /// `options.regex`.
+    pub fn grep(
+        &self,
+        pattern: &str,
+        prefix: &str,
+        options: &GrepOptions,
+    ) -> Result<Vec<GrepMatch>> {
+        self.grep_in(None, pattern, prefix, options)
+    }
+
+    pub(crate) fn grep_in(
+        &self,
+        ns: Option<&str>,
+        pattern: &str,
+        prefix: &str,
+        options: &GrepOptions,
+    ) -> Result<Vec<GrepMatch>> {
+        let matcher = Matcher::new(pattern, options)?;
+        let limit = options.limit.unwrap_or(usize::MAX);
+        let tree = self.current_tree()?;
+        let mut found = Vec::new();
+        for (key, value) in scan_page(&tree, ns, prefix, None, &ScanOptions::default()) {
+            if found.len() >= limit {
+                break;
+            }
+            found.extend(matcher.search(key, &self.load_value(value)?));
+        }
+        found.truncate(limit);
+        Ok(found)
+    }
+
+    /// `scan_page` with the values read.
+    pub(crate) fn scan_tree(
+        &self,
+        tree: &Tree,
+        ns: Option<&str>,
+        start: &str,
+        end: Option<&str>,
+        options: &ScanOptions,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        scan_page(tree, ns, start, end, options)
+            .map(|(k, v)| Ok((k.to_string(), self.load_value(v)?)))
+            .collect()
+    }
+
+    /// Metadata about a key's current value (size, storage location, and the
+    /// commit that last changed it) without reading the value itself.
+    pub fn stat_key(&self, key: &str) -> Result<KeyStat> {
+        let tree = self.current_tree()?;
+        let value = tree
+            .get(key)
+            .ok_or_else(|| IcebergError::KeyNotFound(key.into()))?;
+        let mut stats = self.stat_values(vec![(key.to_string(), value.clone())])?;
+        Ok(stats.remove(0))
+    }
+
+    /// `stat_key` for every key under a prefix, resolving all last-modified
+    /// commits in a single history walk.
+    pub fn stat_prefix(&self, prefix: &str) -> Result<Vec<KeyStat>> {
+        let tree = self.current_tree()?;
+        let values = tree
+            .scan_prefix(prefix)
+            .into_iter()
+            .filter(|(k, _)| namespace::split(k).0.is_none())
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        self.stat_values(values)
+    }
+
+    fn stat_values(&self, values: Vec<(String, TreeValue)>) -> Result<Vec<KeyStat>> {
+        let mut last_commit: HashMap<String, Commit> = HashMap::new();
+        let mut commit = Some(self.head_commit()?);
+        while let Some(c) = commit {
+            if last_commit.len() == values.len() {
+                break;
+            }
+            let parent = match &c.parent {
+                Some(pid) => Some(self.load_commit(pid)?),
+                None => None,
+            };
+            // A parent tree that cannot hold any key still being traced
+            // changed them all; no need to load it.
+            let pending = values
+                .iter()
+                .map(|(key, _)| key.as_str())
+                .filter(|key| !last_commit.contains_key(*key));
+            let parent_tree = match &parent {
+                Some(p) if self.tree_may_contain(&p.tree_root, pending) => {
+                    self.load_tree(&p.tree_root)?
+                }
+                _ => Arc::new(Tree::empty()),
+            };
+            for (key, value) in &values {
+                if !last_commit.contains_key(key) && parent_tree.get(key) != Some(value) {
+                    last_commit.insert(key.clone(), c.clone());
+                }
+            }
+            commit = parent;
+        }
+
+        values
+            .into_iter()
+            .map(|(key, value)| {
+                let last_commit = last_commit
+                    .remove(&key)
+                    .ok_or_else(|| IcebergError::Corruption(format!("no history for {}", key)))?;
+                Ok(KeyStat {
+                    size: value.size(),
+                    inline: value.as_inline().is_some(),
+                    block_hash: value.block_hash().map(String::from),
+                    blob_hash: value.blob_hash().map(String::from),
+                    chunks: match &value {
+                        TreeValue::Chunked { chunks, .. } => chunks.len(),
+                        _ => 0,
+                    },
+                    key,
+                    last_commit,
+                })
+            })
+            .collect()
+    }
+
+    // ── Key Locks ─────────────────────────────────────────────
+
+    /// Take an advisory lock on `key` for `holder`, lasting `ttl`, or renew
+    /// one `holder` already has. Fails with `KeyLocked` while another
+    /// holder's lock is live. Locks are kept in the database, so other
+    /// processes sharing it see them; only writes that ask, such as
+    /// `put_as`, respect them.
+    pub fn lock_key(&self, key: &str, holder: &str, ttl: std::time::Duration) -> Result<KeyLock> {
+        namespace::check_key(key)?;
+        let _writer = self.writer.lock().unwrap();
+        let mut locks = self.load_key_locks()?;
+        let lock = locks.acquire(key, holder, ttl, chrono::Utc::now())?;
+        self.save_key_locks(&locks)?;
+        Ok(lock)
+    }
+
+    /// Release `holder`'s lock on `key`, returning whether it had a live
+    /// one. Fails with `KeyLocked` while another holder's lock is live.
+    pub fn unlock_key(&self, key: &str, holder: &str) -> Result<bool> {
+        let _writer = self.writer.lock().unwrap();
+        let mut locks = self.load_key_locks()?;
+        let held = locks.release(key, holder, chrono::Utc::now())?;
+        self.save_key_locks(&locks)?;
+        Ok(held)
+    }
+
+    /// The live key locks, by key.
+    pub fn key_locks(&self) -> Result<Vec<KeyLock>> {
+        Ok(self.load_key_locks()?.all_live(chrono::Utc::now()))
+    }
+
+    /// Read fresh each time, as another process may have changed them.
+    fn load_key_locks(&self) -> Result<KeyLocks> {
+        match self.backend.read(KEY_LOCKS_FILE)? {
+            Some(data) => Ok(serde_json::from_slice(&data)?),
+            None => Ok(KeyLocks::default()),
+        }
+    }
+
+    fn save_key_locks(&self, locks: &KeyLocks) -> Result<()> {
+        let data = serde_json::to_vec_pretty(locks)?;
+        self.backend.write(KEY_LOCKS_FILE, &data)
+    }
+
+    // ── Namespaces ────────────────────────────────────────────
+
+    /// A handle on the namespace `name`, whose keys, secondary indexes and
+    /// bloom filter are separate from the default namespace's and every
+    /// other's. Namespaces need no creating: one exists once it has a key.
+    pub fn namespace(&self, name: &str) -> Result<Namespace<'_>> {
+        Namespace::new(self, name)
+    }
+
+    /// Namespaces with keys at the current branch HEAD, with their key
+    /// counts.
+    pub fn namespaces(&self) -> Result<BTreeMap<String, usize>> {
+        let tree = self
+            .current_tree()
+            .unwrap_or_else(|_| Arc::new(Tree::empty()));
+        Ok(namespace_counts(&tree))
+    }
+
+    // ── Tenants ───────────────────────────────────────────────
+
+    /// A handle on the tenant `name`: the branches named `<name>/...`, with
+    /// their own HEAD. Tenants share the block store, so identical values
+    /// across tenants are stored once. A tenant exists once it has a branch.
+    pub fn tenant(&self, name: &str) -> Result<Tenant<'_>> {
+        Tenant::new(self, name)
+    }
+
+    /// A handle reading and writing branch `name` without checking it out,
+    /// so HEAD stays where other users of the database left it.
+    pub fn on_branch(&self, name: &str) -> Result<Branch<'_>> {
+        let refs = self.load_refs()?;
+        if !refs.branches.contains_key(name) && refs.head != name {
+            return Err(IcebergError::BranchNotFound(name.into()));
+        }
+        Ok(Branch::new(self, name))
+    }
+
+    /// A read-only view of the current branch HEAD that stays put while
+    /// others write, check out other branches or compact. Pins are held in
+    /// memory, so only compactions through this handle respect them.
+    pub fn snapshot(&self) -> Result<Snapshot<'_>> {
+        self.snapshot_of(Target::Head)
+    }
+
+    /// `snapshot` of the tip of `target`.
+    pub(crate) fn snapshot_of(&self, target: Target) -> Result<Snapshot<'_>> {
+        // Under the writer lock no sweep can collect the tree before it is
+        // pinned.
+        let _writer = self.writer.lock().unwrap();
+        let commit = self.target_commit(target)?;
+        let tree = self.target_tree(target)?;
+        *self
+            .pins
+            .lock()
+            .unwrap()
+            .entry(tree.root_hash.clone())
+            .or_default() += 1;
+        Ok(Snapshot::new(self, commit, tree))
+    }
+
+    /// Release a `snapshot`'s hold on its tree.
+    pub(crate) fn unpin_tree(&self, root: &str) {
+        let mut pins = self.pins.lock().unwrap();
+        if let Some(count) = pins.get_mut(root) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(root);
+            }
+        }
+    }
+
+    /// Names of all tenants: those with a branch or a checked-out HEAD.
+    pub fn tenants(&self) -> Result<Vec<String>> {
+        let refs = self.load_refs()?;
+        let mut names: HashSet<&str> = refs.tenant_heads.keys().map(String::as_str).collect();
+        names.extend(refs.branches.keys().filter_map(|b| tenant::split(b).0));
+        let mut names: Vec<String> = names.into_iter().map(String::from).collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Branches of a tenant by their names within it, including its HEAD
+    /// branch even before its first commit.
+    pub(crate) fn tenant_branches(&self, tenant: &str) -> Result<Vec<String>> {
+        let refs = self.load_refs()?;
+        let head = refs.head_of(Some(tenant));
+        let mut names: Vec<String> = refs
+            .branches
+            .keys()
+            .chain([&head])
+            .filter_map(|b| match tenant::split(b) {
+                (Some(t), name) if t == tenant => Some(name.to_string()),
+                _ => None,
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// Name of a tenant's checked-out branch, within the tenant.
+    pub(crate) fn tenant_head(&self, tenant: &str) -> Result<String> {
+        let head = self.load_refs()?.head_of(Some(tenant));
+        Ok(tenant::split(&head).1.to_string())
+    }
+
+    // ── Version History ───────────────────────────────────────
+
+    /// Get the current branch's HEAD commit, or the tagged commit while
+    /// HEAD is detached.
+    pub fn head_commit(&self) -> Result<Commit> {
+        self.target_commit(Target::Head)
+    }
+
+    /// The commit at the tip of `branch`.
+    pub(crate) fn branch_commit(&self, branch: &str) -> Result<Commit> {
+        self.target_commit(Target::Branch(branch))
+    }
+
+    /// The commit reads through `target` see.
+    fn target_commit(&self, target: Target) -> Result<Commit> {
+        let refs = self.load_refs()?;
+        let commit_id = refs.tip_of(target).ok_or(IcebergError::EmptyDatabase)?;
+        if let Some((commit, _)) = &self.head.read().unwrap().head {
+            if &commit.id == commit_id {
+                return Ok(commit.clone());
+            }
+        }
+        self.load_commit(commit_id)
+    }
+
+    /// Get the full commit log for the current branch (newest first).
+    pub fn log(&self) -> Result<Vec<Commit>> {
+        self.log_of(Target::Head)
+    }
+
+    /// Commits reachable from any branch or tag, each once, newest first
+    /// but always before their parent.
+    pub fn log_all(&self) -> Result<Vec<Commit>> {
+        let refs = self.load_refs()?;
+        let tags = self.tags()?.into_iter().map(|t| t.commit_id);
+        let mut commits: HashMap<String, Commit> = HashMap::new();
+        for head in refs.branches.into_values().chain(tags) {
+            let mut next = Some(head);
+            while let Some(id) = next.filter(|id| !commits.contains_key(id)) {
+                let commit = self.load_commit(&id)?;
+                next = commit.parent.clone();
+                commits.insert(id, commit);
+            }
+        }
+        let commits: Vec<Commit> = commits.into_values().collect();
+        Ok(graph::topological(&commits).into_iter().cloned().collect())
+    }
+
+    /// `log_all` drawn as ASCII lanes, with branch heads and tags named
+    /// beside their commits. See `graph::render`.
+    pub fn log_graph(&self, limit: usize) -> Result<Vec<String>> {
+        Ok(graph::render(&self.log_all()?, &self.ref_labels()?, limit))
+    }
+
+    /// `log_all` as a Graphviz DOT digraph, labelled like `log_graph`. See
+    /// `graph::dot`.
+    pub fn log_dot(&self, limit: usize) -> Result<String> {
+        Ok(graph::dot(&self.log_all()?, &self.ref_labels()?, limit))
+    }
+
+    /// The branches and tags pointing at each commit, by id, as `log_graph`
+    /// names them.
+    fn ref_labels(&self) -> Result<HashMap<String, Vec<String>>> {
+        let refs = self.load_refs()?;
+        let mut labels: HashMap<String, Vec<String>> = HashMap::new();
+        let mut branches: Vec<_> = refs.branches.iter().collect();
+        branches.sort();
+        if let Some(detached) = &refs.detached {
+            labels
+                .entry(detached.commit.clone())
+                .or_default()
+                .push("HEAD".into());
+        }
+        for (name, id) in branches {
+            let label = match *name == refs.head && refs.detached.is_none() {
+                true => format!("HEAD -> {}", name),
+                false => name.clone(),
+            };
+            labels.entry(id.clone()).or_default().push(label);
+        }
+        let mut tags = self.tags()?;
+        tags.sort_by(|a, b| a.name.cmp(&b.name));
+        for tag in tags {
+            let label = format!("tag: {}", tag.name);
+            labels.entry(tag.commit_id).or_default().push(label);
+        }
+        Ok(labels)
+    }
+
+    /// The commit log of the current branch, newest first, loading each
+    /// commit only when the iterator reaches it. Iteration ends after the
+    /// first error.
+    pub fn log_iter(&self) -> Result<impl Iterator<Item = Result<Commit>> + '_> {
+        self.log_iter_of(Target::Head)
+    }
+
+    /// `limit` commits of the log of the current branch after skipping the
+    /// newest `skip`; only those commits are loaded, and the skipped ones
+    /// too unless the commit graph is on.
+    pub fn log_page(&self, limit: usize, skip: usize) -> Result<Vec<Commit>> {
+        let mut next = match self.target_commit(Target::Head) {
+            Ok(head) => Some(head.id),
+            Err(IcebergError::EmptyDatabase) => None,
+            Err(e) => return Err(e),
+        };
+        for _ in 0..skip {
+            let Some(id) = next else { break };
+            next = self.commit_entry(&id)?.parent;
+        }
+        let mut page = Vec::new();
+        while let Some(id) = next.filter(|_| page.len() < limit) {
+            let commit = self.load_commit(&id)?;
+            next = commit.parent.clone();
+            page.push(commit);
+        }
+        Ok(page)
+    }
+
+    /// The commit log of `target`, newest first.
+    pub(crate) fn log_of(&self, target: Target) -> Result<Vec<Commit>> {
+        self.log_iter_of(target)?.collect()
+    }
+
+    /// `log_iter` for `target`.
+    pub(crate) fn log_iter_of(
+        &self,
+        target: Target,
+    ) -> Result<impl Iterator<Item = Result<Commit>> + '_> {
+        let head = match self.target_commit(target) {
+            Ok(c) => Some(Ok(c)),
+            Err(IcebergError::EmptyDatabase) => None,
+            Err(e) => return Err(e),
+        };
+        Ok(std::iter::successors(head, move |prev| match prev {
+            Ok(commit) => commit.parent.as_ref().map(|id| self.load_commit(id)),
+            Err(_) => None,
+        }))
+    }
+
+    /// Get a tree at a specific commit.
+    pub fn tree_at(&self, commit_id: &str) -> Result<Arc<Tree>> {
+        let commit = self.load_commit(commit_id)?;
+        self.load_tree(&commit.tree_root)
+    }
+
+    /// Load a commit by id.
+    pub fn get_commit(&self, commit_id: &str) -> Result<Commit> {
+        self.load_commit(commit_id)
+    }
+
+    /// Get a value at a specific version.
+    pub fn get_at(&self, key: &str, commit_id: &str) -> Result<Vec<u8>> {
+        let commit = self.load_commit(commit_id)?;
+        if !self.tree_may_contain(&commit.tree_root, [key]) {
+            return Err(IcebergError::KeyNotFound(key.into()));
+        }
+        let tree = self.load_tree(&commit.tree_root)?;
+        match tree.get(key) {
+            Some(v) => self.load_value(v),
+            None => Err(IcebergError::KeyNotFound(key.into())),
+        }
+    }
+
+    /// Resolve `HEAD`, a branch name, a tag name, a commit id or a unique
+    /// prefix of one (at least `MIN_ID_PREFIX` characters) to a commit id.
+    pub fn resolve_ref(&self, spec: &str) -> Result<String> {
+        self.resolve_ref_in(None, spec)
+    }
+
+    /// `resolve_ref` with `HEAD` and branch names taken from a tenant, or
+    /// globally for `None`.
+    pub(crate) fn resolve_ref_in(&self, tenant: Option<&str>, spec: &str) -> Result<String> {
+        let refs = self.load_refs()?;
+        if spec == "HEAD" {
+            let target = tenant.map_or(Target::Head, Target::Tenant);
+            return self.target_commit(target).map(|c| c.id);
+        }
+        if let Some(id) = refs.branches.get(&tenant::scoped(tenant, spec)) {
+            return Ok(id.clone());
+        }
+        if let Some(tag) = self.load_tag_by_name(spec)? {
+            return Ok(tag.commit_id);
+        }
+        match self.load_commit(spec) {
+            Err(IcebergError::CommitNotFound(_))
+                if spec.len() >= MIN_ID_PREFIX && spec.bytes().all(|b| b.is_ascii_hexdigit()) =>
+            {
+                let ids = self.backend.list(COMMITS_DIR)?;
+                let mut matches = ids.into_iter().filter(|id| id.starts_with(spec));
+                match (matches.next(), matches.next()) {
+                    (Some(id), None) => Ok(id),
+                    (Some(_), Some(_)) => Err(IcebergError::CommitNotFound(format!(
+                        "{} (ambiguous prefix)",
+                        spec
+                    ))),
+                    (None, _) => Err(IcebergError::CommitNotFound(spec.into())),
+                }
+            }
+            result => result.map(|c| c.id),
+        }
+    }
+
+    /// Whether commit `ancestor` is reachable from commit `descendant` by
+    /// following parents. A commit counts as its own ancestor.
+    ///
+    /// With the commit graph on, the walk stops once it passes below the
+    /// generation of `ancestor` instead of running down to a root.
+    pub fn is_ancestor(&self, ancestor: &str, descendant: &str) -> Result<bool> {
+        self.commit_entry(ancestor)?;
+        let floor = self.commit_generation(ancestor);
+        let mut next = Some(descendant.to_string());
+        while let Some(id) = next {
+            if id == ancestor {
+                return Ok(true);
+            }
+            if floor
+                .zip(self.commit_generation(&id))
+                .is_some_and(|(f, g)| g <= f)
+            {
+                return Ok(false);
+            }
+            next = self.commit_entry(&id)?.parent;
+        }
+        Ok(false)
+    }
+
+    /// The newest commit that both `a` and `b` descend from, or `None` if
+    /// their histories never meet.
+    ///
+    /// With the commit graph on, generations tell which side to step back
+    /// so only the commits above the merge base are visited.
+    pub fn merge_base(&self, a: &str, b: &str) -> Result<Option<String>> {
+        self.commit_entry(a)?;
+        self.commit_entry(b)?;
+        let generations = self.commit_generation(a).zip(self.commit_generation(b));
+        let (mut a, mut b) = (Some(a.to_string()), Some(b.to_string()));
+        if let Some((mut ga, mut gb)) = generations {
+            while let (Some(x), Some(y)) = (&a, &b) {
+                if x == y {
+                    return Ok(a);
+                }
+                // Generations drop by one per parent.
+                if ga >= gb {
+                    a = self.commit_entry(x)?.parent;
+                    ga -= 1;
+                } else {
+                    b = self.commit_entry(y)?.parent;
+                    gb -= 1;
+                }
+            }
+            return Ok(None);
+        }
+        let mut ancestors = HashSet::new();
+        while let Some(id) = a {
+            a = self.commit_entry(&id)?.parent;
+            ancestors.insert(id);
+        }
+        while let Some(id) = b {
+            if ancestors.contains(&id) {
+                return Ok(Some(id));
+            }
+            b = self.commit_entry(&id)?.parent;
+        }
+        Ok(None)
+    }
+
+    /// Commits whose parent is `commit_id`, oldest first. Every stored
+    /// commit counts, including ones no branch or tag reaches any more
+    /// until compaction deletes them.
+    pub fn children(&self, commit_id: &str) -> Result<Vec<Commit>> {
+        self.load_commit(commit_id)?;
+        let ids: Vec<String> =
+            self.with_children(|index| index.children(commit_id).cloned().collect())?;
+        let mut commits = ids
+            .iter()
+            .map(|id| self.load_commit(id))
+            .collect::<Result<Vec<_>>>()?;
+        commits.sort_by(|a, b| (a.timestamp, &a.id).cmp(&(b.timestamp, &b.id)));
+        Ok(commits)
+    }
+
+    /// Commits with `commit_id` in their history, as `children` counts
+    /// them, newest first but always before their parent.
+    pub fn descendants(&self, commit_id: &str) -> Result<Vec<Commit>> {
+        self.load_commit(commit_id)?;
+        let ids = self.with_children(|index| {
+            let mut found = Vec::new();
+            let mut pending = vec![commit_id.to_string()];
+            while let Some(id) = pending.pop() {
+                for child in index.children(&id) {
+                    found.push(child.clone());
+                    pending.push(child.clone());
+                }
+            }
+            found
+        })?;
+        let commits = ids
+            .iter()
+            .map(|id| self.load_commit(id))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(graph::topological(&commits).into_iter().cloned().collect())
+    }
+
+    /// Diff between two commits.
+    pub fn diff(&self, commit_a: &str, commit_b: &str) -> Result<TreeDiff> {
+        let tree_a = self.tree_at(commit_a)?;
+        let tree_b = self.tree_at(commit_b)?;
+        Ok(tree_a.diff(&tree_b))
+    }
+
+    /// `diff` summarised per key prefix (keys up to their first
+    /// `delimiter`, as in `size_report`), in prefix order.
+    pub fn diff_stat(
+        &self,
+        commit_a: &str,
+        commit_b: &str,
+        delimiter: char,
+    ) -> Result<Vec<(String, DiffStat)>> {
+        let tree_a = self.tree_at(commit_a)?;
+        let tree_b = self.tree_at(commit_b)?;
+        let diff = tree_a.diff(&tree_b);
+        let size = |tree: &Tree, key: &str| tree.get(key).map_or(0, TreeValue::size);
+        let added = diff.added.iter().map(|key| {
+            let bytes_added = size(&tree_b, key);
+            (
+                key,
+                DiffStat {
+                    added: 1,
+                    bytes_added,
+                    ..Default::default()
+                },
+            )
+        });
+        let removed = diff.removed.iter().map(|key| {
+            let bytes_removed = size(&tree_a, key);
+            (
+                key,
+                DiffStat {
+                    removed: 1,
+                    bytes_removed,
+                    ..Default::default()
+                },
+            )
+        });
+        let modified = diff.modified.iter().map(|key| {
+            let (bytes_added, bytes_removed) = (size(&tree_b, key), size(&tree_a, key));
+            let stat = DiffStat {
+                modified: 1,
+                bytes_added,
+                bytes_removed,
+                ..Default::default()
+            };
+            (key, stat)
+        });
+        let mut groups: BTreeMap<String, DiffStat> = BTreeMap::new();
+        for (key, stat) in added.chain(removed).chain(modified) {
+            *groups.entry(key_prefix(key, delimiter)).or_default() += stat;
+        }
+        Ok(groups.into_iter().collect())
+    }
+
+    /// `diff` with the values of each changed key on both sides, in key
+    /// order.
+    pub fn diff_values(&self, commit_a: &str, commit_b: &str) -> Result<Vec<ValueChange>> {
+        let tree_a = self.tree_at(commit_a)?;
+        let tree_b = self.tree_at(commit_b)?;
+        let diff = tree_a.diff(&tree_b);
+        let mut keys: Vec<String> = diff
+            .added
+            .into_iter()
+            .chain(diff.removed)
+            .chain(diff.modified)
+            .collect();
+        keys.sort();
+        let value = |tree: &Tree, key: &str| tree.get(key).map(|v| self.load_value(v)).transpose();
+        keys.into_iter()
+            .map(|key| {
+                Ok(ValueChange {
+                    old: value(&tree_a, &key)?,
+                    new: value(&tree_b, &key)?,
+                    key,
+                })
+            })
+            .collect()
+    }
+
+    /// The changes a commit introduced: its tree diffed against its
+    /// parent's, or against an empty tree for a root commit.
+    pub fn commit_diff(&self, commit_id: &str) -> Result<TreeDiff> {
+        let (parent, tree) = self.commit_trees(commit_id)?;
+        Ok(parent.diff(&tree))
+    }
+
+    /// Counts and sizes of the changes a commit introduced, as
+    /// `commit_diff`.
+    pub fn commit_stat(&self, commit_id: &str) -> Result<DiffStat> {
+        let (parent, tree) = self.commit_trees(commit_id)?;
+        Ok(parent.diff_stat(&tree))
+    }
+
+    /// The trees of a commit's parent (empty for a root commit) and itself.
+    fn commit_trees(&self, commit_id: &str) -> Result<(Arc<Tree>, Arc<Tree>)> {
+        let commit = self.load_commit(commit_id)?;
+        let parent = match &commit.parent {
+            Some(id) => self.tree_at(id)?,
+            None => Arc::new(Tree::empty()),
+        };
+        Ok((parent, self.load_tree(&commit.tree_root)?))
+    }
+
+    // ── Branching ─────────────────────────────────────────────
+
+    /// The tag HEAD is detached at, if it is (see `checkout`).
+    pub fn detached_head(&self) -> Result<Option<String>> {
+        Ok(self.load_refs()?.detached.map(|d| d.tag))
+    }
+
+    /// Get the current branch name; while HEAD is detached, the branch
+    /// checked out before.
+    pub fn current_branch(&self) -> Result<String> {
+        Ok(self.load_refs()?.head)
+    }
+
+    /// List all branches.
+    pub fn branches(&self) -> Result<Vec<String>> {
+        let refs = self.load_refs()?;
+        let mut names: Vec<_> = refs.branches.keys().cloned().collect();
+        names.sort();
+        // Include head branch even if no commits
+        if !names.contains(&refs.head) {
+            names.push(refs.head);
+            names.sort();
+        }
+        Ok(names)
+    }
+
+    /// Create a new branch from the current HEAD.
+    pub fn create_branch(&self, name: &str) -> Result<()> {
+        self.create_branch_in(None, name)
+    }
+
+    /// `create_branch` within a tenant, from its HEAD, or globally for
+    /// `None`.
+    pub(crate) fn create_branch_in(&self, tenant: Option<&str>, name: &str) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        let mut refs = self.load_refs()?;
+        let full = tenant::scoped(tenant, name);
+        if refs.branches.contains_key(&full) {
+            return Err(IcebergError::BranchExists(name.into()));
+        }
+        let head = tenant.map_or(Target::Head, Target::Tenant);
+        if let Some(head_id) = refs.tip_of(head).cloned() {
+            refs.branches.insert(full, head_id);
+        }
+        // If no commits yet, branch will be created on first commit
+        self.save_refs(&refs)
+    }
+
+    /// Create a new branch at a commit, branch or tag (see `resolve_ref`),
+    /// e.g. to bring back a commit found by `lost_found`.
+    pub fn create_branch_at(&self, name: &str, spec: &str) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        let commit_id = self.resolve_ref(spec)?;
+        let mut refs = self.load_refs()?;
+        if refs.branches.contains_key(name) {
+            return Err(IcebergError::BranchExists(name.into()));
+        }
+        refs.branches.insert(name.into(), commit_id.clone());
+        self.save_refs(&refs)?;
+        let tree = self.tree_at(&commit_id)?;
+        self.cover_bloom(&tree)
+    }
+
+    /// Switch to a branch, or detach HEAD at a tag: reads then see the
+    /// tagged commit and writes through HEAD fail until a branch is checked
+    /// out again. Branch names win over tag names.
+    pub fn checkout(&self, name: &str) -> Result<()> {
+        self.checkout_in(None, name)
+    }
+
+    /// `checkout` of a tenant's branch, moving only that tenant's HEAD, or
+    /// globally for `None`.
+    pub(crate) fn checkout_in(&self, tenant: Option<&str>, name: &str) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        let mut refs = self.load_refs()?;
+        let full = tenant::scoped(tenant, name);
+        // Allow checkout even if branch has no commits yet
+        let exists = refs.branches.contains_key(&full) || refs.head_of(tenant) == full;
+        if !exists {
+            let tag = match tenant {
+                None => self.load_tag_by_name(name)?,
+                Some(_) => None,
+            };
+            let Some(tag) = tag else {
+                return Err(IcebergError::BranchNotFound(name.into()));
+            };
+            refs.detached = Some(DetachedHead {
+                tag: tag.name,
+                commit: tag.commit_id.clone(),
+            });
+            self.save_refs(&refs)?;
+            let tree = self.tree_at(&tag.commit_id)?;
+            return self.cover_bloom(&tree);
+        }
+        match tenant {
+            None => {
+                refs.head = name.into();
+                refs.detached = None;
+            }
+            Some(t) => {
+                refs.tenant_heads.insert(t.into(), name.into());
+            }
+        }
+        self.save_refs(&refs)?;
+        match refs.branches.contains_key(&full) {
+            true => self.cover_bloom(&*self.branch_tree(&full)?),
+            false => Ok(()),
+        }
+    }
+
+    /// Delete a branch (cannot delete current branch).
+    pub fn delete_branch(&self, name: &str) -> Result<()> {
+        self.delete_branch_in(None, name)
+    }
+
+    /// `delete_branch` within a tenant, or globally for `None`.
+    pub(crate) fn delete_branch_in(&self, tenant: Option<&str>, name: &str) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        // Commits dropped from history must not look unapplied to WAL replay.
+        self.flush_dirty()?;
+        let mut refs = self.load_refs()?;
+        let full = tenant::scoped(tenant, name);
+        if refs.head_of(tenant) == full {
+            return Err(IcebergError::Corruption(
+                "cannot delete current branch".into(),
+            ));
+        }
+        if refs.branches.remove(&full).is_none() {
+            return Err(IcebergError::BranchNotFound(name.into()));
+        }
+        self.save_refs(&refs)
+    }
+
+    /// Merge another branch into the current branch (fast-forward or snapshot merge).
+    pub fn merge(&self, source_branch: &str, message: Option<&str>) -> Result<Commit> {
+        self.merge_in(None, source_branch, message)
+    }
+
+    /// `merge` between branches of a tenant, or globally for `None`.
+    pub(crate) fn merge_in(
+        &self,
+        tenant: Option<&str>,
+        source_branch: &str,
+        message: Option<&str>,
+    ) -> Result<Commit> {
+        let _writer = self.writer.lock().unwrap();
+        let (branch, _, merged_tree) = self.merged_tree_in(tenant, source_branch)?;
+        let msg = message
+            .map(String::from)
+            .unwrap_or_else(|| format!("merge branch '{}'", source_branch));
+        self.commit_tree(&branch, &merged_tree, &msg)
+    }
+
+    /// What `merge` would change on the current branch, without committing.
+    pub fn merge_preview(&self, source_branch: &str) -> Result<TreeDiff> {
+        let (_, current, merged) = self.merged_tree_in(None, source_branch)?;
+        Ok(current.diff(&merged))
+    }
+
+    /// The branch `merge_in` writes to, its tree, and the merged tree.
+    fn merged_tree_in(
+        &self,
+        tenant: Option<&str>,
+        source_branch: &str,
+    ) -> Result<(String, Arc<Tree>, Tree)> {
+        let refs = self.load_refs()?;
+        let source_id = refs
+            .branches
+            .get(&tenant::scoped(tenant, source_branch))
+            .ok_or_else(|| IcebergError::BranchNotFound(source_branch.into()))?
+            .clone();
+
+        let source_tree = self
+            .load_commit(&source_id)
+            .and_then(|c| self.load_tree(&c.tree_root))?;
+        let branch = refs.write_branch(tenant.map_or(Target::Head, Target::Tenant))?;
+        let current_tree = self
+            .branch_tree(&branch)
+            .unwrap_or_else(|_| Arc::new(Tree::empty()));
+
+        // Simple merge: apply all entries from source on top of current
+        let mut merged = current_tree.entries.clone();
+        for (k, v) in &source_tree.entries {
+            merged.insert(k.clone(), v.clone());
+        }
+
+        let merged_tree = Tree::from_entries(merged);
+        Ok((branch, current_tree, merged_tree))
+    }
+
+    // ── Tags ──────────────────────────────────────────────────
+
+    /// Create a tag pointing to a specific commit (or current HEAD). With a
+    /// message the tag is annotated, otherwise lightweight.
+    pub fn create_tag(
+        &self,
+        name: &str,
+        commit_id: Option<&str>,
+        message: Option<&str>,
+    ) -> Result<Tag> {
+        self.set_tag(name, commit_id, message, false)
+    }
+
+    /// `create_tag`, replacing a tag of the same name if there is one. The
+    /// move is recorded in the tag log.
+    pub fn move_tag(
+        &self,
+        name: &str,
+        commit_id: Option<&str>,
+        message: Option<&str>,
+    ) -> Result<Tag> {
+        self.set_tag(name, commit_id, message, true)
+    }
+
+    fn set_tag(
+        &self,
+        name: &str,
+        commit_id: Option<&str>,
+        message: Option<&str>,
+        force: bool,
+    ) -> Result<Tag> {
+        let _writer = self.writer.lock().unwrap();
+        let old = self.load_tag_by_name(name)?;
+        if old.is_some() && !force {
+            return Err(IcebergError::Corruption(format!(
+                "tag already exists: {}",
+                name
+            )));
+        }
+        let cid = match commit_id {
+            Some(id) => {
+                // Verify commit exists
+                self.load_commit(id)?;
+                id.to_string()
+            }
+            None => self.head_commit()?.id,
+        };
+        let tag = Tag::new(name.into(), cid, message.map(String::from));
+        self.save_tag(&tag)?;
+        if let Some(old) = &old {
+            self.backend.delete(&backend::key(TAGS_DIR, &old.id))?;
+        }
+        self.log_tag(name, old.map(|t| t.commit_id), Some(&tag.commit_id))?;
+        Ok(tag)
+    }
+
+    /// Changes to the tag `name`, newest first, including those from
+    /// before it was last deleted.
+    pub fn tag_log(&self, name: &str) -> Result<Vec<TagLogEntry>> {
+        let data = self.backend.read(TAG_LOG_FILE)?.unwrap_or_default();
+        let mut entries: Vec<TagLogEntry> = data
+            .split(|&b| b == b'\n')
+            // A torn last line from a crash mid-append is skipped.
+            .filter_map(|line| serde_json::from_slice::<TagLogEntry>(line).ok())
+            .filter(|e| e.name == name)
+            .collect();
+        entries.reverse();
+        Ok(entries)
+    }
+
+    /// Record a change to a tag in the tag log.
+    fn log_tag(&self, name: &str, old: Option<String>, new: Option<&str>) -> Result<()> {
+        let entry = TagLogEntry {
+            name: name.to_string(),
+            old,
+            new: new.map(String::from),
+            at: chrono::Utc::now(),
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        self.backend.append(TAG_LOG_FILE, &line)
+    }
+
+    /// Delete a tag object and log it.
+    fn remove_tag(&self, tag: &Tag) -> Result<()> {
+        self.backend.delete(&backend::key(TAGS_DIR, &tag.id))?;
+        self.log_tag(&tag.name, Some(tag.commit_id.clone()), None)
+    }
+
+    /// List all tags.
+    pub fn tags(&self) -> Result<Vec<Tag>> {
+        let mut tags = Vec::new();
+        for name in self.backend.list(TAGS_DIR)? {
+            if let Some(data) = self.backend.read(&backend::key(TAGS_DIR, &name))? {
+                let tag: Tag = serde_json::from_slice(&data)?;
+                tags.push(tag);
+            }
+        }
+        tags.sort_by_key(|t| std::cmp::Reverse(t.created_at));
+        Ok(tags)
+    }
+
+    /// Tags whose names match a glob such as `v*`, highest semantic
+    /// version first; tags not named like a version follow, newest first.
+    pub fn tags_matching(&self, pattern: &str) -> Result<Vec<Tag>> {
+        let pattern = KeyPattern::glob(pattern)?;
+        let mut tags: Vec<Tag> = self
+            .tags()?
+            .into_iter()
+            .filter(|t| pattern.matches(&t.name))
+            .collect();
+        tag::sort_by_version(&mut tags);
+        Ok(tags)
+    }
+
+    /// The tag with the highest semantic version among those matching a
+    /// glob, pre-releases included, or `None` if no such tag is named like
+    /// a version.
+    pub fn latest_tag(&self, pattern: &str) -> Result<Option<Tag>> {
+        Ok(self
+            .tags_matching(pattern)?
+            .into_iter()
+            .next()
+            .filter(|t| t.version().is_some()))
+    }
+
+    /// Get a tag by name.
+    pub fn get_tag(&self, name: &str) -> Result<Tag> {
+        self.load_tag_by_name(name)?
+            .ok_or_else(|| IcebergError::Corruption(format!("tag not found: {}", name)))
+    }
+
+    /// Sign the tag `name` with `key`, replacing any earlier signature.
+    /// The signature covers the tag's name and commit, so moving the tag
+    /// or rewriting the commit's history leaves it failing to verify.
+    pub fn sign_tag(&self, name: &str, key: &SigningKey) -> Result<Tag> {
+        let _writer = self.writer.lock().unwrap();
+        let mut tag = self.get_tag(name)?;
+        tag.sign(key);
+        self.save_tag(&tag)?;
+        Ok(tag)
+    }
+
+    /// Check the signature of the tag `name`, and that it was made with
+    /// `trusted` if given. Returns the tag and the key it was signed with.
+    pub fn verify_tag(
+        &self,
+        name: &str,
+        trusted: Option<&VerifyingKey>,
+    ) -> Result<(Tag, VerifyingKey)> {
+        let tag = self.get_tag(name)?;
+        let key = tag.verify(trusted)?;
+        Ok((tag, key))
+    }
+
+    /// Delete a tag by name.
+    pub fn delete_tag(&self, name: &str) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        let tag = self.get_tag(name)?;
+        self.remove_tag(&tag)
+    }
+
+    // ── Cherry-pick ───────────────────────────────────────────
+
+    /// Cherry-pick a commit onto the current branch.
+    /// Applies the diff introduced by the given commit.
+    pub fn cherry_pick(&self, commit_id: &str, message: Option<&str>) -> Result<Commit> {
+        let _writer = self.writer.lock().unwrap();
+        let (_, picked) = self.cherry_picked_tree(commit_id)?;
+        let msg = message
+            .map(String::from)
+            .unwrap_or_else(|| format!("cherry-pick {}", &commit_id[..8.min(commit_id.len())]));
+        self.commit_tree(
+            &self.load_refs()?.write_branch(Target::Head)?,
+            &picked,
+            &msg,
+        )
+    }
+
+    /// What `cherry_pick` would change on the current branch, without
+    /// committing.
+    pub fn cherry_pick_preview(&self, commit_id: &str) -> Result<TreeDiff> {
+        let (current, picked) = self.cherry_picked_tree(commit_id)?;
+        Ok(current.diff(&picked))
+    }
+
+    /// The current tree and the tree with `commit_id`'s changes applied.
+    fn cherry_picked_tree(&self, commit_id: &str) -> Result<(Tree, Tree)> {
+        let commit = self.load_commit(commit_id)?;
+        let commit_tree = self.load_tree(&commit.tree_root)?;
+
+        // Get the parent tree (empty if no parent)
+        let parent_tree = match &commit.parent {
+            Some(pid) => {
+                let pc = self.load_commit(pid)?;
+                self.load_tree(&pc.tree_root)?
+            }
+            None => Arc::new(Tree::empty()),
+        };
+
+        // Compute the diff introduced by this commit
+        let diff = parent_tree.diff(&commit_tree);
+
+        // Apply the diff to current tree
+        let base = self
+            .current_tree()
+            .map(|t| Tree::clone(&t))
+            .unwrap_or_else(|_| Tree::empty());
+        let mut current = base.clone();
+        for key in &diff.added {
+            if let Some(val) = commit_tree.get(key) {
+                current = current.insert(key.clone(), val.clone());
+            }
+        }
+        for key in &diff.modified {
+            if let Some(val) = commit_tree.get(key) {
+                current = current.insert(key.clone(), val.clone());
+            }
+        }
+        for key in &diff.removed {
+            if current.contains_key(key) {
+                current = current.delete(key);
+            }
+        }
+        Ok((base, current))
+    }
+
+    // ── Rebase ─────────────────────────────────────────────────
+
+    /// Rebase the current branch onto another branch.
+    /// Takes all commits unique to the current branch and replays them
+    /// on top of the target branch's HEAD.
+    pub fn rebase(&self, onto_branch: &str) -> Result<Vec<Commit>> {
+        self.rebase_with_progress(onto_branch, |_, _| {})
+    }
+
+    /// `rebase`, calling `progress(replayed, total)` after each commit.
+    pub fn rebase_with_progress(
+        &self,
+        onto_branch: &str,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<Vec<Commit>> {
+        let _writer = self.writer.lock().unwrap();
+        // Commits dropped from history must not look unapplied to WAL replay.
+        self.flush_dirty()?;
+        let current_branch = self.load_refs()?.write_branch(Target::Head)?;
+        let (onto_id, unique_commits) = self.rebase_plan(onto_branch)?;
+
+        let mut ticker = Ticker::new(unique_commits.len(), 1, &mut progress);
+        if unique_commits.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Switch to onto_branch's state as our new base
+        let onto_tree = self
+            .load_commit(&onto_id)
+            .and_then(|c| self.load_tree(&c.tree_root))?;
+        let mut current_tree = Tree::clone(&onto_tree);
+        let mut parent_id = Some(onto_id);
+        let mut new_commits = Vec::new();
+
+        // Replay each unique commit
+        for old_commit in &unique_commits {
+            let old_tree = self.load_tree(&old_commit.tree_root)?;
+            let old_parent_tree = match &old_commit.parent {
+                Some(pid) => self
+                    .load_commit(pid)
+                    .and_then(|c| self.load_tree(&c.tree_root))
+                    .unwrap_or_else(|_| Arc::new(Tree::empty())),
+                None => Arc::new(Tree::empty()),
+            };
+
+            // Compute the diff this commit introduced
+            let diff = old_parent_tree.diff(&old_tree);
+
+            // Apply the diff to current_tree in one go, so the tree is
+            // hashed once per commit rather than once per key
+            let puts = diff.added.iter().chain(&diff.modified).filter_map(|key| {
+                old_tree
+                    .get(key)
+                    .map(|val| (key.clone(), Some(val.clone())))
+            });
+            let deletes = diff.removed.iter().map(|key| (key.clone(), None));
+            current_tree = current_tree.apply(puts.chain(deletes));
+
+            // Create a new commit with the rebased tree
+            self.save_tree(&current_tree)?;
+            let new_commit = Commit::new(
+                parent_id,
+                current_tree.root_hash.clone(),
+                old_commit.message.clone(),
+            );
+            self.save_commit(&new_commit)?;
+            parent_id = Some(new_commit.id.clone());
+            new_commits.push(new_commit);
+            ticker.tick();
+        }
+
+        // Update the current branch ref to point to the last new commit
+        if let Some(last) = new_commits.last() {
+            let mut refs = self.load_refs()?;
+            refs.branches.insert(current_branch, last.id.clone());
+            self.save_refs(&refs)?;
+            self.cover_bloom(&current_tree)?;
+        }
+
+        Ok(new_commits)
+    }
+
+    /// The commits `rebase` would replay onto `onto_branch`, oldest first,
+    /// without replaying them.
+    pub fn rebase_preview(&self, onto_branch: &str) -> Result<Vec<Commit>> {
+        Ok(self.rebase_plan(onto_branch)?.1)
+    }
+
+    /// The tip of `onto_branch` and the commits of the current branch to
+    /// replay on it, oldest first.
+    fn rebase_plan(&self, onto_branch: &str) -> Result<(String, Vec<Commit>)> {
+        let refs = self.load_refs()?;
+        if refs.head == onto_branch {
+            return Err(IcebergError::Corruption(
+                "cannot rebase a branch onto itself".into(),
+            ));
+        }
+
+        let onto_id = refs
+            .branches
+            .get(onto_branch)
+            .ok_or_else(|| IcebergError::BranchNotFound(onto_branch.into()))?
+            .clone();
+
+        // Collect commits unique to the current branch (stop at fork point)
+        let current_log = self.log()?;
+        let fork = match current_log.first() {
+            Some(head) => self.merge_base(&onto_id, &head.id)?,
+            None => None,
+        };
+        let mut unique_commits: Vec<Commit> = current_log
+            .into_iter()
+            .take_while(|c| Some(&c.id) != fork.as_ref())
+            .collect();
+        unique_commits.reverse(); // oldest first for replay
+        Ok((onto_id, unique_commits))
+    }
+
+    // ── Secondary Indexes ─────────────────────────────────────
+
+    /// Create a secondary index on a JSON field.
+    pub fn create_index(&self, name: &str, field_path: &str) -> Result<()> {
+        self.create_index_with(name, field_path, &IndexOptions::default())
+    }
+
+    /// Create a secondary index on a JSON field with the given settings,
+    /// e.g. a typed index for numeric or date range queries.
+    pub fn create_index_with(
+        &self,
+        name: &str,
+        field_path: &str,
+        options: &IndexOptions,
+    ) -> Result<()> {
+        namespace::check_key(name)?;
+        self.create_index_raw(name, field_path, options)
+    }
+
+    /// `create_index_with`, calling `progress(indexed, total)` now and then
+    /// while the existing values are indexed.
+    ///
+    /// Writers are not blocked meanwhile: the index is built from the tree
+    /// at the current HEAD, then, under the writer lock, brought up to date
+    /// with the changes committed since and made live.
+    pub fn create_index_with_progress(
+        &self,
+        name: &str,
+        field_path: &str,
+        options: &IndexOptions,
+        progress: impl FnMut(usize, usize),
+    ) -> Result<()> {
+        self.build_index_raw(name, field_path, options, progress)
+    }
+
+    /// `create_index_with_progress` on another thread. The returned handle
+    /// reports progress and waits for the index to go live.
+    pub fn create_index_background(
+        self: &Arc<Self>,
+        name: &str,
+        field_path: &str,
+        options: &IndexOptions,
+    ) -> Result<IndexBuild> {
+        self.indexes().lock().unwrap().check_new_name(name)?;
+        let db = Arc::clone(self);
+        let (name, field_path, options) =
+            (name.to_string(), field_path.to_string(), options.clone());
+        let progress = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+        let counters = Arc::clone(&progress);
+        let handle = std::thread::Builder::new()
+            .name(format!("iceberg-index-{}", namespace::split(&name).1))
+            .spawn(move || {
+                db.build_index_raw(&name, &field_path, &options, |done, total| {
+                    counters.0.store(done, Ordering::Relaxed);
+                    counters.1.store(total, Ordering::Relaxed);
+                })
+            })?;
+        Ok(IndexBuild { progress, handle })
+    }
+
+    /// `create_index_with` under a name that may be namespaced; such an
+    /// index only covers its own namespace's keys.
+    pub(crate) fn create_index_raw(
+        &self,
+        name: &str,
+        field_path: &str,
+        options: &IndexOptions,
+    ) -> Result<()> {
+        self.build_index_raw(name, field_path, options, |_, _| {})
+    }
+
+    /// Build a secondary index without holding the writer lock until it
+    /// only has to catch up; see `create_index_with_progress`.
+    pub(crate) fn build_index_raw(
+        &self,
+        name: &str,
+        field_path: &str,
+        options: &IndexOptions,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<()> {
+        self.indexes().lock().unwrap().check_new_name(name)?;
+        let ns = namespace::split(name).0;
+        let mut index =
+            SecondaryIndex::with_options(name.to_string(), field_path.to_string(), options);
+        let snapshot = self
+            .current_tree()
+            .unwrap_or_else(|_| Arc::new(Tree::empty()));
+        let keys: Vec<_> = snapshot
+            .entries
+            .iter()
+            .filter_map(|(k, v)| match namespace::split(k) {
+                (found, key) if found == ns => Some((key, v)),
+                _ => None,
+            })
+            .collect();
+        let mut ticker = Ticker::new(keys.len(), PROGRESS_STEP, &mut progress);
+        for (key, value) in &keys {
+            index.index_entry(key, &self.load_value(value)?);
+            ticker.tick();
+        }
+
+        let _writer = self.writer.lock().unwrap();
+        let current = self
+            .current_tree()
+            .unwrap_or_else(|_| Arc::new(Tree::empty()));
+        let changes = snapshot.diff(&current);
+        for key in changes
+            .added
+            .iter()
+            .chain(&changes.modified)
+            .chain(&changes.removed)
+        {
+            let (found, local) = namespace::split(key);
+            if found != ns {
+                continue;
+            }
+            match current.get(key) {
+                Some(value) => index.index_entry(local, &self.load_value(value)?),
+                None => index.remove_key(local),
+            }
+        }
+        if let Some((value, keys)) = index.duplicate() {
+            return Err(IcebergError::UniqueViolation {
+                index: namespace::split(name).1.to_string(),
+                value,
+                key: keys.join(", "),
+            });
+        }
+        self.indexes().lock().unwrap().add_index(index)?;
+        self.save_indexes()
+    }
+
+    /// Rebuild index `name` from the current tree, if there is one.
+    fn rebuild_from_head(&self, indexes: &mut IndexManager, name: &str) -> Result<()> {
+        if let Ok(tree) = self.current_tree() {
+            let entries = self.namespace_values(&tree, namespace::split(name).0, |_, _| {})?;
+            indexes.rebuild_index(name, &entries);
+        }
+        Ok(())
+    }
+
+    /// The stored keys and values of namespace `ns` in `tree`, calling
+    /// `progress(read, total)` every `PROGRESS_STEP` values and once at the
+    /// end.
+    fn namespace_values(
+        &self,
+        tree: &Tree,
+        ns: Option<&str>,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        let keys: Vec<_> = tree
+            .entries
+            .iter()
+            .filter(|(k, _)| namespace::split(k).0 == ns)
+            .collect();
+        let mut entries = Vec::with_capacity(keys.len());
+        let mut ticker = Ticker::new(keys.len(), PROGRESS_STEP, &mut progress);
+        for (key, value) in &keys {
+            entries.push((key.to_string(), self.load_value(value)?));
+            ticker.tick();
+        }
+        Ok(entries)
+    }
+
+    /// Rebuild an index of the default namespace from the current tree,
+    /// or all of them for `None`, dropping entries that went stale or were
+    /// damaged. Returns the names of the rebuilt indexes.
+    pub fn rebuild_index(&self, name: Option<&str>) -> Result<Vec<String>> {
+        self.rebuild_index_with(name, |_, _| {})
+    }
+
+    /// `rebuild_index`, calling `progress(read, total)` now and then while
+    /// the values are read.
+    pub fn rebuild_index_with(
+        &self,
+        name: Option<&str>,
+        progress: impl FnMut(usize, usize),
+    ) -> Result<Vec<String>> {
+        self.rebuild_indexes_in(None, name, progress)
+    }
+
+    /// `rebuild_index_with` for the indexes of namespace `ns`.
+    pub(crate) fn rebuild_indexes_in(
+        &self,
+        ns: Option<&str>,
+        name: Option<&str>,
+        progress: impl FnMut(usize, usize),
+    ) -> Result<Vec<String>> {
+        let _writer = self.writer.lock().unwrap();
+        let existing = self.indexes_in(ns);
+        let names = match name {
+            Some(name) if !existing.iter().any(|n| n == name) => {
+                return Err(IcebergError::Corruption(format!(
+                    "index not found: {}",
+                    name
+                )));
+            }
+            Some(name) => vec![name.to_string()],
+            None => existing,
+        };
+        if names.is_empty() {
+            return Ok(names);
+        }
+        let tree = self.current_tree()?;
+        let entries = self.namespace_values(&tree, ns, progress)?;
+        {
+            let mut indexes = self.indexes().lock().unwrap();
+            for name in &names {
+                let stored = match ns {
+                    Some(ns) => namespace::qualify(ns, name),
+                    None => name.clone(),
+                };
+                indexes.rebuild_index(&stored, &entries);
+            }
+        }
+        self.save_indexes()?;
+        Ok(names)
+    }
+
+    /// Drop a secondary index.
+    pub fn drop_index(&self, name: &str) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        {
+            let mut indexes = self.indexes().lock().unwrap();
+            indexes.drop_index(name)?;
+        }
+        self.save_indexes()
+    }
+
+    /// Query a secondary index by exact value. Returns matching primary keys.
+    pub fn query_index(&self, index_name: &str, value: &str) -> Result<Vec<String>> {
+        let indexes = self.indexes().lock().unwrap();
+        indexes.query(index_name, value)
+    }
+
+    /// `query_index` as of a past commit: the keys whose values in that
+    /// commit's tree the index would hold under `value`. The index itself
+    /// only reflects the present, so this scans the commit's tree.
+    pub fn query_index_at(
+        &self,
+        index_name: &str,
+        value: &str,
+        commit_id: &str,
+    ) -> Result<Vec<String>> {
+        let index = {
+            let indexes = self.indexes().lock().unwrap();
+            indexes
+                .get_index(index_name)
+                .ok_or_else(|| {
+                    IcebergError::Corruption(format!("index not found: {}", index_name))
+                })?
+                .empty_copy()
+        };
+        let ns = namespace::split(index_name).0;
+        let tree = self.tree_at(commit_id)?;
+        let mut keys = Vec::new();
+        for (key, stored) in &tree.entries {
+            let (key_ns, key) = namespace::split(key);
+            if key_ns != ns {
+                continue;
+            }
+            let value_bytes = match index.reads_values() {
+                true => self.load_value(stored)?,
+                false => Vec::new(),
+            };
+            if index.indexes_under(key, &value_bytes, value) {
+                keys.push(key.to_string());
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Query a secondary index by exact value. Returns the matching keys with
+    /// their values at the current branch HEAD, read in one pass over the
+    /// tree; keys the index holds for other branches are left out.
+    pub fn query_index_entries(
+        &self,
+        index_name: &str,
+        value: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        let keys = self.query_index(index_name, value)?;
+        self.index_entries(None, keys)
+    }
+
+    /// Join keys from an index of namespace `ns` against the current tree.
+    pub(crate) fn index_entries(
+        &self,
+        ns: Option<&str>,
+        keys: Vec<String>,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        let tree = self.current_tree()?;
+        keys.into_iter()
+            .filter_map(|key| {
+                let stored = match ns {
+                    Some(ns) => tree.get(&namespace::qualify(ns, &key)),
+                    None => tree.get(&key),
+                }?;
+                Some(self.load_value(stored).map(|value| (key, value)))
+            })
+            .collect()
+    }
+
+    /// Query a secondary index by prefix. Returns matching primary keys.
+    pub fn query_index_prefix(&self, index_name: &str, prefix: &str) -> Result<Vec<String>> {
+        let indexes = self.indexes().lock().unwrap();
+        indexes.query_prefix(index_name, prefix)
+    }
+
+    /// Query a secondary index for values between `min` and `max`, both
+    /// inclusive and either optional, in the index's ordering (numeric for
+    /// integer and float indexes, chronological for date indexes).
+    pub fn query_index_range(
+        &self,
+        index_name: &str,
+        min: Option<&str>,
+        max: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let indexes = self.indexes().lock().unwrap();
+        indexes.query_range(index_name, min, max)
+    }
+
+    /// Query a secondary index for one page of the entries with values
+    /// between `min` and `max` (pass the same value for an exact match),
+    /// ordered by value. Pass the returned `next` cursor in
+    /// `PageOptions::after` to get the following page.
+    pub fn query_index_page(
+        &self,
+        index_name: &str,
+        min: Option<&str>,
+        max: Option<&str>,
+        options: &PageOptions,
+    ) -> Result<IndexPage> {
+        let indexes = self.indexes().lock().unwrap();
+        indexes.query_page(index_name, min, max, options)
+    }
+
+    /// Statistics of a secondary or vector index. The disk size is that of
+    /// the index's files as last flushed.
+    pub fn index_stats(&self, index_name: &str) -> Result<IndexStats> {
+        let indexes = self.indexes().lock().unwrap();
+        indexes.stats(index_name, self.backend.as_ref())
+    }
+
+    /// List the secondary indexes of the default namespace.
+    pub fn list_indexes(&self) -> Vec<String> {
+        self.indexes_in(None)
+    }
+
+    /// Names of the secondary indexes of a namespace.
+    pub(crate) fn indexes_in(&self, ns: Option<&str>) -> Vec<String> {
+        let indexes = self.indexes().lock().unwrap();
+        indexes
+            .list_indexes()
+            .iter()
+            .filter_map(|name| match namespace::split(name) {
+                (found, name) if found == ns => Some(name.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // ── Bloom Filter ──────────────────────────────────────────
+
+    /// Rebuild the bloom filter from every branch head, so reads from any
+    /// branch or tenant can rely on it.
+    pub fn rebuild_bloom(&self) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        self.rebuild_bloom_locked()
+    }
+
+    fn rebuild_bloom_locked(&self) -> Result<()> {
+        self.foreign_refs.store(false, Ordering::Relaxed);
+        let keys = self.branch_keys()?;
+        *self.bloom().lock().unwrap() = BloomSet::from_keys(self.config().key_filter, &keys);
+        self.save_bloom()
+    }
+
+    /// Remove deleted keys from the key filters, where they can remove
+    /// keys. Only while there is a single branch: a key deleted on one
+    /// branch may still be on another, and the filters are shared. Returns
+    /// whether the filters changed.
+    fn forget_keys<'k>(&self, refs: &Refs, keys: impl IntoIterator<Item = &'k str>) -> bool {
+        if refs.branches.len() > 1 {
+            return false;
+        }
+        let mut bloom = self.bloom().lock().unwrap();
+        keys.into_iter()
+            .fold(false, |forgotten, key| bloom.remove(key) | forgotten)
+    }
+
+    // ── Distinct Counts ───────────────────────────────────────
+
+    /// Keep an approximate distinct count for `spec`: a dotted JSON field
+    /// path such as `address.city` counts the field's distinct values, and
+    /// a key prefix followed by `*` such as `user:*` the distinct keys under
+    /// it. The HyperLogLog sketch is seeded from every branch head and then
+    /// updated on each write, like the bloom filter. Tracking a spec again
+    /// rebuilds it. Only keys of the default namespace are counted.
+    pub fn track_distinct(&self, spec: &str) -> Result<()> {
+        hyperloglog::check_spec(spec)?;
+        let _writer = self.writer.lock().unwrap();
+        let mut sketch = HyperLogLog::new(hyperloglog::DEFAULT_PRECISION);
+        let needs_values = !spec.ends_with('*');
+        for head in self.load_refs()?.branches.values() {
+            let tree = self.load_tree(&self.load_commit(head)?.tree_root)?;
+            for (key, value) in &tree.entries {
+                if namespace::split(key).0.is_some() {
+                    continue;
+                }
+                let value = match needs_values {
+                    true => Some(self.load_value(value)?),
+                    false => None,
+                };
+                hyperloglog::insert_into(&mut sketch, spec, key, value.as_deref(), &mut None);
+            }
+        }
+        self.distinct().lock().unwrap().set(spec, sketch);
+        self.save_distinct()
+    }
+
+    /// Stop keeping the distinct count for `spec`.
+    pub fn untrack_distinct(&self, spec: &str) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        if !self.distinct().lock().unwrap().remove(spec) {
+            return Err(IcebergError::InvalidQuery(format!(
+                "distinct count not tracked: {}",
+                spec
+            )));
+        }
+        self.save_distinct()
+    }
+
+    /// Tracked distinct count specs with their current estimates.
+    pub fn tracked_distinct(&self) -> Vec<(String, u64)> {
+        let distinct = self.distinct().lock().unwrap();
+        distinct
+            .specs()
+            .map(|(spec, sketch)| (spec.clone(), sketch.estimate()))
+            .collect()
+    }
+
+    /// Approximate number of distinct values or keys of a spec tracked with
+    /// `track_distinct`, in constant time and typically within 2%. Like the
+    /// bloom filter, the count spans every branch and still includes keys
+    /// and values deleted or overwritten since the spec was last tracked.
+    pub fn approx_distinct(&self, spec: &str) -> Result<u64> {
+        let distinct = self.distinct().lock().unwrap();
+        let sketch = distinct.get(spec).ok_or_else(|| {
+            IcebergError::InvalidQuery(format!(
+                "distinct count not tracked: {}; track it first",
+                spec
+            ))
+        })?;
+        Ok(sketch.estimate())
+    }
+
+    /// Keys present at any branch head.
+    fn branch_keys(&self) -> Result<HashSet<String>> {
+        let mut keys = HashSet::new();
+        for head in self.load_refs()?.branches.values() {
+            let tree = self.load_tree(&self.load_commit(head)?.tree_root)?;
+            keys.extend(tree.entries.keys().cloned());
+        }
+        Ok(keys)
+    }
+
+    // ── Queries ───────────────────────────────────────────────
+
+    /// Keys and values at the current branch HEAD matching a query, sorted
+    /// by key. Candidates come from the secondary indexes where they can
+    /// narrow the search (see `explain`); otherwise every key is checked.
+    pub fn query(&self, query: &Query) -> Result<Vec<(String, Vec<u8>)>> {
+        self.query_in(None, query, None)
+    }
+
+    /// `query` as of a past commit. Indexes only describe HEAD, so every
+    /// key in the commit's tree is checked.
+    pub fn query_at(&self, query: &Query, commit_id: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.query_in(None, query, Some(commit_id))
+    }
+
+    /// How `query` would find its candidate keys.
+    pub fn explain(&self, query: &Query) -> QueryPlan {
+        self.explain_in(None, query)
+    }
+
+    /// Run a `SELECT` statement (see `sql`) and return its rows.
+    pub fn sql(&self, statement: &str) -> Result<Rows> {
+        let stmt = sql::parse(statement)?;
+        if let Some(ns) = &stmt.namespace {
+            namespace::check_name(ns)?;
+        }
+        let at = stmt
+            .at
+            .as_deref()
+            .map(|r| self.resolve_ref(r))
+            .transpose()?;
+        let filter = stmt.filter.unwrap_or_else(Query::all);
+        let mut entries = self.query_in(stmt.namespace.as_deref(), &filter, at.as_deref())?;
+        if let Some(limit) = stmt.limit {
+            entries.truncate(limit);
+        }
+        Ok(Rows::new(&stmt.columns, entries))
+    }
+
+    /// Aggregate the values matching `filter` at the current branch HEAD,
+    /// optionally grouped by a JSON field. Use `query::key().prefix(p)` to
+    /// aggregate a key range and `Query::all()` for everything.
+    ///
+    /// Counting every key by a field with a plain string index reads only
+    /// the index and the tree, not the values.
+    pub fn aggregate(
+        &self,
+        filter: &Query,
+        group_by: Option<&str>,
+        aggregate: &Aggregate,
+    ) -> Result<Groups> {
+        self.aggregate_in(None, filter, group_by, aggregate)
+    }
+
+    /// `aggregate` over namespace `ns`.
+    pub(crate) fn aggregate_in(
+        &self,
+        ns: Option<&str>,
+        filter: &Query,
+        group_by: Option<&str>,
+        aggregate: &Aggregate,
+    ) -> Result<Groups> {
+        let mut agg = Aggregator::new(aggregate, group_by);
+        if let (Aggregate::Count, Some(field), true) = (aggregate, group_by, filter.is_all()) {
+            let indexes = self.indexes().lock().unwrap();
+            if let Some(postings) = indexes.postings(ns, field) {
+                let tree = self.current_tree()?;
+                let stored = |key: &str| match ns {
+                    Some(ns) => namespace::qualify(ns, key),
+                    None => key.to_string(),
+                };
+                // Indexes also hold keys of other branches; count only
+                // those in this tree.
+                let mut grouped = HashSet::new();
+                for (value, keys) in postings {
+                    let present: Vec<&String> = keys
+                        .iter()
+                        .filter(|k| tree.contains_key(&stored(k)))
+                        .collect();
+                    if !present.is_empty() {
+                        agg.add_count(Some(value.clone()), present.len());
+                        grouped.extend(present);
+                    }
+                }
+                let total = tree
+                    .entries
+                    .keys()
+                    .filter(|k| namespace::split(k).0 == ns)
+                    .count();
+                if total > grouped.len() {
+                    agg.add_count(None, total - grouped.len());
+                }
+                return Ok(agg.finish());
+            };
+        }
+        for (_, value) in self.query_in(ns, filter, None)? {
+            agg.add(&value);
+        }
+        Ok(agg.finish())
+    }
+
+    /// `explain` against the indexes of namespace `ns`.
+    pub(crate) fn explain_in(&self, ns: Option<&str>, query: &Query) -> QueryPlan {
+        query.plan(&self.indexes().lock().unwrap(), ns)
+    }
+
+    /// `query` over namespace `ns`, with keys local to it, at HEAD or at
+    /// commit `at`.
+    pub(crate) fn query_in(
+        &self,
+        ns: Option<&str>,
+        query: &Query,
+        at: Option<&str>,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        let (tree, candidates) = match at {
+            Some(commit_id) => (self.tree_at(commit_id)?, None),
+            None => {
+                let candidates = {
+                    let indexes = self.indexes().lock().unwrap();
+                    query.plan(&indexes, ns).candidates(&indexes, ns)
+                };
+                (self.current_tree()?, candidates)
+            }
+        };
+        let entries: Vec<(String, &TreeValue)> = match candidates {
+            Some(keys) => keys
+                .into_iter()
+                .filter_map(|key| {
+                    let stored = match ns {
+                        Some(ns) => tree.get(&namespace::qualify(ns, &key)),
+                        None => tree.get(&key),
+                    }?;
+                    Some((key, stored))
+                })
+                .collect(),
+            None => tree
+                .entries
+                .iter()
+                .filter_map(|(key, stored)| match namespace::split(key) {
+                    (found, key) if found == ns => Some((key.to_string(), stored)),
+                    _ => None,
+                })
+                .collect(),
+        };
+        let mut matches = Vec::new();
+        for (key, stored) in entries {
+            let value = self.load_value(stored)?;
+            if query.matches(&key, &value) {
+                matches.push((key, value));
+            }
+        }
+        Ok(matches)
+    }
+
+    // ── Vector Indexes ────────────────────────────────────────
+
+    /// Create a vector index over embeddings stored as JSON arrays of
+    /// numbers at `field_path`, for nearest-neighbour queries.
+    pub fn create_vector_index(
+        &self,
+        name: &str,
+        field_path: &str,
+        options: &VectorOptions,
+    ) -> Result<()> {
+        namespace::check_key(name)?;
+        self.create_vector_index_raw(name, field_path, options)
+    }
+
+    /// `create_vector_index` under a name that may be namespaced.
+    pub(crate) fn create_vector_index_raw(
+        &self,
+        name: &str,
+        field_path: &str,
+        options: &VectorOptions,
+    ) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        {
+            let mut indexes = self.indexes().lock().unwrap();
+            indexes.create_vector_index(name, field_path, options)?;
+            self.rebuild_from_head(&mut indexes, name)?;
+        }
+        self.save_indexes()
+    }
+
+    /// The `k` keys whose embeddings are nearest to `query` in a vector
+    /// index, nearest first, with their distances. Approximate on large
+    /// indexes; see `VectorIndex`.
+    pub fn query_vector(
+        &self,
+        index_name: &str,
+        query: &[f32],
+        k: usize,
+    ) -> Result<Vec<(String, f32)>> {
+        let mut indexes = self.indexes().lock().unwrap();
+        indexes.query_vector(index_name, query, k)
+    }
+
+    /// `query_vector` as of a past commit: an exact search over the
+    /// embeddings in that commit's tree, using the index's field and metric.
+    pub fn query_vector_at(
+        &self,
+        index_name: &str,
+        query: &[f32],
+        k: usize,
+        commit_id: &str,
+    ) -> Result<Vec<(String, f32)>> {
+        self.query_vector_at_in(None, index_name, query, k, commit_id)
+    }
+
+    /// `query_vector_at` for a vector index of namespace `ns`.
+    pub(crate) fn query_vector_at_in(
+        &self,
+        ns: Option<&str>,
+        index_name: &str,
+        query: &[f32],
+        k: usize,
+        commit_id: &str,
+    ) -> Result<Vec<(String, f32)>> {
+        let (field_path, dimensions, metric) = {
+            let indexes = self.indexes().lock().unwrap();
+            let idx = indexes.get_vector_index(index_name).ok_or_else(|| {
+                IcebergError::Corruption(format!("index not found: {}", index_name))
+            })?;
+            idx.check_query(query)?;
+            (idx.field_path.clone(), idx.dimensions, idx.metric)
+        };
+        let tree = self.tree_at(commit_id)?;
+        let mut vectors = BTreeMap::new();
+        for (key, value) in &tree.entries {
+            let (key_ns, key) = namespace::split(key);
+            if key_ns != ns {
+                continue;
+            }
+            if let Some(vector) = vector::extract(&self.load_value(value)?, &field_path, dimensions)
+            {
+                vectors.insert(key.to_string(), vector);
+            }
+        }
+        Ok(vector::nearest(metric, query, vectors.iter(), k))
+    }
+
+    /// Get the default namespace's bloom filter stats.
+    pub fn bloom_stats(&self) -> (usize, usize, f64) {
+        self.bloom_stats_in(None)
+    }
+
+    /// Bloom filter stats of a namespace; all zero if it has no filter yet.
+    pub(crate) fn bloom_stats_in(&self, ns: Option<&str>) -> (usize, usize, f64) {
+        let bloom = self.bloom().lock().unwrap();
+        match bloom.filter(ns) {
+            Some(f) => (f.count(), f.num_bits(), f.estimated_fp_rate()),
+            None => (0, 0, 0.0),
+        }
+    }
+
+    // ── Compression ───────────────────────────────────────────
+
+    /// Train a zstd dictionary on up to `max_samples` values from the current
+    /// tree and switch new blocks to zstd compression with that dictionary.
+    pub fn train_dictionary(
+        &self,
+        max_samples: usize,
+        max_size: usize,
+    ) -> Result<TrainedDictionary> {
+        let tree = self.current_tree()?;
+        let step = (tree.len() / max_samples.max(1)).max(1);
+        let samples = tree
+            .entries
+            .values()
+            .step_by(step)
+            .take(max_samples)
+            .map(|v| self.load_value(v))
+            .collect::<Result<Vec<_>>>()?;
+        let dict = compression::train_dictionary(&samples, max_size)?;
+        let id = self.store.put_dictionary(&dict)?;
+        let mut compression = self.config().compression;
+        compression.codec = compression::Codec::Zstd;
+        compression.dictionary = Some(id.clone());
+        self.set_compression(compression)?;
+        Ok(TrainedDictionary {
+            id,
+            size: dict.len(),
+            samples: samples.len(),
+        })
+    }
+
+    // ── Compaction ────────────────────────────────────────────
+
+    /// Run compaction with the given policy on the current branch.
+    /// Removes old commits and unreachable trees/blocks. Commits tagged are
+    /// kept if the policy says so; otherwise their tags go with them.
+    pub fn compact(&self, policy: &CompactionPolicy) -> Result<CompactionResult> {
+        self.compact_with_progress(policy, |_, _| {})
+    }
+
+    /// `compact`, calling `progress(done, total)` now and then while
+    /// commits are removed and trees and blocks are swept. Squashing
+    /// compactions only report when they are done.
+    pub fn compact_with_progress(
+        &self,
+        policy: &CompactionPolicy,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<CompactionResult> {
+        let _writer = self.writer.lock().unwrap();
+        self.compact_locked(policy, &mut progress)
+    }
+
+    /// What `compact` with `policy` would remove, without removing anything.
+    /// Squashing policies rewrite history rather than drop commits, so they
+    /// are not previewed.
+    pub fn compact_preview(&self, policy: &CompactionPolicy) -> Result<CompactionResult> {
+        if policy.squash {
+            return Err(IcebergError::InvalidQuery(
+                "squashing compactions cannot be previewed".into(),
+            ));
+        }
+        // While HEAD is detached, the branch it was detached from.
+        let log = self.log_of(Target::Branch(&self.current_branch()?))?;
+        let tags = self.tags()?;
+        let removable = self.removable_commits(&log, &tags, policy)?;
+        let mut result = CompactionResult::default();
+        if removable.is_empty() {
+            return Ok(result);
+        }
+        let (_, reachable_trees) = self.reachable_from_refs(&removable)?;
+        let live_blocks = self.blocks_of(&reachable_trees)?;
+        for cid in &removable {
+            if self.backend.exists(&backend::key(COMMITS_DIR, cid))? {
+                result.commits_removed += 1;
+            }
+        }
+        result.tags_removed = tags
+            .iter()
+            .filter(|t| removable.contains(&t.commit_id))
+            .count();
+        for name in self.backend.list(TREES_DIR)? {
+            if !reachable_trees.contains(&name) {
+                let size = self.backend.size(&backend::key(TREES_DIR, &name))?;
+                result.trees_removed += 1;
+                result.bytes_reclaimed += size.unwrap_or(0);
+            }
+        }
+        for (hash, size) in self.store.block_sizes()? {
+            if !live_blocks.contains(&hash) {
+                result.blocks_removed += 1;
+                result.bytes_reclaimed += size;
+            }
+        }
+        Ok(result)
+    }
+
+    /// Commits of `log` that `policy` lets go of, sparing tagged ones if it
+    /// keeps them and the one a detached HEAD is at.
+    fn removable_commits(
+        &self,
+        log: &[Commit],
+        tags: &[Tag],
+        policy: &CompactionPolicy,
+    ) -> Result<HashSet<String>> {
+        let commits_with_ts: Vec<_> = log.iter().map(|c| (c.id.clone(), c.timestamp)).collect();
+        let mut removable: HashSet<_> =
+            find_removable_commits(&commits_with_ts, policy, chrono::Utc::now())
+                .into_iter()
+                .collect();
+        if policy.keep_tagged {
+            for tag in tags {
+                removable.remove(&tag.commit_id);
+            }
+        }
+        if let Some(detached) = self.load_refs()?.detached {
+            removable.remove(&detached.commit);
+        }
+        Ok(removable)
+    }
+
+    fn compact_locked(
+        &self,
+        policy: &CompactionPolicy,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<CompactionResult> {
+        self.commits_since_compaction.store(0, Ordering::Relaxed);
+        // Checkpoint first: WAL replay treats commits missing from history as
+        // unapplied, so the log must not outlive the commits it refers to.
+        self.flush_dirty()?;
+        // While HEAD is detached, the branch it was detached from.
+        let log = self.log_of(Target::Branch(&self.current_branch()?))?;
+        let tags = self.tags()?;
+        let removable = self.removable_commits(&log, &tags, policy)?;
+        if removable.is_empty() {
+            progress(0, 0);
+            return Ok(CompactionResult::default());
+        }
+        if policy.squash {
+            // Squash up to the newest commit the policy lets go of.
+            let newest = log.iter().find(|c| removable.contains(&c.id));
+            let Some(newest) = newest.filter(|c| c.parent.is_some()) else {
+                progress(0, 0);
+                return Ok(CompactionResult::default());
+            };
+            let squash = self.squash_locked(&newest.id, None, !policy.keep_tagged)?;
+            progress(1, 1);
+            return Ok(squash.collected);
+        }
+        let dropped_tags: Vec<_> = tags
+            .iter()
+            .filter(|t| removable.contains(&t.commit_id))
+            .collect();
+
+        // Everything still reachable from a branch or a surviving tag once
+        // the removable commits are gone. Work out the live blocks before
+        // deleting anything: a tree we cannot read could hold blocks we
+        // would otherwise collect.
+        let (visited, reachable_trees) = self.reachable_from_refs(&removable)?;
+        let live_blocks = self.blocks_of(&reachable_trees)?;
+
+        let mut result = CompactionResult::default();
+        let total =
+            removable.len() + self.backend.list(TREES_DIR)?.len() + self.store.block_count()?;
+        let mut ticker = Ticker::new(total, PROGRESS_STEP, progress);
+
+        // Remove commits
+        for cid in &removable {
+            if self.backend.exists(&backend::key(COMMITS_DIR, cid))? {
+                self.delete_commit(cid)?;
+                result.commits_removed += 1;
+            }
+            ticker.tick();
+        }
+        for tag in dropped_tags {
+            self.remove_tag(tag)?;
+            result.tags_removed += 1;
+        }
+
+        // Kept commits whose parent was removed become roots: the oldest
+        // kept commit of the branch, and tagged commits kept out of order.
+        for cid in &visited {
+            let commit = self.load_commit(cid)?;
+            if commit
+                .parent
+                .as_ref()
+                .is_some_and(|p| removable.contains(p))
+            {
+                let mut fixed = commit;
+                fixed.parent = None;
+                self.save_commit(&fixed)?;
+            }
+        }
+
+        self.sweep(&reachable_trees, &live_blocks, &mut result, || {
+            ticker.tick()
+        })?;
+        ticker.finish();
+        Ok(result)
+    }
+
+    /// Replace the history of the current branch up to and including
+    /// `before` (a commit, branch or tag) with a single snapshot commit
+    /// holding that commit's tree. Newer commits are rebuilt on top of the
+    /// snapshot, branches and tags on rewritten commits are moved to the new
+    /// ids, and old commits no ref still needs are deleted with their trees
+    /// and blocks.
+    pub fn squash_history(&self, before: &str, message: Option<&str>) -> Result<SquashResult> {
+        let _writer = self.writer.lock().unwrap();
+        self.squash_locked(before, message, false)
+    }
+
+    fn squash_locked(
+        &self,
+        before: &str,
+        message: Option<&str>,
+        drop_tags: bool,
+    ) -> Result<SquashResult> {
+        // Checkpoint: the WAL refers to commits by id.
+        self.flush_dirty()?;
+        self.load_refs()?.write_branch(Target::Head)?;
+        let target = self.resolve_ref(before)?;
+        let log = self.log()?;
+        let Some(pos) = log.iter().position(|c| c.id == target) else {
+            return Err(IcebergError::CommitNotFound(format!(
+                "{} (not in the history of {})",
+                before,
+                self.current_branch()?
+            )));
+        };
+        let (recent, squashed) = log.split_at(pos);
+        let base = &squashed[0];
+        let message = message.map(String::from).unwrap_or_else(|| {
+            format!(
+                "snapshot of {} commits up to {}",
+                squashed.len(),
+                &base.id[..8.min(base.id.len())]
+            )
+        });
+        let snapshot =
+            Commit::with_timestamp(None, base.tree_root.clone(), message, base.timestamp);
+        self.save_commit(&snapshot)?;
+
+        let mut rewritten = BTreeMap::new();
+        rewritten.insert(base.id.clone(), snapshot.id.clone());
+        let mut parent = snapshot.id.clone();
+        for old in recent.iter().rev() {
+            let new = Commit::with_timestamp(
+                Some(parent),
+                old.tree_root.clone(),
+                old.message.clone(),
+                old.timestamp,
+            );
+            self.save_commit(&new)?;
+            rewritten.insert(old.id.clone(), new.id.clone());
+            parent = new.id;
+        }
+        self.retarget_refs(&rewritten)?;
+
+        let mut collected = CompactionResult::default();
+        if drop_tags {
+            for tag in self.tags()? {
+                if squashed.iter().any(|c| c.id == tag.commit_id) {
+                    self.remove_tag(&tag)?;
+                    collected.tags_removed += 1;
+                }
+            }
+        }
+
+        // Old commits are only deleted once nothing refers to them; other
+        // branches or tags may still hold on to the original history.
+        let (visited, reachable_trees) = self.reachable_from_refs(&HashSet::new())?;
+        let live_blocks = self.blocks_of(&reachable_trees)?;
+        for old in &log {
+            if !visited.contains(&old.id) {
+                self.delete_commit(&old.id)?;
+                collected.commits_removed += 1;
+            }
+        }
+        self.sweep(&reachable_trees, &live_blocks, &mut collected, || {})?;
+        Ok(SquashResult {
+            snapshot,
+            squashed: squashed.len(),
+            rewritten,
+            collected,
+        })
+    }
+
+    /// Drop every commit older than `keep` (a commit, branch or tag), which
+    /// becomes a root commit with its tree and id unchanged. Branches and a
+    /// detached HEAD on dropped commits move to `keep`, tags on them are
+    /// deleted, and commits of other branches whose parent was dropped
+    /// become roots too. Trees and blocks no commit uses any more are
+    /// deleted.
+    pub fn truncate_history(&self, keep: &str) -> Result<TruncateResult> {
+        let _writer = self.writer.lock().unwrap();
+        // Checkpoint: the WAL refers to commits by id.
+        self.flush_dirty()?;
+        let keep = self.resolve_ref(keep)?;
+        let mut dropped = HashSet::new();
+        let mut next = self.commit_entry(&keep)?.parent;
+        while let Some(id) = next.filter(|id| !dropped.contains(id)) {
+            next = self.commit_entry(&id)?.parent;
+            dropped.insert(id);
+        }
+
+        let mut refs = self.load_refs()?;
+        let mut moved_branches = Vec::new();
+        for (name, id) in refs.branches.iter_mut() {
+            if dropped.contains(id) {
+                *id = keep.clone();
+                moved_branches.push(name.clone());
+            }
+        }
+        moved_branches.sort();
+        if let Some(detached) = refs.detached.as_mut() {
+            if dropped.contains(&detached.commit) {
+                detached.commit = keep.clone();
+            }
+        }
+        self.save_refs(&refs)?;
+
+        let mut collected = CompactionResult::default();
+        for tag in self.tags()? {
+            if dropped.contains(&tag.commit_id) {
+                self.remove_tag(&tag)?;
+                collected.tags_removed += 1;
+            }
+        }
+        let (visited, reachable_trees) = self.reachable_from_refs(&dropped)?;
+        let live_blocks = self.blocks_of(&reachable_trees)?;
+        for id in &dropped {
+            self.delete_commit(id)?;
+            collected.commits_removed += 1;
+        }
+        // The kept commit, and commits of other branches that forked off
+        // below it, lose their parent.
+        for id in &visited {
+            let commit = self.load_commit(id)?;
+            if commit.parent.as_ref().is_some_and(|p| dropped.contains(p)) {
+                let mut fixed = commit;
+                fixed.parent = None;
+                self.save_commit(&fixed)?;
+            }
+        }
+        self.sweep(&reachable_trees, &live_blocks, &mut collected, || {})?;
+        Ok(TruncateResult {
+            root: self.load_commit(&keep)?,
+            moved_branches,
+            collected,
+        })
+    }
+
+    /// Rewrite the history of every branch and tag as if `key` had never
+    /// been written: it is removed from every tree, commits that only touched
+    /// it are dropped, branches and tags move to the rewritten commits, and
+    /// the old commits, trees and blocks are deleted. Commits no branch or
+    /// tag reaches lose their trees too, so `lost_found` cannot bring the
+    /// key back.
+    pub fn purge_key(&self, key: &str) -> Result<PurgeResult> {
+        let _writer = self.writer.lock().unwrap();
+        // Checkpoint, so the WAL no longer holds the value either.
+        self.flush_dirty()?;
+        let refs = self.load_refs()?;
+        let mut heads: Vec<String> = refs.branches.into_values().collect();
+        heads.extend(refs.detached.map(|d| d.commit));
+        heads.extend(self.tags()?.into_iter().map(|t| t.commit_id));
+
+        let mut result = PurgeResult::default();
+        // Old id → new id, and new id → tree root.
+        let mut new_ids: HashMap<String, String> = HashMap::new();
+        let mut roots: HashMap<String, String> = HashMap::new();
+        for head in heads {
+            let mut chain = Vec::new();
+            let mut next = Some(head);
+            while let Some(id) = next.take() {
+                if new_ids.contains_key(&id) {
+                    break;
+                }
+                let Ok(commit) = self.load_commit(&id) else {
+                    break;
+                };
+                next = commit.parent.clone();
+                chain.push(commit);
+            }
+            // Oldest first, so parents are rewritten before their children.
+            for commit in chain.into_iter().rev() {
+                let parent = commit
+                    .parent
+                    .as_ref()
+                    .map(|p| new_ids.get(p).cloned().unwrap_or_else(|| p.clone()));
+                let tree = self.load_tree(&commit.tree_root)?;
+                let tree_root = if tree.contains_key(key) {
+                    let purged = tree.delete(key);
+                    self.save_tree(&purged)?;
+                    purged.root_hash
+                } else {
+                    commit.tree_root.clone()
+                };
+                let parent_root = parent.as_ref().and_then(|p| roots.get(p));
+                let new_id = match parent {
+                    Some(parent) if parent_root == Some(&tree_root) => {
+                        result.dropped += 1;
+                        parent
+                    }
+                    parent if parent == commit.parent && tree_root == commit.tree_root => {
+                        commit.id.clone()
+                    }
+                    parent => {
+                        let new = Commit::with_timestamp(
+                            parent,
+                            tree_root.clone(),
+                            commit.message.clone(),
+                            commit.timestamp,
+                        );
+                        self.save_commit(&new)?;
+                        new.id
+                    }
+                };
+                roots.insert(new_id.clone(), tree_root);
+                if new_id != commit.id {
+                    result.rewritten.insert(commit.id.clone(), new_id.clone());
+                }
+                new_ids.insert(commit.id, new_id);
+            }
+        }
+        self.retarget_refs(&result.rewritten)?;
+
+        let (visited, reachable_trees) = self.reachable_from_refs(&HashSet::new())?;
+        let live_blocks = self.blocks_of(&reachable_trees)?;
+        for old in result.rewritten.keys() {
+            if !visited.contains(old) {
+                self.delete_commit(old)?;
+                result.collected.commits_removed += 1;
+            }
+        }
+        self.sweep(&reachable_trees, &live_blocks, &mut result.collected, || {})?;
+
+        self.indexes().lock().unwrap().on_delete(key);
+        self.save_indexes()?;
+        Ok(result)
+    }
+
+    /// Move every branch and tag pointing at a key of `mapping` to its value.
+    fn retarget_refs(&self, mapping: &BTreeMap<String, String>) -> Result<()> {
+        let mut refs = self.load_refs()?;
+        let mut moved = false;
+        let detached = refs.detached.as_mut().map(|d| &mut d.commit);
+        for id in refs.branches.values_mut().chain(detached) {
+            if let Some(new) = mapping.get(id) {
+                *id = new.clone();
+                moved = true;
+            }
+        }
+        if moved {
+            self.save_refs(&refs)?;
+        }
+        for mut tag in self.tags()? {
+            if let Some(new) = mapping.get(&tag.commit_id) {
+                let old = std::mem::replace(&mut tag.commit_id, new.clone());
+                self.save_tag(&tag)?;
+                self.log_tag(&tag.name, Some(old), Some(new))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Commits reachable from any branch or tag without passing through
+    /// `excluded`, and the trees they use along with those of snapshots.
+    fn reachable_from_refs(
+        &self,
+        excluded: &HashSet<String>,
+    ) -> Result<(HashSet<String>, HashSet<String>)> {
+        let refs = self.load_refs()?;
+        let mut heads: Vec<String> = refs.branches.into_values().collect();
+        heads.extend(refs.detached.map(|d| d.commit));
+        heads.extend(self.tags()?.into_iter().map(|t| t.commit_id));
+        let mut visited = HashSet::new();
+        let mut trees = HashSet::new();
+        for head in heads {
+            let mut current_id = Some(head);
+            while let Some(id) = current_id {
+                if excluded.contains(&id) || !visited.insert(id.clone()) {
+                    break;
+                }
+                if let Ok(c) = self.commit_entry(&id) {
+                    trees.insert(c.tree_root);
+                    current_id = c.parent;
+                } else {
+                    visited.remove(&id);
+                    break;
+                }
+            }
+        }
+        trees.extend(self.pins.lock().unwrap().keys().cloned());
+        Ok((visited, trees))
+    }
+
+    /// Every block and blob the given trees use. Fails if any tree cannot
+    /// be read.
+    fn blocks_of(&self, trees: &HashSet<String>) -> Result<HashSet<String>> {
+        let mut blocks = HashSet::new();
+        for root in trees {
+            let tree = self.load_tree(root)?;
+            for value in tree.entries.values() {
+                blocks.extend(value.block_hashes().into_iter().map(String::from));
+                blocks.extend(value.blob_hash().map(String::from));
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// Delete every tree not in `trees` and every block and blob not in
+    /// `blocks` or held by a streaming put, calling `tick` for each tree,
+    /// block and blob checked.
+    fn sweep(
+        &self,
+        trees: &HashSet<String>,
+        blocks: &HashSet<String>,
+        result: &mut CompactionResult,
+        mut tick: impl FnMut(),
+    ) -> Result<()> {
+        for name in self.backend.list(TREES_DIR)? {
+            tick();
+            if !trees.contains(&name) {
+                let key = backend::key(TREES_DIR, &name);
+                let size = self.backend.size(&key)?.unwrap_or(0);
+                self.backend.delete(&key)?;
+                self.backend.delete(&backend::key(TREE_BLOOMS_DIR, &name))?;
+                self.tree_cache.lock().unwrap().remove(&name);
+                result.trees_removed += 1;
+                result.bytes_reclaimed += size;
+            }
+        }
+        // Held to the end, so a streaming put cannot store a block between
+        // the check and the delete.
+        let in_flight = self.in_flight.lock().unwrap();
+        let mut held;
+        let blocks = match in_flight.is_empty() {
+            true => blocks,
+            false => {
+                held = blocks.clone();
+                held.extend(in_flight.keys().cloned());
+                &held
+            }
+        };
+        let (blocks_removed, block_bytes) = self.store.retain_with(blocks, &mut tick)?;
+        result.blocks_removed += blocks_removed;
+        result.bytes_reclaimed += block_bytes;
+        let (blobs_removed, blob_bytes) = self.blobs.retain_with(blocks, tick)?;
+        result.blobs_removed += blobs_removed;
+        result.bytes_reclaimed += blob_bytes;
+        Ok(())
+    }
+
+    // ── Stats ─────────────────────────────────────────────────
+
+    /// Database statistics.
+    pub fn stats(&self) -> Result<DbStats> {
+        let tree = self
+            .current_tree()
+            .unwrap_or_else(|_| Arc::new(Tree::empty()));
+        let commits = self.log()?;
+        let branches = self.branches()?;
+        let (bloom_items, bloom_bits, bloom_fp) = self.bloom_stats();
+        let index_count = self.list_indexes().len();
+        let wal_size = self.wal.lock().unwrap().size();
+        let namespaces = namespace_counts(&tree);
+        Ok(DbStats {
+            key_count: tree.len() - namespaces.values().sum::<usize>(),
+            namespace_count: namespaces.len(),
+            commit_count: commits.len(),
+            branch_count: branches.len(),
+            block_count: self.store.block_count()?,
+            disk_usage: self.store.disk_usage()?,
+            key_filter: self.bloom().lock().unwrap().kind(),
+            bloom_items,
+            bloom_bits,
+            bloom_fp_rate: bloom_fp,
+            index_count,
+            wal_size,
+            block_cache: self.store.cache_stats(),
+            tree_cache: self.tree_cache.lock().unwrap().stats(),
+            branches: Vec::new(),
+        })
+    }
+
+    /// Value sizes at HEAD: a histogram, and the `top` largest keys and key
+    /// prefixes (keys up to their first `delimiter`) by bytes. Keys in other
+    /// namespaces are shown as `[ns] key`.
+    pub fn size_report(&self, top: usize, delimiter: char) -> Result<SizeReport> {
+        let tree = self
+            .current_tree()
+            .unwrap_or_else(|_| Arc::new(Tree::empty()));
+        let label = |key: &str| match namespace::split(key) {
+            (Some(ns), key) => format!("[{}] {}", ns, key),
+            (None, key) => key.to_string(),
+        };
+        let mut report = SizeReport::default();
+        let mut sizes = Vec::with_capacity(tree.len());
+        let mut prefixes: HashMap<String, (u64, usize)> = HashMap::new();
+        for (key, value) in &tree.entries {
+            let size = value.size();
+            report.keys += 1;
+            report.total_bytes += size;
+            sizes.push((size, key.as_str()));
+
+            let bucket = (size.max(1).ilog2() / 2).saturating_sub(1) as usize;
+            if report.histogram.len() <= bucket {
+                report
+                    .histogram
+                    .resize_with(bucket + 1, SizeBucket::default);
+            }
+            report.histogram[bucket].values += 1;
+            report.histogram[bucket].bytes += size;
+
+            let entry = prefixes.entry(key_prefix(key, delimiter)).or_default();
+            entry.0 += size;
+            entry.1 += 1;
+        }
+        for (i, bucket) in report.histogram.iter_mut().enumerate() {
+            bucket.below = 16 << (2 * i);
+        }
+        sizes.sort_by_key(|&(size, key)| (std::cmp::Reverse(size), key));
+        report.largest_keys = sizes
+            .into_iter()
+            .take(top)
+            .map(|(size, key)| (label(key), size))
+            .collect();
+        let mut prefixes: Vec<_> = prefixes
+            .into_iter()
+            .map(|(prefix, (bytes, keys))| (prefix, bytes, keys))
+            .collect();
+        prefixes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        prefixes.truncate(top);
+        report.largest_prefixes = prefixes;
+        Ok(report)
+    }
+
+    /// Bytes on disk by component, and how many of them belong to objects
+    /// no branch or tag reaches any more.
+    pub fn disk_usage(&self) -> Result<DiskUsage> {
+        let _writer = self.writer.lock().unwrap();
+        let (commits, trees) = self.reachable_from_refs(&HashSet::new())?;
+        let live_blocks = self.blocks_of(&trees)?;
+        let mut usage = DiskUsage::default();
+        for (hash, size) in self.store.block_sizes()? {
+            usage.blocks += size;
+            if !live_blocks.contains(&hash) {
+                usage.unreachable += size;
+                usage.unreachable_blocks += 1;
+            }
+        }
+        for (hash, size) in self.blobs.blob_sizes()? {
+            usage.blobs += size;
+            if !live_blocks.contains(&hash) {
+                usage.unreachable += size;
+                usage.unreachable_blobs += 1;
+            }
+        }
+        for (dir, total, live, count) in [
+            (
+                TREES_DIR,
+                &mut usage.trees,
+                &trees,
+                &mut usage.unreachable_trees,
+            ),
+            (
+                COMMITS_DIR,
+                &mut usage.commits,
+                &commits,
+                &mut usage.unreachable_commits,
+            ),
+        ] {
+            for name in self.backend.list(dir)? {
+                let size = self.backend.size(&backend::key(dir, &name))?.unwrap_or(0);
+                *total += size;
+                if !live.contains(&name) {
+                    usage.unreachable += size;
+                    *count += 1;
+                }
+            }
+        }
+        usage.wal = self.wal.lock().unwrap().size();
+        usage.bloom = self.backend.size(BLOOM_FILE)?.unwrap_or(0)
+            + self.backend.size(LEGACY_BLOOM_FILE)?.unwrap_or(0)
+            + self.backend.size(LEGACY_NAMESPACE_BLOOM_FILE)?.unwrap_or(0)
+            + self.backend.size(DISTINCT_FILE)?.unwrap_or(0)
+            + self.dir_bytes(TREE_BLOOMS_DIR)?;
+        usage.indexes =
+            self.dir_bytes(INDEX_DIR)? + self.backend.size(LEGACY_INDEXES_FILE)?.unwrap_or(0);
+        usage.other = self.dir_bytes(TAGS_DIR)?
+            + self.backend.size(TAG_LOG_FILE)?.unwrap_or(0)
+            + self.backend.size(REFS_FILE)?.unwrap_or(0)
+            + self.backend.size(CONFIG_FILE)?.unwrap_or(0)
+            + self.store.metadata_bytes()?;
+        Ok(usage)
+    }
+
+    /// Total bytes of the objects directly in `dir`.
+    fn dir_bytes(&self, dir: &str) -> Result<u64> {
+        let mut total = 0;
+        for name in self.backend.list(dir)? {
+            total += self.backend.size(&backend::key(dir, &name))?.unwrap_or(0);
+        }
+        Ok(total)
+    }
+
+    /// `stats` with `branches` filled in for every branch.
+    pub fn stats_all_branches(&self) -> Result<DbStats> {
+        let mut stats = self.stats()?;
+        stats.branches = self.branch_stats()?;
+        Ok(stats)
+    }
+
+    /// Key and commit counts of every branch, and how far each has
+    /// diverged from `DEFAULT_BRANCH`.
+    pub fn branch_stats(&self) -> Result<Vec<BranchStats>> {
+        let default = commit_ids(&self.log_of(Target::Branch(DEFAULT_BRANCH))?);
+        let mut stats = Vec::new();
+        for name in self.branches()? {
+            let log = self.log_of(Target::Branch(&name))?;
+            let tree = match log.first() {
+                Some(_) => self.branch_tree(&name)?,
+                None => Arc::new(Tree::empty()),
+            };
+            let own = commit_ids(&log);
+            stats.push(BranchStats {
+                head: log.first().map(|c| c.id.clone()),
+                key_count: tree.len() - namespace_counts(&tree).values().sum::<usize>(),
+                commit_count: log.len(),
+                ahead: own.difference(&default).count(),
+                behind: default.difference(&own).count(),
+                name,
+            });
+        }
+        Ok(stats)
+    }
+
+    /// The checked-out branch, its HEAD commit and key counts, and how far
+    /// it has diverged from `DEFAULT_BRANCH`.
+    pub fn status(&self) -> Result<Status> {
+        let refs = self.load_refs()?;
+        let branch = refs.head;
+        let log = self.log_of(Target::Head)?;
+        let tree = match log.first() {
+            Some(_) => self.current_tree()?,
+            None => Arc::new(Tree::empty()),
+        };
+        let namespaced = namespace_counts(&tree).values().sum::<usize>();
+        let detached = refs.detached.map(|d| d.tag);
+        let base = match branch.as_str() {
+            DEFAULT_BRANCH if detached.is_none() => None,
+            _ => {
+                let own = commit_ids(&log);
+                let base = commit_ids(&self.log_of(Target::Branch(DEFAULT_BRANCH))?);
+                Some((
+                    DEFAULT_BRANCH.to_string(),
+                    own.difference(&base).count(),
+                    base.difference(&own).count(),
+                ))
+            }
+        };
+        Ok(Status {
+            head: log.into_iter().next(),
+            key_count: tree.len() - namespaced,
+            namespaced_keys: namespaced,
+            base,
+            wal_size: self.wal.lock().unwrap().size(),
+            branch,
+            detached,
+        })
+    }
+
+    /// A stored object as JSON, read from the backend rather than caches.
+    /// Tags may be named by id or name. Blocks are decoded and shown with
+    /// their size and data, as `text` when it is UTF-8 and `hex` otherwise.
+    pub fn inspect(&self, kind: ObjectKind, id: &str) -> Result<serde_json::Value> {
+        let (dir, id) = match kind {
+            ObjectKind::Commit => (COMMITS_DIR, id.to_string()),
+            ObjectKind::Tree => (TREES_DIR, id.to_string()),
+            ObjectKind::Tag => match self.load_tag_by_name(id)? {
+                Some(tag) => (TAGS_DIR, tag.id),
+                None => (TAGS_DIR, id.to_string()),
+            },
+            ObjectKind::Block => {
+                let block = self.store.get(id)?;
+                let data = match std::str::from_utf8(&block.data) {
+                    Ok(text) => serde_json::json!({ "text": text }),
+                    Err(_) => {
+                        let hex: String = block.data.iter().map(|b| format!("{:02x}", b)).collect();
+                        serde_json::json!({ "hex": hex })
+                    }
+                };
+                return Ok(serde_json::json!({
+                    "hash": block.hash,
+                    "size": block.data.len(),
+                    "data": data,
+                }));
+            }
+        };
+        let data = self
+            .backend
+            .read(&backend::key(dir, &id))?
+            .ok_or_else(|| match kind {
+                ObjectKind::Commit => IcebergError::CommitNotFound(id.clone()),
+                _ => IcebergError::Corruption(format!("{} not found: {}", kind, id)),
+            })?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// The branch heads and checked-out branches, as stored.
+    pub fn inspect_refs(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self.load_refs()?)?)
+    }
+
+    // ── Integrity ─────────────────────────────────────────────
+
+    /// Verify the whole database: walk every branch and tag through its
+    /// commits, trees and blocks, recomputing hashes and checking links, and
+    /// check the bloom filter and index files against the live keys.
+    pub fn fsck(&self) -> Result<FsckReport> {
+        let _writer = self.writer.lock().unwrap();
+        // Check the files as they would be loaded, with pending changes in.
+        self.flush_dirty()?;
+        self.run_fsck()
+    }
+
+    /// Repair what `fsck` finds, as far as possible.
+    ///
+    /// Corrupt objects and files are moved under `corrupt/`, missing trees
+    /// are rebuilt from a neighbouring commit's tree when the result matches
+    /// the recorded root hash, branches move back to their newest fully
+    /// intact commit (cutting history below damaged ancestors), tags on
+    /// damaged commits are removed, and the bloom filter and indexes are
+    /// rebuilt. A report of the run is kept in `corrupt/`.
+    pub fn repair(&self) -> Result<RepairReport> {
+        let _writer = self.writer.lock().unwrap();
+        self.flush_dirty()?;
+        let before = self.run_fsck()?;
+        if before.is_healthy() {
+            return Ok(RepairReport {
+                after: before.clone(),
+                before,
+                actions: Vec::new(),
+            });
+        }
+        // Quarantined commits go without `delete_commit`; rebuild on next use.
+        *self.children.lock().unwrap() = None;
+        *self.commit_graph.write().unwrap() = None;
+        self.backend.delete(COMMIT_GRAPH_FILE)?;
+        let mut actions = Vec::new();
+
+        for issue in &before.issues {
+            let (key, reason) = match issue {
+                FsckIssue::CorruptCommit { commit, reason } => {
+                    (backend::key(COMMITS_DIR, commit), reason)
+                }
+                FsckIssue::CorruptTree { tree, reason } => {
+                    self.tree_cache.lock().unwrap().remove(tree);
+                    (backend::key(TREES_DIR, tree), reason)
+                }
+                FsckIssue::CorruptBlock { block, reason } => {
+                    if let Some(raw) = self.store.remove(block)? {
+                        let key = self.store.block_key(block);
+                        self.backend.write(&backend::key(CORRUPT_DIR, &key), &raw)?;
+                        actions.push(RepairAction::Quarantined {
+                            key,
+                            reason: reason.clone(),
+                        });
+                    }
+                    continue;
+                }
+                FsckIssue::CorruptFile { file, reason } => (file.clone(), reason),
+                _ => continue,
+            };
+            if self.quarantine(&key)? {
+                actions.push(RepairAction::Quarantined {
+                    key,
+                    reason: reason.clone(),
+                });
+            }
+        }
+
+        let mut refs = self.load_refs()?;
+        let mut branches: Vec<_> = refs.branches.clone().into_iter().collect();
+        branches.sort();
+        let tags = self.tags()?;
+        let mut chains = Vec::new();
+        for (_, head) in &branches {
+            chains.push(self.intact_chain(head)?);
+        }
+        for tag in &tags {
+            chains.push(self.intact_chain(&tag.commit_id)?);
+        }
+        for chain in &chains {
+            for (i, commit) in chain.iter().enumerate() {
+                if self.load_valid_tree(&commit.tree_root).is_some() {
+                    continue;
+                }
+                let neighbours = [i.checked_sub(1).map(|c| &chain[c]), chain.get(i + 1)];
+                let [child, parent] =
+                    neighbours.map(|c| c.and_then(|c| self.load_valid_tree(&c.tree_root)));
+                if let Some(tree) = regenerate_tree(&commit.tree_root, parent, child) {
+                    self.save_tree(&tree)?;
+                    actions.push(RepairAction::RegeneratedTree {
+                        tree: commit.tree_root.clone(),
+                        commit: commit.id.clone(),
+                    });
+                }
+            }
+        }
+
+        let mut intact = HashMap::new();
+        let mut grafted = HashSet::new();
+        for ((name, head), chain) in branches.iter().zip(&chains) {
+            match self.graft_intact(chain, &mut intact, &mut grafted, &mut actions)? {
+                Some(to) if to == *head => {}
+                Some(to) => {
+                    actions.push(RepairAction::TruncatedBranch {
+                        branch: name.clone(),
+                        from: head.clone(),
+                        to: to.clone(),
+                    });
+                    refs.branches.insert(name.clone(), to);
+                }
+                None => {
+                    let tree = Tree::empty();
+                    self.save_tree(&tree)?;
+                    let commit = Commit::new(
+                        None,
+                        tree.root_hash,
+                        format!("repair: reset branch {}", name),
+                    );
+                    self.save_commit(&commit)?;
+                    actions.push(RepairAction::ResetBranch {
+                        branch: name.clone(),
+                        commit: commit.id.clone(),
+                    });
+                    refs.branches.insert(name.clone(), commit.id);
+                }
+            }
+        }
+        if let Some(detached) = refs.detached.take() {
+            let chain = self.intact_chain(&detached.commit)?;
+            let kept = self.graft_intact(&chain, &mut intact, &mut grafted, &mut actions)?;
+            match kept.as_ref() == Some(&detached.commit) {
+                true => refs.detached = Some(detached),
+                false => actions.push(RepairAction::ReattachedHead {
+                    tag: detached.tag,
+                    commit: detached.commit,
+                }),
+            }
+        }
+        self.save_refs(&refs)?;
+        for (tag, chain) in tags.iter().zip(&chains[branches.len()..]) {
+            let kept = self.graft_intact(chain, &mut intact, &mut grafted, &mut actions)?;
+            if kept.as_ref() != Some(&tag.commit_id) {
+                self.remove_tag(tag)?;
+                actions.push(RepairAction::RemovedTag {
+                    name: tag.name.clone(),
+                    commit: tag.commit_id.clone(),
+                });
+            }
+        }
+
+        self.rebuild_bloom_locked()?;
+        actions.push(RepairAction::RebuiltBloom);
+
+        let tree = self.current_tree()?;
+        let entries = tree
+            .entries
+            .iter()
+            .map(|(k, v)| Ok((k.clone(), self.load_value(v)?)))
+            .collect::<Result<Vec<_>>>()?;
+        self.indexes().lock().unwrap().rebuild_all(&entries);
+        self.save_indexes()?;
+        actions.push(RepairAction::RebuiltIndexes);
+
+        if self.config().commit_graph {
+            self.load_commit_graph()?;
+            self.save_commit_graph()?;
+        }
+
+        let report = RepairReport {
+            before,
+            actions,
+            after: self.run_fsck()?,
+        };
+        let name = format!(
+            "repair-{}.txt",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ")
+        );
+        self.backend.write(
+            &backend::key(CORRUPT_DIR, &name),
+            report.to_string().as_bytes(),
+        )?;
+        Ok(report)
+    }
+
+    /// Commits no branch or tag leads to, such as those of a deleted branch,
+    /// newest first. Only the tip of each lost line of history is listed;
+    /// branching from it (`create_branch_at`) brings back its ancestors too.
+    pub fn lost_found(&self) -> Result<Vec<Commit>> {
+        let mut heads: Vec<String> = self.load_refs()?.branches.into_values().collect();
+        heads.extend(self.tags()?.into_iter().map(|t| t.commit_id));
+        let mut reachable = HashSet::new();
+        for head in heads {
+            let mut next = Some(head);
+            while let Some(id) = next.take() {
+                if !reachable.insert(id.clone()) {
+                    break;
+                }
+                next = self.commit_entry(&id).ok().and_then(|c| c.parent);
+            }
+        }
+
+        let mut lost = Vec::new();
+        for id in self.backend.list(COMMITS_DIR)? {
+            if reachable.contains(&id) {
+                continue;
+            }
+            // Unreadable commits are fsck's business, not ours.
+            if let Ok(commit) = self.load_commit(&id) {
+                lost.push(commit);
+            }
+        }
+        let parents: HashSet<_> = lost.iter().filter_map(|c| c.parent.clone()).collect();
+        lost.retain(|c| !parents.contains(&c.id));
+        lost.sort_by(|a, b| b.timestamp.cmp(&a.timestamp).then(a.id.cmp(&b.id)));
+        Ok(lost)
+    }
+
+    fn run_fsck(&self) -> Result<FsckReport> {
+        let mut report = FsckReport::default();
+
+        let refs = self.load_refs()?;
+        let mut starts: Vec<(String, String)> = refs
+            .branches
+            .iter()
+            .map(|(name, id)| (format!("branch {}", name), id.clone()))
+            .collect();
+        for tag in self.tags()? {
+            starts.push((format!("tag {}", tag.name), tag.commit_id));
+        }
+        starts.sort();
+
+        let mut commits = HashSet::new();
+        let mut trees = BTreeMap::new(); // tree → a commit using it
+        let mut commit_trees = HashMap::new();
+        for (name, id) in &starts {
+            let mut next = Some((id.clone(), None::<String>));
+            while let Some((id, child)) = next.take() {
+                if !commits.insert(id.clone()) {
+                    break;
+                }
+                let commit = match self.backend.read(&backend::key(COMMITS_DIR, &id))? {
+                    None => {
+                        commits.remove(&id);
+                        report.issues.push(match child {
+                            Some(child) => FsckIssue::MissingCommit {
+                                commit: id,
+                                referenced_by: child,
+                            },
+                            None => FsckIssue::DanglingRef {
+                                name: name.clone(),
+                                commit: id,
+                            },
+                        });
+                        break;
+                    }
+                    Some(data) => match serde_json::from_slice::<Commit>(&data) {
+                        Ok(commit) => commit,
+                        Err(e) => {
+                            report.issues.push(FsckIssue::CorruptCommit {
+                                commit: id,
+                                reason: e.to_string(),
+                            });
+                            break;
+                        }
+                    },
+                };
+                report.commits_checked += 1;
+                // Compaction grafts the oldest kept commit onto nothing,
+                // keeping its id, so root commits may legitimately differ.
+                if commit.id != id || (!commit.verify() && commit.parent.is_some()) {
+                    report.issues.push(FsckIssue::CorruptCommit {
+                        commit: id.clone(),
+                        reason: "content does not match id".into(),
+                    });
+                }
+                trees.entry(commit.tree_root.clone()).or_insert(id.clone());
+                commit_trees.insert(id.clone(), commit.tree_root);
+                next = commit.parent.map(|parent| (parent, Some(id)));
+            }
+        }
+
+        let head_trees: HashSet<_> = refs
+            .branches
+            .values()
+            .filter_map(|id| commit_trees.get(id))
+            .collect();
+        let mut blocks = BTreeMap::new(); // block → a key whose value uses it
+        let mut blobs = BTreeMap::new(); // likewise for blobs
+        let mut live_keys = HashSet::new();
+        for (tree_root, commit) in &trees {
+            let Some(data) = self.backend.read(&backend::key(TREES_DIR, tree_root))? else {
+                report.issues.push(FsckIssue::MissingTree {
+                    tree: tree_root.clone(),
+                    commit: commit.clone(),
+                });
+                continue;
+            };
+            let tree = match serde_json::from_slice::<Tree>(&data) {
+                Ok(tree) => tree,
+                Err(e) => {
+                    report.issues.push(FsckIssue::CorruptTree {
+                        tree: tree_root.clone(),
+                        reason: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            report.trees_checked += 1;
+            if tree.root_hash != *tree_root || !tree.verify() {
+                report.issues.push(FsckIssue::CorruptTree {
+                    tree: tree_root.clone(),
+                    reason: "content does not match root hash".into(),
+                });
+            }
+            for (key, value) in &tree.entries {
+                for hash in value.block_hashes() {
+                    blocks
+                        .entry(hash.to_string())
+                        .or_insert_with(|| key.clone());
+                }
+                if let Some(hash) = value.blob_hash() {
+                    blobs.entry(hash.to_string()).or_insert_with(|| key.clone());
+                }
+            }
+            if head_trees.contains(tree_root) {
+                live_keys.extend(tree.entries.into_keys());
+            }
+        }
+
+        for (hash, key) in &blocks {
+            if !self.store.contains(hash) {
+                report.issues.push(FsckIssue::MissingBlock {
+                    block: hash.clone(),
+                    key: key.clone(),
+                });
+                continue;
+            }
+            report.blocks_checked += 1;
+            if let Err(e) = self.store.verify(hash) {
+                report.issues.push(FsckIssue::CorruptBlock {
+                    block: hash.clone(),
+                    reason: e.to_string(),
+                });
+            }
+        }
+        // Blobs are reported like blocks.
+        for (hash, key) in &blobs {
+            if !self.blobs.contains(hash) {
+                report.issues.push(FsckIssue::MissingBlock {
+                    block: hash.clone(),
+                    key: key.clone(),
+                });
+                continue;
+            }
+            report.blocks_checked += 1;
+            if let Err(e) = self.blobs.verify(hash) {
+                report.issues.push(FsckIssue::CorruptBlock {
+                    block: hash.clone(),
+                    reason: e.to_string(),
+                });
+            }
+        }
+
+        self.check_derived_files(&live_keys, &mut report)?;
+
+        report.unreachable_commits = self
+            .backend
+            .list(COMMITS_DIR)?
+            .into_iter()
+            .filter(|id| !commits.contains(id))
+            .collect();
+        report.unreachable_trees = self
+            .backend
+            .list(TREES_DIR)?
+            .into_iter()
+            .filter(|id| !trees.contains_key(id))
+            .collect();
+        report.unreachable_blocks = self
+            .store
+            .hashes()?
+            .into_iter()
+            .filter(|hash| !blocks.contains_key(hash))
+            .collect();
+        Ok(report)
+    }
+
+    /// Move `key` to `corrupt/<key>`; returns whether it existed.
+    fn quarantine(&self, key: &str) -> Result<bool> {
+        let Some(data) = self.backend.read(key)? else {
+            return Ok(false);
+        };
+        self.backend.write(&backend::key(CORRUPT_DIR, key), &data)?;
+        self.backend.delete(key)?;
+        Ok(true)
+    }
+
+    /// A tree that loads and matches its root hash, or `None`.
+    fn load_valid_tree(&self, root_hash: &str) -> Option<Arc<Tree>> {
+        self.load_tree(root_hash)
+            .ok()
+            .filter(|tree| tree.root_hash == root_hash && tree.verify())
+    }
+
+    /// The commits from `head` back to the first one that is missing or
+    /// corrupt (after quarantine, a corrupt commit is missing), newest first.
+    fn intact_chain(&self, head: &str) -> Result<Vec<Commit>> {
+        let mut chain: Vec<Commit> = Vec::new();
+        let mut seen = HashSet::new();
+        let mut next = Some(head.to_string());
+        while let Some(id) = next.take() {
+            let Ok(commit) = self.load_commit(&id) else {
+                break;
+            };
+            if commit.id != id || !seen.insert(id) {
+                break;
+            }
+            next = commit.parent.clone();
+            chain.push(commit);
+        }
+        Ok(chain)
+    }
+
+    /// Find the newest commit of `chain` whose tree and blocks are intact,
+    /// and cut history at the first damaged commit below it. Returns the
+    /// commit the ref should point at, or `None` if nothing is intact.
+    fn graft_intact(
+        &self,
+        chain: &[Commit],
+        intact: &mut HashMap<String, bool>,
+        grafted: &mut HashSet<String>,
+        actions: &mut Vec<RepairAction>,
+    ) -> Result<Option<String>> {
+        let mut is_intact = |commit: &Commit| -> bool {
+            *intact.entry(commit.id.clone()).or_insert_with(|| {
+                self.load_valid_tree(&commit.tree_root).is_some_and(|tree| {
+                    tree.entries.values().all(|v| {
+                        v.block_hashes()
+                            .into_iter()
+                            .all(|hash| self.store.verify(hash).is_ok())
+                            && v.blob_hash()
+                                .is_none_or(|hash| self.blobs.verify(hash).is_ok())
+                    })
+                })
+            })
+        };
+        let Some(start) = chain.iter().position(&mut is_intact) else {
+            return Ok(None);
+        };
+        let mut end = start;
+        while end + 1 < chain.len() && is_intact(&chain[end + 1]) {
+            end += 1;
+        }
+        // The oldest intact commit's parent is either gone or damaged.
+        let oldest = &chain[end];
+        if let Some(parent) = &oldest.parent {
+            if grafted.insert(oldest.id.clone()) {
+                let mut root = oldest.clone();
+                root.parent = None;
+                self.save_commit(&root)?;
+                actions.push(RepairAction::GraftedCommit {
+                    commit: oldest.id.clone(),
+                    lost_parent: parent.clone(),
+                });
+            }
+        }
+        Ok(Some(chain[start].id.clone()))
+    }
+
+    /// Check the persisted bloom filter and indexes against the live keys.
+    fn check_derived_files(
+        &self,
+        live_keys: &HashSet<String>,
+        report: &mut FsckReport,
+    ) -> Result<()> {
+        let mut corrupt = Vec::new();
+        let bloom = Self::read_bloom(self.backend.as_ref(), &mut corrupt)?;
+        report.issues.extend(
+            corrupt
+                .into_iter()
+                .map(|(file, reason)| FsckIssue::CorruptFile { file, reason }),
+        );
+        if let Some(bloom) = bloom {
+            let mut missing: Vec<_> = live_keys
+                .iter()
+                .filter(|key| !bloom.may_contain(key))
+                .collect();
+            missing.sort();
+            report
+                .issues
+                .extend(missing.into_iter().map(|key| FsckIssue::BloomMissingKey {
+                    key: key.to_string(),
+                }));
+        }
+        let (indexes, corrupt) = IndexManager::load(self.backend.as_ref())?;
+        report.issues.extend(
+            corrupt
+                .into_iter()
+                .map(|(file, reason)| FsckIssue::CorruptFile { file, reason }),
+        );
+        for name in indexes.list_indexes() {
+            let Some(index) = indexes.get_index(&name) else {
+                continue;
+            };
+            let ns = namespace::split(&name).0;
+            for key in index.indexed_keys() {
+                let stored = match ns {
+                    Some(ns) => namespace::qualify(ns, key),
+                    None => key.to_string(),
+                };
+                if !live_keys.contains(&stored) {
+                    report.issues.push(FsckIssue::DanglingIndexEntry {
+                        index: name.clone(),
+                        key: key.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // ── Internal ──────────────────────────────────────────────
+
+    /// The tree at the current branch HEAD, served from the head cache
+    /// while HEAD stays on the same commit.
+    pub(crate) fn current_tree(&self) -> Result<Arc<Tree>> {
+        self.target_tree(Target::Head)
+    }
+
+    /// The tree at the tip of `branch`, through the same cache as HEAD's.
+    pub(crate) fn branch_tree(&self, branch: &str) -> Result<Arc<Tree>> {
+        self.target_tree(Target::Branch(branch))
+    }
+
+    /// The tree reads through `target` see, through the same cache.
+    fn target_tree(&self, target: Target) -> Result<Arc<Tree>> {
+        let commit = self.target_commit(target)?;
+        if let Some((cached, tree)) = &self.head.read().unwrap().head {
+            if cached.id == commit.id {
+                return Ok(Arc::clone(tree));
+            }
+        }
+        let tree = self.load_tree(&commit.tree_root)?;
+        self.head.write().unwrap().head = Some((commit, Arc::clone(&tree)));
+        Ok(tree)
+    }
+
+    fn commit_tree(&self, branch: &str, tree: &Tree, message: &str) -> Result<Commit> {
+        let (commit, saved) = self.prepare_commit(branch, tree, message)?;
+        self.advance_branch(branch, &commit, saved)?;
+        self.cover_bloom(tree)?;
+        Ok(commit)
+    }
+
+    /// Begin a WAL transaction writing to `branch`, recording the branch
+    /// when it is not simply the checked-out one.
+    fn begin_logged(&self, wal: &mut Wal, target: Target, branch: &str) -> Result<u64> {
+        let tx = wal.begin()?;
+        if !matches!(target, Target::Head) {
+            wal.log_branch(tx, branch.into())?;
+        }
+        Ok(tx)
+    }
+
+    /// Commit a tree built on `base`, the tip of `branch` when the write
+    /// began, for a WAL transaction. The tree and commit objects are
+    /// written before the WAL commit record and the branch ref after it, so
+    /// recovery can finish a transaction that crashed in between.
+    ///
+    /// The writer lock only keeps out writers of this process: should
+    /// another process have moved the branch since `base`, this rolls the
+    /// transaction back and fails with `Conflict`, before writing anything,
+    /// rather than commit over its changes.
+    fn commit_logged(
+        &self,
+        branch: &str,
+        base: Option<&String>,
+        tx_id: u64,
+        tree: &Tree,
+        message: &str,
+    ) -> Result<Commit> {
+        let parent = self.branch_commit(branch).ok().map(|c| c.id);
+        if parent.as_ref() != base {
+            self.wal.lock().unwrap().rollback(tx_id)?;
+            let no_commits = || "no commits".to_string();
+            return Err(IcebergError::Conflict {
+                branch: branch.to_string(),
+                expected: base.cloned().unwrap_or_else(no_commits),
+                actual: parent.unwrap_or_else(no_commits),
+            });
+        }
+        let (commit, saved) = self.write_commit(parent, tree, message)?;
+        self.wal.lock().unwrap().commit(tx_id, commit.id.clone())?;
+        self.advance_branch(branch, &commit, saved)?;
+        Ok(commit)
+    }
+
+    /// Write the tree and a commit on top of `branch`, without moving it.
+    /// Returns the commit and the tree as cached.
+    fn prepare_commit(
+        &self,
+        branch: &str,
+        tree: &Tree,
+        message: &str,
+    ) -> Result<(Commit, Arc<Tree>)> {
+        let parent = self.branch_commit(branch).ok().map(|c| c.id);
+        self.write_commit(parent, tree, message)
+    }
+
+    /// Write the tree and a commit of it on `parent`. Returns the commit and
+    /// the tree as cached.
+    fn write_commit(
+        &self,
+        parent: Option<String>,
+        tree: &Tree,
+        message: &str,
+    ) -> Result<(Commit, Arc<Tree>)> {
+        // Save tree (large values were already written as blocks by `store_value`)
+        let saved = self.save_tree(tree)?;
+        let commit = Commit::new(parent, tree.root_hash.clone(), message.into());
+        self.save_commit(&commit)?;
+        Ok((commit, saved))
+    }
+
+    /// Point `branch` at `commit`, whose tree is `tree`.
+    fn advance_branch(&self, branch: &str, commit: &Commit, tree: Arc<Tree>) -> Result<()> {
+        let mut refs = self.load_refs()?;
+        refs.branches.insert(branch.into(), commit.id.clone());
+        self.save_refs(&refs)?;
+        self.commits_since_compaction
+            .fetch_add(1, Ordering::Relaxed);
+        self.head.write().unwrap().head = Some((commit.clone(), tree));
+        Ok(())
+    }
+
+    /// Ids of every commit reachable from a branch head.
+    fn reachable_commits(&self) -> Result<HashSet<String>> {
+        let refs = self.load_refs()?;
+        let mut reachable = HashSet::new();
+        for head in refs.branches.values() {
+            let mut current = Some(head.clone());
+            while let Some(id) = current {
+                if !reachable.insert(id.clone()) {
+                    break;
+                }
+                current = match self.load_commit(&id) {
+                    Ok(c) => c.parent,
+                    Err(IcebergError::CommitNotFound(_)) => None,
+                    Err(e) => return Err(e),
+                };
+            }
+        }
+        Ok(reachable)
+    }
+
+    /// Turn raw bytes into a tree value: inline below the configured
+    /// threshold, otherwise written to the block store and referenced by hash.
+    fn store_value(&self, value: Vec<u8>) -> Result<TreeValue> {
+        if let Some(stored) = self.spill(&value)? {
+            return Ok(stored);
+        }
+        let threshold = self.config.lock().unwrap().inline_threshold;
+        if value.len() < threshold {
+            return Ok(TreeValue::Inline(value));
+        }
+        let size = value.len() as u64;
+        let hash = self.store.put(&Block::new(value))?;
+        Ok(TreeValue::Block { hash, size })
+    }
+
+    /// `store_value` for several values, hashing and writing their blocks
+    /// in parallel.
+    fn store_values(&self, values: Vec<Vec<u8>>) -> Result<Vec<TreeValue>> {
+        let threshold = self.config.lock().unwrap().inline_threshold;
+        let mut stored = Vec::with_capacity(values.len());
+        let mut large = Vec::new();
+        for (i, value) in values.into_iter().enumerate() {
+            match self.spill(&value)? {
+                Some(spilled) => stored.push(Some(spilled)),
+                None if value.len() < threshold => stored.push(Some(TreeValue::Inline(value))),
+                None => {
+                    stored.push(None);
+                    large.push((i, value));
+                }
+            }
+        }
+        let hash = |(_, value): &(usize, Vec<u8>)| compute_hash(value);
+        let bytes: usize = large.iter().map(|(_, value)| value.len()).sum();
+        let hashes = match bytes < PARALLEL_MIN_BYTES {
+            true => large.iter().map(hash).collect(),
+            false => par_map(&large, hash),
+        };
+        let (slots, blocks): (Vec<usize>, Vec<Block>) = large
+            .into_iter()
+            .zip(hashes)
+            .map(|((i, data), hash)| (i, Block { hash, data }))
+            .unzip();
+        self.store.put_many(&blocks)?;
+        for (i, block) in slots.into_iter().zip(blocks) {
+            stored[i] = Some(TreeValue::Block {
+                hash: block.hash,
+                size: block.data.len() as u64,
+            });
+        }
+        Ok(stored.into_iter().flatten().collect())
+    }
+
+    /// Store `value` in the blob store if it reaches
+    /// `DbConfig::blob_threshold`.
+    fn spill(&self, value: &[u8]) -> Result<Option<TreeValue>> {
+        let threshold = self.config.lock().unwrap().blob_threshold;
+        if threshold == 0 || (value.len() as u64) < threshold {
+            return Ok(None);
+        }
+        Ok(Some(TreeValue::Blob {
+            blob: self.blobs.put(value)?,
+            size: value.len() as u64,
+        }))
+    }
+
+    /// Materialize a tree value, reading it from the block or blob store if
+    /// needed.
+    pub fn load_value(&self, value: &TreeValue) -> Result<Vec<u8>> {
+        match value {
+            TreeValue::Inline(v) => Ok(v.clone()),
+            TreeValue::Block { hash, .. } => Ok(self.store.get(hash)?.data),
+            TreeValue::Chunked { chunks, size } => {
+                let mut data = Vec::with_capacity(*size as usize);
+                for hash in chunks {
+                    data.extend_from_slice(&self.store.get(hash)?.data);
+                }
+                Ok(data)
+            }
+            TreeValue::Blob { blob, .. } => self.blobs.get(blob),
+        }
+    }
+
+    /// Write `tree` and its bloom filter, and cache it. Returns the cached
+    /// copy.
+    fn save_tree(&self, tree: &Tree) -> Result<Arc<Tree>> {
+        self.backend
+            .write(&backend::key(TREES_DIR, &tree.root_hash), &tree.to_bytes())?;
+        let bloom = BloomFilter::with_keys(tree.entries.keys());
+        self.backend.write(
+            &backend::key(TREE_BLOOMS_DIR, &tree.root_hash),
+            &bloom.to_bytes(),
+        )?;
+        let saved = Arc::new(tree.clone());
+        self.tree_cache.lock().unwrap().insert(
+            tree.root_hash.clone(),
+            Arc::clone(&saved),
+            tree.approx_bytes(),
+        );
+        Ok(saved)
+    }
+
+    /// Whether the tree `root_hash` may hold any of `keys`, by its bloom
+    /// filter. Trees without a readable filter (written before trees had
+    /// them) may hold anything.
+    fn tree_may_contain<'k>(
+        &self,
+        root_hash: &str,
+        keys: impl IntoIterator<Item = &'k str>,
+    ) -> bool {
+        let bloom = self
+            .backend
+            .read(&backend::key(TREE_BLOOMS_DIR, root_hash))
+            .ok()
+            .flatten()
+            .and_then(|data| BloomFilter::from_bytes(&data).ok());
+        match bloom {
+            Some(bloom) => keys
+                .into_iter()
+                .any(|key| bloom.may_contain(key.as_bytes())),
+            None => true,
+        }
+    }
+
+    fn load_tree(&self, root_hash: &str) -> Result<Arc<Tree>> {
+        if let Some(tree) = self.tree_cache.lock().unwrap().get(root_hash) {
+            return Ok(tree);
+        }
+        let data = self
+            .backend
+            .read(&backend::key(TREES_DIR, root_hash))?
+            .ok_or_else(|| IcebergError::Corruption(format!("tree not found: {}", root_hash)))?;
+        let tree: Arc<Tree> = Arc::new(serde_json::from_slice(&data)?);
+        let size = tree.approx_bytes();
+        self.tree_cache
+            .lock()
+            .unwrap()
+            .insert(root_hash.to_string(), Arc::clone(&tree), size);
+        Ok(tree)
+    }
+
+    fn save_commit(&self, commit: &Commit) -> Result<()> {
+        let data = serde_json::to_vec_pretty(commit)?;
+        self.backend
+            .write(&backend::key(COMMITS_DIR, &commit.id), &data)?;
+        // Compaction rewrites commits in place; keep a cached HEAD in step.
+        if let Some((cached, _)) = &mut self.head.write().unwrap().head {
+            if cached.id == commit.id {
+                *cached = commit.clone();
+            }
+        }
+        if let Some(index) = self.children.lock().unwrap().as_mut() {
+            index.insert(commit);
+        }
+        let rewritten = match self.commit_graph.write().unwrap().as_mut() {
+            Some(graph) => graph.insert(commit),
+            None => return Ok(()),
+        };
+        // Opening tops up a stale file with commits written since, but
+        // cannot tell that one was rewritten: drop it until the next flush.
+        if rewritten {
+            self.backend.delete(COMMIT_GRAPH_FILE)?;
+        }
+        self.dirty.lock().unwrap().commit_graph = true;
+        Ok(())
+    }
+
+    fn delete_commit(&self, id: &str) -> Result<()> {
+        self.backend.delete(&backend::key(COMMITS_DIR, id))?;
+        if let Some(index) = self.children.lock().unwrap().as_mut() {
+            index.remove(id);
+        }
+        if let Some(graph) = self.commit_graph.write().unwrap().as_mut() {
+            graph.remove(id);
+            self.dirty.lock().unwrap().commit_graph = true;
+        }
+        Ok(())
+    }
+
+    /// A commit's generation, while the commit graph is on.
+    fn commit_generation(&self, id: &str) -> Option<u64> {
+        {
+            let graph = self.commit_graph.read().unwrap();
+            if !graph.as_ref()?.generations_stale() {
+                return graph.as_ref()?.generation(id);
+            }
+        }
+        let mut graph = self.commit_graph.write().unwrap();
+        let graph = graph.as_mut()?;
+        graph.refresh_generations();
+        graph.generation(id)
+    }
+
+    /// The links of a commit, from the commit graph if it is on.
+    fn commit_entry(&self, id: &str) -> Result<GraphEntry> {
+        if let Some(entry) = self
+            .commit_graph
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|g| g.get(id))
+        {
+            return Ok(entry.clone());
+        }
+        self.load_commit(id).map(|c| GraphEntry::from(&c))
+    }
+
+    /// Run `f` on the child index, building it from every stored commit
+    /// the first time.
+    fn with_children<T>(&self, f: impl FnOnce(&ChildIndex) -> T) -> Result<T> {
+        let mut children = self.children.lock().unwrap();
+        let graph = self.commit_graph.read().unwrap();
+        let index = match (children.take(), graph.as_ref()) {
+            (Some(index), _) => index,
+            (None, Some(graph)) => ChildIndex::from_links(graph.links()),
+            (None, None) => {
+                let mut commits = Vec::new();
+                for id in self.backend.list(COMMITS_DIR)? {
+                    // Unreadable commits are for fsck to report.
+                    if let Ok(commit) = self.load_commit(&id) {
+                        commits.push(commit);
+                    }
+                }
+                ChildIndex::build(commits)
+            }
+        };
+        Ok(f(children.insert(index)))
+    }
+
+    fn load_commit(&self, id: &str) -> Result<Commit> {
+        let data = self
+            .backend
+            .read(&backend::key(COMMITS_DIR, id))?
+            .ok_or_else(|| IcebergError::CommitNotFound(id.into()))?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    fn load_refs(&self) -> Result<Refs> {
+        let Some(version) = self.backend.version(REFS_FILE)? else {
+            return Ok(Refs::new());
+        };
+        if let Some((cached, refs)) = &self.head.read().unwrap().refs {
+            if *cached == version {
+                return Ok(refs.clone());
+            }
+            self.foreign_refs.store(true, Ordering::Relaxed);
+        }
+        let Some(data) = self.backend.read(REFS_FILE)? else {
+            return Ok(Refs::new());
+        };
+        let refs: Refs = serde_json::from_slice(&data)?;
+        // Should the file have changed since `version` was taken, the next
+        // load misses and reads it again.
+        self.head.write().unwrap().refs = Some((version, refs.clone()));
+        Ok(refs)
+    }
+
+    fn save_refs(&self, refs: &Refs) -> Result<()> {
+        let data = serde_json::to_vec_pretty(refs)?;
+        self.backend.write(REFS_FILE, &data)?;
+        let cached = self
+            .backend
+            .version(REFS_FILE)?
+            .map(|version| (version, refs.clone()));
+        self.head.write().unwrap().refs = cached;
+        Ok(())
+    }
+
+    fn save_tag(&self, tag: &Tag) -> Result<()> {
+        let data = serde_json::to_vec_pretty(tag)?;
+        self.backend.write(&backend::key(TAGS_DIR, &tag.id), &data)
+    }
+
+    fn load_tag_by_name(&self, name: &str) -> Result<Option<Tag>> {
+        for id in self.backend.list(TAGS_DIR)? {
+            let Some(data) = self.backend.read(&backend::key(TAGS_DIR, &id))? else {
+                continue;
+            };
+            let tag: Tag = serde_json::from_slice(&data)?;
+            if tag.name == name {
+                return Ok(Some(tag));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Rebuild the tree `root_hash` of a commit from the trees of its parent and
+/// child. A commit usually changes a single key, so its tree is one of them
+/// with at most one key switched to the other's value; a candidate is only
+/// accepted if it hashes to `root_hash`. Neighbours differing in more than
+/// `REGENERATE_MAX_CHANGES` keys are only tried as they are.
+fn regenerate_tree(
+    root_hash: &str,
+    parent: Option<Arc<Tree>>,
+    child: Option<Arc<Tree>>,
+) -> Option<Tree> {
+    let parent = parent.unwrap_or_else(|| Arc::new(Tree::empty()));
+    let child = child.unwrap_or_else(|| Arc::new(Tree::empty()));
+    if let Some(tree) = [&parent, &child]
+        .into_iter()
+        .find(|tree| tree.root_hash == root_hash)
+    {
+        return Some(Tree::clone(tree));
+    }
+    let diff = parent.diff(&child);
+    if diff.total_changes() > REGENERATE_MAX_CHANGES {
+        return None;
+    }
+    let changed = diff.added.iter().chain(&diff.removed).chain(&diff.modified);
+    let regenerated = changed
+        .flat_map(|key| [(&parent, &child, key), (&child, &parent, key)])
+        .map(|(base, other, key)| match other.get(key) {
+            Some(value) => base.insert(key.clone(), value.clone()),
+            None => base.delete(key),
+        })
+        .find(|tree| tree.root_hash == root_hash);
+    regenerated
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        // Best effort: anything left unflushed is rebuilt from the WAL on open.
+        let _ = self.flush();
+    }
+}
+
+/// Read until `buf` is full or the reader is exhausted. Returns bytes read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+/// Paging and key filtering for `Database::scan_prefix_with` and
+/// `Database::range_with`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// Most entries to return; `None` for all of them.
+    pub limit: Option<usize>,
+    /// Entries to skip, counted after `start_after`.
+    pub offset: usize,
+    /// Only return keys after this one; pass the last key of the previous
+    /// page to get the next.
+    pub start_after: Option<String>,
+    /// Only keys matching this glob or regex; the prefix still applies.
+    pub matching: Option<KeyPattern>,
+}
+
+impl From<KeyPattern> for ScanOptions {
+    fn from(pattern: KeyPattern) -> Self {
+        Self {
+            matching: Some(pattern),
+            ..Default::default()
+        }
+    }
+}
+
+/// A quota and how much of it the current branch uses, as returned by
+/// `Database::quotas`.
+#[derive(Debug, Clone)]
+pub struct QuotaUsage {
+    pub prefix: String,
+    pub quota: Quota,
+    /// Keys under the prefix.
+    pub keys: u64,
+    /// Total size of their values in bytes.
+    pub bytes: u64,
+}
+
+/// Metadata about a stored value, as returned by `Database::stat_key`.
+#[derive(Debug, Clone)]
+pub struct KeyStat {
+    pub key: String,
+    /// Value size in bytes.
+    pub size: u64,
+    /// Whether the value is stored inline in the tree.
+    pub inline: bool,
+    /// Hash of the block holding the value, for single-block values.
+    pub block_hash: Option<String>,
+    /// Hash of the blob holding the value, for values in the blob store.
+    pub blob_hash: Option<String>,
+    /// Number of chunks, for streamed values (0 otherwise).
+    pub chunks: usize,
+    /// The commit that last changed this key's value.
+    pub last_commit: Commit,
+}
+
+/// Result of training a zstd dictionary.
+#[derive(Debug, Clone)]
+pub struct TrainedDictionary {
+    /// Dictionary id (hash of its bytes).
+    pub id: String,
+    /// Dictionary size in bytes.
+    pub size: usize,
+    /// Number of values sampled for training.
+    pub samples: usize,
+}
+
+/// Number of keys in each namespace of `tree`, leaving out the default one.
+fn namespace_counts(tree: &Tree) -> BTreeMap<String, usize> {
+    let mut counts = BTreeMap::new();
+    for key in tree.entries.keys() {
+        if let (Some(ns), _) = namespace::split(key) {
+            *counts.entry(ns.to_string()).or_default() += 1;
+        }
+    }
+    counts
+}
+
+/// Keys of namespace `ns` in `tree` from `start` (a prefix when there is no
+/// `end`) up to `end`, exclusive, paged by `options` and named within the
+/// namespace. No values are read.
+pub(crate) fn scan_page<'t>(
+    tree: &'t Tree,
+    ns: Option<&'t str>,
+    start: &str,
+    end: Option<&str>,
+    options: &ScanOptions,
+) -> impl Iterator<Item = (&'t str, &'t TreeValue)> {
+    use std::ops::Bound;
+    let stored = |key: &str| match ns {
+        Some(ns) => namespace::qualify(ns, key),
+        None => key.to_string(),
+    };
+    let (first, prefix) = (stored(start), end.is_none().then(|| stored(start)));
+    let matching = options.matching.clone();
+    let lower = match options.start_after.as_deref().map(stored) {
+        Some(after) if after >= first => Bound::Excluded(after),
+        _ => Bound::Included(first),
+    };
+    let upper = match end {
+        Some(end) => Bound::Excluded(stored(end)),
+        None => Bound::Unbounded,
+    };
+    let empty = matches!(
+        (&lower, &upper),
+        (Bound::Included(l) | Bound::Excluded(l), Bound::Excluded(u)) if l >= u
+    );
+    (!empty)
+        .then(|| tree.entries.range((lower, upper)))
+        .into_iter()
+        .flatten()
+        .take_while(move |(k, _)| prefix.as_ref().is_none_or(|p| k.starts_with(p)))
+        .filter_map(move |(k, v)| match namespace::split(k) {
+            (found, key) if found == ns => Some((key, v)),
+            _ => None,
+        })
+        .filter(move |(key, _)| matching.as_ref().is_none_or(|p| p.matches(key)))
+        .skip(options.offset)
+        .take(options.limit.unwrap_or(usize::MAX))
+}
+
+/// A key up to and including its first `delimiter`, the whole key if it has
+/// none, shown as `[ns] prefix` for keys in other namespaces.
+fn key_prefix(key: &str, delimiter: char) -> String {
+    let (ns, local) = namespace::split(key);
+    let end = local
+        .find(delimiter)
+        .map_or(local.len(), |i| i + delimiter.len_utf8());
+    match ns {
+        Some(ns) => format!("[{}] {}", ns, &local[..end]),
+        None => local[..end].to_string(),
+    }
+}
+
+/// Ids of the commits of a log.
+fn commit_ids(log: &[Commit]) -> HashSet<String> {
+    log.iter().map(|c| c.id.clone()).collect()
+}
+
+/// Reports `(done, total)` to a progress callback every `step` items and
+/// after the last one, or once right away when there are none.
+struct Ticker<'a> {
+    done: usize,
+    total: usize,
+    step: usize,
+    progress: &'a mut dyn FnMut(usize, usize),
+}
+
+impl<'a> Ticker<'a> {
+    fn new(total: usize, step: usize, progress: &'a mut dyn FnMut(usize, usize)) -> Self {
+        if total == 0 {
+            progress(0, 0);
+        }
+        Self {
+            done: 0,
+            total,
+            step,
+            progress,
+        }
+    }
+
+    fn tick(&mut self) {
+        self.done += 1;
+        if self.done.is_multiple_of(self.step) || self.done == self.total {
+            (self.progress)(self.done, self.total);
+        }
+    }
+
+    /// Report completion even if fewer items than expected turned up.
+    fn finish(&mut self) {
+        if self.done < self.total {
+            self.done = self.total;
+            (self.progress)(self.done, self.total);
+        }
+    }
+}
+
+/// Database statistics.
+#[derive(Debug, Clone, Serialize)]
+pub struct DbStats {
+    /// Keys in the default namespace.
+    pub key_count: usize,
+    /// Other namespaces with at least one key.
+    pub namespace_count: usize,
+    pub commit_count: usize,
+    pub branch_count: usize,
+    pub block_count: usize,
+    pub disk_usage: u64,
+    /// Kind of the key filter the `bloom_*` fields describe.
+    pub key_filter: FilterKind,
+    pub bloom_items: usize,
+    pub bloom_bits: usize,
+    pub bloom_fp_rate: f64,
+    pub index_count: usize,
+    pub wal_size: u64,
+    pub block_cache: CacheStats,
+    pub tree_cache: CacheStats,
+    /// Per-branch statistics, from `Database::stats_all_branches` (empty
+    /// otherwise).
+    pub branches: Vec<BranchStats>,
+}
+
+/// On-disk bytes by component, from `Database::disk_usage`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    pub blocks: u64,
+    pub blobs: u64,
+    pub trees: u64,
+    pub commits: u64,
+    pub wal: u64,
+    pub bloom: u64,
+    pub indexes: u64,
+    /// Refs, tags, config, the block log and compression dictionaries.
+    pub other: u64,
+    /// Bytes of the blocks, trees and commits below that no branch or tag
+    /// reaches; compaction reclaims them.
+    pub unreachable: u64,
+    pub unreachable_blocks: usize,
+    pub unreachable_blobs: usize,
+    pub unreachable_trees: usize,
+    pub unreachable_commits: usize,
+}
+
+impl DiskUsage {
+    /// Bytes of all components together.
+    pub fn total(&self) -> u64 {
+        self.blocks + self.trees + self.commits + self.wal + self.bloom + self.indexes + self.other
+    }
+}
+
+impl std::fmt::Display for DiskUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, bytes) in [
+            ("Blocks", self.blocks),
+            ("Blobs", self.blobs),
+            ("Trees", self.trees),
+            ("Commits", self.commits),
+            ("WAL", self.wal),
+            ("Bloom", self.bloom),
+            ("Indexes", self.indexes),
+            ("Other", self.other),
+            ("Total", self.total()),
+        ] {
+            writeln!(f, "{:<12} {:>12} bytes", format!("{}:", name), bytes)?;
+        }
+        writeln!(
+            f,
+            "{:<12} {:>12} bytes ({} blocks, {} blobs, {} trees, {} commits)",
+            "Unreachable:",
+            self.unreachable,
+            self.unreachable_blocks,
+            self.unreachable_blobs,
+            self.unreachable_trees,
+            self.unreachable_commits
+        )
+    }
+}
+
+/// Value sizes across a tree, from `Database::size_report`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SizeReport {
+    pub keys: usize,
+    pub total_bytes: u64,
+    /// Values by size, in buckets growing fourfold from `< 16` bytes up to
+    /// the one holding the largest value.
+    pub histogram: Vec<SizeBucket>,
+    /// The largest keys with their sizes, largest first.
+    pub largest_keys: Vec<(String, u64)>,
+    /// The key prefixes holding the most bytes, with their bytes and keys.
+    pub largest_prefixes: Vec<(String, u64, usize)>,
+}
+
+/// Values whose size is below `below` bytes and at least a quarter of it
+/// (or any size, for the first bucket).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SizeBucket {
+    pub below: u64,
+    pub values: usize,
+    pub bytes: u64,
+}
+
+impl std::fmt::Display for SizeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Values: {} ({} bytes)", self.keys, self.total_bytes)?;
+        for bucket in &self.histogram {
+            writeln!(
+                f,
+                "  < {:>10} bytes: {:>8} values, {} bytes",
+                bucket.below, bucket.values, bucket.bytes
+            )?;
+        }
+        writeln!(f, "Largest keys:")?;
+        for (key, size) in &self.largest_keys {
+            writeln!(f, "  {:>10} bytes  {}", size, key)?;
+        }
+        writeln!(f, "Largest prefixes:")?;
+        for (prefix, bytes, keys) in &self.largest_prefixes {
+            writeln!(f, "  {:>10} bytes  {} ({} keys)", bytes, prefix, keys)?;
+        }
+        Ok(())
+    }
+}
+
+/// A kind of stored object, for `Database::inspect`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    Commit,
+    Tree,
+    Block,
+    Tag,
+}
+
+impl std::str::FromStr for ObjectKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "commit" => Ok(ObjectKind::Commit),
+            "tree" => Ok(ObjectKind::Tree),
+            "block" => Ok(ObjectKind::Block),
+            "tag" => Ok(ObjectKind::Tag),
+            other => Err(format!("unknown object kind: {}", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for ObjectKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ObjectKind::Commit => "commit",
+            ObjectKind::Tree => "tree",
+            ObjectKind::Block => "block",
+            ObjectKind::Tag => "tag",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Where the checked-out branch stands, from `Database::status`.
+///
+/// Rebases and merges finish within a single call, so there is no
+/// in-progress state to report.
+#[derive(Debug, Clone)]
+pub struct Status {
+    /// The branch checked out, or last checked out while HEAD is detached.
+    pub branch: String,
+    /// The tag HEAD is detached at, if it is.
+    pub detached: Option<String>,
+    /// The HEAD commit; `None` before the first commit.
+    pub head: Option<Commit>,
+    /// Keys in the default namespace at HEAD.
+    pub key_count: usize,
+    /// Keys in all other namespaces at HEAD.
+    pub namespaced_keys: usize,
+    /// `(base, ahead, behind)` in commits against `DEFAULT_BRANCH`, unless
+    /// that is the branch checked out and HEAD is not detached.
+    pub base: Option<(String, usize, usize)>,
+    /// Bytes of writes in the WAL not yet checkpointed.
+    pub wal_size: u64,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.detached {
+            Some(tag) => writeln!(f, "HEAD detached at tag {}", tag)?,
+            None => writeln!(f, "On branch {}", self.branch)?,
+        }
+        match &self.head {
+            Some(head) => writeln!(
+                f,
+                "HEAD: {} {} ({})",
+                &head.id[..8.min(head.id.len())],
+                head.message,
+                age(chrono::Utc::now() - head.timestamp)
+            )?,
+            None => writeln!(f, "HEAD: (no commits yet)")?,
+        }
+        match self.namespaced_keys {
+            0 => writeln!(f, "Keys: {}", self.key_count)?,
+            n => writeln!(f, "Keys: {} (+{} in namespaces)", self.key_count, n)?,
+        }
+        match &self.base {
+            Some((base, 0, 0)) => writeln!(f, "Up to date with {}", base)?,
+            Some((base, ahead, behind)) => {
+                writeln!(f, "{} commits ahead of {}, {} behind", ahead, base, behind)?
+            }
+            None => {}
+        }
+        if self.wal_size > 0 {
+            writeln!(f, "WAL: {} bytes not yet checkpointed", self.wal_size)?;
+        }
+        Ok(())
+    }
+}
+
+/// A rough, human-readable age such as `5 minutes ago`.
+fn age(elapsed: chrono::Duration) -> String {
+    let secs = elapsed.num_seconds().max(0);
+    let (n, unit) = match secs {
+        0..60 => return "just now".to_string(),
+        60..3600 => (secs / 60, "minute"),
+        3600..86400 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+    format!("{} {}{} ago", n, unit, if n == 1 { "" } else { "s" })
+}
+
+/// Statistics of one branch, relative to `DEFAULT_BRANCH`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BranchStats {
+    pub name: String,
+    /// Id of the branch's head commit; `None` before its first commit.
+    pub head: Option<String>,
+    /// Keys in the default namespace at the branch head.
+    pub key_count: usize,
+    /// Commits in the branch's history.
+    pub commit_count: usize,
+    /// Commits on this branch that the default branch doesn't have.
+    pub ahead: usize,
+    /// Commits on the default branch that this branch doesn't have.
+    pub behind: usize,
+}
+
+/// A secondary index being built on another thread, from
+/// `Database::create_index_background`.
+pub struct IndexBuild {
+    /// Values indexed so far and values to index.
+    progress: Arc<(AtomicUsize, AtomicUsize)>,
+    handle: std::thread::JoinHandle<Result<()>>,
+}
+
+impl IndexBuild {
+    /// Existing values indexed so far, out of how many; `(0, 0)` until the
+    /// first report.
+    pub fn progress(&self) -> (usize, usize) {
+        (
+            self.progress.0.load(Ordering::Relaxed),
+            self.progress.1.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Whether the build has succeeded or failed.
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Wait until the index is live or the build has failed.
+    pub fn wait(self) -> Result<()> {
+        self.handle
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+}
+
+impl std::fmt::Display for DbStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Keys:       {}", self.key_count)?;
+        writeln!(f, "Namespaces: {}", self.namespace_count)?;
+        writeln!(f, "Commits:    {}", self.commit_count)?;
+        writeln!(f, "Branches:   {}", self.branch_count)?;
+        writeln!(f, "Blocks:     {}", self.block_count)?;
+        writeln!(f, "Disk:       {} bytes", self.disk_usage)?;
+        let label = match self.key_filter {
+            FilterKind::Bloom => "Bloom:",
+            FilterKind::Cuckoo => "Cuckoo:",
+        };
+        writeln!(
+            f,
+            "{:<12}{} items, {} bits, {:.4}% FP",
+            label,
+            self.bloom_items,
+            self.bloom_bits,
+            self.bloom_fp_rate * 100.0
+        )?;
+        writeln!(f, "Indexes:    {}", self.index_count)?;
+        writeln!(f, "WAL size:   {} bytes", self.wal_size)?;
+        for (name, cache) in [
+            ("Block cache", &self.block_cache),
+            ("Tree cache", &self.tree_cache),
+        ] {
+            writeln!(
+                f,
+                "{}: {} hits, {} misses ({:.1}% hit rate), {}/{} bytes",
+                name,
+                cache.hits,
+                cache.misses,
+                cache.hit_rate() * 100.0,
+                cache.bytes,
+                cache.capacity
+            )?;
+        }
+        if !self.branches.is_empty() {
+            writeln!(f, "Branches (ahead/behind {}):", DEFAULT_BRANCH)?;
+        }
+        for branch in &self.branches {
+            writeln!(
+                f,
+                "  {}: {} keys, {} commits, head {}, +{}/-{}",
+                branch.name,
+                branch.key_count,
+                branch.commit_count,
+                branch.head.as_deref().map_or("(none)", |id| &id[..8]),
+                branch.ahead,
+                branch.behind
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use crate::config::WalConfig;
+    use crate::cuckoo::CuckooFilter;
+    use crate::query::field;
+    use std::fs;
+
+    fn test_db() -> (tempfile::TempDir, Database) {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::init(tmp.path()).unwrap();
+        (tmp, db)
+    }
+
+    #[test]
+    fn put_and_get() {
+        let (_tmp, db) = test_db();
+        db.put("name", b"iceberg".to_vec(), None).unwrap();
+        assert_eq!(db.get("name").unwrap(), b"iceberg");
+    }
+
+    #[test]
+    fn key_not_found() {
+        let (_tmp, db) = test_db();
+        assert!(db.get("missing").is_err());
+    }
+
+    #[test]
+    fn delete_key() {
+        let (_tmp, db) = test_db();
+        db.put("x", b"1".to_vec(), None).unwrap();
+        db.delete("x", None).unwrap();
+        assert!(db.get("x").is_err());
+    }
+
+    #[test]
+    fn version_history() {
+        let (_tmp, db) = test_db();
+        db.put("a", b"1".to_vec(), Some("first")).unwrap();
+        db.put("b", b"2".to_vec(), Some("second")).unwrap();
+
+        let log = db.log().unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].message, "second");
+        assert_eq!(log[1].message, "first");
+    }
+
+    #[test]
+    fn time_travel() {
+        let (_tmp, db) = test_db();
+        let c1 = db.put("val", b"old".to_vec(), Some("v1")).unwrap();
+        db.put("val", b"new".to_vec(), Some("v2")).unwrap();
+
+        // Current version
+        assert_eq!(db.get("val").unwrap(), b"new");
+        // Old version
+        assert_eq!(db.get_at("val", &c1.id).unwrap(), b"old");
+    }
+
+    #[test]
+    fn historical_lookups_skip_trees_by_bloom_filter() {
+        let (tmp, db) = test_db();
+        let c1 = db.put("a", b"1".to_vec(), None).unwrap();
+        let c2 = db.put("b", b"2".to_vec(), None).unwrap();
+        db.put("c", b"3".to_vec(), None).unwrap();
+        drop(db);
+
+        // Without its tree, c1 can still rule out keys its filter lacks.
+        let db = Database::open(tmp.path()).unwrap();
+        fs::remove_file(tmp.path().join(TREES_DIR).join(&c1.tree_root)).unwrap();
+        assert!(matches!(
+            db.get_at("c", &c1.id),
+            Err(IcebergError::KeyNotFound(_))
+        ));
+        assert!(db.get_at("a", &c1.id).is_err());
+        assert_eq!(db.stat_key("b").unwrap().last_commit.id, c2.id);
+
+        // Trees without a filter are loaded as before.
+        fs::remove_file(tmp.path().join(TREE_BLOOMS_DIR).join(&c2.tree_root)).unwrap();
+        assert_eq!(db.get_at("a", &c2.id).unwrap(), b"1");
+        assert!(matches!(
+            db.get_at("c", &c2.id),
+            Err(IcebergError::KeyNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn branching() {
+        let (_tmp, db) = test_db();
+        db.put("shared", b"data".to_vec(), None).unwrap();
+
+        db.create_branch("feature").unwrap();
+        db.checkout("feature").unwrap();
+        db.put("feature_key", b"feature_val".to_vec(), None)
+            .unwrap();
+
+        db.checkout("main").unwrap();
+        assert!(db.get("feature_key").is_err()); // not on main
+        assert_eq!(db.get("shared").unwrap(), b"data"); // shared data still there
+    }
+
+    #[test]
+    fn merge_branches() {
+        let (_tmp, db) = test_db();
+        db.put("base", b"val".to_vec(), None).unwrap();
+
+        db.create_branch("feat").unwrap();
+        db.checkout("feat").unwrap();
+        db.put("new_key", b"new_val".to_vec(), None).unwrap();
+
+        db.checkout("main").unwrap();
+        db.merge("feat", None).unwrap();
+        assert_eq!(db.get("new_key").unwrap(), b"new_val");
+        assert_eq!(db.get("base").unwrap(), b"val");
+    }
+
+    #[test]
+    fn previews_leave_history_untouched() {
+        let (_tmp, db) = test_db();
+        db.put("a", b"1".to_vec(), None).unwrap();
+        db.put("b", b"1".to_vec(), None).unwrap();
+        db.create_branch("feat").unwrap();
+        db.checkout("feat").unwrap();
+        db.put("a", b"2".to_vec(), None).unwrap();
+        let picked = db.put("c", b"3".to_vec(), None).unwrap();
+        db.checkout("main").unwrap();
+        let head = db.head_commit().unwrap().id;
+
+        let diff = db.merge_preview("feat").unwrap();
+        assert_eq!(
+            (diff.added, diff.modified),
+            (vec!["c".into()], vec!["a".into()])
+        );
+        let diff = db.cherry_pick_preview(&picked.id).unwrap();
+        assert_eq!(diff.added, vec!["c"]);
+        assert!(diff.modified.is_empty());
+        assert_eq!(db.range_keys("a", "b").unwrap(), vec!["a"]);
+        assert_eq!(db.head_commit().unwrap().id, head);
+        assert!(db.merge_preview("nope").is_err());
+    }
+
+    #[test]
+    fn diff_versions() {
+        let (_tmp, db) = test_db();
+        let c1 = db.put("a", b"1".to_vec(), None).unwrap();
+        let c2 = db.put("b", b"2".to_vec(), None).unwrap();
+
+        let diff = db.diff(&c1.id, &c2.id).unwrap();
+        assert_eq!(diff.added, vec!["b"]);
+        assert!(diff.removed.is_empty());
+    }
+
+    #[test]
+    fn scan_prefix_works() {
+        let (_tmp, db) = test_db();
+        db.put("user:1", b"alice".to_vec(), None).unwrap();
+        db.put("user:2", b"bob".to_vec(), None).unwrap();
+        db.put("order:1", b"o1".to_vec(), None).unwrap();
+
+        let users = db.scan_prefix("user:").unwrap();
+        assert_eq!(users.len(), 2);
+    }
+
+    #[test]
+    fn stats_work() {
+        let (_tmp, db) = test_db();
+        db.put("k", b"v".to_vec(), None).unwrap();
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.key_count, 1);
+        assert_eq!(stats.commit_count, 1);
+    }
+
+    #[test]
+    fn create_and_list_tags() {
+        let (_tmp, db) = test_db();
+        let c = db.put("k", b"v".to_vec(), None).unwrap();
+        let tag = db
+            .create_tag("v1.0", Some(&c.id), Some("first release"))
+            .unwrap();
+        assert_eq!(tag.name, "v1.0");
+        assert_eq!(tag.commit_id, c.id);
+
+        let tags = db.tags().unwrap();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].name, "v1.0");
+    }
+
+    #[test]
+    fn tags_match_globs_in_version_order() {
+        let (_tmp, db) = test_db();
+        db.put("k", b"v".to_vec(), None).unwrap();
+        assert!(db.latest_tag("v*").unwrap().is_none());
+        for name in [
+            "v1.10.0",
+            "v1.9.0",
+            "release-1.11.0",
+            "v-next",
+            "v2.0.0-rc.1",
+        ] {
+            db.create_tag(name, None, None).unwrap();
+        }
+        let names: Vec<String> = db
+            .tags_matching("v*")
+            .unwrap()
+            .into_iter()
+            .map(|t| t.name)
+            .collect();
+        assert_eq!(names, ["v2.0.0-rc.1", "v1.10.0", "v1.9.0", "v-next"]);
+        assert_eq!(db.latest_tag("v1.*").unwrap().unwrap().name, "v1.10.0");
+        assert!(db.latest_tag("v-*").unwrap().is_none());
+        assert!(db.tags_matching("[").is_err());
+    }
+
+    #[test]
+    fn forced_tags_move_and_log_their_targets() {
+        let (_tmp, db) = test_db();
+        let first = db.put("k", b"1".to_vec(), None).unwrap();
+        db.create_tag("v1", None, Some("release")).unwrap();
+        let second = db.put("k", b"2".to_vec(), None).unwrap();
+        assert!(db.create_tag("v1", None, None).is_err());
+
+        let moved = db.move_tag("v1", None, None).unwrap();
+        assert_eq!(moved.commit_id, second.id);
+        assert_eq!(moved.kind(), crate::tag::TagKind::Lightweight);
+        assert_eq!(db.tags().unwrap().len(), 1);
+        assert_eq!(db.get_tag("v1").unwrap().commit_id, second.id);
+        db.delete_tag("v1").unwrap();
+
+        let moves: Vec<_> = db
+            .tag_log("v1")
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.old, e.new))
+            .collect();
+        assert_eq!(
+            moves,
+            [
+                (Some(second.id.clone()), None),
+                (Some(first.id.clone()), Some(second.id)),
+                (None, Some(first.id)),
+            ]
+        );
+        assert!(db.tag_log("v2").unwrap().is_empty());
+    }
+
+    #[test]
+    fn signed_tags_verify_until_moved() {
+        let (_tmp, db) = test_db();
+        let first = db.put("k", b"1".to_vec(), None).unwrap();
+        db.put("k", b"2".to_vec(), None).unwrap();
+        db.create_tag("v1", Some(&first.id), Some("release"))
+            .unwrap();
+        let key = SigningKey::from_bytes(&[1; 32]);
+        assert!(db.verify_tag("v1", None).is_err());
+
+        let signed = db.sign_tag("v1", &key).unwrap();
+        assert_eq!(signed.commit_id, first.id);
+        let (tag, signer) = db.verify_tag("v1", Some(&key.verifying_key())).unwrap();
+        assert_eq!(signer, key.verifying_key());
+        assert_eq!(tag.message.as_deref(), Some("release"));
+        let stranger = SigningKey::from_bytes(&[2; 32]).verifying_key();
+        assert!(matches!(
+            db.verify_tag("v1", Some(&stranger)),
+            Err(IcebergError::BadSignature(_))
+        ));
+
+        // Moving a tag replaces it with an unsigned one.
+        db.move_tag("v1", None, None).unwrap();
+        assert!(db.verify_tag("v1", None).is_err());
+        assert!(db.sign_tag("missing", &key).is_err());
+    }
+
+    #[test]
+    fn tag_current_head() {
+        let (_tmp, db) = test_db();
+        db.put("k", b"v".to_vec(), None).unwrap();
+        let tag = db.create_tag("latest", None, None).unwrap();
+        let head = db.head_commit().unwrap();
+        assert_eq!(tag.commit_id, head.id);
+    }
+
+    #[test]
+    fn interrupted_tag_write_is_ignored() {
+        let (tmp, db) = test_db();
+        db.put("k", b"v".to_vec(), None).unwrap();
+        db.create_tag("v1", None, None).unwrap();
+        fs::write(tmp.path().join(TAGS_DIR).join("partial.tmp"), b"{\"na").unwrap();
+        assert_eq!(db.tags().unwrap().len(), 1);
+        assert!(db.get_tag("v1").is_ok());
+    }
+
+    #[test]
+    fn duplicate_tag_fails() {
+        let (_tmp, db) = test_db();
+        db.put("k", b"v".to_vec(), None).unwrap();
+        db.create_tag("v1", None, None).unwrap();
+        assert!(db.create_tag("v1", None, None).is_err());
+    }
+
+    #[test]
+    fn delete_tag() {
+        let (_tmp, db) = test_db();
+        db.put("k", b"v".to_vec(), None).unwrap();
+        db.create_tag("v1", None, None).unwrap();
+        db.delete_tag("v1").unwrap();
+        assert!(db.tags().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cherry_pick_commit() {
+        let (_tmp, db) = test_db();
+        db.put("shared", b"data".to_vec(), None).unwrap();
+
+        // Create a feature branch with a new key
+        db.create_branch("feature").unwrap();
+        db.checkout("feature").unwrap();
+        let feat_commit = db.put("feat_key", b"feat_val".to_vec(), None).unwrap();
+
+        // Switch back to main and cherry-pick
+        db.checkout("main").unwrap();
+        assert!(db.get("feat_key").is_err());
+
+        db.cherry_pick(&feat_commit.id, Some("picked feature"))
+            .unwrap();
+        assert_eq!(db.get("feat_key").unwrap(), b"feat_val");
+        assert_eq!(db.get("shared").unwrap(), b"data");
+    }
+
+    #[test]
+    fn cherry_pick_delete() {
+        let (_tmp, db) = test_db();
+        db.put("a", b"1".to_vec(), None).unwrap();
+        db.put("b", b"2".to_vec(), None).unwrap();
+
+        db.create_branch("cleanup").unwrap();
+        db.checkout("cleanup").unwrap();
+        let del_commit = db.delete("a", None).unwrap();
+
+        db.checkout("main").unwrap();
+        assert_eq!(db.get("a").unwrap(), b"1"); // still there
+
+        db.cherry_pick(&del_commit.id, None).unwrap();
+        assert!(db.get("a").is_err()); // now gone
+    }
+
+    #[test]
+    fn compact_with_max_versions() {
+        let (_tmp, db) = test_db();
+        for i in 0..5 {
+            db.put("k", format!("v{}", i).into_bytes(), None).unwrap();
+        }
+        assert_eq!(db.log().unwrap().len(), 5);
+
+        let policy = crate::compaction::CompactionPolicy {
+            max_versions: 2,
+            ..Default::default()
+        };
+        let result = db.compact(&policy).unwrap();
+        assert!(result.commits_removed > 0);
+
+        // Current value should still work
+        assert_eq!(db.get("k").unwrap(), b"v4");
+    }
+
+    #[test]
+    fn compact_collects_unreferenced_blocks() {
+        let (_tmp, db) = test_db();
+        db.put("shared", vec![9u8; 10_000], None).unwrap();
+        for i in 0..4u8 {
+            db.put("k", vec![i; 10_000], None).unwrap();
+        }
+        assert_eq!(db.store.block_count().unwrap(), 5);
+
+        let policy = crate::compaction::CompactionPolicy {
+            max_versions: 2,
+            ..Default::default()
+        };
+        let preview = db.compact_preview(&policy).unwrap();
+        assert_eq!(db.store.block_count().unwrap(), 5);
+        let result = db.compact(&policy).unwrap();
+        assert_eq!(preview, result);
+        assert_eq!(result.commits_removed, 3);
+        assert_eq!(result.blocks_removed, 2);
+        assert_eq!(db.store.block_count().unwrap(), 3);
+        assert_eq!(db.get("shared").unwrap(), vec![9u8; 10_000]);
+        assert_eq!(db.get("k").unwrap(), vec![3u8; 10_000]);
+        assert!(db.fsck().unwrap().is_healthy());
+    }
+
+    #[test]
+    fn compact_keeps_tagged_commits() {
+        let (_tmp, db) = test_db();
+        db.put("k", vec![1u8; 10_000], None).unwrap();
+        let tagged = db.put("k", vec![2u8; 10_000], None).unwrap();
+        db.create_tag("v1", None, None).unwrap();
+        for i in 3..6u8 {
+            db.put("k", vec![i; 10_000], None).unwrap();
+        }
+
+        let policy = CompactionPolicy {
+            max_versions: 1,
+            ..Default::default()
+        };
+        let result = db.compact(&policy).unwrap();
+        assert_eq!(result.commits_removed, 3);
+        assert_eq!(result.tags_removed, 0);
+        let at = db.resolve_ref("v1").unwrap();
+        assert_eq!(db.get_at("k", &at).unwrap(), vec![2u8; 10_000]);
+        assert_eq!(db.get_commit(&tagged.id).unwrap().parent, None);
+        assert_eq!(db.log().unwrap().len(), 1);
+        assert_eq!(db.store.block_count().unwrap(), 2);
+        assert!(db.fsck().unwrap().is_healthy());
+    }
+
+    #[test]
+    fn compact_can_drop_tagged_commits() {
+        let (_tmp, db) = test_db();
+        db.put("k", b"1".to_vec(), None).unwrap();
+        db.create_tag("v1", None, None).unwrap();
+        db.put("k", b"2".to_vec(), None).unwrap();
+
+        let policy = CompactionPolicy {
+            max_versions: 1,
+            keep_tagged: false,
+            ..Default::default()
+        };
+        let result = db.compact(&policy).unwrap();
+        assert_eq!(result.commits_removed, 1);
+        assert_eq!(result.tags_removed, 1);
+        assert!(db.tags().unwrap().is_empty());
+        assert!(db.fsck().unwrap().is_healthy());
+    }
+
+    #[test]
+    fn squash_history_replaces_prefix_with_snapshot() {
+        let (_tmp, db) = test_db();
+        db.put("big", vec![1u8; 10_000], None).unwrap();
+        db.put("big", vec![2u8; 10_000], None).unwrap();
+        let base = db.put("a", b"1".to_vec(), None).unwrap();
+        let next = db.put("b", b"2".to_vec(), None).unwrap();
+        db.create_tag("v1", None, None).unwrap();
+        db.put("c", b"3".to_vec(), None).unwrap();
+
+        let result = db.squash_history(&base.id, None).unwrap();
+        assert_eq!(result.squashed, 3);
+        assert_eq!(result.rewritten.len(), 3);
+        assert_eq!(result.rewritten[&base.id], result.snapshot.id);
+        assert_eq!(result.collected.commits_removed, 5);
+        assert_eq!(result.collected.blocks_removed, 1);
+
+        let log = db.log().unwrap();
+        assert_eq!(log.len(), 3);
+        assert_eq!(log[2], result.snapshot);
+        assert_eq!(result.snapshot.parent, None);
+        assert_eq!(result.snapshot.tree_root, base.tree_root);
+        assert!(result.snapshot.message.starts_with("snapshot of 3 commits"));
+        assert_eq!(log[1].message, next.message);
+        assert_eq!(db.resolve_ref("v1").unwrap(), result.rewritten[&next.id]);
+        assert_eq!(db.get("big").unwrap(), vec![2u8; 10_000]);
+        assert_eq!(db.get("c").unwrap(), b"3");
+        assert!(db.get_commit(&base.id).is_err());
+        let report = db.fsck().unwrap();
+        assert!(report.is_healthy(), "{}", report);
+        assert!(report.unreachable_commits.is_empty());
+    }
+
+    #[test]
+    fn squash_history_keeps_history_other_branches_need() {
+        let (_tmp, db) = test_db();
+        let first = db.put("a", b"1".to_vec(), None).unwrap();
+        db.create_branch("old").unwrap();
+        let second = db.put("b", b"2".to_vec(), None).unwrap();
+        db.put("c", b"3".to_vec(), None).unwrap();
+
+        db.squash_history(&second.id, Some("squashed")).unwrap();
+        assert_eq!(db.log().unwrap()[1].message, "squashed");
+        assert!(db.get_commit(&second.id).is_err());
+        db.checkout("old").unwrap();
+        assert_eq!(db.log().unwrap(), vec![first]);
+        assert!(db.fsck().unwrap().is_healthy());
+
+        assert!(matches!(
+            db.squash_history(&second.id, None),
+            Err(IcebergError::CommitNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn truncate_history_makes_a_commit_the_root() {
+        let (_tmp, db) = test_db();
+        let first = db.put("a", vec![1; 8192], None).unwrap();
+        db.create_tag("v1", None, None).unwrap();
+        db.create_branch("old").unwrap();
+        let second = db.put("a", b"2".to_vec(), None).unwrap();
+        db.create_branch("dev").unwrap();
+        let third = db.put("b", b"3".to_vec(), None).unwrap();
+        let fourth = db.put("c", b"4".to_vec(), None).unwrap();
+        db.checkout("dev").unwrap();
+        let forked = db.put("d", b"5".to_vec(), None).unwrap();
+        db.checkout("main").unwrap();
+
+        let result = db.truncate_history(&third.id).unwrap();
+        assert_eq!(result.root.id, third.id);
+        assert_eq!(result.root.parent, None);
+        assert_eq!(result.moved_branches, ["old"]);
+        assert_eq!(result.collected.commits_removed, 2);
+        assert_eq!(result.collected.tags_removed, 1);
+        assert_eq!(result.collected.blocks_removed, 1);
+
+        let log: Vec<String> = db.log().unwrap().into_iter().map(|c| c.id).collect();
+        assert_eq!(log, [fourth.id, third.id.clone()]);
+        assert_eq!(db.get("a").unwrap(), b"2");
+        assert_eq!(db.resolve_ref("old").unwrap(), third.id);
+        assert!(db.get_tag("v1").is_err());
+        for gone in [&first.id, &second.id] {
+            assert!(matches!(
+                db.get_commit(gone),
+                Err(IcebergError::CommitNotFound(_))
+            ));
+        }
+        // A branch that forked below the kept commit keeps its own commits.
+        db.checkout("dev").unwrap();
+        let log = db.log().unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].id, forked.id);
+        assert_eq!(db.get("a").unwrap(), b"2");
+        assert!(db.fsck().unwrap().is_healthy());
+
+        // Truncating at a root changes nothing.
+        let again = db.truncate_history(&forked.id).unwrap();
+        assert_eq!(again.collected, CompactionResult::default());
+    }
+
+    #[test]
+    fn compact_can_squash_instead_of_dropping() {
+        let (_tmp, db) = test_db();
+        for i in 0..5u8 {
+            db.put(&format!("k{}", i), vec![i], None).unwrap();
+        }
+        let policy = CompactionPolicy {
+            max_versions: 2,
+            squash: true,
+            ..Default::default()
+        };
+        let result = db.compact(&policy).unwrap();
+        assert_eq!(result.commits_removed, 5);
+        let log = db.log().unwrap();
+        assert_eq!(log.len(), 3);
+        assert!(log[2].message.starts_with("snapshot of 3 commits"));
+        for i in 0..5u8 {
+            assert_eq!(db.get(&format!("k{}", i)).unwrap(), vec![i]);
+        }
+        assert!(db.fsck().unwrap().is_healthy());
+    }
+
+    #[test]
+    fn purge_key_rewrites_every_branch() {
+        let (tmp, db) = test_db();
+        let first = db.put("keep", b"1".to_vec(), None).unwrap();
+        db.put("secret", vec![7u8; 10_000], None).unwrap();
+        let old_head = db.put("keep", b"2".to_vec(), None).unwrap();
+        db.create_tag("v1", None, None).unwrap();
+        db.create_branch("dev").unwrap();
+        db.checkout("dev").unwrap();
+        db.put("secret", b"small".to_vec(), None).unwrap();
+        db.put("other", b"3".to_vec(), None).unwrap();
+        db.checkout("main").unwrap();
+
+        let result = db.purge_key("secret").unwrap();
+        assert_eq!(result.dropped, 2);
+        assert!(!result.rewritten.contains_key(&first.id));
+        assert!(result.rewritten.contains_key(&old_head.id));
+        assert_eq!(result.collected.blocks_removed, 1);
+
+        let main = db.log().unwrap();
+        assert_eq!(main.len(), 2);
+        assert_eq!(main[1].id, first.id);
+        assert_eq!(db.resolve_ref("v1").unwrap(), main[0].id);
+        assert_eq!(db.get("keep").unwrap(), b"2");
+        db.checkout("dev").unwrap();
+        let dev = db.log().unwrap();
+        assert_eq!(dev.len(), 3);
+        assert_eq!(dev[1].id, main[0].id);
+        for commit in &dev {
+            assert!(db.get_at("secret", &commit.id).is_err());
+        }
+        assert_eq!(db.get("other").unwrap(), b"3");
+
+        let report = db.fsck().unwrap();
+        assert!(report.is_healthy(), "{}", report);
+        assert!(report.unreachable_commits.is_empty());
+        assert_eq!(db.store.block_count().unwrap(), 0);
+        db.flush().unwrap();
+        for dir in [TREES_DIR, COMMITS_DIR] {
+            for entry in fs::read_dir(tmp.path().join(dir)).unwrap() {
+                let data = fs::read(entry.unwrap().path()).unwrap();
+                assert!(!String::from_utf8_lossy(&data).contains("secret"));
+            }
+        }
+    }
+
+    #[test]
+    fn namespaces_isolate_keys_and_share_history() {
+        let (_tmp, db) = test_db();
+        let users = db.namespace("users").unwrap();
+        db.put("alice", b"default".to_vec(), None).unwrap();
+        let commit = users.put("alice", b"user".to_vec(), None).unwrap();
+        assert_eq!(commit.message, "put alice in users");
+        users.put("bob", b"user".to_vec(), None).unwrap();
+
+        assert_eq!(db.get("alice").unwrap(), b"default");
+        assert_eq!(users.get("alice").unwrap(), b"user");
+        assert!(matches!(users.get("carol"), Err(IcebergError::KeyNotFound(k)) if k == "carol"));
+        assert_eq!(db.scan_prefix("").unwrap().len(), 1);
+        let scanned = users.scan_prefix("").unwrap();
+        assert_eq!(scanned[0].0, "alice");
+        assert_eq!(scanned.len(), 2);
+        assert_eq!(users.len().unwrap(), 2);
+        assert!(db.namespace("orders").unwrap().is_empty().unwrap());
+        assert_eq!(db.namespaces().unwrap().get("users"), Some(&2));
+        assert_eq!(db.stats().unwrap().key_count, 1);
+
+        let key = namespace::qualify("users", "alice");
+        assert!(matches!(
+            db.put(&key, b"x".to_vec(), None),
+            Err(IcebergError::InvalidKey(_))
+        ));
+        assert!(db.namespace("").is_err());
+
+        // One history: namespaced writes are ordinary commits on the branch.
+        assert_eq!(db.log().unwrap().len(), 3);
+        users.delete("bob", None).unwrap();
+        assert!(users.get("bob").is_err());
+        assert!(users.get_at("bob", &commit.id).is_err());
+        assert_eq!(users.get_at("alice", &commit.id).unwrap(), b"user");
+        db.create_branch("dev").unwrap();
+        db.checkout("dev").unwrap();
+        assert_eq!(users.get("alice").unwrap(), b"user");
+    }
+
+    #[test]
+    fn namespaces_have_their_own_indexes_and_bloom_filters() {
+        let (tmp, db) = test_db();
+        let users = db.namespace("users").unwrap();
+        db.create_index("city", "city").unwrap();
+        users.create_index("city", "city").unwrap();
+        db.put("a", br#"{"city":"Zurich"}"#.to_vec(), None).unwrap();
+        users
+            .put("b", br#"{"city":"Zurich"}"#.to_vec(), None)
+            .unwrap();
+
+        assert_eq!(db.query_index("city", "Zurich").unwrap(), vec!["a"]);
+        assert_eq!(users.query_index("city", "Zurich").unwrap(), vec!["b"]);
+        assert_eq!(db.list_indexes(), vec!["city"]);
+        assert_eq!(users.list_indexes(), vec!["city"]);
+        users.drop_index("city").unwrap();
+        assert!(users.list_indexes().is_empty());
+        assert_eq!(db.list_indexes(), vec!["city"]);
+
+        assert_eq!(db.bloom_stats().0, 1);
+        assert_eq!(users.bloom_stats().0, 1);
+        assert_eq!(db.namespace("orders").unwrap().bloom_stats().0, 0);
+
+        db.flush().unwrap();
+        drop(db);
+        let db = Database::open(tmp.path()).unwrap();
+        let users = db.namespace("users").unwrap();
+        assert_eq!(users.bloom_stats().0, 1);
+        assert_eq!(users.get("b").unwrap(), br#"{"city":"Zurich"}"#);
+        let report = db.fsck().unwrap();
+        assert!(report.is_healthy(), "{}", report);
+        db.rebuild_bloom().unwrap();
+        assert_eq!(users.bloom_stats().0, 1);
+    }
+
+    #[test]
+    fn tenants_have_their_own_branches_and_heads() {
+        let (_tmp, db) = test_db();
+        db.put("k", b"default".to_vec(), None).unwrap();
+        let a = db.tenant("tenant-a").unwrap();
+        assert_eq!(a.current_branch().unwrap(), "main");
+        assert!(a.get("k").is_err());
+        a.put("k", b"a".to_vec(), None).unwrap();
+        assert_eq!(a.get("k").unwrap(), b"a");
+        assert_eq!(db.get("k").unwrap(), b"default");
+
+        a.create_branch("dev").unwrap();
+        a.checkout("dev").unwrap();
+        a.put("x", b"1".to_vec(), None).unwrap();
+        assert_eq!(a.branches().unwrap(), vec!["dev", "main"]);
+        assert_eq!(a.log().unwrap().len(), 2);
+        assert_eq!(db.current_branch().unwrap(), "main");
+        assert_eq!(db.log().unwrap().len(), 1);
+        assert!(db.branches().unwrap().contains(&"tenant-a/dev".to_string()));
+
+        let b = db.tenant("tenant-b").unwrap();
+        assert_eq!(b.branches().unwrap(), vec!["main"]);
+        assert!(b.checkout("dev").is_err());
+        assert!(b
+            .scan_prefix("")
+            .unwrap_err()
+            .to_string()
+            .contains("no commits"));
+        assert_eq!(db.tenants().unwrap(), vec!["tenant-a"]);
+
+        a.checkout("main").unwrap();
+        assert!(a.get("x").is_err());
+        assert!(a.delete_branch("main").is_err());
+        a.merge("dev", None).unwrap();
+        assert_eq!(a.get("x").unwrap(), b"1");
+        assert_eq!(a.resolve_ref("HEAD").unwrap(), a.head_commit().unwrap().id);
+        assert!(a.resolve_ref("dev").is_ok());
+        assert!(db.tenant("a/b").is_err());
+    }
+
+    #[test]
+    fn on_branch_reads_and_writes_without_moving_head() {
+        let (_tmp, db) = test_db();
+        db.put("k", b"main".to_vec(), None).unwrap();
+        db.create_branch("dev").unwrap();
+        let dev = db.on_branch("dev").unwrap();
+        dev.put("k", b"dev".to_vec(), None).unwrap();
+        dev.put("x", b"1".to_vec(), None).unwrap();
+        dev.delete("x", None).unwrap();
+
+        assert_eq!(db.current_branch().unwrap(), "main");
+        assert_eq!(db.get("k").unwrap(), b"main");
+        assert_eq!(dev.get("k").unwrap(), b"dev");
+        assert_eq!(dev.count("").unwrap(), 1);
+        assert_eq!(dev.log().unwrap().len(), 4);
+        assert_eq!(db.log().unwrap().len(), 1);
+        let mut out = Vec::new();
+        dev.get_writer("k", &mut out).unwrap();
+        assert_eq!(out, b"dev");
+        assert!(matches!(
+            db.on_branch("nope"),
+            Err(IcebergError::BranchNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn writes_on_other_branches_leave_head_indexes_alone() {
+        let (_tmp, db) = test_db();
+        db.create_index("city", "city").unwrap();
+        db.put("u:1", br#"{"city":"Bern"}"#.to_vec(), None).unwrap();
+        db.create_branch("dev").unwrap();
+        let dev = db.on_branch("dev").unwrap();
+        dev.put("u:2", br#"{"city":"Bern"}"#.to_vec(), None)
+            .unwrap();
+        dev.put_reader("u:3", &b"streamed"[..], None).unwrap();
+        dev.delete("u:1", None).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put("u:4", br#"{"city":"Bern"}"#.to_vec());
+        dev.write(&batch, None).unwrap();
+        dev.delete_range("u:2", "u:3", None).unwrap();
+
+        assert_eq!(db.query_index("city", "Bern").unwrap(), ["u:1"]);
+        assert_eq!(db.get("u:1").unwrap(), br#"{"city":"Bern"}"#);
+        assert!(matches!(db.get("u:4"), Err(IcebergError::KeyNotFound(_))));
+        assert_eq!(dev.get("u:4").unwrap(), br#"{"city":"Bern"}"#);
+    }
+
+    #[test]
+    fn branch_handles_serve_branches_concurrently() {
+        let (_tmp, db) = test_db();
+        db.put("base", b"0".to_vec(), None).unwrap();
+        let names = ["a", "b", "c"];
+        for name in names {
+            db.create_branch(name).unwrap();
+        }
+        std::thread::scope(|scope| {
+            for name in names {
+                let branch = db.on_branch(name).unwrap();
+                scope.spawn(move || {
+                    for i in 0..5 {
+                        branch
+                            .put(&format!("{}:{}", name, i), name.as_bytes().to_vec(), None)
+                            .unwrap();
+                    }
+                    let mut batch = WriteBatch::new();
+                    batch
+                        .delete(&format!("{}:0", name))
+                        .put("last", name.into());
+                    let head = branch.head_commit().unwrap().id;
+                    branch.write_if_head(&batch, &head, None).unwrap();
+                    assert!(branch.put_if_head("x", vec![], &head, None).is_err());
+                });
+            }
+        });
+
+        assert_eq!(db.current_branch().unwrap(), "main");
+        assert_eq!(db.scan_keys("").unwrap(), ["base"]);
+        for name in names {
+            let branch = db.on_branch(name).unwrap();
+            assert_eq!(branch.get("last").unwrap(), name.as_bytes());
+            assert_eq!(branch.scan_keys(&format!("{}:", name)).unwrap().len(), 4);
+            assert_eq!(branch.range("a", "z").unwrap().len(), 6);
+            let snap = branch.snapshot().unwrap();
+            branch.delete_range("", "z", None).unwrap().unwrap();
+            assert_eq!(branch.count("").unwrap(), 0);
+            assert_eq!(snap.get("base").unwrap(), b"0");
+        }
+    }
+
+    #[test]
+    fn tenants_share_blocks() {
+        let (_tmp, db) = test_db();
+        let big = vec![9u8; 100_000];
+        db.tenant("a").unwrap().put("k", big.clone(), None).unwrap();
+        db.tenant("b").unwrap().put("k", big, None).unwrap();
+        assert_eq!(db.store.block_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn replay_applies_tenant_writes_to_their_branch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::init(tmp.path()).unwrap();
+        db.put("a", b"1".to_vec(), None).unwrap();
+        db.tenant("t")
+            .unwrap()
+            .put("a", b"t1".to_vec(), None)
+            .unwrap();
+        {
+            let mut wal = db.wal.lock().unwrap();
+            let tx = wal.begin().unwrap();
+            wal.log_branch(tx, "t/main".into()).unwrap();
+            wal.log_write(tx, "b".into(), b"t2".to_vec()).unwrap();
+            wal.commit(tx, "lost".into()).unwrap();
+        }
+        std::mem::forget(db);
+
+        let db = Database::open(tmp.path()).unwrap();
+        let t = db.tenant("t").unwrap();
+        assert_eq!(t.get("b").unwrap(), b"t2");
+        assert_eq!(t.log().unwrap().len(), 2);
+        assert!(db.get("b").is_err());
+        assert_eq!(db.log().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn compact_no_policy_removes_nothing() {
+        let (_tmp, db) = test_db();
+        db.put("a", b"1".to_vec(), None).unwrap();
+        db.put("b", b"2".to_vec(), None).unwrap();
+
+        let policy = crate::compaction::CompactionPolicy::default();
+        let result = db.compact(&policy).unwrap();
+        assert_eq!(result.commits_removed, 0);
+        assert_eq!(db.log().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn delete_branch() {
+        let (_tmp, db) = test_db();
+        db.put("x", b"1".to_vec(), None).unwrap();
+        db.create_branch("temp").unwrap();
+        db.delete_branch("temp").unwrap();
+        assert!(!db.branches().unwrap().contains(&"temp".to_string()));
+    }
+
+    #[test]
+    fn bloom_filter_fast_negative() {
+        let (_tmp, db) = test_db();
+        db.put("exists", b"val".to_vec(), None).unwrap();
+        // Bloom should say "maybe" for existing key
+        assert!(db.get("exists").is_ok());
+        // Non-existing key: bloom may short-circuit, but result is the same
+        assert!(db.get("nope").is_err());
+    }
+
+    #[test]
+    fn approx_distinct_tracks_fields_and_prefixes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::init(tmp.path()).unwrap();
+        for (i, city) in ["Bern", "Basel", "Bern"].iter().enumerate() {
+            let value = format!(r#"{{"city": "{}"}}"#, city);
+            db.put(&format!("user:{}", i), value.into_bytes(), None)
+                .unwrap();
+        }
+        assert!(db.approx_distinct("city").is_err());
+        db.track_distinct("city").unwrap();
+        db.track_distinct("user:*").unwrap();
+        assert!(db.track_distinct("").is_err());
+        assert_eq!(db.approx_distinct("city").unwrap(), 2);
+
+        db.put("user:9", br#"{"city": "Chur"}"#.to_vec(), None)
+            .unwrap();
+        db.put_reader("user:10", &b"blob"[..], None).unwrap();
+        assert_eq!(db.approx_distinct("city").unwrap(), 3);
+        assert_eq!(db.approx_distinct("user:*").unwrap(), 5);
+        db.close().unwrap();
+
+        let db = Database::open(tmp.path()).unwrap();
+        assert_eq!(
+            db.tracked_distinct(),
+            vec![("city".to_string(), 3), ("user:*".to_string(), 5)]
+        );
+        db.untrack_distinct("city").unwrap();
+        assert!(db.untrack_distinct("city").is_err());
+        assert!(db.approx_distinct("city").is_err());
+    }
+
+    #[test]
+    fn rebuild_bloom() {
+        let (_tmp, db) = test_db();
+        db.put("a", b"1".to_vec(), None).unwrap();
+        db.put("b", b"2".to_vec(), None).unwrap();
+        db.rebuild_bloom().unwrap();
+        let (items, _, _) = db.bloom_stats();
+        assert_eq!(items, 2);
+    }
+
+    #[test]
+    fn bloom_migrates_from_legacy_json_files() {
+        let (tmp, db) = test_db();
+        db.put("a", b"1".to_vec(), None).unwrap();
+        db.namespace("users")
+            .unwrap()
+            .put("b", b"2".to_vec(), None)
+            .unwrap();
+        db.close().unwrap();
+        fs::remove_file(tmp.path().join(BLOOM_FILE)).unwrap();
+        let default = BloomFilter::with_keys(["a".to_string()].iter());
+        let users = BloomFilter::with_keys([namespace::qualify("users", "b")].iter());
+        fs::write(
+            tmp.path().join(LEGACY_BLOOM_FILE),
+            serde_json::to_vec(&default).unwrap(),
+        )
+        .unwrap();
+        fs::write(
+            tmp.path().join(LEGACY_NAMESPACE_BLOOM_FILE),
+            serde_json::to_vec(&BTreeMap::from([("users", users)])).unwrap(),
+        )
+        .unwrap();
+
+        let db = Database::open(tmp.path()).unwrap();
+        assert_eq!(db.bloom_stats().0, 1);
+        assert_eq!(db.namespace("users").unwrap().bloom_stats().0, 1);
+        assert!(db.fsck().unwrap().is_healthy());
+        db.put("c", b"3".to_vec(), None).unwrap();
+        db.close().unwrap();
+        assert!(!tmp.path().join(LEGACY_BLOOM_FILE).exists());
+        assert!(!tmp.path().join(LEGACY_NAMESPACE_BLOOM_FILE).exists());
+        let db = Database::open(tmp.path()).unwrap();
+        assert_eq!(db.bloom_stats().0, 2);
+        assert_eq!(db.namespace("users").unwrap().get("b").unwrap(), b"2");
+    }
+
+    #[test]
+    fn cuckoo_filter_forgets_deleted_keys_and_grows_when_full() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = DbConfig {
+            key_filter: FilterKind::Cuckoo,
+            ..Default::default()
+        };
+        let db = Database::init_with_config(tmp.path(), config).unwrap();
+        db.put("a", b"1".to_vec(), None).unwrap();
+        db.put("a", b"2".to_vec(), None).unwrap();
+        db.put("b", b"3".to_vec(), None).unwrap();
+        assert_eq!(db.bloom_stats().0, 2);
+        db.delete("a", None).unwrap();
+        assert_eq!(db.bloom_stats().0, 1);
+        assert_eq!(db.stats().unwrap().key_filter, FilterKind::Cuckoo);
+
+        // A key deleted on one branch may live on in another.
+        db.create_branch("feature").unwrap();
+        db.delete("b", None).unwrap();
+        assert_eq!(db.bloom_stats().0, 1);
+        db.checkout("feature").unwrap();
+        assert_eq!(db.get("b").unwrap(), b"3");
+
+        *db.bloom().lock().unwrap() =
+            BloomSet::new(KeyFilter::Cuckoo(CuckooFilter::new(8)), BTreeMap::new());
+        for i in 0..50 {
+            db.put(&format!("k{}", i), vec![], None).unwrap();
+        }
+        let bloom = db.bloom().lock().unwrap().clone();
+        assert!(!bloom.is_full());
+        assert_eq!(bloom.default_filter().count(), 51);
+        assert!((0..50).all(|i| bloom.may_contain(&format!("k{}", i))));
+
+        db.close().unwrap();
+        let db = Database::open(tmp.path()).unwrap();
+        assert_eq!(db.bloom_stats().0, 51);
+        db.set_key_filter(FilterKind::Bloom).unwrap();
+        assert_eq!(db.bloom().lock().unwrap().kind(), FilterKind::Bloom);
+        assert_eq!(db.get("k7").unwrap(), b"");
+    }
+
+    #[test]
+    fn key_filters_and_indexes_load_on_first_use() {
+        let (tmp, db) = test_db();
+        db.create_index("city", "city").unwrap();
+        db.put("a", br#"{"city": "Bern"}"#.to_vec(), None).unwrap();
+        db.close().unwrap();
+
+        let db = Database::open(tmp.path()).unwrap();
+        db.log().unwrap();
+        db.branches().unwrap();
+        assert!(db.bloom.get().is_none() && db.indexes.get().is_none());
+        assert_eq!(db.query_index("city", "Bern").unwrap(), vec!["a"]);
+        assert!(db.bloom.get().is_none() && db.indexes.get().is_some());
+        assert_eq!(db.get("a").unwrap(), br#"{"city": "Bern"}"#);
+        assert!(db.bloom.get().is_some() && db.distinct.get().is_none());
+
+        // Filters of a kind no longer configured are rebuilt on the next
+        // write, not at open.
+        db.update_config(|c| c.key_filter = FilterKind::Cuckoo)
+            .unwrap();
+        db.close().unwrap();
+        let db = Database::open(tmp.path()).unwrap();
+        assert!(db.bloom.get().is_none());
+        assert_eq!(db.bloom().lock().unwrap().kind(), FilterKind::Bloom);
+        assert_eq!(db.get("a").unwrap(), br#"{"city": "Bern"}"#);
+        db.put("b", b"2".to_vec(), None).unwrap();
+        assert_eq!(db.bloom().lock().unwrap().kind(), FilterKind::Cuckoo);
+        assert_eq!(db.bloom_stats().0, 2);
+    }
+
+    #[test]
+    fn bloom_covers_branch_heads_after_switches() {
+        let (tmp, db) = test_db();
+        let c1 = db.put("old", b"1".to_vec(), None).unwrap();
+        db.delete("old", None).unwrap();
+        db.put("main", b"2".to_vec(), None).unwrap();
+        // The rebuilt filter knows only the keys on branch heads.
+        db.rebuild_bloom().unwrap();
+
+        db.create_branch_at("past", &c1.id).unwrap();
+        db.checkout("past").unwrap();
+        assert_eq!(db.get("old").unwrap(), b"1");
+        db.checkout("main").unwrap();
+        assert!(db.get("old").is_err());
+        db.cherry_pick(&c1.id, None).unwrap();
+        assert_eq!(db.get("old").unwrap(), b"1");
+
+        // Filters that went stale behind the database's back are topped up
+        // when a branch is checked out.
+        db.create_branch("feature").unwrap();
+        db.checkout("feature").unwrap();
+        db.put("feat", b"3".to_vec(), None).unwrap();
+        db.checkout("main").unwrap();
+        db.close().unwrap();
+        fs::write(
+            tmp.path().join(BLOOM_FILE),
+            BloomSet::from_keys(FilterKind::Bloom, &["main".to_string()]).to_bytes(),
+        )
+        .unwrap();
+        let db = Database::open(tmp.path()).unwrap();
+        db.checkout("feature").unwrap();
+        assert_eq!(db.get("feat").unwrap(), b"3");
+        assert_eq!(db.get("old").unwrap(), b"1");
+        drop(db);
+        let db = Database::open(tmp.path()).unwrap();
+        assert_eq!(db.get("feat").unwrap(), b"3");
+    }
+
+    #[test]
+    fn rebase_branch() {
+        let (_tmp, db) = test_db();
+        // Setup: main has "base"
+        db.put("base", b"val".to_vec(), Some("base commit"))
+            .unwrap();
+
+        // Create feature branch with a new key
+        db.create_branch("feature").unwrap();
+        db.checkout("feature").unwrap();
+        db.put("feat", b"f1".to_vec(), Some("feat commit")).unwrap();
+
+        // Add more to main
+        db.checkout("main").unwrap();
+        db.put("main_extra", b"m1".to_vec(), Some("main extra"))
+            .unwrap();
+
+        // Rebase feature onto main
+        db.checkout("feature").unwrap();
+        let preview = db.rebase_preview("main").unwrap();
+        assert_eq!(preview.len(), 1);
+        assert_eq!(preview[0].message, "feat commit");
+        assert!(db.get("main_extra").is_err());
+        let rebased = db.rebase("main").unwrap();
+        assert_eq!(rebased.len(), 1);
+
+        // Feature branch should now have all keys
+        assert_eq!(db.get("base").unwrap(), b"val");
+        assert_eq!(db.get("feat").unwrap(), b"f1");
+        assert_eq!(db.get("main_extra").unwrap(), b"m1");
+    }
+
+    #[test]
+    fn rebase_and_compaction_report_progress() {
+        let (_tmp, db) = test_db();
+        db.put("base", b"v".to_vec(), None).unwrap();
+        db.create_branch("feature").unwrap();
+        db.checkout("feature").unwrap();
+        for i in 0..3 {
+            db.put(&format!("f{}", i), b"v".to_vec(), None).unwrap();
+        }
+        db.checkout("main").unwrap();
+        db.put("main", b"v".to_vec(), None).unwrap();
+        db.checkout("feature").unwrap();
+
+        let mut reports = Vec::new();
+        db.rebase_with_progress("main", |done, total| reports.push((done, total)))
+            .unwrap();
+        assert_eq!(reports, [(1, 3), (2, 3), (3, 3)]);
+
+        let mut reports = Vec::new();
+        let policy = crate::compaction::CompactionPolicy {
+            max_versions: 1,
+            ..Default::default()
+        };
+        let result = db
+            .compact_with_progress(&policy, |done, total| reports.push((done, total)))
+            .unwrap();
+        assert!(result.commits_removed > 0);
+        let &(done, total) = reports.last().unwrap();
+        assert!(total > result.commits_removed && done == total);
+
+        let mut reports = Vec::new();
+        db.compact_with_progress(&policy, |done, total| reports.push((done, total)))
+            .unwrap();
+        assert_eq!(reports, [(0, 0)]);
+    }
+
+    #[test]
+    fn rebase_onto_self_fails() {
+        let (_tmp, db) = test_db();
+        db.put("k", b"v".to_vec(), None).unwrap();
+        assert!(db.rebase("main").is_err());
+    }
+
+    #[test]
+    fn secondary_index_lifecycle() {
+        let (_tmp, db) = test_db();
+        // Create index
+        db.create_index("city", "city").unwrap();
+        assert_eq!(db.list_indexes(), vec!["city"]);
+
+        // Put JSON values
+        let zurich =
+            serde_json::to_vec(&serde_json::json!({"city": "Zurich", "pop": 400000})).unwrap();
+        let berlin =
+            serde_json::to_vec(&serde_json::json!({"city": "Berlin", "pop": 3600000})).unwrap();
+        db.put("ch:zurich", zurich, None).unwrap();
+        db.put("de:berlin", berlin, None).unwrap();
+
+        // Query index
+        assert_eq!(db.query_index("city", "Zurich").unwrap(), vec!["ch:zurich"]);
+        assert_eq!(db.query_index("city", "Berlin").unwrap(), vec!["de:berlin"]);
+        assert!(db.query_index("city", "Paris").unwrap().is_empty());
+
+        // Delete and verify index updated
+        db.delete("ch:zurich", None).unwrap();
+        assert!(db.query_index("city", "Zurich").unwrap().is_empty());
+
+        // Drop index
+        db.drop_index("city").unwrap();
+        assert!(db.list_indexes().is_empty());
+    }
+
+    #[test]
+    fn query_index_entries_returns_values_at_head() {
+        let (_tmp, db) = test_db();
+        let city = |c: &str| serde_json::to_vec(&serde_json::json!({ "city": c })).unwrap();
+        db.create_index("city", "city").unwrap();
+        db.put("u:1", city("Zurich"), None).unwrap();
+        db.put("u:2", city("Berlin"), None).unwrap();
+        db.create_branch("other").unwrap();
+        db.checkout("other").unwrap();
+        db.put("u:3", city("Zurich"), None).unwrap();
+        db.checkout("main").unwrap();
+
+        // u:3 is indexed but only exists on the other branch.
+        assert_eq!(
+            db.query_index("city", "Zurich").unwrap(),
+            vec!["u:1", "u:3"]
+        );
+        assert_eq!(
+            db.query_index_entries("city", "Zurich").unwrap(),
+            vec![("u:1".to_string(), city("Zurich"))]
+        );
+        assert!(db.query_index_entries("city", "Paris").unwrap().is_empty());
+
+        let users = db.namespace("users").unwrap();
+        users.create_index("city", "city").unwrap();
+        users.put("u:9", city("Zurich"), None).unwrap();
+        assert_eq!(
+            users.query_index_entries("city", "Zurich").unwrap(),
+            vec![("u:9".to_string(), city("Zurich"))]
+        );
+    }
+
+    #[test]
+    fn key_part_index_covers_streamed_values_and_survives_reopen() {
+        let (tmp, db) = test_db();
+        let options = IndexOptions {
+            source: crate::index::IndexSource::Key {
+                delimiter: ":".into(),
+                position: 1,
+            },
+            ..Default::default()
+        };
+        db.put("order:alice:1", b"raw".to_vec(), None).unwrap();
+        db.create_index_with("customer", "", &options).unwrap();
+        db.put("order:bob:2", b"{}".to_vec(), None).unwrap();
+        db.put_reader("order:alice:3", &b"streamed"[..], None)
+            .unwrap();
+        assert_eq!(
+            db.query_index("customer", "alice").unwrap(),
+            vec!["order:alice:1", "order:alice:3"]
+        );
+        db.close().unwrap();
+
+        let db = Database::open(tmp.path()).unwrap();
+        assert_eq!(
+            db.query_index("customer", "bob").unwrap(),
+            vec!["order:bob:2"]
+        );
+        db.delete("order:bob:2", None).unwrap();
+        assert!(db.query_index("customer", "bob").unwrap().is_empty());
+    }
+
+    #[test]
+    fn vector_index_searches_head_and_past_commits() {
+        let (tmp, db) = test_db();
+        let doc = |v: [f32; 2]| serde_json::to_vec(&serde_json::json!({ "emb": v })).unwrap();
+        db.create_vector_index("emb", "emb", &VectorOptions::new(2))
+            .unwrap();
+        db.put("east", doc([1.0, 0.0]), None).unwrap();
+        let before = db.put("north", doc([0.0, 1.0]), None).unwrap();
+        db.put("north", doc([-1.0, 0.0]), Some("north turns west"))
+            .unwrap();
+        assert_eq!(db.list_indexes(), vec!["emb"]);
+
+        let nearest = |hits: Vec<(String, f32)>| hits.into_iter().next().unwrap().0;
+        assert_eq!(
+            nearest(db.query_vector("emb", &[0.1, 1.0], 1).unwrap()),
+            "east"
+        );
+        assert_eq!(
+            nearest(
+                db.query_vector_at("emb", &[0.1, 1.0], 1, &before.id)
+                    .unwrap()
+            ),
+            "north"
+        );
+        assert!(matches!(
+            db.query_vector("emb", &[1.0, 2.0, 3.0], 1),
+            Err(IcebergError::InvalidQuery(_))
+        ));
+        db.close().unwrap();
+
+        let db = Database::open(tmp.path()).unwrap();
+        let hits = db.query_vector("emb", &[-1.0, 0.0], 2).unwrap();
+        assert_eq!(hits[0].0, "north");
+        assert_eq!(hits.len(), 2);
+        db.drop_index("emb").unwrap();
+        assert!(db.query_vector("emb", &[-1.0, 0.0], 2).is_err());
+    }
+
+    #[test]
+    fn query_combines_indexes_and_checks_values_at_head() {
+        let (_tmp, db) = test_db();
+        let user = |city: &str, age: u32| {
+            serde_json::to_vec(&serde_json::json!({ "city": city, "age": age })).unwrap()
+        };
+        db.put("u:1", user("Zurich", 31), None).unwrap();
+        db.put("u:2", user("Zurich", 25), None).unwrap();
+        db.put("u:3", user("Berlin", 40), None).unwrap();
+        let q = field("city").eq("Zurich").and(field("age").gt(30));
+        assert_eq!(db.explain(&q), QueryPlan::Scan);
+        let scanned = db.query(&q).unwrap();
+        assert_eq!(scanned, vec![("u:1".to_string(), user("Zurich", 31))]);
+
+        db.create_index("city", "city").unwrap();
+        assert!(matches!(db.explain(&q), QueryPlan::Lookup { ref index, .. } if index == "city"));
+        assert_eq!(db.query(&q).unwrap(), scanned);
+
+        // The index still lists u:1 after it moves, but the value at HEAD
+        // no longer matches.
+        db.put("u:1", user("Zurich", 29), None).unwrap();
+        db.create_branch("other").unwrap();
+        db.checkout("other").unwrap();
+        db.put("u:4", user("Zurich", 50), None).unwrap();
+        db.checkout("main").unwrap();
+        assert!(db.query(&q).unwrap().is_empty());
+
+        let users = db.namespace("users").unwrap();
+        users.put("u:1", user("Zurich", 31), None).unwrap();
+        assert_eq!(users.query(&q).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn sql_selects_columns_at_head_and_past_commits() {
+        let (_tmp, db) = test_db();
+        let user = |city: &str, age: u32| {
+            serde_json::to_vec(&serde_json::json!({ "city": city, "age": age })).unwrap()
+        };
+        db.put("u:1", user("Zurich", 31), None).unwrap();
+        db.create_tag("v1", None, None).unwrap();
+        db.put("u:2", user("Bern", 40), None).unwrap();
+        db.put("note", b"plain text".to_vec(), None).unwrap();
+        db.namespace("users")
+            .unwrap()
+            .put("u:9", user("Basel", 50), None)
+            .unwrap();
+        db.create_index("city", "city").unwrap();
+
+        let rows = db
+            .sql("SELECT key, value.city FROM db WHERE value.age >= 31 ORDER")
+            .unwrap_err();
+        assert!(matches!(rows, IcebergError::InvalidQuery(_)));
+
+        let rows = db
+            .sql("SELECT key, value.city FROM db WHERE value.age >= 31")
+            .unwrap();
+        assert_eq!(rows.columns, vec!["key", "value.city"]);
+        assert_eq!(
+            rows.rows,
+            vec![
+                vec![serde_json::json!("u:1"), serde_json::json!("Zurich")],
+                vec![serde_json::json!("u:2"), serde_json::json!("Bern")],
+            ]
+        );
+
+        let rows = db.sql("SELECT * FROM db LIMIT 1").unwrap();
+        assert_eq!(
+            rows.rows,
+            vec![vec![
+                serde_json::json!("note"),
+                serde_json::json!("plain text")
+            ]]
+        );
+
+        let rows = db
+            .sql("SELECT key FROM db WHERE value.city = 'Bern' OR key LIKE 'u:%' AT 'v1'")
+            .unwrap();
+        assert_eq!(rows.rows, vec![vec![serde_json::json!("u:1")]]);
+
+        let rows = db.sql("SELECT key, value.age FROM users").unwrap();
+        assert_eq!(
+            rows.rows,
+            vec![vec![serde_json::json!("u:9"), serde_json::json!(50)]]
+        );
+        assert!(db.sql("SELECT key FROM db AT 'nope'").is_err());
+    }
+
+    #[test]
+    fn scans_page_with_limits_offsets_and_cursors() {
+        let (_tmp, db) = test_db();
+        for key in ["a:1", "a:2", "a:3", "a:4", "b:1"] {
+            db.put(key, key.as_bytes().to_vec(), None).unwrap();
+        }
+        let users = db.namespace("users").unwrap();
+        users.put("a:9", b"ns".to_vec(), None).unwrap();
+        let keys = |entries: Vec<(String, Vec<u8>)>| -> Vec<String> {
+            entries.into_iter().map(|(k, _)| k).collect()
+        };
+
+        let page = |start_after: Option<&str>, offset| ScanOptions {
+            limit: Some(2),
+            offset,
+            start_after: start_after.map(String::from),
+            ..Default::default()
+        };
+        assert_eq!(
+            keys(db.scan_prefix_with("a:", &page(None, 0)).unwrap()),
+            ["a:1", "a:2"]
+        );
+        assert_eq!(
+            keys(db.scan_prefix_with("a:", &page(Some("a:2"), 0)).unwrap()),
+            ["a:3", "a:4"]
+        );
+        assert!(db
+            .scan_prefix_with("a:", &page(Some("a:4"), 0))
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            keys(db.scan_prefix_with("a:", &page(Some("0"), 1)).unwrap()),
+            ["a:2", "a:3"]
+        );
+        assert_eq!(
+            keys(db.range_with("a:2", "z", &page(Some("a:3"), 0)).unwrap()),
+            ["a:4", "b:1"]
+        );
+        assert!(db.range_with("b", "a", &page(None, 0)).unwrap().is_empty());
+        assert_eq!(
+            keys(users.scan_prefix_with("", &page(None, 0)).unwrap()),
+            ["a:9"]
+        );
+        assert!(users
+            .scan_prefix_with("", &page(Some("a:9"), 0))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn scan_keys_lists_keys_without_values() {
+        let (_tmp, db) = test_db();
+        for key in ["a:1", "a:2", "a:3", "b:1"] {
+            db.put(key, key.as_bytes().to_vec(), None).unwrap();
+        }
+        let users = db.namespace("users").unwrap();
+        users.put("a:9", b"ns".to_vec(), None).unwrap();
+
+        assert_eq!(db.scan_keys("a:").unwrap(), ["a:1", "a:2", "a:3"]);
+        assert_eq!(users.scan_keys("").unwrap(), ["a:9"]);
+        let options = ScanOptions {
+            limit: Some(1),
+            start_after: Some("a:1".into()),
+            ..Default::default()
+        };
+        assert_eq!(db.scan_keys_with("a:", &options).unwrap(), ["a:2"]);
+        assert!(users.scan_keys("\u{1f}").is_err());
+    }
+
+    #[test]
+    fn scans_match_globs_and_regexes() {
+        let (_tmp, db) = test_db();
+        for key in [
+            "user:1:settings",
+            "user:2:profile",
+            "user:3:settings",
+            "x:settings",
+        ] {
+            db.put(key, b"{}".to_vec(), None).unwrap();
+        }
+        let users = db.namespace("users").unwrap();
+        users.put("user:9:settings", b"{}".to_vec(), None).unwrap();
+        let keys = |entries: Vec<(String, Vec<u8>)>| -> Vec<String> {
+            entries.into_iter().map(|(k, _)| k).collect()
+        };
+
+        assert_eq!(
+            keys(db.scan_glob("user:*:settings").unwrap()),
+            ["user:1:settings", "user:3:settings"]
+        );
+        assert_eq!(
+            keys(db.scan_regex(r"^(user:\d|x):settings$").unwrap()),
+            ["user:1:settings", "user:3:settings", "x:settings"]
+        );
+        assert_eq!(
+            keys(users.scan_glob("user:*:settings").unwrap()),
+            ["user:9:settings"]
+        );
+        let options = ScanOptions {
+            limit: Some(1),
+            start_after: Some("user:1:settings".into()),
+            matching: Some(KeyPattern::glob("*settings").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            db.scan_keys_with("", &options).unwrap(),
+            ["user:3:settings"]
+        );
+        assert!(db.scan_regex("(").is_err());
+    }
+
+    #[test]
+    fn disk_usage_splits_components_and_finds_garbage() {
+        let (_tmp, db) = test_db();
+        db.set_inline_threshold(0).unwrap();
+        db.put("a", vec![b'a'; 1000], None).unwrap();
+        db.create_index("x", "x").unwrap();
+        db.flush().unwrap();
+        let usage = db.disk_usage().unwrap();
+        assert!(usage.blocks > 0 && usage.trees > 0 && usage.commits > 0);
+        assert!(usage.bloom > 0 && usage.indexes > 0 && usage.other > 0);
+        assert_eq!(usage.unreachable, 0);
+
+        db.create_branch("tmp").unwrap();
+        db.checkout("tmp").unwrap();
+        db.put("b", vec![b'b'; 1000], None).unwrap();
+        db.checkout("main").unwrap();
+        db.delete_branch("tmp").unwrap();
+        let usage = db.disk_usage().unwrap();
+        assert_eq!(
+            (
+                usage.unreachable_blocks,
+                usage.unreachable_trees,
+                usage.unreachable_commits
+            ),
+            (1, 1, 1)
+        );
+        assert!(usage.unreachable > 0);
+        assert!(usage.to_string().contains("Unreachable:"));
+    }
+
+    #[test]
+    fn size_report_buckets_values_and_ranks_keys() {
+        let (_tmp, db) = test_db();
+        db.put("user:1", vec![b'x'; 10], None).unwrap();
+        db.put("user:2", vec![b'x'; 100], None).unwrap();
+        db.put("log:1", vec![b'x'; 70], None).unwrap();
+        db.put("solo", Vec::new(), None).unwrap();
+        db.namespace("users")
+            .unwrap()
+            .put("big", vec![b'x'; 5000], None)
+            .unwrap();
+
+        let report = db.size_report(2, ':').unwrap();
+        assert_eq!((report.keys, report.total_bytes), (5, 5180));
+        let counts: Vec<_> = report
+            .histogram
+            .iter()
+            .map(|b| (b.below, b.values))
+            .collect();
+        assert_eq!(
+            counts,
+            [(16, 2), (64, 0), (256, 2), (1024, 0), (4096, 0), (16384, 1)]
+        );
+        assert_eq!(
+            report.largest_keys,
+            [
+                ("[users] big".to_string(), 5000),
+                ("user:2".to_string(), 100)
+            ]
+        );
+        assert_eq!(
+            report.largest_prefixes,
+            [
+                ("[users] big".to_string(), 5000, 1),
+                ("user:".to_string(), 110, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn log_pages_load_commits_lazily() {
+        let (_tmp, db) = test_db();
+        assert!(db.log_page(5, 0).unwrap().is_empty());
+        for i in 0..10 {
+            db.put(&format!("k{}", i), b"v".to_vec(), None).unwrap();
+        }
+        let page: Vec<String> = db
+            .log_page(3, 2)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.message)
+            .collect();
+        assert_eq!(page, ["put k7", "put k6", "put k5"]);
+        assert_eq!(db.log_page(5, 8).unwrap().len(), 2);
+
+        // Only the commits reached are read: losing an old one doesn't break
+        // the newest page.
+        let oldest = db.log().unwrap().pop().unwrap();
+        db.backend
+            .delete(&backend::key(COMMITS_DIR, &oldest.id))
+            .unwrap();
+        assert_eq!(db.log_page(3, 0).unwrap().len(), 3);
+        assert!(db.log().is_err());
+        let mut iter = db.log_iter().unwrap();
+        assert_eq!(iter.by_ref().filter(Result::is_ok).count(), 9);
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn log_all_covers_every_branch_and_tag() {
+        let (_tmp, db) = test_db();
+        let root = db.put("a", b"1".to_vec(), None).unwrap();
+        db.create_branch("dev").unwrap();
+        db.create_branch("old").unwrap();
+        let main = db.put("b", b"2".to_vec(), None).unwrap();
+        db.checkout("old").unwrap();
+        let tagged = db.put("t", b"0".to_vec(), None).unwrap();
+        db.create_tag("keep", None, None).unwrap();
+        db.checkout("dev").unwrap();
+        db.delete_branch("old").unwrap();
+        let dev = db.put("c", b"3".to_vec(), None).unwrap();
+
+        let ids: Vec<String> = db.log_all().unwrap().into_iter().map(|c| c.id).collect();
+        assert_eq!(ids.len(), 4);
+        assert_eq!(ids.last(), Some(&root.id));
+        for id in [&main.id, &tagged.id, &dev.id] {
+            assert!(ids.contains(id));
+        }
+        assert_eq!(db.log().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn log_graph_draws_all_branches() {
+        let (_tmp, db) = test_db();
+        db.put("a", b"1".to_vec(), None).unwrap();
+        db.create_branch("dev").unwrap();
+        db.put("b", b"2".to_vec(), None).unwrap();
+        db.create_tag("v1", None, None).unwrap();
+        db.checkout("dev").unwrap();
+        db.put("c", b"3".to_vec(), None).unwrap();
+
+        let lines = db.log_graph(usize::MAX).unwrap();
+        assert_eq!(lines.len(), 4, "{:#?}", lines);
+        assert!(lines[0].starts_with("* ") && lines[0].contains("(HEAD -> dev) put c"));
+        assert!(lines[1].starts_with("| * ") && lines[1].contains("(main, tag: v1) put b"));
+        assert_eq!(lines[2], "|/");
+        assert!(lines[3].ends_with("put a"));
+    }
+
+    #[test]
+    fn inspect_shows_stored_objects() {
+        let (_tmp, db) = test_db();
+        db.put("small", b"hi".to_vec(), None).unwrap();
+        let commit = db.put("big", vec![0xff; 4096], Some("big")).unwrap();
+        db.create_tag("v1", None, None).unwrap();
+
+        let raw = db.inspect(ObjectKind::Commit, &commit.id).unwrap();
+        assert_eq!(raw["message"], "big");
+        let tree = db.inspect(ObjectKind::Tree, &commit.tree_root).unwrap();
+        assert_eq!(tree["entries"]["small"], serde_json::json!([104, 105]));
+        let hash = match db.tree_at(&commit.id).unwrap().get("big") {
+            Some(TreeValue::Block { hash, .. }) => hash.clone(),
+            other => panic!("expected a block, got {:?}", other),
+        };
+        let block = db.inspect(ObjectKind::Block, &hash).unwrap();
+        assert_eq!(block["size"], 4096);
+        assert!(block["data"]["hex"].as_str().unwrap().starts_with("ffff"));
+        let tag = db.inspect(ObjectKind::Tag, "v1").unwrap();
+        assert_eq!(tag["commit_id"], commit.id.as_str());
+        assert_eq!(
+            db.inspect(ObjectKind::Tag, tag["id"].as_str().unwrap())
+                .unwrap(),
+            tag
+        );
+        assert_eq!(db.inspect_refs().unwrap()["head"], "main");
+
+        assert!(matches!(
+            db.inspect(ObjectKind::Commit, "nope"),
+            Err(IcebergError::CommitNotFound(_))
+        ));
+        assert!(db.inspect(ObjectKind::Tree, "nope").is_err());
+        assert_eq!("tag".parse(), Ok(ObjectKind::Tag));
+        assert!("blob".parse::<ObjectKind>().is_err());
+    }
+
+    #[test]
+    fn diff_stat_groups_changes_by_prefix() {
+        let (_tmp, db) = test_db();
+        db.put("user:1", b"aa".to_vec(), None).unwrap();
+        let before = db.put("order:1", b"x".to_vec(), None).unwrap();
+        db.put("user:1", b"aaaa".to_vec(), None).unwrap();
+        db.put("user:2", b"b".to_vec(), None).unwrap();
+        db.delete("order:1", None).unwrap();
+        let users = db.namespace("users").unwrap();
+        let after = users.put("u:9", b"z".to_vec(), None).unwrap();
+
+        let stats = db.diff_stat(&before.id, &after.id, ':').unwrap();
+        let prefixes: Vec<&str> = stats.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(prefixes, ["[users] u:", "order:", "user:"]);
+        let user = stats[2].1;
+        assert_eq!((user.added, user.modified, user.removed), (1, 1, 0));
+        assert_eq!((user.bytes_added, user.bytes_removed), (5, 2));
+        let mut total = DiffStat::default();
+        for (_, stat) in &stats {
+            total += *stat;
+        }
+        assert_eq!(
+            total,
+            db.tree_at(&before.id)
+                .unwrap()
+                .diff_stat(&db.tree_at(&after.id).unwrap())
+        );
+    }
+
+    #[test]
+    fn diff_values_pairs_old_and_new_values() {
+        let (_tmp, db) = test_db();
+        db.put("gone", b"x".to_vec(), None).unwrap();
+        let before = db.put("edit", vec![1; 4096], None).unwrap();
+        db.delete("gone", None).unwrap();
+        db.put("edit", vec![2; 4096], None).unwrap();
+        let after = db.put("new", b"n".to_vec(), None).unwrap();
+
+        let changes = db.diff_values(&before.id, &after.id).unwrap();
+        let keys: Vec<&str> = changes.iter().map(|c| c.key.as_str()).collect();
+        assert_eq!(keys, ["edit", "gone", "new"]);
+        assert_eq!(changes[0].old, Some(vec![1; 4096]));
+        assert_eq!(changes[0].new, Some(vec![2; 4096]));
+        assert_eq!(
+            (changes[1].old.as_deref(), changes[1].new.as_deref()),
+            (Some(&b"x"[..]), None)
+        );
+        assert_eq!(
+            (changes[2].old.as_deref(), changes[2].new.as_deref()),
+            (None, Some(&b"n"[..]))
+        );
+    }
+
+    #[test]
+    fn commit_diff_compares_against_the_parent() {
+        let (_tmp, db) = test_db();
+        let first = db.put("a", b"1".to_vec(), None).unwrap();
+        db.put("b", b"2".to_vec(), None).unwrap();
+        let update = db.put("a", b"3".to_vec(), None).unwrap();
+        let last = db.delete("b", None).unwrap();
+
+        assert_eq!(db.commit_diff(&first.id).unwrap().added, ["a"]);
+        assert_eq!(db.commit_diff(&update.id).unwrap().modified, ["a"]);
+        let stat = db.commit_stat(&update.id).unwrap();
+        assert_eq!(
+            (stat.modified, stat.bytes_added, stat.bytes_removed),
+            (1, 1, 1)
+        );
+        let diff = db.commit_diff(&last.id).unwrap();
+        assert_eq!(
+            (diff.removed.as_slice(), diff.total_changes()),
+            (&["b".to_string()][..], 1)
+        );
+    }
+
+    #[test]
+    fn status_reports_branch_head_and_divergence() {
+        let (_tmp, db) = test_db();
+        let status = db.status().unwrap();
+        assert_eq!(
+            (status.branch.as_str(), status.head.is_none()),
+            ("main", true)
+        );
+        assert!(status.to_string().contains("no commits yet"));
+
+        db.put("a", b"1".to_vec(), None).unwrap();
+        db.create_branch("dev").unwrap();
+        db.checkout("dev").unwrap();
+        db.namespace("users")
+            .unwrap()
+            .put("x", b"2".to_vec(), None)
+            .unwrap();
+        let head = db.put("b", b"3".to_vec(), Some("add b")).unwrap();
+        let status = db.status().unwrap();
+        assert_eq!(status.head.as_ref().map(|c| &c.id), Some(&head.id));
+        assert_eq!((status.key_count, status.namespaced_keys), (2, 1));
+        assert_eq!(status.base, Some(("main".to_string(), 2, 0)));
+        let text = status.to_string();
+        assert!(text.contains("On branch dev"), "{}", text);
+        assert!(text.contains("add b (just now)"), "{}", text);
+        assert!(
+            text.contains("2 commits ahead of main, 0 behind"),
+            "{}",
+            text
+        );
+
+        db.checkout("main").unwrap();
+        assert_eq!(db.status().unwrap().base, None);
+        assert_eq!(age(chrono::Duration::minutes(1)), "1 minute ago");
+        assert_eq!(age(chrono::Duration::hours(30)), "1 day ago");
+    }
+
+    #[test]
+    fn branch_stats_report_divergence_from_main() {
+        let (_tmp, db) = test_db();
+        db.put("a", b"1".to_vec(), None).unwrap();
+        db.put("b", b"2".to_vec(), None).unwrap();
+        db.create_branch("dev").unwrap();
+        db.checkout("dev").unwrap();
+        db.put("c", b"3".to_vec(), None).unwrap();
+        db.namespace("users")
+            .unwrap()
+            .put("x", b"4".to_vec(), None)
+            .unwrap();
+        db.checkout("main").unwrap();
+        let head = db.delete("a", None).unwrap();
+
+        assert!(db.stats().unwrap().branches.is_empty());
+        let stats = db.stats_all_branches().unwrap();
+        let dev = &stats.branches[0];
+        assert_eq!(
+            (
+                dev.name.as_str(),
+                dev.key_count,
+                dev.commit_count,
+                dev.ahead,
+                dev.behind
+            ),
+            ("dev", 3, 4, 2, 1)
+        );
+        let main = &stats.branches[1];
+        assert_eq!(main.head.as_deref(), Some(head.id.as_str()));
+        assert_eq!((main.key_count, main.ahead, main.behind), (1, 0, 0));
+        assert!(stats.to_string().contains("dev: 3 keys, 4 commits"));
+    }
+
+    #[test]
+    fn delete_range_removes_keys_in_one_commit() {
+        let (_tmp, db) = test_db();
+        for key in ["a", "b:1", "b:2", "c"] {
+            db.put(key, br#"{"kind":"x"}"#.to_vec(), None).unwrap();
+        }
+        db.create_index("kind", "kind").unwrap();
+        let users = db.namespace("users").unwrap();
+        users.put("b:9", b"v".to_vec(), None).unwrap();
+        let before = db.log().unwrap().len();
+
+        let commit = db.delete_range("b", "c", None).unwrap().unwrap();
+        assert_eq!(commit.message, "delete 2 keys in [b, c)");
+        assert_eq!(db.log().unwrap().len(), before + 1);
+        assert_eq!(db.count("").unwrap(), 2);
+        assert_eq!(db.query_index("kind", "x").unwrap(), ["a", "c"]);
+        assert!(db.delete_range("b", "c", None).unwrap().is_none());
+        assert_eq!(users.count("").unwrap(), 1);
+
+        users.delete_range("", "z", None).unwrap().unwrap();
+        assert!(users.is_empty().unwrap());
+        assert_eq!(db.count("").unwrap(), 2);
+    }
+
+    #[test]
+    fn write_applies_a_batch_in_one_commit() {
+        let (_tmp, db) = test_db();
+        db.create_index("kind", "kind").unwrap();
+        db.put("a", br#"{"kind":"x"}"#.to_vec(), None).unwrap();
+        let before = db.log().unwrap().len();
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put("b", br#"{"kind":"x"}"#.to_vec())
+            .put("c", b"temp".to_vec())
+            .delete("a")
+            .delete("c")
+            .put("d", vec![7; 4096]);
+        let commit = db.write(&batch, None).unwrap().unwrap();
+        assert_eq!(commit.message, "write 5 keys");
+        assert_eq!(db.log().unwrap().len(), before + 1);
+        assert_eq!(db.scan_keys("").unwrap(), ["b", "d"]);
+        assert_eq!(db.get("d").unwrap(), vec![7; 4096]);
+        assert_eq!(db.query_index("kind", "x").unwrap(), ["b"]);
+        assert!(db.write(&WriteBatch::new(), None).unwrap().is_none());
+
+        // A failing write leaves the whole batch unapplied.
+        let head = db.head_commit().unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put("e", b"1".to_vec()).delete("a");
+        assert!(matches!(
+            db.write(&batch, None),
+            Err(IcebergError::KeyNotFound(ref key)) if key == "a"
+        ));
+        let unique = IndexOptions {
+            unique: true,
+            ..Default::default()
+        };
+        db.create_index_with("one", "kind", &unique).unwrap();
+        let mut batch = WriteBatch::new();
+        batch
+            .put("e", br#"{"kind":"y"}"#.to_vec())
+            .put("f", br#"{"kind":"y"}"#.to_vec());
+        assert!(matches!(
+            db.write(&batch, None),
+            Err(IcebergError::UniqueViolation { ref key, .. }) if key == "e"
+        ));
+        assert_eq!(db.head_commit().unwrap().id, head.id);
+        assert!(db.get("e").is_err());
+    }
+
+    #[test]
+    fn key_locks_hold_back_writers_that_respect_them() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::init(tmp.path()).unwrap();
+        let ttl = std::time::Duration::from_secs(60);
+        db.lock_key("hot", "alice", ttl).unwrap();
+        assert!(matches!(
+            db.lock_key("hot", "bob", ttl),
+            Err(IcebergError::KeyLocked { ref holder, .. }) if holder == "alice"
+        ));
+        assert!(matches!(
+            db.put_as("hot", b"b".to_vec(), "bob", None),
+            Err(IcebergError::KeyLocked { .. })
+        ));
+        assert!(db.get("hot").is_err());
+        db.put_as("hot", b"a".to_vec(), "alice", None).unwrap();
+        db.put_as("cold", b"b".to_vec(), "bob", None).unwrap();
+        // Locks are advisory: a plain put goes through.
+        db.put("hot", b"c".to_vec(), None).unwrap();
+
+        // Another handle on the same files sees the lock.
+        let other = Database::open(tmp.path()).unwrap();
+        let locks = other.key_locks().unwrap();
+        assert_eq!(locks.len(), 1);
+        assert_eq!(
+            (locks[0].key.as_str(), locks[0].holder.as_str()),
+            ("hot", "alice")
+        );
+        assert!(other.unlock_key("hot", "bob").is_err());
+        assert!(db.unlock_key("hot", "alice").unwrap());
+        assert!(!db.unlock_key("hot", "alice").unwrap());
+        other.put_as("hot", b"b".to_vec(), "bob", None).unwrap();
+
+        // A lapsed lease no longer holds anyone back.
+        db.lock_key("hot", "alice", std::time::Duration::ZERO)
+            .unwrap();
+        assert!(db.key_locks().unwrap().is_empty());
+        db.lock_key("hot", "bob", ttl).unwrap();
+    }
+
+    #[test]
+    fn lock_holders_on_two_handles_keep_each_others_commits() {
+        let tmp = tempfile::tempdir().unwrap();
+        let a = Database::init(tmp.path()).unwrap();
+        let b = Database::open(tmp.path()).unwrap();
+        let ttl = std::time::Duration::from_secs(60);
+        a.lock_key("x", "alice", ttl).unwrap();
+        b.lock_key("y", "bob", ttl).unwrap();
+        a.put_as("x", b"1".to_vec(), "alice", None).unwrap();
+        b.put_as("y", b"2".to_vec(), "bob", None).unwrap();
+        a.put_as("x", b"3".to_vec(), "alice", None).unwrap();
+        assert_eq!(b.get("x").unwrap(), b"3");
+        assert_eq!(a.get("y").unwrap(), b"2");
+        assert_eq!(b.log().unwrap().len(), 3);
+
+        // A commit built on a head another process has since moved past
+        // is refused instead of dropping that process's commits.
+        let base = a.load_refs().unwrap().branches.get("main").cloned();
+        let tree = a.current_tree().unwrap().insert("x".into(), b"4".to_vec());
+        b.put_as("y", b"5".to_vec(), "bob", None).unwrap();
+        let tx = a.wal.lock().unwrap().begin().unwrap();
+        assert!(matches!(
+            a.commit_logged("main", base.as_ref(), tx, &tree, "stale"),
+            Err(IcebergError::Conflict { .. })
+        ));
+        assert_eq!(a.get("y").unwrap(), b"5");
+        assert_eq!(a.get("x").unwrap(), b"3");
+        // Nothing of the refused commit is left behind.
+        assert!(a.lost_found().unwrap().is_empty());
+        assert!(!a
+            .backend
+            .exists(&backend::key(TREES_DIR, &tree.root_hash))
+            .unwrap());
+        let recovery = a.wal.lock().unwrap().recover().unwrap();
+        assert!(!recovery.uncommitted.contains(&tx));
+    }
+
+    #[test]
+    fn write_limits_reject_bad_keys_and_large_values() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::init(tmp.path()).unwrap();
+        db.put("Not Checked", b"old".to_vec(), None).unwrap();
+        assert!(db
+            .set_write_limits(WriteLimits {
+                key_pattern: Some("[a-z".into()),
+                ..WriteLimits::default()
+            })
+            .is_err());
+        assert_eq!(db.config().limits, WriteLimits::default());
+        db.set_write_limits(WriteLimits {
+            max_key_len: 8,
+            key_pattern: Some("[a-z0-9:]+".into()),
+            max_value_size: 4,
+        })
+        .unwrap();
+
+        db.put("user:1", b"ok".to_vec(), None).unwrap();
+        for key in ["user:1234", "User:1", "user:1\n"] {
+            assert!(
+                matches!(db.put(key, vec![], None), Err(IcebergError::InvalidKey(_))),
+                "{}",
+                key
+            );
+        }
+        // The pattern must match the whole key.
+        assert!(db.put("a b", vec![], None).is_err());
+        assert!(matches!(
+            db.put("user:1", b"large".to_vec(), None),
+            Err(IcebergError::ValueTooLarge {
+                size: 5,
+                limit: 4,
+                ..
+            })
+        ));
+        assert!(db.put_reader("user:2", &b"large"[..], None).is_err());
+        let mut batch = WriteBatch::new();
+        batch.put("user:2", b"ok".to_vec()).put("BAD", vec![]);
+        assert!(db.write(&batch, None).is_err());
+        assert!(db.get("user:2").is_err());
+
+        // Keys already stored can still be deleted, and namespaced keys
+        // are checked without their namespace.
+        db.delete("Not Checked", None).unwrap();
+        let ns = db.namespace("Some NS").unwrap();
+        ns.put("k", b"v".to_vec(), None).unwrap();
+        assert!(ns.put("K", b"v".to_vec(), None).is_err());
+
+        // The limits survive a reopen.
+        drop(ns);
+        drop(db);
+        let db = Database::open(tmp.path()).unwrap();
+        assert!(db.put("User:1", vec![], None).is_err());
+    }
+
+    #[test]
+    fn quotas_cap_prefixes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::init(tmp.path()).unwrap();
+        db.put("t1:a", vec![0; 10], None).unwrap();
+        db.set_quota(
+            "t1:",
+            Quota {
+                max_keys: 2,
+                max_bytes: 25,
+            },
+        )
+        .unwrap();
+
+        db.put("t1:b", vec![0; 10], None).unwrap();
+        assert!(matches!(
+            db.put("t1:c", vec![], None),
+            Err(IcebergError::QuotaExceeded {
+                resource: "keys",
+                limit: 2,
+                requested: 3,
+                ..
+            })
+        ));
+        assert!(matches!(
+            db.put("t1:b", vec![0; 16], None),
+            Err(IcebergError::QuotaExceeded {
+                resource: "bytes",
+                ..
+            })
+        ));
+        assert!(db.put_reader("t1:b", &[0u8; 16][..], None).is_err());
+        db.put("t1:b", vec![0; 15], None).unwrap();
+        db.put("t2:x", vec![0; 100], None).unwrap();
+
+        // A batch counts its writes together, later ones winning.
+        let mut batch = WriteBatch::new();
+        batch
+            .delete("t1:a")
+            .put("t1:c", vec![0; 5])
+            .put("t1:d", vec![]);
+        assert!(db.write(&batch, None).is_err());
+        let mut batch = WriteBatch::new();
+        batch.delete("t1:a").put("t1:c", vec![0; 5]);
+        db.write(&batch, None).unwrap();
+
+        // Lowering a limit below the usage still lets the prefix shrink.
+        db.set_quota(
+            "t1:",
+            Quota {
+                max_keys: 1,
+                max_bytes: 0,
+            },
+        )
+        .unwrap();
+        db.put("t1:b", vec![0; 1], None).unwrap();
+        assert!(db.put("t1:e", vec![], None).is_err());
+        db.delete("t1:b", None).unwrap();
+
+        let db = Database::open(tmp.path()).unwrap();
+        let usage = db.quotas().unwrap();
+        assert_eq!(usage.len(), 1);
+        assert_eq!((usage[0].keys, usage[0].bytes), (1, 5));
+        assert!(db.remove_quota("t1:").unwrap());
+        db.put("t1:e", vec![], None).unwrap();
+    }
+
+    #[test]
+    fn writes_against_a_stale_head_conflict() {
+        let (_tmp, db) = test_db();
+        let seen = db.put("n", b"1".to_vec(), None).unwrap();
+        db.put("n", b"2".to_vec(), None).unwrap();
+
+        let err = db.put_if_head("n", b"3".to_vec(), &seen.id, None);
+        assert!(matches!(
+            err,
+            Err(IcebergError::Conflict { ref branch, ref expected, .. })
+                if branch == DEFAULT_BRANCH && *expected == seen.id
+        ));
+        assert!(db.delete_if_head("n", &seen.id, None).is_err());
+        let mut batch = WriteBatch::new();
+        batch.put("m", b"1".to_vec());
+        assert!(db.write_if_head(&batch, &seen.id, None).is_err());
+        assert_eq!(db.get("n").unwrap(), b"2");
+        assert!(db.get("m").is_err());
+
+        // A retry loop: re-read the head and value, then try again.
+        let increment = || loop {
+            let head = db.head_commit().unwrap().id;
+            let n: u32 = String::from_utf8(db.get("n").unwrap())
+                .unwrap()
+                .parse()
+                .unwrap();
+            match db.put_if_head("n", (n + 1).to_string().into_bytes(), &head, None) {
+                Err(IcebergError::Conflict { .. }) => continue,
+                result => break result.unwrap(),
+            }
+        };
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..5 {
+                        increment();
+                    }
+                });
+            }
+        });
+        assert_eq!(db.get("n").unwrap(), b"22");
+
+        let head = db.head_commit().unwrap().id;
+        db.write_if_head(&batch, &head, None).unwrap().unwrap();
+        let head = db.head_commit().unwrap().id;
+        db.delete_if_head("m", &head, None).unwrap();
+    }
+
+    #[test]
+    fn snapshot_reads_stay_put_and_survive_compaction() {
+        let (_tmp, db) = test_db();
+        db.put("a", b"1".to_vec(), None).unwrap();
+        db.put("b", vec![5; 4096], None).unwrap();
+        let snap = db.snapshot().unwrap();
+        assert_eq!(snap.commit().id, db.head_commit().unwrap().id);
+
+        db.put("a", b"2".to_vec(), None).unwrap();
+        db.delete("b", None).unwrap();
+        db.put("c", b"3".to_vec(), None).unwrap();
+        db.create_branch("dev").unwrap();
+        db.checkout("dev").unwrap();
+        db.put("d", b"4".to_vec(), None).unwrap();
+        let policy = CompactionPolicy {
+            max_versions: 1,
+            ..Default::default()
+        };
+        assert!(db.compact(&policy).unwrap().commits_removed > 0);
+
+        assert_eq!(snap.get("a").unwrap(), b"1");
+        assert_eq!(snap.get("b").unwrap(), vec![5; 4096]);
+        assert!(!snap.contains_key("c"));
+        let keys: Vec<String> = snap
+            .scan_prefix("")
+            .unwrap()
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(keys, ["a", "b"]);
+        assert_eq!(snap.range("b", "z").unwrap().len(), 1);
+        let diff = snap.diff(&db.head_commit().unwrap().id).unwrap();
+        assert_eq!(
+            (diff.added, diff.removed, diff.modified),
+            (
+                vec!["c".to_string(), "d".to_string()],
+                vec!["b".to_string()],
+                vec!["a".to_string()]
+            )
+        );
+        assert_eq!(db.disk_usage().unwrap().unreachable_trees, 0);
+
+        // Once the snapshot is gone its tree is garbage like any other.
+        drop(snap);
+        let usage = db.disk_usage().unwrap();
+        assert_eq!(usage.unreachable_trees, 1);
+        assert_eq!(usage.unreachable_blocks, 1);
+    }
+
+    #[test]
+    fn count_tallies_keys_now_and_in_the_past() {
+        let (_tmp, db) = test_db();
+        db.put("a:1", b"1".to_vec(), None).unwrap();
+        let first = db.put("a:2", b"2".to_vec(), None).unwrap();
+        db.put("a:3", b"3".to_vec(), None).unwrap();
+        db.put("b:1", b"1".to_vec(), None).unwrap();
+        let users = db.namespace("users").unwrap();
+        users.put("a:9", b"9".to_vec(), None).unwrap();
+
+        assert_eq!(db.count("a:").unwrap(), 3);
+        assert_eq!(db.count("").unwrap(), 4);
+        assert_eq!(db.count_at("a:", &first.id).unwrap(), 2);
+        assert_eq!(users.count("a:").unwrap(), 1);
+        assert_eq!(users.count_at("", &first.id).unwrap(), 0);
+    }
+
+    #[test]
+    fn sample_picks_keys_uniformly() {
+        let (_tmp, db) = test_db();
+        let keys: Vec<String> = (0..20).map(|i| format!("k:{:02}", i)).collect();
+        for key in &keys {
+            db.put(key, b"v".to_vec(), None).unwrap();
+        }
+        db.put("other", b"v".to_vec(), None).unwrap();
+
+        assert_eq!(db.sample(50, "k:").unwrap(), keys);
+        assert!(db.sample(0, "k:").unwrap().is_empty());
+        let sample = db.sample_with_seed(5, "k:", 7).unwrap();
+        assert_eq!(sample.len(), 5);
+        assert!(sample.windows(2).all(|w| w[0] < w[1]));
+        assert!(sample.iter().all(|k| k.starts_with("k:")));
+        assert_eq!(db.sample_with_seed(5, "k:", 7).unwrap(), sample);
+
+        // Every key turns up about equally often across seeds.
+        let mut hits = HashMap::new();
+        for seed in 1..=2000 {
+            for key in db.sample_with_seed(5, "k:", seed).unwrap() {
+                *hits.entry(key).or_insert(0) += 1;
+            }
+        }
+        assert_eq!(hits.len(), 20);
+        assert!(
+            hits.values().all(|&n| (350..650).contains(&n)),
+            "{:?}",
+            hits
+        );
+    }
+
+    #[test]
+    fn grep_searches_values_under_a_prefix() {
+        let (_tmp, db) = test_db();
+        db.put("u:1", br#"{"city":"Zurich"}"#.to_vec(), None)
+            .unwrap();
+        db.put(
+            "u:2",
+            br#"{"city":"Bern","note":"not Zurich"}"#.to_vec(),
+            None,
+        )
+        .unwrap();
+        db.put("v:1", b"Zurich".to_vec(), None).unwrap();
+        let users = db.namespace("users").unwrap();
+        users.put("u:9", b"Zurich".to_vec(), None).unwrap();
+        let keys =
+            |found: Vec<GrepMatch>| -> Vec<String> { found.into_iter().map(|m| m.key).collect() };
+
+        let options = GrepOptions::default();
+        assert_eq!(
+            keys(db.grep("Zurich", "u:", &options).unwrap()),
+            ["u:1", "u:2"]
+        );
+        assert_eq!(keys(users.grep("Zurich", "", &options).unwrap()), ["u:9"]);
+        let json = GrepOptions {
+            json_strings: true,
+            limit: Some(2),
+            ..Default::default()
+        };
+        let found = db
+            .grep(
+                "^(Bern|Zurich)$",
+                "",
+                &GrepOptions {
+                    regex: true,
+                    ..json
+                },
+            )
+            .unwrap();
+        assert_eq!(keys(found.clone()), ["u:1", "u:2"]);
+        assert_eq!(found[1].field.as_deref(), Some("city"));
+        assert_eq!(found[1].snippet, "Bern");
+    }
+
+    #[test]
+    fn query_index_at_answers_for_past_commits() {
+        let (_tmp, db) = test_db();
+        let city = |c: &str| serde_json::to_vec(&serde_json::json!({ "city": c })).unwrap();
+        db.create_index_with(
+            "city",
+            "city",
+            &IndexOptions {
+                collation: crate::index::Collation::CaseInsensitive,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        db.put("u:1", city("Bern"), None).unwrap();
+        let v1 = db.put("u:2", city("bern"), None).unwrap().id;
+        db.put("u:1", city("Zurich"), None).unwrap();
+        db.delete("u:2", None).unwrap();
+        let users = db.namespace("users").unwrap();
+        users.create_index("city", "city").unwrap();
+        let v2 = users.put("u:9", city("Bern"), None).unwrap().id;
+        users.put("u:9", city("Basel"), None).unwrap();
+
+        assert!(db.query_index("city", "BERN").unwrap().is_empty());
+        assert_eq!(
+            db.query_index_at("city", "BERN", &v1).unwrap(),
+            vec!["u:1", "u:2"]
+        );
+        assert_eq!(
+            db.query_index_at("city", "Zurich", &v2).unwrap(),
+            vec!["u:1"]
+        );
+        assert_eq!(
+            users.query_index_at("city", "Bern", &v2).unwrap(),
+            vec!["u:9"]
+        );
+        assert!(users
+            .query_index_at("city", "Bern", &v1)
+            .unwrap()
+            .is_empty());
+        assert!(db.query_index_at("nope", "Bern", &v1).is_err());
+    }
+
+    #[test]
+    fn index_builds_catch_up_with_writes_made_meanwhile() {
+        let (_tmp, db) = test_db();
+        let city = |c: &str| serde_json::to_vec(&serde_json::json!({ "city": c })).unwrap();
+        db.put("u:1", city("Bern"), None).unwrap();
+        db.put("u:2", city("Zurich"), None).unwrap();
+
+        let mut reports = Vec::new();
+        db.create_index_with_progress("city", "city", &IndexOptions::default(), |done, total| {
+            reports.push((done, total));
+            // Writers are not blocked while the index is built.
+            db.put("u:3", city("Bern"), None).unwrap();
+            db.delete("u:2", None).unwrap();
+            db.put("u:1", city("Basel"), None).unwrap();
+        })
+        .unwrap();
+        assert_eq!(reports, vec![(2, 2)]);
+        assert_eq!(db.query_index("city", "Bern").unwrap(), vec!["u:3"]);
+        assert_eq!(db.query_index("city", "Basel").unwrap(), vec!["u:1"]);
+        assert!(db.query_index("city", "Zurich").unwrap().is_empty());
+
+        let db = Arc::new(db);
+        assert!(db
+            .create_index_background("city", "city", &IndexOptions::default())
+            .is_err());
+        let build = db
+            .create_index_background("town", "city", &IndexOptions::default())
+            .unwrap();
+        while !build.is_finished() {
+            std::thread::yield_now();
+        }
+        assert_eq!(build.progress(), (2, 2));
+        build.wait().unwrap();
+        assert_eq!(db.query_index("town", "Bern").unwrap(), vec!["u:3"]);
+        db.put("u:4", city("Bern"), None).unwrap();
+        assert_eq!(db.query_index("town", "Bern").unwrap(), vec!["u:3", "u:4"]);
+    }
+
+    #[test]
+    fn index_stats_count_values_and_files() {
+        let (_tmp, db) = test_db();
+        db.create_index("city", "city").unwrap();
+        for (key, city) in [("u:1", "Bern"), ("u:2", "Zurich"), ("u:3", "Bern")] {
+            let value = serde_json::to_vec(&serde_json::json!({ "city": city, "v": [1.0, 0.0] }));
+            db.put(key, value.unwrap(), None).unwrap();
+        }
+        db.create_vector_index("v", "v", &VectorOptions::new(2))
+            .unwrap();
+        db.flush().unwrap();
+
+        let stats = db.index_stats("city").unwrap();
+        assert_eq!(stats.kind, Some(crate::index::IndexKind::String));
+        assert_eq!(
+            (stats.cardinality, stats.total_entries, stats.keys),
+            (2, 3, 3)
+        );
+        assert_eq!(
+            stats.top_values,
+            vec![("Bern".to_string(), 2), ("Zurich".to_string(), 1)]
+        );
+        assert!(stats.memory_bytes > 0 && stats.disk_bytes > 0);
+        let vectors = db.index_stats("v").unwrap();
+        assert_eq!((vectors.kind, vectors.keys), (None, 3));
+        assert!(db.index_stats("nope").is_err());
+    }
+
+    #[test]
+    fn rebuild_index_drops_stale_entries() {
+        let (_tmp, db) = test_db();
+        db.create_index("city", "city").unwrap();
+        db.create_index("age", "age").unwrap();
+        db.put("u:1", br#"{"city":"Bern","age":3}"#.to_vec(), None)
+            .unwrap();
+        db.indexes()
+            .lock()
+            .unwrap()
+            .on_put("ghost", br#"{"city":"Bern","age":3}"#);
+        let users = db.namespace("users").unwrap();
+        users.create_index("city", "city").unwrap();
+        users
+            .put("u:9", br#"{"city":"Basel"}"#.to_vec(), None)
+            .unwrap();
+
+        let mut reports = Vec::new();
+        let rebuilt = db
+            .rebuild_index_with(Some("city"), |read, total| reports.push((read, total)))
+            .unwrap();
+        assert_eq!(rebuilt, vec!["city"]);
+        assert_eq!(reports, vec![(1, 1)]);
+        assert_eq!(db.query_index("city", "Bern").unwrap(), vec!["u:1"]);
+        assert_eq!(db.query_index("age", "3").unwrap(), vec!["ghost", "u:1"]);
+
+        assert_eq!(db.rebuild_index(None).unwrap(), vec!["age", "city"]);
+        assert_eq!(db.query_index("age", "3").unwrap(), vec!["u:1"]);
+        assert_eq!(users.rebuild_index(None).unwrap(), vec!["city"]);
+        assert_eq!(users.query_index("city", "Basel").unwrap(), vec!["u:9"]);
+        assert!(db.rebuild_index(Some("nope")).is_err());
+    }
+
+    #[test]
+    fn aggregate_counts_from_indexes_and_scans_values() {
+        let (_tmp, db) = test_db();
+        let user = |city: &str, age: u32| {
+            serde_json::to_vec(&serde_json::json!({ "city": city, "age": age })).unwrap()
+        };
+        db.put("u:1", user("Zurich", 31), None).unwrap();
+        db.put("u:2", user("Bern", 40), None).unwrap();
+        db.put("u:3", user("Zurich", 50), None).unwrap();
+        db.put("note", b"plain text".to_vec(), None).unwrap();
+        db.create_index("city", "city").unwrap();
+        db.create_branch("other").unwrap();
+        db.checkout("other").unwrap();
+        db.put("u:4", user("Basel", 20), None).unwrap();
+        db.checkout("main").unwrap();
+        db.namespace("users")
+            .unwrap()
+            .put("u:9", user("Basel", 60), None)
+            .unwrap();
+
+        let groups = |pairs: &[(Option<&str>, f64)]| -> Groups {
+            pairs
+                .iter()
+                .map(|(g, n)| (g.map(String::from), *n))
+                .collect()
+        };
+        // Served by the index: u:4 is only on the other branch.
+        let counts = db
+            .aggregate(&Query::all(), Some("city"), &Aggregate::Count)
+            .unwrap();
+        assert_eq!(
+            counts,
+            groups(&[(None, 1.0), (Some("Bern"), 1.0), (Some("Zurich"), 2.0)])
+        );
+        // Scanned: same answer without the index.
+        let scanned = db
+            .aggregate(&Query::all(), Some("age"), &Aggregate::Count)
+            .unwrap();
+        assert_eq!(scanned.len(), 4);
+
+        let sums = db
+            .aggregate(
+                &crate::query::key().prefix("u:"),
+                Some("city"),
+                &Aggregate::Sum("age".into()),
+            )
+            .unwrap();
+        assert_eq!(
+            sums,
+            groups(&[(Some("Bern"), 40.0), (Some("Zurich"), 81.0)])
+        );
+        let max = db
+            .aggregate(
+                &field("city").eq("Zurich"),
+                None,
+                &"max(age)".parse().unwrap(),
+            )
+            .unwrap();
+        assert_eq!(max, groups(&[(None, 50.0)]));
+
+        let users = db.namespace("users").unwrap();
+        let counts = users
+            .aggregate(&Query::all(), Some("city"), &Aggregate::Count)
+            .unwrap();
+        assert_eq!(counts, groups(&[(Some("Basel"), 1.0)]));
+    }
+
+    #[test]
+    fn secondary_index_prefix_query() {
+        let (_tmp, db) = test_db();
+        db.create_index("city", "city").unwrap();
+
+        let z1 = serde_json::to_vec(&serde_json::json!({"city": "Zurich"})).unwrap();
+        let z2 = serde_json::to_vec(&serde_json::json!({"city": "Zug"})).unwrap();
+        let b1 = serde_json::to_vec(&serde_json::json!({"city": "Berlin"})).unwrap();
+        db.put("c:1", z1, None).unwrap();
+        db.put("c:2", z2, None).unwrap();
+        db.put("c:3", b1, None).unwrap();
+
+        let results = db.query_index_prefix("city", "Zu").unwrap();
+        assert_eq!(results.len(), 2);
+    }
+
+    #[test]
+    fn typed_index_range_query_survives_reopen() {
+        let (tmp, db) = test_db();
+        let options = IndexOptions {
+            kind: crate::index::IndexKind::Integer,
+            ..Default::default()
+        };
+        db.create_index_with("age", "age", &options).unwrap();
+        for (key, age) in [("u:1", 9), ("u:2", 10), ("u:3", 30), ("u:4", 100)] {
+            let value = serde_json::to_vec(&serde_json::json!({ "age": age })).unwrap();
+            db.put(key, value, None).unwrap();
+        }
+        assert_eq!(
+            db.query_index_range("age", Some("9"), Some("30")).unwrap(),
+            vec!["u:1", "u:2", "u:3"]
+        );
+        db.close().unwrap();
+
+        let db = Database::open(tmp.path()).unwrap();
+        assert_eq!(
+            db.query_index_range("age", Some("10"), None).unwrap(),
+            vec!["u:2", "u:3", "u:4"]
+        );
+        assert!(matches!(
+            db.query_index_range("age", Some("ten"), None),
+            Err(IcebergError::InvalidQuery(_))
+        ));
+    }
+
+    #[test]
+    fn unique_index_rejects_duplicate_values() {
+        let (_tmp, db) = test_db();
+        let email = |e: &str| serde_json::to_vec(&serde_json::json!({ "email": e })).unwrap();
+        let unique = IndexOptions {
+            unique: true,
+            ..Default::default()
+        };
+        db.create_index_with("email", "email", &unique).unwrap();
+        db.put("u:1", email("a@x"), None).unwrap();
+        let head = db.head_commit().unwrap();
+
+        let err = db.put("u:2", email("a@x"), None).unwrap_err();
+        assert!(matches!(err, IcebergError::UniqueViolation { ref key, .. } if key == "u:1"));
+        assert_eq!(db.head_commit().unwrap().id, head.id);
+        assert!(db.get("u:2").is_err());
+
+        // Rewriting the holder of a value is fine, and frees the old value.
+        db.put("u:1", email("a@x"), None).unwrap();
+        db.put("u:1", email("b@x"), None).unwrap();
+        db.put("u:2", email("a@x"), None).unwrap();
+
+        db.create_index("plain", "email").unwrap();
+        db.drop_index("email").unwrap();
+        db.put("u:3", email("a@x"), None).unwrap();
+
+        // An index cannot be made unique over values that already repeat.
+        assert!(matches!(
+            db.create_index_with("email", "email", &unique),
+            Err(IcebergError::UniqueViolation { .. })
+        ));
+        assert_eq!(db.list_indexes(), vec!["plain"]);
+    }
+
+    #[test]
+    fn config_persists_across_reopen() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut config = DbConfig::default();
+        config.compression.codec = compression::Codec::Zstd;
+        config.compression.zstd_level = 12;
+        {
+            let db = Database::init_with_config(tmp.path(), config.clone()).unwrap();
+            db.put("k", b"v".to_vec(), None).unwrap();
+        }
+        let db = Database::open(tmp.path()).unwrap();
+        assert_eq!(db.config(), config);
+        assert_eq!(db.get("k").unwrap(), b"v");
+    }
 
-        let mut reachable_trees = HashSet::new();
-        for cid in &all_reachable_commits {
-            if removable.contains(cid) && !keep_commit_ids.contains(cid) {
-                continue;
-            }
-            if let Ok(c) = self.load_commit(cid) {
-                reachable_trees.insert(c.tree_root.clone());
-            }
+    #[test]
+    fn train_dictionary_switches_to_zstd() {
+        let (_tmp, db) = test_db();
+        for i in 0..200 {
+            let doc = serde_json::json!({"id": i, "name": format!("user{}", i), "city": "Zurich"});
+            db.put(
+                &format!("user:{}", i),
+                serde_json::to_vec(&doc).unwrap(),
+                None,
+            )
+            .unwrap();
         }
+        let trained = db.train_dictionary(200, 4096).unwrap();
+        assert!(trained.size > 0);
+        let cfg = db.config().compression;
+        assert_eq!(cfg.codec, compression::Codec::Zstd);
+        assert_eq!(cfg.dictionary, Some(trained.id));
+
+        db.set_inline_threshold(0).unwrap();
+        let value = br#"{"id":1,"name":"x","city":"Zurich"}"#.to_vec();
+        db.put("user:new", value.clone(), None).unwrap();
+        assert_eq!(db.get("user:new").unwrap(), value);
+    }
 
-        let mut result = CompactionResult::default();
+    #[test]
+    fn inline_threshold_splits_small_and_large_values() {
+        let (_tmp, db) = test_db();
+        db.set_inline_threshold(16).unwrap();
+        let big = vec![7u8; 1000];
+        db.put("small", b"tiny".to_vec(), None).unwrap();
+        db.put("big", big.clone(), None).unwrap();
+
+        let tree = db.current_tree().unwrap();
+        assert_eq!(
+            tree.get("small"),
+            Some(&TreeValue::Inline(b"tiny".to_vec()))
+        );
+        let big_ref = tree.get("big").unwrap();
+        assert!(big_ref.as_inline().is_none());
+        assert_eq!(big_ref.size(), 1000);
+        assert!(db.store.contains(big_ref.block_hash().unwrap()));
+
+        assert_eq!(db.get("big").unwrap(), big);
+        assert_eq!(db.scan_prefix("b").unwrap(), vec![("big".to_string(), big)]);
+        assert_eq!(db.store.block_count().unwrap(), 1);
+    }
 
-        // Remove commits
-        for cid in &removable {
-            // Only remove if not reachable from other branches
-            if all_reachable_commits.contains(cid) && keep_commit_ids.contains(cid) {
-                continue;
-            }
-            let path = self.root.join(COMMITS_DIR).join(cid);
-            if path.exists() {
-                // Rewrite parent pointer of child commit if needed
-                fs::remove_file(&path)?;
-                result.commits_removed += 1;
-            }
-        }
+    /// Feeds `data` to a streaming put, calling `interrupt` once the first
+    /// chunk has been read and stored.
+    struct InterruptedReader<'a, F: FnMut()> {
+        data: &'a [u8],
+        read: usize,
+        interrupt: Option<F>,
+    }
 
-        // If we removed commits, fix the chain: find the oldest kept commit
-        // and set its parent to None
-        if result.commits_removed > 0 {
-            let kept_commits: Vec<_> = log.iter().filter(|c| !removable.contains(&c.id)).collect();
-            if let Some(oldest_kept) = kept_commits.last() {
-                if let Some(ref parent_id) = oldest_kept.parent {
-                    let parent_path = self.root.join(COMMITS_DIR).join(parent_id);
-                    if !parent_path.exists() {
-                        // Rewrite this commit with parent = None
-                        let mut fixed = (*oldest_kept).clone();
-                        fixed.parent = None;
-                        self.save_commit(&fixed)?;
-                    }
-                }
+    impl<'a, F: FnMut()> InterruptedReader<'a, F> {
+        fn new(data: &'a [u8], interrupt: F) -> Self {
+            Self {
+                data,
+                read: 0,
+                interrupt: Some(interrupt),
             }
         }
+    }
 
-        // Clean up unreachable trees
-        let trees_dir = self.root.join(TREES_DIR);
-        if trees_dir.exists() {
-            for entry in fs::read_dir(&trees_dir)? {
-                let entry = entry?;
-                let name = entry.file_name().to_string_lossy().to_string();
-                if !reachable_trees.contains(&name) {
-                    let size = entry.metadata()?.len();
-                    fs::remove_file(entry.path())?;
-                    result.trees_removed += 1;
-                    result.bytes_reclaimed += size;
+    impl<F: FnMut()> Read for InterruptedReader<'_, F> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.read >= STREAM_CHUNK_SIZE {
+                if let Some(mut interrupt) = self.interrupt.take() {
+                    interrupt();
                 }
             }
+            let n = buf.len().min(self.data.len() - self.read);
+            buf[..n].copy_from_slice(&self.data[self.read..self.read + n]);
+            self.read += n;
+            Ok(n)
         }
-
-        Ok(result)
     }
 
-    // ── Stats ─────────────────────────────────────────────────
-
-    /// Database statistics.
-    pub fn stats(&self) -> Result<DbStats> {
-        let tree = self.current_tree().unwrap_or_else(|_| Tree::empty());
-        let commits = self.log()?;
-        let branches = self.branches()?;
-        let (bloom_items, bloom_bits, bloom_fp) = self.bloom_stats();
-        let index_count = self.list_indexes().len();
-        let wal_size = self.wal.lock().unwrap().size();
-        Ok(DbStats {
-            key_count: tree.len(),
-            commit_count: commits.len(),
-            branch_count: branches.len(),
-            block_count: self.store.block_count()?,
-            disk_usage: self.store.disk_usage()?,
-            bloom_items,
-            bloom_bits,
-            bloom_fp_rate: bloom_fp,
-            index_count,
-            wal_size,
-        })
+    #[test]
+    fn compaction_keeps_blocks_of_streams_in_flight() {
+        let (_tmp, db) = test_db();
+        db.put("k", vec![1; 10_000], None).unwrap();
+        db.put("k", vec![2; 10_000], None).unwrap();
+        let data: Vec<u8> = (0..(STREAM_CHUNK_SIZE * 3))
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut compacted = None;
+        let reader = InterruptedReader::new(&data, || {
+            let policy = crate::compaction::CompactionPolicy {
+                max_versions: 1,
+                ..Default::default()
+            };
+            compacted = Some(db.compact(&policy).unwrap());
+        });
+        db.put_reader("big", reader, None).unwrap();
+        // Only the overwritten value went.
+        assert_eq!(compacted.unwrap().blocks_removed, 1);
+        assert_eq!(db.get("big").unwrap(), data);
+        assert!(db.fsck().unwrap().is_healthy());
+        assert!(db.in_flight.lock().unwrap().is_empty());
     }
 
-    // ── Internal ──────────────────────────────────────────────
+    #[test]
+    fn compaction_keeps_blobs_of_streams_in_flight() {
+        let (_tmp, db) = test_db();
+        db.set_blob_threshold(STREAM_CHUNK_SIZE as u64).unwrap();
+        db.put("k", vec![1; 10_000], None).unwrap();
+        db.put("k", vec![2; 10_000], None).unwrap();
+        let data: Vec<u8> = (0..(STREAM_CHUNK_SIZE * 2 + 5))
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let policy = crate::compaction::CompactionPolicy {
+            max_versions: 1,
+            ..Default::default()
+        };
+        std::thread::scope(|scope| {
+            let writer = db.writer.lock().unwrap();
+            let put = scope.spawn(|| db.put_reader("big", &data[..], None));
+            // The blob is stored before the put waits for the writer lock.
+            while db.blobs.blob_sizes().unwrap().is_empty() {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            let result = db.compact_locked(&policy, &mut |_, _| {}).unwrap();
+            assert_eq!((result.blocks_removed, result.blobs_removed), (1, 0));
+            drop(writer);
+            put.join().unwrap().unwrap();
+        });
+        assert!(db
+            .current_tree()
+            .unwrap()
+            .get("big")
+            .unwrap()
+            .blob_hash()
+            .is_some());
+        assert_eq!(db.get("big").unwrap(), data);
+        assert!(db.fsck().unwrap().is_healthy());
+    }
 
-    fn current_tree(&self) -> Result<Tree> {
-        let commit = self.head_commit()?;
-        self.load_tree(&commit.tree_root)
+    #[test]
+    fn auto_compaction_keeps_blocks_of_streams_in_flight() {
+        let (_tmp, db) = test_db();
+        let policy = crate::compaction::CompactionPolicy {
+            max_versions: 1,
+            ..Default::default()
+        };
+        db.set_auto_compaction(AutoCompactionConfig {
+            policy: Some(policy.clone()),
+            commits: 1,
+            disk_bytes: 0,
+            inline: true,
+        })
+        .unwrap();
+        let data: Vec<u8> = (0..(STREAM_CHUNK_SIZE * 3))
+            .map(|i| (i % 251) as u8)
+            .collect();
+        // Other writes compact inline after each commit, and a maintenance
+        // pass runs, while the stream is open.
+        let reader = InterruptedReader::new(&data, || {
+            db.put("k", vec![1; 10_000], None).unwrap();
+            db.put("k", vec![2; 10_000], None).unwrap();
+            let report = db.maintain(Some(&policy)).unwrap();
+            assert!(report.compaction.is_some());
+        });
+        db.put_reader("big", reader, None).unwrap();
+        assert_eq!(db.get("big").unwrap(), data);
+        assert_eq!(db.get("k").unwrap(), vec![2; 10_000]);
+        assert!(db.fsck().unwrap().is_healthy());
     }
 
-    fn commit_tree(&self, tree: &Tree, message: &str) -> Result<Commit> {
-        // Save tree
-        self.save_tree(tree)?;
+    #[test]
+    fn streaming_put_and_get() {
+        let (_tmp, db) = test_db();
+        let data: Vec<u8> = (0..(STREAM_CHUNK_SIZE * 2 + 123))
+            .map(|i| (i % 251) as u8)
+            .collect();
+        db.put_reader("blob", &data[..], None).unwrap();
 
-        // Save data blocks
-        for v in tree.entries.values() {
-            let block = Block::new(v.clone());
-            self.store.put(&block)?;
+        let tree = db.current_tree().unwrap();
+        match tree.get("blob").unwrap() {
+            TreeValue::Chunked { chunks, size } => {
+                assert_eq!(chunks.len(), 3);
+                assert_eq!(*size, data.len() as u64);
+            }
+            other => panic!("expected chunked value, got {:?}", other),
         }
 
-        // Create commit
-        let parent = self.head_commit().ok().map(|c| c.id);
-        let commit = Commit::new(parent, tree.root_hash.clone(), message.into());
-        self.save_commit(&commit)?;
-
-        // Update branch ref
-        let mut refs = self.load_refs()?;
-        refs.branches.insert(refs.head.clone(), commit.id.clone());
-        self.save_refs(&refs)?;
-
-        Ok(commit)
+        let mut out = Vec::new();
+        let written = db.get_writer("blob", &mut out).unwrap();
+        assert_eq!(written, data.len() as u64);
+        assert_eq!(out, data);
+        assert_eq!(db.get("blob").unwrap(), data);
     }
 
-    fn save_tree(&self, tree: &Tree) -> Result<()> {
-        let path = self.root.join(TREES_DIR).join(&tree.root_hash);
-        let data = serde_json::to_vec_pretty(tree)?;
-        fs::write(path, data)?;
-        Ok(())
+    #[test]
+    fn large_values_spill_to_the_blob_store() {
+        let (tmp, db) = test_db();
+        let threshold = STREAM_CHUNK_SIZE as u64 + 10;
+        db.set_blob_threshold(threshold).unwrap();
+        let data: Vec<u8> = (0..(STREAM_CHUNK_SIZE * 2 + 123))
+            .map(|i| (i % 251) as u8)
+            .collect();
+        db.put("put", data.clone(), None).unwrap();
+        db.put_reader("streamed", &data[..], None).unwrap();
+        let mut batch = WriteBatch::new();
+        batch
+            .put("batched", data[..threshold as usize].to_vec())
+            .put("small", vec![1; 10]);
+        db.write(&batch, None).unwrap();
+
+        let tree = db.current_tree().unwrap();
+        let blob = tree.get("put").unwrap().blob_hash().unwrap().to_string();
+        // Stored once however it was written, and no blocks left in use.
+        assert_eq!(tree.get("streamed").unwrap().blob_hash(), Some(&*blob));
+        assert!(tree.get("batched").unwrap().blob_hash().is_some());
+        assert!(tree.get("small").unwrap().blob_hash().is_none());
+        assert_eq!(db.blobs.blob_sizes().unwrap().len(), 2);
+        assert_eq!(db.stat_key("put").unwrap().blob_hash, Some(blob.clone()));
+
+        let mut out = Vec::new();
+        assert_eq!(
+            db.get_writer("streamed", &mut out).unwrap(),
+            data.len() as u64
+        );
+        assert_eq!(out, data);
+        drop(db);
+        let db = Database::open(tmp.path()).unwrap();
+        assert_eq!(db.get("put").unwrap(), data);
+        assert_eq!(
+            db.disk_usage().unwrap().blobs,
+            data.len() as u64 + threshold
+        );
+
+        // Compaction keeps blobs in use and drops the rest.
+        db.delete("batched", None).unwrap();
+        let result = db
+            .compact(&CompactionPolicy {
+                max_versions: 1,
+                ..CompactionPolicy::default()
+            })
+            .unwrap();
+        assert_eq!(result.blobs_removed, 1);
+        assert!(db.blobs.contains(&blob));
+        assert!(db.fsck().unwrap().issues.is_empty());
+
+        db.backend
+            .write(&db.blobs.blob_key(&blob), b"tampered")
+            .unwrap();
+        assert!(db.get("put").is_err());
+        assert!(matches!(
+            db.fsck().unwrap().issues[..],
+            [FsckIssue::CorruptBlock { .. }]
+        ));
     }
 
-    fn load_tree(&self, root_hash: &str) -> Result<Tree> {
-        let path = self.root.join(TREES_DIR).join(root_hash);
-        if !path.exists() {
-            return Err(IcebergError::Corruption(format!(
-                "tree not found: {}",
-                root_hash
-            )));
+    #[test]
+    fn batches_of_large_values_keep_their_order() {
+        let (_tmp, db) = test_db();
+        let mut batch = WriteBatch::new();
+        for i in 0..40u8 {
+            batch.put(&format!("k{:02}", i), vec![i; 10_000]);
         }
-        let data = fs::read(path)?;
-        Ok(serde_json::from_slice(&data)?)
+        batch
+            .put("small", b"inline".to_vec())
+            .put("k00", vec![99; 10_000]);
+        db.write(&batch, None).unwrap();
+        assert_eq!(db.get("k00").unwrap(), vec![99; 10_000]);
+        assert_eq!(db.get("k39").unwrap(), vec![39; 10_000]);
+        assert_eq!(db.get("small").unwrap(), b"inline");
+        assert_eq!(db.store.block_count().unwrap(), 41);
+        assert!(db.fsck().unwrap().issues.is_empty());
     }
 
-    fn save_commit(&self, commit: &Commit) -> Result<()> {
-        let path = self.root.join(COMMITS_DIR).join(&commit.id);
-        let data = serde_json::to_vec_pretty(commit)?;
-        fs::write(path, data)?;
-        Ok(())
+    #[test]
+    fn get_writer_handles_inline_values() {
+        let (_tmp, db) = test_db();
+        db.put("k", b"small".to_vec(), None).unwrap();
+        let mut out = Vec::new();
+        db.get_writer("k", &mut out).unwrap();
+        assert_eq!(out, b"small");
+        assert!(db.get_writer("missing", &mut out).is_err());
     }
 
-    fn load_commit(&self, id: &str) -> Result<Commit> {
-        let path = self.root.join(COMMITS_DIR).join(id);
-        if !path.exists() {
-            return Err(IcebergError::CommitNotFound(id.into()));
-        }
-        let data = fs::read(path)?;
-        Ok(serde_json::from_slice(&data)?)
+    #[test]
+    fn stat_key_reports_size_and_last_commit() {
+        let (_tmp, db) = test_db();
+        db.set_inline_threshold(8).unwrap();
+        db.put("a", b"1".to_vec(), None).unwrap();
+        let c2 = db.put("big", vec![1u8; 100], None).unwrap();
+        let c3 = db.put("a", b"2".to_vec(), None).unwrap();
+        db.put("other", b"x".to_vec(), None).unwrap();
+
+        let a = db.stat_key("a").unwrap();
+        assert_eq!(a.size, 1);
+        assert!(a.inline);
+        assert_eq!(a.last_commit.id, c3.id);
+
+        let big = db.stat_key("big").unwrap();
+        assert_eq!(big.size, 100);
+        assert!(!big.inline);
+        assert_eq!(big.block_hash, Some(Block::new(vec![1u8; 100]).hash));
+        assert_eq!(big.last_commit.id, c2.id);
+
+        assert!(db.stat_key("missing").is_err());
+        let all = db.stat_prefix("").unwrap();
+        assert_eq!(all.len(), 3);
     }
 
-    fn refs_path(&self) -> PathBuf {
-        self.root.join(REFS_DIR).join("refs.json")
+    #[test]
+    fn get_ref_uses_mmap_for_raw_blocks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut config = DbConfig::default();
+        config.compression.codec = compression::Codec::None;
+        config.inline_threshold = 64;
+        config.mmap_reads = true;
+        let db = Database::init_with_config(tmp.path(), config).unwrap();
+
+        let big = vec![9u8; 4096];
+        db.put("big", big.clone(), None).unwrap();
+        db.put("small", b"s".to_vec(), None).unwrap();
+
+        let value = db.get_ref("big").unwrap();
+        assert!(value.is_mapped());
+        assert_eq!(&*value, &big[..]);
+        assert_eq!(&*db.get_ref("small").unwrap(), b"s");
     }
 
-    fn load_refs(&self) -> Result<Refs> {
-        let path = self.refs_path();
-        if !path.exists() {
-            return Ok(Refs {
-                branches: HashMap::new(),
-                head: "main".into(),
-            });
+    #[test]
+    fn caches_serve_repeated_reads() {
+        let (_tmp, db) = test_db();
+        db.set_inline_threshold(4).unwrap();
+        let commit = db.put("big", b"0123456789".to_vec(), None).unwrap();
+        for _ in 0..3 {
+            assert_eq!(db.get_at("big", &commit.id).unwrap(), b"0123456789");
         }
-        let data = fs::read(path)?;
-        Ok(serde_json::from_slice(&data)?)
+        let stats = db.stats().unwrap();
+        assert!(stats.tree_cache.hits >= 3);
+        assert!(stats.block_cache.hits >= 2);
+        // Hits share the cached tree rather than copying it.
+        let tree = db.tree_at(&commit.id).unwrap();
+        assert!(Arc::ptr_eq(&tree, &db.tree_at(&commit.id).unwrap()));
+
+        db.set_cache_config(CacheConfig {
+            block_bytes: 0,
+            tree_bytes: 0,
+        })
+        .unwrap();
+        let before = db.stats().unwrap().tree_cache.misses;
+        db.get_at("big", &commit.id).unwrap();
+        assert!(db.stats().unwrap().tree_cache.misses > before);
     }
 
-    fn save_refs(&self, refs: &Refs) -> Result<()> {
-        let data = serde_json::to_vec_pretty(refs)?;
-        fs::write(self.refs_path(), data)?;
-        Ok(())
-    }
+    #[test]
+    fn head_cache_follows_writes_and_checkout() {
+        let (_tmp, db) = test_db();
+        db.put("k", b"main".to_vec(), None).unwrap();
+        db.create_branch("dev").unwrap();
+        db.checkout("dev").unwrap();
+        db.put("k", b"dev".to_vec(), None).unwrap();
+        assert_eq!(db.get("k").unwrap(), b"dev");
 
-    fn save_tag(&self, tag: &Tag) -> Result<()> {
-        let path = self.root.join(TAGS_DIR).join(&tag.id);
-        let data = serde_json::to_vec_pretty(tag)?;
-        fs::write(path, data)?;
-        Ok(())
+        db.checkout("main").unwrap();
+        assert_eq!(db.get("k").unwrap(), b"main");
+        let head = db.head_commit().unwrap();
+        assert!(Arc::ptr_eq(
+            &db.current_tree().unwrap(),
+            &db.current_tree().unwrap()
+        ));
+
+        db.merge("dev", None).unwrap();
+        assert_eq!(db.get("k").unwrap(), b"dev");
+        assert_ne!(db.head_commit().unwrap().id, head.id);
     }
 
-    fn load_tag_by_name(&self, name: &str) -> Result<Option<Tag>> {
-        let dir = self.root.join(TAGS_DIR);
-        if !dir.exists() {
-            return Ok(None);
-        }
-        for entry in fs::read_dir(&dir)? {
-            let entry = entry?;
-            let data = fs::read(entry.path())?;
-            let tag: Tag = serde_json::from_slice(&data)?;
-            if tag.name == name {
-                return Ok(Some(tag));
-            }
-        }
-        Ok(None)
+    #[test]
+    fn handles_on_one_directory_see_each_others_refs() {
+        let (tmp, a) = test_db();
+        a.put("k", b"1".to_vec(), None).unwrap();
+        let b = Database::open(tmp.path()).unwrap();
+        assert_eq!(b.get("k").unwrap(), b"1");
+        a.put("k", b"2".to_vec(), None).unwrap();
+        assert_eq!(b.get("k").unwrap(), b"2");
+
+        // Each builds on the other's commits instead of dropping them.
+        b.put("k", b"3".to_vec(), None).unwrap();
+        a.create_branch("dev").unwrap();
+        assert_eq!(b.branches().unwrap(), vec!["dev", "main"]);
+        assert_eq!(a.get("k").unwrap(), b"3");
+        assert_eq!(a.log().unwrap().len(), 3);
     }
-}
 
-/// Database statistics.
-#[derive(Debug, Clone)]
-pub struct DbStats {
-    pub key_count: usize,
-    pub commit_count: usize,
-    pub branch_count: usize,
-    pub block_count: usize,
-    pub disk_usage: u64,
-    pub bloom_items: usize,
-    pub bloom_bits: usize,
-    pub bloom_fp_rate: f64,
-    pub index_count: usize,
-    pub wal_size: u64,
-}
-
-impl std::fmt::Display for DbStats {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "Keys:       {}", self.key_count)?;
-        writeln!(f, "Commits:    {}", self.commit_count)?;
-        writeln!(f, "Branches:   {}", self.branch_count)?;
-        writeln!(f, "Blocks:     {}", self.block_count)?;
-        writeln!(f, "Disk:       {} bytes", self.disk_usage)?;
-        writeln!(
-            f,
-            "Bloom:      {} items, {} bits, {:.4}% FP",
-            self.bloom_items,
-            self.bloom_bits,
-            self.bloom_fp_rate * 100.0
-        )?;
-        writeln!(f, "Indexes:    {}", self.index_count)?;
-        writeln!(f, "WAL size:   {} bytes", self.wal_size)?;
-        Ok(())
+    #[test]
+    fn head_cache_sees_compacted_parent() {
+        let (_tmp, db) = test_db();
+        db.put("a", b"1".to_vec(), None).unwrap();
+        db.put("a", b"2".to_vec(), None).unwrap();
+        db.compact(&CompactionPolicy {
+            max_versions: 1,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(db.head_commit().unwrap().parent, None);
+        assert_eq!(db.log().unwrap().len(), 1);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn bloom_and_indexes_are_flushed_lazily() {
+        let (tmp, db) = test_db();
+        db.create_index("by_city", "city").unwrap();
+        db.put("u1", br#"{"city":"Bern"}"#.to_vec(), None).unwrap();
+        let on_disk = || {
+            fs::read_dir(tmp.path().join(crate::index::INDEX_DIR))
+                .unwrap()
+                .map(|f| fs::read_to_string(f.unwrap().path()).unwrap())
+                .collect::<String>()
+        };
+        assert!(!on_disk().contains("u1"));
+        assert!(db.wal.lock().unwrap().size() > 0);
 
-    fn test_db() -> (tempfile::TempDir, Database) {
-        let tmp = tempfile::tempdir().unwrap();
-        let db = Database::init(tmp.path()).unwrap();
-        (tmp, db)
+        db.flush().unwrap();
+        assert!(on_disk().contains("u1"));
+        assert_eq!(db.wal.lock().unwrap().size(), 0);
+
+        let cfg = DbConfig {
+            flush_interval: 2,
+            ..Default::default()
+        };
+        db.update_config(|c| *c = cfg).unwrap();
+        db.put("u2", br#"{"city":"Bern"}"#.to_vec(), None).unwrap();
+        assert!(!on_disk().contains("u2"));
+        db.put("u3", br#"{"city":"Bern"}"#.to_vec(), None).unwrap();
+        assert!(on_disk().contains("u3"));
     }
 
     #[test]
-    fn put_and_get() {
-        let (_tmp, db) = test_db();
-        db.put("name", b"iceberg".to_vec(), None).unwrap();
-        assert_eq!(db.get("name").unwrap(), b"iceberg");
+    fn unflushed_state_is_rebuilt_from_wal() {
+        let tmp = tempfile::tempdir().unwrap();
+        {
+            let db = Database::init(tmp.path()).unwrap();
+            db.create_index("by_city", "city").unwrap();
+            db.put("a", br#"{"city":"Oslo"}"#.to_vec(), None).unwrap();
+            db.close().unwrap();
+        }
+        {
+            let db = Database::open(tmp.path()).unwrap();
+            db.put("b", br#"{"city":"Oslo"}"#.to_vec(), None).unwrap();
+            db.delete("a", None).unwrap();
+            // Simulate a crash: skip the flush in `Drop`.
+            std::mem::forget(db);
+        }
+        let db = Database::open(tmp.path()).unwrap();
+        assert_eq!(db.get("b").unwrap(), br#"{"city":"Oslo"}"#);
+        assert_eq!(db.query_index("by_city", "Oslo").unwrap(), vec!["b"]);
     }
 
     #[test]
-    fn key_not_found() {
+    fn wal_checkpoints_at_thresholds() {
         let (_tmp, db) = test_db();
-        assert!(db.get("missing").is_err());
+        db.update_config(|c| {
+            c.flush_interval = 0;
+            c.wal = WalConfig {
+                checkpoint_bytes: 0,
+                checkpoint_entries: 6,
+            };
+        })
+        .unwrap();
+        db.put("a", b"1".to_vec(), None).unwrap();
+        assert_eq!(db.wal.lock().unwrap().entry_count(), 3);
+        db.put("b", b"2".to_vec(), None).unwrap();
+        assert_eq!(db.wal.lock().unwrap().entry_count(), 0);
+
+        db.update_config(|c| {
+            c.wal = WalConfig {
+                checkpoint_bytes: 1024,
+                checkpoint_entries: 0,
+            };
+        })
+        .unwrap();
+        db.put("small", b"x".to_vec(), None).unwrap();
+        assert!(db.wal.lock().unwrap().size() > 0);
+        db.put("large", vec![7; 2048], None).unwrap();
+        assert_eq!(db.wal.lock().unwrap().size(), 0);
     }
 
     #[test]
-    fn delete_key() {
+    fn maintain_flushes_and_compacts() {
         let (_tmp, db) = test_db();
-        db.put("x", b"1".to_vec(), None).unwrap();
-        db.delete("x", None).unwrap();
-        assert!(db.get("x").is_err());
+        for i in 0..3 {
+            db.put("k", vec![i], None).unwrap();
+        }
+        let policy = CompactionPolicy {
+            max_versions: 1,
+            ..Default::default()
+        };
+        let report = db.maintain(Some(&policy)).unwrap();
+        assert!(report.flushed);
+        assert_eq!(report.compaction.unwrap().commits_removed, 2);
+        assert_eq!(db.wal.lock().unwrap().size(), 0);
+
+        let report = db.maintain(None).unwrap();
+        assert_eq!(report, MaintenanceReport::default());
     }
 
     #[test]
-    fn version_history() {
+    fn auto_compaction_runs_inline_after_commit_threshold() {
         let (_tmp, db) = test_db();
-        db.put("a", b"1".to_vec(), Some("first")).unwrap();
-        db.put("b", b"2".to_vec(), Some("second")).unwrap();
-
-        let log = db.log().unwrap();
-        assert_eq!(log.len(), 2);
-        assert_eq!(log[0].message, "second");
-        assert_eq!(log[1].message, "first");
+        db.set_auto_compaction(AutoCompactionConfig {
+            policy: Some(CompactionPolicy {
+                max_versions: 2,
+                ..Default::default()
+            }),
+            commits: 5,
+            disk_bytes: 0,
+            inline: true,
+        })
+        .unwrap();
+        for i in 0..4 {
+            db.put("k", vec![i], None).unwrap();
+        }
+        assert_eq!(db.log().unwrap().len(), 4);
+        db.put("k", vec![4], None).unwrap();
+        assert_eq!(db.log().unwrap().len(), 2);
+        assert_eq!(db.get("k").unwrap(), vec![4]);
     }
 
     #[test]
-    fn time_travel() {
+    fn auto_compaction_runs_on_maintenance_pass() {
         let (_tmp, db) = test_db();
-        let c1 = db.put("val", b"old".to_vec(), Some("v1")).unwrap();
-        db.put("val", b"new".to_vec(), Some("v2")).unwrap();
+        db.put("a", vec![1u8; 10_000], None).unwrap();
+        let one_block = db.store.disk_usage().unwrap();
+        db.set_auto_compaction(AutoCompactionConfig {
+            policy: Some(CompactionPolicy {
+                max_versions: 1,
+                ..Default::default()
+            }),
+            commits: 0,
+            disk_bytes: one_block + one_block / 2,
+            inline: false,
+        })
+        .unwrap();
+        assert_eq!(db.maintain(None).unwrap().compaction, None);
 
-        // Current version
-        assert_eq!(db.get("val").unwrap(), b"new");
-        // Old version
-        assert_eq!(db.get_at("val", &c1.id).unwrap(), b"old");
+        db.put("a", vec![2u8; 10_000], None).unwrap();
+        assert_eq!(db.log().unwrap().len(), 2);
+        let report = db.maintain(None).unwrap();
+        let result = report.compaction.unwrap();
+        assert_eq!(result.commits_removed, 1);
+        assert_eq!(result.blocks_removed, 1);
+        assert_eq!(db.maintain(None).unwrap().compaction, None);
     }
 
     #[test]
-    fn branching() {
-        let (_tmp, db) = test_db();
-        db.put("shared", b"data".to_vec(), None).unwrap();
+    fn background_maintenance_flushes_writes() {
+        let tmp = tempfile::tempdir().unwrap();
+        Database::init(tmp.path()).unwrap();
+        let db = Database::open_with_maintenance(
+            tmp.path(),
+            MaintenanceOptions {
+                interval: std::time::Duration::from_millis(10),
+                compaction: None,
+            },
+        )
+        .unwrap();
+        db.put("k", b"v".to_vec(), None).unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        let status = loop {
+            let status = db.maintenance_status().unwrap();
+            if status.runs > 0 && db.wal.lock().unwrap().size() == 0 {
+                break status;
+            }
+            assert!(
+                std::time::Instant::now() < deadline,
+                "WAL never checkpointed"
+            );
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        };
+        assert_eq!(status.failures, 0);
+        drop(db);
+        assert_eq!(Database::open(tmp.path()).unwrap().get("k").unwrap(), b"v");
+    }
 
-        db.create_branch("feature").unwrap();
-        db.checkout("feature").unwrap();
-        db.put("feature_key", b"feature_val".to_vec(), None)
-            .unwrap();
+    /// Where `crash_during_put` stops, following the steps of `put`.
+    #[derive(Clone, Copy, PartialEq)]
+    enum CrashAfter {
+        WalWrite,
+        Prepare,
+        WalCommit,
+    }
 
-        db.checkout("main").unwrap();
-        assert!(db.get("feature_key").is_err()); // not on main
-        assert_eq!(db.get("shared").unwrap(), b"data"); // shared data still there
+    /// Run `put`'s steps up to `point`, then abandon the database without
+    /// flushing, as if the process had been killed there. Returns the id of
+    /// the prepared commit, if one was written.
+    fn crash_during_put(
+        db: Database,
+        key: &str,
+        value: &[u8],
+        point: CrashAfter,
+    ) -> Option<String> {
+        let tx = {
+            let mut wal = db.wal.lock().unwrap();
+            let tx = wal.begin().unwrap();
+            wal.log_write(tx, key.into(), value.to_vec()).unwrap();
+            tx
+        };
+        let mut prepared = None;
+        if point != CrashAfter::WalWrite {
+            let value = db.store_value(value.to_vec()).unwrap();
+            let tree = db.current_tree().unwrap().insert(key.into(), value);
+            let (commit, _) = db.prepare_commit("main", &tree, "crashed put").unwrap();
+            if point == CrashAfter::WalCommit {
+                db.wal
+                    .lock()
+                    .unwrap()
+                    .commit(tx, commit.id.clone())
+                    .unwrap();
+            }
+            prepared = Some(commit.id);
+        }
+        std::mem::forget(db);
+        prepared
     }
 
     #[test]
-    fn merge_branches() {
-        let (_tmp, db) = test_db();
-        db.put("base", b"val".to_vec(), None).unwrap();
-
-        db.create_branch("feat").unwrap();
-        db.checkout("feat").unwrap();
-        db.put("new_key", b"new_val".to_vec(), None).unwrap();
-
-        db.checkout("main").unwrap();
-        db.merge("feat", None).unwrap();
-        assert_eq!(db.get("new_key").unwrap(), b"new_val");
-        assert_eq!(db.get("base").unwrap(), b"val");
+    fn crash_before_wal_commit_loses_only_that_write() {
+        for point in [CrashAfter::WalWrite, CrashAfter::Prepare] {
+            let tmp = tempfile::tempdir().unwrap();
+            let db = Database::init(tmp.path()).unwrap();
+            db.put("a", b"1".to_vec(), None).unwrap();
+            crash_during_put(db, "b", b"2", point);
+
+            let db = Database::open(tmp.path()).unwrap();
+            assert_eq!(db.get("a").unwrap(), b"1");
+            assert!(matches!(db.get("b"), Err(IcebergError::KeyNotFound(_))));
+            assert_eq!(db.log().unwrap().len(), 1);
+        }
     }
 
     #[test]
-    fn diff_versions() {
-        let (_tmp, db) = test_db();
-        let c1 = db.put("a", b"1".to_vec(), None).unwrap();
-        let c2 = db.put("b", b"2".to_vec(), None).unwrap();
+    fn crash_after_wal_commit_is_replayed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::init(tmp.path()).unwrap();
+        db.put("a", b"1".to_vec(), None).unwrap();
+        let prepared = crash_during_put(db, "b", b"2", CrashAfter::WalCommit).unwrap();
 
-        let diff = db.diff(&c1.id, &c2.id).unwrap();
-        assert_eq!(diff.added, vec!["b"]);
-        assert!(diff.removed.is_empty());
+        let db = Database::open(tmp.path()).unwrap();
+        assert_eq!(db.get("b").unwrap(), b"2");
+        assert_eq!(db.head_commit().unwrap().id, prepared);
+        assert_eq!(db.log().unwrap().len(), 2);
+        assert_eq!(db.wal.lock().unwrap().entry_count(), 0);
     }
 
     #[test]
-    fn scan_prefix_works() {
-        let (_tmp, db) = test_db();
-        db.put("user:1", b"alice".to_vec(), None).unwrap();
-        db.put("user:2", b"bob".to_vec(), None).unwrap();
-        db.put("order:1", b"o1".to_vec(), None).unwrap();
+    fn replay_reapplies_operations_without_prepared_commit() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::init(tmp.path()).unwrap();
+        db.put("a", b"1".to_vec(), None).unwrap();
+        let prepared = crash_during_put(db, "b", b"2", CrashAfter::WalCommit).unwrap();
+        fs::remove_file(tmp.path().join(COMMITS_DIR).join(&prepared)).unwrap();
 
-        let users = db.scan_prefix("user:").unwrap();
-        assert_eq!(users.len(), 2);
+        let db = Database::open(tmp.path()).unwrap();
+        assert_eq!(db.get("a").unwrap(), b"1");
+        assert_eq!(db.get("b").unwrap(), b"2");
+        let log = db.log().unwrap();
+        assert_eq!(log.len(), 2);
+        assert!(log[0].message.starts_with("replay WAL transaction"));
     }
 
     #[test]
-    fn stats_work() {
-        let (_tmp, db) = test_db();
-        db.put("k", b"v".to_vec(), None).unwrap();
-        let stats = db.stats().unwrap();
-        assert_eq!(stats.key_count, 1);
-        assert_eq!(stats.commit_count, 1);
+    fn replay_skips_transactions_already_applied() {
+        let tmp = tempfile::tempdir().unwrap();
+        {
+            let db = Database::init(tmp.path()).unwrap();
+            db.put("a", b"1".to_vec(), None).unwrap();
+            db.put("a", b"2".to_vec(), None).unwrap();
+            std::mem::forget(db);
+        }
+        let db = Database::open(tmp.path()).unwrap();
+        assert_eq!(db.get("a").unwrap(), b"2");
+        assert_eq!(db.log().unwrap().len(), 2);
     }
 
     #[test]
-    fn create_and_list_tags() {
+    fn database_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Database>();
+    }
+
+    #[test]
+    fn concurrent_writers_never_lose_updates() {
         let (_tmp, db) = test_db();
-        let c = db.put("k", b"v".to_vec(), None).unwrap();
-        let tag = db
-            .create_tag("v1.0", Some(&c.id), Some("first release"))
-            .unwrap();
-        assert_eq!(tag.name, "v1.0");
-        assert_eq!(tag.commit_id, c.id);
+        db.put("seed", b"0".to_vec(), None).unwrap();
+        let db = Arc::new(db);
+        let writers: Vec<_> = (0..8)
+            .map(|t| {
+                let db = Arc::clone(&db);
+                std::thread::spawn(move || {
+                    for i in 0..25 {
+                        let key = format!("t{}/k{}", t, i);
+                        db.put(&key, key.clone().into_bytes(), None).unwrap();
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let db = Arc::clone(&db);
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        assert_eq!(db.get("seed").unwrap(), b"0");
+                        let n = db.scan_prefix("t").unwrap().len();
+                        assert!(n <= 200);
+                    }
+                })
+            })
+            .collect();
+        for handle in writers.into_iter().chain(readers) {
+            handle.join().unwrap();
+        }
 
-        let tags = db.tags().unwrap();
-        assert_eq!(tags.len(), 1);
-        assert_eq!(tags[0].name, "v1.0");
+        assert_eq!(db.scan_prefix("t").unwrap().len(), 200);
+        assert_eq!(db.log().unwrap().len(), 201);
+        for t in 0..8 {
+            assert_eq!(
+                db.get(&format!("t{}/k24", t)).unwrap(),
+                format!("t{}/k24", t).as_bytes()
+            );
+        }
     }
 
     #[test]
-    fn tag_current_head() {
+    fn maintenance_runs_safely_alongside_writes() {
         let (_tmp, db) = test_db();
-        db.put("k", b"v".to_vec(), None).unwrap();
-        let tag = db.create_tag("latest", None, None).unwrap();
-        let head = db.head_commit().unwrap();
-        assert_eq!(tag.commit_id, head.id);
+        db.put("base", b"0".to_vec(), None).unwrap();
+        let db = Arc::new(db);
+        let writer = {
+            let db = Arc::clone(&db);
+            std::thread::spawn(move || {
+                for i in 0..30 {
+                    db.put(&format!("k{}", i), vec![i], None).unwrap();
+                }
+            })
+        };
+        let maintainer = {
+            let db = Arc::clone(&db);
+            std::thread::spawn(move || {
+                for _ in 0..30 {
+                    db.maintain(None).unwrap();
+                }
+            })
+        };
+        writer.join().unwrap();
+        maintainer.join().unwrap();
+        assert_eq!(db.log().unwrap().len(), 31);
+        assert_eq!(db.scan_prefix("k").unwrap().len(), 30);
     }
 
     #[test]
-    fn duplicate_tag_fails() {
-        let (_tmp, db) = test_db();
-        db.put("k", b"v".to_vec(), None).unwrap();
-        db.create_tag("v1", None, None).unwrap();
-        assert!(db.create_tag("v1", None, None).is_err());
+    fn wal_protects_writes() {
+        let tmp = tempfile::tempdir().unwrap();
+        {
+            let db = Database::init(tmp.path()).unwrap();
+            db.put("key", b"value".to_vec(), None).unwrap();
+        }
+        // Reopen — WAL should recover cleanly
+        let db = Database::open(tmp.path()).unwrap();
+        assert_eq!(db.get("key").unwrap(), b"value");
     }
 
     #[test]
-    fn delete_tag() {
-        let (_tmp, db) = test_db();
-        db.put("k", b"v".to_vec(), None).unwrap();
-        db.create_tag("v1", None, None).unwrap();
-        db.delete_tag("v1").unwrap();
-        assert!(db.tags().unwrap().is_empty());
+    fn database_runs_on_memory_backend() {
+        let backend: Arc<dyn Backend> = Arc::new(MemoryBackend::new());
+        {
+            let db = Database::init_with_backend(Arc::clone(&backend)).unwrap();
+            db.put("k", b"v1".to_vec(), None).unwrap();
+            db.create_branch("dev").unwrap();
+            db.put("big", vec![7u8; 10_000], None).unwrap();
+            db.create_tag("v1", None, None).unwrap();
+            db.close().unwrap();
+        }
+        assert!(backend.exists(REFS_FILE).unwrap());
+        assert!(!backend.list("store/blocks").unwrap().is_empty());
+
+        let db = Database::open_with_backend(backend).unwrap();
+        assert_eq!(db.get("k").unwrap(), b"v1");
+        assert_eq!(db.get("big").unwrap(), vec![7u8; 10_000]);
+        assert_eq!(db.branches().unwrap().len(), 2);
+        assert_eq!(db.get_tag("v1").unwrap().name, "v1");
     }
 
     #[test]
-    fn cherry_pick_commit() {
+    fn checkout_of_a_tag_detaches_head() {
         let (_tmp, db) = test_db();
-        db.put("shared", b"data".to_vec(), None).unwrap();
+        let first = db.put("a", b"1".to_vec(), None).unwrap();
+        db.create_tag("v1", None, None).unwrap();
+        db.put("a", b"2".to_vec(), None).unwrap();
+        let main = db.put("b", b"3".to_vec(), None).unwrap();
+
+        db.checkout("v1").unwrap();
+        assert_eq!(db.detached_head().unwrap().as_deref(), Some("v1"));
+        assert_eq!(db.head_commit().unwrap().id, first.id);
+        assert_eq!(db.resolve_ref("HEAD").unwrap(), first.id);
+        assert_eq!(db.get("a").unwrap(), b"1");
+        assert_eq!(db.scan_keys("").unwrap(), ["a"]);
+        assert_eq!(db.log().unwrap().len(), 1);
+        assert_eq!(db.on_branch("main").unwrap().get("b").unwrap(), b"3");
+        let status = db.status().unwrap();
+        assert_eq!(status.detached.as_deref(), Some("v1"));
+        assert_eq!(status.base, Some((DEFAULT_BRANCH.to_string(), 0, 2)));
+
+        // Nothing writes through a detached HEAD, but named branches work.
+        for result in [
+            db.put("c", b"4".to_vec(), None).map(|_| ()),
+            db.delete("a", None).map(|_| ()),
+            db.write(WriteBatch::new().put("c", vec![4]), None)
+                .map(|_| ()),
+            db.cherry_pick(&main.id, None).map(|_| ()),
+            db.squash_history("v1", None).map(|_| ()),
+        ] {
+            assert!(matches!(result, Err(IcebergError::DetachedHead(_))));
+        }
+        db.on_branch("main")
+            .unwrap()
+            .put("c", b"4".to_vec(), None)
+            .unwrap();
 
-        // Create a feature branch with a new key
-        db.create_branch("feature").unwrap();
-        db.checkout("feature").unwrap();
-        let feat_commit = db.put("feat_key", b"feat_val".to_vec(), None).unwrap();
+        // HEAD stays on the commit when the tag moves, and compaction keeps it.
+        db.move_tag("v1", Some(&main.id), None).unwrap();
+        let policy = CompactionPolicy {
+            max_versions: 1,
+            ..Default::default()
+        };
+        db.compact(&policy).unwrap();
+        assert_eq!(db.head_commit().unwrap().id, first.id);
+        assert_eq!(db.get("a").unwrap(), b"1");
 
-        // Switch back to main and cherry-pick
+        db.create_branch("fix").unwrap();
+        assert_eq!(db.on_branch("fix").unwrap().get("a").unwrap(), b"1");
         db.checkout("main").unwrap();
-        assert!(db.get("feat_key").is_err());
-
-        db.cherry_pick(&feat_commit.id, Some("picked feature"))
-            .unwrap();
-        assert_eq!(db.get("feat_key").unwrap(), b"feat_val");
-        assert_eq!(db.get("shared").unwrap(), b"data");
+        assert_eq!(db.detached_head().unwrap(), None);
+        assert_eq!(db.get("a").unwrap(), b"2");
+        let at = db.scan_prefix_at("", &first.id, &ScanOptions::default());
+        assert_eq!(at.unwrap(), [("a".to_string(), b"1".to_vec())]);
+        db.put("d", b"5".to_vec(), None).unwrap();
+        assert!(matches!(
+            db.checkout("nope"),
+            Err(IcebergError::BranchNotFound(_))
+        ));
     }
 
     #[test]
-    fn cherry_pick_delete() {
+    fn is_ancestor_follows_parents() {
         let (_tmp, db) = test_db();
-        db.put("a", b"1".to_vec(), None).unwrap();
-        db.put("b", b"2".to_vec(), None).unwrap();
-
-        db.create_branch("cleanup").unwrap();
-        db.checkout("cleanup").unwrap();
-        let del_commit = db.delete("a", None).unwrap();
+        let first = db.put("k", b"1".to_vec(), None).unwrap();
+        db.create_branch("dev").unwrap();
+        let second = db.put("k", b"2".to_vec(), None).unwrap();
+        db.checkout("dev").unwrap();
+        let side = db.put("k", b"3".to_vec(), None).unwrap();
+
+        assert!(db.is_ancestor(&first.id, &second.id).unwrap());
+        assert!(db.is_ancestor(&first.id, &side.id).unwrap());
+        assert!(db.is_ancestor(&second.id, &second.id).unwrap());
+        assert!(!db.is_ancestor(&second.id, &first.id).unwrap());
+        assert!(!db.is_ancestor(&second.id, &side.id).unwrap());
+        assert!(matches!(
+            db.is_ancestor("missing", &side.id),
+            Err(IcebergError::CommitNotFound(_))
+        ));
+    }
 
-        db.checkout("main").unwrap();
-        assert_eq!(db.get("a").unwrap(), b"1"); // still there
+    #[test]
+    fn commit_graph_follows_commits_across_reopens() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::init(tmp.path()).unwrap();
+        let first = db.put("k", b"1".to_vec(), None).unwrap();
+        db.set_commit_graph(true).unwrap();
+        assert_eq!(db.commit_graph_len(), Some(1));
+        let stale = fs::read(tmp.path().join(COMMIT_GRAPH_FILE)).unwrap();
+        let second = db.put("k", b"2".to_vec(), None).unwrap();
+        let third = db.put("k", b"3".to_vec(), None).unwrap();
+        drop(db);
+
+        // A file saved before later commits is topped up on open.
+        fs::write(tmp.path().join(COMMIT_GRAPH_FILE), &stale).unwrap();
+        let db = Database::open(tmp.path()).unwrap();
+        assert_eq!(db.commit_graph_len(), Some(3));
+        assert!(db.is_ancestor(&first.id, &third.id).unwrap());
+        let page = db.log_page(1, 1).unwrap();
+        assert_eq!(page[0].id, second.id);
+
+        // Re-rooting rewrites commits in place; a crash before the next
+        // flush must not leave the old links on disk.
+        db.truncate_history(&second.id).unwrap();
+        assert!(!tmp.path().join(COMMIT_GRAPH_FILE).exists());
+        assert!(db.is_ancestor(&second.id, &third.id).unwrap());
+        assert!(matches!(
+            db.is_ancestor(&first.id, &third.id),
+            Err(IcebergError::CommitNotFound(_))
+        ));
+        db.flush().unwrap();
+        drop(db);
+        let db = Database::open(tmp.path()).unwrap();
+        assert_eq!(db.commit_graph_len(), Some(2));
+        assert!(db.get_commit(&second.id).unwrap().parent.is_none());
+        assert_eq!(db.log_page(5, 1).unwrap().len(), 1);
 
-        db.cherry_pick(&del_commit.id, None).unwrap();
-        assert!(db.get("a").is_err()); // now gone
+        db.set_commit_graph(false).unwrap();
+        assert_eq!(db.commit_graph_len(), None);
+        assert!(!tmp.path().join(COMMIT_GRAPH_FILE).exists());
     }
 
     #[test]
-    fn compact_with_max_versions() {
+    fn merge_base_with_and_without_generations() {
         let (_tmp, db) = test_db();
-        for i in 0..5 {
-            db.put("k", format!("v{}", i).into_bytes(), None).unwrap();
+        let first = db.put("k", b"1".to_vec(), None).unwrap();
+        db.create_branch("dev").unwrap();
+        let second = db.put("k", b"2".to_vec(), None).unwrap();
+        let third = db.put("k", b"3".to_vec(), None).unwrap();
+        db.checkout("dev").unwrap();
+        let side = db.put("k", b"4".to_vec(), None).unwrap();
+
+        for graph in [false, true] {
+            db.set_commit_graph(graph).unwrap();
+            assert_eq!(db.commit_generation(&third.id), graph.then_some(3));
+            let base = |a: &str, b: &str| db.merge_base(a, b).unwrap();
+            assert_eq!(base(&third.id, &side.id), Some(first.id.clone()));
+            assert_eq!(base(&side.id, &third.id), Some(first.id.clone()));
+            assert_eq!(base(&second.id, &third.id), Some(second.id.clone()));
+            assert_eq!(base(&third.id, &third.id), Some(third.id.clone()));
+            assert!(db.is_ancestor(&second.id, &third.id).unwrap());
+            assert!(!db.is_ancestor(&third.id, &second.id).unwrap());
+            assert!(!db.is_ancestor(&second.id, &side.id).unwrap());
         }
-        assert_eq!(db.log().unwrap().len(), 5);
-
-        let policy = crate::compaction::CompactionPolicy {
-            max_versions: 2,
-            max_age_days: None,
-        };
-        let result = db.compact(&policy).unwrap();
-        assert!(result.commits_removed > 0);
+        assert!(db.merge_base("missing", &side.id).is_err());
 
-        // Current value should still work
-        assert_eq!(db.get("k").unwrap(), b"v4");
+        // Re-rooting renumbers the generations above.
+        db.truncate_history(&second.id).unwrap();
+        assert_eq!(db.commit_generation(&third.id), Some(2));
+        assert_eq!(db.merge_base(&third.id, &side.id).unwrap(), None);
     }
 
     #[test]
-    fn compact_no_policy_removes_nothing() {
+    fn children_and_descendants_walk_forward() {
         let (_tmp, db) = test_db();
-        db.put("a", b"1".to_vec(), None).unwrap();
-        db.put("b", b"2".to_vec(), None).unwrap();
-
-        let policy = crate::compaction::CompactionPolicy::default();
-        let result = db.compact(&policy).unwrap();
-        assert_eq!(result.commits_removed, 0);
-        assert_eq!(db.log().unwrap().len(), 2);
+        let first = db.put("k", b"1".to_vec(), None).unwrap();
+        db.create_branch("dev").unwrap();
+        let second = db.put("k", b"2".to_vec(), None).unwrap();
+        db.checkout("dev").unwrap();
+        let side = db.put("k", b"3".to_vec(), None).unwrap();
+
+        let ids =
+            |commits: Vec<Commit>| -> Vec<String> { commits.into_iter().map(|c| c.id).collect() };
+        let mut children = ids(db.children(&first.id).unwrap());
+        children.sort();
+        let mut expected = vec![second.id.clone(), side.id.clone()];
+        expected.sort();
+        assert_eq!(children, expected);
+        assert!(db.children(&side.id).unwrap().is_empty());
+
+        // The index, once built, follows new commits and compaction.
+        let third = db.put("k", b"4".to_vec(), None).unwrap();
+        assert_eq!(
+            ids(db.children(&side.id).unwrap()),
+            std::slice::from_ref(&third.id)
+        );
+        let descendants = ids(db.descendants(&first.id).unwrap());
+        assert_eq!(descendants.len(), 3);
+        let pos = |id: &str| descendants.iter().position(|d| d == id).unwrap();
+        assert!(pos(&third.id) < pos(&side.id));
+
+        db.truncate_history(&side.id).unwrap();
+        assert!(db.children(&first.id).is_err());
+        assert_eq!(ids(db.descendants(&side.id).unwrap()), [third.id]);
+        assert!(db.descendants(&second.id).unwrap().is_empty());
     }
 
     #[test]
-    fn delete_branch() {
+    fn resolve_ref_accepts_branches_tags_and_commits() {
         let (_tmp, db) = test_db();
-        db.put("x", b"1".to_vec(), None).unwrap();
-        db.create_branch("temp").unwrap();
-        db.delete_branch("temp").unwrap();
-        assert!(!db.branches().unwrap().contains(&"temp".to_string()));
+        let first = db.put("k", b"1".to_vec(), None).unwrap();
+        db.create_tag("v1", None, None).unwrap();
+        db.create_branch("dev").unwrap();
+        let second = db.put("k", b"2".to_vec(), None).unwrap();
+
+        assert_eq!(db.resolve_ref("HEAD").unwrap(), second.id);
+        assert_eq!(db.resolve_ref("main").unwrap(), second.id);
+        assert_eq!(db.resolve_ref("dev").unwrap(), first.id);
+        assert_eq!(db.resolve_ref("v1").unwrap(), first.id);
+        assert_eq!(db.resolve_ref(&first.id).unwrap(), first.id);
+        assert_eq!(db.resolve_ref(&second.id[..8]).unwrap(), second.id);
+        assert!(db.resolve_ref(&second.id[..MIN_ID_PREFIX - 1]).is_err());
+        assert!(matches!(
+            db.resolve_ref("nope"),
+            Err(IcebergError::CommitNotFound(_))
+        ));
     }
 
     #[test]
-    fn bloom_filter_fast_negative() {
+    fn fsck_reports_healthy_database() {
         let (_tmp, db) = test_db();
-        db.put("exists", b"val".to_vec(), None).unwrap();
-        // Bloom should say "maybe" for existing key
-        assert!(db.get("exists").is_ok());
-        // Non-existing key: bloom may short-circuit, but result is the same
-        assert!(db.get("nope").is_err());
+        db.create_index("by_city", "city").unwrap();
+        db.put("a", br#"{"city":"Oslo"}"#.to_vec(), None).unwrap();
+        db.put("big", vec![1u8; 10_000], None).unwrap();
+        db.create_branch("dev").unwrap();
+        db.create_tag("v1", None, None).unwrap();
+        let report = db.fsck().unwrap();
+        assert!(report.is_healthy(), "{}", report);
+        assert_eq!(report.commits_checked, 2);
+        assert_eq!(report.blocks_checked, 1);
+        assert!(report.unreachable_commits.is_empty());
     }
 
     #[test]
-    fn rebuild_bloom() {
-        let (_tmp, db) = test_db();
-        db.put("a", b"1".to_vec(), None).unwrap();
-        db.put("b", b"2".to_vec(), None).unwrap();
-        db.rebuild_bloom().unwrap();
-        let (items, _, _) = db.bloom_stats();
-        assert_eq!(items, 2);
+    fn fsck_detects_missing_and_corrupt_objects() {
+        let (tmp, db) = test_db();
+        let first = db.put("k", b"1".to_vec(), None).unwrap();
+        let second = db.put("big", vec![2u8; 10_000], None).unwrap();
+        let last = db.put("k", b"3".to_vec(), None).unwrap();
+        drop(db);
+
+        let block = db_block_files(tmp.path()).pop().unwrap();
+        fs::write(&block, b"garbage").unwrap();
+        fs::remove_file(tmp.path().join(COMMITS_DIR).join(&first.id)).unwrap();
+        fs::write(tmp.path().join(BLOOM_FILE), b"{").unwrap();
+
+        let db = Database::open(tmp.path()).unwrap();
+        let issues = db.fsck().unwrap().issues;
+        assert!(issues.contains(&FsckIssue::MissingCommit {
+            commit: first.id.clone(),
+            referenced_by: second.id,
+        }));
+        assert!(issues
+            .iter()
+            .any(|i| matches!(i, FsckIssue::CorruptBlock { .. })));
+        assert!(issues
+            .iter()
+            .any(|i| matches!(i, FsckIssue::CorruptFile { file, .. } if file == BLOOM_FILE)));
+
+        let tree = db.get_commit(&last.id).unwrap().tree_root;
+        fs::remove_file(tmp.path().join(TREES_DIR).join(&tree)).unwrap();
+        assert!(db.fsck().unwrap().issues.contains(&FsckIssue::MissingTree {
+            tree,
+            commit: last.id,
+        }));
     }
 
     #[test]
-    fn rebase_branch() {
+    fn fsck_lists_unreachable_commits_and_dangling_index_entries() {
         let (_tmp, db) = test_db();
-        // Setup: main has "base"
-        db.put("base", b"val".to_vec(), Some("base commit"))
-            .unwrap();
-
-        // Create feature branch with a new key
-        db.create_branch("feature").unwrap();
-        db.checkout("feature").unwrap();
-        db.put("feat", b"f1".to_vec(), Some("feat commit")).unwrap();
-
-        // Add more to main
+        db.create_index("by_city", "city").unwrap();
+        db.put("a", br#"{"city":"Oslo"}"#.to_vec(), None).unwrap();
+        db.create_branch("dev").unwrap();
+        db.checkout("dev").unwrap();
+        let dropped = db.put("b", br#"{"city":"Rome"}"#.to_vec(), None).unwrap();
         db.checkout("main").unwrap();
-        db.put("main_extra", b"m1".to_vec(), Some("main extra"))
-            .unwrap();
-
-        // Rebase feature onto main
-        db.checkout("feature").unwrap();
-        let rebased = db.rebase("main").unwrap();
-        assert_eq!(rebased.len(), 1);
-
-        // Feature branch should now have all keys
-        assert_eq!(db.get("base").unwrap(), b"val");
-        assert_eq!(db.get("feat").unwrap(), b"f1");
-        assert_eq!(db.get("main_extra").unwrap(), b"m1");
+        db.delete_branch("dev").unwrap();
+
+        let report = db.fsck().unwrap();
+        assert_eq!(report.unreachable_commits, vec![dropped.id]);
+        assert_eq!(
+            report.issues,
+            vec![FsckIssue::DanglingIndexEntry {
+                index: "by_city".into(),
+                key: "b".into(),
+            }]
+        );
     }
 
     #[test]
-    fn rebase_onto_self_fails() {
-        let (_tmp, db) = test_db();
-        db.put("k", b"v".to_vec(), None).unwrap();
-        assert!(db.rebase("main").is_err());
+    fn repair_quarantines_corrupt_blocks_and_truncates_the_branch() {
+        let (tmp, db) = test_db();
+        let first = db.put("k", b"1".to_vec(), None).unwrap();
+        let damaged = db.put("big", vec![2u8; 10_000], None).unwrap();
+        let head = db.put("k", b"3".to_vec(), None).unwrap();
+        drop(db);
+        let block = db_block_files(tmp.path()).pop().unwrap();
+        fs::write(&block, b"garbage").unwrap();
+
+        let db = Database::open(tmp.path()).unwrap();
+        let report = db.repair().unwrap();
+        assert!(!report.before.is_healthy());
+        assert!(report.after.is_healthy(), "{}", report);
+        assert!(report.actions.contains(&RepairAction::TruncatedBranch {
+            branch: "main".into(),
+            from: head.id,
+            to: first.id.clone(),
+        }));
+        let rel = block.strip_prefix(tmp.path()).unwrap();
+        assert_eq!(
+            fs::read(tmp.path().join(CORRUPT_DIR).join(rel)).unwrap(),
+            b"garbage"
+        );
+        assert!(!block.exists());
+        assert_eq!(db.log().unwrap()[0].id, first.id);
+        assert_eq!(db.get("k").unwrap(), b"1");
+        assert!(db.get("big").is_err());
+        assert_eq!(db.fsck().unwrap().unreachable_commits.len(), 2);
+        assert!(db.fsck().unwrap().unreachable_commits.contains(&damaged.id));
+        let reports: Vec<_> = fs::read_dir(tmp.path().join(CORRUPT_DIR))
+            .unwrap()
+            .filter_map(|e| e.unwrap().file_name().into_string().ok())
+            .filter(|name| name.starts_with("repair-"))
+            .collect();
+        assert_eq!(reports.len(), 1);
     }
 
     #[test]
-    fn secondary_index_lifecycle() {
-        let (_tmp, db) = test_db();
-        // Create index
-        db.create_index("city", "city").unwrap();
-        assert_eq!(db.list_indexes(), vec!["city"]);
+    fn repair_regenerates_missing_trees() {
+        let (tmp, db) = test_db();
+        db.put("a", b"1".to_vec(), None).unwrap();
+        let middle = db.put("b", b"2".to_vec(), Some("custom")).unwrap();
+        db.put("c", b"3".to_vec(), None).unwrap();
+        drop(db);
+        fs::remove_file(tmp.path().join(TREES_DIR).join(&middle.tree_root)).unwrap();
 
-        // Put JSON values
-        let zurich =
-            serde_json::to_vec(&serde_json::json!({"city": "Zurich", "pop": 400000})).unwrap();
-        let berlin =
-            serde_json::to_vec(&serde_json::json!({"city": "Berlin", "pop": 3600000})).unwrap();
-        db.put("ch:zurich", zurich, None).unwrap();
-        db.put("de:berlin", berlin, None).unwrap();
+        let db = Database::open(tmp.path()).unwrap();
+        let report = db.repair().unwrap();
+        assert!(report.after.is_healthy(), "{}", report);
+        assert!(report.actions.contains(&RepairAction::RegeneratedTree {
+            tree: middle.tree_root.clone(),
+            commit: middle.id.clone(),
+        }));
+        assert_eq!(db.get_at("b", &middle.id).unwrap(), b"2");
+        assert!(db.get_at("c", &middle.id).is_err());
+        assert_eq!(db.log().unwrap().len(), 3);
+    }
 
-        // Query index
-        assert_eq!(db.query_index("city", "Zurich").unwrap(), vec!["ch:zurich"]);
-        assert_eq!(db.query_index("city", "Berlin").unwrap(), vec!["de:berlin"]);
-        assert!(db.query_index("city", "Paris").unwrap().is_empty());
+    #[test]
+    fn repair_cuts_history_below_a_missing_commit_and_drops_its_tags() {
+        let (tmp, db) = test_db();
+        let first = db.put("a", b"1".to_vec(), None).unwrap();
+        db.create_tag("old", Some(&first.id), None).unwrap();
+        let second = db.put("b", b"2".to_vec(), None).unwrap();
+        db.put("c", b"3".to_vec(), None).unwrap();
+        drop(db);
+        fs::remove_file(tmp.path().join(COMMITS_DIR).join(&first.id)).unwrap();
+        fs::write(tmp.path().join(BLOOM_FILE), b"{").unwrap();
 
-        // Delete and verify index updated
-        db.delete("ch:zurich", None).unwrap();
-        assert!(db.query_index("city", "Zurich").unwrap().is_empty());
+        let db = Database::open(tmp.path()).unwrap();
+        let report = db.repair().unwrap();
+        assert!(report.after.is_healthy(), "{}", report);
+        assert!(report.actions.contains(&RepairAction::GraftedCommit {
+            commit: second.id.clone(),
+            lost_parent: first.id.clone(),
+        }));
+        assert!(report.actions.contains(&RepairAction::RemovedTag {
+            name: "old".into(),
+            commit: first.id,
+        }));
+        assert!(report.actions.contains(&RepairAction::Quarantined {
+            key: BLOOM_FILE.into(),
+            reason: report
+                .before
+                .issues
+                .iter()
+                .find_map(|i| match i {
+                    FsckIssue::CorruptFile { reason, .. } => Some(reason.clone()),
+                    _ => None,
+                })
+                .unwrap(),
+        }));
+        assert_eq!(db.log().unwrap().len(), 2);
+        assert!(db.tags().unwrap().is_empty());
+        assert_eq!(db.get("a").unwrap(), b"1");
+    }
 
-        // Drop index
-        db.drop_index("city").unwrap();
-        assert!(db.list_indexes().is_empty());
+    #[test]
+    fn repair_leaves_a_healthy_database_alone() {
+        let (tmp, db) = test_db();
+        db.put("a", b"1".to_vec(), None).unwrap();
+        let report = db.repair().unwrap();
+        assert!(report.actions.is_empty());
+        assert!(!tmp.path().join(CORRUPT_DIR).exists());
     }
 
     #[test]
-    fn secondary_index_prefix_query() {
+    fn lost_found_lists_tips_of_deleted_branches() {
         let (_tmp, db) = test_db();
-        db.create_index("city", "city").unwrap();
-
-        let z1 = serde_json::to_vec(&serde_json::json!({"city": "Zurich"})).unwrap();
-        let z2 = serde_json::to_vec(&serde_json::json!({"city": "Zug"})).unwrap();
-        let b1 = serde_json::to_vec(&serde_json::json!({"city": "Berlin"})).unwrap();
-        db.put("c:1", z1, None).unwrap();
-        db.put("c:2", z2, None).unwrap();
-        db.put("c:3", b1, None).unwrap();
-
-        let results = db.query_index_prefix("city", "Zu").unwrap();
-        assert_eq!(results.len(), 2);
+        db.put("a", b"1".to_vec(), None).unwrap();
+        db.create_branch("dev").unwrap();
+        db.checkout("dev").unwrap();
+        db.put("b", b"2".to_vec(), None).unwrap();
+        let tip = db.put("c", b"3".to_vec(), None).unwrap();
+        db.checkout("main").unwrap();
+        db.delete_branch("dev").unwrap();
+        assert!(db.get("c").is_err());
+
+        let lost = db.lost_found().unwrap();
+        assert_eq!(lost, vec![tip.clone()]);
+
+        db.create_branch_at("recovered", &tip.id).unwrap();
+        assert!(db.lost_found().unwrap().is_empty());
+        db.checkout("recovered").unwrap();
+        assert_eq!(db.get("b").unwrap(), b"2");
+        assert_eq!(db.get("c").unwrap(), b"3");
+        assert!(matches!(
+            db.create_branch_at("main", &tip.id),
+            Err(IcebergError::BranchExists(_))
+        ));
     }
 
-    #[test]
-    fn wal_protects_writes() {
-        let tmp = tempfile::tempdir().unwrap();
-        {
-            let db = Database::init(tmp.path()).unwrap();
-            db.put("key", b"value".to_vec(), None).unwrap();
+    fn db_block_files(root: &Path) -> Vec<std::path::PathBuf> {
+        let mut files = Vec::new();
+        for prefix in fs::read_dir(root.join("store/blocks")).unwrap() {
+            for entry in fs::read_dir(prefix.unwrap().path()).unwrap() {
+                files.push(entry.unwrap().path());
+            }
         }
-        // Reopen — WAL should recover cleanly
-        let db = Database::open(tmp.path()).unwrap();
-        assert_eq!(db.get("key").unwrap(), b"value");
+        files
     }
 }
@@ -23,6 +23,62 @@ pub enum IcebergError {
     #[error("Empty database — no commits yet")]
     EmptyDatabase,
 
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+
+    #[error("Unique index {index} already has {value:?} for key {key}")]
+    UniqueViolation {
+        index: String,
+        value: String,
+        key: String,
+    },
+
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+
+    #[error("Parse error on line {line}: {source}")]
+    Parse {
+        line: usize,
+        source: serde_json::Error,
+    },
+
+    #[error("Invalid tenant name: {0}")]
+    InvalidTenant(String),
+
+    #[error("Branch {branch} moved: expected head {expected}, found {actual}")]
+    Conflict {
+        branch: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("Key {key} is locked by {holder} until {expires_at}")]
+    KeyLocked {
+        key: String,
+        holder: String,
+        expires_at: chrono::DateTime<chrono::Utc>,
+    },
+
+    #[error(
+        "Quota for prefix {prefix:?} exceeded: {requested} {resource} over a limit of {limit}"
+    )]
+    QuotaExceeded {
+        prefix: String,
+        /// `keys` or `bytes`.
+        resource: &'static str,
+        limit: u64,
+        requested: u64,
+    },
+
+    #[error("Value of {key} is {size} bytes, over the limit of {limit}")]
+    ValueTooLarge { key: String, size: u64, limit: u64 },
+
+    #[error("HEAD is detached at tag {0}; check out a branch to write")]
+    DetachedHead(String),
+
+    #[error("Bad signature: {0}")]
+    BadSignature(String),
+
     #[error("Corruption: {0}")]
     Corruption(String),
 }
@@ -0,0 +1,220 @@
+use crate::block::BlockHash;
+use std::fmt;
+
+/// A problem found by `Database::fsck`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckIssue {
+    /// A branch or tag points at a commit that does not exist.
+    DanglingRef { name: String, commit: BlockHash },
+    /// A commit's parent does not exist.
+    MissingCommit {
+        commit: BlockHash,
+        referenced_by: BlockHash,
+    },
+    /// A commit cannot be parsed or does not match its id.
+    CorruptCommit { commit: BlockHash, reason: String },
+    /// A commit's tree does not exist.
+    MissingTree { tree: BlockHash, commit: BlockHash },
+    /// A tree cannot be parsed or does not match its root hash.
+    CorruptTree { tree: BlockHash, reason: String },
+    /// A block referenced by a tree does not exist.
+    MissingBlock { block: BlockHash, key: String },
+    /// A block cannot be decoded or does not match its hash.
+    CorruptBlock { block: BlockHash, reason: String },
+    /// The bloom filter or index file cannot be parsed.
+    CorruptFile { file: String, reason: String },
+    /// A live key the bloom filter claims is absent; reads of it would fail.
+    BloomMissingKey { key: String },
+    /// An index entry for a key that no branch contains.
+    DanglingIndexEntry { index: String, key: String },
+}
+
+impl fmt::Display for FsckIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsckIssue::DanglingRef { name, commit } => {
+                write!(f, "{} points at missing commit {}", name, commit)
+            }
+            FsckIssue::MissingCommit {
+                commit,
+                referenced_by,
+            } => write!(f, "missing commit {} (parent of {})", commit, referenced_by),
+            FsckIssue::CorruptCommit { commit, reason } => {
+                write!(f, "corrupt commit {}: {}", commit, reason)
+            }
+            FsckIssue::MissingTree { tree, commit } => {
+                write!(f, "missing tree {} (of commit {})", tree, commit)
+            }
+            FsckIssue::CorruptTree { tree, reason } => {
+                write!(f, "corrupt tree {}: {}", tree, reason)
+            }
+            FsckIssue::MissingBlock { block, key } => {
+                write!(f, "missing block {} (value of {:?})", block, key)
+            }
+            FsckIssue::CorruptBlock { block, reason } => {
+                write!(f, "corrupt block {}: {}", block, reason)
+            }
+            FsckIssue::CorruptFile { file, reason } => {
+                write!(f, "corrupt {}: {}", file, reason)
+            }
+            FsckIssue::BloomMissingKey { key } => {
+                write!(f, "bloom filter is missing live key {:?}", key)
+            }
+            FsckIssue::DanglingIndexEntry { index, key } => {
+                write!(f, "index {} references missing key {:?}", index, key)
+            }
+        }
+    }
+}
+
+/// Result of `Database::fsck`.
+///
+/// Unreachable objects are not problems — compaction and deleted branches
+/// leave them behind — but are listed so they can be inspected or collected.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    pub commits_checked: usize,
+    pub trees_checked: usize,
+    pub blocks_checked: usize,
+    pub issues: Vec<FsckIssue>,
+    pub unreachable_commits: Vec<BlockHash>,
+    pub unreachable_trees: Vec<BlockHash>,
+    pub unreachable_blocks: Vec<BlockHash>,
+}
+
+impl FsckReport {
+    /// Whether no problems were found.
+    pub fn is_healthy(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Checked {} commits, {} trees, {} blocks",
+            self.commits_checked, self.trees_checked, self.blocks_checked
+        )?;
+        for issue in &self.issues {
+            writeln!(f, "error: {}", issue)?;
+        }
+        let unreachable = [
+            ("commits", &self.unreachable_commits),
+            ("trees", &self.unreachable_trees),
+            ("blocks", &self.unreachable_blocks),
+        ];
+        for (what, ids) in unreachable {
+            if !ids.is_empty() {
+                writeln!(f, "Unreachable {}: {}", what, ids.len())?;
+            }
+        }
+        if self.is_healthy() {
+            writeln!(f, "No problems found")
+        } else {
+            writeln!(f, "{} problem(s) found", self.issues.len())
+        }
+    }
+}
+
+/// A change made by `Database::repair`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RepairAction {
+    /// A corrupt object was moved from `key` to `corrupt/<key>`.
+    Quarantined { key: String, reason: String },
+    /// A missing tree was rebuilt from a neighbouring commit's tree and
+    /// matched its root hash.
+    RegeneratedTree { tree: BlockHash, commit: BlockHash },
+    /// A branch was moved back to its newest fully intact commit.
+    TruncatedBranch {
+        branch: String,
+        from: BlockHash,
+        to: BlockHash,
+    },
+    /// A branch had no intact commit and now points at a new empty commit.
+    ResetBranch { branch: String, commit: BlockHash },
+    /// History below an intact commit was damaged, so the commit was made a
+    /// root, as compaction does.
+    GraftedCommit {
+        commit: BlockHash,
+        lost_parent: BlockHash,
+    },
+    /// A tag pointed at a damaged commit and was removed.
+    RemovedTag { name: String, commit: BlockHash },
+    /// HEAD was detached at a damaged commit and went back to its branch.
+    ReattachedHead { tag: String, commit: BlockHash },
+    /// The bloom filter was rebuilt from every branch head.
+    RebuiltBloom,
+    /// The secondary indexes were rebuilt from the current branch.
+    RebuiltIndexes,
+}
+
+impl fmt::Display for RepairAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepairAction::Quarantined { key, reason } => {
+                write!(f, "quarantined {} ({})", key, reason)
+            }
+            RepairAction::RegeneratedTree { tree, commit } => {
+                write!(f, "regenerated tree {} of commit {}", tree, commit)
+            }
+            RepairAction::TruncatedBranch { branch, from, to } => {
+                write!(f, "truncated branch {} from {} to {}", branch, from, to)
+            }
+            RepairAction::ResetBranch { branch, commit } => {
+                write!(f, "reset branch {} to empty commit {}", branch, commit)
+            }
+            RepairAction::GraftedCommit {
+                commit,
+                lost_parent,
+            } => write!(
+                f,
+                "cut history below {} (lost parent {})",
+                commit, lost_parent
+            ),
+            RepairAction::RemovedTag { name, commit } => {
+                write!(f, "removed tag {} (pointed at {})", name, commit)
+            }
+            RepairAction::ReattachedHead { tag, commit } => write!(
+                f,
+                "reattached HEAD (was detached at tag {}, commit {})",
+                tag, commit
+            ),
+            RepairAction::RebuiltBloom => write!(f, "rebuilt bloom filter"),
+            RepairAction::RebuiltIndexes => write!(f, "rebuilt secondary indexes"),
+        }
+    }
+}
+
+/// Result of `Database::repair`: the problems found, what was done about
+/// them, and what a fresh check finds afterwards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepairReport {
+    pub before: FsckReport,
+    pub actions: Vec<RepairAction>,
+    pub after: FsckReport,
+}
+
+impl fmt::Display for RepairReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.before.is_healthy() {
+            return writeln!(f, "No problems found; nothing to repair");
+        }
+        writeln!(f, "Found {} problem(s)", self.before.issues.len())?;
+        for issue in &self.before.issues {
+            writeln!(f, "  {}", issue)?;
+        }
+        for action in &self.actions {
+            writeln!(f, "repair: {}", action)?;
+        }
+        if self.after.is_healthy() {
+            writeln!(f, "Database is healthy")
+        } else {
+            writeln!(f, "{} problem(s) remain", self.after.issues.len())?;
+            for issue in &self.after.issues {
+                writeln!(f, "  {}", issue)?;
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! ASCII rendering of commit history across branches, for `log --graph`,
+//! its Graphviz DOT form for `graph --format dot`, and the child links that
+//! commits, which only name their parent, lack.
+//!
+//! Every commit has at most one parent, so history is a forest: branches
+//! fork from a shared commit but never join again. Each branch gets a lane,
+//! drawn as `|`; a lane folds back (`/`) towards the commit it forked from,
+//! one column to its left.
+
+use crate::commit::Commit;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
+
+/// Render `commits` newest first, children always above their parent, one
+/// line per commit plus a line wherever lanes fold together. `labels` maps
+/// commit ids to decorations such as branch and tag names. Stops after
+/// `limit` commits.
+pub fn render(
+    commits: &[Commit],
+    labels: &HashMap<String, Vec<String>>,
+    limit: usize,
+) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut lanes: Vec<Option<&str>> = Vec::new();
+    // Callers usually pass `Database::log_all`, already in this order, but
+    // lanes are only right if it holds.
+    for commit in topological(commits).into_iter().take(limit) {
+        let mut columns = lanes
+            .iter()
+            .enumerate()
+            .filter(|(_, lane)| **lane == Some(commit.id.as_str()))
+            .map(|(i, _)| i);
+        let column = match columns.next() {
+            Some(column) => {
+                let folded: Vec<usize> = columns.collect();
+                if !folded.is_empty() {
+                    for &i in &folded {
+                        lanes[i] = None;
+                    }
+                    let mut line: Vec<char> = row(&lanes, |_| None).chars().collect();
+                    for &i in &folded {
+                        line.resize(line.len().max(2 * i), ' ');
+                        line[2 * i - 1] = '/';
+                    }
+                    lines.push(line.into_iter().collect::<String>().trim_end().to_string());
+                }
+                column
+            }
+            None => match lanes.iter().position(Option::is_none) {
+                Some(free) => free,
+                None => {
+                    lanes.push(None);
+                    lanes.len() - 1
+                }
+            },
+        };
+        lanes[column] = Some(commit.id.as_str());
+        let mut line = row(&lanes, |i| (i == column).then_some('*'));
+        line.push(' ');
+        line.push_str(&commit.id[..8.min(commit.id.len())]);
+        if let Some(names) = labels.get(&commit.id) {
+            line.push_str(&format!(" ({})", names.join(", ")));
+        }
+        line.push(' ');
+        line.push_str(&commit.message);
+        lines.push(line);
+
+        lanes[column] = commit.parent.as_deref();
+        while lanes.last() == Some(&None) {
+            lanes.pop();
+        }
+    }
+    lines
+}
+
+/// `commits` as a Graphviz DOT digraph: a box per commit with its short
+/// id, `labels` and message, in bold where it has labels, and an edge from
+/// each commit to its parent. Takes the first `limit` commits in the order
+/// of `render`; edges to commits left out are dropped.
+pub fn dot(commits: &[Commit], labels: &HashMap<String, Vec<String>>, limit: usize) -> String {
+    let shown: Vec<&Commit> = topological(commits).into_iter().take(limit).collect();
+    let ids: HashSet<&str> = shown.iter().map(|c| c.id.as_str()).collect();
+    let mut out =
+        String::from("digraph commits {\n    node [shape=box, fontname=\"monospace\"];\n");
+    for commit in &shown {
+        let mut label = commit.id[..8.min(commit.id.len())].to_string();
+        let names = labels.get(&commit.id);
+        if let Some(names) = names {
+            label.push_str(&format!(" ({})", names.join(", ")));
+        }
+        label.push('\n');
+        label.push_str(&commit.message);
+        let style = if names.is_some() { ", style=bold" } else { "" };
+        out.push_str(&format!(
+            "    {} [label={}{}];\n",
+            quote(&commit.id),
+            quote(&label),
+            style
+        ));
+    }
+    for commit in &shown {
+        if let Some(parent) = commit.parent.as_deref().filter(|p| ids.contains(p)) {
+            out.push_str(&format!(
+                "    {} -> {};\n",
+                quote(&commit.id),
+                quote(parent)
+            ));
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// `s` as a DOT string literal.
+fn quote(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// One row of lane markers: `mark(i)` where it gives one, otherwise `|` for
+/// an open lane and a space for a free one.
+fn row(lanes: &[Option<&str>], mark: impl Fn(usize) -> Option<char>) -> String {
+    let marks: Vec<String> = lanes
+        .iter()
+        .enumerate()
+        .map(|(i, lane)| {
+            mark(i)
+                .unwrap_or(if lane.is_some() { '|' } else { ' ' })
+                .to_string()
+        })
+        .collect();
+    marks.join(" ").trim_end().to_string()
+}
+
+/// `commits` newest first, but never a parent before one of its children.
+pub(crate) fn topological(commits: &[Commit]) -> Vec<&Commit> {
+    let by_id: HashMap<&str, &Commit> = commits.iter().map(|c| (c.id.as_str(), c)).collect();
+    let mut children: HashMap<&str, usize> = HashMap::new();
+    for commit in by_id.values() {
+        if let Some(parent) = commit.parent.as_deref() {
+            *children.entry(parent).or_default() += 1;
+        }
+    }
+    let mut ready: BinaryHeap<_> = by_id
+        .values()
+        .filter(|c| !children.contains_key(c.id.as_str()))
+        .map(|c| (c.timestamp, c.id.as_str()))
+        .collect();
+    let mut order = Vec::with_capacity(by_id.len());
+    while let Some((_, id)) = ready.pop() {
+        let commit = by_id[id];
+        order.push(commit);
+        let Some(parent) = commit.parent.as_deref() else {
+            continue;
+        };
+        let remaining = children.get_mut(parent).map(|n| {
+            *n -= 1;
+            *n
+        });
+        if let (Some(0), Some(parent)) = (remaining, by_id.get(parent)) {
+            ready.push((parent.timestamp, parent.id.as_str()));
+        }
+    }
+    order
+}
+
+/// The children of each commit: the parent links of a set of commits,
+/// reversed.
+#[derive(Debug, Default)]
+pub(crate) struct ChildIndex {
+    children: HashMap<String, BTreeSet<String>>,
+    parents: HashMap<String, String>,
+}
+
+impl ChildIndex {
+    pub(crate) fn build(commits: impl IntoIterator<Item = Commit>) -> Self {
+        let mut index = Self::default();
+        for commit in commits {
+            index.insert(&commit);
+        }
+        index
+    }
+
+    /// Build from commit ids and their parents.
+    pub(crate) fn from_links<'a>(
+        links: impl IntoIterator<Item = (&'a str, Option<&'a str>)>,
+    ) -> Self {
+        let mut index = Self::default();
+        for (id, parent) in links {
+            index.link(id, parent);
+        }
+        index
+    }
+
+    /// Add a commit, or update the parent of one rewritten in place.
+    pub(crate) fn insert(&mut self, commit: &Commit) {
+        self.link(&commit.id, commit.parent.as_deref());
+    }
+
+    fn link(&mut self, id: &str, parent: Option<&str>) {
+        self.remove(id);
+        if let Some(parent) = parent {
+            self.children
+                .entry(parent.to_string())
+                .or_default()
+                .insert(id.to_string());
+            self.parents.insert(id.to_string(), parent.to_string());
+        }
+    }
+
+    /// Forget a deleted commit's link to its parent. Links from its
+    /// children stay until they are rewritten or deleted too.
+    pub(crate) fn remove(&mut self, id: &str) {
+        let Some(parent) = self.parents.remove(id) else {
+            return;
+        };
+        if let Some(siblings) = self.children.get_mut(&parent) {
+            siblings.remove(id);
+            if siblings.is_empty() {
+                self.children.remove(&parent);
+            }
+        }
+    }
+
+    /// Ids of the commits whose parent is `id`.
+    pub(crate) fn children(&self, id: &str) -> impl Iterator<Item = &String> {
+        self.children.get(id).into_iter().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Utc};
+
+    fn commit(id: &str, parent: Option<&str>, age: i64) -> Commit {
+        Commit {
+            id: format!("{:0<8}", id),
+            parent: parent.map(|p| format!("{:0<8}", p)),
+            tree_root: String::new(),
+            timestamp: Utc::now() - Duration::seconds(age),
+            message: format!("commit {}", id),
+        }
+    }
+
+    #[test]
+    fn draws_forks_as_lanes() {
+        let commits = [
+            commit("a", None, 50),
+            commit("b", Some("a"), 40),
+            commit("c", Some("b"), 30),
+            commit("d", Some("b"), 20),
+            commit("e", Some("c"), 10),
+        ];
+        let labels = HashMap::from([
+            ("e0000000".to_string(), vec!["HEAD -> main".to_string()]),
+            (
+                "d0000000".to_string(),
+                vec!["dev".to_string(), "tag: v1".to_string()],
+            ),
+        ]);
+        assert_eq!(
+            render(&commits, &labels, usize::MAX),
+            [
+                "* e0000000 (HEAD -> main) commit e",
+                "| * d0000000 (dev, tag: v1) commit d",
+                "* | c0000000 commit c",
+                "|/",
+                "* b0000000 commit b",
+                "* a0000000 commit a",
+            ]
+        );
+        assert_eq!(render(&commits, &labels, 2).len(), 2);
+    }
+
+    #[test]
+    fn exports_dot_with_labels_and_parent_edges() {
+        let mut commits = vec![
+            commit("a", None, 30),
+            commit("b", Some("a"), 20),
+            commit("c", Some("a"), 10),
+        ];
+        commits[2].message = "say \"hi\"\\\nbye".into();
+        let labels = HashMap::from([("c0000000".to_string(), vec!["tag: v1".to_string()])]);
+        assert_eq!(
+            dot(&commits, &labels, usize::MAX),
+            r#"digraph commits {
+    node [shape=box, fontname="monospace"];
+    "c0000000" [label="c0000000 (tag: v1)\nsay \"hi\"\\\nbye", style=bold];
+    "b0000000" [label="b0000000\ncommit b"];
+    "a0000000" [label="a0000000\ncommit a"];
+    "c0000000" -> "a0000000";
+    "b0000000" -> "a0000000";
+}
+"#
+        );
+        // Edges to commits past the limit are left out.
+        assert!(!dot(&commits, &labels, 2).contains("->"));
+    }
+
+    #[test]
+    fn child_index_reverses_parent_links() {
+        let mut index = ChildIndex::build([
+            commit("a", None, 30),
+            commit("b", Some("a"), 20),
+            commit("c", Some("a"), 10),
+        ]);
+        let children = |index: &ChildIndex, id: &str| -> Vec<String> {
+            index.children(&format!("{:0<8}", id)).cloned().collect()
+        };
+        assert_eq!(children(&index, "a"), ["b0000000", "c0000000"]);
+        assert!(children(&index, "b").is_empty());
+
+        // Re-rooting a commit in place moves it out from under its parent.
+        index.insert(&commit("b", None, 20));
+        assert_eq!(children(&index, "a"), ["c0000000"]);
+        index.remove("c0000000");
+        assert!(children(&index, "a").is_empty());
+    }
+
+    #[test]
+    fn keeps_children_above_parents_despite_clock_skew() {
+        let commits = [commit("a", None, 0), commit("b", Some("a"), 100)];
+        let lines = render(&commits, &HashMap::new(), usize::MAX);
+        assert_eq!(lines, ["* b0000000 commit b", "* a0000000 commit a"]);
+    }
+}
@@ -0,0 +1,181 @@
+//! Searching values for a substring or regex, for `Database::grep`.
+
+use crate::error::{IcebergError, Result};
+use regex::Regex;
+
+/// How `Database::grep` searches values.
+#[derive(Debug, Clone, Default)]
+pub struct GrepOptions {
+    /// Treat the pattern as a regex rather than a plain substring.
+    pub regex: bool,
+    /// Only search the string fields of JSON values; other values are
+    /// skipped.
+    pub json_strings: bool,
+    /// Characters of context kept on each side of a match.
+    pub context: usize,
+    /// Stop after this many matches; `None` for all of them.
+    pub limit: Option<usize>,
+}
+
+/// A match found by `Database::grep`: the first one in each value, or in
+/// each string field with `json_strings`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrepMatch {
+    pub key: String,
+    /// Dotted path of the string field that matched (array items by index,
+    /// as in `tags.0`); `None` when the whole value was searched.
+    pub field: Option<String>,
+    /// The matched text with up to `context` characters either side.
+    pub snippet: String,
+}
+
+/// A compiled `grep` pattern.
+pub(crate) struct Matcher {
+    regex: Regex,
+    options: GrepOptions,
+}
+
+impl Matcher {
+    pub(crate) fn new(pattern: &str, options: &GrepOptions) -> Result<Self> {
+        let expr = match options.regex {
+            true => pattern.to_string(),
+            false => regex::escape(pattern),
+        };
+        let regex = Regex::new(&expr).map_err(|e| IcebergError::InvalidQuery(e.to_string()))?;
+        Ok(Self {
+            regex,
+            options: options.clone(),
+        })
+    }
+
+    /// The matches in the value of `key`.
+    pub(crate) fn search(&self, key: &str, value: &[u8]) -> Vec<GrepMatch> {
+        let mut found = Vec::new();
+        if self.options.json_strings {
+            if let Ok(doc) = serde_json::from_slice(value) {
+                self.search_strings(key, &doc, &mut String::new(), &mut found);
+            }
+        } else if let Some(snippet) = self.snippet(&String::from_utf8_lossy(value)) {
+            found.push(GrepMatch {
+                key: key.to_string(),
+                field: None,
+                snippet,
+            });
+        }
+        found
+    }
+
+    fn search_strings(
+        &self,
+        key: &str,
+        value: &serde_json::Value,
+        path: &mut String,
+        found: &mut Vec<GrepMatch>,
+    ) {
+        let mut child = |path: &mut String, name: &str, value| {
+            let len = path.len();
+            if !path.is_empty() {
+                path.push('.');
+            }
+            path.push_str(name);
+            self.search_strings(key, value, path, found);
+            path.truncate(len);
+        };
+        match value {
+            serde_json::Value::String(s) => {
+                if let Some(snippet) = self.snippet(s) {
+                    found.push(GrepMatch {
+                        key: key.to_string(),
+                        field: (!path.is_empty()).then(|| path.clone()),
+                        snippet,
+                    });
+                }
+            }
+            serde_json::Value::Array(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    child(path, &i.to_string(), item);
+                }
+            }
+            serde_json::Value::Object(fields) => {
+                for (name, field) in fields {
+                    child(path, name, field);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The first match in `text` with its context.
+    fn snippet(&self, text: &str) -> Option<String> {
+        let m = self.regex.find(text)?;
+        let context = self.options.context;
+        let start = text[..m.start()]
+            .char_indices()
+            .rev()
+            .take(context)
+            .last()
+            .map_or(m.start(), |(i, _)| i);
+        let end = m.end()
+            + text[m.end()..]
+                .chars()
+                .take(context)
+                .map(char::len_utf8)
+                .sum::<usize>();
+        Some(text[start..end].to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_substrings_with_context() {
+        let options = GrepOptions {
+            context: 3,
+            ..Default::default()
+        };
+        let m = Matcher::new("a.b", &options).unwrap();
+        assert!(m.search("k", b"xxa+b").is_empty());
+        let found = m.search("k", "héllo a.b wörld".as_bytes());
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].snippet, "lo a.b wö");
+        assert_eq!(found[0].field, None);
+
+        let regex = Matcher::new(
+            r"\d{3}",
+            &GrepOptions {
+                regex: true,
+                ..options
+            },
+        )
+        .unwrap();
+        assert_eq!(regex.search("k", b"id 12345")[0].snippet, "id 12345");
+        assert!(Matcher::new(
+            "(",
+            &GrepOptions {
+                regex: true,
+                ..Default::default()
+            }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn json_strings_only_searches_string_fields() {
+        let options = GrepOptions {
+            json_strings: true,
+            ..Default::default()
+        };
+        let m = Matcher::new("zur", &options).unwrap();
+        let doc = br#"{"zur": 1, "city": "zurich", "tags": ["a", "zurzach"], "n": "zur"}"#;
+        let fields: Vec<_> = m
+            .search("k", doc)
+            .into_iter()
+            .map(|found| found.field.unwrap())
+            .collect();
+        assert_eq!(fields, ["city", "n", "tags.1"]);
+        assert!(m.search("k", b"zurich").is_empty());
+        assert_eq!(m.search("k", br#""zurich""#)[0].field, None);
+    }
+}
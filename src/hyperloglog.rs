@@ -0,0 +1,227 @@
+//! HyperLogLog sketches for approximate distinct counts, kept alongside the
+//! bloom filter for `Database::approx_distinct`.
+
+use crate::error::{IcebergError, Result};
+use crate::index::{field_string, json_field};
+use crate::namespace;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// Register index bits of the sketches `Database::track_distinct` keeps:
+/// 4096 one-byte registers, for a standard error of about 1.6%.
+pub const DEFAULT_PRECISION: u8 = 12;
+
+/// A HyperLogLog sketch: estimates how many distinct items were inserted
+/// in constant space. Inserting an item again changes nothing, and items
+/// cannot be removed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    /// An empty sketch with `2^precision` registers; `precision` is clamped
+    /// to 4..=18.
+    pub fn new(precision: u8) -> Self {
+        let precision = precision.clamp(4, 18);
+        Self {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    /// Insert an item.
+    pub fn insert(&mut self, item: &[u8]) {
+        let digest = Sha256::digest(item);
+        let hash = u64::from_le_bytes(digest[..8].try_into().unwrap());
+        let index = (hash >> (64 - self.precision)) as usize;
+        // Position of the first set bit in the rest of the hash; a sentinel
+        // bit bounds it when the rest is all zeros.
+        let rest = (hash << self.precision) | (1 << (self.precision - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    /// Estimated number of distinct items inserted.
+    pub fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|&r| 2f64.powi(-i32::from(r)))
+            .sum();
+        let raw = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        // Linear counting is far more accurate while many registers are
+        // still empty.
+        let estimate = match raw <= 2.5 * m && zeros > 0 {
+            true => m * (m / zeros as f64).ln(),
+            false => raw,
+        };
+        estimate.round() as u64
+    }
+
+    /// Fold `other` into this sketch, as if its items had been inserted
+    /// here. Returns `false`, changing nothing, if the precisions differ.
+    pub fn merge(&mut self, other: &HyperLogLog) -> bool {
+        if other.precision != self.precision {
+            return false;
+        }
+        for (mine, theirs) in self.registers.iter_mut().zip(&other.registers) {
+            *mine = (*mine).max(*theirs);
+        }
+        true
+    }
+
+    /// Number of register index bits.
+    pub fn precision(&self) -> u8 {
+        self.precision
+    }
+
+    /// Relative standard error of `estimate`.
+    pub fn standard_error(&self) -> f64 {
+        1.04 / (self.registers.len() as f64).sqrt()
+    }
+}
+
+/// Check a `track_distinct` spec: a dotted JSON field path, or a key
+/// prefix followed by `*`.
+pub fn check_spec(spec: &str) -> Result<()> {
+    if spec.is_empty() || spec.contains(namespace::SEPARATOR) {
+        return Err(IcebergError::InvalidQuery(format!(
+            "invalid distinct spec {:?}: use a field path like address.city or a key prefix like user:*",
+            spec
+        )));
+    }
+    Ok(())
+}
+
+/// The sketches of every tracked spec.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct DistinctSet {
+    sketches: BTreeMap<String, HyperLogLog>,
+}
+
+impl DistinctSet {
+    pub(crate) fn get(&self, spec: &str) -> Option<&HyperLogLog> {
+        self.sketches.get(spec)
+    }
+
+    pub(crate) fn specs(&self) -> impl Iterator<Item = (&String, &HyperLogLog)> {
+        self.sketches.iter()
+    }
+
+    pub(crate) fn set(&mut self, spec: &str, sketch: HyperLogLog) {
+        self.sketches.insert(spec.to_string(), sketch);
+    }
+
+    pub(crate) fn remove(&mut self, spec: &str) -> bool {
+        self.sketches.remove(spec).is_some()
+    }
+
+    /// Count a write of `key`, with its value unless it was streamed. Keys
+    /// in namespaces other than the default one are not counted.
+    pub(crate) fn insert(&mut self, key: &str, value: Option<&[u8]>) {
+        if self.sketches.is_empty() || namespace::split(key).0.is_some() {
+            return;
+        }
+        let mut parsed = None;
+        for (spec, sketch) in &mut self.sketches {
+            insert_into(sketch, spec, key, value, &mut parsed);
+        }
+    }
+}
+
+/// Insert what `spec` counts of a write into `sketch`. `parsed` caches the
+/// value as JSON across specs.
+pub(crate) fn insert_into(
+    sketch: &mut HyperLogLog,
+    spec: &str,
+    key: &str,
+    value: Option<&[u8]>,
+    parsed: &mut Option<Option<serde_json::Value>>,
+) {
+    if let Some(prefix) = spec.strip_suffix('*') {
+        if key.starts_with(prefix) {
+            sketch.insert(key.as_bytes());
+        }
+        return;
+    }
+    let Some(value) = value else {
+        return;
+    };
+    let doc = parsed.get_or_insert_with(|| serde_json::from_slice(value).ok());
+    match doc.as_ref().and_then(|doc| json_field(doc, spec)) {
+        Some(serde_json::Value::Array(items)) => {
+            for item in items {
+                sketch.insert(field_string(item).as_bytes());
+            }
+        }
+        Some(field) => sketch.insert(field_string(field).as_bytes()),
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_within_error_bounds() {
+        let mut hll = HyperLogLog::new(DEFAULT_PRECISION);
+        assert_eq!(hll.estimate(), 0);
+        for n in [10u64, 1_000, 20_000] {
+            let mut hll = HyperLogLog::new(DEFAULT_PRECISION);
+            for i in 0..n {
+                hll.insert(format!("item-{}", i).as_bytes());
+                hll.insert(format!("item-{}", i / 2).as_bytes());
+            }
+            let error = (hll.estimate() as f64 - n as f64).abs() / n as f64;
+            assert!(
+                error < 4.0 * hll.standard_error(),
+                "{} ~ {}",
+                n,
+                hll.estimate()
+            );
+        }
+        for i in 0..500 {
+            hll.insert(format!("a{}", i).as_bytes());
+        }
+        let mut other = HyperLogLog::new(DEFAULT_PRECISION);
+        for i in 250..750 {
+            other.insert(format!("a{}", i).as_bytes());
+        }
+        assert!(hll.merge(&other));
+        assert!((720..=780).contains(&hll.estimate()));
+        assert!(!hll.merge(&HyperLogLog::new(8)));
+    }
+
+    #[test]
+    fn counts_fields_and_prefixes() {
+        let mut set = DistinctSet::default();
+        set.set("city", HyperLogLog::new(DEFAULT_PRECISION));
+        set.set("user:*", HyperLogLog::new(DEFAULT_PRECISION));
+        set.insert("user:1", Some(br#"{"city": "Bern"}"#));
+        set.insert("user:2", Some(br#"{"city": "Bern"}"#));
+        set.insert("order:1", Some(br#"{"city": ["Basel", "Chur"]}"#));
+        set.insert(
+            &namespace::qualify("ns", "user:3"),
+            Some(br#"{"city": "Sion"}"#),
+        );
+        set.insert("user:4", None);
+        assert_eq!(set.get("city").unwrap().estimate(), 3);
+        assert_eq!(set.get("user:*").unwrap().estimate(), 3);
+        assert!(check_spec("").is_err());
+        assert!(check_spec("address.city").is_ok());
+    }
+}
@@ -1,6 +1,431 @@
+use crate::backend::Backend;
 use crate::error::{IcebergError, Result};
+use crate::namespace;
+use crate::query::{Condition, Op};
+use crate::vector::{VectorIndex, VectorOptions};
+use chrono::{DateTime, NaiveDate, SecondsFormat};
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, BTreeSet};
+use std::ops::{Bound, RangeBounds};
+
+/// How an index interprets and orders the values it extracts.
+///
+/// Typed values are stored in an encoding that sorts in their natural order,
+/// so range queries on numbers and dates work; values that do not parse as
+/// the index's type are not indexed.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexKind {
+    /// Values compared as strings.
+    #[default]
+    String,
+    /// 64-bit signed integers.
+    Integer,
+    /// Finite 64-bit floats.
+    Float,
+    /// RFC 3339 timestamps or `YYYY-MM-DD` dates (as midnight UTC).
+    Date,
+}
+
+impl std::str::FromStr for IndexKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "string" => Ok(IndexKind::String),
+            "integer" | "int" => Ok(IndexKind::Integer),
+            "float" => Ok(IndexKind::Float),
+            "date" => Ok(IndexKind::Date),
+            other => Err(format!("unknown index type: {}", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for IndexKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            IndexKind::String => "string",
+            IndexKind::Integer => "integer",
+            IndexKind::Float => "float",
+            IndexKind::Date => "date",
+        };
+        f.write_str(name)
+    }
+}
+
+const SIGN_BIT: u64 = 1 << 63;
+
+impl IndexKind {
+    /// The stored form of `value`, or `None` if it is not of this kind.
+    fn encode(self, value: &str) -> Option<String> {
+        let sortable = match self {
+            IndexKind::String => return Some(value.to_string()),
+            IndexKind::Integer => value.parse::<i64>().ok()? as u64 ^ SIGN_BIT,
+            IndexKind::Float => {
+                let f = value.parse::<f64>().ok().filter(|f| f.is_finite())?;
+                // Adding zero turns -0.0 into 0.0.
+                let bits = (f + 0.0).to_bits();
+                if bits & SIGN_BIT != 0 {
+                    !bits
+                } else {
+                    bits | SIGN_BIT
+                }
+            }
+            IndexKind::Date => parse_date(value)? as u64 ^ SIGN_BIT,
+        };
+        Some(format!("{:016x}", sortable))
+    }
+
+    /// The value a stored form was encoded from, in canonical form.
+    fn decode(self, stored: &str) -> String {
+        let Ok(sortable) = u64::from_str_radix(stored, 16) else {
+            return stored.to_string();
+        };
+        match self {
+            IndexKind::String => stored.to_string(),
+            IndexKind::Integer => ((sortable ^ SIGN_BIT) as i64).to_string(),
+            IndexKind::Float => {
+                let bits = if sortable & SIGN_BIT != 0 {
+                    sortable & !SIGN_BIT
+                } else {
+                    !sortable
+                };
+                f64::from_bits(bits).to_string()
+            }
+            IndexKind::Date => DateTime::from_timestamp_millis((sortable ^ SIGN_BIT) as i64)
+                .map(|d| d.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+                .unwrap_or_else(|| stored.to_string()),
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch of an RFC 3339 timestamp or a date.
+fn parse_date(value: &str) -> Option<i64> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(value) {
+        return Some(t.timestamp_millis());
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp_millis())
+}
+
+/// How a string index compares values.
+///
+/// Values are stored folded, so lookups, prefix scans and ranges all see the
+/// collation, and `distinct_values` lists the folded forms. Locale-specific
+/// orderings are not supported; other index kinds ignore the collation.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Collation {
+    /// Byte-wise comparison.
+    #[default]
+    Binary,
+    /// Letters compared by their lowercase forms.
+    CaseInsensitive,
+    /// Runs of digits compared as numbers, so `item2` sorts before `item10`
+    /// and `item007` equals `item7`.
+    Numeric,
+    /// Unicode case folding: like `CaseInsensitive`, and also treats `ß` as
+    /// `ss` and final sigma as sigma.
+    Casefold,
+}
+
+impl std::str::FromStr for Collation {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "binary" => Ok(Collation::Binary),
+            "case-insensitive" | "nocase" => Ok(Collation::CaseInsensitive),
+            "numeric" => Ok(Collation::Numeric),
+            "casefold" => Ok(Collation::Casefold),
+            other => Err(format!("unknown collation: {}", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for Collation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Collation::Binary => "binary",
+            Collation::CaseInsensitive => "case-insensitive",
+            Collation::Numeric => "numeric",
+            Collation::Casefold => "casefold",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Longest digit run `Collation::Numeric` stores as one number; the length
+/// prefix is two decimal digits.
+const MAX_DIGIT_RUN: usize = 99;
+
+impl Collation {
+    /// The canonical form of `value`: values with the same canonical form
+    /// are equal under this collation.
+    fn fold(self, value: &str) -> String {
+        match self {
+            Collation::Binary => value.to_string(),
+            Collation::CaseInsensitive => value.to_lowercase(),
+            Collation::Numeric => {
+                let mut out = String::with_capacity(value.len());
+                for (digits, run) in digit_runs(value) {
+                    if digits {
+                        let trimmed = run.trim_start_matches('0');
+                        out.push_str(if trimmed.is_empty() { "0" } else { trimmed });
+                    } else {
+                        out.push_str(run);
+                    }
+                }
+                out
+            }
+            Collation::Casefold => value
+                .chars()
+                .flat_map(|c| match c {
+                    'ß' | 'ẞ' => "ss".chars().collect::<Vec<_>>(),
+                    'ς' => vec!['σ'],
+                    c => c.to_lowercase().collect(),
+                })
+                .collect(),
+        }
+    }
+
+    /// The stored form of `value`: its canonical form, with digit runs
+    /// length-prefixed for `Numeric` so that they sort by magnitude.
+    fn encode(self, value: &str) -> String {
+        let folded = self.fold(value);
+        if self != Collation::Numeric {
+            return folded;
+        }
+        let mut out = String::with_capacity(folded.len() + 2);
+        for (digits, run) in digit_runs(&folded) {
+            if !digits {
+                out.push_str(run);
+                continue;
+            }
+            // Absurdly long runs are split; they sort correctly only
+            // against runs of the same length.
+            for chunk in run.as_bytes().chunks(MAX_DIGIT_RUN) {
+                out.push_str(&format!("{:02}", chunk.len()));
+                out.push_str(std::str::from_utf8(chunk).unwrap_or_default());
+            }
+        }
+        out
+    }
+
+    /// The canonical form a stored form was encoded from.
+    fn decode(self, stored: &str) -> String {
+        if self != Collation::Numeric {
+            return stored.to_string();
+        }
+        let mut out = String::with_capacity(stored.len());
+        let mut rest = stored;
+        while let Some(c) = rest.chars().next() {
+            let len = rest
+                .get(..2)
+                .filter(|_| c.is_ascii_digit())
+                .and_then(|n| n.parse::<usize>().ok());
+            match len {
+                Some(len) if rest.len() >= 2 + len => {
+                    out.push_str(&rest[2..2 + len]);
+                    rest = &rest[2 + len..];
+                }
+                _ => {
+                    out.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Split `value` into maximal runs of ASCII digits and of other characters,
+/// each tagged with whether it is digits.
+fn digit_runs(value: &str) -> Vec<(bool, &str)> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    for (i, c) in value.char_indices().skip(1) {
+        let digits = c.is_ascii_digit();
+        if digits != value[start..].starts_with(|c: char| c.is_ascii_digit()) {
+            runs.push((!digits, &value[start..i]));
+            start = i;
+        }
+    }
+    if start < value.len() {
+        runs.push((
+            value[start..].starts_with(|c: char| c.is_ascii_digit()),
+            &value[start..],
+        ));
+    }
+    runs
+}
+
+/// Where an index takes the values it indexes a key under from.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase", tag = "from")]
+pub enum IndexSource {
+    /// The field at the index's field path in the JSON value.
+    #[default]
+    Value,
+    /// Part `position` (from 0) of the key split on `delimiter`, so keys
+    /// like `order:<customer>:<id>` can be indexed by customer with `":"`
+    /// and 1. Keys with fewer parts are not indexed; the field path is not
+    /// used.
+    Key { delimiter: String, position: usize },
+}
+
+/// Settings of a secondary index chosen at creation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexOptions {
+    pub kind: IndexKind,
+    /// What the index extracts its values from.
+    pub source: IndexSource,
+    /// How a string index compares values.
+    pub collation: Collation,
+    /// Reject writes that would give two keys the same indexed value.
+    pub unique: bool,
+}
+
+/// Approximate bookkeeping bytes per stored string, for `memory_bytes`.
+pub(crate) const ENTRY_OVERHEAD: usize = 48;
+
+/// Values listed in `IndexStats::top_values`.
+pub const STATS_TOP_VALUES: usize = 10;
+
+/// Statistics of one index, from `Database::index_stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexStats {
+    /// Index name, within its namespace.
+    pub name: String,
+    pub field_path: String,
+    /// Value type of a secondary index; `None` for a vector index.
+    pub kind: Option<IndexKind>,
+    /// Distinct indexed values (embeddings, for a vector index).
+    pub cardinality: usize,
+    /// Key references across all values.
+    pub total_entries: usize,
+    /// Distinct keys with at least one entry.
+    pub keys: usize,
+    /// The most frequent values with their key counts.
+    pub top_values: Vec<(String, usize)>,
+    /// Rough size of the in-memory index.
+    pub memory_bytes: usize,
+    /// Size of the index's files as last persisted.
+    pub disk_bytes: u64,
+}
+
+impl std::fmt::Display for IndexStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            Some(kind) => writeln!(
+                f,
+                "Index:    {} ({} on '{}')",
+                self.name, kind, self.field_path
+            )?,
+            None => writeln!(
+                f,
+                "Index:    {} (vector on '{}')",
+                self.name, self.field_path
+            )?,
+        }
+        writeln!(f, "Distinct: {}", self.cardinality)?;
+        writeln!(f, "Entries:  {}", self.total_entries)?;
+        writeln!(f, "Keys:     {}", self.keys)?;
+        writeln!(f, "Memory:   ~{} bytes", self.memory_bytes)?;
+        writeln!(f, "Disk:     {} bytes", self.disk_bytes)?;
+        if !self.top_values.is_empty() {
+            writeln!(f, "Top values:")?;
+            for (value, count) in &self.top_values {
+                writeln!(f, "  {:>8}  {}", count, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Direction of paged index results.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Order {
+    /// Smallest indexed value first.
+    #[default]
+    Asc,
+    /// Largest indexed value first.
+    Desc,
+}
+
+impl std::str::FromStr for Order {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "asc" => Ok(Order::Asc),
+            "desc" => Ok(Order::Desc),
+            other => Err(format!("unknown order: {}", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for Order {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Order::Asc => "asc",
+            Order::Desc => "desc",
+        })
+    }
+}
+
+/// Which slice of an index query to return.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PageOptions {
+    pub order: Order,
+    /// Most entries to return; `None` for all of them.
+    pub limit: Option<usize>,
+    /// Entries to skip, counted after the cursor if there is one.
+    pub offset: usize,
+    /// The `next` cursor of the previous page. Unlike an offset it stays
+    /// put when entries before it are added or removed.
+    pub after: Option<String>,
+}
+
+/// One page of an index query, from `Database::query_index_page`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexPage {
+    /// Keys with the indexed value they were found under, ordered by value
+    /// and then key. A key indexed under several values appears once per
+    /// value.
+    pub entries: Vec<(String, String)>,
+    /// Cursor for the following page, if there are more entries.
+    pub next: Option<String>,
+}
+
+/// An opaque, printable cursor pointing at one index entry.
+fn encode_cursor(stored: &str, key: &str) -> String {
+    let bytes = serde_json::to_vec(&(stored, key)).expect("strings serialize");
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_cursor(cursor: &str) -> Result<(String, String)> {
+    let bytes: Option<Vec<u8>> = (0..cursor.len())
+        .step_by(2)
+        .map(|i| {
+            cursor
+                .get(i..i + 2)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        })
+        .collect();
+    bytes
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .ok_or_else(|| IcebergError::InvalidQuery(format!("invalid cursor {:?}", cursor)))
+}
+
+/// Whether `BTreeMap::range` would panic on these bounds.
+fn reversed(start: &Bound<String>, end: &Bound<String>) -> bool {
+    match (start, end) {
+        (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => s > e,
+        _ => false,
+    }
+}
 
 /// A secondary index that maps extracted field values back to primary keys.
 ///
@@ -12,40 +437,136 @@ pub struct SecondaryIndex {
     pub name: String,
     /// The JSON field path this index extracts (e.g., "city" or "address.city").
     pub field_path: String,
-    /// Inverted index: field_value → set of primary keys.
+    /// How extracted values are interpreted and ordered.
+    #[serde(default)]
+    pub kind: IndexKind,
+    /// Whether each indexed value may belong to only one key.
+    #[serde(default)]
+    pub unique: bool,
+    /// How values are compared, for string indexes.
+    #[serde(default)]
+    pub collation: Collation,
+    /// Whether values come from the JSON value or from the key.
+    #[serde(default)]
+    pub source: IndexSource,
+    /// Inverted index: stored field value → set of primary keys.
     entries: BTreeMap<String, BTreeSet<String>>,
 }
 
 impl SecondaryIndex {
     /// Create a new empty secondary index.
     pub fn new(name: String, field_path: String) -> Self {
+        Self::with_options(name, field_path, &IndexOptions::default())
+    }
+
+    /// Create a new empty secondary index with the given settings.
+    pub fn with_options(name: String, field_path: String, options: &IndexOptions) -> Self {
         Self {
             name,
             field_path,
+            kind: options.kind,
+            unique: options.unique,
+            collation: options.collation,
+            source: options.source.clone(),
             entries: BTreeMap::new(),
         }
     }
 
+    /// An empty index with the same definition.
+    pub fn empty_copy(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            field_path: self.field_path.clone(),
+            kind: self.kind,
+            unique: self.unique,
+            collation: self.collation,
+            source: self.source.clone(),
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Whether the index takes its values from stored values rather than
+    /// from keys alone.
+    pub fn reads_values(&self) -> bool {
+        self.source == IndexSource::Value
+    }
+
+    /// Whether `index_entry(primary_key, value)` would index the key under
+    /// `field_value`.
+    pub fn indexes_under(&self, primary_key: &str, value: &[u8], field_value: &str) -> bool {
+        self.encode(field_value)
+            .is_some_and(|v| self.stored_values(primary_key, value).contains(&v))
+    }
+
+    /// The stored form of `value`, or `None` if it is not of the index's kind.
+    fn encode(&self, value: &str) -> Option<String> {
+        match self.kind {
+            IndexKind::String => Some(self.collation.encode(value)),
+            kind => kind.encode(value),
+        }
+    }
+
+    /// The value a stored form was encoded from, in canonical form.
+    fn decode(&self, stored: &str) -> String {
+        match self.kind {
+            IndexKind::String => self.collation.decode(stored),
+            kind => kind.decode(stored),
+        }
+    }
+
+    /// The canonical form of a query value.
+    fn fold(&self, value: &str) -> String {
+        match self.kind {
+            IndexKind::String => self.collation.fold(value),
+            _ => value.to_string(),
+        }
+    }
+
     /// Index a key-value pair. Extracts the field from the value (assumes JSON).
     /// If the value is not JSON or the field is missing, the key is not indexed.
+    /// If the field is an array, the key is indexed under each element.
     pub fn index_entry(&mut self, primary_key: &str, value: &[u8]) {
-        // First remove any old entry for this key
-        self.remove_key(primary_key);
+        let stored = self.stored_values(primary_key, value);
+        self.set_entry(primary_key, &stored);
+    }
 
-        // Try to extract the field value
-        if let Some(field_val) = self.extract_field(value) {
-            self.entries
-                .entry(field_val)
-                .or_default()
-                .insert(primary_key.to_string());
+    /// For a unique index, the key other than `primary_key` that already
+    /// holds the value `value` would be indexed under, with that value.
+    pub fn conflict(&self, primary_key: &str, value: &[u8]) -> Option<(String, &str)> {
+        if !self.unique {
+            return None;
         }
+        self.extract(primary_key, value)
+            .into_iter()
+            .find_map(|field_val| {
+                let holders = self.entries.get(&self.encode(&field_val)?)?;
+                let holder = holders.iter().find(|k| *k != primary_key)?;
+                Some((field_val, holder.as_str()))
+            })
+    }
+
+    /// For a unique index, a value held by more than one key, with its keys.
+    pub fn duplicate(&self) -> Option<(String, Vec<String>)> {
+        if !self.unique {
+            return None;
+        }
+        self.entries
+            .iter()
+            .find(|(_, keys)| keys.len() > 1)
+            .map(|(val, keys)| (self.decode(val), keys.iter().cloned().collect()))
     }
 
     /// Remove a primary key from the index.
     pub fn remove_key(&mut self, primary_key: &str) {
+        self.unindex(primary_key);
+    }
+
+    /// `remove_key`, returning whether the key had any entries.
+    fn unindex(&mut self, primary_key: &str) -> bool {
+        let mut removed = false;
         let mut empty_values = Vec::new();
         for (val, keys) in self.entries.iter_mut() {
-            keys.remove(primary_key);
+            removed |= keys.remove(primary_key);
             if keys.is_empty() {
                 empty_values.push(val.clone());
             }
@@ -53,45 +574,190 @@ impl SecondaryIndex {
         for val in empty_values {
             self.entries.remove(&val);
         }
+        removed
+    }
+
+    /// The stored values `index_entry` would index `primary_key` under.
+    fn stored_values(&self, primary_key: &str, value: &[u8]) -> Vec<String> {
+        self.extract(primary_key, value)
+            .into_iter()
+            .filter_map(|v| self.encode(&v))
+            .collect()
+    }
+
+    /// Index `primary_key` under exactly these stored values. Returns
+    /// whether the key had or now has any entries.
+    fn set_entry(&mut self, primary_key: &str, stored: &[String]) -> bool {
+        let had = self.unindex(primary_key);
+        for val in stored {
+            self.entries
+                .entry(val.clone())
+                .or_default()
+                .insert(primary_key.to_string());
+        }
+        had || !stored.is_empty()
     }
 
     /// Look up primary keys by an exact field value.
     pub fn lookup(&self, field_value: &str) -> Vec<String> {
-        self.entries
-            .get(field_value)
+        self.encode(field_value)
+            .and_then(|v| self.entries.get(&v))
             .map(|s| s.iter().cloned().collect())
             .unwrap_or_default()
     }
 
     /// Range lookup: find keys where the indexed field is in [start, end).
     pub fn range_lookup(&self, start: &str, end: &str) -> Vec<String> {
-        use std::ops::Bound;
-        let mut result = Vec::new();
-        for (_val, keys) in self.entries.range::<String, _>((
-            Bound::Included(&start.to_string()),
-            Bound::Excluded(&end.to_string()),
-        )) {
-            result.extend(keys.iter().cloned());
+        match (self.encode(start), self.encode(end)) {
+            (Some(start), Some(end)) => self.keys_in(Bound::Included(start), Bound::Excluded(end)),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Find keys where the indexed field is between `min` and `max`, both
+    /// inclusive and either optional, in the index's ordering.
+    pub fn range(&self, min: Option<&str>, max: Option<&str>) -> Result<Vec<String>> {
+        Ok(self.keys_in(self.bound(min)?, self.bound(max)?))
+    }
+
+    /// Like `range`, but one page of entries in the requested order.
+    pub fn page(
+        &self,
+        min: Option<&str>,
+        max: Option<&str>,
+        options: &PageOptions,
+    ) -> Result<IndexPage> {
+        let (mut start, mut end) = (self.bound(min)?, self.bound(max)?);
+        let after = options.after.as_deref().map(decode_cursor).transpose()?;
+        if let Some((stored, _)) = &after {
+            if !(start.clone(), end.clone()).contains(stored) {
+                return Err(IcebergError::InvalidQuery(
+                    "cursor does not belong to this query".into(),
+                ));
+            }
+            // Skip straight to the cursor's value.
+            match options.order {
+                Order::Asc => start = Bound::Included(stored.clone()),
+                Order::Desc => end = Bound::Included(stored.clone()),
+            }
+        }
+        let mut entries = Vec::new();
+        if reversed(&start, &end) {
+            return Ok(IndexPage {
+                entries,
+                next: None,
+            });
+        }
+        let range = self.entries.range((start, end));
+        let pairs: Box<dyn Iterator<Item = (&String, &String)>> = match options.order {
+            Order::Asc => Box::new(range.flat_map(|(v, keys)| keys.iter().map(move |k| (v, k)))),
+            Order::Desc => Box::new(
+                range
+                    .rev()
+                    .flat_map(|(v, keys)| keys.iter().rev().map(move |k| (v, k))),
+            ),
+        };
+        let mut pairs = pairs
+            .skip_while(|(v, k)| match &after {
+                Some((stored, key)) if *v == stored => match options.order {
+                    Order::Asc => *k <= key,
+                    Order::Desc => *k >= key,
+                },
+                _ => false,
+            })
+            .skip(options.offset);
+        let mut last = None;
+        for (v, k) in pairs.by_ref().take(options.limit.unwrap_or(usize::MAX)) {
+            entries.push((k.clone(), self.decode(v)));
+            last = Some((v, k));
+        }
+        let next = match (last, pairs.next()) {
+            (Some((v, k)), Some(_)) => Some(encode_cursor(v, k)),
+            _ => None,
+        };
+        Ok(IndexPage { entries, next })
+    }
+
+    /// The stored-value bound for an inclusive query limit.
+    fn bound(&self, value: Option<&str>) -> Result<Bound<String>> {
+        match value {
+            None => Ok(Bound::Unbounded),
+            Some(v) => self.encode(v).map(Bound::Included).ok_or_else(|| {
+                IcebergError::InvalidQuery(format!(
+                    "{:?} is not a valid {} for index {}",
+                    v, self.kind, self.name
+                ))
+            }),
+        }
+    }
+
+    /// Whether `select` can answer `condition` with a superset of the keys
+    /// whose values match it.
+    fn serves(&self, condition: &Condition) -> bool {
+        if self.source != IndexSource::Value || self.field_path != condition.field {
+            return false;
+        }
+        let numeric = condition.number().is_some();
+        match (self.kind, condition.op) {
+            (IndexKind::Float, Op::Prefix) => false,
+            (IndexKind::Float, _) => numeric,
+            (IndexKind::String, Op::Eq) => !numeric,
+            (IndexKind::String, _) => !numeric && self.collation == Collation::Binary,
+            (IndexKind::Integer | IndexKind::Date, _) => false,
         }
+    }
+
+    /// The keys whose indexed values satisfy `condition`.
+    fn select(&self, condition: &Condition) -> BTreeSet<String> {
+        let value = &condition.value;
+        let bound = |make: fn(String) -> Bound<String>| match self.encode(value) {
+            Some(v) => make(v),
+            None => Bound::Unbounded,
+        };
+        let keys = match condition.op {
+            Op::Eq => self.lookup(value),
+            Op::Prefix => self.prefix_lookup(value),
+            Op::Gt => self.keys_in(bound(Bound::Excluded), Bound::Unbounded),
+            Op::Gte => self.keys_in(bound(Bound::Included), Bound::Unbounded),
+            Op::Lt => self.keys_in(Bound::Unbounded, bound(Bound::Excluded)),
+            Op::Lte => self.keys_in(Bound::Unbounded, bound(Bound::Included)),
+        };
+        keys.into_iter().collect()
+    }
+
+    /// Keys with a stored value within the bounds, sorted.
+    fn keys_in(&self, start: Bound<String>, end: Bound<String>) -> Vec<String> {
+        if reversed(&start, &end) {
+            return Vec::new();
+        }
+        let mut result: Vec<String> = self
+            .entries
+            .range((start, end))
+            .flat_map(|(_, keys)| keys.iter().cloned())
+            .collect();
+        // A key indexed under several array elements is listed once.
         result.sort();
+        result.dedup();
         result
     }
 
     /// Prefix lookup on the indexed field values.
     pub fn prefix_lookup(&self, prefix: &str) -> Vec<String> {
+        let prefix = self.fold(prefix);
         let mut result = Vec::new();
         for (val, keys) in &self.entries {
-            if val.starts_with(prefix) {
+            if self.decode(val).starts_with(&prefix) {
                 result.extend(keys.iter().cloned());
             }
         }
         result.sort();
+        result.dedup();
         result
     }
 
-    /// Get all distinct indexed values.
+    /// Get all distinct indexed values, in the index's ordering.
     pub fn distinct_values(&self) -> Vec<String> {
-        self.entries.keys().cloned().collect()
+        self.entries.keys().map(|v| self.decode(v)).collect()
     }
 
     /// Number of distinct indexed values.
@@ -99,32 +765,195 @@ impl SecondaryIndex {
         self.entries.len()
     }
 
+    /// Every primary key with an entry in the index.
+    pub fn indexed_keys(&self) -> BTreeSet<&str> {
+        self.entries
+            .values()
+            .flat_map(|keys| keys.iter().map(String::as_str))
+            .collect()
+    }
+
     /// Total number of indexed key references.
     pub fn total_entries(&self) -> usize {
         self.entries.values().map(|s| s.len()).sum()
     }
 
-    /// Extract a field value from a JSON byte slice.
-    fn extract_field(&self, value: &[u8]) -> Option<String> {
-        let parsed: serde_json::Value = serde_json::from_slice(value).ok()?;
-        let parts: Vec<&str> = self.field_path.split('.').collect();
-        let mut current = &parsed;
-        for part in parts {
-            current = current.get(part)?;
+    /// The `n` values indexing the most keys, with their key counts, most
+    /// frequent first and in index order among equals.
+    pub fn top_values(&self, n: usize) -> Vec<(String, usize)> {
+        let mut counts: Vec<(&String, usize)> = self
+            .entries
+            .iter()
+            .map(|(v, keys)| (v, keys.len()))
+            .collect();
+        counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        counts
+            .into_iter()
+            .take(n)
+            .map(|(v, count)| (self.decode(v), count))
+            .collect()
+    }
+
+    /// Rough number of bytes the index occupies in memory.
+    pub fn memory_bytes(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(v, keys)| {
+                v.len()
+                    + ENTRY_OVERHEAD
+                    + keys.iter().map(|k| k.len() + ENTRY_OVERHEAD).sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// The values to index `primary_key` with `value` under.
+    fn extract(&self, primary_key: &str, value: &[u8]) -> Vec<String> {
+        match &self.source {
+            IndexSource::Value => self.extract_field(value),
+            IndexSource::Key {
+                delimiter,
+                position,
+            } => primary_key
+                .split(delimiter.as_str())
+                .nth(*position)
+                .map(String::from)
+                .into_iter()
+                .collect(),
         }
-        match current {
-            serde_json::Value::String(s) => Some(s.clone()),
-            serde_json::Value::Number(n) => Some(n.to_string()),
-            serde_json::Value::Bool(b) => Some(b.to_string()),
-            _ => Some(current.to_string()),
+    }
+
+    /// Extract the field values from a JSON byte slice: one for a scalar
+    /// field, one per element for an array, none if the field is missing.
+    fn extract_field(&self, value: &[u8]) -> Vec<String> {
+        let Ok(parsed) = serde_json::from_slice::<serde_json::Value>(value) else {
+            return Vec::new();
+        };
+        match json_field(&parsed, &self.field_path) {
+            Some(serde_json::Value::Array(items)) => items.iter().map(field_string).collect(),
+            Some(field) => vec![field_string(field)],
+            None => Vec::new(),
         }
     }
 }
 
+/// The field at a dotted path (e.g. `address.city`) in a JSON value.
+pub(crate) fn json_field<'a>(
+    value: &'a serde_json::Value,
+    field_path: &str,
+) -> Option<&'a serde_json::Value> {
+    field_path
+        .split('.')
+        .try_fold(value, |current, part| current.get(part))
+}
+
+/// The indexes whose names are in namespace `ns`, with their names.
+fn in_namespace<'a, T>(
+    indexes: &'a mut BTreeMap<String, T>,
+    ns: Option<&'a str>,
+) -> impl Iterator<Item = (&'a String, &'a mut T)> + 'a {
+    indexes
+        .iter_mut()
+        .filter(move |(name, _)| namespace::split(name).0 == ns)
+}
+
+/// The string a JSON field value is indexed under.
+pub(crate) fn field_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Number(n) => n.to_string(),
+        serde_json::Value::Bool(b) => b.to_string(),
+        _ => value.to_string(),
+    }
+}
+
+/// Directory holding a snapshot and a delta log per index.
+pub(crate) const INDEX_DIR: &str = "indexes";
+/// Where every index used to be stored, in one file rewritten on each
+/// flush. Read once to migrate, then removed.
+pub(crate) const LEGACY_INDEXES_FILE: &str = "indexes.json";
+/// A delta log is folded into its snapshot once it has more lines than
+/// this or than the index has keys, whichever is larger.
+const LOG_COMPACT_MIN: usize = 1024;
+
+/// One change to an index, as a line of its delta log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Delta {
+    /// The key is now indexed under exactly these stored values.
+    Put { key: String, values: Vec<String> },
+    /// The key now has this embedding.
+    Vector { key: String, vector: Vec<f32> },
+    /// The key is no longer indexed.
+    Remove { key: String },
+}
+
+/// Unsaved changes to one index.
+#[derive(Debug, Clone)]
+enum Pending {
+    /// Write a fresh snapshot.
+    Rewrite,
+    /// Append to the delta log.
+    Deltas(Vec<Delta>),
+    /// Delete the index's files.
+    Dropped,
+}
+
+/// The delta log an index's snapshot is read with.
+#[derive(Debug, Clone, Copy, Default)]
+struct LogState {
+    /// Bumped by each snapshot, so a log is never replayed onto a snapshot
+    /// that already contains it.
+    generation: u64,
+    lines: usize,
+}
+
+/// On-disk form of one index, `indexes/<hex name>.json`.
+#[derive(Serialize, Deserialize)]
+struct Snapshot<S, V> {
+    generation: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secondary: Option<S>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vector: Option<V>,
+}
+
+/// File names are the hex of the index name, which may hold any character.
+fn file_stem(name: &str) -> String {
+    name.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn snapshot_key(stem: &str) -> String {
+    crate::backend::key(INDEX_DIR, &format!("{}.json", stem))
+}
+
+fn log_key(stem: &str, generation: u64) -> String {
+    crate::backend::key(INDEX_DIR, &format!("{}.{}.log", stem, generation))
+}
+
 /// Manages multiple secondary indexes for a database.
+///
+/// An index whose name is namespaced (see `namespace::qualify`) covers only
+/// the keys of that namespace, and stores them by their name within it;
+/// other indexes cover only the default namespace.
+///
+/// Each index is persisted on its own: `persist` appends the changes since
+/// the last call to the delta logs of the indexes they touched, and
+/// rewrites an index's snapshot only when it was created or rebuilt or its
+/// log has grown long.
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct IndexManager {
     indexes: BTreeMap<String, SecondaryIndex>,
+    /// Vector indexes; their names share one namespace with `indexes`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    vectors: BTreeMap<String, VectorIndex>,
+    #[serde(skip)]
+    pending: BTreeMap<String, Pending>,
+    #[serde(skip)]
+    logs: BTreeMap<String, LogState>,
+    /// Files to delete on the next `persist`: logs of older generations and
+    /// the legacy single file.
+    #[serde(skip)]
+    obsolete: Vec<String>,
 }
 
 impl IndexManager {
@@ -134,42 +963,180 @@ impl IndexManager {
 
     /// Create a new secondary index.
     pub fn create_index(&mut self, name: &str, field_path: &str) -> Result<()> {
-        if self.indexes.contains_key(name) {
+        self.create_index_with(name, field_path, &IndexOptions::default())
+    }
+
+    /// Create a new secondary index with the given settings.
+    pub fn create_index_with(
+        &mut self,
+        name: &str,
+        field_path: &str,
+        options: &IndexOptions,
+    ) -> Result<()> {
+        self.check_new_name(name)?;
+        let idx = SecondaryIndex::with_options(name.to_string(), field_path.to_string(), options);
+        self.indexes.insert(name.to_string(), idx);
+        self.pending.insert(name.to_string(), Pending::Rewrite);
+        Ok(())
+    }
+
+    /// Create a new vector index.
+    pub fn create_vector_index(
+        &mut self,
+        name: &str,
+        field_path: &str,
+        options: &VectorOptions,
+    ) -> Result<()> {
+        self.check_new_name(name)?;
+        let idx = VectorIndex::new(name.to_string(), field_path.to_string(), options)?;
+        self.vectors.insert(name.to_string(), idx);
+        self.pending.insert(name.to_string(), Pending::Rewrite);
+        Ok(())
+    }
+
+    /// Add an index built elsewhere; it must be up to date.
+    pub(crate) fn add_index(&mut self, index: SecondaryIndex) -> Result<()> {
+        self.check_new_name(&index.name)?;
+        self.pending.insert(index.name.clone(), Pending::Rewrite);
+        self.indexes.insert(index.name.clone(), index);
+        Ok(())
+    }
+
+    pub(crate) fn check_new_name(&self, name: &str) -> Result<()> {
+        if self.indexes.contains_key(name) || self.vectors.contains_key(name) {
             return Err(IcebergError::Corruption(format!(
                 "index already exists: {}",
                 name
             )));
         }
-        let idx = SecondaryIndex::new(name.to_string(), field_path.to_string());
-        self.indexes.insert(name.to_string(), idx);
         Ok(())
     }
 
     /// Drop an index.
     pub fn drop_index(&mut self, name: &str) -> Result<()> {
-        if self.indexes.remove(name).is_none() {
+        if self.indexes.remove(name).is_none() && self.vectors.remove(name).is_none() {
             return Err(IcebergError::Corruption(format!(
                 "index not found: {}",
                 name
             )));
         }
+        self.pending.insert(name.to_string(), Pending::Dropped);
         Ok(())
     }
 
-    /// Index a key-value pair across all indexes.
+    /// Index a key-value pair across all indexes of its namespace.
     pub fn on_put(&mut self, key: &str, value: &[u8]) {
-        for idx in self.indexes.values_mut() {
-            idx.index_entry(key, value);
+        let (ns, key) = namespace::split(key);
+        let mut deltas = Vec::new();
+        for (name, idx) in in_namespace(&mut self.indexes, ns) {
+            let values = idx.stored_values(key, value);
+            if idx.set_entry(key, &values) {
+                let key = key.to_string();
+                deltas.push((name.clone(), Delta::Put { key, values }));
+            }
         }
+        for (name, idx) in in_namespace(&mut self.vectors, ns) {
+            let vector = idx.extract(value);
+            if idx.set_vector(key, vector.clone()) {
+                let key = key.to_string();
+                let delta = match vector {
+                    Some(vector) => Delta::Vector { key, vector },
+                    None => Delta::Remove { key },
+                };
+                deltas.push((name.clone(), delta));
+            }
+        }
+        self.record(deltas);
     }
 
-    /// Remove a key from all indexes.
+    /// Remove a key from all indexes of its namespace.
     pub fn on_delete(&mut self, key: &str) {
-        for idx in self.indexes.values_mut() {
-            idx.remove_key(key);
+        let (ns, key) = namespace::split(key);
+        let mut deltas = Vec::new();
+        for (name, idx) in in_namespace(&mut self.indexes, ns) {
+            if idx.unindex(key) {
+                deltas.push((name.clone(), Delta::Remove { key: key.into() }));
+            }
+        }
+        for (name, idx) in in_namespace(&mut self.vectors, ns) {
+            if idx.set_vector(key, None) {
+                deltas.push((name.clone(), Delta::Remove { key: key.into() }));
+            }
+        }
+        self.record(deltas);
+    }
+
+    /// Queue deltas for the logs of their indexes; a pending rewrite
+    /// already covers them.
+    fn record(&mut self, deltas: Vec<(String, Delta)>) {
+        for (name, delta) in deltas {
+            let pending = self
+                .pending
+                .entry(name)
+                .or_insert_with(|| Pending::Deltas(Vec::new()));
+            if let Pending::Deltas(queued) = pending {
+                queued.push(delta);
+            }
         }
     }
 
+    /// Fail with `UniqueViolation` if putting `value` under `key` would
+    /// break a unique index of the key's namespace.
+    pub fn check_unique(&self, key: &str, value: &[u8]) -> Result<()> {
+        let (ns, local) = namespace::split(key);
+        for (name, idx) in &self.indexes {
+            if namespace::split(name).0 != ns {
+                continue;
+            }
+            if let Some((value, holder)) = idx.conflict(local, value) {
+                return Err(IcebergError::UniqueViolation {
+                    index: namespace::split(name).1.to_string(),
+                    value,
+                    key: holder.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// `check_unique` for writes applied in order, each a key with its new
+    /// value or `None` for a delete, so that they are also checked against
+    /// each other.
+    pub fn check_unique_all<'w>(
+        &self,
+        writes: impl IntoIterator<Item = (&'w str, Option<&'w [u8]>)>,
+    ) -> Result<()> {
+        // Only unique indexes can fail, and they are rare: stage copies of
+        // those rather than of every index.
+        let mut unique: BTreeMap<String, SecondaryIndex> = self
+            .indexes
+            .iter()
+            .filter(|(_, idx)| idx.unique)
+            .map(|(name, idx)| (name.clone(), idx.clone()))
+            .collect();
+        if unique.is_empty() {
+            return Ok(());
+        }
+        for (key, value) in writes {
+            let (ns, local) = namespace::split(key);
+            for (name, idx) in in_namespace(&mut unique, ns) {
+                let Some(value) = value else {
+                    idx.remove_key(local);
+                    continue;
+                };
+                if let Some((value, holder)) = idx.conflict(local, value) {
+                    return Err(IcebergError::UniqueViolation {
+                        index: namespace::split(name).1.to_string(),
+                        value,
+                        key: holder.to_string(),
+                    });
+                }
+                idx.index_entry(local, value);
+            }
+        }
+        Ok(())
+    }
+
     /// Query an index by exact value.
     pub fn query(&self, index_name: &str, value: &str) -> Result<Vec<String>> {
         let idx = self
@@ -188,30 +1155,362 @@ impl IndexManager {
         Ok(idx.prefix_lookup(prefix))
     }
 
+    /// Query an index for values between `min` and `max`, inclusive.
+    pub fn query_range(
+        &self,
+        index_name: &str,
+        min: Option<&str>,
+        max: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let idx = self
+            .indexes
+            .get(index_name)
+            .ok_or_else(|| IcebergError::Corruption(format!("index not found: {}", index_name)))?;
+        idx.range(min, max)
+    }
+
+    /// Query an index for one page of entries between `min` and `max`.
+    pub fn query_page(
+        &self,
+        index_name: &str,
+        min: Option<&str>,
+        max: Option<&str>,
+        options: &PageOptions,
+    ) -> Result<IndexPage> {
+        let idx = self
+            .indexes
+            .get(index_name)
+            .ok_or_else(|| IcebergError::Corruption(format!("index not found: {}", index_name)))?;
+        idx.page(min, max, options)
+    }
+
+    /// Query a vector index for the `k` nearest vectors to `query`.
+    pub fn query_vector(
+        &mut self,
+        index_name: &str,
+        query: &[f32],
+        k: usize,
+    ) -> Result<Vec<(String, f32)>> {
+        let idx = self
+            .vectors
+            .get_mut(index_name)
+            .ok_or_else(|| IcebergError::Corruption(format!("index not found: {}", index_name)))?;
+        idx.search(query, k)
+    }
+
+    /// The values and keys of an index of namespace `ns` whose values are
+    /// exactly the string forms of `field_path`, if there is one.
+    pub(crate) fn postings(
+        &self,
+        ns: Option<&str>,
+        field_path: &str,
+    ) -> Option<impl Iterator<Item = (&String, &BTreeSet<String>)>> {
+        self.indexes
+            .iter()
+            .find(|(name, idx)| {
+                namespace::split(name).0 == ns
+                    && idx.source == IndexSource::Value
+                    && idx.field_path == field_path
+                    && idx.kind == IndexKind::String
+                    && idx.collation == Collation::Binary
+            })
+            .map(|(_, idx)| idx.entries.iter())
+    }
+
+    /// Name, within namespace `ns`, of an index of `ns` that can narrow
+    /// down the keys matching `condition`.
+    pub(crate) fn index_for(&self, ns: Option<&str>, condition: &Condition) -> Option<String> {
+        self.indexes
+            .iter()
+            .find_map(|(name, idx)| match namespace::split(name) {
+                (found, local) if found == ns && idx.serves(condition) => Some(local.to_string()),
+                _ => None,
+            })
+    }
+
+    /// Keys of namespace `ns` that index `name` of that namespace returns
+    /// for `condition`.
+    pub(crate) fn select(
+        &self,
+        ns: Option<&str>,
+        name: &str,
+        condition: &Condition,
+    ) -> Option<BTreeSet<String>> {
+        let name = match ns {
+            Some(ns) => namespace::qualify(ns, name),
+            None => name.to_string(),
+        };
+        Some(self.indexes.get(&name)?.select(condition))
+    }
+
+    /// Statistics of an index, with the size of its files in `backend`.
+    pub(crate) fn stats(&self, name: &str, backend: &dyn Backend) -> Result<IndexStats> {
+        let stem = file_stem(name);
+        let generation = self.logs.get(name).map_or(0, |log| log.generation);
+        let disk_bytes = backend.size(&snapshot_key(&stem))?.unwrap_or(0)
+            + backend.size(&log_key(&stem, generation))?.unwrap_or(0);
+        let local = namespace::split(name).1.to_string();
+        if let Some(idx) = self.indexes.get(name) {
+            return Ok(IndexStats {
+                name: local,
+                field_path: idx.field_path.clone(),
+                kind: Some(idx.kind),
+                cardinality: idx.cardinality(),
+                total_entries: idx.total_entries(),
+                keys: idx.indexed_keys().len(),
+                top_values: idx.top_values(STATS_TOP_VALUES),
+                memory_bytes: idx.memory_bytes(),
+                disk_bytes,
+            });
+        }
+        let idx = self
+            .vectors
+            .get(name)
+            .ok_or_else(|| IcebergError::Corruption(format!("index not found: {}", name)))?;
+        Ok(IndexStats {
+            name: local,
+            field_path: idx.field_path.clone(),
+            kind: None,
+            cardinality: idx.len(),
+            total_entries: idx.len(),
+            keys: idx.len(),
+            top_values: Vec::new(),
+            memory_bytes: idx.memory_bytes(),
+            disk_bytes,
+        })
+    }
+
     /// Get an index by name.
     pub fn get_index(&self, name: &str) -> Option<&SecondaryIndex> {
         self.indexes.get(name)
     }
 
-    /// List all index names.
+    /// Get a vector index by name.
+    pub fn get_vector_index(&self, name: &str) -> Option<&VectorIndex> {
+        self.vectors.get(name)
+    }
+
+    /// List all index names, vector indexes included.
     pub fn list_indexes(&self) -> Vec<String> {
-        self.indexes.keys().cloned().collect()
+        let mut names: Vec<String> = self
+            .indexes
+            .keys()
+            .chain(self.vectors.keys())
+            .cloned()
+            .collect();
+        names.sort();
+        names
     }
 
     /// Rebuild all indexes from a full set of key-value pairs.
     pub fn rebuild_all(&mut self, entries: &[(String, Vec<u8>)]) {
-        for idx in self.indexes.values_mut() {
+        for name in self.list_indexes() {
+            self.rebuild_index(&name, entries);
+        }
+    }
+
+    /// Rebuild one index from a full set of key-value pairs.
+    pub fn rebuild_index(&mut self, name: &str, entries: &[(String, Vec<u8>)]) {
+        let ns = namespace::split(name).0;
+        let in_ns = entries
+            .iter()
+            .filter_map(|(key, value)| match namespace::split(key) {
+                (found, key) if found == ns => Some((key, value)),
+                _ => None,
+            });
+        if let Some(idx) = self.indexes.get_mut(name) {
             idx.entries.clear();
-            for (key, value) in entries {
+            for (key, value) in in_ns {
+                idx.index_entry(key, value);
+            }
+        } else if let Some(idx) = self.vectors.get_mut(name) {
+            idx.clear();
+            for (key, value) in in_ns {
                 idx.index_entry(key, value);
             }
+        } else {
+            return;
+        }
+        self.pending.insert(name.to_string(), Pending::Rewrite);
+    }
+
+    /// Load the indexes persisted in `backend`, migrating the legacy single
+    /// file if there are no per-index files yet. Snapshots that cannot be
+    /// read are left out and returned as `(file, reason)`.
+    pub(crate) fn load(backend: &dyn Backend) -> Result<(Self, Vec<(String, String)>)> {
+        let mut mgr = Self::new();
+        let mut corrupt = Vec::new();
+        let files = backend.list(INDEX_DIR)?;
+        if files.is_empty() {
+            if let Some(data) = backend.read(LEGACY_INDEXES_FILE)? {
+                match serde_json::from_slice::<Self>(&data) {
+                    Ok(legacy) => {
+                        mgr = legacy;
+                        for name in mgr.list_indexes() {
+                            mgr.pending.insert(name, Pending::Rewrite);
+                        }
+                        mgr.obsolete.push(LEGACY_INDEXES_FILE.into());
+                    }
+                    Err(e) => corrupt.push((LEGACY_INDEXES_FILE.into(), e.to_string())),
+                }
+            }
+            return Ok((mgr, corrupt));
+        }
+        let mut logs: BTreeSet<&str> = files
+            .iter()
+            .filter_map(|f| f.strip_suffix(".log"))
+            .collect();
+        for file in &files {
+            let Some(stem) = file.strip_suffix(".json") else {
+                continue;
+            };
+            let key = snapshot_key(stem);
+            let snapshot = backend
+                .read(&key)?
+                .map(|data| serde_json::from_slice::<Snapshot<SecondaryIndex, VectorIndex>>(&data));
+            let snapshot = match snapshot {
+                Some(Ok(snapshot)) => snapshot,
+                Some(Err(e)) => {
+                    corrupt.push((key, e.to_string()));
+                    continue;
+                }
+                None => continue,
+            };
+            let generation = snapshot.generation;
+            let name = match (snapshot.secondary, snapshot.vector) {
+                (Some(idx), _) => {
+                    let name = idx.name.clone();
+                    mgr.indexes.insert(name.clone(), idx);
+                    name
+                }
+                (None, Some(idx)) => {
+                    let name = idx.name.clone();
+                    mgr.vectors.insert(name.clone(), idx);
+                    name
+                }
+                (None, None) => {
+                    corrupt.push((key, "holds no index".into()));
+                    continue;
+                }
+            };
+            logs.remove(format!("{}.{}", stem, generation).as_str());
+            let log = backend
+                .read(&log_key(stem, generation))?
+                .unwrap_or_default();
+            let mut state = LogState {
+                generation,
+                lines: 0,
+            };
+            let mut torn = false;
+            for line in log.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+                match serde_json::from_slice::<Delta>(line) {
+                    Ok(delta) => {
+                        mgr.apply(&name, delta);
+                        state.lines += 1;
+                    }
+                    // A write cut short by a crash; the WAL still holds it.
+                    Err(_) => torn = true,
+                }
+            }
+            if torn || !log.is_empty() && !log.ends_with(b"\n") {
+                // Appending after a torn line would corrupt the next one.
+                mgr.pending.insert(name.clone(), Pending::Rewrite);
+            }
+            mgr.logs.insert(name, state);
+        }
+        mgr.obsolete.extend(
+            logs.into_iter()
+                .map(|log| crate::backend::key(INDEX_DIR, &format!("{}.log", log))),
+        );
+        Ok((mgr, corrupt))
+    }
+
+    fn apply(&mut self, name: &str, delta: Delta) {
+        match delta {
+            Delta::Put { key, values } => {
+                if let Some(idx) = self.indexes.get_mut(name) {
+                    idx.set_entry(&key, &values);
+                }
+            }
+            Delta::Vector { key, vector } => {
+                if let Some(idx) = self.vectors.get_mut(name) {
+                    idx.set_vector(&key, Some(vector));
+                }
+            }
+            Delta::Remove { key } => {
+                if let Some(idx) = self.indexes.get_mut(name) {
+                    idx.unindex(&key);
+                } else if let Some(idx) = self.vectors.get_mut(name) {
+                    idx.set_vector(&key, None);
+                }
+            }
+        }
+    }
+
+    /// Write the changes since the last call to `backend`: deltas are
+    /// appended to their index's log, and created, rebuilt or long-logged
+    /// indexes get a new snapshot.
+    pub(crate) fn persist(&mut self, backend: &dyn Backend) -> Result<()> {
+        let names: Vec<String> = self.pending.keys().cloned().collect();
+        for name in names {
+            let stem = file_stem(&name);
+            let state = self.logs.get(&name).copied().unwrap_or_default();
+            let size = match (self.indexes.get(&name), self.vectors.get(&name)) {
+                (Some(idx), _) => idx.entries.values().map(BTreeSet::len).sum(),
+                (None, Some(idx)) => idx.len(),
+                (None, None) => 0,
+            };
+            match &self.pending[&name] {
+                Pending::Dropped => {
+                    backend.delete(&snapshot_key(&stem))?;
+                    backend.delete(&log_key(&stem, state.generation))?;
+                    self.logs.remove(&name);
+                }
+                Pending::Deltas(deltas)
+                    if state.lines + deltas.len() <= LOG_COMPACT_MIN.max(size) =>
+                {
+                    let mut data = Vec::new();
+                    for delta in deltas {
+                        serde_json::to_writer(&mut data, delta)?;
+                        data.push(b'\n');
+                    }
+                    let log = log_key(&stem, state.generation);
+                    backend.append(&log, &data)?;
+                    backend.sync(&log)?;
+                    let lines = state.lines + deltas.len();
+                    self.logs.insert(name.clone(), LogState { lines, ..state });
+                }
+                Pending::Rewrite | Pending::Deltas(_) => {
+                    let generation = state.generation + 1;
+                    let snapshot = Snapshot {
+                        generation,
+                        secondary: self.indexes.get(&name),
+                        vector: self.vectors.get(&name),
+                    };
+                    backend.write(&snapshot_key(&stem), &serde_json::to_vec(&snapshot)?)?;
+                    backend.delete(&log_key(&stem, state.generation))?;
+                    self.logs.insert(
+                        name.clone(),
+                        LogState {
+                            generation,
+                            lines: 0,
+                        },
+                    );
+                }
+            }
+            self.pending.remove(&name);
+        }
+        for file in std::mem::take(&mut self.obsolete) {
+            backend.delete(&file)?;
         }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::backend::MemoryBackend;
 
     fn json_value(city: &str, age: u32) -> Vec<u8> {
         serde_json::to_vec(&serde_json::json!({
@@ -255,6 +1554,184 @@ mod tests {
         assert!(idx.lookup("Zurich").is_empty());
     }
 
+    #[test]
+    fn array_fields_index_each_element() {
+        let mut idx = SecondaryIndex::new("tags".into(), "tags".into());
+        let tags =
+            |t: serde_json::Value| serde_json::to_vec(&serde_json::json!({ "tags": t })).unwrap();
+        idx.index_entry("a", &tags(serde_json::json!(["red", "green"])));
+        idx.index_entry("b", &tags(serde_json::json!(["green", "green"])));
+        idx.index_entry("c", &tags(serde_json::json!("red")));
+        idx.index_entry("d", &tags(serde_json::json!([])));
+
+        assert_eq!(idx.lookup("red"), vec!["a", "c"]);
+        assert_eq!(idx.lookup("green"), vec!["a", "b"]);
+        assert_eq!(idx.prefix_lookup(""), vec!["a", "b", "c"]);
+        assert_eq!(idx.distinct_values(), vec!["green", "red"]);
+
+        idx.index_entry("a", &tags(serde_json::json!(["blue"])));
+        assert_eq!(idx.lookup("red"), vec!["c"]);
+        assert_eq!(idx.lookup("blue"), vec!["a"]);
+
+        let mut ages = SecondaryIndex::with_options(
+            "ages".into(),
+            "tags".into(),
+            &IndexOptions {
+                kind: IndexKind::Integer,
+                ..Default::default()
+            },
+        );
+        ages.index_entry("x", &tags(serde_json::json!([3, 40])));
+        assert_eq!(ages.range(Some("1"), Some("50")).unwrap(), vec!["x"]);
+    }
+
+    #[test]
+    fn collations_fold_and_order_values() {
+        let index = |collation| {
+            let opts = IndexOptions {
+                collation,
+                ..Default::default()
+            };
+            let mut idx = SecondaryIndex::with_options("name".into(), "name".into(), &opts);
+            for (key, name) in [
+                ("a", "Zurich"),
+                ("b", "zurich"),
+                ("c", "item10"),
+                ("d", "item2"),
+            ] {
+                let value = serde_json::to_vec(&serde_json::json!({ "name": name })).unwrap();
+                idx.index_entry(key, &value);
+            }
+            idx
+        };
+
+        let binary = index(Collation::Binary);
+        assert_eq!(binary.lookup("zurich"), vec!["b"]);
+        assert_eq!(
+            binary.range(Some("item"), Some("item9")).unwrap(),
+            vec!["c", "d"]
+        );
+
+        let nocase = index(Collation::CaseInsensitive);
+        assert_eq!(nocase.lookup("ZURICH"), vec!["a", "b"]);
+        assert_eq!(nocase.prefix_lookup("Zu"), vec!["a", "b"]);
+        assert_eq!(nocase.distinct_values(), vec!["item10", "item2", "zurich"]);
+
+        let numeric = index(Collation::Numeric);
+        assert_eq!(
+            numeric.distinct_values(),
+            vec!["Zurich", "item2", "item10", "zurich"]
+        );
+        assert_eq!(numeric.lookup("item002"), vec!["d"]);
+        assert_eq!(
+            numeric.range(Some("item3"), Some("item99")).unwrap(),
+            vec!["c"]
+        );
+        assert_eq!(numeric.prefix_lookup("item1"), vec!["c"]);
+
+        assert_eq!(Collation::Casefold.fold("Straße"), "strasse");
+        assert_eq!(
+            Collation::Casefold.fold("ΣΟΦΟΣ"),
+            Collation::Casefold.fold("σοφος")
+        );
+        assert_eq!(
+            "nocase".parse::<Collation>(),
+            Ok(Collation::CaseInsensitive)
+        );
+    }
+
+    #[test]
+    fn key_source_indexes_key_parts() {
+        let opts = IndexOptions {
+            source: IndexSource::Key {
+                delimiter: ":".into(),
+                position: 1,
+            },
+            ..Default::default()
+        };
+        let mut idx = SecondaryIndex::with_options("customer".into(), String::new(), &opts);
+        idx.index_entry("order:alice:1", b"not json");
+        idx.index_entry("order:alice:2", &json_value("Zurich", 30));
+        idx.index_entry("order:bob:3", b"");
+        idx.index_entry("order", b"");
+
+        assert_eq!(idx.lookup("alice"), vec!["order:alice:1", "order:alice:2"]);
+        assert_eq!(idx.distinct_values(), vec!["alice", "bob"]);
+        idx.remove_key("order:bob:3");
+        assert!(idx.lookup("bob").is_empty());
+    }
+
+    #[test]
+    fn pages_follow_value_order_and_cursors() {
+        let opts = IndexOptions {
+            kind: IndexKind::Integer,
+            ..Default::default()
+        };
+        let mut idx = SecondaryIndex::with_options("age".into(), "age".into(), &opts);
+        for (key, age) in [
+            ("u:1", 30),
+            ("u:2", 9),
+            ("u:3", 30),
+            ("u:4", 100),
+            ("u:5", 41),
+        ] {
+            idx.index_entry(key, &json_value("Zurich", age));
+        }
+        let entries = |page: &IndexPage| -> Vec<String> {
+            page.entries
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect()
+        };
+
+        let first = idx
+            .page(
+                None,
+                None,
+                &PageOptions {
+                    limit: Some(2),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(entries(&first), vec!["u:2=9", "u:1=30"]);
+        let rest = PageOptions {
+            after: first.next.clone(),
+            ..Default::default()
+        };
+        let second = idx.page(None, None, &rest).unwrap();
+        assert_eq!(entries(&second), vec!["u:3=30", "u:5=41", "u:4=100"]);
+        assert_eq!(second.next, None);
+
+        let desc = PageOptions {
+            order: Order::Desc,
+            limit: Some(1),
+            offset: 1,
+            after: None,
+        };
+        let page = idx.page(Some("10"), Some("99"), &desc).unwrap();
+        assert_eq!(entries(&page), vec!["u:3=30"]);
+        let desc = PageOptions {
+            after: page.next,
+            limit: None,
+            offset: 0,
+            ..desc
+        };
+        let page = idx.page(Some("10"), Some("99"), &desc).unwrap();
+        assert_eq!(entries(&page), vec!["u:1=30"]);
+
+        let foreign = PageOptions {
+            after: first.next,
+            ..Default::default()
+        };
+        assert!(idx.page(Some("40"), None, &foreign).is_err());
+        let garbage = PageOptions {
+            after: Some("zz".into()),
+            ..Default::default()
+        };
+        assert!(idx.page(None, None, &garbage).is_err());
+    }
+
     #[test]
     fn nested_field_path() {
         let mut idx = SecondaryIndex::new("country_idx".into(), "address.country".into());
@@ -360,4 +1837,182 @@ mod tests {
         mgr.create_index("b", "f2").unwrap();
         assert_eq!(mgr.list_indexes(), vec!["a", "b"]);
     }
+
+    #[test]
+    fn typed_indexes_order_numbers_and_dates() {
+        let opts = |kind| IndexOptions {
+            kind,
+            ..Default::default()
+        };
+        let mut ages =
+            SecondaryIndex::with_options("age".into(), "age".into(), &opts(IndexKind::Integer));
+        for (key, age) in [("u:1", 9), ("u:2", 10), ("u:3", 30), ("u:4", 31)] {
+            ages.index_entry(key, &json_value("Zurich", age));
+        }
+        assert_eq!(
+            ages.range(Some("9"), Some("30")).unwrap(),
+            vec!["u:1", "u:2", "u:3"]
+        );
+        assert_eq!(ages.range(None, Some("9")).unwrap(), vec!["u:1"]);
+        assert!(ages.range(Some("31"), Some("9")).unwrap().is_empty());
+        assert!(ages.range(Some("nine"), None).is_err());
+        assert_eq!(ages.lookup("10"), vec!["u:2"]);
+        assert_eq!(ages.distinct_values(), vec!["9", "10", "30", "31"]);
+
+        let mut temps =
+            SecondaryIndex::with_options("t".into(), "t".into(), &opts(IndexKind::Float));
+        for (key, t) in [("a", -2.5), ("b", -0.0), ("c", 1.5), ("d", 10.0)] {
+            temps.index_entry(
+                key,
+                &serde_json::to_vec(&serde_json::json!({ "t": t })).unwrap(),
+            );
+        }
+        assert_eq!(temps.range(Some("-3"), Some("0")).unwrap(), vec!["a", "b"]);
+        assert_eq!(temps.range(Some("1"), None).unwrap(), vec!["c", "d"]);
+        assert_eq!(temps.distinct_values(), vec!["-2.5", "0", "1.5", "10"]);
+
+        let mut dates =
+            SecondaryIndex::with_options("d".into(), "d".into(), &opts(IndexKind::Date));
+        for (key, d) in [
+            ("x", "2024-01-05"),
+            ("y", "2024-01-05T12:00:00+02:00"),
+            ("z", "2023-12-31"),
+        ] {
+            dates.index_entry(
+                key,
+                &serde_json::to_vec(&serde_json::json!({ "d": d })).unwrap(),
+            );
+        }
+        dates.index_entry("bad", br#"{"d":"yesterday"}"#);
+        assert_eq!(
+            dates.range(Some("2024-01-01"), None).unwrap(),
+            vec!["x", "y"]
+        );
+        assert_eq!(dates.lookup("2023-12-31T00:00:00Z"), vec!["z"]);
+        assert_eq!(dates.total_entries(), 3);
+    }
+
+    #[test]
+    fn unique_index_reports_conflicts() {
+        let mut mgr = IndexManager::new();
+        let unique = IndexOptions {
+            unique: true,
+            ..Default::default()
+        };
+        mgr.create_index_with("city", "city", &unique).unwrap();
+        mgr.on_put("u:1", &json_value("Zurich", 30));
+
+        assert!(mgr.check_unique("u:1", &json_value("Zurich", 31)).is_ok());
+        assert!(mgr.check_unique("u:2", &json_value("Berlin", 30)).is_ok());
+        let err = mgr
+            .check_unique("u:2", &json_value("Zurich", 30))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            IcebergError::UniqueViolation { ref key, ref value, .. } if key == "u:1" && value == "Zurich"
+        ));
+        // Keys of other namespaces are not held to the default namespace's index.
+        let other = namespace::qualify("users", "u:2");
+        assert!(mgr.check_unique(&other, &json_value("Zurich", 30)).is_ok());
+
+        // Batched writes are checked against each other too.
+        let bern = json_value("Bern", 30);
+        let zurich = json_value("Zurich", 30);
+        assert!(mgr
+            .check_unique_all([("u:2", Some(&bern[..])), ("u:3", Some(&bern[..]))])
+            .is_err());
+        assert!(mgr
+            .check_unique_all([("u:1", None), ("u:2", Some(&zurich[..]))])
+            .is_ok());
+        assert!(mgr
+            .check_unique_all([("u:2", Some(&bern[..])), ("u:2", Some(&zurich[..]))])
+            .is_err());
+    }
+
+    #[test]
+    fn persist_appends_deltas_and_compacts_logs() {
+        let backend = MemoryBackend::new();
+        let mut mgr = IndexManager::new();
+        mgr.create_index("city", "city").unwrap();
+        mgr.create_index("name", "name").unwrap();
+        mgr.persist(&backend).unwrap();
+        let name_snapshot = snapshot_key(&file_stem("name"));
+        let before = backend.read(&name_snapshot).unwrap();
+
+        // Values without a name leave the name index's files alone.
+        mgr.on_put("u:1", &json_value("Zurich", 30));
+        mgr.on_put("u:2", &json_value("Bern", 40));
+        mgr.on_delete("u:2");
+        mgr.persist(&backend).unwrap();
+        assert_eq!(backend.read(&name_snapshot).unwrap(), before);
+        assert!(!backend.exists(&log_key(&file_stem("name"), 1)).unwrap());
+        let city_log = log_key(&file_stem("city"), 1);
+        let log = backend.read(&city_log).unwrap().unwrap();
+        assert_eq!(
+            log.split(|b| *b == b'\n').filter(|l| !l.is_empty()).count(),
+            3
+        );
+
+        let (loaded, corrupt) = IndexManager::load(&backend).unwrap();
+        assert!(corrupt.is_empty());
+        assert_eq!(loaded.query("city", "Zurich").unwrap(), vec!["u:1"]);
+        assert!(loaded.query("city", "Bern").unwrap().is_empty());
+
+        // A log longer than the index is folded into a new snapshot; the
+        // next round starts a log of the new generation.
+        for round in 0..2 {
+            for i in 0..LOG_COMPACT_MIN {
+                let city = if round == 0 { "Basel" } else { "Chur" };
+                mgr.on_put(&format!("k{}", i), &json_value(city, 1));
+            }
+            mgr.persist(&backend).unwrap();
+        }
+        assert!(!backend.exists(&city_log).unwrap());
+        assert!(backend.exists(&log_key(&file_stem("city"), 2)).unwrap());
+        let (loaded, _) = IndexManager::load(&backend).unwrap();
+        assert_eq!(loaded.query("city", "Chur").unwrap().len(), LOG_COMPACT_MIN);
+        assert!(loaded.query("city", "Basel").unwrap().is_empty());
+
+        mgr.drop_index("city").unwrap();
+        mgr.persist(&backend).unwrap();
+        assert_eq!(
+            backend.list(INDEX_DIR).unwrap(),
+            vec![format!("{}.json", file_stem("name"))]
+        );
+    }
+
+    #[test]
+    fn load_migrates_legacy_file_and_survives_torn_logs() {
+        let backend = MemoryBackend::new();
+        let mut legacy = IndexManager::new();
+        legacy.create_index("city", "city").unwrap();
+        legacy.on_put("u:1", &json_value("Zurich", 30));
+        let data = serde_json::to_vec(&legacy).unwrap();
+        backend.write(LEGACY_INDEXES_FILE, &data).unwrap();
+
+        let (mut mgr, _) = IndexManager::load(&backend).unwrap();
+        assert_eq!(mgr.query("city", "Zurich").unwrap(), vec!["u:1"]);
+        mgr.persist(&backend).unwrap();
+        assert!(!backend.exists(LEGACY_INDEXES_FILE).unwrap());
+
+        mgr.on_put("u:2", &json_value("Zurich", 40));
+        mgr.persist(&backend).unwrap();
+        let log = log_key(&file_stem("city"), 1);
+        backend.append(&log, b"{\"put\":{\"key\"").unwrap();
+        let (mut mgr, corrupt) = IndexManager::load(&backend).unwrap();
+        assert!(corrupt.is_empty());
+        assert_eq!(mgr.query("city", "Zurich").unwrap(), vec!["u:1", "u:2"]);
+        // The torn log is replaced by a snapshot rather than appended to.
+        mgr.persist(&backend).unwrap();
+        assert!(!backend.exists(&log).unwrap());
+        let (mgr, _) = IndexManager::load(&backend).unwrap();
+        assert_eq!(mgr.query("city", "Zurich").unwrap(), vec!["u:1", "u:2"]);
+
+        backend
+            .write(&snapshot_key(&file_stem("city")), b"{")
+            .unwrap();
+        let (mgr, corrupt) = IndexManager::load(&backend).unwrap();
+        assert!(mgr.list_indexes().is_empty());
+        assert_eq!(corrupt.len(), 1);
+    }
 }
@@ -0,0 +1,131 @@
+//! Advisory lease locks on keys, for `Database::lock_key`.
+//!
+//! Locks only hold back writers that ask for them, such as
+//! `Database::put_as`; plain writes ignore them. A lock lapses at its
+//! expiry unless its holder renews it, so a crashed holder cannot keep a
+//! key locked for ever.
+
+use crate::error::{IcebergError, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// A lease on a key.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KeyLock {
+    pub key: String,
+    /// Who holds the lock, as named by the caller.
+    pub holder: String,
+    /// When the holder first took the lock; renewing keeps it.
+    pub acquired_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl KeyLock {
+    pub fn is_live_at(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at > now
+    }
+}
+
+/// Every lock taken and not yet released, live or lapsed, by key.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct KeyLocks {
+    locks: BTreeMap<String, KeyLock>,
+}
+
+impl KeyLocks {
+    /// The live lock on `key`, if any.
+    fn live(&self, key: &str, now: DateTime<Utc>) -> Option<&KeyLock> {
+        self.locks.get(key).filter(|lock| lock.is_live_at(now))
+    }
+
+    /// Fail with `KeyLocked` if someone other than `holder` has a live lock
+    /// on `key`.
+    pub(crate) fn check(&self, key: &str, holder: &str, now: DateTime<Utc>) -> Result<()> {
+        match self.live(key, now) {
+            Some(lock) if lock.holder != holder => Err(IcebergError::KeyLocked {
+                key: key.to_string(),
+                holder: lock.holder.clone(),
+                expires_at: lock.expires_at,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Take or renew the lock on `key` for `ttl` from `now`, dropping
+    /// lapsed locks along the way.
+    pub(crate) fn acquire(
+        &mut self,
+        key: &str,
+        holder: &str,
+        ttl: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<KeyLock> {
+        self.check(key, holder, now)?;
+        let ttl = chrono::Duration::from_std(ttl)
+            .map_err(|_| IcebergError::InvalidQuery("lock ttl out of range".into()))?;
+        let acquired_at = self
+            .live(key, now)
+            .map_or(now, |renewed| renewed.acquired_at);
+        self.locks.retain(|_, lock| lock.is_live_at(now));
+        let lock = KeyLock {
+            key: key.to_string(),
+            holder: holder.to_string(),
+            acquired_at,
+            expires_at: now + ttl,
+        };
+        self.locks.insert(key.to_string(), lock.clone());
+        Ok(lock)
+    }
+
+    /// Release `holder`'s lock on `key`, returning whether it held a live
+    /// one. Fails if another holder's lock is live.
+    pub(crate) fn release(&mut self, key: &str, holder: &str, now: DateTime<Utc>) -> Result<bool> {
+        self.check(key, holder, now)?;
+        let held = self.live(key, now).is_some();
+        self.locks.remove(key);
+        Ok(held)
+    }
+
+    /// The live locks, by key.
+    pub(crate) fn all_live(&self, now: DateTime<Utc>) -> Vec<KeyLock> {
+        self.locks
+            .values()
+            .filter(|lock| lock.is_live_at(now))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leases_exclude_other_holders_until_they_lapse() {
+        let mut locks = KeyLocks::default();
+        let now = Utc::now();
+        let ttl = Duration::from_secs(60);
+        let first = locks.acquire("k", "a", ttl, now).unwrap();
+        assert!(matches!(
+            locks.acquire("k", "b", ttl, now),
+            Err(IcebergError::KeyLocked { holder, .. }) if holder == "a"
+        ));
+        assert!(locks.check("k", "b", now).is_err());
+        assert!(locks.check("other", "b", now).is_ok());
+
+        let later = now + chrono::Duration::seconds(30);
+        let renewed = locks.acquire("k", "a", ttl, later).unwrap();
+        assert_eq!(renewed.acquired_at, first.acquired_at);
+        assert!(renewed.expires_at > first.expires_at);
+        assert!(locks.release("k", "b", later).is_err());
+
+        // Once lapsed, anyone may take the key.
+        let lapsed = later + chrono::Duration::seconds(61);
+        assert!(locks.all_live(lapsed).is_empty());
+        assert!(!locks.release("k", "b", lapsed).unwrap());
+        locks.acquire("k", "b", ttl, lapsed).unwrap();
+        assert!(locks.release("k", "b", lapsed).unwrap());
+    }
+}
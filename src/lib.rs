@@ -1,12 +1,39 @@
+pub mod backend;
+pub mod batch;
+pub mod bench;
+pub mod blob;
 pub mod block;
 pub mod bloom;
+pub mod branch;
+#[cfg(feature = "browser")]
+pub mod browser;
+pub mod cache;
 pub mod commit;
+pub mod commitgraph;
 pub mod compaction;
 pub mod compression;
+pub mod config;
+pub mod cuckoo;
 pub mod db;
 pub mod error;
+pub mod fsck;
+pub mod graph;
+pub mod grep;
+pub mod hyperloglog;
 pub mod index;
+pub mod keylock;
+pub mod maintenance;
+#[cfg(target_os = "linux")]
+pub mod mount;
+pub mod namespace;
+pub mod pattern;
+pub mod query;
+pub mod snapshot;
+pub mod sql;
 pub mod storage;
 pub mod tag;
+pub mod tenant;
+pub mod textdiff;
 pub mod tree;
+pub mod vector;
 pub mod wal;
@@ -1,7 +1,25 @@
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use iceberg::backend::{self, MemoryBackend};
+use iceberg::batch::WriteBatch;
+use iceberg::bench::{self, BenchOptions, Workload};
+use iceberg::bloom::FilterKind;
+use iceberg::branch::Branch;
 use iceberg::compaction::CompactionPolicy;
-use iceberg::db::Database;
+use iceberg::compression::Codec;
+use iceberg::config::{AutoCompactionConfig, DbConfig};
+use iceberg::db::{Database, ObjectKind, ScanOptions};
+use iceberg::grep::GrepOptions;
+use iceberg::index::{Collation, IndexKind, IndexOptions, IndexSource, Order, PageOptions};
+use iceberg::namespace::Namespace;
+use iceberg::pattern::KeyPattern;
+use iceberg::query::{self, Aggregate, Condition, Query};
+use iceberg::textdiff;
+use iceberg::tree::{DiffStat, TreeDiff};
+use iceberg::vector::{Metric, VectorOptions};
+use std::fs::File;
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Parser)]
 #[command(
@@ -14,28 +32,193 @@ struct Cli {
     #[arg(long, default_value = "iceberg.db")]
     db: PathBuf,
 
+    /// Namespace for key and index commands (default: the default namespace)
+    #[arg(long, global = true)]
+    ns: Option<String>,
+
+    /// Branch for put, get, delete, apply-batch, scan, count and log, instead of the
+    /// checked-out one; HEAD is left where it is
+    #[arg(long = "branch", value_name = "BRANCH", global = true)]
+    on_branch: Option<String>,
+
+    /// Output format; get, scan, log, diff, stats, branches, tags and
+    /// compact can print JSON, and graph DOT
+    // Not `--output`: `get --output FILE` already has that name.
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    format: Format,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+/// How commands print their results.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+    /// Graphviz DOT
+    Dot,
+}
+
+/// The order `tags` lists tags in.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum TagSort {
+    /// Newest first
+    Created,
+    /// Highest semantic version first, then tags not named like a version
+    Semver,
+}
+
+/// How `get` prints a value.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Encoding {
+    /// UTF-8 text and a newline; other values are refused
+    Text,
+    /// The bytes exactly as stored
+    Raw,
+    Hex,
+    Base64,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Initialize a new database
-    Init,
+    Init {
+        /// Block compression codec (none, lz4, zstd)
+        #[arg(long, default_value = "lz4")]
+        compression: Codec,
+        /// zstd compression level
+        #[arg(long, default_value = "3")]
+        level: i32,
+        /// Values smaller than this many bytes are stored inline in trees
+        #[arg(long, default_value = "4096")]
+        inline_threshold: usize,
+        /// Values of at least this many bytes go to the blob store (0 = never)
+        #[arg(long, default_value = "67108864")]
+        blob_threshold: u64,
+        /// Memory-map block files on read
+        #[arg(long)]
+        mmap: bool,
+    },
+    /// Show or change block compression for new writes
+    Compression {
+        /// Codec to switch to (none, lz4, zstd); omit to show current settings
+        codec: Option<Codec>,
+        /// zstd compression level
+        #[arg(long)]
+        level: Option<i32>,
+    },
+    /// Show or change the membership filter kept of the keys
+    KeyFilter {
+        /// Filter kind to switch to (bloom, cuckoo); omit to show the
+        /// current one
+        kind: Option<FilterKind>,
+    },
+    /// Show the commit-graph file, or turn it on or off
+    CommitGraph {
+        /// Build and keep the commit-graph file
+        #[arg(long, conflicts_with = "off")]
+        on: bool,
+        /// Delete the commit-graph file and stop keeping it
+        #[arg(long)]
+        off: bool,
+    },
+    /// Train a zstd dictionary on sampled values and enable it
+    TrainDict {
+        /// Maximum number of values to sample
+        #[arg(long, default_value = "1000")]
+        samples: usize,
+        /// Maximum dictionary size in bytes
+        #[arg(long, default_value = "112640")]
+        size: usize,
+    },
     /// Store a key-value pair
     Put {
         key: String,
-        value: String,
+        /// Value to store (omit when using --file or --stdin)
+        #[arg(required_unless_present_any = ["file", "stdin"])]
+        value: Option<String>,
+        /// Stream the value from a file instead of the command line
+        #[arg(long, conflicts_with = "value")]
+        file: Option<PathBuf>,
+        /// Stream the value from standard input
+        #[arg(long, conflicts_with_all = ["value", "file"])]
+        stdin: bool,
         /// Commit message
         #[arg(short, long)]
         message: Option<String>,
+        /// Respect key locks: fail if anyone but this holder has the key
+        /// locked
+        #[arg(long, conflicts_with_all = ["file", "stdin"])]
+        holder: Option<String>,
+    },
+    /// Take or renew an advisory lock on a key
+    Lock {
+        key: String,
+        /// Name of the lock holder
+        #[arg(long)]
+        holder: String,
+        /// Seconds until the lock lapses unless renewed
+        #[arg(long, default_value = "60")]
+        ttl: u64,
+    },
+    /// Release an advisory lock on a key
+    Unlock {
+        key: String,
+        /// Name of the lock holder
+        #[arg(long)]
+        holder: String,
+    },
+    /// List live key locks
+    Locks,
+    /// Show quotas and their usage, or set or remove the one on a prefix
+    Quota {
+        /// Key prefix to change the quota of
+        prefix: Option<String>,
+        /// Most keys under the prefix (0 = unlimited)
+        #[arg(long, requires = "prefix")]
+        max_keys: Option<u64>,
+        /// Most bytes of values under the prefix (0 = unlimited)
+        #[arg(long, requires = "prefix")]
+        max_bytes: Option<u64>,
+        /// Remove the quota on the prefix
+        #[arg(long, requires = "prefix", conflicts_with_all = ["max_keys", "max_bytes"])]
+        remove: bool,
+    },
+    /// Show the limits on written keys and values, or change them
+    Limits {
+        /// Longest key allowed, in bytes (0 = unlimited)
+        #[arg(long)]
+        max_key_len: Option<usize>,
+        /// Regular expression every key must match in full
+        #[arg(long)]
+        key_pattern: Option<String>,
+        /// Drop the key pattern
+        #[arg(long, conflicts_with = "key_pattern")]
+        no_key_pattern: bool,
+        /// Largest value allowed, in bytes (0 = unlimited)
+        #[arg(long)]
+        max_value_size: Option<u64>,
     },
     /// Retrieve a value by key
     Get {
         key: String,
-        /// Get value at a specific commit
+        /// Get value at a specific commit, branch or tag
         #[arg(long)]
         at: Option<String>,
+        /// Write the raw value to a file instead of stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// How to print the value: text (UTF-8 only), raw, hex or base64
+        #[arg(long, value_enum, default_value = "text", conflicts_with = "output")]
+        encoding: Encoding,
+    },
+    /// Show size, storage, and last-modifying commit of a key without reading it
+    Stat {
+        key: String,
+        /// Treat KEY as a prefix and stat every matching key
+        #[arg(long)]
+        prefix: bool,
     },
     /// Delete a key
     Delete {
@@ -43,29 +226,158 @@ enum Commands {
         #[arg(short, long)]
         message: Option<String>,
     },
-    /// List keys matching a prefix
-    Scan { prefix: String },
+    /// Apply a file of JSON lines, each {"op": "put", "key": ..., "value": ...}
+    /// or {"op": "delete", "key": ...}, as a single commit: all or nothing
+    ApplyBatch {
+        file: PathBuf,
+        #[arg(short, long)]
+        message: Option<String>,
+    },
+    /// Delete every key from START up to, but not including, END
+    DeleteRange {
+        start: String,
+        end: String,
+        #[arg(short, long)]
+        message: Option<String>,
+        /// Print what would change without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// List keys matching a prefix, glob or regex
+    Scan {
+        prefix: String,
+        /// Print at most N entries, then the key to continue after
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Only keys after this one
+        #[arg(long)]
+        after: Option<String>,
+        /// Print keys without reading their values
+        #[arg(long)]
+        keys_only: bool,
+        /// Treat the prefix as a glob over whole keys: `*`, `?`, `[a-z]`
+        #[arg(long)]
+        glob: bool,
+        /// Treat the prefix as a regex matched anywhere in keys
+        #[arg(long, conflicts_with = "glob")]
+        regex: bool,
+        /// Scan as of this commit, branch or tag
+        #[arg(long)]
+        at: Option<String>,
+    },
+    /// Count keys matching a prefix
+    Count {
+        #[arg(default_value = "")]
+        prefix: String,
+        /// Count as of this commit, branch or tag
+        #[arg(long)]
+        at: Option<String>,
+    },
+    /// Print a uniform random sample of keys
+    Sample {
+        /// Number of keys to sample
+        n: usize,
+        /// Only keys starting with this prefix
+        #[arg(long, default_value = "")]
+        prefix: String,
+        /// Seed for a repeatable sample
+        #[arg(long)]
+        seed: Option<u64>,
+    },
     /// Show version history
     Log {
         /// Max entries to show
         #[arg(short = 'n', long, default_value = "20")]
         limit: usize,
+        /// Draw the history of all branches as a graph
+        #[arg(long)]
+        graph: bool,
+        /// List commits of every branch and tag, not just the current branch
+        #[arg(long)]
+        all: bool,
+        /// Skip this many of the newest commits
+        #[arg(long, default_value = "0")]
+        skip: usize,
+        /// Summarize the keys and bytes each commit changed
+        #[arg(long)]
+        stat: bool,
+    },
+    /// Draw the history of all branches, as ASCII lanes or for Graphviz
+    /// with --format dot
+    Graph {
+        /// Max commits to show (default: all)
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
     },
     /// Create a new branch
     Branch { name: String },
-    /// Switch to a branch
+    /// Switch to a branch, or detach HEAD at a tag to read its snapshot
     Checkout { name: String },
     /// List all branches
     Branches,
     /// Delete a branch
     DeleteBranch { name: String },
-    /// Diff between two commits
-    Diff { commit_a: String, commit_b: String },
+    /// List the commits made on top of a commit
+    Children {
+        /// Commit, branch or tag
+        commit: String,
+        /// List every descendant, not just direct children
+        #[arg(long)]
+        all: bool,
+    },
+    /// Check whether commit A is an ancestor of (or the same as) B; exits
+    /// with status 1 if not
+    IsAncestor {
+        /// Commit, branch or tag that may be an ancestor
+        a: String,
+        /// Commit, branch or tag whose history is searched
+        b: String,
+    },
+    /// Print the newest commit both A and B descend from
+    MergeBase {
+        /// Commit, branch or tag
+        a: String,
+        /// Commit, branch or tag
+        b: String,
+    },
+    /// Diff between two commits, branches or tags
+    Diff {
+        /// Old side
+        commit_a: String,
+        /// New side (default: HEAD)
+        #[arg(default_value = "HEAD")]
+        commit_b: String,
+        /// Show how values changed, as unified diffs for text and JSON
+        #[arg(long, conflicts_with = "stat")]
+        values: bool,
+        /// Summarize changes per key prefix instead of listing keys
+        #[arg(long)]
+        stat: bool,
+        /// Key prefixes for --stat end at the first occurrence of this
+        #[arg(long, default_value = ":")]
+        delimiter: char,
+    },
+    /// Print a stored commit, tree, block, tag or the refs as JSON
+    Inspect {
+        /// commit, tree, block, tag or refs
+        kind: String,
+        /// Object id; commits also take a branch or tag, tags their name
+        id: Option<String>,
+    },
+    /// Show a commit and the keys it changed
+    Show {
+        /// Branch, tag or commit id
+        #[arg(default_value = "HEAD")]
+        commit: String,
+    },
     /// Merge a branch into current
     Merge {
         branch: String,
         #[arg(short, long)]
         message: Option<String>,
+        /// Print what would change without changing anything
+        #[arg(long)]
+        dry_run: bool,
     },
     /// Cherry-pick a commit onto the current branch
     CherryPick {
@@ -73,6 +385,9 @@ enum Commands {
         commit: String,
         #[arg(short, long)]
         message: Option<String>,
+        /// Print what would change without changing anything
+        #[arg(long)]
+        dry_run: bool,
     },
     /// Create a tag
     Tag {
@@ -81,43 +396,213 @@ enum Commands {
         /// Commit to tag (default: HEAD)
         #[arg(long)]
         commit: Option<String>,
-        /// Tag message
+        /// Tag message; makes the tag annotated rather than lightweight
         #[arg(short, long)]
         message: Option<String>,
+        /// Move the tag if it already exists
+        #[arg(short, long)]
+        force: bool,
+        /// Sign the tag with the key in this file (see `tag-key`)
+        #[arg(long)]
+        sign_key: Option<PathBuf>,
+    },
+    /// Generate a key for signing tags, print its public key
+    TagKey {
+        /// File to write the secret key to; must not exist yet
+        file: PathBuf,
+    },
+    /// Check a tag's signature
+    VerifyTag {
+        name: String,
+        /// Only accept a signature made with this public key (hex)
+        #[arg(long)]
+        public_key: Option<String>,
     },
     /// List all tags
-    Tags,
+    Tags {
+        /// Only tags whose names match this glob, e.g. "v*"
+        #[arg(long)]
+        pattern: Option<String>,
+        #[arg(long, value_enum, default_value = "created")]
+        sort: TagSort,
+    },
     /// Delete a tag
     DeleteTag { name: String },
+    /// Show where a tag has pointed over time, newest first
+    TagLog { name: String },
     /// Rebase current branch onto another branch
     Rebase {
         /// Target branch to rebase onto
         onto: String,
+        /// Print what would change without changing anything
+        #[arg(long)]
+        dry_run: bool,
     },
     /// Create a secondary index on a JSON field
     CreateIndex {
         /// Index name
         name: String,
         /// JSON field path (e.g., "city" or "address.country")
-        field: String,
+        #[arg(required_unless_present = "key_part")]
+        field: Option<String>,
+        /// Index part N (from 0) of the key instead of a field of the value
+        #[arg(long, value_name = "N", conflicts_with = "field")]
+        key_part: Option<usize>,
+        /// Delimiter the key is split on for --key-part
+        #[arg(long, default_value = ":", requires = "key_part")]
+        delimiter: String,
+        /// Value type, for ordering and range queries (string, integer, float, date)
+        #[arg(long = "type", default_value = "string")]
+        kind: IndexKind,
+        /// Reject puts that give a second key an already indexed value
+        #[arg(long)]
+        unique: bool,
+        /// How string values compare (binary, case-insensitive, numeric, casefold)
+        #[arg(long, default_value = "binary")]
+        collation: Collation,
     },
     /// Drop a secondary index
     DropIndex {
         /// Index name
         name: String,
     },
+    /// Rebuild secondary and vector indexes from the current branch
+    Reindex {
+        /// Index to rebuild (default: all)
+        name: Option<String>,
+    },
     /// Query a secondary index
     QueryIndex {
         /// Index name
         name: String,
         /// Value to search for
-        value: String,
+        #[arg(required_unless_present_any = ["min", "max"])]
+        value: Option<String>,
         /// Use prefix matching
         #[arg(long)]
         prefix: bool,
+        /// Match values from this one on (inclusive)
+        #[arg(long, conflicts_with_all = ["value", "prefix"])]
+        min: Option<String>,
+        /// Match values up to this one (inclusive)
+        #[arg(long, conflicts_with_all = ["value", "prefix"])]
+        max: Option<String>,
+        /// Print each matching key's value too (exact matches only)
+        #[arg(long, conflicts_with_all = ["prefix", "min", "max"])]
+        with_values: bool,
+        /// Largest indexed value first
+        #[arg(long, conflicts_with_all = ["prefix", "with_values"])]
+        desc: bool,
+        /// Print at most N matches, then a cursor for the rest
+        #[arg(long, conflicts_with_all = ["prefix", "with_values"])]
+        limit: Option<usize>,
+        /// Skip the first N matches
+        #[arg(long, default_value = "0", conflicts_with_all = ["prefix", "with_values"])]
+        offset: usize,
+        /// Continue after a cursor printed by an earlier --limit query
+        #[arg(long, conflicts_with_all = ["prefix", "with_values"])]
+        after: Option<String>,
+        /// Look the value up as of a commit, branch or tag instead
+        #[arg(
+            long,
+            requires = "value",
+            conflicts_with_all = ["prefix", "with_values", "desc", "limit", "offset", "after"]
+        )]
+        at: Option<String>,
+    },
+    /// Create a vector index over embeddings (JSON arrays of numbers)
+    CreateVectorIndex {
+        /// Index name
+        name: String,
+        /// JSON field path of the embedding
+        field: String,
+        /// Length of the embeddings
+        #[arg(long)]
+        dims: usize,
+        /// Distance measure (cosine, euclidean, dot)
+        #[arg(long, default_value = "cosine")]
+        metric: Metric,
+    },
+    /// Find the keys with the nearest embeddings in a vector index
+    QueryVector {
+        /// Index name
+        name: String,
+        /// Query embedding as comma-separated numbers
+        #[arg(allow_hyphen_values = true)]
+        vector: String,
+        /// Number of results
+        #[arg(short, default_value = "10")]
+        k: usize,
+        /// Search the embeddings at a commit, branch or tag instead
+        #[arg(long)]
+        at: Option<String>,
+    },
+    /// Run a SELECT statement, or list keys and values matching conditions
+    Query {
+        /// e.g. "SELECT key, value.city FROM db WHERE value.age > 30 AT 'v1'"
+        #[arg(required_unless_present = "conditions", conflicts_with = "conditions")]
+        statement: Option<String>,
+        /// Condition like city=Zurich, age>30, age<=40 or name^=Jo (prefix)
+        #[arg(long = "where")]
+        conditions: Vec<Condition>,
+        /// Show how the conditions would find candidates instead of running them
+        #[arg(long, requires = "conditions")]
+        explain: bool,
+    },
+    /// Count, sum, min or max values, optionally per group
+    Aggregate {
+        /// count, sum(FIELD), min(FIELD) or max(FIELD)
+        aggregate: Aggregate,
+        /// Group by the values of a JSON field
+        #[arg(long)]
+        group_by: Option<String>,
+        /// Only values whose key starts with this prefix
+        #[arg(long)]
+        prefix: Option<String>,
+        /// Condition like city=Zurich, age>30, age<=40 or name^=Jo (prefix)
+        #[arg(long = "where")]
+        conditions: Vec<Condition>,
+    },
+    /// Search values for a substring or regex
+    Grep {
+        pattern: String,
+        /// Only values whose key starts with this prefix
+        #[arg(long, default_value = "")]
+        prefix: String,
+        /// Treat the pattern as a regex
+        #[arg(long)]
+        regex: bool,
+        /// Only search the string fields of JSON values
+        #[arg(long)]
+        json_strings: bool,
+        /// Characters of context to print around each match
+        #[arg(short = 'C', long, default_value = "20")]
+        context: usize,
+        /// Stop after N matches
+        #[arg(long)]
+        limit: Option<usize>,
     },
     /// List secondary indexes
     Indexes,
+    /// Show the size and most frequent values of indexes
+    IndexStats {
+        /// Index to describe (default: all)
+        name: Option<String>,
+    },
+    /// Approximate number of distinct values of a field, or keys under a prefix
+    Distinct {
+        /// JSON field path (e.g. "city") or key prefix ending in * (e.g. "user:*");
+        /// lists every tracked spec when omitted
+        spec: Option<String>,
+        /// Start keeping the count, or rebuild it
+        #[arg(long, requires = "spec")]
+        track: bool,
+        /// Stop keeping the count
+        #[arg(long, requires = "spec", conflicts_with = "track")]
+        untrack: bool,
+    },
+    /// List namespaces with their key counts
+    Namespaces,
     /// Run compaction / garbage collection
     Compact {
         /// Keep at most N versions (0 = unlimited)
@@ -126,55 +611,660 @@ enum Commands {
         /// Keep commits at most N days old
         #[arg(long)]
         max_age_days: Option<u64>,
+        /// Keep tagged commits regardless of the limits (false deletes their tags)
+        #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+        keep_tagged: bool,
+        /// Replace removed commits with one snapshot commit instead of dropping them
+        #[arg(long)]
+        squash: bool,
+        /// Print what would change without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Rewrite all history so a key (and its blocks) never existed
+    PurgeKey { key: String },
+    /// Replace history up to a commit with a single snapshot commit
+    SquashHistory {
+        /// Last commit (or branch/tag) to fold into the snapshot
+        #[arg(long)]
+        before: String,
+        /// Message of the snapshot commit
+        #[arg(short, long)]
+        message: Option<String>,
+    },
+    /// Drop all history older than a commit, which becomes the new root
+    TruncateHistory {
+        /// Oldest commit (or branch/tag) to keep
+        #[arg(long)]
+        keep: String,
+    },
+    /// Show or change automatic compaction; omit all options to show it
+    AutoCompact {
+        /// Keep at most N versions when compacting (0 = unlimited)
+        #[arg(long)]
+        max_versions: Option<usize>,
+        /// Keep commits at most N days old when compacting
+        #[arg(long)]
+        max_age_days: Option<u64>,
+        /// Compact after N commits (0 = no commit threshold)
+        #[arg(long)]
+        commits: Option<usize>,
+        /// Compact once block files exceed N bytes (0 = no size threshold)
+        #[arg(long)]
+        disk_bytes: Option<u64>,
+        /// Compact right after the triggering commit instead of in background maintenance
+        #[arg(long, action = clap::ArgAction::Set)]
+        inline: Option<bool>,
+        /// Turn automatic compaction off
+        #[arg(long, conflicts_with_all = ["max_versions", "max_age_days", "commits", "disk_bytes", "inline"])]
+        off: bool,
     },
     /// Show database statistics
-    Stats,
+    Stats {
+        /// Also report keys, commits and divergence of every branch
+        #[arg(long)]
+        all_branches: bool,
+        /// Also report a value-size histogram and the largest keys and prefixes
+        #[arg(long)]
+        sizes: bool,
+        /// Number of largest keys and prefixes to list
+        #[arg(long, default_value = "10", requires = "sizes")]
+        top: usize,
+        /// Keys are grouped into prefixes up to their first DELIMITER
+        #[arg(long, default_value = ":", requires = "sizes")]
+        delimiter: char,
+    },
+    /// Show the current branch, HEAD commit and divergence from main
+    Status,
+    /// Show disk usage by component, including unreachable data
+    Du,
+    /// Print a completion script for a shell
+    Completions { shell: clap_complete::Shell },
+    /// Print the man page in roff format
+    Man,
+    /// Measure throughput and latency on a scratch database
+    Bench {
+        /// Operations to time
+        #[arg(long, default_value = "10000")]
+        ops: usize,
+        /// Bytes per value, e.g. 100, 1k or 4M
+        #[arg(long, default_value = "1k", value_parser = bench::parse_size)]
+        value_size: usize,
+        /// put, get or mixed
+        #[arg(long, default_value = "put")]
+        workload: Workload,
+        /// Keys written up front for the get and mixed workloads
+        #[arg(long, default_value = "1000")]
+        keys: usize,
+        /// Keep the scratch database in memory instead of on disk
+        #[arg(long)]
+        memory: bool,
+        /// New directory for the scratch database (default: under the
+        /// system temp directory); removed afterwards
+        #[arg(long, conflicts_with = "memory")]
+        dir: Option<PathBuf>,
+    },
+    /// Verify the integrity of refs, commits, trees, blocks, bloom filter and indexes
+    Fsck {
+        /// Also list commits no branch or tag leads to
+        #[arg(long)]
+        lost_found: bool,
+        /// Bring back a lost commit (id or unique id prefix) on a new branch
+        #[arg(long, value_name = "COMMIT", requires = "lost_found")]
+        resurrect: Option<String>,
+        /// Name of the branch for --resurrect (default: recovered-<id prefix>)
+        #[arg(long, value_name = "BRANCH", requires = "resurrect")]
+        to: Option<String>,
+    },
+    /// Repair recoverable corruption, quarantining damaged objects in corrupt/
+    Repair,
+    /// Mount a snapshot read-only as a directory tree (keys become files)
+    #[cfg(target_os = "linux")]
+    Mount {
+        /// Directory to mount on
+        mountpoint: PathBuf,
+        /// Branch, tag or commit to expose (default: HEAD)
+        #[arg(long, default_value = "HEAD")]
+        at: String,
+    },
+}
+
+impl Commands {
+    /// Whether the command works within the namespace chosen with `--ns`.
+    fn takes_namespace(&self) -> bool {
+        matches!(
+            self,
+            Commands::Put { .. }
+                | Commands::Get { .. }
+                | Commands::Delete { .. }
+                | Commands::DeleteRange { .. }
+                | Commands::Scan { .. }
+                | Commands::Count { .. }
+                | Commands::Sample { .. }
+                | Commands::CreateIndex { .. }
+                | Commands::DropIndex { .. }
+                | Commands::Reindex { .. }
+                | Commands::QueryIndex { .. }
+                | Commands::CreateVectorIndex { .. }
+                | Commands::QueryVector { .. }
+                | Commands::Query { .. }
+                | Commands::Aggregate { .. }
+                | Commands::Grep { .. }
+                | Commands::Indexes
+                | Commands::IndexStats { .. }
+        )
+    }
+
+    /// Whether the command can work on a branch given with `--branch`.
+    fn takes_branch(&self) -> bool {
+        matches!(
+            self,
+            Commands::Put { .. }
+                | Commands::Get { .. }
+                | Commands::Delete { .. }
+                | Commands::ApplyBatch { .. }
+                | Commands::Scan { .. }
+                | Commands::Count { .. }
+                | Commands::Log { .. }
+        )
+    }
+
+    /// Whether the command can print its results as JSON.
+    fn prints_json(&self) -> bool {
+        matches!(
+            self,
+            Commands::Get { .. }
+                | Commands::Scan { .. }
+                | Commands::Log { .. }
+                | Commands::Diff { .. }
+                | Commands::Stats { .. }
+                | Commands::Branches
+                | Commands::Tags { .. }
+                | Commands::Compact { .. }
+        )
+    }
+}
+
+/// Where key and index commands operate: the default namespace, the one
+/// chosen with `--ns`, or for key commands the branch chosen with
+/// `--branch`.
+enum Scope<'a> {
+    Default(&'a Database),
+    Namespace(Namespace<'a>),
+    Branch(Branch<'a>),
+}
+
+impl<'a> Scope<'a> {
+    fn new(db: &'a Database, ns: Option<&str>) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(match ns {
+            Some(ns) => Scope::Namespace(db.namespace(ns)?),
+            None => Scope::Default(db),
+        })
+    }
+
+    fn of(db: &'a Database, args: ScopeArgs) -> Result<Self, Box<dyn std::error::Error>> {
+        match args.branch {
+            Some(branch) => Ok(Scope::Branch(db.on_branch(branch)?)),
+            None => Scope::new(db, args.ns),
+        }
+    }
+}
+
+/// `--ns` and `--branch`, for the commands taking both.
+#[derive(Clone, Copy)]
+struct ScopeArgs<'a> {
+    ns: Option<&'a str>,
+    branch: Option<&'a str>,
+}
+
+/// Call a method that `Database` and `Namespace` both have on a `Scope`.
+macro_rules! scoped {
+    ($scope:expr, $method:ident($($arg:expr),*)) => {
+        match $scope {
+            Scope::Default(db) => db.$method($($arg),*),
+            Scope::Namespace(ns) => ns.$method($($arg),*),
+            Scope::Branch(_) => unreachable!("--branch is checked in main"),
+        }
+    };
+}
+
+/// `scoped!` for a method that `Branch` has too.
+macro_rules! branch_scoped {
+    ($scope:expr, $method:ident($($arg:expr),*)) => {
+        match $scope {
+            Scope::Default(db) => db.$method($($arg),*),
+            Scope::Namespace(ns) => ns.$method($($arg),*),
+            Scope::Branch(branch) => branch.$method($($arg),*),
+        }
+    };
 }
 
 fn main() {
     let cli = Cli::parse();
+    let ns = cli.ns.as_deref();
+    if ns.is_some() && !cli.command.takes_namespace() {
+        eprintln!("error: --ns is not supported by this command");
+        std::process::exit(2);
+    }
+    let branch = cli.on_branch.as_deref();
+    if branch.is_some() && !cli.command.takes_branch() {
+        eprintln!("error: --branch is not supported by this command");
+        std::process::exit(2);
+    }
+    if branch.is_some() && ns.is_some() {
+        eprintln!("error: --branch and --ns cannot be used together");
+        std::process::exit(2);
+    }
+    let scope = ScopeArgs { ns, branch };
+    let json = cli.format == Format::Json;
+    if json && !cli.command.prints_json() {
+        eprintln!("error: --format json is not supported by this command");
+        std::process::exit(2);
+    }
+    if cli.format == Format::Dot && !matches!(cli.command, Commands::Graph { .. }) {
+        eprintln!("error: --format dot is only supported by graph");
+        std::process::exit(2);
+    }
 
     let result = match cli.command {
-        Commands::Init => cmd_init(&cli.db),
+        Commands::Init {
+            compression,
+            level,
+            inline_threshold,
+            blob_threshold,
+            mmap,
+        } => cmd_init(
+            &cli.db,
+            compression,
+            level,
+            inline_threshold,
+            blob_threshold,
+            mmap,
+        ),
+        Commands::Compression { codec, level } => cmd_compression(&cli.db, codec, level),
+        Commands::KeyFilter { kind } => cmd_key_filter(&cli.db, kind),
+        Commands::CommitGraph { on, off } => cmd_commit_graph(&cli.db, on, off),
+        Commands::TrainDict { samples, size } => cmd_train_dict(&cli.db, samples, size),
         Commands::Put {
             key,
             value,
+            file,
+            stdin,
+            message,
+            holder,
+        } => {
+            let args = PutArgs {
+                value,
+                file,
+                stdin,
+                message,
+                holder,
+            };
+            cmd_put(&cli.db, scope, &key, args)
+        }
+        Commands::Lock { key, holder, ttl } => cmd_lock(&cli.db, &key, &holder, ttl),
+        Commands::Unlock { key, holder } => cmd_unlock(&cli.db, &key, &holder),
+        Commands::Locks => cmd_locks(&cli.db),
+        Commands::Quota {
+            prefix,
+            max_keys,
+            max_bytes,
+            remove,
+        } => cmd_quota(&cli.db, prefix.as_deref(), max_keys, max_bytes, remove),
+        Commands::Limits {
+            max_key_len,
+            key_pattern,
+            no_key_pattern,
+            max_value_size,
+        } => cmd_limits(
+            &cli.db,
+            max_key_len,
+            key_pattern,
+            no_key_pattern,
+            max_value_size,
+        ),
+        Commands::Get {
+            key,
+            at,
+            output,
+            encoding,
+        } => cmd_get(
+            &cli.db,
+            scope,
+            &key,
+            at.as_deref(),
+            output.as_deref(),
+            encoding,
+            json,
+        ),
+        Commands::Stat { key, prefix } => cmd_stat(&cli.db, &key, prefix),
+        Commands::Delete { key, message } => cmd_delete(&cli.db, scope, &key, message.as_deref()),
+        Commands::ApplyBatch { file, message } => {
+            cmd_apply_batch(&cli.db, branch, &file, message.as_deref())
+        }
+        Commands::DeleteRange {
+            start,
+            end,
             message,
-        } => cmd_put(&cli.db, &key, &value, message.as_deref()),
-        Commands::Get { key, at } => cmd_get(&cli.db, &key, at.as_deref()),
-        Commands::Delete { key, message } => cmd_delete(&cli.db, &key, message.as_deref()),
-        Commands::Scan { prefix } => cmd_scan(&cli.db, &prefix),
-        Commands::Log { limit } => cmd_log(&cli.db, limit),
+            dry_run,
+        } => cmd_delete_range(&cli.db, ns, &start, &end, message.as_deref(), dry_run),
+        Commands::Scan {
+            prefix,
+            limit,
+            after,
+            keys_only,
+            glob,
+            regex,
+            at,
+        } => {
+            let compile: Option<fn(&str) -> iceberg::error::Result<KeyPattern>> =
+                match (glob, regex) {
+                    (true, _) => Some(KeyPattern::glob),
+                    (_, true) => Some(KeyPattern::regex),
+                    _ => None,
+                };
+            let args = ScanArgs {
+                compile,
+                limit,
+                after,
+                keys_only,
+                at,
+            };
+            cmd_scan(&cli.db, scope, &prefix, args, json)
+        }
+        Commands::Count { prefix, at } => cmd_count(&cli.db, scope, &prefix, at.as_deref()),
+        Commands::Sample { n, prefix, seed } => cmd_sample(&cli.db, ns, n, &prefix, seed),
+        Commands::Log {
+            limit,
+            graph,
+            all,
+            skip,
+            stat,
+        } => {
+            let args = LogArgs {
+                limit,
+                skip,
+                graph,
+                all,
+                stat,
+            };
+            cmd_log(&cli.db, branch, args, json)
+        }
+        Commands::Graph { limit } => cmd_graph(&cli.db, limit, cli.format == Format::Dot),
         Commands::Branch { name } => cmd_branch(&cli.db, &name),
         Commands::Checkout { name } => cmd_checkout(&cli.db, &name),
-        Commands::Branches => cmd_branches(&cli.db),
+        Commands::Branches => cmd_branches(&cli.db, json),
         Commands::DeleteBranch { name } => cmd_delete_branch(&cli.db, &name),
-        Commands::Diff { commit_a, commit_b } => cmd_diff(&cli.db, &commit_a, &commit_b),
-        Commands::Merge { branch, message } => cmd_merge(&cli.db, &branch, message.as_deref()),
-        Commands::CherryPick { commit, message } => {
-            cmd_cherry_pick(&cli.db, &commit, message.as_deref())
-        }
+        Commands::Diff {
+            commit_a,
+            commit_b,
+            values,
+            stat,
+            delimiter,
+        } => match stat {
+            true => cmd_diff_stat(&cli.db, &commit_a, &commit_b, delimiter, json),
+            false => cmd_diff(&cli.db, &commit_a, &commit_b, values, json),
+        },
+        Commands::Show { commit } => cmd_show(&cli.db, &commit),
+        Commands::Inspect { kind, id } => cmd_inspect(&cli.db, &kind, id.as_deref()),
+        Commands::Merge {
+            branch,
+            message,
+            dry_run,
+        } => cmd_merge(&cli.db, &branch, message.as_deref(), dry_run),
+        Commands::CherryPick {
+            commit,
+            message,
+            dry_run,
+        } => cmd_cherry_pick(&cli.db, &commit, message.as_deref(), dry_run),
         Commands::Tag {
             name,
             commit,
             message,
-        } => cmd_tag(&cli.db, &name, commit.as_deref(), message.as_deref()),
-        Commands::Tags => cmd_tags(&cli.db),
+            force,
+            sign_key,
+        } => cmd_tag(
+            &cli.db,
+            &name,
+            commit.as_deref(),
+            message.as_deref(),
+            force,
+            sign_key.as_deref(),
+        ),
+        Commands::TagKey { file } => cmd_tag_key(&file),
+        Commands::VerifyTag { name, public_key } => {
+            cmd_verify_tag(&cli.db, &name, public_key.as_deref())
+        }
+        Commands::Tags { pattern, sort } => cmd_tags(&cli.db, pattern.as_deref(), sort, json),
         Commands::DeleteTag { name } => cmd_delete_tag(&cli.db, &name),
-        Commands::Rebase { onto } => cmd_rebase(&cli.db, &onto),
-        Commands::CreateIndex { name, field } => cmd_create_index(&cli.db, &name, &field),
-        Commands::DropIndex { name } => cmd_drop_index(&cli.db, &name),
+        Commands::TagLog { name } => cmd_tag_log(&cli.db, &name),
+        Commands::Rebase { onto, dry_run } => cmd_rebase(&cli.db, &onto, dry_run),
+        Commands::CreateIndex {
+            name,
+            field,
+            key_part,
+            delimiter,
+            kind,
+            unique,
+            collation,
+        } => {
+            let source = match key_part {
+                Some(position) => IndexSource::Key {
+                    delimiter,
+                    position,
+                },
+                None => IndexSource::Value,
+            };
+            let options = IndexOptions {
+                kind,
+                unique,
+                collation,
+                source,
+            };
+            let field = field.unwrap_or_default();
+            cmd_create_index(&cli.db, ns, &name, &field, options)
+        }
+        Commands::DropIndex { name } => cmd_drop_index(&cli.db, ns, &name),
+        Commands::Reindex { name } => cmd_reindex(&cli.db, ns, name.as_deref()),
         Commands::QueryIndex {
             name,
             value,
             prefix,
-        } => cmd_query_index(&cli.db, &name, &value, prefix),
-        Commands::Indexes => cmd_indexes(&cli.db),
+            min,
+            max,
+            with_values,
+            desc,
+            limit,
+            offset,
+            after,
+            at,
+        } => {
+            let paged = desc || limit.is_some() || offset > 0 || after.is_some();
+            let options = PageOptions {
+                order: if desc { Order::Desc } else { Order::Asc },
+                limit,
+                offset,
+                after,
+            };
+            let query = match (value, at) {
+                (Some(value), Some(at)) => IndexQuery::At(value, at),
+                (Some(value), _) if paged => {
+                    IndexQuery::Page(Some(value.clone()), Some(value), options)
+                }
+                (None, _) if paged => IndexQuery::Page(min, max, options),
+                (Some(value), _) if with_values => IndexQuery::Entries(value),
+                (Some(value), _) if prefix => IndexQuery::Prefix(value),
+                (Some(value), _) => IndexQuery::Exact(value),
+                (None, _) => IndexQuery::Range(min, max),
+            };
+            cmd_query_index(&cli.db, ns, &name, query)
+        }
+        Commands::CreateVectorIndex {
+            name,
+            field,
+            dims,
+            metric,
+        } => {
+            let options = VectorOptions {
+                dimensions: dims,
+                metric,
+            };
+            cmd_create_vector_index(&cli.db, ns, &name, &field, &options)
+        }
+        Commands::QueryVector {
+            name,
+            vector,
+            k,
+            at,
+        } => cmd_query_vector(&cli.db, ns, &name, &vector, k, at.as_deref()),
+        Commands::Query {
+            statement,
+            conditions,
+            explain,
+        } => match statement {
+            Some(_) if ns.is_some() => {
+                Err("--ns does not apply to statements; use FROM <namespace>".into())
+            }
+            Some(statement) => cmd_sql(&cli.db, &statement),
+            None => {
+                let query = conditions
+                    .into_iter()
+                    .map(Query::Condition)
+                    .reduce(Query::and)
+                    .expect("clap requires a condition");
+                cmd_query(&cli.db, ns, &query, explain)
+            }
+        },
+        Commands::Aggregate {
+            aggregate,
+            group_by,
+            prefix,
+            conditions,
+        } => {
+            let query = prefix
+                .map(|p| query::key().prefix(&p))
+                .into_iter()
+                .chain(conditions.into_iter().map(Query::Condition))
+                .fold(Query::all(), Query::and);
+            cmd_aggregate(&cli.db, ns, &query, group_by.as_deref(), &aggregate)
+        }
+        Commands::Grep {
+            pattern,
+            prefix,
+            regex,
+            json_strings,
+            context,
+            limit,
+        } => {
+            let options = GrepOptions {
+                regex,
+                json_strings,
+                context,
+                limit,
+            };
+            cmd_grep(&cli.db, ns, &pattern, &prefix, &options)
+        }
+        Commands::Indexes => cmd_indexes(&cli.db, ns),
+        Commands::IndexStats { name } => cmd_index_stats(&cli.db, ns, name),
+        Commands::Distinct {
+            spec,
+            track,
+            untrack,
+        } => cmd_distinct(&cli.db, spec.as_deref(), track, untrack),
+        Commands::Namespaces => cmd_namespaces(&cli.db),
         Commands::Compact {
             max_versions,
             max_age_days,
-        } => cmd_compact(&cli.db, max_versions, max_age_days),
-        Commands::Stats => cmd_stats(&cli.db),
+            keep_tagged,
+            squash,
+            dry_run,
+        } => cmd_compact(
+            &cli.db,
+            &CompactionPolicy {
+                max_versions,
+                max_age_days,
+                keep_tagged,
+                squash,
+            },
+            dry_run,
+            json,
+        ),
+        Commands::PurgeKey { key } => cmd_purge_key(&cli.db, &key),
+        Commands::SquashHistory { before, message } => {
+            cmd_squash_history(&cli.db, &before, message.as_deref())
+        }
+        Commands::Children { commit, all } => cmd_children(&cli.db, &commit, all),
+        Commands::IsAncestor { a, b } => cmd_is_ancestor(&cli.db, &a, &b),
+        Commands::MergeBase { a, b } => cmd_merge_base(&cli.db, &a, &b),
+        Commands::TruncateHistory { keep } => cmd_truncate_history(&cli.db, &keep),
+        Commands::AutoCompact {
+            max_versions,
+            max_age_days,
+            commits,
+            disk_bytes,
+            inline,
+            off,
+        } => cmd_auto_compact(
+            &cli.db,
+            AutoCompactArgs {
+                max_versions,
+                max_age_days,
+                commits,
+                disk_bytes,
+                inline,
+                off,
+            },
+        ),
+        Commands::Status => cmd_status(&cli.db),
+        Commands::Du => cmd_du(&cli.db),
+        Commands::Completions { shell } => {
+            clap_complete::generate(
+                shell,
+                &mut Cli::command(),
+                "iceberg",
+                &mut std::io::stdout(),
+            );
+            Ok(())
+        }
+        Commands::Man => clap_mangen::Man::new(Cli::command())
+            .render(&mut std::io::stdout())
+            .map_err(Into::into),
+        Commands::Bench {
+            ops,
+            value_size,
+            workload,
+            keys,
+            memory,
+            dir,
+        } => {
+            let options = BenchOptions {
+                workload,
+                ops,
+                value_size,
+                keys,
+                ..Default::default()
+            };
+            cmd_bench(&options, memory, dir)
+        }
+        Commands::Stats {
+            all_branches,
+            sizes,
+            top,
+            delimiter,
+        } => cmd_stats(
+            &cli.db,
+            all_branches,
+            sizes.then_some((top, delimiter)),
+            json,
+        ),
+        Commands::Fsck {
+            lost_found,
+            resurrect,
+            to,
+        } => cmd_fsck(&cli.db, lost_found, resurrect.as_deref(), to.as_deref()),
+        Commands::Repair => cmd_repair(&cli.db),
+        #[cfg(target_os = "linux")]
+        Commands::Mount { mountpoint, at } => cmd_mount(&cli.db, &mountpoint, &at),
     };
 
     if let Err(e) = result {
@@ -183,127 +1273,825 @@ fn main() {
     }
 }
 
-fn cmd_init(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
-    Database::init(path)?;
+fn cmd_init(
+    path: &Path,
+    codec: Codec,
+    level: i32,
+    inline_threshold: usize,
+    blob_threshold: u64,
+    mmap: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = DbConfig::default();
+    config.compression.codec = codec;
+    config.compression.zstd_level = level;
+    config.inline_threshold = inline_threshold;
+    config.blob_threshold = blob_threshold;
+    config.mmap_reads = mmap;
+    Database::init_with_config(path, config)?;
     println!("Initialized iceberg database at {}", path.display());
     Ok(())
 }
 
-fn cmd_put(
+fn cmd_compression(
     path: &Path,
-    key: &str,
-    value: &str,
-    msg: Option<&str>,
+    codec: Option<Codec>,
+    level: Option<i32>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    let commit = db.put(key, value.as_bytes().to_vec(), msg)?;
-    println!("[{}] {}", &commit.id[..8], commit.message);
+    let mut compression = db.config().compression;
+    if codec.is_some() || level.is_some() {
+        if let Some(codec) = codec {
+            compression.codec = codec;
+        }
+        if let Some(level) = level {
+            compression.zstd_level = level;
+        }
+        db.set_compression(compression.clone())?;
+    }
+    println!("Codec:      {}", compression.codec);
+    println!("zstd level: {}", compression.zstd_level);
+    match &compression.dictionary {
+        Some(id) => println!("Dictionary: {}", &id[..8]),
+        None => println!("Dictionary: (none)"),
+    }
     Ok(())
 }
 
-fn cmd_get(path: &Path, key: &str, at: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+fn cmd_key_filter(path: &Path, kind: Option<FilterKind>) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    let value = match at {
-        Some(commit_id) => db.get_at(key, commit_id)?,
-        None => db.get(key)?,
-    };
-    println!("{}", String::from_utf8_lossy(&value));
+    if let Some(kind) = kind {
+        db.set_key_filter(kind)?;
+    }
+    let (items, bits, fp) = db.bloom_stats();
+    println!("Kind:  {}", db.config().key_filter);
+    println!("Items: {}", items);
+    println!("Size:  {} bytes", bits / 8);
+    println!("FP:    {:.4}%", fp * 100.0);
     Ok(())
 }
 
-fn cmd_delete(path: &Path, key: &str, msg: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+fn cmd_commit_graph(path: &Path, on: bool, off: bool) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    let commit = db.delete(key, msg)?;
-    println!("[{}] {}", &commit.id[..8], commit.message);
+    if on || off {
+        db.set_commit_graph(on)?;
+    }
+    match db.commit_graph_len() {
+        Some(commits) => println!("Commit graph: on ({} commits)", commits),
+        None => println!("Commit graph: off"),
+    }
     Ok(())
 }
 
-fn cmd_scan(path: &Path, prefix: &str) -> Result<(), Box<dyn std::error::Error>> {
+fn cmd_train_dict(
+    path: &Path,
+    samples: usize,
+    size: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    let entries = db.scan_prefix(prefix)?;
-    for (k, v) in entries {
-        println!("{} = {}", k, String::from_utf8_lossy(&v));
-    }
+    let dict = db.train_dictionary(samples, size)?;
+    println!(
+        "Trained dictionary {} ({} bytes from {} samples); zstd enabled",
+        &dict.id[..8],
+        dict.size,
+        dict.samples
+    );
     Ok(())
 }
 
-fn cmd_log(path: &Path, limit: usize) -> Result<(), Box<dyn std::error::Error>> {
+struct PutArgs {
+    value: Option<String>,
+    file: Option<PathBuf>,
+    stdin: bool,
+    message: Option<String>,
+    holder: Option<String>,
+}
+
+fn cmd_put(
+    path: &Path,
+    scope: ScopeArgs,
+    key: &str,
+    args: PutArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let PutArgs {
+        value,
+        file,
+        stdin,
+        message,
+        holder,
+    } = args;
+    let msg = message.as_deref();
     let db = Database::open(path)?;
-    let log = db.log()?;
-    for commit in log.iter().take(limit) {
-        println!(
-            "{} {} {}",
-            &commit.id[..8],
-            commit.timestamp.format("%Y-%m-%d %H:%M:%S"),
-            commit.message,
-        );
-    }
-    if log.is_empty() {
-        println!("(no commits yet)");
-    }
+    let scope = Scope::of(&db, scope)?;
+    let commit = match (file, holder.as_deref()) {
+        (_, Some(holder)) => {
+            let Scope::Default(db) = scope else {
+                return Err("--holder cannot be used with --ns or --branch".into());
+            };
+            let value = value.unwrap_or_default().into_bytes();
+            db.put_as(key, value, holder, msg)?
+        }
+        (Some(file), None) => {
+            let reader = std::io::BufReader::new(File::open(file)?);
+            branch_scoped!(&scope, put_reader(key, reader, msg))?
+        }
+        (None, None) if stdin => {
+            branch_scoped!(&scope, put_reader(key, std::io::stdin().lock(), msg))?
+        }
+        (None, None) => {
+            let value = value.unwrap_or_default().into_bytes();
+            branch_scoped!(&scope, put(key, value, msg))?
+        }
+    };
+    println!("[{}] {}", &commit.id[..8], commit.message);
     Ok(())
 }
 
-fn cmd_branch(path: &Path, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+fn cmd_lock(
+    path: &Path,
+    key: &str,
+    holder: &str,
+    ttl: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    db.create_branch(name)?;
-    println!("Created branch '{}'", name);
+    let lock = db.lock_key(key, holder, std::time::Duration::from_secs(ttl))?;
+    println!(
+        "Locked {} for {} until {}",
+        lock.key,
+        lock.holder,
+        lock.expires_at.format("%Y-%m-%d %H:%M:%S")
+    );
     Ok(())
 }
 
-fn cmd_checkout(path: &Path, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+fn cmd_unlock(path: &Path, key: &str, holder: &str) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    db.checkout(name)?;
-    println!("Switched to branch '{}'", name);
+    match db.unlock_key(key, holder)? {
+        true => println!("Unlocked {}", key),
+        false => println!("{} was not locked by {}", key, holder),
+    }
     Ok(())
 }
 
-fn cmd_branches(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+fn cmd_quota(
+    path: &Path,
+    prefix: Option<&str>,
+    max_keys: Option<u64>,
+    max_bytes: Option<u64>,
+    remove: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    let current = db.current_branch()?;
-    let branches = db.branches()?;
-    for b in branches {
-        if b == current {
-            println!("* {}", b);
-        } else {
-            println!("  {}", b);
+    if let Some(prefix) = prefix {
+        if remove {
+            if !db.remove_quota(prefix)? {
+                return Err(format!("no quota on {:?}", prefix).into());
+            }
+        } else if max_keys.is_some() || max_bytes.is_some() {
+            let mut quota = db.config().quotas.get(prefix).copied().unwrap_or_default();
+            quota.max_keys = max_keys.unwrap_or(quota.max_keys);
+            quota.max_bytes = max_bytes.unwrap_or(quota.max_bytes);
+            db.set_quota(prefix, quota)?;
         }
     }
+    let usages = db.quotas()?;
+    if usages.is_empty() {
+        println!("(no quotas)");
+    }
+    let limit = |max: u64| match max {
+        0 => "unlimited".to_string(),
+        max => max.to_string(),
+    };
+    for usage in usages
+        .iter()
+        .filter(|u| prefix.is_none_or(|p| u.prefix == p))
+    {
+        println!(
+            "{:?}  keys {}/{}  bytes {}/{}",
+            usage.prefix,
+            usage.keys,
+            limit(usage.quota.max_keys),
+            usage.bytes,
+            limit(usage.quota.max_bytes)
+        );
+    }
     Ok(())
 }
 
-fn cmd_delete_branch(path: &Path, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+fn cmd_limits(
+    path: &Path,
+    max_key_len: Option<usize>,
+    key_pattern: Option<String>,
+    no_key_pattern: bool,
+    max_value_size: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    db.delete_branch(name)?;
-    println!("Deleted branch '{}'", name);
+    let mut limits = db.config().limits;
+    let before = limits.clone();
+    limits.max_key_len = max_key_len.unwrap_or(limits.max_key_len);
+    limits.max_value_size = max_value_size.unwrap_or(limits.max_value_size);
+    if no_key_pattern {
+        limits.key_pattern = None;
+    } else if key_pattern.is_some() {
+        limits.key_pattern = key_pattern;
+    }
+    if limits != before {
+        db.set_write_limits(limits.clone())?;
+    }
+    let limit = |max: u64| match max {
+        0 => "unlimited".to_string(),
+        max => max.to_string(),
+    };
+    println!("max key length: {}", limit(limits.max_key_len as u64));
+    println!(
+        "key pattern:    {}",
+        limits.key_pattern.as_deref().unwrap_or("(none)")
+    );
+    println!("max value size: {}", limit(limits.max_value_size));
     Ok(())
 }
 
-fn cmd_diff(path: &Path, a: &str, b: &str) -> Result<(), Box<dyn std::error::Error>> {
+fn cmd_locks(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    let diff = db.diff(a, b)?;
-    if diff.is_empty() {
-        println!("No differences");
-    } else {
-        for k in &diff.added {
-            println!("+ {}", k);
+    let locks = db.key_locks()?;
+    if locks.is_empty() {
+        println!("(no locks)");
+    }
+    for lock in locks {
+        println!(
+            "{}  {}  until {}",
+            lock.key,
+            lock.holder,
+            lock.expires_at.format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+    Ok(())
+}
+
+fn cmd_get(
+    path: &Path,
+    scope: ScopeArgs,
+    key: &str,
+    at: Option<&str>,
+    output: Option<&Path>,
+    encoding: Encoding,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let scope = Scope::of(&db, scope)?;
+    let at = at.map(|spec| db.resolve_ref(spec)).transpose()?;
+    match (at.as_deref(), output) {
+        // Into a temp file renamed over `output` once complete, so that a
+        // missing key or a failed read leaves an existing file untouched.
+        (None, Some(output)) => {
+            let written = backend::write_atomic_with(output, |file| {
+                branch_scoped!(&scope, get_writer(key, std::io::BufWriter::new(file)))
+            })?;
+            eprintln!("Wrote {} bytes to {}", written, output.display());
+        }
+        (Some(commit_id), Some(output)) => {
+            let value = branch_scoped!(&scope, get_at(key, commit_id))?;
+            backend::write_atomic(output, &value)?;
+            eprintln!("Wrote {} bytes to {}", value.len(), output.display());
+        }
+        (at, None) => {
+            let value = match at {
+                Some(commit_id) => branch_scoped!(&scope, get_at(key, commit_id))?,
+                None => branch_scoped!(&scope, get(key))?,
+            };
+            if json {
+                return print_json(&entry_json(key, &value));
+            }
+            match encoding {
+                Encoding::Text => match std::str::from_utf8(&value) {
+                    Ok(text) => println!("{}", text),
+                    Err(_) => {
+                        return Err(format!(
+                        "value of {} is not UTF-8; use --encoding raw, hex or base64, or --output",
+                        key
+                    )
+                        .into())
+                    }
+                },
+                Encoding::Raw => {
+                    let mut stdout = std::io::stdout().lock();
+                    stdout.write_all(&value)?;
+                    stdout.flush()?;
+                }
+                Encoding::Hex => println!("{}", hex(&value)),
+                Encoding::Base64 => println!("{}", base64(&value)),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn cmd_stat(path: &Path, key: &str, prefix: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let stats = if prefix {
+        db.stat_prefix(key)?
+    } else {
+        vec![db.stat_key(key)?]
+    };
+    for st in &stats {
+        let storage = if st.inline {
+            "inline".to_string()
+        } else if let Some(hash) = &st.block_hash {
+            format!("block {}", &hash[..8])
+        } else if let Some(hash) = &st.blob_hash {
+            format!("blob {}", &hash[..8])
+        } else {
+            format!("{} chunks", st.chunks)
+        };
+        println!(
+            "{}  {} bytes  {}  [{}] {}",
+            st.key,
+            st.size,
+            storage,
+            &st.last_commit.id[..8],
+            st.last_commit.timestamp.format("%Y-%m-%d %H:%M:%S"),
+        );
+    }
+    Ok(())
+}
+
+fn cmd_delete(
+    path: &Path,
+    scope: ScopeArgs,
+    key: &str,
+    msg: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let commit = branch_scoped!(&Scope::of(&db, scope)?, delete(key, msg))?;
+    println!("[{}] {}", &commit.id[..8], commit.message);
+    Ok(())
+}
+
+fn cmd_apply_batch(
+    path: &Path,
+    branch: Option<&str>,
+    file: &Path,
+    msg: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let batch = WriteBatch::from_jsonl(std::io::BufReader::new(File::open(file)?))
+        .map_err(|e| format!("{}: {}", file.display(), e))?;
+    let msg = msg
+        .map(String::from)
+        .unwrap_or_else(|| format!("apply {} operations from {}", batch.len(), file.display()));
+    let db = Database::open(path)?;
+    let commit = match branch {
+        Some(branch) => db.on_branch(branch)?.write(&batch, Some(&msg))?,
+        None => db.write(&batch, Some(&msg))?,
+    };
+    match commit {
+        Some(commit) => println!("[{}] {}", &commit.id[..8], commit.message),
+        None => println!("(no operations)"),
+    }
+    Ok(())
+}
+
+fn cmd_delete_range(
+    path: &Path,
+    ns: Option<&str>,
+    start: &str,
+    end: &str,
+    msg: Option<&str>,
+    dry_run: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let scope = Scope::new(&db, ns)?;
+    if dry_run {
+        let keys = scoped!(&scope, range_keys(start, end))?;
+        println!("Would delete {} key(s):", keys.len());
+        for key in &keys {
+            println!("- {}", key);
+        }
+        return Ok(());
+    }
+    match scoped!(&scope, delete_range(start, end, msg))? {
+        Some(commit) => println!("[{}] {}", &commit.id[..8], commit.message),
+        None => println!("(no keys in range)"),
+    }
+    Ok(())
+}
+
+struct ScanArgs {
+    compile: Option<fn(&str) -> iceberg::error::Result<KeyPattern>>,
+    limit: Option<usize>,
+    after: Option<String>,
+    keys_only: bool,
+    at: Option<String>,
+}
+
+fn cmd_scan(
+    path: &Path,
+    scope: ScopeArgs,
+    prefix: &str,
+    args: ScanArgs,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ScanArgs {
+        compile,
+        limit,
+        after,
+        keys_only,
+        at,
+    } = args;
+    let db = Database::open(path)?;
+    let at = at.map(|spec| db.resolve_ref(&spec)).transpose()?;
+    let matching = compile.map(|compile| compile(prefix)).transpose()?;
+    let prefix = matching.as_ref().map_or(prefix, |p| p.prefix()).to_string();
+    let prefix = prefix.as_str();
+    // One entry past the limit tells whether there is more.
+    let options = ScanOptions {
+        limit: limit.map(|n| n + 1),
+        start_after: after,
+        matching,
+        ..Default::default()
+    };
+    let scope = Scope::of(&db, scope)?;
+    let (more, last) = if keys_only {
+        let mut keys = match &at {
+            Some(commit_id) => branch_scoped!(&scope, scan_keys_at(prefix, commit_id, &options))?,
+            None => branch_scoped!(&scope, scan_keys_with(prefix, &options))?,
+        };
+        let more = limit.is_some_and(|n| keys.len() > n);
+        keys.truncate(limit.unwrap_or(keys.len()));
+        if json {
+            let next = keys.last().filter(|_| more);
+            return print_json(&serde_json::json!({ "keys": keys, "next": next }));
+        }
+        for k in &keys {
+            println!("{}", k);
+        }
+        (more, keys.pop())
+    } else {
+        let mut entries = match &at {
+            Some(commit_id) => branch_scoped!(&scope, scan_prefix_at(prefix, commit_id, &options))?,
+            None => branch_scoped!(&scope, scan_prefix_with(prefix, &options))?,
+        };
+        let more = limit.is_some_and(|n| entries.len() > n);
+        entries.truncate(limit.unwrap_or(entries.len()));
+        if json {
+            let next = entries.last().filter(|_| more).map(|(k, _)| k);
+            let entries: Vec<_> = entries.iter().map(|(k, v)| entry_json(k, v)).collect();
+            return print_json(&serde_json::json!({ "entries": entries, "next": next }));
+        }
+        for (k, v) in &entries {
+            println!("{} = {}", k, String::from_utf8_lossy(v));
+        }
+        (more, entries.pop().map(|(k, _)| k))
+    };
+    if let (true, Some(last)) = (more, last) {
+        println!("(more: --after {})", last);
+    }
+    Ok(())
+}
+
+struct LogArgs {
+    limit: usize,
+    skip: usize,
+    graph: bool,
+    all: bool,
+    stat: bool,
+}
+
+fn cmd_log(
+    path: &Path,
+    branch: Option<&str>,
+    args: LogArgs,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let LogArgs {
+        limit,
+        skip,
+        graph,
+        all,
+        stat,
+    } = args;
+    let db = Database::open(path)?;
+    if graph && json {
+        return Err("--graph has no JSON form".into());
+    }
+    if branch.is_some() && (graph || all) {
+        return Err("--branch shows one branch; drop --graph or --all".into());
+    }
+    if graph {
+        let lines = db.log_graph(limit)?;
+        for line in &lines {
+            println!("{}", line);
+        }
+        if lines.is_empty() {
+            println!("(no commits yet)");
+        }
+        return Ok(());
+    }
+    let log = match (branch, all) {
+        (Some(branch), _) => db
+            .on_branch(branch)?
+            .log_iter()?
+            .skip(skip)
+            .take(limit)
+            .collect::<iceberg::error::Result<_>>()?,
+        (None, true) => db.log_all()?.into_iter().skip(skip).take(limit).collect(),
+        (None, false) => db.log_page(limit, skip)?,
+    };
+    if json {
+        let mut commits = Vec::with_capacity(log.len());
+        for commit in &log {
+            let mut value = serde_json::to_value(commit)?;
+            if stat {
+                value["stat"] = serde_json::to_value(db.commit_stat(&commit.id)?)?;
+            }
+            commits.push(value);
+        }
+        return print_json(&commits);
+    }
+    for commit in &log {
+        println!(
+            "{} {} {}",
+            &commit.id[..8],
+            commit.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            commit.message,
+        );
+        if stat {
+            println!("    {}", db.commit_stat(&commit.id)?);
+        }
+    }
+    if log.is_empty() {
+        println!("(no commits yet)");
+    }
+    Ok(())
+}
+
+fn cmd_graph(
+    path: &Path,
+    limit: Option<usize>,
+    dot: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let limit = limit.unwrap_or(usize::MAX);
+    if dot {
+        print!("{}", db.log_dot(limit)?);
+        return Ok(());
+    }
+    let lines = db.log_graph(limit)?;
+    for line in &lines {
+        println!("{}", line);
+    }
+    if lines.is_empty() {
+        println!("(no commits yet)");
+    }
+    Ok(())
+}
+
+fn cmd_branch(path: &Path, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    db.create_branch(name)?;
+    println!("Created branch '{}'", name);
+    Ok(())
+}
+
+fn cmd_checkout(path: &Path, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    db.checkout(name)?;
+    match db.detached_head()? {
+        Some(tag) => println!(
+            "HEAD is now detached at tag '{}' ({}); check out a branch to write",
+            tag,
+            &db.head_commit()?.id[..8]
+        ),
+        None => println!("Switched to branch '{}'", name),
+    }
+    Ok(())
+}
+
+fn cmd_branches(path: &Path, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let detached = db.detached_head()?;
+    // While HEAD is detached no branch is current.
+    let current = match detached {
+        Some(_) => None,
+        None => Some(db.current_branch()?),
+    };
+    let is_current = |b: &String| current.as_ref() == Some(b);
+    let branches = db.branches()?;
+    if json {
+        let branches: Vec<_> = branches
+            .iter()
+            .map(|b| serde_json::json!({ "name": b, "current": is_current(b) }))
+            .collect();
+        return print_json(&branches);
+    }
+    if let Some(tag) = detached {
+        println!("* (HEAD detached at tag {})", tag);
+    }
+    for b in branches {
+        if is_current(&b) {
+            println!("* {}", b);
+        } else {
+            println!("  {}", b);
         }
-        for k in &diff.removed {
-            println!("- {}", k);
+    }
+    Ok(())
+}
+
+fn cmd_delete_branch(path: &Path, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    db.delete_branch(name)?;
+    println!("Deleted branch '{}'", name);
+    Ok(())
+}
+
+fn cmd_diff(
+    path: &Path,
+    a: &str,
+    b: &str,
+    values: bool,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let (a, b) = (&db.resolve_ref(a)?, &db.resolve_ref(b)?);
+    if !values {
+        let diff = db.diff(a, b)?;
+        match json {
+            true => print_json(&diff)?,
+            false => print_diff(&diff),
+        }
+        return Ok(());
+    }
+    let changes = db.diff_values(a, b)?;
+    if json {
+        let side = |value: &Option<Vec<u8>>| value.as_deref().map(bytes_json);
+        let changes: Vec<_> = changes
+            .iter()
+            .map(|c| serde_json::json!({ "key": c.key, "old": side(&c.old), "new": side(&c.new) }))
+            .collect();
+        return print_json(&changes);
+    }
+    if changes.is_empty() {
+        println!("No differences");
+    }
+    for change in changes {
+        let (sign, old, new) = match (&change.old, &change.new) {
+            (None, Some(new)) => ('+', &[][..], &new[..]),
+            (Some(old), None) => ('-', &old[..], &[][..]),
+            (Some(old), Some(new)) => ('~', &old[..], &new[..]),
+            (None, None) => continue,
+        };
+        println!("{} {}", sign, change.key);
+        match (textdiff::as_text(old), textdiff::as_text(new)) {
+            (Some(old), Some(new)) => {
+                for line in textdiff::unified(&old, &new, 3) {
+                    println!("  {}", line);
+                }
+            }
+            _ => println!("  binary value: {} -> {} bytes", old.len(), new.len()),
         }
-        for k in &diff.modified {
-            println!("~ {}", k);
+    }
+    Ok(())
+}
+
+fn cmd_diff_stat(
+    path: &Path,
+    a: &str,
+    b: &str,
+    delimiter: char,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let stats = db.diff_stat(&db.resolve_ref(a)?, &db.resolve_ref(b)?, delimiter)?;
+    if json {
+        let mut total = DiffStat::default();
+        let mut prefixes = Vec::with_capacity(stats.len());
+        for (prefix, stat) in stats {
+            total += stat;
+            let mut value = serde_json::to_value(stat)?;
+            value["prefix"] = prefix.into();
+            prefixes.push(value);
         }
+        return print_json(&serde_json::json!({ "prefixes": prefixes, "total": total }));
+    }
+    if stats.is_empty() {
+        println!("No differences");
+        return Ok(());
+    }
+    let width = stats
+        .iter()
+        .map(|(prefix, _)| prefix.len())
+        .max()
+        .unwrap_or(0);
+    let mut total = DiffStat::default();
+    for (prefix, stat) in stats {
+        println!("{:width$}  {}", prefix, stat, width = width);
+        total += stat;
+    }
+    println!("total: {}", total);
+    Ok(())
+}
+
+fn cmd_show(path: &Path, spec: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let commit = db.get_commit(&db.resolve_ref(spec)?)?;
+    println!("commit {}", commit.id);
+    if let Some(parent) = &commit.parent {
+        println!("parent {}", parent);
     }
+    println!(
+        "date   {}",
+        commit.timestamp.format("%Y-%m-%d %H:%M:%S UTC")
+    );
+    println!();
+    println!("    {}", commit.message);
+    println!();
+    print_diff(&db.commit_diff(&commit.id)?);
+    Ok(())
+}
+
+fn cmd_inspect(
+    path: &Path,
+    kind: &str,
+    id: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let object = match (kind, id) {
+        ("refs", _) => db.inspect_refs()?,
+        (kind, Some(id)) => match kind.parse()? {
+            ObjectKind::Commit => db.inspect(ObjectKind::Commit, &db.resolve_ref(id)?)?,
+            kind => db.inspect(kind, id)?,
+        },
+        (kind, None) => return Err(format!("inspect {} needs an id", kind).into()),
+    };
+    println!("{}", serde_json::to_string_pretty(&object)?);
     Ok(())
 }
 
+fn print_json(value: &impl serde::Serialize) -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// A value for JSON output: `{"text": ..}` when it is UTF-8, `{"hex": ..}`
+/// otherwise, as `inspect block` shows blocks.
+fn bytes_json(value: &[u8]) -> serde_json::Value {
+    match std::str::from_utf8(value) {
+        Ok(text) => serde_json::json!({ "text": text }),
+        Err(_) => serde_json::json!({ "hex": hex(value) }),
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Standard base64 with padding.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().fold(0u32, |n, &b| n << 8 | b as u32) << (8 * (3 - chunk.len()));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+/// A key and its value for JSON output; see `bytes_json`.
+fn entry_json(key: &str, value: &[u8]) -> serde_json::Value {
+    let mut entry = bytes_json(value);
+    entry["key"] = key.into();
+    entry
+}
+
+fn print_diff(diff: &TreeDiff) {
+    if diff.is_empty() {
+        println!("No differences");
+    }
+    for k in &diff.added {
+        println!("+ {}", k);
+    }
+    for k in &diff.removed {
+        println!("- {}", k);
+    }
+    for k in &diff.modified {
+        println!("~ {}", k);
+    }
+}
+
 fn cmd_merge(
     path: &Path,
     branch: &str,
     msg: Option<&str>,
+    dry_run: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
+    if dry_run {
+        println!("Would merge '{}' into '{}':", branch, db.current_branch()?);
+        print_diff(&db.merge_preview(branch)?);
+        return Ok(());
+    }
     let commit = db.merge(branch, msg)?;
     println!("[{}] {}", &commit.id[..8], commit.message);
     Ok(())
@@ -313,8 +2101,18 @@ fn cmd_cherry_pick(
     path: &Path,
     commit_id: &str,
     msg: Option<&str>,
+    dry_run: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
+    if dry_run {
+        println!(
+            "Would apply {} to '{}':",
+            &commit_id[..8.min(commit_id.len())],
+            db.current_branch()?
+        );
+        print_diff(&db.cherry_pick_preview(commit_id)?);
+        return Ok(());
+    }
     let commit = db.cherry_pick(commit_id, msg)?;
     println!("[{}] {}", &commit.id[..8], commit.message);
     Ok(())
@@ -325,16 +2123,106 @@ fn cmd_tag(
     name: &str,
     commit: Option<&str>,
     msg: Option<&str>,
+    force: bool,
+    sign_key: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    // Read the key first so a bad key file leaves no unsigned tag behind.
+    let key = match sign_key {
+        Some(file) => Some(iceberg::tag::parse_signing_key(&std::fs::read_to_string(
+            file,
+        )?)?),
+        None => None,
+    };
+    let mut tag = match force {
+        true => db.move_tag(name, commit, msg)?,
+        false => db.create_tag(name, commit, msg)?,
+    };
+    if let Some(key) = &key {
+        tag = db.sign_tag(name, key)?;
+    }
+    let signed = if tag.signature.is_some() {
+        " (signed)"
+    } else {
+        ""
+    };
+    println!("Tagged {} → {}{}", tag.name, &tag.commit_id[..8], signed);
+    Ok(())
+}
+
+fn cmd_tag_key(file: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    use rand_core::RngCore;
+    let mut secret = [0u8; 32];
+    rand_core::OsRng.fill_bytes(&mut secret);
+    let key = iceberg::tag::SigningKey::from_bytes(&secret);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut out = options.open(file)?;
+    writeln!(out, "{}", iceberg::tag::to_hex(&key.to_bytes()))?;
+    println!("{}", iceberg::tag::to_hex(key.verifying_key().as_bytes()));
+    Ok(())
+}
+
+fn cmd_verify_tag(
+    path: &Path,
+    name: &str,
+    public_key: Option<&str>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    let tag = db.create_tag(name, commit, msg)?;
-    println!("Tagged {} → {}", tag.name, &tag.commit_id[..8]);
+    let trusted = public_key
+        .map(iceberg::tag::parse_verifying_key)
+        .transpose()?;
+    let (tag, key) = db.verify_tag(name, trusted.as_ref())?;
+    println!(
+        "Good signature on {} → {} by {}",
+        tag.name,
+        &tag.commit_id[..8],
+        iceberg::tag::to_hex(key.as_bytes())
+    );
+    Ok(())
+}
+
+fn cmd_tag_log(path: &Path, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let entries = db.tag_log(name)?;
+    if entries.is_empty() {
+        println!("(no changes to tag '{}')", name);
+    }
+    let short = |id: &Option<String>| match id {
+        Some(id) => id[..8.min(id.len())].to_string(),
+        None => "-".repeat(8),
+    };
+    for entry in &entries {
+        println!(
+            "{} {} → {}",
+            entry.at.format("%Y-%m-%d %H:%M:%S"),
+            short(&entry.old),
+            short(&entry.new)
+        );
+    }
     Ok(())
 }
 
-fn cmd_tags(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+fn cmd_tags(
+    path: &Path,
+    pattern: Option<&str>,
+    sort: TagSort,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    let tags = db.tags()?;
+    let mut tags = match pattern {
+        Some(pattern) => db.tags_matching(pattern)?,
+        None => db.tags()?,
+    };
+    match sort {
+        TagSort::Created => tags.sort_by_key(|t| std::cmp::Reverse(t.created_at)),
+        TagSort::Semver => iceberg::tag::sort_by_version(&mut tags),
+    }
+    if json {
+        return print_json(&tags);
+    }
     if tags.is_empty() {
         println!("(no tags)");
     } else {
@@ -344,11 +2232,17 @@ fn cmd_tags(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
                 .as_deref()
                 .map(|m| format!(" — {}", m))
                 .unwrap_or_default();
+            let signed = if tag.signature.is_some() {
+                " (signed)"
+            } else {
+                ""
+            };
             println!(
-                "{} → {} {}{}",
+                "{} → {} {}{}{}",
                 tag.name,
                 &tag.commit_id[..8],
                 tag.created_at.format("%Y-%m-%d %H:%M:%S"),
+                signed,
                 msg,
             );
         }
@@ -363,9 +2257,21 @@ fn cmd_delete_tag(path: &Path, name: &str) -> Result<(), Box<dyn std::error::Err
     Ok(())
 }
 
-fn cmd_rebase(path: &Path, onto: &str) -> Result<(), Box<dyn std::error::Error>> {
+fn cmd_rebase(path: &Path, onto: &str, dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    let commits = db.rebase(onto)?;
+    if dry_run {
+        let commits = db.rebase_preview(onto)?;
+        if commits.is_empty() {
+            println!("Nothing to rebase — already up to date.");
+        } else {
+            println!("Would replay {} commit(s) onto '{}':", commits.len(), onto);
+            for c in &commits {
+                println!("  [{}] {}", &c.id[..8], c.message);
+            }
+        }
+        return Ok(());
+    }
+    let commits = db.rebase_with_progress(onto, progress("Replaying"))?;
     if commits.is_empty() {
         println!("Nothing to rebase — already up to date.");
     } else {
@@ -377,35 +2283,131 @@ fn cmd_rebase(path: &Path, onto: &str) -> Result<(), Box<dyn std::error::Error>>
     Ok(())
 }
 
+/// Report `(done, total)` progress on one line of stderr.
+fn progress(label: &'static str) -> impl FnMut(usize, usize) {
+    move |done, total| {
+        if total > 0 {
+            eprint!("\r{}: {}/{}", label, done, total);
+            if done == total {
+                eprintln!();
+            }
+        }
+    }
+}
+
 fn cmd_create_index(
     path: &Path,
+    ns: Option<&str>,
     name: &str,
     field: &str,
+    options: IndexOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    db.create_index(name, field)?;
-    println!("Created index '{}' on field '{}'", name, field);
+    scoped!(
+        &Scope::new(&db, ns)?,
+        create_index_with_progress(name, field, &options, progress("Indexing values"))
+    )?;
+    let unique = if options.unique { "unique " } else { "" };
+    let on = match &options.source {
+        IndexSource::Value => format!("field '{}'", field),
+        IndexSource::Key {
+            delimiter,
+            position,
+        } => format!("key part {} (split on '{}')", position, delimiter),
+    };
+    println!(
+        "Created {}{} index '{}' on {}",
+        unique, options.kind, name, on
+    );
     Ok(())
 }
 
-fn cmd_drop_index(path: &Path, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+fn cmd_drop_index(
+    path: &Path,
+    ns: Option<&str>,
+    name: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    db.drop_index(name)?;
+    scoped!(&Scope::new(&db, ns)?, drop_index(name))?;
     println!("Dropped index '{}'", name);
     Ok(())
 }
 
+fn cmd_reindex(
+    path: &Path,
+    ns: Option<&str>,
+    name: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let rebuilt = scoped!(
+        &Scope::new(&db, ns)?,
+        rebuild_index_with(name, progress("Reading values"))
+    )?;
+    if rebuilt.is_empty() {
+        println!("(no indexes)");
+    }
+    for name in rebuilt {
+        println!("Rebuilt index '{}'", name);
+    }
+    Ok(())
+}
+
+/// What `query-index` looks for.
+enum IndexQuery {
+    Exact(String),
+    Entries(String),
+    Prefix(String),
+    Range(Option<String>, Option<String>),
+    Page(Option<String>, Option<String>, PageOptions),
+    /// A value as of a commit, branch or tag.
+    At(String, String),
+}
+
 fn cmd_query_index(
     path: &Path,
+    ns: Option<&str>,
     name: &str,
-    value: &str,
-    prefix: bool,
+    query: IndexQuery,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    let keys = if prefix {
-        db.query_index_prefix(name, value)?
-    } else {
-        db.query_index(name, value)?
+    let scope = Scope::new(&db, ns)?;
+    let keys = match &query {
+        IndexQuery::Exact(value) => scoped!(&scope, query_index(name, value))?,
+        IndexQuery::Entries(value) => {
+            let entries = scoped!(&scope, query_index_entries(name, value))?;
+            if entries.is_empty() {
+                println!("(no matches)");
+            }
+            for (k, v) in entries {
+                println!("{} = {}", k, String::from_utf8_lossy(&v));
+            }
+            return Ok(());
+        }
+        IndexQuery::Page(min, max, options) => {
+            let page = scoped!(
+                &scope,
+                query_index_page(name, min.as_deref(), max.as_deref(), options)
+            )?;
+            if page.entries.is_empty() {
+                println!("(no matches)");
+            }
+            for (k, value) in page.entries {
+                println!("{}\t{}", value, k);
+            }
+            if let Some(cursor) = page.next {
+                println!("(more: --after {})", cursor);
+            }
+            return Ok(());
+        }
+        IndexQuery::At(value, spec) => {
+            let commit_id = db.resolve_ref(spec)?;
+            scoped!(&scope, query_index_at(name, value, &commit_id))?
+        }
+        IndexQuery::Prefix(value) => scoped!(&scope, query_index_prefix(name, value))?,
+        IndexQuery::Range(min, max) => scoped!(
+            &scope,
+            query_index_range(name, min.as_deref(), max.as_deref())
+        )?,
     };
     if keys.is_empty() {
         println!("(no matches)");
@@ -417,9 +2419,170 @@ fn cmd_query_index(
     Ok(())
 }
 
-fn cmd_indexes(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+fn cmd_create_vector_index(
+    path: &Path,
+    ns: Option<&str>,
+    name: &str,
+    field: &str,
+    options: &VectorOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    scoped!(
+        &Scope::new(&db, ns)?,
+        create_vector_index(name, field, options)
+    )?;
+    println!(
+        "Created {}-dimensional {} vector index '{}' on field '{}'",
+        options.dimensions, options.metric, name, field
+    );
+    Ok(())
+}
+
+fn cmd_query_vector(
+    path: &Path,
+    ns: Option<&str>,
+    name: &str,
+    vector: &str,
+    k: usize,
+    at: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let query = vector
+        .split(',')
+        .map(|x| x.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid query vector {:?}: {}", vector, e))?;
+    let db = Database::open(path)?;
+    let scope = Scope::new(&db, ns)?;
+    let hits = match at {
+        Some(spec) => {
+            let commit_id = db.resolve_ref(spec)?;
+            scoped!(&scope, query_vector_at(name, &query, k, &commit_id))?
+        }
+        None => scoped!(&scope, query_vector(name, &query, k))?,
+    };
+    if hits.is_empty() {
+        println!("(no matches)");
+    }
+    for (key, distance) in hits {
+        println!("{:.4}  {}", distance, key);
+    }
+    Ok(())
+}
+
+fn cmd_query(
+    path: &Path,
+    ns: Option<&str>,
+    query: &Query,
+    explain: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let scope = Scope::new(&db, ns)?;
+    if explain {
+        println!("{}", scoped!(&scope, explain(query)));
+        return Ok(());
+    }
+    let matches = scoped!(&scope, query(query))?;
+    if matches.is_empty() {
+        println!("(no matches)");
+    }
+    for (k, v) in matches {
+        println!("{} = {}", k, String::from_utf8_lossy(&v));
+    }
+    Ok(())
+}
+
+fn cmd_aggregate(
+    path: &Path,
+    ns: Option<&str>,
+    query: &Query,
+    group_by: Option<&str>,
+    aggregate: &Aggregate,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let scope = Scope::new(&db, ns)?;
+    let groups = scoped!(&scope, aggregate(query, group_by, aggregate))?;
+    if groups.is_empty() {
+        println!("(no values)");
+    }
+    for (group, value) in groups {
+        match (group_by, group) {
+            (None, _) => println!("{}", value),
+            (Some(_), Some(group)) => println!("{}\t{}", group, value),
+            (Some(_), None) => println!("(none)\t{}", value),
+        }
+    }
+    Ok(())
+}
+
+fn cmd_count(
+    path: &Path,
+    scope: ScopeArgs,
+    prefix: &str,
+    at: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let scope = Scope::of(&db, scope)?;
+    let count = match at {
+        Some(spec) => {
+            let commit_id = db.resolve_ref(spec)?;
+            branch_scoped!(&scope, count_at(prefix, &commit_id))?
+        }
+        None => branch_scoped!(&scope, count(prefix))?,
+    };
+    println!("{}", count);
+    Ok(())
+}
+
+fn cmd_sample(
+    path: &Path,
+    ns: Option<&str>,
+    n: usize,
+    prefix: &str,
+    seed: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let scope = Scope::new(&db, ns)?;
+    let keys = match seed {
+        Some(seed) => scoped!(&scope, sample_with_seed(n, prefix, seed))?,
+        None => scoped!(&scope, sample(n, prefix))?,
+    };
+    for key in keys {
+        println!("{}", key);
+    }
+    Ok(())
+}
+
+fn cmd_grep(
+    path: &Path,
+    ns: Option<&str>,
+    pattern: &str,
+    prefix: &str,
+    options: &GrepOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    let indexes = db.list_indexes();
+    let found = scoped!(&Scope::new(&db, ns)?, grep(pattern, prefix, options))?;
+    for m in &found {
+        let snippet = m.snippet.replace(['\n', '\r'], " ");
+        match &m.field {
+            Some(field) => println!("{}\t{}\t{}", m.key, field, snippet),
+            None => println!("{}\t{}", m.key, snippet),
+        }
+    }
+    if found.is_empty() {
+        println!("(no matches)");
+    }
+    Ok(())
+}
+
+fn cmd_sql(path: &Path, statement: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    print!("{}", db.sql(statement)?);
+    Ok(())
+}
+
+fn cmd_indexes(path: &Path, ns: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let indexes = scoped!(&Scope::new(&db, ns)?, list_indexes());
     if indexes.is_empty() {
         println!("(no indexes)");
     } else {
@@ -430,24 +2593,342 @@ fn cmd_indexes(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+fn cmd_distinct(
+    path: &Path,
+    spec: Option<&str>,
+    track: bool,
+    untrack: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let Some(spec) = spec else {
+        let tracked = db.tracked_distinct();
+        if tracked.is_empty() {
+            println!("(no distinct counts tracked)");
+        }
+        for (spec, estimate) in tracked {
+            println!("{}  ~{}", spec, estimate);
+        }
+        return Ok(());
+    };
+    if untrack {
+        db.untrack_distinct(spec)?;
+        println!("Stopped tracking '{}'", spec);
+        return Ok(());
+    }
+    if track {
+        db.track_distinct(spec)?;
+    }
+    println!("~{}", db.approx_distinct(spec)?);
+    Ok(())
+}
+
+fn cmd_index_stats(
+    path: &Path,
+    ns: Option<&str>,
+    name: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let scope = Scope::new(&db, ns)?;
+    let names = match name {
+        Some(name) => vec![name],
+        None => scoped!(&scope, list_indexes()),
+    };
+    if names.is_empty() {
+        println!("(no indexes)");
+    }
+    for (i, name) in names.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print!("{}", scoped!(&scope, index_stats(name))?);
+    }
+    Ok(())
+}
+
+fn cmd_namespaces(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let namespaces = db.namespaces()?;
+    if namespaces.is_empty() {
+        println!("(no namespaces)");
+    } else {
+        for (name, keys) in &namespaces {
+            println!("{}  {} keys", name, keys);
+        }
+    }
+    Ok(())
+}
+
 fn cmd_compact(
     path: &Path,
-    max_versions: usize,
-    max_age_days: Option<u64>,
+    policy: &CompactionPolicy,
+    dry_run: bool,
+    json: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    let policy = CompactionPolicy {
-        max_versions,
-        max_age_days,
+    let result = match dry_run {
+        true => db.compact_preview(policy)?,
+        false => db.compact_with_progress(policy, progress("Compacting"))?,
     };
-    let result = db.compact(&policy)?;
+    match json {
+        true => print_json(&result)?,
+        false if dry_run => print!("Dry run; nothing was removed.\n{}", result),
+        false => print!("{}", result),
+    }
+    Ok(())
+}
+
+fn cmd_purge_key(path: &Path, key: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let result = db.purge_key(key)?;
+    print!("{}", result);
+    for (old, new) in &result.rewritten {
+        println!("{} -> {}", &old[..8], &new[..8]);
+    }
+    Ok(())
+}
+
+fn cmd_squash_history(
+    path: &Path,
+    before: &str,
+    message: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let result = db.squash_history(before, message)?;
     print!("{}", result);
     Ok(())
 }
 
-fn cmd_stats(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+fn cmd_children(path: &Path, spec: &str, all: bool) -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::open(path)?;
-    let stats = db.stats()?;
+    let commit_id = db.resolve_ref(spec)?;
+    let commits = match all {
+        true => db.descendants(&commit_id)?,
+        false => db.children(&commit_id)?,
+    };
+    if commits.is_empty() {
+        println!("(no children)");
+    }
+    for commit in &commits {
+        println!(
+            "{} {} {}",
+            &commit.id[..8],
+            commit.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            commit.message,
+        );
+    }
+    Ok(())
+}
+
+fn cmd_is_ancestor(path: &Path, a: &str, b: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    if !db.is_ancestor(&db.resolve_ref(a)?, &db.resolve_ref(b)?)? {
+        return Err(format!("{} is not an ancestor of {}", a, b).into());
+    }
+    println!("{} is an ancestor of {}", a, b);
+    Ok(())
+}
+
+fn cmd_merge_base(path: &Path, a: &str, b: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    match db.merge_base(&db.resolve_ref(a)?, &db.resolve_ref(b)?)? {
+        Some(base) => println!("{}", base),
+        None => return Err(format!("{} and {} have no common ancestor", a, b).into()),
+    }
+    Ok(())
+}
+
+fn cmd_truncate_history(path: &Path, keep: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    print!("{}", db.truncate_history(keep)?);
+    Ok(())
+}
+
+struct AutoCompactArgs {
+    max_versions: Option<usize>,
+    max_age_days: Option<u64>,
+    commits: Option<usize>,
+    disk_bytes: Option<u64>,
+    inline: Option<bool>,
+    off: bool,
+}
+
+fn cmd_auto_compact(path: &Path, args: AutoCompactArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let mut auto = db.config().auto_compaction;
+    let changing = args.max_versions.is_some()
+        || args.max_age_days.is_some()
+        || args.commits.is_some()
+        || args.disk_bytes.is_some()
+        || args.inline.is_some();
+    if args.off {
+        auto = AutoCompactionConfig::default();
+        db.set_auto_compaction(auto.clone())?;
+    } else if changing {
+        let policy = auto.policy.get_or_insert_with(CompactionPolicy::default);
+        if let Some(n) = args.max_versions {
+            policy.max_versions = n;
+        }
+        if args.max_age_days.is_some() {
+            policy.max_age_days = args.max_age_days;
+        }
+        if let Some(n) = args.commits {
+            auto.commits = n;
+        }
+        if let Some(n) = args.disk_bytes {
+            auto.disk_bytes = n;
+        }
+        if let Some(inline) = args.inline {
+            auto.inline = inline;
+        }
+        db.set_auto_compaction(auto.clone())?;
+    }
+    let Some(policy) = auto.policy.as_ref().filter(|_| auto.is_enabled()) else {
+        println!("Auto-compaction: off");
+        return Ok(());
+    };
+    println!(
+        "Auto-compaction: {}",
+        if auto.inline { "inline" } else { "background" }
+    );
+    println!("Max versions:    {}", policy.max_versions);
+    match policy.max_age_days {
+        Some(days) => println!("Max age (days):  {}", days),
+        None => println!("Max age (days):  (unlimited)"),
+    }
+    println!("After commits:   {}", auto.commits);
+    println!("Above bytes:     {}", auto.disk_bytes);
+    Ok(())
+}
+
+fn cmd_stats(
+    path: &Path,
+    all_branches: bool,
+    sizes: Option<(usize, char)>,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let stats = match all_branches {
+        true => db.stats_all_branches()?,
+        false => db.stats()?,
+    };
+    if json {
+        let mut value = serde_json::to_value(&stats)?;
+        if let Some((top, delimiter)) = sizes {
+            value["sizes"] = serde_json::to_value(db.size_report(top, delimiter)?)?;
+        }
+        return print_json(&value);
+    }
     print!("{}", stats);
+    if let Some((top, delimiter)) = sizes {
+        print!("{}", db.size_report(top, delimiter)?);
+    }
+    Ok(())
+}
+
+fn cmd_status(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    print!("{}", db.status()?);
+    Ok(())
+}
+
+fn cmd_du(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    print!("{}", db.disk_usage()?);
+    Ok(())
+}
+
+fn cmd_bench(
+    options: &BenchOptions,
+    memory: bool,
+    dir: Option<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if memory {
+        let db = Database::init_with_backend(Arc::new(MemoryBackend::new()))?;
+        print!("{}", bench::run(&db, options, progress("Running"))?);
+        return Ok(());
+    }
+    let dir = dir.unwrap_or_else(|| {
+        std::env::temp_dir().join(format!("iceberg-bench-{}", std::process::id()))
+    });
+    if dir.exists() {
+        return Err(format!("{} already exists", dir.display()).into());
+    }
+    let result = Database::init(&dir).and_then(|db| bench::run(&db, options, progress("Running")));
+    std::fs::remove_dir_all(&dir)?;
+    print!("{}", result?);
+    Ok(())
+}
+
+fn cmd_fsck(
+    path: &Path,
+    lost_found: bool,
+    resurrect: Option<&str>,
+    branch: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    if let Some(prefix) = resurrect {
+        let lost = db.lost_found()?;
+        let matches: Vec<_> = lost.iter().filter(|c| c.id.starts_with(prefix)).collect();
+        let commit = match matches.as_slice() {
+            [commit] => commit,
+            [] => return Err(format!("no lost commit matches {}", prefix).into()),
+            _ => return Err(format!("{} matches several lost commits", prefix).into()),
+        };
+        let name = branch
+            .map(String::from)
+            .unwrap_or_else(|| format!("recovered-{}", &commit.id[..8]));
+        db.create_branch_at(&name, &commit.id)?;
+        println!("Created branch {} at {}", name, &commit.id[..8]);
+        return Ok(());
+    }
+    let report = db.fsck()?;
+    print!("{}", report);
+    if lost_found {
+        let lost = db.lost_found()?;
+        if lost.is_empty() {
+            println!("No lost commits");
+        }
+        for commit in lost {
+            println!(
+                "lost {} {} {}",
+                &commit.id[..8],
+                commit.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                commit.message,
+            );
+        }
+    }
+    if !report.is_healthy() {
+        return Err("integrity check failed".into());
+    }
+    Ok(())
+}
+
+fn cmd_repair(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(path)?;
+    let report = db.repair()?;
+    print!("{}", report);
+    if !report.after.is_healthy() {
+        return Err("some problems could not be repaired".into());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn cmd_mount(path: &Path, mountpoint: &Path, at: &str) -> Result<(), Box<dyn std::error::Error>> {
+    use iceberg::mount::{self, SnapshotFs};
+
+    let db = Database::open(path)?;
+    let commit = db.get_commit(&db.resolve_ref(at)?)?;
+    let tree = db.tree_at(&commit.id)?;
+    let fs = SnapshotFs::new(&db, &tree, commit.timestamp);
+    eprintln!(
+        "Mounted {} ({}) at {}; unmount with `umount {}` or `fusermount -u {}`",
+        at,
+        &commit.id[..12.min(commit.id.len())],
+        mountpoint.display(),
+        mountpoint.display(),
+        mountpoint.display()
+    );
+    mount::mount(fs, mountpoint)?;
     Ok(())
 }
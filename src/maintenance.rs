@@ -0,0 +1,108 @@
+use crate::compaction::{CompactionPolicy, CompactionResult};
+use crate::db::Database;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Default interval between background maintenance passes.
+pub const DEFAULT_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Options for `Database::open_with_maintenance`.
+#[derive(Debug, Clone)]
+pub struct MaintenanceOptions {
+    /// Time between maintenance passes.
+    pub interval: Duration,
+    /// Compaction policy applied on every pass (None = never compact).
+    pub compaction: Option<CompactionPolicy>,
+}
+
+impl Default for MaintenanceOptions {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_MAINTENANCE_INTERVAL,
+            compaction: None,
+        }
+    }
+}
+
+/// What a single maintenance pass did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    /// Whether pending bloom filter / index changes were flushed and the WAL
+    /// checkpointed.
+    pub flushed: bool,
+    /// Result of compaction, if a policy is configured.
+    pub compaction: Option<CompactionResult>,
+}
+
+/// Counters kept by the background worker.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MaintenanceStatus {
+    /// Number of completed passes.
+    pub runs: u64,
+    /// Number of passes that failed.
+    pub failures: u64,
+    /// Error message of the most recent failed pass.
+    pub last_error: Option<String>,
+}
+
+/// Handle to the background maintenance thread.
+///
+/// The thread only holds a `Weak` reference to the database, so it never
+/// keeps it alive; dropping the handle (or the database) stops it.
+pub(crate) struct MaintenanceWorker {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+    status: Arc<Mutex<MaintenanceStatus>>,
+}
+
+impl MaintenanceWorker {
+    pub(crate) fn spawn(db: Weak<Database>, options: MaintenanceOptions) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let status = Arc::new(Mutex::new(MaintenanceStatus::default()));
+        let worker_status = Arc::clone(&status);
+        let handle = thread::Builder::new()
+            .name("iceberg-maintenance".into())
+            .spawn(move || loop {
+                match stopped.recv_timeout(options.interval) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+                let Some(db) = db.upgrade() else {
+                    return;
+                };
+                let result = db.maintain(options.compaction.as_ref());
+                let mut status = worker_status.lock().unwrap();
+                status.runs += 1;
+                if let Err(e) = result {
+                    status.failures += 1;
+                    status.last_error = Some(e.to_string());
+                }
+            })
+            .expect("failed to spawn maintenance thread");
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+            status,
+        }
+    }
+
+    pub(crate) fn status(&self) -> MaintenanceStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MaintenanceWorker {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes the worker and makes it exit.
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            // The last `Arc<Database>` may be released by the worker itself
+            // mid-pass; it cannot join its own thread.
+            if handle.thread().id() != thread::current().id() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
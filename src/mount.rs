@@ -0,0 +1,647 @@
+use crate::db::Database;
+use crate::error::{IcebergError, Result};
+use crate::tree::{Tree, TreeValue};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process::Command;
+
+/// Name under which a key's value appears when other keys use the key as a
+/// directory (e.g. `a` alongside `a/b`): the directory `a` wins and the value
+/// of `a` is shown as `a/.value`.
+pub const SHADOWED_VALUE_NAME: &str = ".value";
+
+/// Inode of the root directory, fixed by the FUSE protocol.
+const ROOT_INO: u64 = 1;
+
+/// Largest read the kernel may send; the request buffer adds room for headers.
+const MAX_READ: u32 = 128 * 1024;
+
+/// Snapshots never change, so the kernel may cache entries and attributes
+/// for as long as it likes.
+const CACHE_TTL_SECS: u64 = 24 * 60 * 60;
+
+enum Node {
+    Dir {
+        parent: u64,
+        children: BTreeMap<String, u64>,
+    },
+    File {
+        value: TreeValue,
+    },
+}
+
+/// Attributes of a file or directory in a mounted snapshot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attr {
+    pub ino: u64,
+    pub size: u64,
+    pub is_dir: bool,
+}
+
+/// A snapshot's keys laid out as a read-only directory tree.
+///
+/// Slashes in keys become directories; empty path segments are skipped, and
+/// `.`/`..` segments are written as `%2E`/`%2E%2E` since they cannot be names.
+pub struct SnapshotFs<'a> {
+    db: &'a Database,
+    nodes: Vec<Node>,
+    /// Values of open files by handle, loaded once on open.
+    open: HashMap<u64, Vec<u8>>,
+    next_fh: u64,
+    mtime: DateTime<Utc>,
+}
+
+impl<'a> SnapshotFs<'a> {
+    /// Lay out `tree`; `mtime` is reported as every entry's modification time.
+    pub fn new(db: &'a Database, tree: &Tree, mtime: DateTime<Utc>) -> Self {
+        let mut fs = Self {
+            db,
+            nodes: vec![Node::Dir {
+                parent: ROOT_INO,
+                children: BTreeMap::new(),
+            }],
+            open: HashMap::new(),
+            next_fh: 1,
+            mtime,
+        };
+        for (key, value) in &tree.entries {
+            fs.add(key, value);
+        }
+        fs
+    }
+
+    fn add(&mut self, key: &str, value: &TreeValue) {
+        let mut segments: Vec<String> = key
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(|s| match s {
+                "." => "%2E".to_string(),
+                ".." => "%2E%2E".to_string(),
+                s => s.to_string(),
+            })
+            .collect();
+        let name = segments.pop().unwrap_or_else(|| SHADOWED_VALUE_NAME.into());
+        let mut dir = ROOT_INO;
+        for segment in &segments {
+            dir = self.subdir(dir, segment);
+        }
+        let file = self.push(Node::File {
+            value: value.clone(),
+        });
+        let target = match self.child(dir, &name) {
+            Some(existing) if self.is_dir(existing) => existing,
+            _ => dir,
+        };
+        let name = if target == dir {
+            name
+        } else {
+            SHADOWED_VALUE_NAME.into()
+        };
+        self.children_mut(target).insert(name, file);
+    }
+
+    /// The directory `name` inside `parent`, created if missing. A file of
+    /// that name is moved inside it as `SHADOWED_VALUE_NAME`.
+    fn subdir(&mut self, parent: u64, name: &str) -> u64 {
+        let existing = self.child(parent, name);
+        if let Some(ino) = existing.filter(|&ino| self.is_dir(ino)) {
+            return ino;
+        }
+        let mut children = BTreeMap::new();
+        if let Some(file) = existing {
+            children.insert(SHADOWED_VALUE_NAME.to_string(), file);
+        }
+        let dir = self.push(Node::Dir { parent, children });
+        self.children_mut(parent).insert(name.to_string(), dir);
+        dir
+    }
+
+    fn push(&mut self, node: Node) -> u64 {
+        self.nodes.push(node);
+        self.nodes.len() as u64
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get(ino.checked_sub(1)? as usize)
+    }
+
+    fn is_dir(&self, ino: u64) -> bool {
+        matches!(self.node(ino), Some(Node::Dir { .. }))
+    }
+
+    fn child(&self, dir: u64, name: &str) -> Option<u64> {
+        match self.node(dir)? {
+            Node::Dir { children, .. } => children.get(name).copied(),
+            Node::File { .. } => None,
+        }
+    }
+
+    fn children_mut(&mut self, dir: u64) -> &mut BTreeMap<String, u64> {
+        match &mut self.nodes[dir as usize - 1] {
+            Node::Dir { children, .. } => children,
+            Node::File { .. } => unreachable!("inode {} is not a directory", dir),
+        }
+    }
+
+    /// Look up `name` in directory `parent`.
+    pub fn lookup(&self, parent: u64, name: &str) -> Option<u64> {
+        self.child(parent, name)
+    }
+
+    /// Attributes of an inode.
+    pub fn attr(&self, ino: u64) -> Option<Attr> {
+        Some(match self.node(ino)? {
+            Node::Dir { .. } => Attr {
+                ino,
+                size: 0,
+                is_dir: true,
+            },
+            Node::File { value } => Attr {
+                ino,
+                size: value.size(),
+                is_dir: false,
+            },
+        })
+    }
+
+    /// Entries of a directory, including `.` and `..`, as (inode, name, is_dir).
+    pub fn read_dir(&self, ino: u64) -> Option<Vec<(u64, String, bool)>> {
+        let Node::Dir { parent, children } = self.node(ino)? else {
+            return None;
+        };
+        let mut entries = vec![
+            (ino, ".".to_string(), true),
+            (*parent, "..".to_string(), true),
+        ];
+        for (name, &child) in children {
+            entries.push((child, name.clone(), self.is_dir(child)));
+        }
+        Some(entries)
+    }
+
+    /// Open a file, loading its value. Returns a handle for `read`.
+    pub fn open(&mut self, ino: u64) -> Result<u64> {
+        let Some(Node::File { value }) = self.node(ino) else {
+            return Err(IcebergError::Corruption(format!("not a file: {}", ino)));
+        };
+        let data = self.db.load_value(value)?;
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.open.insert(fh, data);
+        Ok(fh)
+    }
+
+    /// Read up to `size` bytes at `offset` from an open file.
+    pub fn read(&self, fh: u64, offset: u64, size: usize) -> Option<&[u8]> {
+        let data = self.open.get(&fh)?;
+        let start = (offset as usize).min(data.len());
+        let end = start.saturating_add(size).min(data.len());
+        Some(&data[start..end])
+    }
+
+    /// Close a handle returned by `open`.
+    pub fn release(&mut self, fh: u64) {
+        self.open.remove(&fh);
+    }
+}
+
+/// Mount `fs` read-only at `mountpoint` and serve requests until it is
+/// unmounted (`umount <mountpoint>` or `fusermount -u <mountpoint>`).
+///
+/// Mounting directly needs `CAP_SYS_ADMIN`; otherwise the setuid
+/// `fusermount3`/`fusermount` helper from the FUSE package is used.
+pub fn mount(mut fs: SnapshotFs<'_>, mountpoint: &Path) -> Result<()> {
+    let dev = open_device(mountpoint)?;
+    Session {
+        fs: &mut fs,
+        dev,
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+    }
+    .run()
+}
+
+fn open_device(mountpoint: &Path) -> Result<File> {
+    let target = CString::new(mountpoint.as_os_str().as_bytes())
+        .map_err(|_| IcebergError::Corruption("mountpoint contains NUL".into()))?;
+    if let Ok(dev) = OpenOptions::new().read(true).write(true).open("/dev/fuse") {
+        let options = CString::new(format!(
+            "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+            dev.as_raw_fd(),
+            unsafe { libc::getuid() },
+            unsafe { libc::getgid() },
+        ))
+        .expect("mount options contain no NUL");
+        // SAFETY: all pointers are valid NUL-terminated strings for the call.
+        let rc = unsafe {
+            libc::mount(
+                c"iceberg".as_ptr(),
+                target.as_ptr(),
+                c"fuse.iceberg".as_ptr(),
+                libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+                options.as_ptr().cast(),
+            )
+        };
+        if rc == 0 {
+            return Ok(dev);
+        }
+    }
+    fusermount(mountpoint)
+}
+
+/// Mount through the setuid helper, which passes back the opened
+/// `/dev/fuse` descriptor over a socket named by `_FUSE_COMMFD`.
+fn fusermount(mountpoint: &Path) -> Result<File> {
+    let mut last_error = None;
+    for helper in ["fusermount3", "fusermount"] {
+        let (ours, theirs) = UnixStream::pair()?;
+        // The helper must inherit its end of the socket.
+        // SAFETY: `theirs` is an open descriptor owned by this function.
+        unsafe { libc::fcntl(theirs.as_raw_fd(), libc::F_SETFD, 0) };
+        let status = Command::new(helper)
+            .args(["-o", "ro,nosuid,nodev,fsname=iceberg,subtype=iceberg", "--"])
+            .arg(mountpoint)
+            .env("_FUSE_COMMFD", theirs.as_raw_fd().to_string())
+            .status();
+        drop(theirs);
+        match status {
+            Ok(s) if s.success() => return receive_fd(&ours),
+            Ok(s) => last_error = Some(format!("{} exited with {}", helper, s)),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(IcebergError::Io(std::io::Error::other(
+        last_error
+            .unwrap_or_else(|| "cannot mount: not permitted and no fusermount helper found".into()),
+    )))
+}
+
+fn receive_fd(socket: &UnixStream) -> Result<File> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: byte.len(),
+    };
+    let mut control = [0u64; 8];
+    // SAFETY: msghdr is plain data; every pointer set below outlives recvmsg.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+    // SAFETY: `msg` is fully initialised and the socket is open.
+    if unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    // SAFETY: the control buffer was filled by recvmsg and stays alive.
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null() || (*cmsg).cmsg_type != libc::SCM_RIGHTS {
+            return Err(IcebergError::Io(std::io::Error::other(
+                "fusermount did not pass a FUSE descriptor",
+            )));
+        }
+        let fd = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+        Ok(File::from_raw_fd(fd))
+    }
+}
+
+// FUSE opcodes (include/uapi/linux/fuse.h).
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_SETATTR: u32 = 4;
+const FUSE_MKNOD: u32 = 8;
+const FUSE_MKDIR: u32 = 9;
+const FUSE_UNLINK: u32 = 10;
+const FUSE_RMDIR: u32 = 11;
+const FUSE_RENAME: u32 = 12;
+const FUSE_LINK: u32 = 13;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_WRITE: u32 = 16;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_SETXATTR: u32 = 21;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_CREATE: u32 = 35;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+const FUSE_RENAME2: u32 = 45;
+
+const IN_HEADER_LEN: usize = 40;
+const OUT_HEADER_LEN: usize = 16;
+/// Kernel ABI version spoken here.
+const KERNEL_VERSION: u32 = 7;
+const KERNEL_MINOR_VERSION: u32 = 31;
+/// `FOPEN_KEEP_CACHE`: file contents never change, keep the page cache.
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+struct Session<'s, 'a> {
+    fs: &'s mut SnapshotFs<'a>,
+    dev: File,
+    uid: u32,
+    gid: u32,
+}
+
+/// Little-endian reply body builder.
+#[derive(Default)]
+struct Out(Vec<u8>);
+
+impl Out {
+    fn u16(&mut self, v: u16) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+    fn u32(&mut self, v: u32) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+    fn u64(&mut self, v: u64) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+}
+
+fn u32_at(buf: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(buf.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_at(buf: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(buf.get(at..at + 8)?.try_into().ok()?))
+}
+
+impl Session<'_, '_> {
+    fn run(&mut self) -> Result<()> {
+        let mut buf = vec![0u8; MAX_READ as usize + 4096];
+        loop {
+            let n = match self.dev.read(&mut buf) {
+                Ok(n) => n,
+                Err(e) => match e.raw_os_error() {
+                    // Interrupted or already-answered requests.
+                    Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::ENOENT) => continue,
+                    // Unmounted.
+                    Some(libc::ENODEV) => return Ok(()),
+                    _ => return Err(e.into()),
+                },
+            };
+            let req = &buf[..n];
+            let (Some(opcode), Some(unique), Some(ino)) =
+                (u32_at(req, 4), u64_at(req, 8), u64_at(req, 16))
+            else {
+                continue;
+            };
+            let body = &req[IN_HEADER_LEN.min(n)..];
+            let reply = match opcode {
+                FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => None,
+                _ => Some(self.handle(opcode, ino, body)),
+            };
+            if let Some(reply) = reply {
+                self.reply(unique, reply)?;
+            }
+            if opcode == FUSE_DESTROY {
+                return Ok(());
+            }
+        }
+    }
+
+    fn handle(&mut self, opcode: u32, ino: u64, body: &[u8]) -> std::result::Result<Vec<u8>, i32> {
+        match opcode {
+            FUSE_INIT => {
+                let major = u32_at(body, 0).ok_or(libc::EINVAL)?;
+                if major < KERNEL_VERSION {
+                    return Err(libc::EPROTO);
+                }
+                let max_readahead = u32_at(body, 8).unwrap_or(MAX_READ);
+                let mut out = Out::default();
+                out.u32(KERNEL_VERSION)
+                    .u32(KERNEL_MINOR_VERSION)
+                    .u32(max_readahead)
+                    .u32(0) // flags
+                    .u16(16) // max_background
+                    .u16(12) // congestion_threshold
+                    .u32(MAX_READ) // max_write
+                    .u32(1) // time_gran
+                    .u16(0) // max_pages
+                    .u16(0) // map_alignment
+                    .u32(0); // flags2
+                out.0.resize(64, 0);
+                Ok(out.0)
+            }
+            FUSE_DESTROY | FUSE_FLUSH | FUSE_RELEASEDIR => Ok(Vec::new()),
+            FUSE_LOOKUP => {
+                let name = body.split(|&b| b == 0).next().unwrap_or_default();
+                let name = std::str::from_utf8(name).map_err(|_| libc::ENOENT)?;
+                let child = self.fs.lookup(ino, name).ok_or(libc::ENOENT)?;
+                let mut out = Out::default();
+                out.u64(child)
+                    .u64(0) // generation
+                    .u64(CACHE_TTL_SECS)
+                    .u64(CACHE_TTL_SECS)
+                    .u32(0)
+                    .u32(0);
+                self.push_attr(&mut out, child)?;
+                Ok(out.0)
+            }
+            FUSE_GETATTR => {
+                let mut out = Out::default();
+                out.u64(CACHE_TTL_SECS).u32(0).u32(0);
+                self.push_attr(&mut out, ino)?;
+                Ok(out.0)
+            }
+            FUSE_OPEN => {
+                let flags = u32_at(body, 0).unwrap_or(0) as i32;
+                if flags & libc::O_ACCMODE != libc::O_RDONLY {
+                    return Err(libc::EROFS);
+                }
+                if self.fs.attr(ino).ok_or(libc::ENOENT)?.is_dir {
+                    return Err(libc::EISDIR);
+                }
+                let fh = self.fs.open(ino).map_err(|_| libc::EIO)?;
+                let mut out = Out::default();
+                out.u64(fh).u32(FOPEN_KEEP_CACHE).u32(0);
+                Ok(out.0)
+            }
+            FUSE_READ => {
+                let fh = u64_at(body, 0).ok_or(libc::EINVAL)?;
+                let offset = u64_at(body, 8).ok_or(libc::EINVAL)?;
+                let size = u32_at(body, 16).ok_or(libc::EINVAL)?;
+                let data = self.fs.read(fh, offset, size as usize).ok_or(libc::EBADF)?;
+                Ok(data.to_vec())
+            }
+            FUSE_RELEASE => {
+                self.fs.release(u64_at(body, 0).ok_or(libc::EINVAL)?);
+                Ok(Vec::new())
+            }
+            FUSE_OPENDIR => {
+                if !self.fs.attr(ino).ok_or(libc::ENOENT)?.is_dir {
+                    return Err(libc::ENOTDIR);
+                }
+                let mut out = Out::default();
+                out.u64(0).u32(FOPEN_KEEP_CACHE).u32(0);
+                Ok(out.0)
+            }
+            FUSE_READDIR => {
+                let offset = u64_at(body, 8).ok_or(libc::EINVAL)? as usize;
+                let size = u32_at(body, 16).ok_or(libc::EINVAL)? as usize;
+                let entries = self.fs.read_dir(ino).ok_or(libc::ENOTDIR)?;
+                let mut out = Vec::new();
+                for (i, (child, name, is_dir)) in entries.iter().enumerate().skip(offset) {
+                    let entry_len = (24 + name.len()).next_multiple_of(8);
+                    if out.len() + entry_len > size {
+                        break;
+                    }
+                    let mut dirent = Out::default();
+                    dirent
+                        .u64(*child)
+                        .u64(i as u64 + 1) // offset of the next entry
+                        .u32(name.len() as u32)
+                        .u32(if *is_dir { libc::DT_DIR } else { libc::DT_REG } as u32);
+                    dirent.0.extend_from_slice(name.as_bytes());
+                    dirent.0.resize(entry_len, 0);
+                    out.extend_from_slice(&dirent.0);
+                }
+                Ok(out)
+            }
+            FUSE_STATFS => {
+                let mut out = Out::default();
+                out.u64(0) // blocks
+                    .u64(0) // bfree
+                    .u64(0) // bavail
+                    .u64(self.fs.nodes.len() as u64) // files
+                    .u64(0) // ffree
+                    .u32(4096) // bsize
+                    .u32(255) // namelen
+                    .u32(4096); // frsize
+                out.0.resize(80, 0);
+                Ok(out.0)
+            }
+            FUSE_SETATTR | FUSE_MKNOD | FUSE_MKDIR | FUSE_UNLINK | FUSE_RMDIR | FUSE_RENAME
+            | FUSE_RENAME2 | FUSE_LINK | FUSE_WRITE | FUSE_SETXATTR | FUSE_CREATE => {
+                Err(libc::EROFS)
+            }
+            _ => Err(libc::ENOSYS),
+        }
+    }
+
+    fn push_attr(&self, out: &mut Out, ino: u64) -> std::result::Result<(), i32> {
+        let attr = self.fs.attr(ino).ok_or(libc::ENOENT)?;
+        let secs = self.fs.mtime.timestamp().max(0) as u64;
+        let nsecs = self.fs.mtime.timestamp_subsec_nanos();
+        let (mode, nlink) = if attr.is_dir {
+            (libc::S_IFDIR | 0o555, 2)
+        } else {
+            (libc::S_IFREG | 0o444, 1)
+        };
+        out.u64(attr.ino)
+            .u64(attr.size)
+            .u64(attr.size.div_ceil(512))
+            .u64(secs)
+            .u64(secs)
+            .u64(secs)
+            .u32(nsecs)
+            .u32(nsecs)
+            .u32(nsecs)
+            .u32(mode)
+            .u32(nlink)
+            .u32(self.uid)
+            .u32(self.gid)
+            .u32(0) // rdev
+            .u32(4096) // blksize
+            .u32(0); // flags
+        Ok(())
+    }
+
+    fn reply(&mut self, unique: u64, reply: std::result::Result<Vec<u8>, i32>) -> Result<()> {
+        let (error, payload) = match reply {
+            Ok(payload) => (0, payload),
+            Err(errno) => (-errno, Vec::new()),
+        };
+        let mut msg = Out::default();
+        msg.u32((OUT_HEADER_LEN + payload.len()) as u32)
+            .u32(error as u32)
+            .u64(unique);
+        msg.0.extend_from_slice(&payload);
+        match self.dev.write(&msg.0) {
+            // The request was interrupted meanwhile; nobody is waiting.
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            Err(e) => Err(e.into()),
+            Ok(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(fs: &SnapshotFs, ino: u64) -> Vec<String> {
+        fs.read_dir(ino)
+            .unwrap()
+            .into_iter()
+            .skip(2)
+            .map(|(_, name, _)| name)
+            .collect()
+    }
+
+    #[test]
+    fn keys_become_directories() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::init(tmp.path()).unwrap();
+        db.put("users/alice", b"a".to_vec(), None).unwrap();
+        db.put("users/bob", b"bob".to_vec(), None).unwrap();
+        db.put("top", b"t".to_vec(), None).unwrap();
+        let commit = db.head_commit().unwrap();
+        let tree = db.tree_at(&commit.id).unwrap();
+        let mut fs = SnapshotFs::new(&db, &tree, commit.timestamp);
+
+        assert_eq!(names(&fs, ROOT_INO), vec!["top", "users"]);
+        let users = fs.lookup(ROOT_INO, "users").unwrap();
+        assert!(fs.attr(users).unwrap().is_dir);
+        assert_eq!(names(&fs, users), vec!["alice", "bob"]);
+
+        let bob = fs.lookup(users, "bob").unwrap();
+        assert_eq!(fs.attr(bob).unwrap().size, 3);
+        let fh = fs.open(bob).unwrap();
+        assert_eq!(fs.read(fh, 1, 10).unwrap(), b"ob");
+        assert_eq!(fs.read(fh, 10, 10).unwrap(), b"");
+        fs.release(fh);
+        assert!(fs.read(fh, 0, 1).is_none());
+    }
+
+    #[test]
+    fn key_that_is_also_a_prefix_is_shadowed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::init(tmp.path()).unwrap();
+        db.put("a", b"outer".to_vec(), None).unwrap();
+        db.put("a/b", b"inner".to_vec(), None).unwrap();
+        db.put("x//../y", b"odd".to_vec(), None).unwrap();
+        let tree = db.tree_at(&db.head_commit().unwrap().id).unwrap();
+        let mut fs = SnapshotFs::new(&db, &tree, Utc::now());
+
+        let a = fs.lookup(ROOT_INO, "a").unwrap();
+        assert_eq!(names(&fs, a), vec![SHADOWED_VALUE_NAME, "b"]);
+        let outer = fs.lookup(a, SHADOWED_VALUE_NAME).unwrap();
+        let fh = fs.open(outer).unwrap();
+        assert_eq!(fs.read(fh, 0, 100).unwrap(), b"outer");
+
+        let x = fs.lookup(ROOT_INO, "x").unwrap();
+        let dots = fs.lookup(x, "%2E%2E").unwrap();
+        assert_eq!(names(&fs, dots), vec!["y"]);
+        let (_, parent, _) = &fs.read_dir(dots).unwrap()[1];
+        assert_eq!(parent, "..");
+    }
+}
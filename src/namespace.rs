@@ -0,0 +1,484 @@
+use crate::commit::Commit;
+use crate::db::{Database, ScanOptions, Target};
+use crate::error::{IcebergError, Result};
+use crate::grep::{GrepMatch, GrepOptions};
+use crate::index::{IndexOptions, IndexPage, IndexStats, PageOptions};
+use crate::pattern::KeyPattern;
+use crate::query::{Aggregate, Groups, Query, QueryPlan};
+use crate::vector::VectorOptions;
+use std::io::{Read, Write};
+
+/// Separates a namespace from a key in the keys stored in trees.
+///
+/// `users` + `alice` is stored as `"users\u{1f}alice"`. Keys in the default
+/// namespace never contain it; writes through the plain `Database` API
+/// reject keys that do.
+pub const SEPARATOR: char = '\u{1f}';
+
+/// The key stored in trees for `key` in namespace `ns`.
+pub fn qualify(ns: &str, key: &str) -> String {
+    format!("{}{}{}", ns, SEPARATOR, key)
+}
+
+/// Split a stored key into its namespace (`None` for the default namespace)
+/// and the key within it.
+pub fn split(key: &str) -> (Option<&str>, &str) {
+    match key.split_once(SEPARATOR) {
+        Some((ns, key)) => (Some(ns), key),
+        None => (None, key),
+    }
+}
+
+/// Reject keys that would land in a namespace.
+pub fn check_key(key: &str) -> Result<()> {
+    if key.contains(SEPARATOR) {
+        return Err(IcebergError::InvalidKey(format!(
+            "{:?} contains the namespace separator",
+            key
+        )));
+    }
+    Ok(())
+}
+
+/// Check that `name` can be used as a namespace name.
+pub fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.chars().any(char::is_control) {
+        return Err(IcebergError::InvalidKey(format!(
+            "invalid namespace name {:?}",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// A view of one namespace of a database, from `Database::namespace`.
+///
+/// Keys, secondary indexes and the bloom filter are separate from those of
+/// other namespaces; commits, branches and tags are shared, so every write
+/// here is a commit on the current branch like any other.
+pub struct Namespace<'a> {
+    db: &'a Database,
+    name: String,
+}
+
+impl<'a> Namespace<'a> {
+    pub(crate) fn new(db: &'a Database, name: &str) -> Result<Self> {
+        check_name(name)?;
+        Ok(Self {
+            db,
+            name: name.to_string(),
+        })
+    }
+
+    /// The namespace name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn key(&self, key: &str) -> Result<String> {
+        check_key(key)?;
+        Ok(qualify(&self.name, key))
+    }
+
+    fn message(&self, verb: &str, key: &str, message: Option<&str>) -> String {
+        message
+            .map(String::from)
+            .unwrap_or_else(|| format!("{} {} in {}", verb, key, self.name))
+    }
+
+    /// Get a value from the current branch HEAD.
+    pub fn get(&self, key: &str) -> Result<Vec<u8>> {
+        self.db
+            .get(&self.key(key)?)
+            .map_err(|e| self.local_error(e))
+    }
+
+    /// Get a value as of a specific commit.
+    pub fn get_at(&self, key: &str, commit_id: &str) -> Result<Vec<u8>> {
+        self.db
+            .get_at(&self.key(key)?, commit_id)
+            .map_err(|e| self.local_error(e))
+    }
+
+    /// Stream a value from the current branch HEAD into a writer.
+    pub fn get_writer(&self, key: &str, writer: impl Write) -> Result<u64> {
+        self.db
+            .get_writer(&self.key(key)?, writer)
+            .map_err(|e| self.local_error(e))
+    }
+
+    /// Put a key-value pair; creates a new commit on the current branch.
+    pub fn put(&self, key: &str, value: Vec<u8>, message: Option<&str>) -> Result<Commit> {
+        let msg = self.message("put", key, message);
+        self.db
+            .put_raw(Target::Head, &self.key(key)?, value, None, None, &msg)
+    }
+
+    /// Stream a value from a reader into the store; creates a new commit.
+    pub fn put_reader(
+        &self,
+        key: &str,
+        reader: impl Read,
+        message: Option<&str>,
+    ) -> Result<Commit> {
+        let msg = self.message("put", key, message);
+        self.db
+            .put_reader_raw(Target::Head, &self.key(key)?, reader, &msg)
+    }
+
+    /// Delete a key; creates a new commit.
+    pub fn delete(&self, key: &str, message: Option<&str>) -> Result<Commit> {
+        let msg = self.message("delete", key, message);
+        self.db
+            .delete_raw(Target::Head, &self.key(key)?, None, &msg)
+            .map_err(|e| self.local_error(e))
+    }
+
+    /// Delete every key of this namespace in `[start, end)` in a single
+    /// commit, or return `None` when there are none.
+    pub fn delete_range(
+        &self,
+        start: &str,
+        end: &str,
+        message: Option<&str>,
+    ) -> Result<Option<Commit>> {
+        check_key(start)?;
+        check_key(end)?;
+        let msg = self.message("delete", &format!("[{}, {})", start, end), message);
+        self.db
+            .delete_range_raw(Target::Head, Some(&self.name), start, end, Some(&msg))
+    }
+
+    /// Keys of this namespace in `[start, end)`, without reading any values.
+    pub fn range_keys(&self, start: &str, end: &str) -> Result<Vec<String>> {
+        check_key(start)?;
+        check_key(end)?;
+        self.db.range_keys_in(Some(&self.name), start, end)
+    }
+
+    /// Scan keys in this namespace by prefix.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.scan_prefix_with(prefix, &ScanOptions::default())
+    }
+
+    /// One page of `scan_prefix`; only the values on the page are read.
+    pub fn scan_prefix_with(
+        &self,
+        prefix: &str,
+        options: &ScanOptions,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        check_key(prefix)?;
+        let tree = self.db.current_tree()?;
+        self.db
+            .scan_tree(&tree, Some(&self.name), prefix, None, options)
+    }
+
+    /// One page of `scan_prefix` as of a past commit.
+    pub fn scan_prefix_at(
+        &self,
+        prefix: &str,
+        commit_id: &str,
+        options: &ScanOptions,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        check_key(prefix)?;
+        let tree = self.db.tree_at(commit_id)?;
+        self.db
+            .scan_tree(&tree, Some(&self.name), prefix, None, options)
+    }
+
+    /// Keys in this namespace matching a glob, with their values.
+    pub fn scan_glob(&self, pattern: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let pattern = KeyPattern::glob(pattern)?;
+        let prefix = pattern.prefix().to_string();
+        self.scan_prefix_with(&prefix, &pattern.into())
+    }
+
+    /// Keys in this namespace matching a regex, with their values.
+    pub fn scan_regex(&self, pattern: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.scan_prefix_with("", &KeyPattern::regex(pattern)?.into())
+    }
+
+    /// Keys in this namespace under a prefix, without reading any values.
+    pub fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.scan_keys_with(prefix, &ScanOptions::default())
+    }
+
+    /// One page of `scan_keys`.
+    pub fn scan_keys_with(&self, prefix: &str, options: &ScanOptions) -> Result<Vec<String>> {
+        check_key(prefix)?;
+        self.db
+            .scan_keys_in(Some(&self.name), prefix, None, options)
+    }
+
+    /// One page of `scan_keys` as of a past commit.
+    pub fn scan_keys_at(
+        &self,
+        prefix: &str,
+        commit_id: &str,
+        options: &ScanOptions,
+    ) -> Result<Vec<String>> {
+        check_key(prefix)?;
+        self.db
+            .scan_keys_in(Some(&self.name), prefix, Some(commit_id), options)
+    }
+
+    /// Search the values in this namespace under a prefix; see
+    /// `Database::grep`.
+    pub fn grep(
+        &self,
+        pattern: &str,
+        prefix: &str,
+        options: &GrepOptions,
+    ) -> Result<Vec<GrepMatch>> {
+        check_key(prefix)?;
+        self.db.grep_in(Some(&self.name), pattern, prefix, options)
+    }
+
+    /// Number of keys in this namespace under a prefix.
+    pub fn count(&self, prefix: &str) -> Result<usize> {
+        check_key(prefix)?;
+        self.db.count_in(Some(&self.name), prefix, None)
+    }
+
+    /// Number of keys in this namespace under a prefix as of a past commit.
+    pub fn count_at(&self, prefix: &str, commit_id: &str) -> Result<usize> {
+        check_key(prefix)?;
+        self.db.count_in(Some(&self.name), prefix, Some(commit_id))
+    }
+
+    /// Up to `n` keys in this namespace under a prefix, sampled uniformly.
+    pub fn sample(&self, n: usize, prefix: &str) -> Result<Vec<String>> {
+        self.sample_with_seed(n, prefix, uuid::Uuid::new_v4().as_u64_pair().0)
+    }
+
+    /// `sample` with a fixed seed.
+    pub fn sample_with_seed(&self, n: usize, prefix: &str, seed: u64) -> Result<Vec<String>> {
+        check_key(prefix)?;
+        self.db.sample_in(Some(&self.name), n, prefix, seed)
+    }
+
+    /// Number of keys in this namespace at the current branch HEAD.
+    pub fn len(&self) -> Result<usize> {
+        let tree = self.db.current_tree()?;
+        Ok(tree.scan_prefix(&qualify(&self.name, "")).len())
+    }
+
+    /// Whether this namespace has no keys at the current branch HEAD.
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Create a secondary index over the values in this namespace.
+    pub fn create_index(&self, name: &str, field_path: &str) -> Result<()> {
+        self.create_index_with(name, field_path, &IndexOptions::default())
+    }
+
+    /// Create a secondary index over the values in this namespace with the
+    /// given settings.
+    pub fn create_index_with(
+        &self,
+        name: &str,
+        field_path: &str,
+        options: &IndexOptions,
+    ) -> Result<()> {
+        self.db
+            .create_index_raw(&self.key(name)?, field_path, options)
+    }
+
+    /// `create_index_with`, calling `progress(indexed, total)` now and then
+    /// without blocking writers; see `Database::create_index_with_progress`.
+    pub fn create_index_with_progress(
+        &self,
+        name: &str,
+        field_path: &str,
+        options: &IndexOptions,
+        progress: impl FnMut(usize, usize),
+    ) -> Result<()> {
+        self.db
+            .build_index_raw(&self.key(name)?, field_path, options, progress)
+    }
+
+    /// Drop one of this namespace's secondary indexes.
+    pub fn drop_index(&self, name: &str) -> Result<()> {
+        self.db.drop_index(&self.key(name)?)
+    }
+
+    /// Rebuild one of this namespace's indexes from the current tree, or all
+    /// of them for `None`.
+    pub fn rebuild_index(&self, name: Option<&str>) -> Result<Vec<String>> {
+        self.rebuild_index_with(name, |_, _| {})
+    }
+
+    /// `rebuild_index`, calling `progress(read, total)` now and then while
+    /// the values are read.
+    pub fn rebuild_index_with(
+        &self,
+        name: Option<&str>,
+        progress: impl FnMut(usize, usize),
+    ) -> Result<Vec<String>> {
+        self.db.rebuild_indexes_in(Some(&self.name), name, progress)
+    }
+
+    /// Query one of this namespace's indexes by exact value.
+    pub fn query_index(&self, index_name: &str, value: &str) -> Result<Vec<String>> {
+        self.db.query_index(&self.key(index_name)?, value)
+    }
+
+    /// Query one of this namespace's indexes by exact value as of a past
+    /// commit.
+    pub fn query_index_at(
+        &self,
+        index_name: &str,
+        value: &str,
+        commit_id: &str,
+    ) -> Result<Vec<String>> {
+        self.db
+            .query_index_at(&self.key(index_name)?, value, commit_id)
+    }
+
+    /// Query one of this namespace's indexes by exact value, returning the
+    /// matching keys with their values.
+    pub fn query_index_entries(
+        &self,
+        index_name: &str,
+        value: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        let keys = self.query_index(index_name, value)?;
+        self.db.index_entries(Some(&self.name), keys)
+    }
+
+    /// Query one of this namespace's indexes by prefix.
+    pub fn query_index_prefix(&self, index_name: &str, prefix: &str) -> Result<Vec<String>> {
+        self.db.query_index_prefix(&self.key(index_name)?, prefix)
+    }
+
+    /// Query one of this namespace's indexes for values between `min` and
+    /// `max`, inclusive.
+    pub fn query_index_range(
+        &self,
+        index_name: &str,
+        min: Option<&str>,
+        max: Option<&str>,
+    ) -> Result<Vec<String>> {
+        self.db.query_index_range(&self.key(index_name)?, min, max)
+    }
+
+    /// Query one of this namespace's indexes for one page of entries between
+    /// `min` and `max`.
+    pub fn query_index_page(
+        &self,
+        index_name: &str,
+        min: Option<&str>,
+        max: Option<&str>,
+        options: &PageOptions,
+    ) -> Result<IndexPage> {
+        self.db
+            .query_index_page(&self.key(index_name)?, min, max, options)
+    }
+
+    /// Create a vector index over the embeddings in this namespace.
+    pub fn create_vector_index(
+        &self,
+        name: &str,
+        field_path: &str,
+        options: &VectorOptions,
+    ) -> Result<()> {
+        self.db
+            .create_vector_index_raw(&self.key(name)?, field_path, options)
+    }
+
+    /// Query one of this namespace's vector indexes for the `k` nearest
+    /// embeddings to `query`.
+    pub fn query_vector(
+        &self,
+        index_name: &str,
+        query: &[f32],
+        k: usize,
+    ) -> Result<Vec<(String, f32)>> {
+        self.db.query_vector(&self.key(index_name)?, query, k)
+    }
+
+    /// `query_vector` as of a past commit.
+    pub fn query_vector_at(
+        &self,
+        index_name: &str,
+        query: &[f32],
+        k: usize,
+        commit_id: &str,
+    ) -> Result<Vec<(String, f32)>> {
+        self.db.query_vector_at_in(
+            Some(&self.name),
+            &self.key(index_name)?,
+            query,
+            k,
+            commit_id,
+        )
+    }
+
+    /// Keys and values in this namespace matching a query, using this
+    /// namespace's indexes.
+    pub fn query(&self, query: &Query) -> Result<Vec<(String, Vec<u8>)>> {
+        self.db.query_in(Some(&self.name), query, None)
+    }
+
+    /// Aggregate the values in this namespace matching `filter`, optionally
+    /// grouped by a JSON field.
+    pub fn aggregate(
+        &self,
+        filter: &Query,
+        group_by: Option<&str>,
+        aggregate: &Aggregate,
+    ) -> Result<Groups> {
+        self.db
+            .aggregate_in(Some(&self.name), filter, group_by, aggregate)
+    }
+
+    /// How `query` would find its candidate keys in this namespace.
+    pub fn explain(&self, query: &Query) -> QueryPlan {
+        self.db.explain_in(Some(&self.name), query)
+    }
+
+    /// Statistics of one of this namespace's indexes.
+    pub fn index_stats(&self, index_name: &str) -> Result<IndexStats> {
+        self.db.index_stats(&self.key(index_name)?)
+    }
+
+    /// List this namespace's secondary indexes.
+    pub fn list_indexes(&self) -> Vec<String> {
+        self.db.indexes_in(Some(&self.name))
+    }
+
+    /// Stats of this namespace's bloom filter.
+    pub fn bloom_stats(&self) -> (usize, usize, f64) {
+        self.db.bloom_stats_in(Some(&self.name))
+    }
+
+    /// Report missing keys by their name within the namespace.
+    fn local_error(&self, e: IcebergError) -> IcebergError {
+        match e {
+            IcebergError::KeyNotFound(key) => IcebergError::KeyNotFound(split(&key).1.into()),
+            e => e,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qualify_and_split_round_trip() {
+        let key = qualify("users", "alice");
+        assert_eq!(split(&key), (Some("users"), "alice"));
+        assert_eq!(split("alice"), (None, "alice"));
+        assert!(check_key(&key).is_err());
+        assert!(check_key("alice").is_ok());
+    }
+
+    #[test]
+    fn names_must_be_non_empty_and_printable() {
+        assert!(check_name("users").is_ok());
+        assert!(check_name("").is_err());
+        assert!(check_name("a\u{1f}b").is_err());
+    }
+}
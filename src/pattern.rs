@@ -0,0 +1,150 @@
+//! Key patterns for scans that a plain prefix can't express.
+//!
+//! Globs match whole keys: `*` matches any run of characters, `?` any one
+//! character, `[abc]`, `[a-z]` and `[!abc]` one character of a set, and `\`
+//! makes the next character literal. Regexes use the `regex` crate's syntax
+//! and match anywhere in the key unless anchored.
+
+use crate::error::{IcebergError, Result};
+use regex::Regex;
+
+/// A compiled glob or regex over keys, for `ScanOptions::matching`.
+#[derive(Debug, Clone)]
+pub struct KeyPattern {
+    source: String,
+    regex: Regex,
+    prefix: String,
+}
+
+impl KeyPattern {
+    /// Compile a glob such as `user:*:settings`.
+    pub fn glob(pattern: &str) -> Result<Self> {
+        let mut expr = String::from("^");
+        let mut prefix = String::new();
+        let mut literal = true;
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' => expr.push_str(".*"),
+                '?' => expr.push('.'),
+                '[' => {
+                    let mut class = String::from("[");
+                    if chars.as_str().starts_with('!') {
+                        chars.next();
+                        class.push('^');
+                    }
+                    loop {
+                        match chars.next() {
+                            Some(']') if class.len() > 1 && !class.ends_with("[^") => break,
+                            Some(c @ ('\\' | '[' | ']' | '&' | '~' | '^')) => {
+                                class.push('\\');
+                                class.push(c);
+                            }
+                            Some(c) => class.push(c),
+                            None => {
+                                return Err(IcebergError::InvalidQuery(format!(
+                                    "unclosed [ in glob {:?}",
+                                    pattern
+                                )))
+                            }
+                        }
+                    }
+                    class.push(']');
+                    expr.push_str(&class);
+                }
+                c => {
+                    let c = if c == '\\' {
+                        chars.next().unwrap_or('\\')
+                    } else {
+                        c
+                    };
+                    expr.push_str(&regex::escape(c.encode_utf8(&mut [0; 4])));
+                    if literal {
+                        prefix.push(c);
+                    }
+                    continue;
+                }
+            }
+            literal = false;
+        }
+        expr.push('$');
+        Ok(Self {
+            source: pattern.to_string(),
+            regex: compile(&expr)?,
+            prefix,
+        })
+    }
+
+    /// Compile a regex; it matches anywhere in a key unless anchored.
+    pub fn regex(pattern: &str) -> Result<Self> {
+        Ok(Self {
+            source: pattern.to_string(),
+            regex: compile(pattern)?,
+            prefix: String::new(),
+        })
+    }
+
+    /// The pattern as given.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// A prefix every matching key starts with, so scans can skip the rest.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Whether `key` matches.
+    pub fn matches(&self, key: &str) -> bool {
+        self.regex.is_match(key)
+    }
+}
+
+impl PartialEq for KeyPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.regex.as_str() == other.regex.as_str()
+    }
+}
+
+impl Eq for KeyPattern {}
+
+fn compile(expr: &str) -> Result<Regex> {
+    Regex::new(expr).map_err(|e| IcebergError::InvalidQuery(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_whole_keys() {
+        let p = KeyPattern::glob("user:*:settings").unwrap();
+        assert_eq!(p.prefix(), "user:");
+        assert!(p.matches("user:42:settings"));
+        assert!(p.matches("user:a:b:settings"));
+        assert!(!p.matches("user:42:settings.old"));
+        assert!(!p.matches("xuser:42:settings"));
+
+        let p = KeyPattern::glob("log-202?-[0-1][!0]").unwrap();
+        assert_eq!(p.prefix(), "log-202");
+        assert!(p.matches("log-2024-11"));
+        assert!(!p.matches("log-2024-10"));
+        assert!(!p.matches("log-2024-21"));
+
+        let p = KeyPattern::glob(r"a\*b.c").unwrap();
+        assert_eq!(p.prefix(), "a*b.c");
+        assert!(p.matches("a*b.c"));
+        assert!(!p.matches("aXb.c"));
+        assert!(!p.matches("a*bxc"));
+        assert!(KeyPattern::glob("a[bc").is_err());
+    }
+
+    #[test]
+    fn regexes_match_anywhere_unless_anchored() {
+        let p = KeyPattern::regex(r"\d+$").unwrap();
+        assert_eq!(p.prefix(), "");
+        assert!(p.matches("order:17"));
+        assert!(!p.matches("order:17x"));
+        assert!(KeyPattern::regex("(").is_err());
+    }
+}
@@ -0,0 +1,596 @@
+use crate::index::{self, IndexManager};
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// How a condition compares a field with its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    /// The field, as a string, starts with the value.
+    Prefix,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self {
+            Op::Eq => "=",
+            Op::Gt => ">",
+            Op::Gte => ">=",
+            Op::Lt => "<",
+            Op::Lte => "<=",
+            Op::Prefix => "starts with",
+        };
+        f.write_str(op)
+    }
+}
+
+/// One comparison of a JSON field with a value.
+///
+/// A value that parses as a number compares numerically, and then only
+/// matches fields holding numbers (or numeric strings); any other value
+/// compares as a string. A condition on an array field matches if any
+/// element matches, as array fields are indexed per element.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub field: String,
+    pub op: Op,
+    pub value: String,
+}
+
+impl Condition {
+    /// The value as a number, if the condition compares numerically.
+    pub(crate) fn number(&self) -> Option<f64> {
+        number(self.op, &self.value)
+    }
+
+    fn matches(&self, doc: &serde_json::Value) -> bool {
+        match index::json_field(doc, &self.field) {
+            Some(serde_json::Value::Array(items)) => items.iter().any(|v| self.matches_one(v)),
+            Some(field) => self.matches_one(field),
+            None => false,
+        }
+    }
+
+    fn matches_one(&self, field: &serde_json::Value) -> bool {
+        compare(self.op, &self.value, &index::field_string(field))
+    }
+}
+
+/// `value` as a number, if a condition with `op` compares numerically.
+fn number(op: Op, value: &str) -> Option<f64> {
+    if op == Op::Prefix {
+        return None;
+    }
+    value.parse::<f64>().ok().filter(|n| n.is_finite())
+}
+
+/// Whether `field` satisfies `op` against `value`, with the rules
+/// described on `Condition`.
+fn compare(op: Op, value: &str, field: &str) -> bool {
+    if op == Op::Prefix {
+        return field.starts_with(value);
+    }
+    let ordering = match number(op, value) {
+        Some(n) => match field.parse::<f64>() {
+            Ok(f) => f.partial_cmp(&n),
+            Err(_) => None,
+        },
+        None => Some(field.cmp(value)),
+    };
+    match (op, ordering) {
+        (_, None) => false,
+        (Op::Eq, Some(o)) => o == Ordering::Equal,
+        (Op::Gt, Some(o)) => o == Ordering::Greater,
+        (Op::Gte, Some(o)) => o != Ordering::Less,
+        (Op::Lt, Some(o)) => o == Ordering::Less,
+        (Op::Lte, Some(o)) => o != Ordering::Greater,
+        (Op::Prefix, _) => unreachable!(),
+    }
+}
+
+/// Parses `field<op>value` with `op` one of `=`, `>`, `>=`, `<`, `<=` or
+/// `^=` (prefix), e.g. `age>=30` or `address.city=Zurich`.
+impl std::str::FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let at = s
+            .find(['=', '<', '>', '^'])
+            .ok_or_else(|| format!("no comparison in condition: {}", s))?;
+        let (field, rest) = s.split_at(at);
+        let (op, value) = [
+            (">=", Op::Gte),
+            ("<=", Op::Lte),
+            ("^=", Op::Prefix),
+            (">", Op::Gt),
+            ("<", Op::Lt),
+            ("=", Op::Eq),
+        ]
+        .into_iter()
+        .find_map(|(token, op)| Some((op, rest.strip_prefix(token)?)))
+        .ok_or_else(|| format!("invalid comparison in condition: {}", s))?;
+        if field.is_empty() {
+            return Err(format!("no field in condition: {}", s));
+        }
+        Ok(Condition {
+            field: field.to_string(),
+            op,
+            value: value.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {:?}", self.field, self.op, self.value)
+    }
+}
+
+/// A condition on a JSON field or the key, to be completed with a
+/// comparison.
+pub struct Field(Option<String>);
+
+/// Start a condition on the JSON field at `path` (e.g. `address.city`).
+pub fn field(path: &str) -> Field {
+    Field(Some(path.to_string()))
+}
+
+/// Start a condition on the key itself.
+pub fn key() -> Field {
+    Field(None)
+}
+
+impl Field {
+    fn op(self, op: Op, value: impl ToString) -> Query {
+        let value = value.to_string();
+        match self.0 {
+            Some(field) => Query::Condition(Condition { field, op, value }),
+            None => Query::Key { op, value },
+        }
+    }
+
+    pub fn eq(self, value: impl ToString) -> Query {
+        self.op(Op::Eq, value)
+    }
+
+    pub fn gt(self, value: impl ToString) -> Query {
+        self.op(Op::Gt, value)
+    }
+
+    pub fn gte(self, value: impl ToString) -> Query {
+        self.op(Op::Gte, value)
+    }
+
+    pub fn lt(self, value: impl ToString) -> Query {
+        self.op(Op::Lt, value)
+    }
+
+    pub fn lte(self, value: impl ToString) -> Query {
+        self.op(Op::Lte, value)
+    }
+
+    pub fn prefix(self, value: impl ToString) -> Query {
+        self.op(Op::Prefix, value)
+    }
+}
+
+/// A filter over JSON values, built with `field`, `and` and `or`, and run
+/// with `Database::query`.
+///
+/// ```
+/// use iceberg::query::field;
+/// let q = field("city").eq("Zurich").and(field("age").gt(30));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Condition(Condition),
+    /// A comparison of the key, with the same rules as a `Condition`.
+    Key {
+        op: Op,
+        value: String,
+    },
+    And(Vec<Query>),
+    Or(Vec<Query>),
+}
+
+impl Query {
+    /// A query matching every key.
+    pub fn all() -> Query {
+        Query::And(Vec::new())
+    }
+
+    /// Whether this is `Query::all()`.
+    pub fn is_all(&self) -> bool {
+        matches!(self, Query::And(parts) if parts.is_empty())
+    }
+
+    /// Match values matching both queries.
+    pub fn and(self, other: Query) -> Query {
+        match self {
+            Query::And(mut parts) => {
+                parts.push(other);
+                Query::And(parts)
+            }
+            q => Query::And(vec![q, other]),
+        }
+    }
+
+    /// Match values matching either query.
+    pub fn or(self, other: Query) -> Query {
+        match self {
+            Query::Or(mut parts) => {
+                parts.push(other);
+                Query::Or(parts)
+            }
+            q => Query::Or(vec![q, other]),
+        }
+    }
+
+    /// Whether a key and its stored value match. Conditions on fields never
+    /// match values that are not JSON.
+    pub fn matches(&self, key: &str, value: &[u8]) -> bool {
+        let doc = serde_json::from_slice::<serde_json::Value>(value).ok();
+        self.matches_doc(key, doc.as_ref())
+    }
+
+    fn matches_doc(&self, key: &str, doc: Option<&serde_json::Value>) -> bool {
+        match self {
+            Query::Condition(c) => doc.is_some_and(|doc| c.matches(doc)),
+            Query::Key { op, value } => compare(*op, value, key),
+            Query::And(parts) => parts.iter().all(|q| q.matches_doc(key, doc)),
+            Query::Or(parts) => parts.iter().any(|q| q.matches_doc(key, doc)),
+        }
+    }
+
+    /// Plan the query against the indexes of namespace `ns`.
+    pub(crate) fn plan(&self, indexes: &IndexManager, ns: Option<&str>) -> QueryPlan {
+        match self {
+            Query::Condition(c) => indexes
+                .index_for(ns, c)
+                .map(|index| QueryPlan::Lookup {
+                    index,
+                    condition: c.clone(),
+                })
+                .unwrap_or(QueryPlan::Scan),
+            Query::Key { .. } => QueryPlan::Scan,
+            Query::And(parts) => {
+                // Conditions no index serves are left to the final filter.
+                let plans: Vec<QueryPlan> = parts
+                    .iter()
+                    .map(|q| q.plan(indexes, ns))
+                    .filter(|p| *p != QueryPlan::Scan)
+                    .collect();
+                match plans.len() {
+                    0 => QueryPlan::Scan,
+                    1 => plans.into_iter().next().unwrap(),
+                    _ => QueryPlan::Intersect(plans),
+                }
+            }
+            Query::Or(parts) => {
+                let plans: Vec<QueryPlan> = parts.iter().map(|q| q.plan(indexes, ns)).collect();
+                // One unindexed branch means every value must be looked at.
+                if plans.contains(&QueryPlan::Scan) {
+                    QueryPlan::Scan
+                } else {
+                    QueryPlan::Union(plans)
+                }
+            }
+        }
+    }
+}
+
+/// How `Database::query` finds candidate keys, from `Database::explain`.
+///
+/// Candidates are always checked against the whole query at the current
+/// branch HEAD, so an index only has to return a superset of the matches.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryPlan {
+    /// Every key is checked.
+    Scan,
+    /// The keys an index returns for a condition.
+    Lookup { index: String, condition: Condition },
+    /// Keys returned by every plan.
+    Intersect(Vec<QueryPlan>),
+    /// Keys returned by any plan.
+    Union(Vec<QueryPlan>),
+}
+
+impl QueryPlan {
+    /// The candidate keys, local to the namespace, or `None` for a scan.
+    pub(crate) fn candidates(
+        &self,
+        indexes: &IndexManager,
+        ns: Option<&str>,
+    ) -> Option<BTreeSet<String>> {
+        match self {
+            QueryPlan::Scan => None,
+            QueryPlan::Lookup { index, condition } => indexes.select(ns, index, condition),
+            QueryPlan::Intersect(plans) => {
+                let mut sets = plans.iter().map(|p| p.candidates(indexes, ns));
+                let first = sets.next()??;
+                sets.try_fold(first, |acc, set| {
+                    Some(acc.intersection(&set?).cloned().collect())
+                })
+            }
+            QueryPlan::Union(plans) => plans.iter().try_fold(BTreeSet::new(), |mut acc, p| {
+                acc.extend(p.candidates(indexes, ns)?);
+                Some(acc)
+            }),
+        }
+    }
+}
+
+impl fmt::Display for QueryPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |f: &mut fmt::Formatter<'_>, name: &str, plans: &[QueryPlan]| {
+            write!(f, "{}(", name)?;
+            for (i, plan) in plans.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", plan)?;
+            }
+            write!(f, ")")
+        };
+        match self {
+            QueryPlan::Scan => write!(f, "scan"),
+            QueryPlan::Lookup { index, condition } => {
+                write!(f, "index {} ({})", index, condition)
+            }
+            QueryPlan::Intersect(plans) => list(f, "intersect", plans),
+            QueryPlan::Union(plans) => list(f, "union", plans),
+        }
+    }
+}
+
+/// What `Database::aggregate` computes for each group.
+///
+/// `Sum`, `Min` and `Max` read a numeric JSON field (numbers or numeric
+/// strings; every element of an array); values without one are counted by
+/// `Count` but do not contribute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Aggregate {
+    Count,
+    Sum(String),
+    Min(String),
+    Max(String),
+}
+
+/// Parses `count`, `sum(<field>)`, `min(<field>)` or `max(<field>)`.
+impl std::str::FromStr for Aggregate {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("count") || s.eq_ignore_ascii_case("count(*)") {
+            return Ok(Aggregate::Count);
+        }
+        let (function, field) = s
+            .strip_suffix(')')
+            .and_then(|s| s.split_once('('))
+            .filter(|(_, field)| !field.is_empty())
+            .ok_or_else(|| format!("unknown aggregate: {}", s))?;
+        let field = field.to_string();
+        match function.to_ascii_lowercase().as_str() {
+            "sum" => Ok(Aggregate::Sum(field)),
+            "min" => Ok(Aggregate::Min(field)),
+            "max" => Ok(Aggregate::Max(field)),
+            _ => Err(format!("unknown aggregate: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Aggregate::Count => write!(f, "count"),
+            Aggregate::Sum(field) => write!(f, "sum({})", field),
+            Aggregate::Min(field) => write!(f, "min({})", field),
+            Aggregate::Max(field) => write!(f, "max({})", field),
+        }
+    }
+}
+
+/// Result of `Database::aggregate`: one value per group, sorted by group.
+///
+/// Without grouping there is a single group, `None`. With grouping, a key is
+/// counted in the group of each value of the field (arrays put it in several
+/// groups), and keys without the field are grouped under `None`. Groups with
+/// no numeric values are left out of `Min` and `Max`.
+pub type Groups = Vec<(Option<String>, f64)>;
+
+/// Accumulates values into the groups of an aggregate.
+pub(crate) struct Aggregator<'a> {
+    aggregate: &'a Aggregate,
+    group_by: Option<&'a str>,
+    groups: std::collections::BTreeMap<Option<String>, Option<f64>>,
+}
+
+impl<'a> Aggregator<'a> {
+    pub(crate) fn new(aggregate: &'a Aggregate, group_by: Option<&'a str>) -> Self {
+        Self {
+            aggregate,
+            group_by,
+            groups: Default::default(),
+        }
+    }
+
+    /// Add one stored value.
+    pub(crate) fn add(&mut self, value: &[u8]) {
+        let doc = serde_json::from_slice::<serde_json::Value>(value).ok();
+        let groups: Vec<Option<String>> = match (self.group_by, &doc) {
+            (None, _) => vec![None],
+            (Some(path), Some(doc)) => match index::json_field(doc, path) {
+                Some(serde_json::Value::Array(items)) if !items.is_empty() => {
+                    items.iter().map(|v| Some(index::field_string(v))).collect()
+                }
+                Some(serde_json::Value::Array(_)) | None => vec![None],
+                Some(field) => vec![Some(index::field_string(field))],
+            },
+            (Some(_), None) => vec![None],
+        };
+        let numbers: Vec<f64> = match (self.aggregate, &doc) {
+            (Aggregate::Count, _) | (_, None) => Vec::new(),
+            (Aggregate::Sum(path) | Aggregate::Min(path) | Aggregate::Max(path), Some(doc)) => {
+                match index::json_field(doc, path) {
+                    Some(serde_json::Value::Array(items)) => {
+                        items.iter().filter_map(as_number).collect()
+                    }
+                    Some(field) => as_number(field).into_iter().collect(),
+                    None => Vec::new(),
+                }
+            }
+        };
+        for group in groups {
+            let acc = self.groups.entry(group).or_insert(None);
+            match self.aggregate {
+                Aggregate::Count => *acc = Some(acc.unwrap_or(0.0) + 1.0),
+                Aggregate::Sum(_) => *acc = Some(acc.unwrap_or(0.0) + numbers.iter().sum::<f64>()),
+                Aggregate::Min(_) => {
+                    for n in &numbers {
+                        *acc = Some(acc.map_or(*n, |a| a.min(*n)));
+                    }
+                }
+                Aggregate::Max(_) => {
+                    for n in &numbers {
+                        *acc = Some(acc.map_or(*n, |a| a.max(*n)));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Add `count` keys to a group, for counts taken from an index.
+    pub(crate) fn add_count(&mut self, group: Option<String>, count: usize) {
+        let acc = self.groups.entry(group).or_insert(None);
+        *acc = Some(acc.unwrap_or(0.0) + count as f64);
+    }
+
+    pub(crate) fn finish(self) -> Groups {
+        self.groups
+            .into_iter()
+            .filter_map(|(group, acc)| Some((group, acc?)))
+            .collect()
+    }
+}
+
+fn as_number(value: &serde_json::Value) -> Option<f64> {
+    index::field_string(value)
+        .parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{IndexKind, IndexOptions};
+
+    fn doc(city: &str, age: f64) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({ "city": city, "age": age, "tags": ["a", "b"] }))
+            .unwrap()
+    }
+
+    #[test]
+    fn conditions_compare_numbers_and_strings() {
+        let zurich = doc("Zurich", 31.0);
+        assert!(field("city").eq("Zurich").matches("u:1", &zurich));
+        assert!(field("age").gt(30).matches("u:1", &zurich));
+        assert!(field("age").eq("31.0").matches("u:1", &zurich));
+        assert!(!field("age").lt(31).matches("u:1", &zurich));
+        assert!(field("city").gte("Z").matches("u:1", &zurich));
+        assert!(!field("city").gt(1).matches("u:1", &zurich));
+        assert!(field("city").prefix("Zu").matches("u:1", &zurich));
+        assert!(field("tags").eq("b").matches("u:1", &zurich));
+        assert!(!field("missing").eq("x").matches("u:1", &zurich));
+        assert!(!field("city").eq("Zurich").matches("u:1", b"not json"));
+        assert!(key().prefix("u:").matches("u:1", b"not json"));
+        assert!(!key().gt("u:1").matches("u:1", &zurich));
+
+        let parsed: Condition = "age>=31".parse().unwrap();
+        assert_eq!(Query::Condition(parsed), field("age").gte(31));
+        assert_eq!("city^=Zu".parse::<Condition>().unwrap().op, Op::Prefix);
+        assert!("city".parse::<Condition>().is_err());
+        assert!("=x".parse::<Condition>().is_err());
+
+        let q = field("city")
+            .eq("Berlin")
+            .or(field("age").gte(31))
+            .and(field("tags").eq("a"));
+        assert!(q.matches("u:1", &zurich));
+    }
+
+    #[test]
+    fn aggregator_groups_and_accumulates() {
+        let docs = [doc("Zurich", 31.0), doc("Zurich", 25.0), doc("Bern", 40.0)];
+        let run = |aggregate: &Aggregate, group_by| {
+            let mut agg = Aggregator::new(aggregate, group_by);
+            for d in &docs {
+                agg.add(d);
+            }
+            agg.add(b"not json");
+            agg.finish()
+        };
+        let zurich = || Some("Zurich".to_string());
+        let bern = || Some("Bern".to_string());
+
+        assert_eq!(run(&Aggregate::Count, None), vec![(None, 4.0)]);
+        assert_eq!(
+            run(&Aggregate::Count, Some("city")),
+            vec![(None, 1.0), (bern(), 1.0), (zurich(), 2.0)]
+        );
+        assert_eq!(
+            run(&"sum(age)".parse().unwrap(), Some("city")),
+            vec![(None, 0.0), (bern(), 40.0), (zurich(), 56.0)]
+        );
+        assert_eq!(
+            run(&Aggregate::Min("age".into()), Some("city")),
+            vec![(bern(), 40.0), (zurich(), 25.0)]
+        );
+        assert_eq!(run(&Aggregate::Max("age".into()), None), vec![(None, 40.0)]);
+        // Each tag is a group of its own.
+        assert_eq!(
+            run(&Aggregate::Count, Some("tags")),
+            vec![
+                (None, 1.0),
+                (Some("a".into()), 3.0),
+                (Some("b".into()), 3.0)
+            ]
+        );
+        assert!("avg(age)".parse::<Aggregate>().is_err());
+        assert_eq!("COUNT".parse::<Aggregate>(), Ok(Aggregate::Count));
+    }
+
+    #[test]
+    fn planner_uses_indexes_that_fit() {
+        let mut mgr = IndexManager::new();
+        mgr.create_index("city", "city").unwrap();
+        let float = IndexOptions {
+            kind: IndexKind::Float,
+            ..Default::default()
+        };
+        mgr.create_index_with("age", "age", &float).unwrap();
+        mgr.on_put("u:1", &doc("Zurich", 31.0));
+        mgr.on_put("u:2", &doc("Zurich", 25.0));
+        mgr.on_put("u:3", &doc("Berlin", 40.0));
+
+        let q = field("city").eq("Zurich").and(field("age").gt(30));
+        let plan = q.plan(&mgr, None);
+        assert!(matches!(plan, QueryPlan::Intersect(ref p) if p.len() == 2));
+        let keys = plan.candidates(&mgr, None).unwrap();
+        assert_eq!(keys.into_iter().collect::<Vec<_>>(), vec!["u:1"]);
+
+        // The string index cannot answer numeric conditions, or anything
+        // about unindexed fields.
+        assert_eq!(field("city").gt(3).plan(&mgr, None), QueryPlan::Scan);
+        let q = field("city").eq("Berlin").or(field("name").eq("x"));
+        assert_eq!(q.plan(&mgr, None), QueryPlan::Scan);
+        let q = field("city").eq("Berlin").or(field("age").lte(25));
+        let keys = q.plan(&mgr, None).candidates(&mgr, None).unwrap();
+        assert_eq!(keys.into_iter().collect::<Vec<_>>(), vec!["u:2", "u:3"]);
+    }
+}
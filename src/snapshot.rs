@@ -0,0 +1,84 @@
+use crate::commit::Commit;
+use crate::db::{Database, ScanOptions};
+use crate::error::{IcebergError, Result};
+use crate::tree::{Tree, TreeDiff};
+use std::sync::Arc;
+
+/// A read-only view of the database as of one commit, from
+/// `Database::snapshot`.
+///
+/// Reads keep seeing that commit's tree however branches move or HEAD is
+/// switched afterwards, and compaction keeps the tree and its blocks while
+/// the snapshot lives, even once no branch or tag reaches them.
+pub struct Snapshot<'a> {
+    db: &'a Database,
+    commit: Commit,
+    tree: Arc<Tree>,
+}
+
+impl<'a> Snapshot<'a> {
+    /// A snapshot of `tree`, which the caller has already pinned.
+    pub(crate) fn new(db: &'a Database, commit: Commit, tree: Arc<Tree>) -> Self {
+        Self { db, commit, tree }
+    }
+
+    /// The commit the snapshot reads.
+    pub fn commit(&self) -> &Commit {
+        &self.commit
+    }
+
+    /// Get a value.
+    pub fn get(&self, key: &str) -> Result<Vec<u8>> {
+        match self.tree.get(key) {
+            Some(v) => self.db.load_value(v),
+            None => Err(IcebergError::KeyNotFound(key.into())),
+        }
+    }
+
+    /// Whether a key exists.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.tree.contains_key(key)
+    }
+
+    /// Scan keys by prefix.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.scan_prefix_with(prefix, &ScanOptions::default())
+    }
+
+    /// One page of `scan_prefix`; only the values on the page are read.
+    pub fn scan_prefix_with(
+        &self,
+        prefix: &str,
+        options: &ScanOptions,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        self.db.scan_tree(&self.tree, None, prefix, None, options)
+    }
+
+    /// Range scan.
+    pub fn range(&self, start: &str, end: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.range_with(start, end, &ScanOptions::default())
+    }
+
+    /// One page of `range`; only the values on the page are read.
+    pub fn range_with(
+        &self,
+        start: &str,
+        end: &str,
+        options: &ScanOptions,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        self.db
+            .scan_tree(&self.tree, None, start, Some(end), options)
+    }
+
+    /// Diff from the snapshot to a commit.
+    pub fn diff(&self, commit_id: &str) -> Result<TreeDiff> {
+        let tree = self.db.tree_at(commit_id)?;
+        Ok(self.tree.diff(&tree))
+    }
+}
+
+impl Drop for Snapshot<'_> {
+    fn drop(&mut self) {
+        self.db.unpin_tree(&self.tree.root_hash);
+    }
+}
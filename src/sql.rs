@@ -0,0 +1,446 @@
+//! A small SQL-like language over keys and JSON values:
+//!
+//! ```text
+//! SELECT key, value.city FROM db WHERE value.age > 30 AND key LIKE 'user:%'
+//!     AT 'v1.0' LIMIT 10
+//! ```
+//!
+//! `FROM db` reads the default namespace and `FROM <name>` a namespace.
+//! Columns are `*` (key and value), `key`, `value` or `value.<path>`.
+//! Conditions compare `key` or `value.<path>` with a quoted string or a
+//! number using `=`, `<`, `<=`, `>`, `>=` or `LIKE 'prefix%'`, combined with
+//! `AND`, `OR` and parentheses. `AT` takes a commit, branch or tag; without
+//! it the query runs at HEAD and can use secondary indexes.
+
+use crate::error::{IcebergError, Result};
+use crate::index;
+use crate::query::{self, Op, Query};
+use std::fmt;
+
+/// A parsed `SELECT` statement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub columns: Vec<Column>,
+    /// Namespace to read, `None` for the default namespace (`FROM db`).
+    pub namespace: Option<String>,
+    pub filter: Option<Query>,
+    /// Commit, branch or tag to read at instead of HEAD.
+    pub at: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// An output column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Column {
+    Key,
+    /// The whole value, as JSON if it parses and as a string otherwise.
+    Value,
+    /// A field of the JSON value; null where it is missing.
+    Field(String),
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Column::Key => write!(f, "key"),
+            Column::Value => write!(f, "value"),
+            Column::Field(path) => write!(f, "value.{}", path),
+        }
+    }
+}
+
+/// Rows returned by `Database::sql`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rows {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<serde_json::Value>>,
+}
+
+impl Rows {
+    /// Build the rows of `columns` for matching entries.
+    pub(crate) fn new(columns: &[Column], entries: Vec<(String, Vec<u8>)>) -> Self {
+        let rows = entries
+            .into_iter()
+            .map(|(key, value)| {
+                let doc = serde_json::from_slice::<serde_json::Value>(&value).ok();
+                columns
+                    .iter()
+                    .map(|column| match column {
+                        Column::Key => serde_json::Value::String(key.clone()),
+                        Column::Value => doc.clone().unwrap_or_else(|| {
+                            serde_json::Value::String(String::from_utf8_lossy(&value).into())
+                        }),
+                        Column::Field(path) => doc
+                            .as_ref()
+                            .and_then(|doc| index::json_field(doc, path))
+                            .cloned()
+                            .unwrap_or(serde_json::Value::Null),
+                    })
+                    .collect()
+            })
+            .collect();
+        Self {
+            columns: columns.iter().map(Column::to_string).collect(),
+            rows,
+        }
+    }
+}
+
+/// Tab-separated, with a header line; strings are printed bare.
+impl fmt::Display for Rows {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.columns.join("\t"))?;
+        for row in &self.rows {
+            let cells: Vec<String> = row
+                .iter()
+                .map(|cell| match cell {
+                    serde_json::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                })
+                .collect();
+            writeln!(f, "{}", cells.join("\t"))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Number(String),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(w) | Token::Number(w) => write!(f, "{}", w),
+            Token::Str(s) => write!(f, "'{}'", s),
+            Token::Symbol(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 9] = ["<=", ">=", "(", ")", ",", "*", "=", "<", ">"];
+
+fn invalid(message: String) -> IcebergError {
+    IcebergError::InvalidQuery(message)
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = input.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c == '\'' {
+            // A quote inside a string is written twice.
+            let mut value = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, '\'')) if rest[1 + i + 1..].starts_with('\'') => {
+                        value.push('\'');
+                        chars.next();
+                    }
+                    Some((i, '\'')) => break 1 + i + 1,
+                    Some((_, c)) => value.push(c),
+                    None => return Err(invalid("unterminated string".into())),
+                }
+            };
+            tokens.push(Token::Str(value));
+            rest = &rest[end..];
+        } else if let Some(symbol) = SYMBOLS.iter().find(|s| rest.starts_with(*s)) {
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else if c.is_ascii_digit() || c == '-' || c == '.' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+')))
+                .unwrap_or(rest.len());
+            let number = &rest[..end];
+            if number.parse::<f64>().is_err() {
+                return Err(invalid(format!("invalid number {}", number)));
+            }
+            tokens.push(Token::Number(number.to_string()));
+            rest = &rest[end..];
+        } else if c.is_alphanumeric() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || matches!(c, '_' | '.' | '-')))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Word(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            return Err(invalid(format!("unexpected character {:?}", c)));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        if self.keyword(keyword) {
+            Ok(())
+        } else {
+            Err(self.unexpected(keyword))
+        }
+    }
+
+    fn symbol(&mut self, symbol: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn unexpected(&self, expected: &str) -> IcebergError {
+        match self.peek() {
+            Some(token) => invalid(format!("expected {}, found {}", expected, token)),
+            None => invalid(format!("expected {}, found end of query", expected)),
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement> {
+        self.expect_keyword("SELECT")?;
+        let mut columns = Vec::new();
+        loop {
+            if self.symbol("*") {
+                columns.extend([Column::Key, Column::Value]);
+            } else {
+                columns.push(self.column()?);
+            }
+            if !self.symbol(",") {
+                break;
+            }
+        }
+        self.expect_keyword("FROM")?;
+        let namespace = match self.next() {
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case("db") => None,
+            Some(Token::Word(w)) | Some(Token::Str(w)) => Some(w),
+            _ => {
+                self.pos -= 1;
+                return Err(self.unexpected("db or a namespace"));
+            }
+        };
+        let filter = if self.keyword("WHERE") {
+            Some(self.or()?)
+        } else {
+            None
+        };
+        let at = if self.keyword("AT") {
+            match self.next() {
+                Some(Token::Str(s)) | Some(Token::Word(s)) => Some(s),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.unexpected("a commit, branch or tag"));
+                }
+            }
+        } else {
+            None
+        };
+        let limit = if self.keyword("LIMIT") {
+            match self.next() {
+                Some(Token::Number(n)) => Some(
+                    n.parse()
+                        .map_err(|_| invalid(format!("invalid limit {}", n)))?,
+                ),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.unexpected("a limit"));
+                }
+            }
+        } else {
+            None
+        };
+        if self.peek().is_some() {
+            return Err(self.unexpected("end of query"));
+        }
+        Ok(Statement {
+            columns,
+            namespace,
+            filter,
+            at,
+            limit,
+        })
+    }
+
+    fn column(&mut self) -> Result<Column> {
+        match self.next() {
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case("key") => Ok(Column::Key),
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case("value") => Ok(Column::Value),
+            Some(Token::Word(w)) => match w.strip_prefix("value.") {
+                Some(path) if !path.is_empty() => Ok(Column::Field(path.to_string())),
+                _ => Err(invalid(format!("unknown column {}", w))),
+            },
+            _ => {
+                self.pos -= 1;
+                Err(self.unexpected("a column"))
+            }
+        }
+    }
+
+    fn or(&mut self) -> Result<Query> {
+        let mut query = self.and()?;
+        while self.keyword("OR") {
+            query = query.or(self.and()?);
+        }
+        Ok(query)
+    }
+
+    fn and(&mut self) -> Result<Query> {
+        let mut query = self.atom()?;
+        while self.keyword("AND") {
+            query = query.and(self.atom()?);
+        }
+        Ok(query)
+    }
+
+    fn atom(&mut self) -> Result<Query> {
+        if self.symbol("(") {
+            let query = self.or()?;
+            if !self.symbol(")") {
+                return Err(self.unexpected(")"));
+            }
+            return Ok(query);
+        }
+        let target = match self.column()? {
+            Column::Key => query::key(),
+            Column::Field(path) => query::field(&path),
+            Column::Value => return Err(invalid("compare value.<field>, not value".into())),
+        };
+        let op = match self.next() {
+            Some(Token::Symbol("=")) => Op::Eq,
+            Some(Token::Symbol("<")) => Op::Lt,
+            Some(Token::Symbol("<=")) => Op::Lte,
+            Some(Token::Symbol(">")) => Op::Gt,
+            Some(Token::Symbol(">=")) => Op::Gte,
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case("LIKE") => Op::Prefix,
+            _ => {
+                self.pos -= 1;
+                return Err(self.unexpected("a comparison"));
+            }
+        };
+        let value = match self.next() {
+            Some(Token::Str(s)) if op == Op::Prefix => match s.strip_suffix('%') {
+                Some(prefix) if !prefix.contains('%') => prefix.to_string(),
+                _ => {
+                    return Err(invalid(format!(
+                        "LIKE only supports 'prefix%', got '{}'",
+                        s
+                    )))
+                }
+            },
+            Some(Token::Str(s)) | Some(Token::Number(s)) if op != Op::Prefix => s,
+            Some(Token::Word(w))
+                if op != Op::Prefix
+                    && (w.eq_ignore_ascii_case("true") || w.eq_ignore_ascii_case("false")) =>
+            {
+                w.to_ascii_lowercase()
+            }
+            _ => {
+                self.pos -= 1;
+                return Err(self.unexpected("a quoted string or number"));
+            }
+        };
+        Ok(match op {
+            Op::Eq => target.eq(value),
+            Op::Lt => target.lt(value),
+            Op::Lte => target.lte(value),
+            Op::Gt => target.gt(value),
+            Op::Gte => target.gte(value),
+            Op::Prefix => target.prefix(value),
+        })
+    }
+}
+
+/// Parse a `SELECT` statement.
+pub fn parse(statement: &str) -> Result<Statement> {
+    Parser {
+        tokens: tokenize(statement)?,
+        pos: 0,
+    }
+    .statement()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::{field, key};
+
+    #[test]
+    fn parses_select_statements() {
+        let stmt = parse(
+            "select key, value.city FROM db WHERE value.age > 30 AND (key LIKE 'u:%' OR value.city = 'O''Brien') AT 'v1' LIMIT 5",
+        )
+        .unwrap();
+        assert_eq!(
+            stmt.columns,
+            vec![Column::Key, Column::Field("city".into())]
+        );
+        assert_eq!(stmt.namespace, None);
+        assert_eq!(
+            stmt.filter,
+            Some(
+                field("age")
+                    .gt(30)
+                    .and(key().prefix("u:").or(field("city").eq("O'Brien")))
+            )
+        );
+        assert_eq!(stmt.at.as_deref(), Some("v1"));
+        assert_eq!(stmt.limit, Some(5));
+
+        let stmt = parse("SELECT * FROM users").unwrap();
+        assert_eq!(stmt.columns, vec![Column::Key, Column::Value]);
+        assert_eq!(stmt.namespace.as_deref(), Some("users"));
+        assert_eq!(stmt.filter, None);
+    }
+
+    #[test]
+    fn rejects_malformed_statements() {
+        for bad in [
+            "",
+            "SELECT FROM db",
+            "SELECT key db",
+            "SELECT key FROM db WHERE value.age >",
+            "SELECT key FROM db WHERE value = 1",
+            "SELECT key FROM db WHERE key LIKE '%x'",
+            "SELECT key FROM db WHERE (key = 'a'",
+            "SELECT key FROM db LIMIT x",
+            "SELECT key FROM db extra",
+            "SELECT key FROM db WHERE key = 'open",
+        ] {
+            assert!(
+                matches!(parse(bad), Err(IcebergError::InvalidQuery(_))),
+                "{:?} should not parse",
+                bad
+            );
+        }
+    }
+}
@@ -1,16 +1,135 @@
-use crate::block::{Block, BlockHash};
+use crate::backend::{self, Backend, FsBackend};
+use crate::block::{compute_hash, Block, BlockHash};
+use crate::cache::{CacheStats, LruCache};
+use crate::compression::{self, Codec};
+use crate::config::CompressionConfig;
 use crate::error::{IcebergError, Result};
+#[cfg(not(target_arch = "wasm32"))]
+use memmap2::Mmap;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs;
-use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Magic prefix of binary block files.
+const BLOCK_MAGIC: &[u8; 4] = b"IBLK";
+/// Current binary block format version.
+const BLOCK_FORMAT_VERSION: u8 = 1;
+/// Size at which the append log's active segment is rotated.
+const LOG_SEGMENT_BYTES: u64 = 4 * 1024 * 1024;
+/// Bytes below which blocks are hashed and written on the calling thread,
+/// as spawning workers would cost more than it saves.
+pub(crate) const PARALLEL_MIN_BYTES: usize = 256 * 1024;
 
 /// Append-only, content-addressable block store.
 ///
-/// Blocks are stored as individual JSON files keyed by their SHA-256 hash.
-/// Duplicate writes are no-ops (content-addressable dedup).
+/// Blocks are stored as individual objects keyed by their SHA-256 hash.
+/// Duplicate writes are no-ops (content-addressable dedup). Payloads are
+/// compressed with the configured codec; the hash always covers the
+/// uncompressed data.
+///
+/// Block file layout: `IBLK`, format version, codec tag, dictionary id
+/// length + id, then the payload. Files written by older versions are JSON
+/// and remain readable.
+///
+/// New blocks are recorded in an append log, `log/append.jsonl`, which is
+/// rotated to `log/append-<last sequence>.jsonl` once it reaches
+/// `LOG_SEGMENT_BYTES`. The last sequence number is kept in memory, read
+/// from the active segment on first use, so each store handle assumes it
+/// is the only one adding blocks.
 pub struct BlockStore {
-    dir: PathBuf,
+    backend: Arc<dyn Backend>,
+    /// Key prefix of the store inside the backend.
+    dir: String,
+    compression: Mutex<CompressionConfig>,
+    dictionaries: Mutex<HashMap<BlockHash, Vec<u8>>>,
+    mmap: AtomicBool,
+    cache: Mutex<LruCache<BlockHash, Vec<u8>>>,
+    /// Where the append log stands; `None` until first needed.
+    log_state: Mutex<Option<LogState>>,
+    /// `LOG_SEGMENT_BYTES`, or less in tests.
+    log_segment_bytes: u64,
+}
+
+/// The append log's last sequence number and active segment size.
+#[derive(Debug, Clone, Copy)]
+struct LogState {
+    last_sequence: u64,
+    segment_bytes: u64,
+}
+
+/// A block's bytes, either borrowed from a memory-mapped file or owned.
+///
+/// Uncompressed blocks read with memory-mapping enabled are served straight
+/// from the page cache without copying; everything else is decoded into a buffer.
+/// There is no memory-mapping on wasm32, so values there are always owned.
+pub enum ValueRef {
+    #[cfg(not(target_arch = "wasm32"))]
+    Mapped {
+        map: Mmap,
+        start: usize,
+    },
+    Owned(Vec<u8>),
+}
+
+impl ValueRef {
+    /// Whether the bytes are borrowed from a memory-mapped file.
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, ValueRef::Mapped { .. })
+    }
+
+    /// Copy the bytes into an owned buffer.
+    pub fn into_vec(self) -> Vec<u8> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            ValueRef::Mapped { map, start } => map[start..].to_vec(),
+            ValueRef::Owned(v) => v,
+        }
+    }
+}
+
+impl Deref for ValueRef {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            ValueRef::Mapped { map, start } => &map[*start..],
+            ValueRef::Owned(v) => v,
+        }
+    }
+}
+
+impl AsRef<[u8]> for ValueRef {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Header of a binary block file.
+struct BlockHeader {
+    codec: Codec,
+    dict: Option<BlockHash>,
+    payload_offset: usize,
+}
+
+/// JSON block files written before the binary format.
+#[derive(Debug, Serialize, Deserialize)]
+struct LegacyBlock {
+    hash: BlockHash,
+    /// Blocks written before compression support carry no codec field.
+    #[serde(default = "legacy_codec")]
+    codec: Codec,
+    /// zstd dictionary the payload was compressed with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dict: Option<BlockHash>,
+    data: Vec<u8>,
+}
+
+fn legacy_codec() -> Codec {
+    Codec::None
 }
 
 /// The append-only log records every write in order, enabling replay and auditing.
@@ -24,101 +143,604 @@ pub struct LogEntry {
 impl BlockStore {
     /// Open or create a block store at the given directory.
     pub fn open(dir: &Path) -> Result<Self> {
-        fs::create_dir_all(dir.join("blocks"))?;
-        fs::create_dir_all(dir.join("log"))?;
-        Ok(Self {
-            dir: dir.to_path_buf(),
-        })
+        Ok(Self::with_backend(Arc::new(FsBackend::open(dir)?), ""))
+    }
+
+    /// Open a block store under the `dir` key prefix of a backend.
+    pub fn with_backend(backend: Arc<dyn Backend>, dir: &str) -> Self {
+        Self {
+            backend,
+            dir: dir.to_string(),
+            compression: Mutex::new(CompressionConfig::default()),
+            dictionaries: Mutex::new(HashMap::new()),
+            mmap: AtomicBool::new(false),
+            cache: Mutex::new(LruCache::new(crate::config::DEFAULT_CACHE_BYTES)),
+            log_state: Mutex::new(None),
+            log_segment_bytes: LOG_SEGMENT_BYTES,
+        }
+    }
+
+    /// Set the byte budget of the decoded-block cache (0 disables it).
+    pub fn set_cache_capacity(&self, bytes: usize) {
+        self.cache.lock().unwrap().set_capacity(bytes);
+    }
+
+    /// Block cache counters.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().stats()
+    }
+
+    /// Enable or disable memory-mapped reads in `get_ref`.
+    pub fn set_mmap(&self, enabled: bool) {
+        self.mmap.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Set the compression used for blocks written from now on.
+    /// Existing blocks keep their codec and remain readable.
+    pub fn set_compression(&self, config: CompressionConfig) {
+        *self.compression.lock().unwrap() = config;
+    }
+
+    /// Current compression settings.
+    pub fn compression(&self) -> CompressionConfig {
+        self.compression.lock().unwrap().clone()
+    }
+
+    /// Store a zstd dictionary. Returns its id (the hash of its bytes).
+    pub fn put_dictionary(&self, dict: &[u8]) -> Result<BlockHash> {
+        let id = compute_hash(dict);
+        let key = self.dict_key(&id);
+        if !self.backend.exists(&key)? {
+            self.backend.write(&key, dict)?;
+        }
+        self.dictionaries
+            .lock()
+            .unwrap()
+            .insert(id.clone(), dict.to_vec());
+        Ok(id)
+    }
+
+    /// Load a zstd dictionary by id.
+    pub fn get_dictionary(&self, id: &str) -> Result<Vec<u8>> {
+        if let Some(dict) = self.dictionaries.lock().unwrap().get(id) {
+            return Ok(dict.clone());
+        }
+        let dict = self
+            .backend
+            .read(&self.dict_key(id))?
+            .ok_or_else(|| IcebergError::Corruption(format!("dictionary not found: {}", id)))?;
+        self.dictionaries
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), dict.clone());
+        Ok(dict)
     }
 
     /// Store a block. Returns the hash. No-op if already present.
     pub fn put(&self, block: &Block) -> Result<BlockHash> {
-        let path = self.block_path(&block.hash);
-        if !path.exists() {
-            let data = serde_json::to_vec(block)?;
-            fs::write(&path, &data)?;
-            self.append_log(&block.hash)?;
+        let key = self.block_key(&block.hash);
+        if !self.backend.exists(&key)? {
+            let data = self.encode(block)?;
+            self.backend.create(&key, &data)?;
+            self.append_log(&[&block.hash])?;
         }
         Ok(block.hash.clone())
     }
 
-    /// Retrieve a block by hash.
+    /// Store several blocks, skipping those already present or repeated,
+    /// and record the new ones in the append log with a single write.
+    /// Returns the hashes in order.
+    ///
+    /// Blocks are compressed and written on several threads at once when
+    /// there is enough data to be worth it.
+    pub fn put_many(&self, blocks: &[Block]) -> Result<Vec<BlockHash>> {
+        let mut seen = HashSet::new();
+        let unique: Vec<&Block> = blocks.iter().filter(|b| seen.insert(&b.hash)).collect();
+        let write = |block: &&Block| -> Result<bool> {
+            let key = self.block_key(&block.hash);
+            if self.backend.exists(&key)? {
+                return Ok(false);
+            }
+            self.backend.create(&key, &self.encode(block)?)?;
+            Ok(true)
+        };
+        let bytes: usize = unique.iter().map(|b| b.data.len()).sum();
+        let written = match bytes < PARALLEL_MIN_BYTES {
+            true => unique.iter().map(write).collect::<Vec<_>>(),
+            false => par_map(&unique, write),
+        };
+        let mut added = Vec::new();
+        let mut failed = None;
+        for (block, written) in unique.iter().zip(written) {
+            match written {
+                Ok(true) => added.push(&block.hash),
+                Ok(false) => {}
+                Err(e) => {
+                    failed.get_or_insert(e);
+                }
+            }
+        }
+        // Blocks other writes stored are logged even if one failed.
+        self.append_log(&added)?;
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(blocks.iter().map(|b| b.hash.clone()).collect()),
+        }
+    }
+
+    /// Retrieve a block by hash. Verified blocks are kept in an LRU cache.
     pub fn get(&self, hash: &str) -> Result<Block> {
-        let path = self.block_path(hash);
+        if let Some(data) = self.cache.lock().unwrap().get(hash) {
+            return Ok(Block {
+                hash: hash.to_string(),
+                data,
+            });
+        }
+        let block = self.read_verified(hash)?;
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(block.hash.clone(), block.data.clone(), block.data.len());
+        Ok(block)
+    }
+
+    /// Re-read a block from storage, bypassing the cache, and check that it
+    /// decodes and matches its hash.
+    pub fn verify(&self, hash: &str) -> Result<()> {
+        self.read_verified(hash).map(|_| ())
+    }
+
+    /// Delete a block, returning its stored (encoded) bytes if it existed.
+    pub fn remove(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        let key = self.block_key(hash);
+        let raw = self.backend.read(&key)?;
+        if raw.is_some() {
+            self.backend.delete(&key)?;
+        }
+        self.cache.lock().unwrap().remove(hash);
+        Ok(raw)
+    }
+
+    /// The backend key of a block's file.
+    pub fn block_key(&self, hash: &str) -> String {
+        // Use first 2 chars as directory prefix (like git)
+        let prefix = &hash[..2.min(hash.len())];
+        backend::key(&self.dir, &format!("blocks/{}/{}", prefix, hash))
+    }
+
+    fn read_verified(&self, hash: &str) -> Result<Block> {
+        let raw = self
+            .backend
+            .read(&self.block_key(hash))?
+            .ok_or_else(|| IcebergError::Corruption(format!("block not found: {}", hash)))?;
+        let block = Block {
+            hash: hash.to_string(),
+            data: self.decode(hash, &raw)?,
+        };
+        if !block.verify() {
+            return Err(IcebergError::Corruption(format!(
+                "block integrity check failed: {}",
+                hash
+            )));
+        }
+        Ok(block)
+    }
+
+    /// Retrieve a block's bytes, memory-mapping the file when mmap reads are
+    /// enabled, the backend keeps blocks in local files, and the block is
+    /// stored uncompressed. Integrity is verified either way.
+    pub fn get_ref(&self, hash: &str) -> Result<ValueRef> {
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.mmap.load(Ordering::Relaxed) {
+            if let Some(path) = self.backend.local_path(&self.block_key(hash)) {
+                return self.get_mapped(hash, &path);
+            }
+        }
+        Ok(ValueRef::Owned(self.get(hash)?.data))
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn get_mapped(&self, hash: &str, path: &Path) -> Result<ValueRef> {
         if !path.exists() {
             return Err(IcebergError::Corruption(format!(
                 "block not found: {}",
                 hash
             )));
         }
-        let data = fs::read(&path)?;
-        let block: Block = serde_json::from_slice(&data)?;
-        if !block.verify() {
+        let file = std::fs::File::open(path)?;
+        // SAFETY: block files are content-addressed, only ever appear by
+        // renaming a complete temp file into place (`Backend::create` is
+        // `write_atomic` on the filesystem) and are never modified after, so
+        // the mapping cannot change underneath us.
+        let map = unsafe { Mmap::map(&file)? };
+        let value = match parse_header(&map)? {
+            Some(header) if header.codec == Codec::None => ValueRef::Mapped {
+                start: header.payload_offset,
+                map,
+            },
+            _ => ValueRef::Owned(self.decode(hash, &map)?),
+        };
+        if compute_hash(&value) != hash {
             return Err(IcebergError::Corruption(format!(
                 "block integrity check failed: {}",
                 hash
             )));
         }
-        Ok(block)
+        Ok(value)
     }
 
     /// Check if a block exists.
     pub fn contains(&self, hash: &str) -> bool {
-        self.block_path(hash).exists()
+        self.backend.exists(&self.block_key(hash)).unwrap_or(false)
     }
 
     /// Count stored blocks.
     pub fn block_count(&self) -> Result<usize> {
-        Ok(fs::read_dir(self.dir.join("blocks"))?
-            .filter_map(|e| e.ok())
-            .count())
+        Ok(self.block_keys()?.len())
     }
 
     /// Return total bytes used by block files.
     pub fn disk_usage(&self) -> Result<u64> {
         let mut total = 0u64;
-        for entry in fs::read_dir(self.dir.join("blocks"))? {
-            let entry = entry?;
-            total += entry.metadata()?.len();
+        for key in self.block_keys()? {
+            total += self.backend.size(&key)?.unwrap_or(0);
         }
         Ok(total)
     }
 
-    fn block_path(&self, hash: &str) -> PathBuf {
-        // Use first 2 chars as directory prefix (like git)
-        let prefix = &hash[..2.min(hash.len())];
-        let dir = self.dir.join("blocks").join(prefix);
-        let _ = fs::create_dir_all(&dir);
-        dir.join(hash)
+    /// Hashes of all stored blocks with the bytes their files take.
+    pub fn block_sizes(&self) -> Result<Vec<(BlockHash, u64)>> {
+        self.block_keys()?
+            .into_iter()
+            .map(|key| {
+                let size = self.backend.size(&key)?.unwrap_or(0);
+                let hash = key.rsplit('/').next().unwrap_or_default().to_string();
+                Ok((hash, size))
+            })
+            .collect()
     }
 
-    fn append_log(&self, hash: &BlockHash) -> Result<()> {
-        let log_path = self.dir.join("log").join("append.jsonl");
-        let seq = self.next_sequence()?;
-        let entry = LogEntry {
-            sequence: seq,
-            hash: hash.clone(),
-            timestamp: chrono::Utc::now().to_rfc3339(),
+    /// Bytes of the store's other files: the append log and compression
+    /// dictionaries.
+    pub fn metadata_bytes(&self) -> Result<u64> {
+        let mut total = 0;
+        for key in self.log_segments()? {
+            total += self.backend.size(&key)?.unwrap_or(0);
+        }
+        let dicts = backend::key(&self.dir, "dicts");
+        for id in self.backend.list(&dicts)? {
+            total += self.backend.size(&self.dict_key(&id))?.unwrap_or(0);
+        }
+        Ok(total)
+    }
+
+    /// Hashes of all stored blocks.
+    pub fn hashes(&self) -> Result<Vec<BlockHash>> {
+        Ok(self
+            .block_keys()?
+            .iter()
+            .filter_map(|key| key.rsplit('/').next())
+            .map(String::from)
+            .collect())
+    }
+
+    /// Delete every block not in `live` and drop its append-log entries.
+    /// Returns the number of blocks removed and the bytes they occupied.
+    pub fn retain(&self, live: &HashSet<BlockHash>) -> Result<(usize, u64)> {
+        self.retain_with(live, || {})
+    }
+
+    /// `retain`, calling `tick` for each block checked.
+    pub fn retain_with(
+        &self,
+        live: &HashSet<BlockHash>,
+        mut tick: impl FnMut(),
+    ) -> Result<(usize, u64)> {
+        let mut removed = HashSet::new();
+        let mut bytes = 0;
+        for key in self.block_keys()? {
+            tick();
+            let hash = key.rsplit('/').next().unwrap_or_default();
+            if live.contains(hash) {
+                continue;
+            }
+            bytes += self.backend.size(&key)?.unwrap_or(0);
+            self.backend.delete(&key)?;
+            self.cache.lock().unwrap().remove(hash);
+            removed.insert(hash.to_string());
+        }
+        if !removed.is_empty() {
+            self.prune_log(&removed)?;
+        }
+        Ok((removed.len(), bytes))
+    }
+
+    /// Keys of all stored blocks (blocks live in two-character prefix directories).
+    fn block_keys(&self) -> Result<Vec<String>> {
+        let blocks = backend::key(&self.dir, "blocks");
+        let mut keys = Vec::new();
+        for prefix in self.backend.list(&blocks)? {
+            let prefix = backend::key(&blocks, &prefix);
+            for name in self.backend.list(&prefix)? {
+                keys.push(backend::key(&prefix, &name));
+            }
+        }
+        Ok(keys)
+    }
+
+    fn encode(&self, block: &Block) -> Result<Vec<u8>> {
+        let cfg = self.compression();
+        let (data, dict) = match cfg.codec {
+            Codec::None => (block.data.clone(), None),
+            Codec::Lz4 => (compression::compress(&block.data), None),
+            Codec::Zstd => match &cfg.dictionary {
+                Some(id) => {
+                    let dict = self.get_dictionary(id)?;
+                    let data =
+                        compression::zstd_compress(&block.data, cfg.zstd_level, Some(&dict))?;
+                    (data, Some(id.clone()))
+                }
+                None => (
+                    compression::zstd_compress(&block.data, cfg.zstd_level, None)?,
+                    None,
+                ),
+            },
+        };
+        let dict = dict.unwrap_or_default();
+        let mut out = Vec::with_capacity(7 + dict.len() + data.len());
+        out.extend_from_slice(BLOCK_MAGIC);
+        out.push(BLOCK_FORMAT_VERSION);
+        out.push(codec_tag(cfg.codec));
+        out.push(dict.len() as u8);
+        out.extend_from_slice(dict.as_bytes());
+        out.extend_from_slice(&data);
+        Ok(out)
+    }
+
+    /// Decode a block file (binary or legacy JSON) into its uncompressed bytes.
+    fn decode(&self, hash: &str, raw: &[u8]) -> Result<Vec<u8>> {
+        match parse_header(raw)? {
+            Some(header) => self.decompress(
+                hash,
+                header.codec,
+                header.dict.as_deref(),
+                &raw[header.payload_offset..],
+            ),
+            None => {
+                let legacy: LegacyBlock = serde_json::from_slice(raw)?;
+                self.decompress(hash, legacy.codec, legacy.dict.as_deref(), &legacy.data)
+            }
+        }
+    }
+
+    fn decompress(
+        &self,
+        hash: &str,
+        codec: Codec,
+        dict: Option<&str>,
+        payload: &[u8],
+    ) -> Result<Vec<u8>> {
+        Ok(match codec {
+            Codec::None => payload.to_vec(),
+            Codec::Lz4 => compression::decompress(payload).map_err(|e| {
+                IcebergError::Corruption(format!("lz4 decode failed for {}: {}", hash, e))
+            })?,
+            Codec::Zstd => match dict {
+                Some(id) => {
+                    let dict = self.get_dictionary(id)?;
+                    compression::zstd_decompress(payload, Some(&dict))?
+                }
+                None => compression::zstd_decompress(payload, None)?,
+            },
+        })
+    }
+
+    fn dict_key(&self, id: &str) -> String {
+        backend::key(&self.dir, &format!("dicts/{}", id))
+    }
+
+    fn log_dir(&self) -> String {
+        backend::key(&self.dir, "log")
+    }
+
+    /// Key of the active append log segment.
+    fn log_key(&self) -> String {
+        backend::key(&self.log_dir(), "append.jsonl")
+    }
+
+    /// Key of the rotated segment ending at `last_sequence`. Zero-padded
+    /// so segments sort in order.
+    fn segment_key(&self, last_sequence: u64) -> String {
+        backend::key(
+            &self.log_dir(),
+            &format!("append-{:020}.jsonl", last_sequence),
+        )
+    }
+
+    /// Keys of the append log's segments, oldest first, the active one last.
+    fn log_segments(&self) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = self
+            .backend
+            .list(&self.log_dir())?
+            .into_iter()
+            .filter(|name| name.starts_with("append-"))
+            .map(|name| backend::key(&self.log_dir(), &name))
+            .collect();
+        keys.push(self.log_key());
+        Ok(keys)
+    }
+
+    /// Record new blocks in the append log, in one write.
+    fn append_log(&self, hashes: &[&BlockHash]) -> Result<()> {
+        if hashes.is_empty() {
+            return Ok(());
+        }
+        let mut guard = self.log_state.lock().unwrap();
+        let state = match guard.as_mut() {
+            Some(state) => state,
+            None => guard.insert(self.load_log_state()?),
         };
-        let mut line = serde_json::to_string(&entry)?;
-        line.push('\n');
-        use std::io::Write;
-        let mut f = fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(log_path)?;
-        f.write_all(line.as_bytes())?;
+        if state.segment_bytes >= self.log_segment_bytes {
+            self.backend
+                .rename(&self.log_key(), &self.segment_key(state.last_sequence))?;
+            state.segment_bytes = 0;
+        }
+        let timestamp = chrono::Utc::now().to_rfc3339();
+        let mut lines = String::new();
+        for (i, hash) in hashes.iter().enumerate() {
+            let entry = LogEntry {
+                sequence: state.last_sequence + 1 + i as u64,
+                hash: hash.to_string(),
+                timestamp: timestamp.clone(),
+            };
+            lines.push_str(&serde_json::to_string(&entry)?);
+            lines.push('\n');
+        }
+        self.backend.append(&self.log_key(), lines.as_bytes())?;
+        state.last_sequence += hashes.len() as u64;
+        state.segment_bytes += lines.len() as u64;
+        Ok(())
+    }
+
+    /// Where the append log stands on disk: the last sequence of the
+    /// active segment, or of the newest rotated one if it is empty.
+    fn load_log_state(&self) -> Result<LogState> {
+        let content = self.backend.read(&self.log_key())?.unwrap_or_default();
+        let lines: Vec<_> = content
+            .split(|&b| b == b'\n')
+            .filter(|l| !l.is_empty())
+            .collect();
+        let rotated = || -> Result<u64> {
+            let segments = self.log_segments()?;
+            Ok(segments
+                .iter()
+                .rev()
+                .filter_map(|key| key.rsplit('/').next())
+                .filter_map(|name| name.strip_prefix("append-")?.strip_suffix(".jsonl"))
+                .find_map(|n| n.parse().ok())
+                .unwrap_or(0))
+        };
+        let last_sequence = match lines.last() {
+            // Collected blocks leave gaps, so continue after the last entry.
+            Some(line) => match serde_json::from_slice::<LogEntry>(line) {
+                Ok(entry) => entry.sequence,
+                Err(_) => rotated()? + lines.len() as u64,
+            },
+            None => rotated()?,
+        };
+        Ok(LogState {
+            last_sequence,
+            segment_bytes: content.len() as u64,
+        })
+    }
+
+    /// Rewrite the append log without the entries of `removed` blocks.
+    /// Remaining entries keep their sequence numbers. Rotated segments left
+    /// empty are deleted, except the newest, whose name carries the last
+    /// sequence while the active segment is empty.
+    fn prune_log(&self, removed: &HashSet<BlockHash>) -> Result<()> {
+        let mut state = self.log_state.lock().unwrap();
+        let segments = self.log_segments()?;
+        let newest_rotated = segments.len().checked_sub(2);
+        for (i, key) in segments.iter().enumerate() {
+            let Some(content) = self.backend.read(key)? else {
+                continue;
+            };
+            let mut kept = Vec::with_capacity(content.len());
+            for line in content.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+                let gone = serde_json::from_slice::<LogEntry>(line)
+                    .is_ok_and(|entry| removed.contains(&entry.hash));
+                if !gone {
+                    kept.extend_from_slice(line);
+                    kept.push(b'\n');
+                }
+            }
+            if kept.len() == content.len() {
+                continue;
+            }
+            if kept.is_empty() && *key != self.log_key() && Some(i) != newest_rotated {
+                self.backend.delete(key)?;
+            } else {
+                self.backend.write(key, &kept)?;
+            }
+        }
+        // The active segment may have shrunk.
+        if let Some(state) = state.as_mut() {
+            state.segment_bytes = self.backend.size(&self.log_key())?.unwrap_or(0);
+        }
         Ok(())
     }
+}
 
-    fn next_sequence(&self) -> Result<u64> {
-        let log_path = self.dir.join("log").join("append.jsonl");
-        if !log_path.exists() {
-            return Ok(1);
+/// `f` applied to each item, spread over one scoped thread per available
+/// core; results come back in order. Runs on the calling thread where
+/// there are no threads.
+pub(crate) fn par_map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+        if workers > 1 && items.len() > 1 {
+            let per_worker = items.len().div_ceil(workers);
+            let f = &f;
+            return std::thread::scope(|scope| {
+                let handles: Vec<_> = items
+                    .chunks(per_worker)
+                    .map(|chunk| scope.spawn(move || chunk.iter().map(f).collect::<Vec<R>>()))
+                    .collect();
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().expect("worker thread panicked"))
+                    .collect()
+            });
         }
-        let content = fs::read_to_string(&log_path)?;
-        Ok(content.lines().count() as u64 + 1)
     }
+    items.iter().map(f).collect()
+}
+
+fn codec_tag(codec: Codec) -> u8 {
+    match codec {
+        Codec::None => 0,
+        Codec::Lz4 => 1,
+        Codec::Zstd => 2,
+    }
+}
+
+/// Parse the header of a binary block file. Returns `None` for legacy JSON files.
+fn parse_header(raw: &[u8]) -> Result<Option<BlockHeader>> {
+    if !raw.starts_with(BLOCK_MAGIC) {
+        return Ok(None);
+    }
+    let corrupt = |msg: &str| IcebergError::Corruption(format!("block header: {}", msg));
+    if raw.len() < 7 {
+        return Err(corrupt("truncated"));
+    }
+    if raw[4] != BLOCK_FORMAT_VERSION {
+        return Err(corrupt(&format!("unsupported version {}", raw[4])));
+    }
+    let codec = match raw[5] {
+        0 => Codec::None,
+        1 => Codec::Lz4,
+        2 => Codec::Zstd,
+        other => return Err(corrupt(&format!("unknown codec tag {}", other))),
+    };
+    let dict_len = raw[6] as usize;
+    let payload_offset = 7 + dict_len;
+    if raw.len() < payload_offset {
+        return Err(corrupt("truncated dictionary id"));
+    }
+    let dict = if dict_len == 0 {
+        None
+    } else {
+        Some(
+            String::from_utf8(raw[7..payload_offset].to_vec())
+                .map_err(|_| corrupt("invalid dictionary id"))?,
+        )
+    };
+    Ok(Some(BlockHeader {
+        codec,
+        dict,
+        payload_offset,
+    }))
 }
 
 /// In-memory block store for testing.
@@ -185,6 +807,264 @@ mod tests {
         assert_eq!(store.block_count().unwrap(), 1);
     }
 
+    #[test]
+    fn blockstore_codecs_roundtrip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = BlockStore::open(tmp.path()).unwrap();
+        for (i, codec) in [Codec::None, Codec::Lz4, Codec::Zstd]
+            .into_iter()
+            .enumerate()
+        {
+            store.set_compression(CompressionConfig {
+                codec,
+                zstd_level: 19,
+                dictionary: None,
+            });
+            let block = Block::new(format!("{} payload payload payload", i).into_bytes());
+            store.put(&block).unwrap();
+            assert_eq!(store.get(&block.hash).unwrap(), block);
+        }
+    }
+
+    #[test]
+    fn blockstore_zstd_dictionary() {
+        let tmp = tempfile::tempdir().unwrap();
+        let samples: Vec<Vec<u8>> = (0..300)
+            .map(|i| format!(r#"{{"user":"u{}","role":"admin","city":"Bern"}}"#, i).into_bytes())
+            .collect();
+        let dict = compression::train_dictionary(&samples, 2048).unwrap();
+        let store = BlockStore::open(tmp.path()).unwrap();
+        let id = store.put_dictionary(&dict).unwrap();
+        store.set_compression(CompressionConfig {
+            codec: Codec::Zstd,
+            zstd_level: 3,
+            dictionary: Some(id),
+        });
+        let block = Block::new(br#"{"user":"u1000","role":"admin","city":"Bern"}"#.to_vec());
+        store.put(&block).unwrap();
+
+        // A fresh handle must find the dictionary on disk.
+        let reopened = BlockStore::open(tmp.path()).unwrap();
+        assert_eq!(reopened.get(&block.hash).unwrap().data, block.data);
+    }
+
+    #[test]
+    fn blockstore_reads_legacy_uncompressed_blocks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = BlockStore::open(tmp.path()).unwrap();
+        let block = Block::new(b"legacy".to_vec());
+        store
+            .backend
+            .create(
+                &store.block_key(&block.hash),
+                &serde_json::to_vec(&block).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(store.get(&block.hash).unwrap(), block);
+    }
+
+    #[test]
+    fn blockstore_reads_legacy_compressed_json_blocks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = BlockStore::open(tmp.path()).unwrap();
+        let data = b"legacy lz4 legacy lz4 legacy lz4".to_vec();
+        let hash = compute_hash(&data);
+        let legacy = LegacyBlock {
+            hash: hash.clone(),
+            codec: Codec::Lz4,
+            dict: None,
+            data: compression::compress(&data),
+        };
+        store
+            .backend
+            .create(
+                &store.block_key(&hash),
+                &serde_json::to_vec(&legacy).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(store.get(&hash).unwrap().data, data);
+    }
+
+    #[test]
+    fn get_ref_maps_uncompressed_blocks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = BlockStore::open(tmp.path()).unwrap();
+        store.set_compression(CompressionConfig {
+            codec: Codec::None,
+            ..CompressionConfig::default()
+        });
+        let raw = Block::new(vec![42u8; 10_000]);
+        store.put(&raw).unwrap();
+        store.set_compression(CompressionConfig::default());
+        let packed = Block::new(vec![7u8; 10_000]);
+        store.put(&packed).unwrap();
+
+        // Without mmap everything is owned.
+        assert!(!store.get_ref(&raw.hash).unwrap().is_mapped());
+
+        store.set_mmap(true);
+        let mapped = store.get_ref(&raw.hash).unwrap();
+        assert!(mapped.is_mapped());
+        assert_eq!(&*mapped, &raw.data[..]);
+
+        // Compressed blocks must be decoded, so they come back owned.
+        let decoded = store.get_ref(&packed.hash).unwrap();
+        assert!(!decoded.is_mapped());
+        assert_eq!(decoded.into_vec(), packed.data);
+    }
+
+    #[test]
+    fn block_cache_counts_hits() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = BlockStore::open(tmp.path()).unwrap();
+        let block = Block::new(b"cached".to_vec());
+        store.put(&block).unwrap();
+
+        store.get(&block.hash).unwrap();
+        store.get(&block.hash).unwrap();
+        let stats = store.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+
+        store.set_cache_capacity(0);
+        store.get(&block.hash).unwrap();
+        assert_eq!(store.cache_stats().misses, 2);
+    }
+
+    #[test]
+    fn blockstore_on_memory_backend() {
+        let store =
+            BlockStore::with_backend(Arc::new(crate::backend::MemoryBackend::new()), "store");
+        store.set_mmap(true);
+        let block = Block::new(b"in memory".to_vec());
+        store.put(&block).unwrap();
+        assert_eq!(store.get(&block.hash).unwrap(), block);
+        // No local file to map, so reads fall back to an owned copy.
+        assert!(!store.get_ref(&block.hash).unwrap().is_mapped());
+        assert_eq!(store.block_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn retain_removes_unlisted_blocks_and_their_log_entries() {
+        let backend = Arc::new(crate::backend::MemoryBackend::new());
+        let store = BlockStore::with_backend(backend.clone(), "store");
+        let keep = Block::new(b"keep".to_vec());
+        let drop = Block::new(b"drop".to_vec());
+        store.put(&drop).unwrap();
+        store.put(&keep).unwrap();
+        store.get(&drop.hash).unwrap();
+
+        let live = HashSet::from([keep.hash.clone()]);
+        let (removed, bytes) = store.retain(&live).unwrap();
+        assert_eq!(removed, 1);
+        assert!(bytes > 0);
+        assert!(!store.contains(&drop.hash));
+        assert!(store.get(&drop.hash).is_err());
+        assert_eq!(store.get(&keep.hash).unwrap(), keep);
+
+        let log =
+            String::from_utf8(backend.read("store/log/append.jsonl").unwrap().unwrap()).unwrap();
+        assert!(!log.contains(&drop.hash));
+        assert!(log.contains(&keep.hash));
+        // Sequence numbers continue after the gap.
+        store.put(&Block::new(b"next".to_vec())).unwrap();
+        let log = backend.read("store/log/append.jsonl").unwrap().unwrap();
+        let last: LogEntry =
+            serde_json::from_slice(log.split(|&b| b == b'\n').rev().nth(1).unwrap()).unwrap();
+        assert_eq!(last.sequence, 3);
+    }
+
+    #[test]
+    fn append_log_rotates_and_keeps_counting() {
+        let backend = Arc::new(crate::backend::MemoryBackend::new());
+        let mut store = BlockStore::with_backend(backend.clone(), "store");
+        store.log_segment_bytes = 300;
+        let blocks: Vec<Block> = (0..10)
+            .map(|i| Block::new(format!("block {}", i).into_bytes()))
+            .collect();
+        for block in &blocks {
+            store.put(block).unwrap();
+        }
+        let segments = store.log_segments().unwrap();
+        assert!(segments.len() > 2, "{:?}", segments);
+        let entries = |key: &str| -> Vec<LogEntry> {
+            let content = backend.read(key).unwrap().unwrap_or_default();
+            content
+                .split(|&b| b == b'\n')
+                .filter(|l| !l.is_empty())
+                .map(|l| serde_json::from_slice(l).unwrap())
+                .collect()
+        };
+        let sequences: Vec<u64> = segments
+            .iter()
+            .flat_map(|key| entries(key))
+            .map(|e| e.sequence)
+            .collect();
+        assert_eq!(sequences, (1..=10).collect::<Vec<_>>());
+
+        // Pruning spans every segment, and a fresh handle carries on after
+        // the last entry even when the active segment is empty.
+        let live: HashSet<BlockHash> = blocks[1..].iter().map(|b| b.hash.clone()).collect();
+        store.retain(&live).unwrap();
+        assert!(entries(&segments[0]).iter().all(|e| e.sequence != 1));
+        backend.delete(&store.log_key()).unwrap();
+        let mut reopened = BlockStore::with_backend(backend.clone(), "store");
+        reopened.log_segment_bytes = 300;
+        reopened.put(&Block::new(b"next".to_vec())).unwrap();
+        let last = entries(&store.log_key()).pop().unwrap();
+        let rotated_last = entries(&segments[segments.len() - 2]).pop().unwrap();
+        assert_eq!(last.sequence, rotated_last.sequence + 1);
+    }
+
+    #[test]
+    fn put_many_skips_stored_and_repeated_blocks() {
+        let backend = Arc::new(crate::backend::MemoryBackend::new());
+        let store = BlockStore::with_backend(backend.clone(), "store");
+        let old = Block::new(b"old".to_vec());
+        store.put(&old).unwrap();
+        let new = Block::new(b"new".to_vec());
+        let blocks = [new.clone(), old.clone(), new.clone()];
+        let hashes = store.put_many(&blocks).unwrap();
+        assert_eq!(hashes, [&*new.hash, &*old.hash, &*new.hash]);
+        assert_eq!(store.get(&new.hash).unwrap(), new);
+        assert_eq!(store.block_count().unwrap(), 2);
+
+        let log = backend.read("store/log/append.jsonl").unwrap().unwrap();
+        let entries: Vec<LogEntry> = log
+            .split(|&b| b == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_slice(l).unwrap())
+            .collect();
+        let logged: Vec<_> = entries.iter().map(|e| (e.sequence, &e.hash)).collect();
+        assert_eq!(logged, [(1, &old.hash), (2, &new.hash)]);
+        assert!(store.put_many(&[]).unwrap().is_empty());
+
+        // Enough data to spread over threads.
+        let large: Vec<Block> = (0..64u8).map(|i| Block::new(vec![i; 8192])).collect();
+        store.put_many(&large).unwrap();
+        for block in &large {
+            assert_eq!(store.get(&block.hash).unwrap(), *block);
+        }
+        assert_eq!(store.block_count().unwrap(), 66);
+    }
+
+    #[test]
+    fn put_many_logs_blocks_written_before_a_failure() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = BlockStore::open(tmp.path()).unwrap();
+        let good = Block::new(b"good".to_vec());
+        let bad = Block::new(b"bad".to_vec());
+        assert_ne!(good.hash[..2], bad.hash[..2]);
+        // A file where the bad block's directory should be fails its write.
+        std::fs::create_dir_all(tmp.path().join("blocks")).unwrap();
+        std::fs::write(tmp.path().join("blocks").join(&bad.hash[..2]), b"").unwrap();
+
+        assert!(store.put_many(&[bad, good.clone()]).is_err());
+        let log = std::fs::read(tmp.path().join(store.log_key())).unwrap();
+        let entry: LogEntry = serde_json::from_slice(log.trim_ascii_end()).unwrap();
+        assert_eq!(entry.hash, good.hash);
+        assert_eq!(store.get(&good.hash).unwrap(), good);
+    }
+
     #[test]
     fn memory_store_basics() {
         let mut store = MemoryStore::new();
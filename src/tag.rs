@@ -1,8 +1,26 @@
 use crate::block::{compute_hash, BlockHash};
+use crate::error::{IcebergError, Result};
 use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Signer, Verifier};
+use semver::Version;
 use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
 
-/// A tag is a named, immutable pointer to a specific commit.
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+/// Whether a tag carries an annotation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TagKind {
+    /// Just a name for a commit.
+    Lightweight,
+    /// A name for a commit with a message, as releases are usually tagged.
+    Annotated,
+}
+
+/// A tag is a named pointer to a specific commit. It only moves when forced
+/// to or when history is rewritten under it; `Database::tag_log` lists
+/// every change.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Tag {
     /// Unique id of this tag (hash of name + commit + timestamp).
@@ -11,10 +29,21 @@ pub struct Tag {
     pub name: String,
     /// The commit this tag points to.
     pub commit_id: BlockHash,
-    /// Optional annotation message.
+    /// Annotation message; annotated tags have one, lightweight ones not.
     pub message: Option<String>,
     /// When the tag was created.
     pub created_at: DateTime<Utc>,
+    /// Attests that the holder of a key named this commit so.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<TagSignature>,
+}
+
+/// An ed25519 signature over a tag's name and commit, with the public key
+/// to check it against, both hex-encoded.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TagSignature {
+    pub public_key: String,
+    pub signature: String,
 }
 
 impl Tag {
@@ -34,10 +63,109 @@ impl Tag {
             commit_id,
             message,
             created_at,
+            signature: None,
         }
     }
 }
 
+impl Tag {
+    /// Whether the tag is annotated.
+    pub fn kind(&self) -> TagKind {
+        match self.message {
+            Some(_) => TagKind::Annotated,
+            None => TagKind::Lightweight,
+        }
+    }
+
+    /// What a signature covers: the name and the commit, so a tag moved
+    /// to another commit no longer verifies.
+    fn signed_payload(&self) -> Vec<u8> {
+        format!("iceberg tag\nname:{}\ncommit:{}", self.name, self.commit_id).into_bytes()
+    }
+
+    /// Sign the tag with `key`, replacing any earlier signature.
+    pub fn sign(&mut self, key: &SigningKey) {
+        let signature = key.sign(&self.signed_payload());
+        self.signature = Some(TagSignature {
+            public_key: to_hex(key.verifying_key().as_bytes()),
+            signature: to_hex(&signature.to_bytes()),
+        });
+    }
+
+    /// Check the tag's signature, and that it was made with `trusted` if
+    /// given. Returns the key it was made with.
+    pub fn verify(&self, trusted: Option<&VerifyingKey>) -> Result<VerifyingKey> {
+        let bad = |why: &str| IcebergError::BadSignature(format!("tag {}: {}", self.name, why));
+        let signed = self.signature.as_ref().ok_or_else(|| bad("not signed"))?;
+        let key = parse_verifying_key(&signed.public_key)?;
+        if trusted.is_some_and(|trusted| *trusted != key) {
+            return Err(bad("signed with an untrusted key"));
+        }
+        let signature = from_hex(&signed.signature)
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or_else(|| bad("malformed signature"))?;
+        key.verify(&self.signed_payload(), &signature)
+            .map_err(|_| bad("signature does not match"))?;
+        Ok(key)
+    }
+
+    /// The semantic version the name spells, with or without a leading
+    /// `v`: `v1.2.0` and `2.0.0-rc.1` do, `v1.2` and `release` do not.
+    pub fn version(&self) -> Option<Version> {
+        let name = self.name.strip_prefix(['v', 'V']).unwrap_or(&self.name);
+        Version::parse(name).ok()
+    }
+}
+
+/// A signing key from the hex form of its 32-byte secret.
+pub fn parse_signing_key(hex: &str) -> Result<SigningKey> {
+    from_hex(hex.trim())
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(|secret: [u8; 32]| SigningKey::from_bytes(&secret))
+        .ok_or_else(|| IcebergError::BadSignature("malformed signing key".into()))
+}
+
+/// A public key from its hex form.
+pub fn parse_verifying_key(hex: &str) -> Result<VerifyingKey> {
+    from_hex(hex.trim())
+        .and_then(|bytes| bytes.try_into().ok())
+        .and_then(|bytes: [u8; 32]| VerifyingKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| IcebergError::BadSignature("malformed public key".into()))
+}
+
+/// Lowercase hex of `bytes`, the form keys and signatures are written in.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// One change to a tag: created (`old` is `None`), moved, or deleted
+/// (`new` is `None`).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TagLogEntry {
+    pub name: String,
+    /// The commit the tag pointed to before.
+    pub old: Option<BlockHash>,
+    /// The commit the tag points to after.
+    pub new: Option<BlockHash>,
+    pub at: DateTime<Utc>,
+}
+
+/// Sort tags by semantic version, highest first, pre-releases below their
+/// release. Tags not named like a version follow, newest first.
+pub fn sort_by_version(tags: &mut [Tag]) {
+    tags.sort_by_cached_key(|t| (Reverse(t.version()), Reverse(t.created_at)));
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -48,7 +176,69 @@ mod tests {
         assert_eq!(tag.name, "v1.0");
         assert_eq!(tag.commit_id, "abc123");
         assert_eq!(tag.message, Some("release".into()));
+        assert_eq!(tag.kind(), TagKind::Annotated);
         assert!(!tag.id.is_empty());
+        let tag = Tag::new("nightly".into(), "abc123".into(), None);
+        assert_eq!(tag.kind(), TagKind::Lightweight);
+    }
+
+    #[test]
+    fn sorts_by_semantic_version() {
+        let mut tags: Vec<Tag> = [
+            "v1.10.0",
+            "nightly",
+            "v1.2.0",
+            "v2.0.0-rc.1",
+            "2.0.0",
+            "v1.2.0-beta",
+            "v1.9",
+        ]
+        .into_iter()
+        .map(|name| Tag::new(name.into(), "abc".into(), None))
+        .collect();
+        sort_by_version(&mut tags);
+        let names: Vec<&str> = tags.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "2.0.0",
+                "v2.0.0-rc.1",
+                "v1.10.0",
+                "v1.2.0",
+                "v1.2.0-beta",
+                "v1.9",
+                "nightly"
+            ]
+        );
+    }
+
+    #[test]
+    fn signatures_cover_name_and_commit() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let other = SigningKey::from_bytes(&[8; 32]);
+        let mut tag = Tag::new("v1.0".into(), "abc".into(), None);
+        assert!(tag.verify(None).is_err());
+
+        tag.sign(&key);
+        let hex = tag.signature.as_ref().unwrap().public_key.clone();
+        assert_eq!(parse_verifying_key(&hex).unwrap(), key.verifying_key());
+        assert_eq!(tag.verify(None).unwrap(), key.verifying_key());
+        assert!(tag.verify(Some(&key.verifying_key())).is_ok());
+        assert!(tag.verify(Some(&other.verifying_key())).is_err());
+
+        let mut moved = tag.clone();
+        moved.commit_id = "def".into();
+        assert!(moved.verify(None).is_err());
+        let mut forged = tag.clone();
+        forged.signature.as_mut().unwrap().public_key = to_hex(other.verifying_key().as_bytes());
+        assert!(forged.verify(None).is_err());
+
+        let secret = to_hex(&key.to_bytes());
+        assert_eq!(
+            parse_signing_key(&secret).unwrap().to_bytes(),
+            key.to_bytes()
+        );
+        assert!(parse_signing_key("abc").is_err());
     }
 
     #[test]
@@ -0,0 +1,173 @@
+use crate::commit::Commit;
+use crate::db::{Database, ScanOptions, Target};
+use crate::error::{IcebergError, Result};
+
+/// Separates a tenant from a branch name: `tenant-a/main`.
+pub const SEPARATOR: char = '/';
+
+/// The branch a tenant has checked out until it checks out another.
+pub const DEFAULT_BRANCH: &str = "main";
+
+/// Full name of `branch` of `tenant`.
+pub fn qualify(tenant: &str, branch: &str) -> String {
+    format!("{}{}{}", tenant, SEPARATOR, branch)
+}
+
+/// Split a full branch name into its tenant, if any, and the name within it.
+pub fn split(branch: &str) -> (Option<&str>, &str) {
+    match branch.split_once(SEPARATOR) {
+        Some((tenant, name)) => (Some(tenant), name),
+        None => (None, branch),
+    }
+}
+
+/// Full name of `branch` of `tenant`, or `branch` itself outside a tenant.
+pub(crate) fn scoped(tenant: Option<&str>, branch: &str) -> String {
+    match tenant {
+        Some(tenant) => qualify(tenant, branch),
+        None => branch.to_string(),
+    }
+}
+
+/// Check that `name` can be used as a tenant name.
+pub fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains(SEPARATOR) || name.chars().any(char::is_control) {
+        return Err(IcebergError::InvalidTenant(format!("{:?}", name)));
+    }
+    Ok(())
+}
+
+/// A view of one tenant of a database, from `Database::tenant`.
+///
+/// Branch names are relative to the tenant and HEAD is the tenant's own, so
+/// one process can serve many tenants without them seeing each other's refs.
+/// Commits, tags and the block store are shared.
+pub struct Tenant<'a> {
+    db: &'a Database,
+    name: String,
+}
+
+impl<'a> Tenant<'a> {
+    pub(crate) fn new(db: &'a Database, name: &str) -> Result<Self> {
+        check_name(name)?;
+        Ok(Self {
+            db,
+            name: name.to_string(),
+        })
+    }
+
+    /// The tenant name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn tenant(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    fn target(&self) -> Target<'_> {
+        Target::Tenant(&self.name)
+    }
+
+    /// Name of the tenant's checked-out branch.
+    pub fn current_branch(&self) -> Result<String> {
+        self.db.tenant_head(&self.name)
+    }
+
+    /// The tenant's branches.
+    pub fn branches(&self) -> Result<Vec<String>> {
+        self.db.tenant_branches(&self.name)
+    }
+
+    /// Create a branch from the tenant's HEAD.
+    pub fn create_branch(&self, name: &str) -> Result<()> {
+        self.db.create_branch_in(self.tenant(), name)
+    }
+
+    /// Switch the tenant's HEAD to another of its branches.
+    pub fn checkout(&self, name: &str) -> Result<()> {
+        self.db.checkout_in(self.tenant(), name)
+    }
+
+    /// Delete one of the tenant's branches (not its current one).
+    pub fn delete_branch(&self, name: &str) -> Result<()> {
+        self.db.delete_branch_in(self.tenant(), name)
+    }
+
+    /// Merge another of the tenant's branches into its current one.
+    pub fn merge(&self, source_branch: &str, message: Option<&str>) -> Result<Commit> {
+        self.db.merge_in(self.tenant(), source_branch, message)
+    }
+
+    /// The commit at the tenant's HEAD.
+    pub fn head_commit(&self) -> Result<Commit> {
+        self.db
+            .branch_commit(&qualify(&self.name, &self.current_branch()?))
+    }
+
+    /// Commit log of the tenant's current branch, newest first.
+    pub fn log(&self) -> Result<Vec<Commit>> {
+        self.db.log_of(self.target())
+    }
+
+    /// `log` loading commits on demand, as `Database::log_iter`.
+    pub fn log_iter(&self) -> Result<impl Iterator<Item = Result<Commit>> + '_> {
+        self.db.log_iter_of(self.target())
+    }
+
+    /// Resolve `HEAD`, one of the tenant's branches, a tag name or a commit id.
+    pub fn resolve_ref(&self, spec: &str) -> Result<String> {
+        self.db.resolve_ref_in(self.tenant(), spec)
+    }
+
+    /// Get a value from the tenant's HEAD.
+    pub fn get(&self, key: &str) -> Result<Vec<u8>> {
+        self.db.get_in(self.target(), key)
+    }
+
+    /// Get a value as of a specific commit.
+    pub fn get_at(&self, key: &str, commit_id: &str) -> Result<Vec<u8>> {
+        self.db.get_at(key, commit_id)
+    }
+
+    /// Put a key-value pair; creates a new commit on the tenant's HEAD.
+    pub fn put(&self, key: &str, value: Vec<u8>, message: Option<&str>) -> Result<Commit> {
+        crate::namespace::check_key(key)?;
+        let msg = message
+            .map(String::from)
+            .unwrap_or_else(|| format!("put {}", key));
+        self.db.put_raw(self.target(), key, value, None, None, &msg)
+    }
+
+    /// Delete a key; creates a new commit on the tenant's HEAD.
+    pub fn delete(&self, key: &str, message: Option<&str>) -> Result<Commit> {
+        crate::namespace::check_key(key)?;
+        let msg = message
+            .map(String::from)
+            .unwrap_or_else(|| format!("delete {}", key));
+        self.db.delete_raw(self.target(), key, None, &msg)
+    }
+
+    /// Scan keys at the tenant's HEAD by prefix.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        self.db
+            .scan_prefix_in(self.target(), prefix, &ScanOptions::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qualify_and_split_round_trip() {
+        let branch = qualify("tenant-a", "main");
+        assert_eq!(branch, "tenant-a/main");
+        assert_eq!(split(&branch), (Some("tenant-a"), "main"));
+        assert_eq!(split("main"), (None, "main"));
+        assert_eq!(scoped(None, "main"), "main");
+        assert!(check_name("tenant-a").is_ok());
+        assert!(check_name("a/b").is_err());
+        assert!(check_name("").is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! Line-level unified diffs of text and JSON values, for `diff --values`.
+
+/// Line pairs above which `unified` stops looking for common lines and
+/// shows the whole old text removed and the whole new text added.
+const MAX_LCS_CELLS: usize = 1 << 22;
+
+/// A value as text for diffing: JSON pretty-printed so a one-field change
+/// shows as one line, other UTF-8 as is, `None` for binary data.
+pub fn as_text(value: &[u8]) -> Option<String> {
+    match serde_json::from_slice::<serde_json::Value>(value) {
+        Ok(doc @ (serde_json::Value::Object(_) | serde_json::Value::Array(_))) => {
+            serde_json::to_string_pretty(&doc).ok()
+        }
+        _ => std::str::from_utf8(value).ok().map(str::to_string),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// A unified diff from `old` to `new` as lines: `@@ -l,n +l,n @@` hunk
+/// headers followed by lines prefixed with ` `, `-` or `+`, keeping
+/// `context` unchanged lines around each change. Empty when equal.
+pub fn unified(old: &str, new: &str, context: usize) -> Vec<String> {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+    let ops = edit_script(&a, &b);
+
+    // (op, line, old index, new index) with indices before the op applies.
+    let mut steps = Vec::with_capacity(ops.len());
+    let (mut i, mut j) = (0, 0);
+    for op in ops {
+        let line = match op {
+            Op::Delete => a[i],
+            Op::Equal | Op::Insert => b[j],
+        };
+        steps.push((op, line, i, j));
+        match op {
+            Op::Equal => (i, j) = (i + 1, j + 1),
+            Op::Delete => i += 1,
+            Op::Insert => j += 1,
+        }
+    }
+
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (at, _) in steps.iter().enumerate().filter(|(_, s)| s.0 != Op::Equal) {
+        let (start, end) = (
+            at.saturating_sub(context),
+            (at + context + 1).min(steps.len()),
+        );
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut lines = Vec::new();
+    for (start, end) in hunks {
+        let hunk = &steps[start..end];
+        let old_len = hunk.iter().filter(|s| s.0 != Op::Insert).count();
+        let new_len = hunk.iter().filter(|s| s.0 != Op::Delete).count();
+        let (_, _, i, j) = hunk[0];
+        let line_no = |at: usize, len: usize| if len == 0 { at } else { at + 1 };
+        lines.push(format!(
+            "@@ -{},{} +{},{} @@",
+            line_no(i, old_len),
+            old_len,
+            line_no(j, new_len),
+            new_len
+        ));
+        for (op, line, _, _) in hunk {
+            let sign = match op {
+                Op::Equal => ' ',
+                Op::Delete => '-',
+                Op::Insert => '+',
+            };
+            lines.push(format!("{}{}", sign, line));
+        }
+    }
+    lines
+}
+
+/// The shortest edit script from `a` to `b`, via their longest common
+/// subsequence of lines.
+fn edit_script(a: &[&str], b: &[&str]) -> Vec<Op> {
+    let (n, m) = (a.len(), b.len());
+    if n.saturating_mul(m) > MAX_LCS_CELLS {
+        return [vec![Op::Delete; n], vec![Op::Insert; m]].concat();
+    }
+    // lcs[i][j]: length of the longest common subsequence of a[i..], b[j..].
+    let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = match a[i] == b[j] {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+    let mut ops = Vec::with_capacity(n + m);
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && a[i] == b[j] {
+            ops.push(Op::Equal);
+            (i, j) = (i + 1, j + 1);
+        } else if j == m || (i < n && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            ops.push(Op::Delete);
+            i += 1;
+        } else {
+            ops.push(Op::Insert);
+            j += 1;
+        }
+    }
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unified_diff_keeps_context_around_changes() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\n";
+        let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\n";
+        assert_eq!(
+            unified(old, new, 1),
+            [
+                "@@ -1,3 +1,3 @@",
+                " a",
+                "-b",
+                "+B",
+                " c",
+                "@@ -8,1 +8,2 @@",
+                " h",
+                "+i",
+            ]
+        );
+        assert_eq!(
+            unified(old, new, 3)
+                .iter()
+                .filter(|l| l.starts_with("@@"))
+                .count(),
+            1
+        );
+        assert!(unified(old, old, 3).is_empty());
+        assert_eq!(unified("", "x", 3), ["@@ -0,0 +1,1 @@", "+x"]);
+    }
+
+    #[test]
+    fn json_is_pretty_printed_and_binary_skipped() {
+        let text = as_text(br#"{"b":1,"a":[true]}"#).unwrap();
+        assert_eq!(text.lines().count(), 6);
+        assert_eq!(as_text(b"plain"), Some("plain".to_string()));
+        assert_eq!(as_text(b"42"), Some("42".to_string()));
+        assert_eq!(as_text(&[0xff, 0xfe]), None);
+    }
+}
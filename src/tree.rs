@@ -1,7 +1,83 @@
 use crate::block::{compute_hash, BlockHash};
+use crate::storage::par_map;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::BTreeMap;
 
+/// Entries from which `Tree::encode_entries` spreads the work over threads.
+const PARALLEL_MIN_ENTRIES: usize = 4096;
+/// Entries per piece of work when encoding in parallel.
+const ENCODE_CHUNK: usize = 1024;
+
+/// A value stored in a tree: either the bytes themselves or a block reference.
+///
+/// Serialized untagged so trees written before block references existed
+/// (plain byte arrays) still load as `Inline` and keep their root hashes.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum TreeValue {
+    /// Small values are kept directly in the tree for fast reads.
+    Inline(Vec<u8>),
+    /// Large values live in the block store; the tree only records the hash.
+    Block { hash: BlockHash, size: u64 },
+    /// Streamed values are split into fixed-size chunks, each stored as a block.
+    Chunked { chunks: Vec<BlockHash>, size: u64 },
+    /// Values over `DbConfig::blob_threshold` live in the blob store.
+    Blob { blob: BlockHash, size: u64 },
+}
+
+impl TreeValue {
+    /// Size of the value in bytes.
+    pub fn size(&self) -> u64 {
+        match self {
+            TreeValue::Inline(v) => v.len() as u64,
+            TreeValue::Block { size, .. }
+            | TreeValue::Chunked { size, .. }
+            | TreeValue::Blob { size, .. } => *size,
+        }
+    }
+
+    /// The inline bytes, if the value is stored inline.
+    pub fn as_inline(&self) -> Option<&[u8]> {
+        match self {
+            TreeValue::Inline(v) => Some(v),
+            TreeValue::Block { .. } | TreeValue::Chunked { .. } | TreeValue::Blob { .. } => None,
+        }
+    }
+
+    /// The referenced block hash, if the value lives in a single block.
+    pub fn block_hash(&self) -> Option<&str> {
+        match self {
+            TreeValue::Block { hash, .. } => Some(hash),
+            TreeValue::Inline(_) | TreeValue::Chunked { .. } | TreeValue::Blob { .. } => None,
+        }
+    }
+
+    /// All block hashes this value references (empty for inline values and
+    /// blobs).
+    pub fn block_hashes(&self) -> Vec<&str> {
+        match self {
+            TreeValue::Inline(_) | TreeValue::Blob { .. } => Vec::new(),
+            TreeValue::Block { hash, .. } => vec![hash.as_str()],
+            TreeValue::Chunked { chunks, .. } => chunks.iter().map(String::as_str).collect(),
+        }
+    }
+
+    /// The hash of the blob holding the value, if it is a blob.
+    pub fn blob_hash(&self) -> Option<&str> {
+        match self {
+            TreeValue::Blob { blob, .. } => Some(blob),
+            _ => None,
+        }
+    }
+}
+
+impl From<Vec<u8>> for TreeValue {
+    fn from(v: Vec<u8>) -> Self {
+        TreeValue::Inline(v)
+    }
+}
+
 /// An immutable sorted key-value tree stored as a content-addressable snapshot.
 ///
 /// Each mutation produces a new `Tree` with a new root hash (copy-on-write semantics).
@@ -9,35 +85,86 @@ use std::collections::BTreeMap;
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Tree {
     pub root_hash: BlockHash,
-    pub entries: BTreeMap<String, Vec<u8>>,
+    pub entries: BTreeMap<String, TreeValue>,
+    #[serde(skip)]
+    encoded: Encoded,
+}
+
+/// The serialized entries a tree was hashed from, kept so that saving the
+/// tree does not serialize them a second time. Clones start without them,
+/// so trees held in caches do not carry the copy around.
+#[derive(Debug, Default)]
+struct Encoded(Option<Vec<u8>>);
+
+impl Clone for Encoded {
+    fn clone(&self) -> Self {
+        Self(None)
+    }
+}
+
+/// Trees are compared by their entries alone.
+impl PartialEq for Encoded {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
 }
 
+impl Eq for Encoded {}
+
 impl Tree {
     /// Create an empty tree.
     pub fn empty() -> Self {
-        let entries = BTreeMap::new();
-        let root_hash = Self::compute_root(&entries);
-        Self { root_hash, entries }
+        Self::from_entries(BTreeMap::new())
+    }
+
+    /// A tree of `entries`, with its root hash.
+    pub fn from_entries(entries: BTreeMap<String, TreeValue>) -> Self {
+        let encoded = Self::encode_entries(&entries);
+        Self {
+            root_hash: compute_hash(&encoded),
+            entries,
+            encoded: Encoded(Some(encoded)),
+        }
     }
 
     /// Insert or update a key. Returns a new tree (immutable).
-    pub fn insert(&self, key: String, value: Vec<u8>) -> Self {
+    pub fn insert(&self, key: String, value: impl Into<TreeValue>) -> Self {
         let mut entries = self.entries.clone();
-        entries.insert(key, value);
-        let root_hash = Self::compute_root(&entries);
-        Self { root_hash, entries }
+        entries.insert(key, value.into());
+        Self::from_entries(entries)
     }
 
     /// Delete a key. Returns a new tree (immutable).
     pub fn delete(&self, key: &str) -> Self {
         let mut entries = self.entries.clone();
         entries.remove(key);
-        let root_hash = Self::compute_root(&entries);
-        Self { root_hash, entries }
+        Self::from_entries(entries)
+    }
+
+    /// Delete several keys at once. Returns a new tree (immutable).
+    pub fn delete_all<'k>(&self, keys: impl IntoIterator<Item = &'k str>) -> Self {
+        let mut entries = self.entries.clone();
+        for key in keys {
+            entries.remove(key);
+        }
+        Self::from_entries(entries)
+    }
+
+    /// Apply puts and deletes (`None`) in order. Returns a new tree
+    /// (immutable).
+    pub fn apply(&self, writes: impl IntoIterator<Item = (String, Option<TreeValue>)>) -> Self {
+        let mut entries = self.entries.clone();
+        for (key, value) in writes {
+            match value {
+                Some(value) => entries.insert(key, value),
+                None => entries.remove(&key),
+            };
+        }
+        Self::from_entries(entries)
     }
 
     /// Get a value by key.
-    pub fn get(&self, key: &str) -> Option<&Vec<u8>> {
+    pub fn get(&self, key: &str) -> Option<&TreeValue> {
         self.entries.get(key)
     }
 
@@ -51,13 +178,22 @@ impl Tree {
         self.entries.len()
     }
 
+    /// Rough in-memory footprint in bytes (keys plus inline values), used to
+    /// budget caches.
+    pub fn approx_bytes(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(k, v)| k.len() + v.as_inline().map_or(64, |b| b.len()))
+            .sum()
+    }
+
     /// Whether the tree is empty.
     pub fn is_empty(&self) -> bool {
         self.entries.is_empty()
     }
 
     /// Range scan: returns entries where `start <= key < end`.
-    pub fn range(&self, start: &str, end: &str) -> Vec<(&String, &Vec<u8>)> {
+    pub fn range(&self, start: &str, end: &str) -> Vec<(&String, &TreeValue)> {
         use std::ops::Bound;
         self.entries
             .range::<String, _>((
@@ -68,13 +204,23 @@ impl Tree {
     }
 
     /// Prefix scan: returns all entries whose key starts with `prefix`.
-    pub fn scan_prefix(&self, prefix: &str) -> Vec<(&String, &Vec<u8>)> {
+    pub fn scan_prefix(&self, prefix: &str) -> Vec<(&String, &TreeValue)> {
         self.entries
             .iter()
             .filter(|(k, _)| k.starts_with(prefix))
             .collect()
     }
 
+    /// Number of keys starting with `prefix` and the total size of their
+    /// values.
+    pub fn prefix_usage(&self, prefix: &str) -> (u64, u64) {
+        use std::ops::Bound;
+        self.entries
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .fold((0, 0), |(keys, bytes), (_, v)| (keys + 1, bytes + v.size()))
+    }
+
     /// Compute diff between two trees. Returns (added, removed, modified) keys.
     pub fn diff(&self, other: &Tree) -> TreeDiff {
         let mut added = Vec::new();
@@ -101,14 +247,96 @@ impl Tree {
         }
     }
 
-    fn compute_root(entries: &BTreeMap<String, Vec<u8>>) -> BlockHash {
-        let serialized = serde_json::to_vec(entries).unwrap_or_default();
-        compute_hash(&serialized)
+    /// Counts and sizes of the changes from this tree to `other`.
+    pub fn diff_stat(&self, other: &Tree) -> DiffStat {
+        let diff = self.diff(other);
+        let size = |tree: &Tree, key: &String| tree.entries.get(key).map_or(0, TreeValue::size);
+        DiffStat {
+            added: diff.added.len(),
+            removed: diff.removed.len(),
+            modified: diff.modified.len(),
+            bytes_added: diff
+                .added
+                .iter()
+                .chain(&diff.modified)
+                .map(|k| size(other, k))
+                .sum(),
+            bytes_removed: diff
+                .removed
+                .iter()
+                .chain(&diff.modified)
+                .map(|k| size(self, k))
+                .sum(),
+        }
+    }
+
+    /// Compute the root hash for a set of entries.
+    pub fn compute_root(entries: &BTreeMap<String, TreeValue>) -> BlockHash {
+        compute_hash(&Self::encode_entries(entries))
+    }
+
+    /// `entries` as compact JSON, byte for byte what `serde_json::to_vec`
+    /// gives. Large maps are encoded a chunk of entries at a time in
+    /// parallel and the pieces joined.
+    pub fn encode_entries(entries: &BTreeMap<String, TreeValue>) -> Vec<u8> {
+        fn encode(items: &[(&String, &TreeValue)]) -> Vec<u8> {
+            let mut out = Vec::new();
+            for (i, (key, value)) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                // Strings and the derived impls of `TreeValue` cannot fail.
+                serde_json::to_writer(&mut out, key).unwrap_or_default();
+                out.push(b':');
+                serde_json::to_writer(&mut out, value).unwrap_or_default();
+            }
+            out
+        }
+        let items: Vec<_> = entries.iter().collect();
+        let pieces = match items.len() < PARALLEL_MIN_ENTRIES {
+            true => vec![encode(&items)],
+            false => {
+                let chunks: Vec<_> = items.chunks(ENCODE_CHUNK).collect();
+                par_map(&chunks, |chunk| encode(chunk))
+            }
+        };
+        let mut out = Vec::with_capacity(pieces.iter().map(|p| p.len() + 1).sum::<usize>() + 1);
+        out.push(b'{');
+        for (i, piece) in pieces.iter().enumerate() {
+            if i > 0 {
+                out.push(b',');
+            }
+            out.extend_from_slice(piece);
+        }
+        out.push(b'}');
+        out
+    }
+
+    /// The tree as stored: compact JSON of its root hash and entries, the
+    /// same as `serde_json::to_vec` would give. Reuses the entries as they
+    /// were serialized for hashing when the tree was built by a mutation.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let entries = match &self.encoded.0 {
+            Some(encoded) => Cow::Borrowed(encoded),
+            None => Cow::Owned(Self::encode_entries(&self.entries)),
+        };
+        let mut out = Vec::with_capacity(entries.len() + self.root_hash.len() + 32);
+        out.extend_from_slice(b"{\"root_hash\":");
+        serde_json::to_writer(&mut out, &self.root_hash).unwrap_or_default();
+        out.extend_from_slice(b",\"entries\":");
+        out.extend_from_slice(&entries);
+        out.push(b'}');
+        out
+    }
+
+    /// Check that the root hash matches the tree's entries.
+    pub fn verify(&self) -> bool {
+        self.root_hash == Self::compute_root(&self.entries)
     }
 }
 
 /// Diff result between two tree versions.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct TreeDiff {
     pub added: Vec<String>,
     pub removed: Vec<String>,
@@ -125,6 +353,46 @@ impl TreeDiff {
     }
 }
 
+/// A changed key with its values on either side: `old` is `None` for an
+/// added key, `new` for a removed one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueChange {
+    pub key: String,
+    pub old: Option<Vec<u8>>,
+    pub new: Option<Vec<u8>>,
+}
+
+/// Summary of a diff between two tree versions. A modified key counts its
+/// new value as added bytes and its old value as removed bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DiffStat {
+    pub added: usize,
+    pub removed: usize,
+    pub modified: usize,
+    pub bytes_added: u64,
+    pub bytes_removed: u64,
+}
+
+impl std::ops::AddAssign for DiffStat {
+    fn add_assign(&mut self, other: Self) {
+        self.added += other.added;
+        self.removed += other.removed;
+        self.modified += other.modified;
+        self.bytes_added += other.bytes_added;
+        self.bytes_removed += other.bytes_removed;
+    }
+}
+
+impl std::fmt::Display for DiffStat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} added, {} removed, {} modified (+{}/-{} bytes)",
+            self.added, self.removed, self.modified, self.bytes_added, self.bytes_removed
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -150,7 +418,7 @@ mod tests {
         let t = Tree::empty()
             .insert("a".into(), b"1".to_vec())
             .insert("b".into(), b"2".to_vec());
-        assert_eq!(t.get("a"), Some(&b"1".to_vec()));
+        assert_eq!(t.get("a"), Some(&TreeValue::Inline(b"1".to_vec())));
         let t2 = t.delete("a");
         assert!(!t2.contains_key("a"));
         assert!(t.contains_key("a")); // original untouched
@@ -187,6 +455,44 @@ mod tests {
         assert_eq!(diff.modified, vec!["b"]);
     }
 
+    #[test]
+    fn block_refs_and_legacy_inline_format() {
+        let t = Tree::empty().insert(
+            "big".into(),
+            TreeValue::Block {
+                hash: "abc".into(),
+                size: 10_000,
+            },
+        );
+        let json = serde_json::to_vec(&t).unwrap();
+        let back: Tree = serde_json::from_slice(&json).unwrap();
+        assert_eq!(back, t);
+        assert_eq!(back.get("big").unwrap().block_hash(), Some("abc"));
+        assert_eq!(back.get("big").unwrap().size(), 10_000);
+
+        let chunked = TreeValue::Chunked {
+            chunks: vec!["c1".into(), "c2".into()],
+            size: 20,
+        };
+        let json = serde_json::to_string(&chunked).unwrap();
+        assert_eq!(serde_json::from_str::<TreeValue>(&json).unwrap(), chunked);
+        assert_eq!(chunked.block_hashes(), vec!["c1", "c2"]);
+
+        let blob = TreeValue::Blob {
+            blob: "b1".into(),
+            size: 1 << 30,
+        };
+        let json = serde_json::to_string(&blob).unwrap();
+        assert_eq!(serde_json::from_str::<TreeValue>(&json).unwrap(), blob);
+        assert!(blob.block_hashes().is_empty());
+        assert_eq!(blob.blob_hash(), Some("b1"));
+
+        // Trees written before block references stored plain byte arrays.
+        let legacy: Tree =
+            serde_json::from_str(r#"{"root_hash":"x","entries":{"k":[104,105]}}"#).unwrap();
+        assert_eq!(legacy.get("k").unwrap().as_inline(), Some(&b"hi"[..]));
+    }
+
     #[test]
     fn same_content_same_hash() {
         let t1 = Tree::empty()
@@ -197,4 +503,57 @@ mod tests {
             .insert("a".into(), b"1".to_vec());
         assert_eq!(t1.root_hash, t2.root_hash);
     }
+
+    #[test]
+    fn diff_stat_counts_keys_and_bytes() {
+        let old = Tree::empty()
+            .insert("keep".into(), b"same".to_vec())
+            .insert("edit".into(), b"12".to_vec())
+            .insert("drop".into(), b"123".to_vec());
+        let new = old
+            .insert("edit".into(), b"12345".to_vec())
+            .delete("drop")
+            .insert("new".into(), b"1".to_vec());
+        let stat = old.diff_stat(&new);
+        assert_eq!((stat.added, stat.removed, stat.modified), (1, 1, 1));
+        assert_eq!((stat.bytes_added, stat.bytes_removed), (6, 5));
+        assert_eq!(
+            stat.to_string(),
+            "1 added, 1 removed, 1 modified (+6/-5 bytes)"
+        );
+        assert_eq!(new.diff_stat(&new), DiffStat::default());
+    }
+
+    #[test]
+    fn encoding_matches_serde_json() {
+        for n in [0, 3, PARALLEL_MIN_ENTRIES + ENCODE_CHUNK / 2] {
+            let entries: BTreeMap<String, TreeValue> = (0..n)
+                .map(|i| {
+                    let value = match i % 3 {
+                        0 => TreeValue::Inline(format!("\"v{}\u{e9}", i).into_bytes()),
+                        1 => TreeValue::Block {
+                            hash: format!("h{}", i),
+                            size: i as u64,
+                        },
+                        _ => TreeValue::Blob {
+                            blob: format!("b{}", i),
+                            size: 1 << 40,
+                        },
+                    };
+                    (format!("k\"{:05}", i), value)
+                })
+                .collect();
+            let encoded = Tree::encode_entries(&entries);
+            assert_eq!(encoded, serde_json::to_vec(&entries).unwrap());
+
+            let tree = Tree::from_entries(entries);
+            assert_eq!(tree.root_hash, compute_hash(&encoded));
+            let bytes = tree.to_bytes();
+            assert_eq!(bytes, serde_json::to_vec(&tree).unwrap());
+            // A clone drops the kept encoding but stores the same bytes.
+            assert_eq!(tree.clone().to_bytes(), bytes);
+            let back: Tree = serde_json::from_slice(&bytes).unwrap();
+            assert!(back == tree && back.verify());
+        }
+    }
 }
@@ -0,0 +1,400 @@
+use crate::error::{IcebergError, Result};
+use crate::index;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// How a vector index measures how far apart two embeddings are.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    /// One minus the cosine of the angle between the vectors.
+    #[default]
+    Cosine,
+    /// Straight-line distance.
+    Euclidean,
+    /// Negated dot product, so larger products rank nearer.
+    Dot,
+}
+
+impl std::str::FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "cosine" => Ok(Metric::Cosine),
+            "euclidean" | "l2" => Ok(Metric::Euclidean),
+            "dot" => Ok(Metric::Dot),
+            other => Err(format!("unknown metric: {}", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for Metric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Metric::Cosine => "cosine",
+            Metric::Euclidean => "euclidean",
+            Metric::Dot => "dot",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Metric {
+    /// Distance between two vectors of the same length; smaller is nearer.
+    pub fn distance(self, a: &[f32], b: &[f32]) -> f32 {
+        let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+        match self {
+            Metric::Cosine => {
+                let norms = norm(a) * norm(b);
+                if norms == 0.0 {
+                    1.0
+                } else {
+                    1.0 - dot / norms
+                }
+            }
+            Metric::Euclidean => a
+                .iter()
+                .zip(b)
+                .map(|(x, y)| (x - y) * (x - y))
+                .sum::<f32>()
+                .sqrt(),
+            Metric::Dot => -dot,
+        }
+    }
+}
+
+fn norm(v: &[f32]) -> f32 {
+    v.iter().map(|x| x * x).sum::<f32>().sqrt()
+}
+
+/// Settings of a vector index chosen at creation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VectorOptions {
+    /// Length of the embeddings; values of another length are not indexed.
+    pub dimensions: usize,
+    pub metric: Metric,
+}
+
+impl VectorOptions {
+    /// Options for `dimensions`-long embeddings compared by cosine distance.
+    pub fn new(dimensions: usize) -> Self {
+        Self {
+            dimensions,
+            metric: Metric::default(),
+        }
+    }
+}
+
+/// Indexes with at most this many vectors are searched exhaustively.
+const EXACT_SEARCH_LIMIT: usize = 512;
+/// Random-hyperplane hash tables used for approximate search.
+const TABLES: usize = 8;
+/// Hyperplanes per table; a table has up to `2^BITS` buckets.
+const BITS: usize = 10;
+
+/// An index over JSON arrays of numbers (embeddings) answering
+/// nearest-neighbour queries.
+///
+/// Small indexes are searched exactly. Larger ones use random-hyperplane
+/// locality-sensitive hashing: only vectors sharing a bucket with the query
+/// in some table are ranked, so a near neighbour can occasionally be missed.
+/// The hash tables are derived from the vectors and rebuilt after loading.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VectorIndex {
+    /// Name of this index.
+    pub name: String,
+    /// The JSON field path holding the embedding.
+    pub field_path: String,
+    pub dimensions: usize,
+    pub metric: Metric,
+    vectors: BTreeMap<String, Vec<f32>>,
+    #[serde(skip)]
+    tables: Vec<HashTable>,
+}
+
+/// One locality-sensitive hash table: vectors on the same side of every
+/// hyperplane share a bucket.
+#[derive(Debug, Clone)]
+struct HashTable {
+    planes: Vec<Vec<f32>>,
+    buckets: HashMap<u64, BTreeSet<String>>,
+}
+
+impl VectorIndex {
+    /// Create a new empty vector index.
+    pub fn new(name: String, field_path: String, options: &VectorOptions) -> Result<Self> {
+        if options.dimensions == 0 {
+            return Err(IcebergError::InvalidQuery(
+                "a vector index needs at least one dimension".into(),
+            ));
+        }
+        Ok(Self {
+            name,
+            field_path,
+            dimensions: options.dimensions,
+            metric: options.metric,
+            vectors: BTreeMap::new(),
+            tables: Vec::new(),
+        })
+    }
+
+    /// Index the embedding in a JSON value under `primary_key`, replacing
+    /// any previous one. Values without a valid embedding are not indexed.
+    pub fn index_entry(&mut self, primary_key: &str, value: &[u8]) {
+        let vector = self.extract(value);
+        self.set_vector(primary_key, vector);
+    }
+
+    /// Index `vector` under `primary_key`, or nothing for `None`, replacing
+    /// any previous one. Returns whether the key had or now has a vector.
+    pub(crate) fn set_vector(&mut self, primary_key: &str, vector: Option<Vec<f32>>) -> bool {
+        let had = self.vectors.contains_key(primary_key);
+        self.remove_key(primary_key);
+        let Some(vector) = vector else {
+            return had;
+        };
+        for table in &mut self.tables {
+            table
+                .buckets
+                .entry(signature(&table.planes, &vector))
+                .or_default()
+                .insert(primary_key.to_string());
+        }
+        self.vectors.insert(primary_key.to_string(), vector);
+        true
+    }
+
+    /// Remove a primary key from the index.
+    pub fn remove_key(&mut self, primary_key: &str) {
+        let Some(vector) = self.vectors.remove(primary_key) else {
+            return;
+        };
+        for table in &mut self.tables {
+            let sig = signature(&table.planes, &vector);
+            if let Some(keys) = table.buckets.get_mut(&sig) {
+                keys.remove(primary_key);
+                if keys.is_empty() {
+                    table.buckets.remove(&sig);
+                }
+            }
+        }
+    }
+
+    /// Remove every vector.
+    pub fn clear(&mut self) {
+        self.vectors.clear();
+        self.tables.clear();
+    }
+
+    /// Number of indexed vectors.
+    pub fn len(&self) -> usize {
+        self.vectors.len()
+    }
+
+    /// Rough number of bytes the vectors occupy in memory.
+    pub fn memory_bytes(&self) -> usize {
+        self.vectors
+            .keys()
+            .map(|k| k.len() + crate::index::ENTRY_OVERHEAD + self.dimensions * 4)
+            .sum()
+    }
+
+    /// Whether no vectors are indexed.
+    pub fn is_empty(&self) -> bool {
+        self.vectors.is_empty()
+    }
+
+    /// The `k` keys whose vectors are nearest to `query`, nearest first,
+    /// with their distances.
+    pub fn search(&mut self, query: &[f32], k: usize) -> Result<Vec<(String, f32)>> {
+        self.check_query(query)?;
+        if self.vectors.len() <= EXACT_SEARCH_LIMIT {
+            return Ok(nearest(self.metric, query, self.vectors.iter(), k));
+        }
+        if self.tables.is_empty() {
+            self.build_tables();
+        }
+        let candidates: BTreeSet<&String> = self
+            .tables
+            .iter()
+            .filter_map(|table| table.buckets.get(&signature(&table.planes, query)))
+            .flatten()
+            .collect();
+        if candidates.len() < k {
+            return Ok(nearest(self.metric, query, self.vectors.iter(), k));
+        }
+        let vectors = &self.vectors;
+        Ok(nearest(
+            self.metric,
+            query,
+            candidates.into_iter().map(|key| (key, &vectors[key])),
+            k,
+        ))
+    }
+
+    /// Fail with `InvalidQuery` unless `query` fits this index.
+    pub fn check_query(&self, query: &[f32]) -> Result<()> {
+        if query.len() != self.dimensions {
+            return Err(IcebergError::InvalidQuery(format!(
+                "index {} holds {}-dimensional vectors, got {}",
+                self.name,
+                self.dimensions,
+                query.len()
+            )));
+        }
+        if query.iter().any(|x| !x.is_finite()) {
+            return Err(IcebergError::InvalidQuery(
+                "query vector has a non-finite component".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn extract(&self, value: &[u8]) -> Option<Vec<f32>> {
+        extract(value, &self.field_path, self.dimensions)
+    }
+
+    fn build_tables(&mut self) {
+        let mut rng = XorShift(0x9e37_79b9_7f4a_7c15 ^ self.dimensions as u64);
+        self.tables = (0..TABLES)
+            .map(|_| {
+                let planes: Vec<Vec<f32>> = (0..BITS)
+                    .map(|_| (0..self.dimensions).map(|_| rng.next_unit()).collect())
+                    .collect();
+                let mut buckets: HashMap<u64, BTreeSet<String>> = HashMap::new();
+                for (key, vector) in &self.vectors {
+                    buckets
+                        .entry(signature(&planes, vector))
+                        .or_default()
+                        .insert(key.clone());
+                }
+                HashTable { planes, buckets }
+            })
+            .collect();
+    }
+}
+
+/// The embedding at `field_path` in a JSON value, if it has one of
+/// `dimensions` finite numbers.
+pub(crate) fn extract(value: &[u8], field_path: &str, dimensions: usize) -> Option<Vec<f32>> {
+    let parsed: serde_json::Value = serde_json::from_slice(value).ok()?;
+    let items = index::json_field(&parsed, field_path)?.as_array()?;
+    if items.len() != dimensions {
+        return None;
+    }
+    items
+        .iter()
+        .map(|x| x.as_f64().map(|x| x as f32).filter(|x| x.is_finite()))
+        .collect()
+}
+
+/// The `k` entries nearest to `query`, nearest first.
+pub(crate) fn nearest<'a>(
+    metric: Metric,
+    query: &[f32],
+    entries: impl Iterator<Item = (&'a String, &'a Vec<f32>)>,
+    k: usize,
+) -> Vec<(String, f32)> {
+    let mut ranked: Vec<(f32, &String)> = entries
+        .map(|(key, vector)| (metric.distance(query, vector), key))
+        .collect();
+    ranked.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(b.1)));
+    ranked
+        .into_iter()
+        .take(k)
+        .map(|(distance, key)| (key.clone(), distance))
+        .collect()
+}
+
+/// Which side of each hyperplane `vector` lies on, as bits.
+fn signature(planes: &[Vec<f32>], vector: &[f32]) -> u64 {
+    planes.iter().enumerate().fold(0, |sig, (i, plane)| {
+        let dot: f32 = plane.iter().zip(vector).map(|(p, v)| p * v).sum();
+        if dot >= 0.0 {
+            sig | (1 << i)
+        } else {
+            sig
+        }
+    })
+}
+
+/// Small deterministic generator for hyperplanes, so tables come out the
+/// same every time they are rebuilt. The seed must not be zero.
+pub(crate) struct XorShift(pub(crate) u64);
+
+impl XorShift {
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform in [-1, 1).
+    fn next_unit(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+    }
+
+    /// Uniform in [0, n), for n > 0.
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn embedding(v: &[f32]) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({ "embedding": v })).unwrap()
+    }
+
+    #[test]
+    fn exact_search_ranks_by_metric() {
+        let mut idx =
+            VectorIndex::new("emb".into(), "embedding".into(), &VectorOptions::new(2)).unwrap();
+        idx.index_entry("east", &embedding(&[1.0, 0.0]));
+        idx.index_entry("north", &embedding(&[0.0, 1.0]));
+        idx.index_entry("far-east", &embedding(&[10.0, 0.5]));
+        idx.index_entry("bad", &embedding(&[1.0, 2.0, 3.0]));
+        assert_eq!(idx.len(), 3);
+
+        let hits = idx.search(&[1.0, 0.0], 2).unwrap();
+        let keys: Vec<&str> = hits.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, vec!["east", "far-east"]);
+        assert!(hits[0].1.abs() < 1e-6);
+
+        idx.metric = Metric::Euclidean;
+        let hits = idx.search(&[0.0, 0.9], 1).unwrap();
+        assert_eq!(hits[0].0, "north");
+
+        assert!(matches!(
+            idx.search(&[1.0], 1),
+            Err(IcebergError::InvalidQuery(_))
+        ));
+        idx.remove_key("north");
+        assert_eq!(idx.search(&[0.0, 1.0], 5).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn approximate_search_finds_stored_vectors() {
+        let mut idx =
+            VectorIndex::new("emb".into(), "embedding".into(), &VectorOptions::new(8)).unwrap();
+        let mut rng = XorShift(42);
+        let vectors: Vec<Vec<f32>> = (0..EXACT_SEARCH_LIMIT * 2)
+            .map(|_| (0..8).map(|_| rng.next_unit()).collect())
+            .collect();
+        for (i, v) in vectors.iter().enumerate() {
+            idx.index_entry(&format!("v{}", i), &embedding(v));
+        }
+        for i in [0, 17, 600, 1000] {
+            let hits = idx.search(&vectors[i], 3).unwrap();
+            assert_eq!(hits[0].0, format!("v{}", i));
+        }
+        // Updates after the tables are built keep them consistent.
+        idx.index_entry("v0", &embedding(&vectors[1]));
+        idx.remove_key("v1");
+        assert_eq!(idx.search(&vectors[1], 1).unwrap()[0].0, "v0");
+    }
+}
@@ -1,8 +1,16 @@
+use crate::backend::{self, Backend, FsBackend};
 use crate::error::{IcebergError, Result};
+use crate::tree::TreeValue;
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::io::Write;
-use std::path::{Path, PathBuf};
+use std::path::Path;
+use std::sync::Arc;
+
+/// WAL file name inside the WAL directory.
+const WAL_FILE: &str = "wal.log";
+/// JSON-lines WAL written by older versions; migrated on open.
+const LEGACY_WAL_FILE: &str = "wal.jsonl";
+/// Bytes of framing before each record: payload length + CRC32 (both LE u32).
+const RECORD_HEADER: usize = 8;
 
 /// Write-Ahead Log entry types.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -15,8 +23,17 @@ pub enum WalEntry {
         key: String,
         value: Vec<u8>,
     },
+    /// A write whose value was already stored in the block store
+    /// (e.g. a streamed value), logged by reference instead of by content.
+    WriteRef {
+        tx_id: u64,
+        key: String,
+        value: TreeValue,
+    },
     /// A delete operation within a transaction.
     Delete { tx_id: u64, key: String },
+    /// The transaction writes to `branch` rather than the checked-out one.
+    Branch { tx_id: u64, branch: String },
     /// Commit the transaction (data is now durable).
     Commit { tx_id: u64, commit_id: String },
     /// Rollback the transaction.
@@ -28,33 +45,68 @@ pub enum WalEntry {
 /// Every mutation is first written to the WAL before being applied to the
 /// main storage. On recovery, uncommitted transactions are rolled back and
 /// committed but unapplied transactions are replayed.
+///
+/// Records are length-prefixed and carry a CRC32 of their payload. A record
+/// that is cut short or fails its checksum marks a torn write at the tail:
+/// it and everything after it are discarded on open, while all earlier
+/// records stay readable.
 pub struct Wal {
-    path: PathBuf,
+    backend: Arc<dyn Backend>,
+    /// Key of the log inside the backend.
+    key: String,
     next_tx: u64,
+    /// Records and bytes in the file since it was last truncated.
+    entry_count: usize,
+    bytes: u64,
 }
 
 impl Wal {
     /// Open or create a WAL at the given directory.
     pub fn open(dir: &Path) -> Result<Self> {
-        fs::create_dir_all(dir)?;
-        let path = dir.join("wal.jsonl");
-        let next_tx = if path.exists() {
-            Self::read_entries_from(&path)?
-                .iter()
-                .map(|e| match e {
-                    WalEntry::Begin { tx_id }
-                    | WalEntry::Write { tx_id, .. }
-                    | WalEntry::Delete { tx_id, .. }
-                    | WalEntry::Commit { tx_id, .. }
-                    | WalEntry::Rollback { tx_id } => *tx_id,
-                })
-                .max()
-                .unwrap_or(0)
-                + 1
-        } else {
-            1
+        Self::with_backend(Arc::new(FsBackend::open(dir)?), "")
+    }
+
+    /// Open or create a WAL under the `dir` key prefix of a backend.
+    pub fn with_backend(backend: Arc<dyn Backend>, dir: &str) -> Result<Self> {
+        let key = backend::key(dir, WAL_FILE);
+        let legacy = backend::key(dir, LEGACY_WAL_FILE);
+        if let Some(content) = backend.read(&legacy)? {
+            Self::migrate_legacy(&backend, &key, &content)?;
+            backend.delete(&legacy)?;
+        }
+        let (entries, valid_len) = match backend.read(&key)? {
+            Some(data) => {
+                let (entries, valid_len) = Self::scan(&data)?;
+                if valid_len < data.len() as u64 {
+                    // Drop the torn tail so new records are appended after
+                    // the last intact one.
+                    backend.write(&key, &data[..valid_len as usize])?;
+                }
+                (entries, valid_len)
+            }
+            None => (Vec::new(), 0),
         };
-        Ok(Self { path, next_tx })
+        let next_tx = entries
+            .iter()
+            .map(|e| match e {
+                WalEntry::Begin { tx_id }
+                | WalEntry::Write { tx_id, .. }
+                | WalEntry::WriteRef { tx_id, .. }
+                | WalEntry::Delete { tx_id, .. }
+                | WalEntry::Branch { tx_id, .. }
+                | WalEntry::Commit { tx_id, .. }
+                | WalEntry::Rollback { tx_id } => *tx_id,
+            })
+            .max()
+            .unwrap_or(0)
+            + 1;
+        Ok(Self {
+            backend,
+            key,
+            next_tx,
+            entry_count: entries.len(),
+            bytes: valid_len,
+        })
     }
 
     /// Start a new transaction. Returns the transaction ID.
@@ -70,18 +122,27 @@ impl Wal {
         self.append(&WalEntry::Write { tx_id, key, value })
     }
 
+    /// Log a write of a value that is already stored as blocks.
+    pub fn log_write_ref(&mut self, tx_id: u64, key: String, value: TreeValue) -> Result<()> {
+        self.append(&WalEntry::WriteRef { tx_id, key, value })
+    }
+
     /// Log a delete operation.
     pub fn log_delete(&mut self, tx_id: u64, key: String) -> Result<()> {
         self.append(&WalEntry::Delete { tx_id, key })
     }
 
+    /// Record that a transaction writes to a branch other than the
+    /// checked-out one.
+    pub fn log_branch(&mut self, tx_id: u64, branch: String) -> Result<()> {
+        self.append(&WalEntry::Branch { tx_id, branch })
+    }
+
     /// Mark a transaction as committed.
     pub fn commit(&mut self, tx_id: u64, commit_id: String) -> Result<()> {
         self.append(&WalEntry::Commit { tx_id, commit_id })?;
         // fsync to ensure durability
-        let f = fs::OpenOptions::new().write(true).open(&self.path)?;
-        f.sync_all()?;
-        Ok(())
+        self.backend.sync(&self.key)
     }
 
     /// Mark a transaction as rolled back.
@@ -91,7 +152,10 @@ impl Wal {
 
     /// Read all entries from the WAL.
     pub fn entries(&self) -> Result<Vec<WalEntry>> {
-        Self::read_entries_from(&self.path)
+        match self.backend.read(&self.key)? {
+            Some(data) => Ok(Self::scan(&data)?.0),
+            None => Ok(Vec::new()),
+        }
     }
 
     /// Recover: returns committed transaction IDs that may need replay,
@@ -101,6 +165,7 @@ impl Wal {
         let mut begun = std::collections::HashSet::new();
         let mut committed = std::collections::HashMap::new(); // tx_id → commit_id
         let mut rolled_back = std::collections::HashSet::new();
+        let mut branches = std::collections::HashMap::new();
 
         for entry in &entries {
             match entry {
@@ -113,6 +178,9 @@ impl Wal {
                 WalEntry::Rollback { tx_id } => {
                     rolled_back.insert(*tx_id);
                 }
+                WalEntry::Branch { tx_id, branch } => {
+                    branches.insert(*tx_id, branch.clone());
+                }
                 _ => {}
             }
         }
@@ -125,6 +193,7 @@ impl Wal {
 
         Ok(WalRecovery {
             committed,
+            branches,
             uncommitted,
             entries,
         })
@@ -132,41 +201,170 @@ impl Wal {
 
     /// Truncate the WAL (call after successful checkpoint).
     pub fn truncate(&mut self) -> Result<()> {
-        fs::write(&self.path, "")?;
+        self.backend.write(&self.key, &[])?;
+        self.entry_count = 0;
+        self.bytes = 0;
         Ok(())
     }
 
     /// Current WAL file size in bytes.
     pub fn size(&self) -> u64 {
-        fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0)
+        self.bytes
     }
 
-    fn append(&self, entry: &WalEntry) -> Result<()> {
-        let mut line = serde_json::to_string(entry)?;
-        line.push('\n');
-        let mut f = fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.path)?;
-        f.write_all(line.as_bytes())?;
+    /// Number of records currently in the WAL.
+    pub fn entry_count(&self) -> usize {
+        self.entry_count
+    }
+
+    fn append(&mut self, entry: &WalEntry) -> Result<()> {
+        let payload = entry.encode()?;
+        let mut record = Vec::with_capacity(RECORD_HEADER + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+        self.backend.append(&self.key, &record)?;
+        self.entry_count += 1;
+        self.bytes += record.len() as u64;
         Ok(())
     }
 
-    fn read_entries_from(path: &Path) -> Result<Vec<WalEntry>> {
-        if !path.exists() {
-            return Ok(Vec::new());
-        }
-        let content = fs::read_to_string(path)?;
+    /// Read records up to the first torn one. Returns the intact entries and
+    /// the byte length they occupy.
+    fn scan(data: &[u8]) -> Result<(Vec<WalEntry>, u64)> {
         let mut entries = Vec::new();
+        let mut pos = 0;
+        while data.len() - pos >= RECORD_HEADER {
+            let len = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            let crc = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap());
+            let start = pos + RECORD_HEADER;
+            if data.len() - start < len {
+                break;
+            }
+            let payload = &data[start..start + len];
+            if crc32fast::hash(payload) != crc {
+                break;
+            }
+            entries.push(WalEntry::decode(payload)?);
+            pos = start + len;
+        }
+        Ok((entries, pos as u64))
+    }
+
+    /// Rewrite a JSON-lines WAL as binary records.
+    fn migrate_legacy(backend: &Arc<dyn Backend>, key: &str, content: &[u8]) -> Result<()> {
+        let content = String::from_utf8_lossy(content);
+        let mut wal = Self {
+            backend: Arc::clone(backend),
+            key: key.to_string(),
+            next_tx: 0,
+            entry_count: 0,
+            bytes: 0,
+        };
         for line in content.lines() {
             if line.trim().is_empty() {
                 continue;
             }
-            let entry: WalEntry = serde_json::from_str(line)
-                .map_err(|e| IcebergError::Corruption(format!("WAL parse error: {}", e)))?;
-            entries.push(entry);
+            // A torn final line in the old format is dropped like a torn record.
+            let Ok(entry) = serde_json::from_str::<WalEntry>(line) else {
+                break;
+            };
+            wal.append(&entry)?;
         }
-        Ok(entries)
+        Ok(())
+    }
+}
+
+const TAG_BEGIN: u8 = 1;
+const TAG_WRITE: u8 = 2;
+const TAG_WRITE_REF: u8 = 3;
+const TAG_DELETE: u8 = 4;
+const TAG_COMMIT: u8 = 5;
+const TAG_ROLLBACK: u8 = 6;
+const TAG_BRANCH: u8 = 7;
+
+impl WalEntry {
+    /// Binary payload: a tag byte, the transaction id (LE u64), then the
+    /// variant's fields as u32-length-prefixed byte strings.
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let put_bytes = |out: &mut Vec<u8>, bytes: &[u8]| {
+            out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            out.extend_from_slice(bytes);
+        };
+        let (tag, tx_id) = match self {
+            WalEntry::Begin { tx_id } => (TAG_BEGIN, tx_id),
+            WalEntry::Write { tx_id, .. } => (TAG_WRITE, tx_id),
+            WalEntry::WriteRef { tx_id, .. } => (TAG_WRITE_REF, tx_id),
+            WalEntry::Delete { tx_id, .. } => (TAG_DELETE, tx_id),
+            WalEntry::Branch { tx_id, .. } => (TAG_BRANCH, tx_id),
+            WalEntry::Commit { tx_id, .. } => (TAG_COMMIT, tx_id),
+            WalEntry::Rollback { tx_id } => (TAG_ROLLBACK, tx_id),
+        };
+        out.push(tag);
+        out.extend_from_slice(&tx_id.to_le_bytes());
+        match self {
+            WalEntry::Begin { .. } | WalEntry::Rollback { .. } => {}
+            WalEntry::Write { key, value, .. } => {
+                put_bytes(&mut out, key.as_bytes());
+                put_bytes(&mut out, value);
+            }
+            WalEntry::WriteRef { key, value, .. } => {
+                put_bytes(&mut out, key.as_bytes());
+                put_bytes(&mut out, &serde_json::to_vec(value)?);
+            }
+            WalEntry::Delete { key, .. } => put_bytes(&mut out, key.as_bytes()),
+            WalEntry::Branch { branch, .. } => put_bytes(&mut out, branch.as_bytes()),
+            WalEntry::Commit { commit_id, .. } => put_bytes(&mut out, commit_id.as_bytes()),
+        }
+        Ok(out)
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self> {
+        let corrupt = |what: &str| IcebergError::Corruption(format!("WAL record: {}", what));
+        let mut rest = payload;
+        let mut take = |n: usize| -> Result<&[u8]> {
+            if rest.len() < n {
+                return Err(corrupt("truncated"));
+            }
+            let (head, tail) = rest.split_at(n);
+            rest = tail;
+            Ok(head)
+        };
+        let tag = take(1)?[0];
+        let tx_id = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        let mut bytes = || -> Result<Vec<u8>> {
+            let len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+            Ok(take(len)?.to_vec())
+        };
+        let string = |b: Vec<u8>| String::from_utf8(b).map_err(|_| corrupt("invalid UTF-8"));
+        Ok(match tag {
+            TAG_BEGIN => WalEntry::Begin { tx_id },
+            TAG_WRITE => WalEntry::Write {
+                tx_id,
+                key: string(bytes()?)?,
+                value: bytes()?,
+            },
+            TAG_WRITE_REF => WalEntry::WriteRef {
+                tx_id,
+                key: string(bytes()?)?,
+                value: serde_json::from_slice(&bytes()?)?,
+            },
+            TAG_DELETE => WalEntry::Delete {
+                tx_id,
+                key: string(bytes()?)?,
+            },
+            TAG_COMMIT => WalEntry::Commit {
+                tx_id,
+                commit_id: string(bytes()?)?,
+            },
+            TAG_ROLLBACK => WalEntry::Rollback { tx_id },
+            TAG_BRANCH => WalEntry::Branch {
+                tx_id,
+                branch: string(bytes()?)?,
+            },
+            other => return Err(corrupt(&format!("unknown tag {}", other))),
+        })
     }
 }
 
@@ -175,6 +373,8 @@ impl Wal {
 pub struct WalRecovery {
     /// Transactions that were committed (tx_id → commit_id).
     pub committed: std::collections::HashMap<u64, String>,
+    /// Target branches of transactions not on the checked-out branch.
+    pub branches: std::collections::HashMap<u64, String>,
     /// Transactions that were started but never committed or rolled back.
     pub uncommitted: Vec<u64>,
     /// All WAL entries.
@@ -184,6 +384,8 @@ pub struct WalRecovery {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::backend::MemoryBackend;
+    use std::fs;
 
     #[test]
     fn wal_begin_commit() {
@@ -287,4 +489,130 @@ mod tests {
         let tx = wal.begin().unwrap();
         assert!(tx > 1);
     }
+
+    #[test]
+    fn all_entry_kinds_roundtrip() {
+        let entries = vec![
+            WalEntry::Begin { tx_id: 7 },
+            WalEntry::Write {
+                tx_id: 7,
+                key: "k".into(),
+                value: vec![0, 255, 10],
+            },
+            WalEntry::WriteRef {
+                tx_id: 7,
+                key: "big".into(),
+                value: TreeValue::Chunked {
+                    chunks: vec!["ab".into(), "cd".into()],
+                    size: 42,
+                },
+            },
+            WalEntry::Delete {
+                tx_id: 7,
+                key: "gone".into(),
+            },
+            WalEntry::Branch {
+                tx_id: 7,
+                branch: "tenant-a/main".into(),
+            },
+            WalEntry::Commit {
+                tx_id: 7,
+                commit_id: "c".into(),
+            },
+            WalEntry::Rollback { tx_id: 8 },
+        ];
+        for entry in entries {
+            assert_eq!(WalEntry::decode(&entry.encode().unwrap()).unwrap(), entry);
+        }
+    }
+
+    #[test]
+    fn torn_tail_is_truncated_on_open() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(WAL_FILE);
+        {
+            let mut wal = Wal::open(tmp.path()).unwrap();
+            let tx = wal.begin().unwrap();
+            wal.log_write(tx, "a".into(), b"1".to_vec()).unwrap();
+            wal.commit(tx, "c1".into()).unwrap();
+        }
+        let intact = fs::metadata(&path).unwrap().len();
+        {
+            let mut wal = Wal::open(tmp.path()).unwrap();
+            let tx = wal.begin().unwrap();
+            wal.log_write(tx, "b".into(), b"2".to_vec()).unwrap();
+        }
+        // Cut the last record in half, as a crash mid-write would.
+        let len = fs::metadata(&path).unwrap().len();
+        let second_begin = intact + (RECORD_HEADER + 9) as u64;
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let mut wal = Wal::open(tmp.path()).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), second_begin);
+        let recovery = wal.recover().unwrap();
+        assert_eq!(recovery.entries.len(), 4);
+        assert_eq!(recovery.committed.len(), 1);
+
+        // New records land after the last intact one.
+        let tx = wal.begin().unwrap();
+        wal.commit(tx, "c2".into()).unwrap();
+        assert_eq!(wal.recover().unwrap().committed.len(), 2);
+    }
+
+    #[test]
+    fn checksum_mismatch_stops_reading() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut wal = Wal::open(tmp.path()).unwrap();
+        let tx = wal.begin().unwrap();
+        wal.log_write(tx, "k".into(), b"value".to_vec()).unwrap();
+        drop(wal);
+
+        let path = tmp.path().join(WAL_FILE);
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&path, data).unwrap();
+
+        let wal = Wal::open(tmp.path()).unwrap();
+        assert_eq!(wal.entries().unwrap(), vec![WalEntry::Begin { tx_id: tx }]);
+    }
+
+    #[test]
+    fn legacy_json_wal_is_migrated() {
+        let tmp = tempfile::tempdir().unwrap();
+        let lines = [
+            serde_json::to_string(&WalEntry::Begin { tx_id: 3 }).unwrap(),
+            serde_json::to_string(&WalEntry::Commit {
+                tx_id: 3,
+                commit_id: "c".into(),
+            })
+            .unwrap(),
+        ];
+        fs::write(tmp.path().join(LEGACY_WAL_FILE), lines.join("\n")).unwrap();
+
+        let mut wal = Wal::open(tmp.path()).unwrap();
+        assert!(!tmp.path().join(LEGACY_WAL_FILE).exists());
+        assert_eq!(wal.entries().unwrap().len(), 2);
+        assert_eq!(wal.begin().unwrap(), 4);
+    }
+
+    #[test]
+    fn wal_on_memory_backend() {
+        let backend: Arc<dyn Backend> = Arc::new(MemoryBackend::new());
+        {
+            let mut wal = Wal::with_backend(Arc::clone(&backend), "wal").unwrap();
+            let tx = wal.begin().unwrap();
+            wal.log_write(tx, "k".into(), b"v".to_vec()).unwrap();
+            wal.commit(tx, "c1".into()).unwrap();
+        }
+        assert!(backend.exists("wal/wal.log").unwrap());
+        let mut wal = Wal::with_backend(backend, "wal").unwrap();
+        assert_eq!(wal.recover().unwrap().committed.len(), 1);
+        assert_eq!(wal.begin().unwrap(), 2);
+    }
 }
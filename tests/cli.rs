@@ -0,0 +1,314 @@
+//! Tests of the `iceberg` command line, run against the built binary.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// A fresh database in a temporary directory.
+struct Cli {
+    _tmp: tempfile::TempDir,
+    db: PathBuf,
+}
+
+impl Cli {
+    fn new() -> Self {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = tmp.path().join("iceberg.db");
+        let cli = Self { _tmp: tmp, db };
+        cli.run(&["init"]);
+        cli
+    }
+
+    fn dir(&self) -> &Path {
+        self._tmp.path()
+    }
+
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_iceberg"));
+        command.arg("--db").arg(&self.db).args(args);
+        command
+    }
+
+    fn output(&self, args: &[&str]) -> Output {
+        self.command(args).output().unwrap()
+    }
+
+    /// Run a command that must succeed with `input` on its standard input.
+    fn run_with_stdin(&self, args: &[&str], input: &[u8]) {
+        let mut child = self
+            .command(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // Dropped once written, so the command sees the end of its input.
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(
+            output.status.success(),
+            "{:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    /// Stdout of a command that must succeed.
+    fn run(&self, args: &[&str]) -> String {
+        let output = self.output(args);
+        assert!(
+            output.status.success(),
+            "{:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    /// The JSON printed by a command run with `--format json`.
+    fn json(&self, args: &[&str]) -> serde_json::Value {
+        let out = self.run(&[args, &["--format", "json"]].concat());
+        serde_json::from_str(&out).unwrap()
+    }
+
+    /// Stderr of a command that must fail.
+    fn fail(&self, args: &[&str]) -> String {
+        let output = self.output(args);
+        assert!(!output.status.success(), "{:?} succeeded", args);
+        String::from_utf8(output.stderr).unwrap()
+    }
+}
+
+#[test]
+fn fsck_resurrects_lost_commits_onto_the_branch_named_with_to() {
+    let cli = Cli::new();
+    cli.run(&["put", "a", "1"]);
+    cli.run(&["branch", "dev"]);
+    cli.run(&["checkout", "dev"]);
+    cli.run(&["put", "b", "2"]);
+    cli.run(&["checkout", "main"]);
+    cli.run(&["delete-branch", "dev"]);
+
+    let lost = cli.run(&["fsck", "--lost-found"]);
+    let line = lost.lines().find(|l| l.starts_with("lost ")).unwrap();
+    let id = line.split_whitespace().nth(1).unwrap();
+    assert!(cli
+        .fail(&[
+            "fsck",
+            "--lost-found",
+            "--resurrect",
+            id,
+            "--branch",
+            "back"
+        ])
+        .contains("--branch is not supported"));
+    cli.run(&["fsck", "--lost-found", "--resurrect", id, "--to", "back"]);
+    assert_eq!(cli.run(&["--branch", "back", "get", "b"]), "2\n");
+    assert!(cli.fail(&["fsck", "--to", "back"]).contains("--resurrect"));
+}
+
+#[test]
+fn get_output_leaves_the_file_alone_unless_the_value_is_read() {
+    let cli = Cli::new();
+    let out = cli.dir().join("value.bin");
+    std::fs::write(&out, b"keep me").unwrap();
+    let out_arg = out.to_str().unwrap();
+    cli.fail(&["get", "missing", "--output", out_arg]);
+    assert_eq!(std::fs::read(&out).unwrap(), b"keep me");
+
+    cli.run(&["put", "k", "value"]);
+    cli.run(&["get", "k", "--output", out_arg]);
+    assert_eq!(std::fs::read(&out).unwrap(), b"value");
+    assert_eq!(std::fs::read_dir(cli.dir()).unwrap().count(), 2);
+}
+
+#[test]
+fn apply_batch_commits_a_file_of_operations() {
+    let cli = Cli::new();
+    cli.run(&["put", "old", "1"]);
+    let ops = cli.dir().join("ops.jsonl");
+    std::fs::write(
+        &ops,
+        "{\"op\": \"put\", \"key\": \"a\", \"value\": \"x\"}\n{\"op\": \"delete\", \"key\": \"old\"}\n",
+    )
+    .unwrap();
+    let out = cli.run(&["apply-batch", ops.to_str().unwrap()]);
+    assert!(out.contains("apply 2 operations from"), "{}", out);
+    assert_eq!(cli.run(&["get", "a"]), "x\n");
+    cli.fail(&["get", "old"]);
+}
+
+#[test]
+fn format_json_prints_parseable_results() {
+    let cli = Cli::new();
+    cli.run(&["put", "a", "1", "-m", "first"]);
+    cli.run(&["put", "b", "2"]);
+    cli.run(&["tag", "v1"]);
+    cli.run(&["put", "a", "3"]);
+    cli.run(&["delete", "b"]);
+
+    assert_eq!(
+        cli.json(&["get", "a"]),
+        serde_json::json!({ "key": "a", "text": "3" })
+    );
+    assert_eq!(
+        cli.json(&["scan", ""]),
+        serde_json::json!({ "entries": [{ "key": "a", "text": "3" }], "next": null })
+    );
+    assert_eq!(
+        cli.json(&["scan", "", "--keys-only"]),
+        serde_json::json!({ "keys": ["a"], "next": null })
+    );
+
+    let log = cli.json(&["log"]);
+    let log = log.as_array().unwrap();
+    assert_eq!(log.len(), 4);
+    assert_eq!(log[3]["message"], "first");
+    assert_eq!(log[0]["parent"], log[1]["id"]);
+    assert!(log[0]["timestamp"].is_string());
+
+    assert_eq!(
+        cli.json(&["diff", "v1"]),
+        serde_json::json!({ "added": [], "removed": ["b"], "modified": ["a"] })
+    );
+    assert_eq!(
+        cli.json(&["diff", "v1", "--values"]),
+        serde_json::json!([
+            { "key": "a", "old": { "text": "1" }, "new": { "text": "3" } },
+            { "key": "b", "old": { "text": "2" }, "new": null },
+        ])
+    );
+    let stat = cli.json(&["diff", "v1", "--stat"]);
+    assert_eq!(stat["prefixes"].as_array().unwrap().len(), 2);
+
+    let stats = cli.json(&["stats"]);
+    assert_eq!(stats["key_count"], 1);
+    assert_eq!(stats["commit_count"], 4);
+    assert_eq!(stats["branch_count"], 1);
+
+    cli.run(&["branch", "dev"]);
+    assert_eq!(
+        cli.json(&["branches"]),
+        serde_json::json!([
+            { "name": "dev", "current": false },
+            { "name": "main", "current": true },
+        ])
+    );
+    let tags = cli.json(&["tags"]);
+    assert_eq!(tags[0]["name"], "v1");
+    assert_eq!(tags[0]["commit_id"], log[2]["id"]);
+
+    let compact = cli.json(&["compact", "--max-versions", "1"]);
+    assert_eq!(compact["commits_removed"], 2);
+    assert!(compact["bytes_reclaimed"].is_u64());
+
+    assert!(cli
+        .fail(&["put", "c", "1", "--format", "json"])
+        .contains("--format json is not supported"));
+}
+
+#[test]
+fn diff_resolves_branches_tags_and_id_prefixes() {
+    let cli = Cli::new();
+    cli.run(&["put", "a", "1"]);
+    cli.run(&["tag", "v1"]);
+    cli.run(&["branch", "dev"]);
+    cli.run(&["put", "b", "2"]);
+    let log = cli.json(&["log"]);
+    let head = log[0]["id"].as_str().unwrap();
+
+    let expected = "+ b\n";
+    assert_eq!(cli.run(&["diff", "dev"]), expected);
+    assert_eq!(cli.run(&["diff", "v1", "main"]), expected);
+    assert_eq!(cli.run(&["diff", "v1", &head[..8]]), expected);
+    assert_eq!(cli.run(&["diff", "main", "dev"]), "- b\n");
+    assert!(cli.fail(&["diff", "nope"]).contains("nope"));
+    assert!(cli.fail(&["diff", "dev", &head[..3]]).contains("not found"));
+
+    // Enough commits that two ids share a shortest allowed prefix.
+    let prefix = {
+        let db = iceberg::db::Database::open(&cli.db).unwrap();
+        let mut seen = std::collections::HashSet::new();
+        (0..).find_map(|i| {
+            let commit = db.put("n", i.to_string().into_bytes(), None).unwrap();
+            let prefix = commit.id[..iceberg::db::MIN_ID_PREFIX].to_string();
+            (!seen.insert(prefix.clone())).then_some(prefix)
+        })
+    }
+    .unwrap();
+    assert!(cli.fail(&["diff", &prefix]).contains("ambiguous"));
+}
+
+#[test]
+fn get_encodes_binary_values() {
+    let cli = Cli::new();
+    let value = b"\x00\xff\x10hi";
+    let file = cli.dir().join("value.bin");
+    std::fs::write(&file, value).unwrap();
+    cli.run(&["put", "bin", "--file", file.to_str().unwrap()]);
+
+    let hex = cli.run(&["get", "bin", "--encoding", "hex"]);
+    let decoded: Vec<u8> = (0..hex.trim_end().len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect();
+    assert_eq!(decoded, value);
+    assert_eq!(
+        cli.run(&["get", "bin", "--encoding", "base64"]),
+        "AP8QaGk=\n"
+    );
+    for (text, encoded) in [("h", "aA=="), ("hi", "aGk="), ("hi!", "aGkh")] {
+        cli.run(&["put", "t", text]);
+        assert_eq!(
+            cli.run(&["get", "t", "--encoding", "base64"]),
+            format!("{}\n", encoded)
+        );
+    }
+    let raw = cli.output(&["get", "bin", "--encoding", "raw"]);
+    assert_eq!(raw.stdout, value);
+
+    let err = cli.fail(&["get", "bin", "--encoding", "text"]);
+    assert!(err.contains("not UTF-8"), "{}", err);
+    assert!(cli.fail(&["get", "bin"]).contains("not UTF-8"));
+}
+
+#[test]
+fn put_stdin_stores_standard_input_as_is() {
+    let cli = Cli::new();
+    // Binary, with newlines, and spanning several stored chunks.
+    let value: Vec<u8> = (0..3 << 20).map(|i: u32| (i * 7 % 251) as u8).collect();
+    cli.run_with_stdin(&["put", "big", "--stdin"], &value);
+    assert_eq!(
+        cli.output(&["get", "big", "--encoding", "raw"]).stdout,
+        value
+    );
+
+    cli.run_with_stdin(&["put", "line", "--stdin", "-m", "from a pipe"], b"text\n");
+    assert_eq!(cli.run(&["get", "line"]), "text\n\n");
+    assert_eq!(cli.json(&["log"])[0]["message"], "from a pipe");
+
+    cli.run_with_stdin(&["put", "empty", "--stdin"], b"");
+    assert!(cli
+        .output(&["get", "empty", "--encoding", "raw"])
+        .stdout
+        .is_empty());
+    assert_eq!(cli.json(&["get", "empty"])["text"], "");
+
+    assert!(cli.fail(&["put", "k", "v", "--stdin"]).contains("--stdin"));
+}
+
+#[test]
+fn completions_and_man_page_are_generated() {
+    let cli = Cli::new();
+    let bash = cli.run(&["completions", "bash"]);
+    assert!(bash.contains("_iceberg()"), "{}", bash);
+    assert!(bash.contains("apply-batch"));
+    assert!(cli
+        .run(&["completions", "zsh"])
+        .starts_with("#compdef iceberg"));
+    assert!(cli.fail(&["completions", "tcsh"]).contains("tcsh"));
+
+    let man = cli.run(&["man"]);
+    assert!(man.contains(".TH iceberg 1"), "{}", man);
+    assert!(man.contains("fsck"));
+}